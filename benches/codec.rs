@@ -0,0 +1,77 @@
+use std::{hint::black_box, time::Instant};
+
+use base64::{Engine, prelude::BASE64_URL_SAFE};
+use wg_disco::{
+    signaling::{BINCODE_CONFIG, Extensions, PeerUpdate, codec},
+    wg::Key,
+};
+
+const ITERS: u32 = 1_000_000;
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    let start = Instant::now();
+
+    for _ in 0..ITERS {
+        f();
+    }
+
+    let nanos = start.elapsed().as_nanos() as f64 / ITERS as f64;
+    println!("{name:<28} {nanos:>8.1} ns/iter");
+}
+
+fn main() {
+    let peer = PeerUpdate {
+        key: Key::random(),
+        endpoint: "203.0.113.7:51820".parse().unwrap(),
+        advertise_routes: vec![],
+        ext: Extensions {
+            timestamp: Some(1_700_000_000_000),
+            local_endpoint: Some("192.168.1.10:51820".parse().unwrap()),
+            ..Default::default()
+        },
+    };
+
+    let mut msg = String::new();
+    codec::encode(&peer, &mut msg).unwrap();
+
+    // a typical line on a busy channel which is not ours
+    let chatter = "hello there, anybody knows how to configure wireguard on openwrt?";
+
+    bench("encode (alloc per message)", || {
+        let bytes = bincode::encode_to_vec(black_box(&peer), BINCODE_CONFIG).unwrap();
+        black_box(BASE64_URL_SAFE.encode(bytes));
+    });
+
+    let mut buf = String::with_capacity(codec::MAX_MSG_LEN);
+    bench("encode (reused buffer)", || {
+        buf.clear();
+        codec::encode(black_box(&peer), &mut buf).unwrap();
+        black_box(&buf);
+    });
+
+    bench("decode (alloc per message)", || {
+        let bytes = BASE64_URL_SAFE.decode(black_box(&msg)).unwrap();
+        let peer: PeerUpdate = bincode::decode_from_slice(&bytes, BINCODE_CONFIG)
+            .unwrap()
+            .0;
+        black_box(peer);
+    });
+
+    bench("decode (stack buffer)", || {
+        black_box(codec::decode(black_box(&msg)).unwrap());
+    });
+
+    bench("reject chatter (alloc)", || {
+        let res = BASE64_URL_SAFE
+            .decode(black_box(chatter))
+            .ok()
+            .and_then(|bytes| {
+                bincode::decode_from_slice::<PeerUpdate, _>(&bytes, BINCODE_CONFIG).ok()
+            });
+        black_box(res);
+    });
+
+    bench("reject chatter (stack buffer)", || {
+        black_box(codec::decode(black_box(chatter)).ok());
+    });
+}
@@ -0,0 +1,17 @@
+//! Android, where wg-disco runs from Termux and everything a regular Linux
+//! system keeps in the root filesystem lives under the app's prefix.
+
+use std::{env, path::PathBuf};
+
+/// Termux prefix when the environment doesn't tell it.
+const TERMUX_PREFIX: &str = "/data/data/com.termux/files/usr";
+
+/// `path` of a regular Linux system, under the Termux prefix on Android.
+pub fn path(path: &str) -> PathBuf {
+    if !cfg!(target_os = "android") {
+        return PathBuf::from(path);
+    }
+
+    let prefix = env::var("PREFIX").unwrap_or_else(|_| TERMUX_PREFIX.into());
+    PathBuf::from(prefix).join(path.trim_start_matches('/'))
+}
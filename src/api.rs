@@ -0,0 +1,492 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+};
+
+use crate::{
+    control::{Command, Event, Handle, Response},
+    json::Value,
+    limits::Limits,
+    metrics::{self, Labels},
+    signaling::skew::unix_ms,
+    systemd,
+};
+
+/// Requests are tiny, anything bigger is not ours.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pins set without `ttl` expire after an hour.
+const DEFAULT_PIN_TTL: Duration = Duration::from_secs(3600);
+
+/// Longest a pin can be set for, a month.
+const MAX_PIN_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// Unix time in seconds and the event, oldest first.
+type EventLog = Arc<Mutex<VecDeque<(u64, Event)>>>;
+
+/// `[api]` section of the config.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiConfig {
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+
+    /// Expected in `Authorization: Bearer <token>`. Left out it's read from
+    /// the `wg-disco-api-token` systemd credential.
+    #[serde(default)]
+    pub token: String,
+}
+
+fn default_listen() -> SocketAddr {
+    (Ipv4Addr::LOCALHOST, 9191).into()
+}
+
+/// Serves the local HTTP API:
+///
+/// - `GET /v1/status`
+/// - `GET /v1/peers`
+/// - `GET /v1/routes`
+/// - `GET /v1/events`
+/// - `POST /v1/announce`
+/// - `POST /v1/pin?key=<key>&endpoint=<ip:port>[&ttl=<seconds>]`
+/// - `DELETE /v1/pin?key=<key>`
+/// - `POST /v1/probe?key=<key>`
+/// - `POST /v1/debug?key=<key>`, `DELETE /v1/debug?key=<key>`: debug logging of one peer
+/// - `POST /v1/block?key=<key>`, `DELETE /v1/block?key=<key>`: ignore a compromised peer
+/// - `POST /v1/revoke?key=<key>`: revoke a peer mesh-wide, on a revocation signer
+/// - `POST /v1/freeze`, `DELETE /v1/freeze`: stop and resume applying changes
+/// - `GET /v1/explain?key=<key>`: why a peer is down, as text
+/// - `GET /metrics`: Prometheus metrics, peers labelled by `labels`
+///
+/// A socket passed by systemd socket activation is used instead of `listen`.
+pub async fn serve(
+    config: ApiConfig,
+    control: Handle,
+    limits: Limits,
+    labels: Labels,
+) -> io::Result<()> {
+    let listener = match systemd::take_tcp_listener() {
+        Some(listener) => TcpListener::from_std(listener)?,
+        None => TcpListener::bind(config.listen).await?,
+    };
+    let listen = listener.local_addr()?;
+
+    if !listen.ip().is_loopback() {
+        log::warn!("http api listens on non-loopback {listen}");
+    }
+
+    let token: Arc<str> = config.token.into();
+    let labels = Arc::new(labels);
+    log::info!("http api listening on {listen}");
+
+    let history = EventLog::default();
+    tokio::spawn(record_events(
+        control.clone(),
+        history.clone(),
+        limits.event_log,
+    ));
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let token = token.clone();
+        let control = control.clone();
+        let history = history.clone();
+        let labels = labels.clone();
+
+        tokio::spawn(async move {
+            let res = tokio::time::timeout(
+                REQUEST_TIMEOUT,
+                handle(stream, &token, &control, &history, &labels),
+            )
+            .await;
+
+            match res {
+                Ok(Err(err)) => log::debug!("http api {peer}: {err}"),
+                Err(_) => log::debug!("http api {peer}: timed out"),
+                Ok(Ok(())) => {}
+            }
+        });
+    }
+}
+
+async fn record_events(control: Handle, history: EventLog, len: usize) {
+    let mut events = control.subscribe();
+
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                let mut history = history.lock().unwrap();
+                if history.len() >= len {
+                    history.pop_front();
+                }
+                history.push_back((unix_ms() / 1000, event));
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::warn!("http api missed {missed} events");
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    token: &str,
+    control: &Handle,
+    history: &EventLog,
+    labels: &Labels,
+) -> io::Result<()> {
+    let Some(head) = read_head(&mut stream).await? else {
+        return respond(&mut stream, 413, "{\"error\":\"request too large\"}").await;
+    };
+
+    // not json, answered before the other routes
+    if authorized(&head, token)
+        && let Some((status, content_type, body)) = text_route(&head, control, labels).await
+    {
+        return respond_with(&mut stream, status, content_type, &body).await;
+    }
+
+    let (status, body) = route(&head, token, control, history).await;
+
+    respond(&mut stream, status, &body).await
+}
+
+/// Reads the request line and headers, `None` when they are too long.
+pub(crate) async fn read_head(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut buf = Vec::with_capacity(1024);
+
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_LEN {
+            return Ok(None);
+        }
+
+        let mut chunk = [0u8; 1024];
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            break;
+        }
+
+        buf.extend_from_slice(&chunk[..len]);
+    }
+
+    Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+async fn route(head: &str, token: &str, control: &Handle, history: &EventLog) -> (u16, String) {
+    let mut request = head.lines().next().unwrap_or_default().split(' ');
+    let (method, target) = (
+        request.next().unwrap_or_default(),
+        request.next().unwrap_or_default(),
+    );
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    if !authorized(head, token) {
+        return (401, "{\"error\":\"unauthorized\"}".into());
+    }
+
+    let command = match (method, path) {
+        ("GET", "/v1/status") => Command::Status,
+        ("GET", "/v1/peers") => Command::Peers,
+        ("GET", "/v1/routes") => Command::Routes,
+        ("POST", "/v1/announce") => Command::Announce,
+        ("POST", "/v1/pin") => match pin_command(query) {
+            Some(command) => command,
+            None => {
+                let err = "{\"error\":\"expected key, endpoint and ttl of at most 30 days\"}";
+                return (400, err.into());
+            }
+        },
+        ("DELETE", "/v1/pin") => match param(query, "key").and_then(|key| key.parse().ok()) {
+            Some(key) => Command::Unpin(key),
+            None => return (400, "{\"error\":\"expected key\"}".into()),
+        },
+        ("POST", "/v1/probe") => match param(query, "key").and_then(|key| key.parse().ok()) {
+            Some(key) => Command::Probe(key),
+            None => return (400, "{\"error\":\"expected key\"}".into()),
+        },
+        ("POST" | "DELETE", "/v1/debug") => {
+            match param(query, "key").and_then(|key| key.parse().ok()) {
+                Some(key) => Command::Debug {
+                    key,
+                    enable: method == "POST",
+                },
+                None => return (400, "{\"error\":\"expected key\"}".into()),
+            }
+        }
+        ("POST" | "DELETE", "/v1/block") => {
+            match param(query, "key").and_then(|key| key.parse().ok()) {
+                Some(key) if method == "POST" => Command::Block(key),
+                Some(key) => Command::Unblock(key),
+                None => return (400, "{\"error\":\"expected key\"}".into()),
+            }
+        }
+        ("POST" | "DELETE", "/v1/freeze") => Command::Freeze {
+            enable: method == "POST",
+        },
+        ("POST", "/v1/revoke") => match param(query, "key").and_then(|key| key.parse().ok()) {
+            Some(key) => Command::Revoke(key),
+            None => return (400, "{\"error\":\"expected key\"}".into()),
+        },
+        ("GET", "/v1/events") => {
+            let history = history.lock().unwrap();
+            let events = history
+                .iter()
+                .map(|(at, event)| {
+                    let (kind, key) = match event {
+                        Event::PeerUp(key) => ("peer_up", key),
+                        Event::PeerDown(key) => ("peer_down", key),
+                        Event::Cloned(key) => ("peer_cloned", key),
+                        Event::ProbeAnswered(key) => ("probe_answered", key),
+                        Event::ProbeSilent(key) => ("probe_silent", key),
+                        Event::Revoked(key) => ("peer_revoked", key),
+                    };
+
+                    Value::object([
+                        ("at", Value::from(*at)),
+                        ("event", Value::from(kind)),
+                        ("key", Value::from(key.to_string())),
+                    ])
+                })
+                .collect();
+
+            return (200, Value::Array(events).to_string());
+        }
+        (
+            _,
+            "/v1/status" | "/v1/peers" | "/v1/routes" | "/v1/events" | "/v1/announce" | "/v1/pin"
+            | "/v1/probe" | "/v1/debug" | "/v1/block" | "/v1/revoke" | "/v1/freeze",
+        ) => {
+            return (405, "{\"error\":\"method not allowed\"}".into());
+        }
+        _ => return (404, "{\"error\":\"not found\"}".into()),
+    };
+
+    match control.call(command).await {
+        Some(res @ Response::Error(_)) => (500, res.to_json().to_string()),
+        Some(res) => (200, res.to_json().to_string()),
+        None => (503, "{\"error\":\"runner is not running\"}".into()),
+    }
+}
+
+fn pin_command(query: &str) -> Option<Command> {
+    let ttl = match param(query, "ttl") {
+        Some(ttl) => Duration::from_secs(ttl.parse().ok()?),
+        None => DEFAULT_PIN_TTL,
+    };
+    if ttl > MAX_PIN_TTL {
+        return None;
+    }
+
+    Some(Command::Pin {
+        key: param(query, "key")?.parse().ok()?,
+        endpoint: param(query, "endpoint")?.parse().ok()?,
+        ttl,
+    })
+}
+
+/// Percent-decoded query parameter. `+` is kept as is, keys are base64.
+fn param(query: &str, name: &str) -> Option<String> {
+    let value = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)?
+        .1;
+
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+async fn respond(stream: &mut TcpStream, status: u16, body: &str) -> io::Result<()> {
+    respond_with(stream, status, "application/json", body).await
+}
+
+pub(crate) async fn respond_with(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        421 => "Misdirected Request",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+
+    let head = format!(
+        "HTTP/1.1 {status} {reason}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn authorized(head: &str, token: &str) -> bool {
+    head.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .filter_map(|(_, value)| value.trim().strip_prefix("Bearer "))
+        .any(|bearer| constant_time_eq(bearer.trim().as_bytes(), token.as_bytes()))
+}
+
+/// Routes answered in plain text, `None` for the others.
+async fn text_route(
+    head: &str,
+    control: &Handle,
+    labels: &Labels,
+) -> Option<(u16, &'static str, String)> {
+    const TEXT: &str = "text/plain; charset=utf-8";
+
+    let mut request = head.lines().next().unwrap_or_default().split(' ');
+    let (method, target) = (request.next()?, request.next()?);
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let res = match (method, path) {
+        ("GET", "/metrics") => match (
+            control.call(Command::Status).await,
+            control.call(Command::Peers).await,
+        ) {
+            (Some(Response::Status(status)), Some(Response::Peers(peers))) => {
+                let body = metrics::render(unix_ms() / 1000, &status, &peers, labels);
+                return Some((200, metrics::CONTENT_TYPE, body));
+            }
+            _ => None,
+        },
+        ("GET", "/v1/explain") => match param(query, "key").and_then(|key| key.parse().ok()) {
+            Some(key) => control.call(Command::Explain(key)).await,
+            None => return Some((400, TEXT, "expected key\n".into())),
+        },
+        _ => return None,
+    };
+
+    Some(match res {
+        Some(Response::Explanation(explanation)) => (200, TEXT, explanation.to_string()),
+        Some(Response::Error(err)) => (500, TEXT, format!("{err}\n")),
+        _ => (503, TEXT, "runner is not running\n".into()),
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        control::{self, Command, Response},
+        wg::Key,
+    };
+
+    use super::{EventLog, pin_command, route};
+
+    #[tokio::test]
+    async fn test_route_auth() {
+        let (handle, mut requests) = control::channel();
+        tokio::spawn(async move {
+            while let Some(req) = requests.recv().await {
+                let _ = req.reply.send(Response::Ok);
+            }
+        });
+
+        let history = EventLog::default();
+        let head = "POST /v1/announce HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n";
+        assert_eq!(
+            route(head, "secret", &handle, &history).await,
+            (200, r#"{"ok":true}"#.into())
+        );
+
+        assert_eq!(route(head, "other", &handle, &history).await.0, 401);
+
+        let head = "GET /v1/announce HTTP/1.1\r\nauthorization: Bearer secret\r\n\r\n";
+        assert_eq!(route(head, "secret", &handle, &history).await.0, 405);
+    }
+
+    #[test]
+    fn test_pin_command() {
+        let key = Key::random();
+        let encoded = key.to_string().replace('/', "%2F").replace('=', "%3D");
+
+        assert_eq!(
+            pin_command(&format!("key={encoded}&endpoint=192.0.2.1:51820&ttl=60")),
+            Some(Command::Pin {
+                key,
+                endpoint: "192.0.2.1:51820".parse().unwrap(),
+                ttl: Duration::from_secs(60),
+            })
+        );
+
+        assert!(pin_command(&format!("key={encoded}&ttl=60")).is_none());
+        assert!(pin_command("key=x&endpoint=192.0.2.1:51820").is_none());
+        assert!(
+            pin_command(&format!(
+                "key={encoded}&endpoint=192.0.2.1:51820&ttl={}",
+                u64::MAX
+            ))
+            .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_block() {
+        let (handle, mut requests) = control::channel();
+        tokio::spawn(async move {
+            while let Some(req) = requests.recv().await {
+                let _ = req
+                    .reply
+                    .send(Response::Error(format!("{:?}", req.command)));
+            }
+        });
+
+        let key = Key::random();
+        let history = EventLog::default();
+        let head = format!("POST /v1/block?key={key} HTTP/1.1\r\nAuthorization: Bearer t\r\n\r\n");
+        let (_, body) = route(&head, "t", &handle, &history).await;
+        assert!(body.contains(&format!("{:?}", Command::Block(key))));
+
+        let head =
+            format!("DELETE /v1/block?key={key} HTTP/1.1\r\nAuthorization: Bearer t\r\n\r\n");
+        let (_, body) = route(&head, "t", &handle, &history).await;
+        assert!(body.contains(&format!("{:?}", Command::Unblock(key))));
+
+        let head = "DELETE /v1/block HTTP/1.1\r\nAuthorization: Bearer t\r\n\r\n";
+        assert_eq!(route(head, "t", &handle, &history).await.0, 400);
+    }
+}
@@ -0,0 +1,340 @@
+//! Capture of a peer's wireguard handshake packets while punching, to tell
+//! which direction a NAT drops. Needs CAP_NET_RAW.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+const UDP: u8 = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// Wireguard message types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Message {
+    Initiation,
+    Response,
+    Cookie,
+    Data,
+}
+
+impl Message {
+    fn parse(payload: &[u8]) -> Option<Message> {
+        // type byte followed by three reserved zero bytes
+        let [kind, 0, 0, 0] = *payload.first_chunk::<4>()? else {
+            return None;
+        };
+
+        match kind {
+            1 => Some(Message::Initiation),
+            2 => Some(Message::Response),
+            3 => Some(Message::Cookie),
+            4 => Some(Message::Data),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Message::Initiation => "initiation",
+            Message::Response => "response",
+            Message::Cookie => "cookie",
+            Message::Data => "data",
+        })
+    }
+}
+
+/// Wireguard packet of an IP packet, if it's one between our `port` and
+/// one of `remotes`. Returns the remote address.
+pub fn parse(
+    packet: &[u8],
+    port: u16,
+    remotes: &[IpAddr],
+) -> Option<(Direction, SocketAddr, Message)> {
+    let (src, dst, udp) = match packet.first()? >> 4 {
+        4 => {
+            let len = (packet[0] & 0x0f) as usize * 4;
+            if packet.len() < len || packet[9] != UDP || len < 20 {
+                return None;
+            }
+
+            let src: [u8; 4] = packet[12..16].try_into().ok()?;
+            let dst: [u8; 4] = packet[16..20].try_into().ok()?;
+            (
+                IpAddr::from(Ipv4Addr::from(src)),
+                IpAddr::from(Ipv4Addr::from(dst)),
+                &packet[len..],
+            )
+        }
+        // extension headers are left out, wireguard doesn't cause them
+        6 if packet.len() >= 40 && packet[6] == UDP => {
+            let src: [u8; 16] = packet[8..24].try_into().ok()?;
+            let dst: [u8; 16] = packet[24..40].try_into().ok()?;
+            (
+                IpAddr::from(Ipv6Addr::from(src)),
+                IpAddr::from(Ipv6Addr::from(dst)),
+                &packet[40..],
+            )
+        }
+        _ => return None,
+    };
+
+    if udp.len() < 8 {
+        return None;
+    }
+    let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+    let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+    let message = Message::parse(&udp[8..])?;
+
+    if src_port == port && remotes.contains(&dst) {
+        Some((Direction::Out, SocketAddr::new(dst, dst_port), message))
+    } else if dst_port == port && remotes.contains(&src) {
+        Some((Direction::In, SocketAddr::new(src, src_port), message))
+    } else {
+        None
+    }
+}
+
+/// What was sent and received during a capture.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Summary {
+    counts: BTreeMap<(Direction, Message), u32>,
+    sent_to: BTreeSet<SocketAddr>,
+    received_from: BTreeSet<SocketAddr>,
+
+    /// Where our initiations went, the addresses we punch.
+    initiated_to: BTreeSet<SocketAddr>,
+}
+
+impl Summary {
+    pub fn record(&mut self, direction: Direction, remote: SocketAddr, message: Message) {
+        *self.counts.entry((direction, message)).or_default() += 1;
+
+        match direction {
+            Direction::Out => self.sent_to.insert(remote),
+            Direction::In => self.received_from.insert(remote),
+        };
+
+        if (direction, message) == (Direction::Out, Message::Initiation) {
+            self.initiated_to.insert(remote);
+        }
+    }
+
+    #[inline]
+    pub fn count(&self, direction: Direction, message: Message) -> u32 {
+        self.counts.get(&(direction, message)).copied().unwrap_or(0)
+    }
+
+    /// Likely cause of a failing handshake.
+    pub fn diagnosis(&self) -> Vec<String> {
+        let sent = self.count(Direction::Out, Message::Initiation);
+        let answered = self.count(Direction::Out, Message::Response);
+        let initiated = self.count(Direction::In, Message::Initiation);
+        let responded = self.count(Direction::In, Message::Response);
+        let data = self.count(Direction::In, Message::Data);
+
+        let mut findings = vec![
+            if responded > 0 || data > 0 {
+                "packets arrive from the peer, the handshake should complete"
+            } else if initiated > 0 && answered == 0 {
+                "the peer's initiations arrive but aren't answered, \
+                 check its public key in our config"
+            } else if initiated > 0 {
+                "we answer the peer's initiations but it never completes: \
+                 its NAT or firewall drops our packets (one-way)"
+            } else if sent > 0 {
+                "nothing arrives from the peer: our initiations are dropped \
+                 before reaching it and it doesn't reach us either"
+            } else {
+                "no handshake packets at all, wireguard didn't try the peer"
+            }
+            .to_string(),
+        ];
+
+        // the same host sending from another port than we punch
+        for from in &self.received_from {
+            if self.initiated_to.contains(from) {
+                continue;
+            }
+
+            if let Some(to) = self.initiated_to.iter().find(|to| to.ip() == from.ip()) {
+                findings.push(format!(
+                    "the peer sends from {from} but we send to {to}: \
+                     its NAT maps ports per destination (symmetric)"
+                ));
+            }
+        }
+
+        findings
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (direction, word, remotes) in [
+            (Direction::Out, "sent", &self.sent_to),
+            (Direction::In, "received", &self.received_from),
+        ] {
+            write!(f, "{word}")?;
+            for message in [
+                Message::Initiation,
+                Message::Response,
+                Message::Cookie,
+                Message::Data,
+            ] {
+                write!(f, " {}x{message}", self.count(direction, message))?;
+            }
+
+            let remotes: Vec<_> = remotes.iter().map(SocketAddr::to_string).collect();
+            match direction {
+                Direction::Out => write!(f, " to [{}]; ", remotes.join(", "))?,
+                Direction::In => write!(f, " from [{}]", remotes.join(", "))?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Captures packets between our `port` and `remotes` on every interface
+/// for `duration`. Blocks meanwhile.
+#[cfg(target_os = "linux")]
+pub fn capture(port: u16, remotes: &[IpAddr], duration: Duration) -> io::Result<Summary> {
+    use std::{os::fd::FromRawFd, time::Instant};
+
+    // cooked packets start at the IP header whatever the link layer is
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            (libc::ETH_P_ALL as u16).to_be() as i32,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { std::fs::File::from_raw_fd(fd) };
+
+    let timeout = libc::timeval {
+        tv_sec: 0,
+        tv_usec: 200_000,
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            (&timeout as *const libc::timeval).cast(),
+            size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut summary = Summary::default();
+    let mut buf = [0u8; 2048];
+    let until = Instant::now() + duration;
+
+    while Instant::now() < until {
+        let len = match io::Read::read(&mut &socket, &mut buf) {
+            Ok(len) => len,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        if let Some((direction, remote, message)) = parse(&buf[..len], port, remotes) {
+            summary.record(direction, remote, message);
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn capture(_port: u16, _remotes: &[IpAddr], _duration: Duration) -> io::Result<Summary> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use super::{Direction, Message, Summary, parse};
+
+    fn packet(src: [u8; 4], src_port: u16, dst: [u8; 4], dst_port: u16, kind: u8) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0];
+        packet.extend(src);
+        packet.extend(dst);
+        packet.extend(src_port.to_be_bytes());
+        packet.extend(dst_port.to_be_bytes());
+        packet.extend([0, 0, 0, 0, kind, 0, 0, 0]);
+        packet
+    }
+
+    #[test]
+    fn test_capture_summary() {
+        let peer: IpAddr = "203.0.113.7".parse().unwrap();
+        let remotes = [peer];
+        let ours = [192, 168, 1, 2];
+
+        let out = packet(ours, 51820, [203, 0, 113, 7], 51820, 1);
+        let to: SocketAddr = "203.0.113.7:51820".parse().unwrap();
+        assert_eq!(
+            parse(&out, 51820, &remotes),
+            Some((Direction::Out, to, Message::Initiation))
+        );
+
+        // another port, another host, not wireguard
+        assert_eq!(parse(&out, 51821, &remotes), None);
+        let other = packet(ours, 51820, [198, 51, 100, 1], 51820, 1);
+        assert_eq!(parse(&other, 51820, &remotes), None);
+        assert_eq!(
+            parse(
+                &packet(ours, 51820, [203, 0, 113, 7], 53, 9),
+                51820,
+                &remotes
+            ),
+            None
+        );
+
+        let mut summary = Summary::default();
+        summary.record(Direction::Out, to, Message::Initiation);
+        summary.record(Direction::Out, to, Message::Initiation);
+        assert!(summary.diagnosis()[0].starts_with("nothing arrives"));
+
+        let from: SocketAddr = "203.0.113.7:40123".parse().unwrap();
+        let incoming = packet([203, 0, 113, 7], 40123, ours, 51820, 1);
+        let (direction, remote, message) = parse(&incoming, 51820, &remotes).unwrap();
+        summary.record(direction, remote, message);
+        summary.record(Direction::Out, from, Message::Response);
+
+        let diagnosis = summary.diagnosis();
+        assert!(diagnosis[0].contains("one-way"));
+        assert!(diagnosis[1].contains("symmetric"));
+        assert_eq!(
+            summary.to_string(),
+            "sent 2xinitiation 1xresponse 0xcookie 0xdata \
+             to [203.0.113.7:40123, 203.0.113.7:51820]; \
+             received 1xinitiation 0xresponse 0xcookie 0xdata from [203.0.113.7:40123]"
+        );
+    }
+}
@@ -0,0 +1,63 @@
+//! Time source of the daemon. Monotonic time is tokio's, so with paused
+//! time in tests timers, expirations and backoff all advance together. A
+//! manual clock makes the wall time put into announcements follow it too.
+
+use tokio::time::Instant;
+
+use crate::signaling::skew;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Clock {
+    /// Tokio instant and unix time the manual clock started at.
+    manual: Option<(Instant, u64)>,
+}
+
+impl Clock {
+    #[inline]
+    pub fn system() -> Self {
+        Self::default()
+    }
+
+    /// Wall time starting at `unix_ms` and advancing with tokio time.
+    pub fn manual(unix_ms: u64) -> Self {
+        Self {
+            manual: Some((Instant::now(), unix_ms)),
+        }
+    }
+
+    #[inline]
+    pub fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Current unix time in milliseconds.
+    pub fn unix_ms(&self) -> u64 {
+        match self.manual {
+            Some((start, unix_ms)) => unix_ms + start.elapsed().as_millis() as u64,
+            None => skew::unix_ms(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::runner::RateLimiter;
+
+    use super::Clock;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_manual_clock_follows_paused_time() {
+        let clock = Clock::manual(1_700_000_000_000);
+        let mut limiter = RateLimiter::new(1, Duration::from_secs(2));
+
+        assert!(limiter.try_acquire(clock.now()));
+        assert!(!limiter.try_acquire(clock.now()));
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        assert_eq!(clock.unix_ms(), 1_700_000_002_000);
+        assert!(limiter.try_acquire(clock.now()));
+    }
+}
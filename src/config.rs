@@ -0,0 +1,497 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    android,
+    api::ApiConfig,
+    error::Error,
+    groups::GroupConfig,
+    health::RouteCheck,
+    json::Value,
+    limits::Limits,
+    provision::ProvisionConfig,
+    retry::RetryPolicy,
+    route::export::RouteExport,
+    secret::{KeySource, Reveal},
+    systemd,
+    transport::TransportConfig,
+    uplink::UplinkConfig,
+    wg::Key,
+};
+
+/// systemd credential holding the api token when `[api]` has none.
+pub const API_TOKEN_CREDENTIAL: &str = "wg-disco-api-token";
+
+/// wg-disco settings, `/etc/wg-disco/<iface>.toml`. Everything is optional,
+/// a missing file means defaults.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub retry: RetryConfig,
+
+    /// `[irc]` signaling networks, flags override them.
+    pub irc: IrcSettings,
+
+    /// `[stun]` servers discovery asks, flags override them.
+    pub stun: StunSettings,
+
+    /// `[[transport]]` helpers used when direct UDP to a peer is blocked.
+    pub transport: Vec<TransportConfig>,
+
+    /// `[relay]` server of last resort, flags override it.
+    pub relay: RelaySettings,
+
+    /// Local HTTP API, disabled without the section.
+    pub api: Option<ApiConfig>,
+
+    /// `[group.<name>]` policies for route acceptance, relaying and
+    /// targeted announcements.
+    pub group: BTreeMap<String, GroupConfig>,
+
+    /// `[[uplink]]` of a multi-homed host, the first one is used.
+    pub uplink: Vec<UplinkConfig>,
+
+    /// `[[route_check]]` health checks of advertised routes.
+    pub route_check: Vec<RouteCheck>,
+
+    /// `[route_export]` hands routes learned from the mesh to BIRD or FRR
+    /// instead of installing them.
+    pub route_export: Option<RouteExport>,
+
+    /// Key of the values given as `enc:...`, see `wg-disco encrypt`.
+    /// Defaults to the `wg-disco` systemd credential when started with it,
+    /// `/etc/wg-disco/secret.key` otherwise.
+    pub secret_key: Option<KeySource>,
+
+    /// Peers whose announcements are always ignored, e.g. a stolen device.
+    /// More are blocked at runtime through the control API.
+    pub blocklist: Vec<Key>,
+
+    /// `[alias]` names of peers as `name = "<key>"`, their label in the
+    /// metrics of the HTTP API.
+    pub alias: BTreeMap<String, Key>,
+
+    /// Keys of the nodes trusted to revoke peers mesh-wide, see
+    /// [`Revocation`](crate::signaling::revocation::Revocation).
+    pub revocation_signers: Vec<Key>,
+
+    /// Smaller buffers, shorter event history and slower polling for
+    /// routers with 64-128 MB of RAM, see [`Limits::LOW_MEMORY`].
+    pub low_memory: bool,
+
+    /// Treat the connection as metered, or not, instead of asking
+    /// NetworkManager. Periodic re-announcements and pushes stop on one.
+    pub metered: Option<bool>,
+
+    /// `[provision]` client configs handed out to the listed peers when
+    /// they ask, makes this node their coordinator.
+    pub provision: Option<ProvisionConfig>,
+
+    /// Runtime worker threads, `1` runs everything on a single thread.
+    /// Defaults to one per core, a single one with `low_memory`.
+    pub threads: Option<usize>,
+}
+
+impl Config {
+    #[inline]
+    pub fn limits(&self) -> Limits {
+        Limits::new(self.low_memory)
+    }
+
+    /// Worker threads of the runtime.
+    pub fn threads(&self) -> Option<usize> {
+        match self.threads {
+            Some(threads) => Some(threads.max(1)),
+            None if self.low_memory => Some(1),
+            None => None,
+        }
+    }
+
+    pub fn path(iface: &str) -> String {
+        android::path(&format!("/etc/wg-disco/{iface}.toml"))
+            .display()
+            .to_string()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        let mut config: Config = match fs::read_to_string(path) {
+            Ok(data) => toml::from_str(&data)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(Error::ReadConfig(path.display().to_string(), err)),
+        };
+
+        config.reveal_secrets()?;
+        Ok(config)
+    }
+
+    /// Decrypts the secrets given encrypted, the key is only needed when
+    /// there are some. Secrets left out are taken from systemd credentials.
+    fn reveal_secrets(&mut self) -> Result<(), Error> {
+        let source = self.secret_key.clone().unwrap_or_default();
+        let mut reveal = Reveal::new(&source);
+
+        if let Some(api) = &mut self.api {
+            if api.token.is_empty() {
+                api.token = match systemd::read_credential(API_TOKEN_CREDENTIAL) {
+                    Some(token) => token?,
+                    None => return Err(Error::NoApiToken),
+                };
+            }
+
+            reveal.reveal(&mut api.token)?;
+        }
+
+        if let Some(psk) = &mut self.irc.psk {
+            reveal.reveal(psk)?;
+        }
+
+        if let Some(password) = &mut self.irc.nickserv_password {
+            reveal.reveal(password)?;
+        }
+
+        Ok(())
+    }
+
+    /// Effective settings with defaults filled in, for tools generating
+    /// configs to compare against. Secrets are left out.
+    pub fn to_json(&self) -> Value {
+        let retry = |policy: &RetryPolicy| {
+            Value::object([
+                ("initial_ms", policy.initial_ms.into()),
+                ("multiplier", policy.multiplier.into()),
+                ("max_ms", policy.max_ms.into()),
+                ("jitter", policy.jitter.into()),
+                ("attempts", policy.attempts.into()),
+            ])
+        };
+
+        Value::object([
+            (
+                "retry",
+                Value::object([
+                    ("stun", retry(&self.retry.stun)),
+                    ("signaling", retry(&self.retry.signaling)),
+                    ("announce", retry(&self.retry.announce)),
+                    ("wg", retry(&self.retry.wg)),
+                    ("punch", retry(&self.retry.punch)),
+                ]),
+            ),
+            (
+                "transport",
+                Value::Array(
+                    self.transport
+                        .iter()
+                        .map(|transport| {
+                            Value::object([
+                                ("name", transport.name.as_str().into()),
+                                ("client", transport.client.as_str().into()),
+                                ("listen", transport.listen.clone().into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "api",
+                self.api.as_ref().map_or(Value::Null, |api| {
+                    Value::object([("listen", api.listen.to_string().into())])
+                }),
+            ),
+            (
+                "group",
+                Value::object(self.group.iter().map(|(name, group)| {
+                    let peers: Vec<String> = group.peers.iter().map(Key::to_string).collect();
+
+                    let value = Value::object([
+                        ("peers", peers.into()),
+                        ("accept_routes", group.accept_routes.into()),
+                        ("relay", group.relay.into()),
+                        ("announce", group.announce.into()),
+                    ]);
+                    (name.as_str(), value)
+                })),
+            ),
+            (
+                "uplink",
+                Value::Array(
+                    self.uplink
+                        .iter()
+                        .map(|uplink| {
+                            Value::object([
+                                ("name", uplink.name.as_str().into()),
+                                ("device", uplink.device.as_str().into()),
+                                ("fwmark", uplink.fwmark.into()),
+                                ("table", uplink.table().into()),
+                                ("gateway", uplink.gateway.map(|ip| ip.to_string()).into()),
+                                ("advertise", uplink.advertise.into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "route_check",
+                Value::Array(
+                    self.route_check
+                        .iter()
+                        .map(|check| {
+                            Value::object([
+                                ("route", check.route.to_string().into()),
+                                ("ping", check.ping.map(|ip| ip.to_string()).into()),
+                                ("device", check.device.clone().into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "route_export",
+                self.route_export.as_ref().map_or(Value::Null, |export| {
+                    Value::object([
+                        (
+                            "daemon",
+                            format!("{:?}", export.daemon).to_lowercase().into(),
+                        ),
+                        ("socket", export.socket().display().to_string().into()),
+                        (
+                            "file",
+                            export
+                                .file
+                                .as_ref()
+                                .map(|file| file.display().to_string())
+                                .into(),
+                        ),
+                    ])
+                }),
+            ),
+            (
+                "secret_key",
+                self.secret_key.as_ref().map(KeySource::to_string).into(),
+            ),
+            (
+                "blocklist",
+                Value::Array(
+                    self.blocklist
+                        .iter()
+                        .map(|key| key.to_string().into())
+                        .collect(),
+                ),
+            ),
+            (
+                "alias",
+                Value::object(
+                    self.alias
+                        .iter()
+                        .map(|(name, key)| (name.as_str(), key.to_string().into())),
+                ),
+            ),
+            (
+                "revocation_signers",
+                Value::Array(
+                    self.revocation_signers
+                        .iter()
+                        .map(|key| key.to_string().into())
+                        .collect(),
+                ),
+            ),
+            (
+                "irc",
+                Value::object([
+                    ("servers", self.irc.servers.clone().into()),
+                    ("channel", self.irc.channel.clone().into()),
+                    ("tls", self.irc.tls.into()),
+                    ("encrypt", self.irc.psk.is_some().into()),
+                    ("nickserv", self.irc.nickserv_password.is_some().into()),
+                    ("regain", self.irc.regain.into()),
+                ]),
+            ),
+            (
+                "stun",
+                Value::object([("servers", self.stun.servers.clone().into())]),
+            ),
+            (
+                "relay",
+                Value::object([("server", self.relay.server.clone().into())]),
+            ),
+            (
+                "provision",
+                self.provision.as_ref().map_or(Value::Null, |provision| {
+                    Value::object([
+                        ("address", provision.address.as_str().into()),
+                        ("routes", provision.routes.clone().into()),
+                        ("dns", provision.dns.clone().into()),
+                        (
+                            "client",
+                            Value::object(provision.client.iter().map(|(name, client)| {
+                                let value = Value::object([
+                                    ("key", client.key.to_string().into()),
+                                    ("n", client.n.into()),
+                                ]);
+                                (name.as_str(), value)
+                            })),
+                        ),
+                    ])
+                }),
+            ),
+            ("metered", self.metered.into()),
+            ("low_memory", self.low_memory.into()),
+            ("threads", self.threads().into()),
+        ])
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IrcSettings {
+    /// Networks as host:port, in order of preference.
+    pub servers: Vec<String>,
+    pub channel: Option<String>,
+
+    /// Connect to every network over TLS, ports default to 6697 then.
+    pub tls: bool,
+
+    /// Shared by every peer of the mesh, enables end-to-end encryption of
+    /// announcements. See [`Seal`](crate::signaling::seal::Seal).
+    pub psk: Option<String>,
+
+    /// NickServ password of our nickname, ghosts of earlier sessions
+    /// holding it are killed with it.
+    pub nickserv_password: Option<String>,
+
+    /// Recover the nickname with NickServ REGAIN instead of GHOST.
+    pub regain: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StunSettings {
+    /// Servers as host:port, in order of preference. The next one is asked
+    /// when one doesn't answer.
+    pub servers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelaySettings {
+    /// Our home relay as host:port, a `wg-disco relay`. Peers no punching
+    /// gets through to are relayed.
+    pub server: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// STUN queries.
+    pub stun: RetryPolicy,
+
+    /// Connecting to signaling backends.
+    pub signaling: RetryPolicy,
+
+    /// Resending failed announcements.
+    pub announce: RetryPolicy,
+
+    /// Wireguard operations failing transiently, e.g. resolving a domain
+    /// endpoint before the network is up.
+    pub wg: RetryPolicy,
+
+    /// Cycling through the addresses of a peer until a handshake happens.
+    pub punch: RetryPolicy,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            stun: RetryPolicy {
+                initial_ms: 500,
+                max_ms: 5000,
+                attempts: 4,
+                ..Default::default()
+            },
+            signaling: RetryPolicy {
+                attempts: 5,
+                max_ms: 60_000,
+                ..Default::default()
+            },
+            announce: RetryPolicy::default(),
+            wg: RetryPolicy::none(),
+            punch: RetryPolicy {
+                initial_ms: 2000,
+                max_ms: 30_000,
+                attempts: 6,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::limits::Limits;
+
+    use super::Config;
+
+    #[test]
+    fn test_partial_retry_config() {
+        let config: Config = toml::from_str(
+            "[retry.stun]
+attempts = 10
+initial_ms = 50
+",
+        )
+        .unwrap();
+
+        assert_eq!(config.retry.stun.attempts, 10);
+        assert_eq!(config.retry.stun.initial_ms, 50);
+        assert_eq!(config.retry.stun.multiplier, 2.0);
+        assert_eq!(config.retry.wg.attempts, 1);
+
+        assert!(toml::from_str::<Config>("[retry.stun]\nattempt = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_json_leaves_out_secrets() {
+        let config: Config = toml::from_str(
+            r#"[api]
+token = "hunter2"
+"#,
+        )
+        .unwrap();
+
+        let json = config.to_json().to_string();
+        assert!(json.contains(r#""api":{"listen":"127.0.0.1:9191"}"#));
+        assert!(!json.contains("hunter2"));
+    }
+
+    #[test]
+    fn test_irc() {
+        let config: Config = toml::from_str(
+            r#"[irc]
+servers = ["irc.oftc.net:6697"]
+tls = true
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.irc.servers, ["irc.oftc.net:6697"]);
+        assert_eq!(config.irc.channel, None);
+        assert!(config.irc.tls);
+        assert!(toml::from_str::<Config>("[irc]\nserver = \"irc.oftc.net\"\n").is_err());
+    }
+
+    #[test]
+    fn test_low_memory() {
+        let config: Config = toml::from_str("low_memory = true\n").unwrap();
+        assert_eq!(config.limits(), Limits::LOW_MEMORY);
+        assert_eq!(config.threads(), Some(1));
+
+        let config: Config = toml::from_str("low_memory = true\nthreads = 2\n").unwrap();
+        assert_eq!(config.threads(), Some(2));
+
+        let config = Config::default();
+        assert_eq!(config.limits(), Limits::DEFAULT);
+        assert_eq!(config.threads(), None);
+    }
+}
@@ -0,0 +1,389 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::{
+    discover::nat::NatType,
+    explain::Explanation,
+    json::Value,
+    signaling::{Feature, Metadata},
+    wg::{Cidr, Endpoint, Key, executor::Timing},
+};
+
+/// Requests queued to the runner before callers get back pressure.
+const QUEUE_LEN: usize = 16;
+
+/// Events kept for slow subscribers before they start missing some.
+const EVENTS_LEN: usize = 64;
+
+/// What frontends (HTTP API, ...) can ask the runner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Status,
+    Peers,
+    Routes,
+
+    /// Announce ourselves to the channel, folded with other announcements
+    /// triggered within a moment.
+    Announce,
+
+    /// Use this endpoint for the peer and ignore its announcements until
+    /// `ttl` passes or the pin is cleared.
+    Pin {
+        key: Key,
+        endpoint: SocketAddr,
+        ttl: Duration,
+    },
+
+    /// Return the peer to its announced endpoint.
+    Unpin(Key),
+
+    /// Ask the peer to handshake with our public endpoint and report if it
+    /// got an answer, see [`Event::ProbeAnswered`].
+    Probe(Key),
+
+    /// Ignore the peer's announcements and withdraw its routes until it is
+    /// unblocked.
+    Block(Key),
+
+    Unblock(Key),
+
+    /// Stop applying endpoints, routes and revocations while still
+    /// following the mesh, or apply what changed meanwhile and resume.
+    Freeze {
+        enable: bool,
+    },
+
+    /// Sign a revocation of the peer and distribute it, for revocation
+    /// signers only.
+    Revoke(Key),
+
+    /// What is known about the peer and why it is down.
+    Explain(Key),
+
+    /// Log debug messages about the peer, or stop it.
+    Debug {
+        key: Key,
+        enable: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub iface: String,
+    pub key: Key,
+    pub endpoint: Option<SocketAddr>,
+    pub listen_port: u16,
+
+    /// How our NAT maps and filters, once classified.
+    pub nat_type: Option<NatType>,
+    pub server: bool,
+    pub kill_switch: bool,
+    pub peers: usize,
+
+    /// Changes are logged but not applied, see [`Command::Freeze`].
+    pub frozen: bool,
+
+    /// On a metered connection, see [`metered`](crate::metered).
+    pub metered: bool,
+
+    /// Routes announced to peers.
+    pub advertise_routes: Vec<Cidr>,
+
+    /// Lowest protocol version of the live peers, `0` for peers predating
+    /// versioning, `None` without any.
+    pub min_protocol: Option<u16>,
+
+    /// Enabled features some live peers can't follow.
+    pub unsupported: Vec<Unsupported>,
+
+    /// What the wireguard operations on the interface took, by name.
+    pub wg_operations: Vec<(&'static str, Timing)>,
+}
+
+/// An enabled announcement feature and the live peers too old for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported {
+    pub feature: Feature,
+    pub peers: Vec<Key>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStatus {
+    pub key: Key,
+    pub endpoint: Option<Endpoint>,
+
+    /// Unix time, seconds.
+    pub latest_handshake: Option<u32>,
+
+    /// Received and sent bytes.
+    pub transfer: Option<(u64, u64)>,
+    pub routes: Vec<Cidr>,
+    pub nat: bool,
+
+    /// How the peer's NAT maps and filters, as it announced.
+    pub nat_type: Option<NatType>,
+    pub server: bool,
+
+    /// Endpoints the peer announced, best first.
+    pub candidates: Vec<SocketAddr>,
+
+    /// The peer is present on the signaling channel.
+    pub online: bool,
+
+    /// Name of the transport helper the peer is reached through.
+    pub transport: Option<String>,
+
+    /// Seconds left of an operator pinned endpoint.
+    pub pinned_for: Option<u64>,
+
+    /// Capacity hint the peer announced, Mbit/s.
+    pub bandwidth: Option<u32>,
+
+    /// Hostname, version and platform the peer announced.
+    pub meta: Option<Metadata>,
+
+    /// Our default route goes via this peer.
+    pub exit: bool,
+
+    /// Debug messages about the peer are logged.
+    pub debug: bool,
+
+    /// Announcements of the peer are ignored.
+    pub blocked: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteStatus {
+    pub cidr: Cidr,
+    pub via: Key,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Status(Status),
+    Peers(Vec<PeerStatus>),
+    Routes(Vec<RouteStatus>),
+    Explanation(Box<Explanation>),
+    Ok,
+    Error(String),
+}
+
+impl Response {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Response::Status(status) => Value::object([
+                ("iface", Value::from(status.iface.as_str())),
+                ("key", Value::from(status.key.to_string())),
+                (
+                    "endpoint",
+                    Value::from(status.endpoint.map(|e| e.to_string())),
+                ),
+                ("listen_port", Value::from(status.listen_port)),
+                (
+                    "nat_type",
+                    Value::from(status.nat_type.map(|t| t.to_string())),
+                ),
+                ("server", Value::from(status.server)),
+                ("kill_switch", Value::from(status.kill_switch)),
+                ("peers", Value::from(status.peers)),
+                ("frozen", Value::from(status.frozen)),
+                ("metered", Value::from(status.metered)),
+                (
+                    "advertise_routes",
+                    Value::Array(
+                        status
+                            .advertise_routes
+                            .iter()
+                            .map(|cidr| Value::from(cidr.to_string()))
+                            .collect(),
+                    ),
+                ),
+                ("min_protocol", Value::from(status.min_protocol)),
+                (
+                    "unsupported",
+                    Value::Array(
+                        status
+                            .unsupported
+                            .iter()
+                            .map(|unsupported| {
+                                Value::object([
+                                    ("feature", Value::from(unsupported.feature.name())),
+                                    ("protocol", Value::from(unsupported.feature.protocol())),
+                                    (
+                                        "peers",
+                                        Value::from(
+                                            unsupported
+                                                .peers
+                                                .iter()
+                                                .map(Key::to_string)
+                                                .collect::<Vec<_>>(),
+                                        ),
+                                    ),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+            ]),
+
+            Response::Peers(peers) => Value::Array(
+                peers
+                    .iter()
+                    .map(|peer| {
+                        Value::object([
+                            ("key", Value::from(peer.key.to_string())),
+                            (
+                                "endpoint",
+                                Value::from(peer.endpoint.as_ref().map(|e| e.to_string())),
+                            ),
+                            ("latest_handshake", Value::from(peer.latest_handshake)),
+                            ("rx", Value::from(peer.transfer.map(|(rx, _)| rx))),
+                            ("tx", Value::from(peer.transfer.map(|(_, tx)| tx))),
+                            (
+                                "routes",
+                                Value::from(
+                                    peer.routes
+                                        .iter()
+                                        .map(|c| c.to_string())
+                                        .collect::<Vec<_>>(),
+                                ),
+                            ),
+                            ("nat", Value::from(peer.nat)),
+                            (
+                                "nat_type",
+                                Value::from(peer.nat_type.map(|t| t.to_string())),
+                            ),
+                            ("server", Value::from(peer.server)),
+                            (
+                                "candidates",
+                                Value::from(
+                                    peer.candidates
+                                        .iter()
+                                        .map(|c| c.to_string())
+                                        .collect::<Vec<_>>(),
+                                ),
+                            ),
+                            ("online", Value::from(peer.online)),
+                            ("transport", Value::from(peer.transport.clone())),
+                            ("pinned_for", Value::from(peer.pinned_for)),
+                            ("bandwidth", Value::from(peer.bandwidth)),
+                            (
+                                "hostname",
+                                Value::from(peer.meta.as_ref().map(|m| m.hostname.clone())),
+                            ),
+                            (
+                                "version",
+                                Value::from(peer.meta.as_ref().map(|m| m.version.clone())),
+                            ),
+                            (
+                                "platform",
+                                Value::from(peer.meta.as_ref().map(|m| m.platform.clone())),
+                            ),
+                            ("exit", Value::from(peer.exit)),
+                            ("debug", Value::from(peer.debug)),
+                            ("blocked", Value::from(peer.blocked)),
+                        ])
+                    })
+                    .collect(),
+            ),
+
+            Response::Routes(routes) => Value::Array(
+                routes
+                    .iter()
+                    .map(|route| {
+                        Value::object([
+                            ("cidr", Value::from(route.cidr.to_string())),
+                            ("via", Value::from(route.via.to_string())),
+                        ])
+                    })
+                    .collect(),
+            ),
+
+            Response::Explanation(explanation) => explanation.to_json(),
+            Response::Ok => Value::object([("ok", Value::from(true))]),
+            Response::Error(err) => Value::object([("error", Value::from(err.as_str()))]),
+        }
+    }
+}
+
+/// Pushed by the runner to every subscribed frontend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// First handshake, or first one after the peer was down.
+    PeerUp(Key),
+
+    /// No handshake for a while.
+    PeerDown(Key),
+
+    /// Two live nodes announce the key, e.g. started from a copied image.
+    Cloned(Key),
+
+    /// The peer reached our public endpoint when asked to probe it.
+    ProbeAnswered(Key),
+
+    /// The peer got no answer from our public endpoint, our NAT doesn't let
+    /// peers in.
+    ProbeSilent(Key),
+
+    /// A signer revoked the peer, it was removed from the interface.
+    Revoked(Key),
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub command: Command,
+    pub reply: oneshot::Sender<Response>,
+}
+
+/// Cloneable side handed to frontends.
+#[derive(Debug, Clone)]
+pub struct Handle {
+    tx: mpsc::Sender<Request>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Handle {
+    /// `None` when the runner is gone.
+    pub async fn call(&self, command: Command) -> Option<Response> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(Request { command, reply }).await.ok()?;
+
+        rx.await.ok()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+}
+
+/// Runner side.
+#[derive(Debug)]
+pub struct Receiver {
+    rx: mpsc::Receiver<Request>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Receiver {
+    pub async fn recv(&mut self) -> Option<Request> {
+        self.rx.recv().await
+    }
+
+    /// Nobody listening is fine.
+    pub fn emit(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+}
+
+pub fn channel() -> (Handle, Receiver) {
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    let (events, _) = broadcast::channel(EVENTS_LEN);
+
+    (
+        Handle {
+            tx,
+            events: events.clone(),
+        },
+        Receiver { rx, events },
+    )
+}
@@ -0,0 +1,617 @@
+use std::cmp::Ordering;
+
+use aes::{
+    Aes256,
+    cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray},
+};
+use hashes::sha2::{sha256, sha512};
+
+const BLOCK_LEN: usize = 64;
+
+/// HMAC-SHA256 (RFC 2104).
+pub fn hmac_sha256(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; BLOCK_LEN];
+
+    if key.len() > BLOCK_LEN {
+        block[..32].copy_from_slice(&sha256::hash(key).into_bytes());
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Vec::with_capacity(BLOCK_LEN + msg.len());
+    inner.extend(block.iter().map(|b| b ^ 0x36));
+    inner.extend_from_slice(msg);
+
+    let mut outer = [0u8; BLOCK_LEN + 32];
+    for (o, b) in outer.iter_mut().zip(block) {
+        *o = b ^ 0x5c;
+    }
+    outer[BLOCK_LEN..].copy_from_slice(&sha256::hash(&inner).into_bytes());
+
+    sha256::hash(&outer).into_bytes()
+}
+
+/// AES-256-CTR keystream applied to `data`, the nonce is the first counter
+/// block.
+pub fn aes256_ctr(key: &[u8; 32], nonce: &[u8; 16], data: &mut [u8]) {
+    let cipher = Aes256::new(GenericArray::from_slice(key));
+    let mut counter = u128::from_be_bytes(*nonce);
+
+    for chunk in data.chunks_mut(16) {
+        let mut block = GenericArray::from(counter.to_be_bytes());
+        cipher.encrypt_block(&mut block);
+
+        for (byte, key) in chunk.iter_mut().zip(block) {
+            *byte ^= key;
+        }
+        counter = counter.wrapping_add(1);
+    }
+}
+
+/// Whether `a` and `b` are equal, in time independent of where they differ.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Field element mod 2^255 - 19 as sixteen 16-bit limbs, the TweetNaCl way.
+type Gf = [i64; 16];
+
+const A24: Gf = [0xdb41, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+const GF0: Gf = [0; 16];
+const GF1: Gf = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Edwards curve constant d and 2d.
+const D: Gf = [
+    0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070, 0xe898, 0x7779, 0x4079, 0x8cc7,
+    0xfe73, 0x2b6f, 0x6cee, 0x5203,
+];
+const D2: Gf = [
+    0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0, 0xd130, 0xeef3, 0x80f2, 0x198e,
+    0xfce7, 0x56df, 0xd9dc, 0x2406,
+];
+
+/// Base point of the Edwards curve.
+const X: Gf = [
+    0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c, 0xdc5c, 0xfdd6, 0xe231, 0xc0a4,
+    0x53fe, 0xcd6e, 0x36d3, 0x2169,
+];
+const Y: Gf = [
+    0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+    0x6666, 0x6666, 0x6666, 0x6666,
+];
+
+/// Square root of -1.
+const I: Gf = [
+    0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43, 0xd7a7, 0x3dfb, 0x0099, 0x2b4d,
+    0xdf0b, 0x4fc1, 0x2480, 0x2b83,
+];
+
+/// Order of the base point, little endian.
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+/// Extended coordinates of an Edwards point.
+type Point = [Gf; 4];
+
+/// X25519 (RFC 7748), constant time.
+pub fn x25519(scalar: &[u8; 32], point: &[u8; 32]) -> [u8; 32] {
+    let mut z = *scalar;
+    z[31] = (z[31] & 127) | 64;
+    z[0] &= 248;
+
+    let x = unpack(point);
+    let mut a: Gf = [0; 16];
+    let mut b = x;
+    let mut c: Gf = [0; 16];
+    let mut d: Gf = [0; 16];
+    a[0] = 1;
+    d[0] = 1;
+
+    for i in (0..255).rev() {
+        let r = ((z[i >> 3] >> (i & 7)) & 1) as i64;
+        sel(&mut a, &mut b, r);
+        sel(&mut c, &mut d, r);
+
+        let mut e = add(&a, &c);
+        a = sub(&a, &c);
+        c = add(&b, &d);
+        b = sub(&b, &d);
+        d = mul(&e, &e);
+        let f = mul(&a, &a);
+        a = mul(&c, &a);
+        c = mul(&b, &e);
+        e = add(&a, &c);
+        a = sub(&a, &c);
+        b = mul(&a, &a);
+        c = sub(&d, &f);
+        a = mul(&c, &A24);
+        a = add(&a, &d);
+        c = mul(&c, &a);
+        a = mul(&d, &f);
+        d = mul(&b, &x);
+        b = mul(&e, &e);
+
+        sel(&mut a, &mut b, r);
+        sel(&mut c, &mut d, r);
+    }
+
+    pack(&mul(&a, &inv(&c)))
+}
+
+/// Public key of a X25519 (wireguard) private key.
+pub fn x25519_base(scalar: &[u8; 32]) -> [u8; 32] {
+    let mut base = [0u8; 32];
+    base[0] = 9;
+
+    x25519(scalar, &base)
+}
+
+/// XEdDSA signature of `msg` made with a X25519 (wireguard) private key,
+/// `random` has to be fresh random bytes. Checked with [`xeddsa_verify`]
+/// against the X25519 public key.
+pub fn xeddsa_sign(private: &[u8; 32], msg: &[u8], random: &[u8; 64]) -> [u8; 64] {
+    let mut k = *private;
+    k[31] = (k[31] & 127) | 64;
+    k[0] &= 248;
+
+    let mut public = pack_point(&scalarbase(&k));
+
+    // the public key is the point with the sign bit cleared, negate the
+    // private key if that isn't ours
+    let mut a = reduce(&k);
+    if public[31] & 0x80 != 0 {
+        a = negate(&a);
+        public[31] &= 0x7f;
+    }
+
+    let mut nonce = vec![0xfe];
+    nonce.extend([0xff; 31]);
+    nonce.extend(a);
+    nonce.extend(msg);
+    nonce.extend(random);
+    let r = reduce(&sha512::hash(&nonce).into_bytes());
+
+    let mut sig = [0u8; 64];
+    sig[..32].copy_from_slice(&pack_point(&scalarbase(&r)));
+
+    let h = reduce(&challenge(&sig[..32], &public, msg));
+    let mut x = [0i64; 64];
+    for (x, r) in x.iter_mut().zip(r) {
+        *x = r as i64;
+    }
+    for i in 0..32 {
+        for j in 0..32 {
+            x[i + j] += h[i] as i64 * a[j] as i64;
+        }
+    }
+    sig[32..].copy_from_slice(&mod_l(&mut x));
+
+    sig
+}
+
+/// Checks a [`xeddsa_sign`] signature against the signer's X25519 public
+/// key.
+pub fn xeddsa_verify(public: &[u8; 32], msg: &[u8], sig: &[u8; 64]) -> bool {
+    ed25519_verify(&xeddsa_public(public), msg, sig)
+}
+
+/// Ed25519 public key [`xeddsa_sign`] signatures of the X25519 public key
+/// are valid for, for protocols that only know Ed25519.
+pub fn xeddsa_public(public: &[u8; 32]) -> [u8; 32] {
+    // Montgomery u to Edwards y = (u - 1) / (u + 1), sign bit cleared
+    let u = unpack(public);
+    let y = mul(&sub(&u, &GF1), &inv(&add(&u, &GF1)));
+    let mut edwards = pack(&y);
+    edwards[31] &= 0x7f;
+
+    edwards
+}
+
+/// Ed25519 (RFC 8032) verification with an Edwards public key.
+fn ed25519_verify(public: &[u8; 32], msg: &[u8], sig: &[u8; 64]) -> bool {
+    // s has to be reduced, s + L is a malleated copy of the same signature
+    if !is_reduced(&sig[32..]) {
+        return false;
+    }
+
+    let Some(a) = unpack_neg(public) else {
+        return false;
+    };
+
+    let h = reduce(&challenge(&sig[..32], public, msg));
+    let s: [u8; 32] = sig[32..].try_into().unwrap();
+
+    let mut p = scalarmult(a, &h);
+    point_add(&mut p, &scalarbase(&s));
+
+    pack_point(&p)[..] == sig[..32]
+}
+
+/// Whether little endian scalar `s` is below [`L`].
+fn is_reduced(s: &[u8]) -> bool {
+    for (&byte, &l) in s.iter().zip(&L).rev() {
+        match (byte as i64).cmp(&l) {
+            Ordering::Less => return true,
+            Ordering::Greater => return false,
+            Ordering::Equal => {}
+        }
+    }
+
+    false
+}
+
+fn challenge(r: &[u8], public: &[u8; 32], msg: &[u8]) -> [u8; 64] {
+    let mut data = Vec::with_capacity(64 + msg.len());
+    data.extend_from_slice(r);
+    data.extend_from_slice(public);
+    data.extend_from_slice(msg);
+
+    sha512::hash(&data).into_bytes()
+}
+
+fn point_add(p: &mut Point, q: &Point) {
+    let a = mul(&sub(&p[1], &p[0]), &sub(&q[1], &q[0]));
+    let b = mul(&add(&p[0], &p[1]), &add(&q[0], &q[1]));
+    let c = mul(&mul(&p[3], &q[3]), &D2);
+    let d = mul(&p[2], &q[2]);
+    let d = add(&d, &d);
+
+    let e = sub(&b, &a);
+    let f = sub(&d, &c);
+    let g = add(&d, &c);
+    let h = add(&b, &a);
+
+    *p = [mul(&e, &f), mul(&h, &g), mul(&g, &f), mul(&e, &h)];
+}
+
+fn point_sel(p: &mut Point, q: &mut Point, b: i64) {
+    for (p, q) in p.iter_mut().zip(q.iter_mut()) {
+        sel(p, q, b);
+    }
+}
+
+fn pack_point(p: &Point) -> [u8; 32] {
+    let zi = inv(&p[2]);
+    let x = mul(&p[0], &zi);
+    let y = mul(&p[1], &zi);
+
+    let mut o = pack(&y);
+    o[31] ^= (pack(&x)[0] & 1) << 7;
+
+    o
+}
+
+/// Negated point of an Edwards public key, `None` when it isn't on the
+/// curve.
+fn unpack_neg(n: &[u8; 32]) -> Option<Point> {
+    let y = unpack(n);
+    let num = mul(&y, &y);
+    let den = mul(&num, &D);
+    let num = sub(&num, &GF1);
+    let den = add(&GF1, &den);
+
+    let den2 = mul(&den, &den);
+    let den4 = mul(&den2, &den2);
+    let den6 = mul(&den4, &den2);
+    let t = mul(&mul(&den6, &num), &den);
+    let t = mul(&mul(&pow2523(&t), &num), &den);
+    let mut x = mul(&mul(&t, &den), &den);
+
+    let check = |x: &Gf| pack(&mul(&mul(x, x), &den)) == pack(&num);
+    if !check(&x) {
+        x = mul(&x, &I);
+    }
+    if !check(&x) {
+        return None;
+    }
+
+    if (pack(&x)[0] & 1) as u8 == n[31] >> 7 {
+        x = sub(&GF0, &x);
+    }
+
+    Some([x, y, GF1, mul(&x, &y)])
+}
+
+fn scalarmult(mut q: Point, s: &[u8; 32]) -> Point {
+    let mut p: Point = [GF0, GF1, GF1, GF0];
+
+    for i in (0..256).rev() {
+        let b = ((s[i >> 3] >> (i & 7)) & 1) as i64;
+        point_sel(&mut p, &mut q, b);
+        point_add(&mut q, &p);
+        let double = p;
+        point_add(&mut p, &double);
+        point_sel(&mut p, &mut q, b);
+    }
+
+    p
+}
+
+fn scalarbase(s: &[u8; 32]) -> Point {
+    scalarmult([X, Y, GF1, mul(&X, &Y)], s)
+}
+
+/// `x` mod L, the limbs of `x` are left in an unspecified state.
+fn mod_l(x: &mut [i64; 64]) -> [u8; 32] {
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j = i - 32;
+
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j += 1;
+        }
+
+        x[j] += carry;
+        x[i] = 0;
+    }
+
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+
+    let mut r = [0u8; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        r[i] = (x[i] & 255) as u8;
+    }
+
+    r
+}
+
+fn reduce(n: &[u8]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for (x, n) in x.iter_mut().zip(n) {
+        *x = *n as i64;
+    }
+
+    mod_l(&mut x)
+}
+
+/// L - `a` for a reduced, non-zero `a`.
+fn negate(a: &[u8; 32]) -> [u8; 32] {
+    let mut r = [0u8; 32];
+    let mut borrow = 0;
+
+    for i in 0..32 {
+        let d = L[i] - a[i] as i64 - borrow;
+        borrow = (d < 0) as i64;
+        r[i] = (d + (borrow << 8)) as u8;
+    }
+
+    r
+}
+
+fn carry(o: &mut Gf) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+
+        o[i] -= c << 16;
+    }
+}
+
+fn sel(p: &mut Gf, q: &mut Gf, b: i64) {
+    let c = !(b - 1);
+
+    for (p, q) in p.iter_mut().zip(q.iter_mut()) {
+        let t = c & (*p ^ *q);
+        *p ^= t;
+        *q ^= t;
+    }
+}
+
+fn unpack(n: &[u8; 32]) -> Gf {
+    let mut o: Gf = [0; 16];
+
+    for (i, o) in o.iter_mut().enumerate() {
+        *o = n[2 * i] as i64 + ((n[2 * i + 1] as i64) << 8);
+    }
+    o[15] &= 0x7fff;
+
+    o
+}
+
+fn pack(n: &Gf) -> [u8; 32] {
+    let mut t = *n;
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+
+    let mut m: Gf = [0; 16];
+    for _ in 0..2 {
+        m[0] = t[0] - 0xffed;
+
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        sel(&mut t, &mut m, 1 - b);
+    }
+
+    let mut o = [0u8; 32];
+    for (i, t) in t.iter().enumerate() {
+        o[2 * i] = *t as u8;
+        o[2 * i + 1] = (*t >> 8) as u8;
+    }
+
+    o
+}
+
+fn add(a: &Gf, b: &Gf) -> Gf {
+    std::array::from_fn(|i| a[i] + b[i])
+}
+
+fn sub(a: &Gf, b: &Gf) -> Gf {
+    std::array::from_fn(|i| a[i] - b[i])
+}
+
+fn mul(a: &Gf, b: &Gf) -> Gf {
+    let mut t = [0i64; 31];
+
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+
+    let mut o: Gf = std::array::from_fn(|i| t[i]);
+    carry(&mut o);
+    carry(&mut o);
+
+    o
+}
+
+fn inv(i: &Gf) -> Gf {
+    let mut c = *i;
+
+    // i^(p - 2)
+    for a in (0..254).rev() {
+        c = mul(&c, &c);
+
+        if a != 2 && a != 4 {
+            c = mul(&c, i);
+        }
+    }
+
+    c
+}
+
+fn pow2523(i: &Gf) -> Gf {
+    let mut c = *i;
+
+    // i^((p - 5) / 8)
+    for a in (0..251).rev() {
+        c = mul(&c, &c);
+
+        if a != 1 {
+            c = mul(&c, i);
+        }
+    }
+
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{L, ed25519_verify, hmac_sha256, x25519, x25519_base, xeddsa_sign, xeddsa_verify};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    // RFC 4231 test cases 2 and 6
+    #[test]
+    fn test_hmac_sha256() {
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    fn unhex(s: &str) -> [u8; 32] {
+        std::array::from_fn(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap())
+    }
+
+    // RFC 7748 section 6.1
+    #[test]
+    fn test_x25519() {
+        let alice = unhex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = unhex("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+
+        let alice_pub = x25519_base(&alice);
+        let bob_pub = x25519_base(&bob);
+
+        assert_eq!(
+            hex(&alice_pub),
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"
+        );
+        assert_eq!(
+            hex(&bob_pub),
+            "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f"
+        );
+
+        let shared = "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742";
+        assert_eq!(hex(&x25519(&alice, &bob_pub)), shared);
+        assert_eq!(hex(&x25519(&bob, &alice_pub)), shared);
+    }
+
+    // RFC 8032 section 7.1, test 1
+    #[test]
+    fn test_ed25519_verify() {
+        let public = unhex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let sig = "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                   5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b";
+        let sig: [u8; 64] = [unhex(&sig[..64]), unhex(&sig[64..])]
+            .concat()
+            .try_into()
+            .unwrap();
+
+        assert!(ed25519_verify(&public, b"", &sig));
+        assert!(!ed25519_verify(&public, b"x", &sig));
+
+        // the same signature with s + L, its top bits still clear
+        let mut malleated = sig;
+        let mut carry = 0;
+        for (byte, l) in malleated[32..].iter_mut().zip(L) {
+            let sum = *byte as i64 + l + carry;
+            *byte = sum as u8;
+            carry = sum >> 8;
+        }
+        assert_eq!(malleated[63] & 0xe0, 0);
+        assert!(!ed25519_verify(&public, b"", &malleated));
+    }
+
+    #[test]
+    fn test_xeddsa() {
+        let alice = unhex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = unhex("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+
+        // one of them has to negate its key to sign
+        for (private, other) in [(alice, bob), (bob, alice)] {
+            let public = x25519_base(&private);
+
+            let mut sig = xeddsa_sign(&private, b"revoke", &[7; 64]);
+            assert!(xeddsa_verify(&public, b"revoke", &sig));
+            assert!(!xeddsa_verify(&public, b"revokE", &sig));
+            assert!(!xeddsa_verify(&x25519_base(&other), b"revoke", &sig));
+
+            sig[40] ^= 1;
+            assert!(!xeddsa_verify(&public, b"revoke", &sig));
+        }
+    }
+}
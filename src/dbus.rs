@@ -0,0 +1,704 @@
+//! Minimal D-Bus client exposing `dev.wgdisco.Manager1` on the system bus.
+//!
+//! Only what the service needs is implemented: EXTERNAL auth, method calls
+//! without arguments, replies and signals with basic types.
+
+use std::io;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixStream, unix::OwnedReadHalf},
+    sync::mpsc,
+};
+
+use crate::control::{Command, Event, Handle, Response};
+
+pub const BUS_NAME: &str = "dev.wgdisco.Manager1";
+pub const INTERFACE: &str = "dev.wgdisco.Manager1";
+pub const OBJECT_PATH: &str = "/dev/wgdisco/Manager1";
+
+const SYSTEM_BUS: &str = "/run/dbus/system_bus_socket";
+
+/// Largest message we accept, the bus limit is far higher but we only
+/// receive argument-less calls.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// Group whose members may call the methods changing anything, next to
+/// root. Everybody else only reads.
+pub const GROUP: &str = "wg-disco";
+
+/// Lets root own the name, root and [`GROUP`] call every method and
+/// everybody the reading ones, installed to
+/// `/etc/dbus-1/system.d/dev.wgdisco.Manager1.conf`. Every rule of the
+/// default context names its member, calls without an interface match
+/// rules naming one.
+pub const POLICY: &str = r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-BUS Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <policy user="root">
+    <allow own="dev.wgdisco.Manager1"/>
+    <allow send_destination="dev.wgdisco.Manager1"/>
+  </policy>
+  <policy group="wg-disco">
+    <allow send_destination="dev.wgdisco.Manager1"/>
+  </policy>
+  <policy context="default">
+    <allow send_destination="dev.wgdisco.Manager1"
+           send_interface="dev.wgdisco.Manager1" send_member="Status"/>
+    <allow send_destination="dev.wgdisco.Manager1"
+           send_interface="dev.wgdisco.Manager1" send_member="ListPeers"/>
+    <allow send_destination="dev.wgdisco.Manager1"
+           send_interface="org.freedesktop.DBus.Introspectable" send_member="Introspect"/>
+    <allow send_destination="dev.wgdisco.Manager1"
+           send_interface="org.freedesktop.DBus.Peer" send_member="Ping"/>
+  </policy>
+</busconfig>
+"#;
+
+const INTROSPECTION: &str = r#"<node>
+  <interface name="dev.wgdisco.Manager1">
+    <method name="Status">
+      <arg name="iface" type="s" direction="out"/>
+      <arg name="key" type="s" direction="out"/>
+      <arg name="endpoint" type="s" direction="out"/>
+      <arg name="listen_port" type="q" direction="out"/>
+      <arg name="server" type="b" direction="out"/>
+      <arg name="kill_switch" type="b" direction="out"/>
+      <arg name="peers" type="u" direction="out"/>
+      <arg name="advertise_routes" type="as" direction="out"/>
+    </method>
+    <method name="ListPeers">
+      <arg name="peers" type="a(ssttt)" direction="out"/>
+    </method>
+    <method name="Announce"/>
+    <signal name="PeerUp">
+      <arg name="key" type="s"/>
+    </signal>
+    <signal name="PeerDown">
+      <arg name="key" type="s"/>
+    </signal>
+    <signal name="PeerCloned">
+      <arg name="key" type="s"/>
+    </signal>
+    <signal name="ProbeAnswered">
+      <arg name="key" type="s"/>
+    </signal>
+    <signal name="ProbeSilent">
+      <arg name="key" type="s"/>
+    </signal>
+    <signal name="PeerRevoked">
+      <arg name="key" type="s"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+
+const NO_REPLY_EXPECTED: u8 = 1;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// Marshals values in D-Bus little endian wire format.
+#[derive(Debug, Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    fn align(&mut self, n: usize) {
+        while !self.buf.len().is_multiple_of(n) {
+            self.buf.push(0);
+        }
+    }
+
+    pub fn byte(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u32(v as u32);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.align(2);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.align(4);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.align(8);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    pub fn signature(&mut self, s: &str) {
+        self.buf.push(s.len() as u8);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    pub fn struct_start(&mut self) {
+        self.align(8);
+    }
+
+    /// Writes an array, `align` is the alignment of its element type.
+    pub fn array(&mut self, align: usize, f: impl FnOnce(&mut Self)) {
+        self.u32(0);
+        let len_at = self.buf.len() - 4;
+
+        self.align(align);
+        let start = self.buf.len();
+        f(self);
+
+        let len = (self.buf.len() - start) as u32;
+        self.buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Str(String),
+    Path(String),
+    Signature(String),
+    U32(u32),
+}
+
+fn message(kind: u8, serial: u32, fields: &[(u8, Field)], signature: &str, body: &[u8]) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.byte(b'l');
+    w.byte(kind);
+    w.byte(0);
+    w.byte(1);
+    w.u32(body.len() as u32);
+    w.u32(serial);
+
+    let signature =
+        (!signature.is_empty()).then(|| (FIELD_SIGNATURE, Field::Signature(signature.into())));
+
+    w.array(8, |w| {
+        for (code, field) in fields.iter().chain(signature.as_ref()) {
+            w.struct_start();
+            w.byte(*code);
+
+            match field {
+                Field::Str(s) => {
+                    w.signature("s");
+                    w.str(s);
+                }
+                Field::Path(s) => {
+                    w.signature("o");
+                    w.str(s);
+                }
+                Field::Signature(s) => {
+                    w.signature("g");
+                    w.signature(s);
+                }
+                Field::U32(v) => {
+                    w.signature("u");
+                    w.u32(*v);
+                }
+            }
+        }
+    });
+
+    w.align(8);
+    w.buf.extend_from_slice(body);
+    w.into_bytes()
+}
+
+/// Header of a received message, the body is not needed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub kind: u8,
+    pub flags: u8,
+    pub serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub sender: Option<String>,
+    pub reply_serial: Option<u32>,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    little: bool,
+}
+
+impl Reader<'_> {
+    fn align(&mut self, n: usize) {
+        self.pos = self.pos.div_ceil(n) * n;
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let b = *self.buf.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.align(4);
+        let bytes: [u8; 4] = self.buf.get(self.pos..self.pos + 4)?.try_into().ok()?;
+        self.pos += 4;
+
+        Some(match self.little {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<String> {
+        let s = self.buf.get(self.pos..self.pos + len)?;
+        self.pos += len + 1;
+        String::from_utf8(s.to_vec()).ok()
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    fn signature(&mut self) -> Option<String> {
+        let len = self.byte()? as usize;
+        self.bytes(len)
+    }
+}
+
+/// Length of the whole message from its first 16 bytes.
+fn message_len(fixed: &[u8; 16]) -> usize {
+    let read = |at: usize| {
+        let bytes = fixed[at..at + 4].try_into().unwrap();
+        match fixed[0] {
+            b'l' => u32::from_le_bytes(bytes),
+            _ => u32::from_be_bytes(bytes),
+        }
+    };
+
+    let fields = 16 + read(12) as usize;
+    fields.div_ceil(8) * 8 + read(4) as usize
+}
+
+pub fn parse_header(buf: &[u8]) -> Option<Header> {
+    let mut r = Reader {
+        buf,
+        pos: 0,
+        little: *buf.first()? == b'l',
+    };
+
+    let mut header = Header::default();
+    r.byte()?;
+    header.kind = r.byte()?;
+    header.flags = r.byte()?;
+    r.byte()?;
+    r.u32()?;
+    header.serial = r.u32()?;
+
+    let end = r.u32()? as usize + r.pos;
+    while r.pos < end {
+        r.align(8);
+        let code = r.byte()?;
+
+        match (code, r.signature()?.as_str()) {
+            (_, "s" | "o") => {
+                let s = r.str()?;
+                match code {
+                    FIELD_PATH => header.path = Some(s),
+                    FIELD_INTERFACE => header.interface = Some(s),
+                    FIELD_MEMBER => header.member = Some(s),
+                    FIELD_SENDER => header.sender = Some(s),
+                    _ => {}
+                }
+            }
+            (_, "g") => {
+                r.signature()?;
+            }
+            (FIELD_REPLY_SERIAL, "u") => header.reply_serial = Some(r.u32()?),
+            (_, "u") => {
+                r.u32()?;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(header)
+}
+
+struct Bus {
+    writer: tokio::net::unix::OwnedWriteHalf,
+    serial: u32,
+}
+
+impl Bus {
+    async fn send(
+        &mut self,
+        kind: u8,
+        fields: &[(u8, Field)],
+        signature: &str,
+        body: &[u8],
+    ) -> io::Result<u32> {
+        self.serial += 1;
+        let msg = message(kind, self.serial, fields, signature, body);
+        self.writer.write_all(&msg).await?;
+
+        Ok(self.serial)
+    }
+
+    async fn call_bus(&mut self, member: &str, signature: &str, body: &[u8]) -> io::Result<u32> {
+        let fields = [
+            (FIELD_PATH, Field::Path("/org/freedesktop/DBus".into())),
+            (FIELD_INTERFACE, Field::Str("org.freedesktop.DBus".into())),
+            (FIELD_MEMBER, Field::Str(member.into())),
+            (FIELD_DESTINATION, Field::Str("org.freedesktop.DBus".into())),
+        ];
+
+        self.send(METHOD_CALL, &fields, signature, body).await
+    }
+
+    async fn reply(&mut self, call: &Header, signature: &str, body: &[u8]) -> io::Result<()> {
+        if call.flags & NO_REPLY_EXPECTED != 0 {
+            return Ok(());
+        }
+
+        let mut fields = vec![(FIELD_REPLY_SERIAL, Field::U32(call.serial))];
+        fields.extend(
+            call.sender
+                .clone()
+                .map(|s| (FIELD_DESTINATION, Field::Str(s))),
+        );
+
+        self.send(METHOD_RETURN, &fields, signature, body).await?;
+        Ok(())
+    }
+
+    async fn error(&mut self, call: &Header, name: &str, text: &str) -> io::Result<()> {
+        if call.flags & NO_REPLY_EXPECTED != 0 {
+            return Ok(());
+        }
+
+        let mut fields = vec![
+            (FIELD_ERROR_NAME, Field::Str(name.into())),
+            (FIELD_REPLY_SERIAL, Field::U32(call.serial)),
+        ];
+        fields.extend(
+            call.sender
+                .clone()
+                .map(|s| (FIELD_DESTINATION, Field::Str(s))),
+        );
+
+        let mut body = Writer::default();
+        body.str(text);
+        self.send(ERROR, &fields, "s", &body.into_bytes()).await?;
+        Ok(())
+    }
+
+    async fn signal(&mut self, member: &str, key: &str) -> io::Result<()> {
+        let fields = [
+            (FIELD_PATH, Field::Path(OBJECT_PATH.into())),
+            (FIELD_INTERFACE, Field::Str(INTERFACE.into())),
+            (FIELD_MEMBER, Field::Str(member.into())),
+        ];
+
+        let mut body = Writer::default();
+        body.str(key);
+        self.send(SIGNAL, &fields, "s", &body.into_bytes()).await?;
+        Ok(())
+    }
+}
+
+fn bus_path() -> String {
+    std::env::var("DBUS_SYSTEM_BUS_ADDRESS")
+        .ok()
+        .and_then(|addr| {
+            addr.split(';').find_map(|a| {
+                a.strip_prefix("unix:path=")
+                    .map(|p| p.split(',').next().unwrap_or(p).to_string())
+            })
+        })
+        .unwrap_or_else(|| SYSTEM_BUS.to_string())
+}
+
+async fn auth(stream: &mut UnixStream) -> io::Result<()> {
+    let uid: String = unsafe { libc::getuid() }
+        .to_string()
+        .bytes()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+
+    stream.write_all(b"\0").await?;
+    stream
+        .write_all(format!("AUTH EXTERNAL {uid}\r\n").as_bytes())
+        .await?;
+
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if line.len() > 512 {
+            break;
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    if !line.starts_with(b"OK ") {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "dbus auth rejected: {}",
+                String::from_utf8_lossy(&line).trim()
+            ),
+        ));
+    }
+
+    stream.write_all(b"BEGIN\r\n").await
+}
+
+async fn read_messages(read: OwnedReadHalf, tx: mpsc::Sender<Header>) -> io::Result<()> {
+    let mut read = BufReader::new(read);
+
+    loop {
+        let mut fixed = [0u8; 16];
+        read.read_exact(&mut fixed).await?;
+
+        let len = message_len(&fixed);
+        if len > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "dbus message too long",
+            ));
+        }
+
+        let mut msg = fixed.to_vec();
+        msg.resize(len, 0);
+        read.read_exact(&mut msg[16..]).await?;
+
+        let Some(header) = parse_header(&msg) else {
+            log::debug!("malformed dbus message");
+            continue;
+        };
+
+        if tx.send(header).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Connects to the system bus, owns [`BUS_NAME`] and serves it until the
+/// connection breaks.
+pub async fn serve(control: Handle) -> io::Result<()> {
+    let mut stream = UnixStream::connect(bus_path()).await?;
+    auth(&mut stream).await?;
+
+    let (read, writer) = stream.into_split();
+    let (tx, mut messages) = mpsc::channel(16);
+    tokio::spawn(async move {
+        if let Err(err) = read_messages(read, tx).await {
+            log::error!("dbus connection lost: {err}");
+        }
+    });
+
+    let mut bus = Bus { writer, serial: 0 };
+    bus.call_bus("Hello", "", &[]).await?;
+
+    let mut body = Writer::default();
+    body.str(BUS_NAME);
+    body.u32(4); // DBUS_NAME_FLAG_DO_NOT_QUEUE
+    let request_name = bus
+        .call_bus("RequestName", "su", &body.into_bytes())
+        .await?;
+
+    let mut events = control.subscribe();
+    log::info!("dbus service {BUS_NAME} started");
+
+    loop {
+        tokio::select! {
+            msg = messages.recv() => {
+                let Some(msg) = msg else { return Ok(()) };
+
+                match msg.kind {
+                    ERROR if msg.reply_serial == Some(request_name) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            format!("can't own {BUS_NAME}, is the bus policy installed?"),
+                        ));
+                    }
+                    METHOD_CALL => handle_call(&mut bus, &control, &msg).await?,
+                    _ => {}
+                }
+            }
+
+            event = events.recv() => match event {
+                Ok(Event::PeerUp(key)) => bus.signal("PeerUp", &key.to_string()).await?,
+                Ok(Event::PeerDown(key)) => bus.signal("PeerDown", &key.to_string()).await?,
+                Ok(Event::Cloned(key)) => bus.signal("PeerCloned", &key.to_string()).await?,
+                Ok(Event::ProbeAnswered(key)) => bus.signal("ProbeAnswered", &key.to_string()).await?,
+                Ok(Event::ProbeSilent(key)) => bus.signal("ProbeSilent", &key.to_string()).await?,
+                Ok(Event::Revoked(key)) => bus.signal("PeerRevoked", &key.to_string()).await?,
+                Err(err) => log::warn!("dbus missed events: {err}"),
+            },
+        }
+    }
+}
+
+async fn handle_call(bus: &mut Bus, control: &Handle, call: &Header) -> io::Result<()> {
+    let interface = call.interface.as_deref();
+    let member = call.member.as_deref().unwrap_or_default();
+
+    if call.path.as_deref() != Some(OBJECT_PATH) {
+        return bus
+            .error(
+                call,
+                "org.freedesktop.DBus.Error.UnknownObject",
+                "no such object",
+            )
+            .await;
+    }
+
+    let command = match (interface, member) {
+        (Some("org.freedesktop.DBus.Introspectable") | None, "Introspect") => {
+            let mut body = Writer::default();
+            body.str(INTROSPECTION);
+            return bus.reply(call, "s", &body.into_bytes()).await;
+        }
+        (Some("org.freedesktop.DBus.Peer") | None, "Ping") => {
+            return bus.reply(call, "", &[]).await;
+        }
+        (Some(INTERFACE) | None, "Status") => Command::Status,
+        (Some(INTERFACE) | None, "ListPeers") => Command::Peers,
+        (Some(INTERFACE) | None, "Announce") => Command::Announce,
+        _ => {
+            return bus
+                .error(call, "org.freedesktop.DBus.Error.UnknownMethod", member)
+                .await;
+        }
+    };
+
+    let mut body = Writer::default();
+    let signature = match control.call(command).await {
+        Some(Response::Status(status)) => {
+            body.str(&status.iface);
+            body.str(&status.key.to_string());
+            body.str(&status.endpoint.map(|e| e.to_string()).unwrap_or_default());
+            body.u16(status.listen_port);
+            body.bool(status.server);
+            body.bool(status.kill_switch);
+            body.u32(status.peers as u32);
+            body.array(4, |w| {
+                for cidr in &status.advertise_routes {
+                    w.str(&cidr.to_string());
+                }
+            });
+            "sssqbbuas"
+        }
+        Some(Response::Peers(peers)) => {
+            body.array(8, |w| {
+                for peer in &peers {
+                    let (rx, tx) = peer.transfer.unwrap_or_default();
+
+                    w.struct_start();
+                    w.str(&peer.key.to_string());
+                    w.str(
+                        &peer
+                            .endpoint
+                            .as_ref()
+                            .map(|e| e.to_string())
+                            .unwrap_or_default(),
+                    );
+                    w.u64(peer.latest_handshake.unwrap_or_default() as u64);
+                    w.u64(rx);
+                    w.u64(tx);
+                }
+            });
+            "a(ssttt)"
+        }
+        Some(Response::Error(err)) => {
+            return bus
+                .error(call, "org.freedesktop.DBus.Error.Failed", &err)
+                .await;
+        }
+        Some(_) => "",
+        None => {
+            return bus
+                .error(
+                    call,
+                    "org.freedesktop.DBus.Error.Failed",
+                    "runner is not running",
+                )
+                .await;
+        }
+    };
+
+    bus.reply(call, signature, &body.into_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FIELD_MEMBER, FIELD_PATH, FIELD_SENDER, Field, GROUP, METHOD_CALL, POLICY, message,
+        message_len, parse_header,
+    };
+
+    #[test]
+    fn test_policy() {
+        assert!(POLICY.contains(&format!(r#"<policy group="{GROUP}">"#)));
+
+        // everybody reads, nobody else announces
+        let (_, default) = POLICY.split_once(r#"<policy context="default">"#).unwrap();
+        let allowed = default.split("<allow").skip(1);
+        for rule in allowed {
+            assert!(rule.contains("send_member="), "{rule}");
+        }
+        assert!(default.contains(r#"send_member="Status""#));
+        assert!(!default.contains("Announce"));
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let msg = message(
+            METHOD_CALL,
+            7,
+            &[
+                (FIELD_PATH, Field::Path("/dev/wgdisco/Manager1".into())),
+                (FIELD_MEMBER, Field::Str("Status".into())),
+                (FIELD_SENDER, Field::Str(":1.42".into())),
+            ],
+            "s",
+            &[1, 0, 0, 0, b'x', 0],
+        );
+
+        assert_eq!(message_len(msg[..16].try_into().unwrap()), msg.len());
+
+        let header = parse_header(&msg).unwrap();
+        assert_eq!(header.kind, METHOD_CALL);
+        assert_eq!(header.serial, 7);
+        assert_eq!(header.path.as_deref(), Some("/dev/wgdisco/Manager1"));
+        assert_eq!(header.member.as_deref(), Some("Status"));
+        assert_eq!(header.sender.as_deref(), Some(":1.42"));
+    }
+}
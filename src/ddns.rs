@@ -0,0 +1,385 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{DecodeSliceError, Engine, prelude::BASE64_STANDARD};
+use tokio::net::UdpSocket;
+
+use crate::{
+    crypto::hmac_sha256,
+    discover::Discover,
+    error::Error,
+    runner::{RunnerOptions, discover_mapping},
+    wg::{Endpoint, Key, WireguardApi, config::ParseError, config::WgConfig},
+};
+
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+const OPCODE_UPDATE: u16 = 5 << 11;
+
+const TSIG_ALGORITHM: &str = "hmac-sha256.";
+const TSIG_FUDGE: u16 = 300;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TSIG key given as `name:base64-secret`, always hmac-sha256.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TsigKey {
+    pub name: String,
+    pub secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TsigKey").field("name", &self.name).finish()
+    }
+}
+
+impl FromStr for TsigKey {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, secret) = s.split_once(':').ok_or(ParseError::Expected(':'))?;
+        let secret = BASE64_STANDARD
+            .decode(secret.trim())
+            .map_err(DecodeSliceError::from)?;
+
+        Ok(TsigKey {
+            name: name.trim().to_string(),
+            secret,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DdnsConfig {
+    /// Primary name server accepting RFC 2136 updates.
+    pub server: SocketAddr,
+    pub zone: String,
+
+    /// Name holding our address.
+    pub name: String,
+    pub ttl: u32,
+    pub key: Option<TsigKey>,
+
+    /// How often our address is rediscovered and peer names re-resolved.
+    pub interval: Duration,
+}
+
+/// RFC 2136 dynamic update client.
+#[derive(Debug, Clone)]
+pub struct DnsUpdater {
+    config: DdnsConfig,
+}
+
+impl DnsUpdater {
+    pub fn new(config: DdnsConfig) -> Self {
+        Self { config }
+    }
+
+    /// Replaces the A (or AAAA) record set of the configured name with `ip`.
+    pub async fn update(&self, ip: IpAddr) -> Result<(), Error> {
+        let id = rand::random();
+        let mut msg = self.update_message(id, ip);
+
+        if let Some(key) = &self.config.key {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+
+            sign(&mut msg, key, now);
+        }
+
+        let bind: SocketAddr = match self.config.server {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+
+        let udp = UdpSocket::bind(bind).await?;
+        udp.connect(self.config.server).await?;
+        udp.send(&msg).await?;
+
+        let mut buf = [0u8; 512];
+        loop {
+            let len = tokio::time::timeout(TIMEOUT, udp.recv(&mut buf))
+                .await
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+            // not a response to our query
+            if len < 12 || buf[..2] != id.to_be_bytes() || buf[2] & 0x80 == 0 {
+                continue;
+            }
+
+            return match buf[3] & 0x0f {
+                0 => Ok(()),
+                rcode => Err(Error::DnsUpdateFail(rcode)),
+            };
+        }
+    }
+
+    fn update_message(&self, id: u16, ip: IpAddr) -> Vec<u8> {
+        let (rtype, rdata) = match ip {
+            IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+        };
+
+        let mut msg = Vec::with_capacity(512);
+        msg.extend(id.to_be_bytes());
+        msg.extend(OPCODE_UPDATE.to_be_bytes());
+        msg.extend(1u16.to_be_bytes()); // zone
+        msg.extend(0u16.to_be_bytes()); // prerequisites
+        msg.extend(2u16.to_be_bytes()); // updates
+        msg.extend(0u16.to_be_bytes()); // additional
+
+        push_name(&mut msg, &self.config.zone);
+        msg.extend(TYPE_SOA.to_be_bytes());
+        msg.extend(CLASS_IN.to_be_bytes());
+
+        // delete the whole rrset
+        push_name(&mut msg, &self.config.name);
+        msg.extend(rtype.to_be_bytes());
+        msg.extend(CLASS_ANY.to_be_bytes());
+        msg.extend(0u32.to_be_bytes());
+        msg.extend(0u16.to_be_bytes());
+
+        // and add the new record
+        push_name(&mut msg, &self.config.name);
+        msg.extend(rtype.to_be_bytes());
+        msg.extend(CLASS_IN.to_be_bytes());
+        msg.extend(self.config.ttl.to_be_bytes());
+        msg.extend((rdata.len() as u16).to_be_bytes());
+        msg.extend(rdata);
+
+        msg
+    }
+}
+
+pub(crate) fn push_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend(label.to_ascii_lowercase().as_bytes());
+    }
+
+    buf.push(0);
+}
+
+/// Appends a TSIG record (RFC 8945) signing the whole message.
+fn sign(msg: &mut Vec<u8>, key: &TsigKey, now: u64) {
+    let id = [msg[0], msg[1]];
+    let time = &now.to_be_bytes()[2..];
+
+    let mut vars = Vec::with_capacity(64);
+    push_name(&mut vars, &key.name);
+    vars.extend(CLASS_ANY.to_be_bytes());
+    vars.extend(0u32.to_be_bytes());
+    push_name(&mut vars, TSIG_ALGORITHM);
+    vars.extend(time);
+    vars.extend(TSIG_FUDGE.to_be_bytes());
+    vars.extend(0u16.to_be_bytes()); // error
+    vars.extend(0u16.to_be_bytes()); // other len
+
+    let mut signed = msg.clone();
+    signed.extend(vars);
+    let mac = hmac_sha256(&key.secret, &signed);
+
+    let mut rdata = Vec::with_capacity(64);
+    push_name(&mut rdata, TSIG_ALGORITHM);
+    rdata.extend(time);
+    rdata.extend(TSIG_FUDGE.to_be_bytes());
+    rdata.extend((mac.len() as u16).to_be_bytes());
+    rdata.extend(mac);
+    rdata.extend(id);
+    rdata.extend(0u16.to_be_bytes());
+    rdata.extend(0u16.to_be_bytes());
+
+    push_name(msg, &key.name);
+    msg.extend(TYPE_TSIG.to_be_bytes());
+    msg.extend(CLASS_ANY.to_be_bytes());
+    msg.extend(0u32.to_be_bytes());
+    msg.extend((rdata.len() as u16).to_be_bytes());
+    msg.extend(rdata);
+
+    let additional = u16::from_be_bytes([msg[10], msg[11]]) + 1;
+    msg[10..12].copy_from_slice(&additional.to_be_bytes());
+}
+
+/// Instead of signaling, publishes our address in DNS and keeps endpoints
+/// of peers configured by domain name re-resolved.
+pub struct DdnsRunner<W, D> {
+    iface: String,
+    config: WgConfig,
+    wg: W,
+    discover: D,
+    options: RunnerOptions,
+    updater: DnsUpdater,
+    interval: Duration,
+}
+
+impl<W, D> DdnsRunner<W, D>
+where
+    W: WireguardApi,
+    D: Discover,
+    Error: From<W::Error> + From<D::Error>,
+{
+    pub fn new(
+        iface: String,
+        config: WgConfig,
+        wg: W,
+        discover: D,
+        options: RunnerOptions,
+        ddns: DdnsConfig,
+    ) -> Self {
+        Self {
+            iface,
+            config,
+            wg,
+            discover,
+            options,
+            interval: ddns.interval,
+            updater: DnsUpdater::new(ddns),
+        }
+    }
+
+    pub async fn run(mut self) -> Result<(), Error> {
+        let (mapping, port) = discover_mapping(
+            &mut self.wg,
+            &self.discover,
+            &self.iface,
+            self.config.interface.listen_port,
+            self.options.port_policy,
+        )
+        .await?;
+
+        log::info!("discovered mapping {mapping}");
+
+        if mapping.public.port() != port {
+            log::warn!(
+                "NAT maps listen port {port} to {}, peers resolving our name will use the wrong port",
+                mapping.public.port()
+            );
+        }
+
+        let mut published = None;
+        let mut public = mapping.public.ip();
+        let mut interval = tokio::time::interval(self.interval);
+
+        loop {
+            interval.tick().await;
+
+            if published != Some(public) {
+                match self.updater.update(public).await {
+                    Ok(()) => {
+                        log::info!("published {public}");
+                        published = Some(public);
+                    }
+                    Err(err) => log::error!("dns update error: {err}"),
+                }
+            }
+
+            if let Err(err) = self.resolve_peers().await {
+                log::error!("resolve error: {err}");
+            }
+
+            // only the address matters for the record, any port will do
+            match self.discover.discover(0).await {
+                Ok(mapping) => public = mapping.public.ip(),
+                Err(err) => log::error!("discover error: {}", Error::from(err)),
+            }
+        }
+    }
+
+    /// Re-resolves peers configured with a domain endpoint and updates the
+    /// ones whose address changed.
+    async fn resolve_peers(&mut self) -> Result<(), Error> {
+        let current = self.wg.get_endpoints(&self.iface).await?;
+        let mut changed: HashMap<Key, (SocketAddr, u16)> = HashMap::new();
+
+        for peer in &self.config.peers {
+            let Some(Endpoint::Domain(host)) = &peer.endpoint else {
+                continue;
+            };
+
+            let addr = match tokio::net::lookup_host(host.as_str()).await {
+                Ok(mut addrs) => addrs.next(),
+                Err(err) => {
+                    log::warn!("can't resolve {host}: {err}");
+                    continue;
+                }
+            };
+
+            if let Some(addr) = addr
+                && current.get(&peer.public_key) != Some(&Some(addr))
+            {
+                log::info!("peer {} {host} resolved to {addr}", peer.public_key);
+
+                let keepalive = peer.persistent_keepalive.unwrap_or(0).min(u16::MAX as u32);
+                changed.insert(peer.public_key, (addr, keepalive as u16));
+            }
+        }
+
+        let endpoints: Vec<_> = changed
+            .iter()
+            .map(|(key, (addr, _))| (*key, Endpoint::from(*addr)))
+            .collect();
+
+        self.wg.set_peer_endpoints(&self.iface, &endpoints).await?;
+
+        // the peer may be NATed too, punch while its mapping is fresh
+        for (key, (_, keepalive)) in changed {
+            if let Err(err) = self.wg.trigger_handshake(&self.iface, key, keepalive).await {
+                log::warn!("can't trigger handshake with {key}: {}", Error::from(err));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{DdnsConfig, DnsUpdater, TsigKey, sign};
+
+    #[test]
+    fn test_update_message() {
+        let updater = DnsUpdater::new(DdnsConfig {
+            server: "127.0.0.1:53".parse().unwrap(),
+            zone: "example.com".into(),
+            name: "Node.example.com.".into(),
+            ttl: 60,
+            key: None,
+            interval: Duration::from_secs(60),
+        });
+
+        let mut msg = updater.update_message(0x1234, "203.0.113.7".parse().unwrap());
+
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            0x12, 0x34, 0x28, 0, 0, 1, 0, 0, 0, 2, 0, 0,
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 6, 0, 1,
+            4, b'n', b'o', b'd', b'e', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            0, 1, 0, 255, 0, 0, 0, 0, 0, 0,
+            4, b'n', b'o', b'd', b'e', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 203, 0, 113, 7,
+        ];
+        assert_eq!(msg, expected);
+
+        let key: TsigKey = "ddns-key:c2VjcmV0".parse().unwrap();
+        assert_eq!(key.secret, b"secret");
+
+        sign(&mut msg, &key, 1_700_000_000);
+        assert_eq!(msg[10..12], [0, 1]);
+        assert_eq!(
+            msg.len(),
+            expected.len() + 10 + 10 + 13 + 6 + 2 + 2 + 32 + 2 + 2 + 2
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! TCP connections to signaling servers, racing their addresses happy
+//! eyeballs style (RFC 8305) instead of waiting out a dead one.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use futures::{StreamExt, stream::FuturesUnordered};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+/// Head start of an attempt before the next address is tried alongside.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The tunnel of [`forward`] waits this long for its client.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects to whichever address of `host` answers first. Addresses are
+/// tried in resolver order with the families alternating, a new attempt
+/// starts every [`ATTEMPT_DELAY`] or right when one fails.
+pub async fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    race(interleave(addrs)).await
+}
+
+async fn race(addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+    let mut pending = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut next = pending.next();
+    let mut last_err = None;
+
+    loop {
+        if let Some(addr) = next.take() {
+            attempts.push(async move { (addr, TcpStream::connect(addr).await) });
+        }
+
+        if attempts.is_empty() {
+            return Err(last_err.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+            }));
+        }
+
+        tokio::select! {
+            Some((addr, res)) = attempts.next() => match res {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    log::debug!("can't connect to {addr}: {err}");
+                    last_err = Some(err);
+                    next = pending.next();
+                }
+            },
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if pending.len() > 0 => {
+                next = pending.next();
+            }
+        }
+    }
+}
+
+/// Alternates address families, starting with the one the resolver
+/// prefers.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(v6) = addrs.first().map(SocketAddr::is_ipv6) else {
+        return addrs;
+    };
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == v6);
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Serves an established connection on a loopback port, for clients that
+/// only connect by themselves. The first connection to it gets `upstream`,
+/// the listener is gone after.
+pub async fn forward<S>(mut upstream: S) -> io::Result<SocketAddr>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let local = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        let Ok(Ok((mut stream, _))) = tokio::time::timeout(ACCEPT_TIMEOUT, listener.accept()).await
+        else {
+            return;
+        };
+        drop(listener);
+
+        if let Err(err) = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await {
+            log::debug!("tunnel to the signaling server closed: {err}");
+        }
+    });
+
+    Ok(local)
+}
+
+/// TLS over an established connection, the certificate has to be valid
+/// for `host`.
+#[cfg(feature = "tls")]
+pub async fn tls(
+    host: &str,
+    stream: TcpStream,
+) -> io::Result<tokio_native_tls::TlsStream<TcpStream>> {
+    let connector = tokio_native_tls::native_tls::TlsConnector::new().map_err(io::Error::other)?;
+
+    tokio_native_tls::TlsConnector::from(connector)
+        .connect(host, stream)
+        .await
+        .map_err(io::Error::other)
+}
+
+#[cfg(not(feature = "tls"))]
+pub async fn tls(_host: &str, _stream: TcpStream) -> io::Result<TcpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "built without the tls feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::TcpListener;
+
+    use super::{interleave, race};
+
+    #[test]
+    fn test_interleave() {
+        let addrs: Vec<SocketAddr> = ["[2001:db8::1]:6667", "[2001:db8::2]:6667", "192.0.2.1:6667"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+
+        assert_eq!(interleave(addrs.clone()), [addrs[0], addrs[2], addrs[1]]);
+        assert_eq!(interleave(Vec::new()), []);
+    }
+
+    #[tokio::test]
+    async fn test_race_skips_dead_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+
+        // nothing listens on the port of a dropped listener
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let stream = race(vec![dead, live]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live);
+        assert!(race(vec![dead]).await.is_err());
+    }
+}
@@ -1,29 +1,188 @@
-use std::net::SocketAddr;
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+};
 
+pub mod composite;
+pub mod local;
+pub mod nat;
+pub mod pcp;
 pub mod stun;
+pub mod upnp;
 
 pub mod fake {
     #[derive(Debug)]
     pub enum Void {}
     use std::net::{SocketAddr, SocketAddrV4};
 
-    use super::Discover;
+    use super::{Discover, Mapping};
 
     #[derive(Debug, Default)]
     pub struct FakeDiscover;
     impl Discover for FakeDiscover {
         type Error = Void;
 
-        async fn discover(&self) -> Result<(std::net::SocketAddr, u16), Self::Error> {
-            Ok((
-                SocketAddr::V4(SocketAddrV4::new([127, 0, 0, 1].into(), 51039)),
-                51039,
-            ))
+        async fn discover(&self, port: u16) -> Result<Mapping, Self::Error> {
+            let port = if port == 0 { 51039 } else { port };
+
+            Ok(Mapping {
+                public: SocketAddr::V4(SocketAddrV4::new([127, 0, 0, 1].into(), port)),
+                local: SocketAddr::V4(SocketAddrV4::new([127, 0, 0, 1].into(), port)),
+            })
+        }
+
+        async fn discover_via(&self, port: u16, _device: &str) -> Result<Mapping, Self::Error> {
+            self.discover(port).await
         }
     }
 }
 
+/// NAT mapping found by discovery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Mapping {
+    /// Address peers outside of our NAT see.
+    pub public: SocketAddr,
+
+    /// Local address the mapping was created for.
+    pub local: SocketAddr,
+}
+
+impl std::fmt::Display for Mapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.local, self.public)
+    }
+}
+
 pub trait Discover {
     type Error;
-    async fn discover(&self) -> Result<(SocketAddr, u16), Self::Error>;
+
+    /// Discovers the mapping of the local udp `port`, `0` picks any free port.
+    async fn discover(&self, port: u16) -> Result<Mapping, Self::Error>;
+
+    /// Same, with the query leaving through network `device`.
+    async fn discover_via(&self, port: u16, device: &str) -> Result<Mapping, Self::Error>;
+
+    /// Other public addresses the last [`discover`](Discover::discover)
+    /// found the port reachable at, best first.
+    fn candidates(&self) -> Vec<SocketAddr> {
+        Vec::new()
+    }
+
+    /// Mapping of the local udp `port` over IPv6, `None` when there is no
+    /// IPv6 to try.
+    async fn discover_v6(&self, _port: u16) -> Option<Result<Mapping, Self::Error>> {
+        None
+    }
+
+    /// Behavior of the NAT in front of the local udp `port`, `None` when
+    /// there is no way to tell.
+    async fn nat_type(&self, _port: u16) -> Option<Result<nat::NatType, Self::Error>> {
+        None
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DiscoverKind {
+    /// Mapping punched by STUN queries.
+    #[default]
+    Stun,
+
+    /// Mapping requested from the router with UPnP IGD, STUN without one.
+    Upnp,
+
+    /// Mapping requested from the router with PCP or NAT-PMP, STUN without
+    /// either.
+    Pcp,
+
+    /// Public addresses of the local interfaces, combined with the others
+    /// as extra candidates.
+    Local,
+}
+
+/// Discovery picked at runtime, each variant keeps the STUN servers for
+/// preflight checks and the kill-switch.
+#[derive(Debug, Clone)]
+pub enum DiscoverBackend {
+    Stun(stun::StunDiscover),
+    Upnp(upnp::UpnpDiscover),
+    Pcp(pcp::PcpDiscover),
+    Composite(composite::CompositeDiscover),
+}
+
+impl DiscoverBackend {
+    /// Several `kinds` run together and rank their candidates.
+    pub fn new(kinds: &[DiscoverKind], stun: stun::StunDiscover) -> Self {
+        match kinds {
+            [] | [DiscoverKind::Stun] => Self::Stun(stun),
+            [DiscoverKind::Upnp] => Self::Upnp(upnp::UpnpDiscover::new(stun)),
+            [DiscoverKind::Pcp] => Self::Pcp(pcp::PcpDiscover::new(stun)),
+            kinds => Self::Composite(composite::CompositeDiscover::new(stun, kinds)),
+        }
+    }
+
+    /// STUN discovery used directly or as the fallback.
+    pub fn stun(&self) -> &stun::StunDiscover {
+        match self {
+            Self::Stun(stun) => stun,
+            Self::Upnp(upnp) => upnp.fallback(),
+            Self::Pcp(pcp) => pcp.fallback(),
+            Self::Composite(composite) => composite.stun(),
+        }
+    }
+}
+
+impl Discover for DiscoverBackend {
+    type Error = stunclient::Error;
+
+    async fn discover(&self, port: u16) -> Result<Mapping, Self::Error> {
+        match self {
+            Self::Stun(stun) => stun.discover(port).await,
+            Self::Upnp(upnp) => upnp.discover(port).await,
+            Self::Pcp(pcp) => pcp.discover(port).await,
+            Self::Composite(composite) => composite.discover(port).await,
+        }
+    }
+
+    async fn discover_via(&self, port: u16, device: &str) -> Result<Mapping, Self::Error> {
+        match self {
+            Self::Stun(stun) => stun.discover_via(port, device).await,
+            Self::Upnp(upnp) => upnp.discover_via(port, device).await,
+            Self::Pcp(pcp) => pcp.discover_via(port, device).await,
+            Self::Composite(composite) => composite.discover_via(port, device).await,
+        }
+    }
+
+    fn candidates(&self) -> Vec<SocketAddr> {
+        match self {
+            Self::Composite(composite) => composite.candidates(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Routers only map IPv4, IPv6 is always asked over STUN.
+    async fn discover_v6(&self, port: u16) -> Option<Result<Mapping, Self::Error>> {
+        self.stun().discover_v6(port).await
+    }
+
+    /// A router mapping doesn't tell how the NAT treats other traffic.
+    async fn nat_type(&self, port: u16) -> Option<Result<nat::NatType, Self::Error>> {
+        self.stun().nat_type(port).await
+    }
+}
+
+/// Not private nor in the shared address space of carrier grade NAT.
+fn is_public(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || (a == 100 && b & 0xc0 == 64))
+}
+
+/// Local udp port nothing is bound to.
+async fn free_port() -> io::Result<u16> {
+    let udp = tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    Ok(udp.local_addr()?.port())
 }
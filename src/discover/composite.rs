@@ -0,0 +1,207 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    Discover, DiscoverKind, Mapping, free_port, local::LocalDiscover, pcp::PcpDiscover,
+    stun::StunDiscover, upnp::UpnpDiscover,
+};
+
+/// Preference of a candidate, lower is better.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Rank {
+    /// Public IPv4 address of an interface, no NAT in the way.
+    Host,
+
+    /// Mapping the router made on request, it lasts without keepalives.
+    Router,
+
+    /// Mapping seen by a STUN server.
+    Reflexive,
+
+    /// Public IPv6 address of an interface, not reachable from IPv4 only
+    /// peers.
+    HostV6,
+}
+
+/// Runs STUN together with router mappings and interface enumeration and
+/// ranks what they find, ICE-style. The best candidate is the mapping, the
+/// others are kept as [`candidates`](Discover::candidates).
+#[derive(Debug, Clone)]
+pub struct CompositeDiscover {
+    stun: StunDiscover,
+    upnp: Option<UpnpDiscover>,
+    pcp: Option<PcpDiscover>,
+    local: Option<LocalDiscover>,
+
+    /// Candidates besides the mapping the last [`Discover::discover`]
+    /// found, best first.
+    gathered: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl CompositeDiscover {
+    /// STUN is always queried, `kinds` add the others.
+    pub fn new(stun: StunDiscover, kinds: &[DiscoverKind]) -> Self {
+        Self {
+            upnp: kinds
+                .contains(&DiscoverKind::Upnp)
+                .then(|| UpnpDiscover::new(stun.clone())),
+            pcp: kinds
+                .contains(&DiscoverKind::Pcp)
+                .then(|| PcpDiscover::new(stun.clone())),
+            local: kinds
+                .contains(&DiscoverKind::Local)
+                .then_some(LocalDiscover),
+            stun,
+            gathered: Default::default(),
+        }
+    }
+
+    pub fn stun(&self) -> &StunDiscover {
+        &self.stun
+    }
+
+    /// Candidates of every source, best first. Routers only map for the
+    /// default route, they are left out with a `device`.
+    async fn gather(
+        &self,
+        port: u16,
+        device: Option<&str>,
+    ) -> Result<Vec<Mapping>, stunclient::Error> {
+        let port = match port {
+            0 => free_port().await.map_err(stunclient::Error::Socket)?,
+            port => port,
+        };
+
+        let stun = async {
+            match device {
+                Some(device) => self.stun.discover_via(port, device).await,
+                None => self.stun.discover(port).await,
+            }
+        };
+        let upnp = async {
+            match (&self.upnp, device) {
+                (Some(upnp), None) => Some(upnp.router_mapping(port).await),
+                _ => None,
+            }
+        };
+        let pcp = async {
+            match (&self.pcp, device) {
+                (Some(pcp), None) => Some(pcp.router_mapping(port).await),
+                _ => None,
+            }
+        };
+        let (stun, upnp, pcp) = futures::join!(stun, upnp, pcp);
+
+        let mut ranked = Vec::new();
+        for (source, res) in [("upnp", upnp), ("pcp", pcp)] {
+            match res {
+                Some(Ok(mapping)) => ranked.push((Rank::Router, mapping)),
+                Some(Err(err)) => log::debug!("no {source} candidate: {err}"),
+                None => {}
+            }
+        }
+
+        if let Some(local) = &self.local {
+            match local.mappings(port, device) {
+                Ok(mappings) => {
+                    ranked.extend(mappings.into_iter().map(|mapping| match mapping.public {
+                        SocketAddr::V4(_) => (Rank::Host, mapping),
+                        SocketAddr::V6(_) => (Rank::HostV6, mapping),
+                    }))
+                }
+                Err(err) => log::debug!("no local candidates: {err}"),
+            }
+        }
+
+        let err = match stun {
+            Ok(mapping) => {
+                ranked.push((Rank::Reflexive, mapping));
+                None
+            }
+            Err(err) => Some(err),
+        };
+
+        let candidates = rank(ranked);
+        match (candidates.is_empty(), err) {
+            (true, Some(err)) => Err(err),
+            (true, None) => Err(stunclient::Error::Socket(io::Error::other("no candidates"))),
+            (false, _) => Ok(candidates),
+        }
+    }
+}
+
+/// Best first, an address found by several sources counts once.
+fn rank(mut ranked: Vec<(Rank, Mapping)>) -> Vec<Mapping> {
+    ranked.sort_by_key(|&(rank, _)| rank);
+
+    let mut candidates: Vec<Mapping> = Vec::new();
+    for (_, mapping) in ranked {
+        if !candidates
+            .iter()
+            .any(|known| known.public == mapping.public)
+        {
+            candidates.push(mapping);
+        }
+    }
+
+    candidates
+}
+
+impl Discover for CompositeDiscover {
+    type Error = stunclient::Error;
+
+    async fn discover(&self, port: u16) -> Result<Mapping, Self::Error> {
+        let mut candidates = self.gather(port, None).await?;
+        let mapping = candidates.remove(0);
+
+        let gathered: Vec<_> = candidates.iter().map(|mapping| mapping.public).collect();
+        log::debug!("discovered {mapping}, other candidates {gathered:?}");
+        *self.gathered.lock().unwrap() = gathered;
+
+        Ok(mapping)
+    }
+
+    async fn discover_via(&self, port: u16, device: &str) -> Result<Mapping, Self::Error> {
+        let mut candidates = self.gather(port, Some(device)).await?;
+        Ok(candidates.remove(0))
+    }
+
+    fn candidates(&self) -> Vec<SocketAddr> {
+        self.gathered.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mapping, Rank, rank};
+
+    #[test]
+    fn test_rank() {
+        let mapping = |public: &str, local: &str| Mapping {
+            public: public.parse().unwrap(),
+            local: local.parse().unwrap(),
+        };
+        let reflexive = mapping("203.0.113.7:51820", "192.168.1.10:51820");
+        let router = mapping("203.0.113.7:51820", "192.168.1.10:51820");
+        let v6 = mapping("[2001:db8::10]:51820", "[2001:db8::10]:51820");
+        let moved = mapping("203.0.113.7:40000", "192.168.1.10:51820");
+
+        assert_eq!(
+            rank(vec![
+                (Rank::HostV6, v6),
+                (Rank::Reflexive, reflexive),
+                (Rank::Router, router),
+            ]),
+            vec![router, v6]
+        );
+
+        // a router mapping its own external port beats what STUN saw
+        assert_eq!(
+            rank(vec![(Rank::Reflexive, reflexive), (Rank::Router, moved)]),
+            vec![moved, reflexive]
+        );
+    }
+}
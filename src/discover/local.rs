@@ -0,0 +1,160 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use super::{Discover, Mapping, free_port, is_public};
+
+/// Publicly routable addresses of the local interfaces, hosts with one need
+/// no NAT traversal at all and IPv6 is mostly reached this way.
+#[derive(Debug, Default, Clone)]
+pub struct LocalDiscover;
+
+impl LocalDiscover {
+    /// Mappings of the local udp `port` on every public address, of
+    /// interface `device` only when given. IPv4 goes first.
+    pub fn mappings(&self, port: u16, device: Option<&str>) -> io::Result<Vec<Mapping>> {
+        let mut addrs: Vec<IpAddr> = interface_addresses()?
+            .into_iter()
+            .filter(|(name, _)| device.is_none_or(|device| device == name))
+            .map(|(_, ip)| ip)
+            .filter(is_global)
+            .collect();
+        addrs.sort_by_key(IpAddr::is_ipv6);
+        addrs.dedup();
+
+        Ok(addrs
+            .into_iter()
+            .map(|ip| Mapping {
+                public: SocketAddr::new(ip, port),
+                local: SocketAddr::new(ip, port),
+            })
+            .collect())
+    }
+
+    async fn first(&self, port: u16, device: Option<&str>) -> Result<Mapping, stunclient::Error> {
+        let port = match port {
+            0 => free_port().await.map_err(stunclient::Error::Socket)?,
+            port => port,
+        };
+
+        self.mappings(port, device)
+            .map_err(stunclient::Error::Socket)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| stunclient::Error::Socket(io::Error::other("no public address")))
+    }
+}
+
+impl Discover for LocalDiscover {
+    type Error = stunclient::Error;
+
+    async fn discover(&self, port: u16) -> Result<Mapping, Self::Error> {
+        self.first(port, None).await
+    }
+
+    async fn discover_via(&self, port: u16, device: &str) -> Result<Mapping, Self::Error> {
+        self.first(port, Some(device)).await
+    }
+}
+
+/// Whether an interface, `device` only when given, has a public IPv6
+/// address. Assumed when interfaces can't be listed.
+pub fn has_global_v6(device: Option<&str>) -> bool {
+    match interface_addresses() {
+        Ok(addrs) => addrs.iter().any(|(name, ip)| {
+            device.is_none_or(|device| device == name) && ip.is_ipv6() && is_global(ip)
+        }),
+        Err(_) => true,
+    }
+}
+
+/// Reachable from anywhere on the internet.
+fn is_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public(*ip) && !ip.is_multicast() && !ip.is_broadcast(),
+        IpAddr::V6(ip) => is_global_v6(ip),
+    }
+}
+
+/// Not loopback, link-local, unique local nor multicast.
+fn is_global_v6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || first & 0xffc0 == 0xfe80
+        || first & 0xfe00 == 0xfc00
+        || ip.to_ipv4_mapped().is_some())
+}
+
+/// Addresses of the interfaces which are up, with the interface name.
+#[cfg(unix)]
+fn interface_addresses() -> io::Result<Vec<(String, IpAddr)>> {
+    let mut ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addrs = Vec::new();
+    let mut next = ifaddrs;
+    while let Some(ifa) = unsafe { next.as_ref() } {
+        next = ifa.ifa_next;
+
+        if ifa.ifa_addr.is_null() || ifa.ifa_flags & libc::IFF_UP as libc::c_uint == 0 {
+            continue;
+        }
+
+        let ip = match unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*ifa.ifa_addr.cast::<libc::sockaddr_in>() };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*ifa.ifa_addr.cast::<libc::sockaddr_in6>() };
+                IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+
+        let name = unsafe { std::ffi::CStr::from_ptr(ifa.ifa_name) };
+        addrs.push((name.to_string_lossy().into_owned(), ip));
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+#[cfg(not(unix))]
+fn interface_addresses() -> io::Result<Vec<(String, IpAddr)>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{interface_addresses, is_global};
+
+    #[test]
+    fn test_is_global() {
+        let global = |ip: &str| is_global(&ip.parse::<IpAddr>().unwrap());
+
+        assert!(global("203.0.113.7"));
+        assert!(global("2001:db8::1"));
+        assert!(!global("192.168.1.10"));
+        assert!(!global("100.64.0.1"));
+        assert!(!global("fe80::1"));
+        assert!(!global("fd00::1"));
+        assert!(!global("::ffff:203.0.113.7"));
+
+        #[cfg(unix)]
+        assert!(
+            interface_addresses()
+                .unwrap()
+                .iter()
+                .any(|(_, ip)| ip.is_loopback())
+        );
+    }
+}
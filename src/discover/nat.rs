@@ -0,0 +1,398 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+
+const MAPPED_ADDRESS: u16 = 0x0001;
+const CHANGE_REQUEST: u16 = 0x0003;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const OTHER_ADDRESS: u16 = 0x802c;
+
+const CHANGE_IP: u8 = 4;
+const CHANGE_PORT: u8 = 2;
+
+/// First retransmission, doubling with every further one.
+const RETRANSMIT: Duration = Duration::from_millis(250);
+const MAX_TRANSMISSIONS: u32 = 3;
+
+/// NAT behavior in the classic terms of RFC 3489, told apart with the tests
+/// of RFC 5780.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// No NAT, the address is public.
+    Open,
+
+    /// One mapping for every destination, anybody may send through it.
+    FullCone,
+
+    /// One mapping for every destination, hosts we sent to may send through
+    /// it from any port.
+    Restricted,
+
+    /// One mapping for every destination, only the addresses we sent to
+    /// may send through it.
+    PortRestricted,
+
+    /// A mapping for every destination, the one a peer learns over
+    /// signaling is of no use to it.
+    Symmetric,
+}
+
+impl NatType {
+    /// Whether punching between hosts behind `self` and `other` can work,
+    /// otherwise only a relay connects them. A symmetric NAT picks a new
+    /// port towards the peer, one filtering by port never lets it in.
+    pub fn punchable(self, other: NatType) -> bool {
+        use NatType::*;
+
+        !matches!(
+            (self, other),
+            (Symmetric, Symmetric) | (Symmetric, PortRestricted) | (PortRestricted, Symmetric)
+        )
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::Open => 1,
+            Self::FullCone => 2,
+            Self::Restricted => 3,
+            Self::PortRestricted => 4,
+            Self::Symmetric => 5,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Self::Open,
+            2 => Self::FullCone,
+            3 => Self::Restricted,
+            4 => Self::PortRestricted,
+            5 => Self::Symmetric,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Open => "open",
+            Self::FullCone => "full-cone",
+            Self::Restricted => "restricted",
+            Self::PortRestricted => "port-restricted",
+            Self::Symmetric => "symmetric",
+        })
+    }
+}
+
+/// Binding response of a STUN server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Binding {
+    mapped: SocketAddr,
+
+    /// Alternate address of a server supporting RFC 5780.
+    other: Option<SocketAddr>,
+
+    /// Where the response came from.
+    origin: SocketAddr,
+}
+
+/// Classifies the NAT `udp` is behind. The mapping is compared across two
+/// servers, or the two addresses of an RFC 5780 server. Filtering can only
+/// be told with such a server, others leave it at port-restricted, the
+/// strictest a cone NAT filters.
+pub async fn detect(udp: &UdpSocket, servers: &[SocketAddr]) -> io::Result<NatType> {
+    let mut answered = None;
+    for &server in servers {
+        if let Some(binding) = binding(udp, server, 0).await? {
+            answered = Some((server, binding));
+            break;
+        }
+    }
+    let Some((server, first)) = answered else {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no stun server answered",
+        ));
+    };
+
+    if first.mapped.ip() == local_ip(server).await? {
+        return Ok(NatType::Open);
+    }
+
+    let second = match first.other {
+        Some(other) => Some(other),
+        None => servers
+            .iter()
+            .copied()
+            .find(|addr| addr.ip() != server.ip() && addr.is_ipv4() == server.is_ipv4()),
+    };
+    if let Some(second) = second {
+        match binding(udp, second, 0).await? {
+            Some(binding) if binding.mapped != first.mapped => return Ok(NatType::Symmetric),
+            Some(_) => {}
+            None => log::debug!("stun server {second} didn't answer, assuming a cone nat"),
+        }
+    }
+
+    if first.other.is_none() {
+        log::debug!("stun server {server} can't test filtering, assuming port-restricted");
+        return Ok(NatType::PortRestricted);
+    }
+
+    // `udp` sent to the other address already, a fresh mapping only lets
+    // it in when the nat doesn't filter by address. Answers of a server
+    // ignoring the change request tell nothing.
+    let fresh = unbound(server).await?;
+    let changed = |binding: Option<Binding>, ip: bool| {
+        binding.is_some_and(|binding| {
+            binding.origin.port() != server.port() && (binding.origin.ip() != server.ip()) == ip
+        })
+    };
+
+    if changed(
+        binding(&fresh, server, CHANGE_IP | CHANGE_PORT).await?,
+        true,
+    ) {
+        return Ok(NatType::FullCone);
+    }
+    if changed(binding(&fresh, server, CHANGE_PORT).await?, false) {
+        return Ok(NatType::Restricted);
+    }
+
+    Ok(NatType::PortRestricted)
+}
+
+/// Socket on any free port of the family of `server`.
+async fn unbound(server: SocketAddr) -> io::Result<UdpSocket> {
+    let any: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    UdpSocket::bind(any).await
+}
+
+/// Source address the kernel picks towards `server`.
+async fn local_ip(server: SocketAddr) -> io::Result<IpAddr> {
+    let udp = unbound(server).await?;
+    udp.connect(server).await?;
+
+    Ok(udp.local_addr()?.ip())
+}
+
+/// Binding request to `server` asking it to answer from its other address
+/// or port with `change`, `None` when no answer comes.
+async fn binding(udp: &UdpSocket, server: SocketAddr, change: u8) -> io::Result<Option<Binding>> {
+    let transaction: [u8; 12] = rand::random();
+    let request = binding_request(&transaction, change);
+    let mut buf = [0u8; 548];
+    let mut timeout = RETRANSMIT;
+
+    for _ in 0..MAX_TRANSMISSIONS {
+        udp.send_to(&request, server).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(res) = tokio::time::timeout_at(deadline, udp.recv_from(&mut buf)).await {
+            let (len, origin) = res?;
+            if let Some((mapped, other)) = parse_binding(&buf[..len], &transaction) {
+                return Ok(Some(Binding {
+                    mapped,
+                    other,
+                    origin,
+                }));
+            }
+        }
+        timeout *= 2;
+    }
+
+    Ok(None)
+}
+
+fn binding_request(transaction: &[u8; 12], change: u8) -> Vec<u8> {
+    let mut req = Vec::with_capacity(28);
+    req.extend(BINDING_REQUEST.to_be_bytes());
+    req.extend(if change == 0 { 0u16 } else { 8 }.to_be_bytes());
+    req.extend(MAGIC_COOKIE.to_be_bytes());
+    req.extend(transaction);
+
+    if change != 0 {
+        req.extend(CHANGE_REQUEST.to_be_bytes());
+        req.extend(4u16.to_be_bytes());
+        req.extend([0, 0, 0, change]);
+    }
+
+    req
+}
+
+/// Mapped and other address of a binding response to `transaction`.
+fn parse_binding(res: &[u8], transaction: &[u8; 12]) -> Option<(SocketAddr, Option<SocketAddr>)> {
+    if res.len() < 20
+        || res[0..2] != BINDING_RESPONSE.to_be_bytes()
+        || res[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || res[8..20] != transaction[..]
+    {
+        return None;
+    }
+
+    let mut mask = [0u8; 16];
+    mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    mask[4..].copy_from_slice(transaction);
+
+    let (mut mapped, mut xor_mapped, mut other) = (None, None, None);
+    let mut attrs = &res[20..];
+    while let [t0, t1, l0, l1, rest @ ..] = attrs {
+        let len = u16::from_be_bytes([*l0, *l1]) as usize;
+        let value = rest.get(..len)?;
+
+        match u16::from_be_bytes([*t0, *t1]) {
+            MAPPED_ADDRESS => mapped = parse_address(value, None),
+            XOR_MAPPED_ADDRESS => xor_mapped = parse_address(value, Some(&mask)),
+            OTHER_ADDRESS => other = parse_address(value, None),
+            _ => {}
+        }
+
+        attrs = rest.get(len.next_multiple_of(4)..).unwrap_or_default();
+    }
+
+    Some((xor_mapped.or(mapped)?, other))
+}
+
+/// Address attribute, xored with the cookie and transaction in `mask`.
+fn parse_address(value: &[u8], mask: Option<&[u8; 16]>) -> Option<SocketAddr> {
+    let mask = mask.copied().unwrap_or_default();
+    let port = u16::from_be_bytes([value.get(2)? ^ mask[0], value.get(3)? ^ mask[1]]);
+
+    let ip = match (value.get(1)?, value.get(4..)?) {
+        (1, ip) => IpAddr::from(xor::<4>(ip, &mask)?),
+        (2, ip) => IpAddr::from(xor::<16>(ip, &mask)?),
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+fn xor<const N: usize>(bytes: &[u8], mask: &[u8; 16]) -> Option<[u8; N]> {
+    let mut out: [u8; N] = bytes.try_into().ok()?;
+    out.iter_mut()
+        .zip(mask)
+        .for_each(|(byte, mask)| *byte ^= mask);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use tokio::net::UdpSocket;
+
+    use super::{
+        BINDING_RESPONSE, CHANGE_IP, MAGIC_COOKIE, NatType, OTHER_ADDRESS, XOR_MAPPED_ADDRESS,
+        detect,
+    };
+
+    /// Response to `req` carrying `mapped` and `other`.
+    fn response(req: &[u8], mapped: SocketAddr, other: SocketAddr) -> Vec<u8> {
+        let SocketAddr::V4(mapped) = mapped else {
+            unreachable!()
+        };
+        let SocketAddr::V4(other) = other else {
+            unreachable!()
+        };
+        let cookie = MAGIC_COOKIE.to_be_bytes();
+
+        let mut res = BINDING_RESPONSE.to_be_bytes().to_vec();
+        res.extend(24u16.to_be_bytes());
+        res.extend(&req[4..20]);
+
+        res.extend(XOR_MAPPED_ADDRESS.to_be_bytes());
+        res.extend([0, 8, 0, 1]);
+        res.extend((mapped.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+        res.extend(mapped.ip().octets().iter().zip(cookie).map(|(a, b)| a ^ b));
+
+        res.extend(OTHER_ADDRESS.to_be_bytes());
+        res.extend([0, 8, 0, 1]);
+        res.extend(other.port().to_be_bytes());
+        res.extend(other.ip().octets());
+
+        res
+    }
+
+    /// RFC 5780 server on 127.0.0.1 with its other address on 127.0.0.2,
+    /// mimicking a nat which maps per destination when `symmetric` and
+    /// doesn't filter when `full_cone`.
+    async fn server(symmetric: bool, full_cone: bool) -> SocketAddr {
+        let primary = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let other = Arc::new(UdpSocket::bind("127.0.0.2:0").await.unwrap());
+        let addr = primary.local_addr().unwrap();
+        let other_addr = other.local_addr().unwrap();
+
+        let mapped: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let moved: SocketAddr = "203.0.113.7:40001".parse().unwrap();
+
+        tokio::spawn({
+            let other = other.clone();
+            async move {
+                let mut buf = [0u8; 548];
+                loop {
+                    let (len, from) = primary.recv_from(&mut buf).await.unwrap();
+                    let res = response(&buf[..len], mapped, other_addr);
+                    match buf[..len].get(27) {
+                        None => primary.send_to(&res, from).await.unwrap(),
+                        Some(change) if change & CHANGE_IP != 0 && full_cone => {
+                            other.send_to(&res, from).await.unwrap()
+                        }
+                        Some(_) => 0,
+                    };
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 548];
+            loop {
+                let (len, from) = other.recv_from(&mut buf).await.unwrap();
+                let mapped = if symmetric { moved } else { mapped };
+                let res = response(&buf[..len], mapped, other_addr);
+                other.send_to(&res, from).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_detect() {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let symmetric = server(true, true).await;
+        assert_eq!(
+            detect(&udp, &[symmetric]).await.unwrap(),
+            NatType::Symmetric
+        );
+
+        let full_cone = server(false, true).await;
+        assert_eq!(detect(&udp, &[full_cone]).await.unwrap(), NatType::FullCone);
+
+        let port_restricted = server(false, false).await;
+        assert_eq!(
+            detect(&udp, &[port_restricted]).await.unwrap(),
+            NatType::PortRestricted
+        );
+
+        assert!(!NatType::Symmetric.punchable(NatType::PortRestricted));
+        assert!(NatType::Symmetric.punchable(NatType::Restricted));
+        assert!(NatType::PortRestricted.punchable(NatType::PortRestricted));
+
+        for nat in [NatType::Open, NatType::Restricted, NatType::Symmetric] {
+            assert_eq!(NatType::from_u8(nat.to_u8()), Some(nat));
+        }
+        assert_eq!(NatType::from_u8(0), None);
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use super::{Discover, Mapping, free_port, is_public, stun::StunDiscover};
+
+/// Port PCP and NAT-PMP servers listen on.
+const SERVER_PORT: u16 = 5351;
+
+const PCP_VERSION: u8 = 2;
+const OPCODE_MAP: u8 = 1;
+const RESPONSE: u8 = 0x80;
+
+const NATPMP_EXTERNAL_ADDRESS: u8 = 0;
+const NATPMP_MAP_UDP: u8 = 1;
+
+/// `UNSUPP_VERSION` result of PCP, answered by NAT-PMP servers as well.
+const UNSUPPORTED_VERSION: u16 = 1;
+
+/// Lifetime asked for, the one RFC 6887 recommends.
+const LIFETIME: Duration = Duration::from_secs(7200);
+
+/// Shortest wait before renewing, against gateways granting tiny lifetimes.
+const MIN_RENEWAL: Duration = Duration::from_secs(30);
+
+/// First retransmission, doubling with every further one.
+const RETRANSMIT: Duration = Duration::from_millis(250);
+const MAX_TRANSMISSIONS: u32 = 4;
+
+/// Mapping requested from the router with PCP, or NAT-PMP on routers only
+/// speaking that, renewed in the background at half its lifetime. Routers
+/// without either, or behind another NAT, fall back to STUN.
+#[derive(Debug, Clone)]
+pub struct PcpDiscover {
+    fallback: StunDiscover,
+    leases: Arc<Mutex<HashMap<u16, Lease>>>,
+}
+
+/// Mapping of a local port kept alive.
+#[derive(Debug)]
+struct Lease {
+    nonce: [u8; 12],
+    external: u16,
+    renewal: JoinHandle<()>,
+}
+
+/// Mapping the gateway granted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Granted {
+    public: SocketAddrV4,
+    local: SocketAddrV4,
+    lifetime: Duration,
+}
+
+impl PcpDiscover {
+    pub fn new(fallback: StunDiscover) -> Self {
+        Self {
+            fallback,
+            leases: Default::default(),
+        }
+    }
+
+    pub fn fallback(&self) -> &StunDiscover {
+        &self.fallback
+    }
+
+    /// Mapping of the local udp `port` from the router alone, without
+    /// falling back to STUN.
+    pub async fn router_mapping(&self, port: u16) -> io::Result<Mapping> {
+        self.map(port).await.map_err(io::Error::other)
+    }
+
+    async fn map(&self, port: u16) -> Result<Mapping, PcpError> {
+        let gateway = default_gateway()?;
+        let server = SocketAddrV4::new(gateway, SERVER_PORT);
+
+        // the same nonce and external port renew an existing mapping
+        let (nonce, external) = match self.leases.lock().unwrap().get(&port) {
+            Some(lease) => (lease.nonce, lease.external),
+            None => (rand::random(), port),
+        };
+
+        let granted = request(server, port, external, &nonce).await?;
+        if !is_public(*granted.public.ip()) {
+            return Err(PcpError::NotPublic(*granted.public.ip()));
+        }
+
+        let lease = Lease {
+            nonce,
+            external: granted.public.port(),
+            renewal: tokio::spawn(renew(server, nonce, granted)),
+        };
+        if let Some(old) = self.leases.lock().unwrap().insert(port, lease) {
+            old.renewal.abort();
+        }
+
+        Ok(Mapping {
+            public: SocketAddr::V4(granted.public),
+            local: SocketAddr::V4(granted.local),
+        })
+    }
+}
+
+impl Discover for PcpDiscover {
+    type Error = stunclient::Error;
+
+    async fn discover(&self, port: u16) -> Result<Mapping, Self::Error> {
+        let port = match port {
+            0 => free_port().await.map_err(stunclient::Error::Socket)?,
+            port => port,
+        };
+
+        match self.router_mapping(port).await {
+            Ok(mapping) => Ok(mapping),
+            Err(err) => {
+                log::warn!("pcp mapping failed: {err}, falling back to stun");
+                self.fallback.discover(port).await
+            }
+        }
+    }
+
+    /// The gateway is the one of the default route, other uplinks use STUN.
+    async fn discover_via(&self, port: u16, device: &str) -> Result<Mapping, Self::Error> {
+        self.fallback.discover_via(port, device).await
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum PcpError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("malformed response")]
+    Malformed,
+
+    #[error("gateway answered result code {0}")]
+    Refused(u16),
+
+    #[error("gateway address {0} is behind another nat")]
+    NotPublic(Ipv4Addr),
+}
+
+/// Keeps renewing the mapping `granted` by `server` at half its lifetime.
+async fn renew(server: SocketAddrV4, nonce: [u8; 12], mut granted: Granted) {
+    let port = granted.local.port();
+    let mut wait = granted.lifetime / 2;
+
+    loop {
+        tokio::time::sleep(wait.max(MIN_RENEWAL)).await;
+
+        match request(server, port, granted.public.port(), &nonce).await {
+            Ok(renewed) => {
+                if renewed.public != granted.public {
+                    log::warn!(
+                        "pcp mapping of port {port} moved from {} to {}",
+                        granted.public,
+                        renewed.public
+                    );
+                }
+                granted = renewed;
+                wait = granted.lifetime / 2;
+            }
+            Err(err) => {
+                log::warn!("can't renew pcp mapping of port {port}: {err}");
+                wait = MIN_RENEWAL;
+            }
+        }
+    }
+}
+
+/// Maps the local udp `port` with PCP, or NAT-PMP when the server doesn't
+/// speak PCP.
+async fn request(
+    server: SocketAddrV4,
+    port: u16,
+    external: u16,
+    nonce: &[u8; 12],
+) -> Result<Granted, PcpError> {
+    let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    udp.connect(server).await?;
+
+    // the address the gateway reaches us at
+    let SocketAddr::V4(local) = udp.local_addr()? else {
+        return Err(io::Error::other("no ipv4 route to the gateway").into());
+    };
+    let local = SocketAddrV4::new(*local.ip(), port);
+
+    let response = exchange(&udp, &map_request(local, external, nonce)).await?;
+    match parse_map(&response, local, nonce) {
+        Err(PcpError::Refused(UNSUPPORTED_VERSION)) => natpmp(&udp, local, external).await,
+        res => res,
+    }
+}
+
+/// Sends `request` until the server answers, retransmitting with backoff.
+async fn exchange(udp: &UdpSocket, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; 1100];
+    let mut timeout = RETRANSMIT;
+
+    for _ in 0..MAX_TRANSMISSIONS {
+        udp.send(request).await?;
+        if let Ok(len) = tokio::time::timeout(timeout, udp.recv(&mut buf)).await {
+            buf.truncate(len?);
+            return Ok(buf);
+        }
+        timeout *= 2;
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no pcp nor nat-pmp gateway answered",
+    ))
+}
+
+/// PCP `MAP` request of RFC 6887 for udp.
+fn map_request(local: SocketAddrV4, external: u16, nonce: &[u8; 12]) -> Vec<u8> {
+    let mut req = Vec::with_capacity(60);
+    req.extend([PCP_VERSION, OPCODE_MAP, 0, 0]);
+    req.extend((LIFETIME.as_secs() as u32).to_be_bytes());
+    req.extend(local.ip().to_ipv6_mapped().octets());
+
+    req.extend(nonce);
+    req.extend([17, 0, 0, 0]);
+    req.extend(local.port().to_be_bytes());
+    req.extend(external.to_be_bytes());
+    req.extend(Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+    req
+}
+
+fn parse_map(res: &[u8], local: SocketAddrV4, nonce: &[u8; 12]) -> Result<Granted, PcpError> {
+    match res {
+        // a NAT-PMP server answering with its own version
+        [0, ..] => return Err(PcpError::Refused(UNSUPPORTED_VERSION)),
+        [PCP_VERSION, op, _, result, ..] if *op == RESPONSE | OPCODE_MAP => match *result {
+            0 => (),
+            result => return Err(PcpError::Refused(result as u16)),
+        },
+        _ => return Err(PcpError::Malformed),
+    }
+
+    if res.len() < 60 || res[24..36] != nonce[..] || res[36] != 17 {
+        return Err(PcpError::Malformed);
+    }
+
+    let lifetime = u32::from_be_bytes(res[4..8].try_into().unwrap());
+    let port = u16::from_be_bytes([res[42], res[43]]);
+    let ip: [u8; 16] = res[44..60].try_into().unwrap();
+    let ip = std::net::Ipv6Addr::from(ip)
+        .to_ipv4_mapped()
+        .ok_or(PcpError::Malformed)?;
+
+    Ok(Granted {
+        public: SocketAddrV4::new(ip, port),
+        local,
+        lifetime: Duration::from_secs(lifetime as u64),
+    })
+}
+
+/// Same with NAT-PMP of RFC 6886, which needs a request of its own for the
+/// external address.
+async fn natpmp(udp: &UdpSocket, local: SocketAddrV4, external: u16) -> Result<Granted, PcpError> {
+    let res = exchange(udp, &[0, NATPMP_EXTERNAL_ADDRESS]).await?;
+    let res = natpmp_response(&res, NATPMP_EXTERNAL_ADDRESS, 12)?;
+    let ip = Ipv4Addr::new(res[8], res[9], res[10], res[11]);
+
+    let mut req = vec![0, NATPMP_MAP_UDP, 0, 0];
+    req.extend(local.port().to_be_bytes());
+    req.extend(external.to_be_bytes());
+    req.extend((LIFETIME.as_secs() as u32).to_be_bytes());
+
+    let res = exchange(udp, &req).await?;
+    let res = natpmp_response(&res, NATPMP_MAP_UDP, 16)?;
+    let port = u16::from_be_bytes([res[10], res[11]]);
+    let lifetime = u32::from_be_bytes(res[12..16].try_into().unwrap());
+
+    Ok(Granted {
+        public: SocketAddrV4::new(ip, port),
+        local,
+        lifetime: Duration::from_secs(lifetime as u64),
+    })
+}
+
+fn natpmp_response(res: &[u8], op: u8, len: usize) -> Result<&[u8], PcpError> {
+    if res.len() < 4 || res[0] != 0 || res[1] != RESPONSE | op {
+        return Err(PcpError::Malformed);
+    }
+
+    match u16::from_be_bytes([res[2], res[3]]) {
+        0 if res.len() >= len => Ok(res),
+        0 => Err(PcpError::Malformed),
+        result => Err(PcpError::Refused(result)),
+    }
+}
+
+/// Gateway of the IPv4 default route, with the lowest metric.
+fn default_gateway() -> io::Result<Ipv4Addr> {
+    let table = fs::read_to_string("/proc/net/route")?;
+    parse_gateway(&table).ok_or_else(|| io::Error::other("no default gateway"))
+}
+
+fn parse_gateway(table: &str) -> Option<Ipv4Addr> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (dest, gateway, metric, mask) = (
+                fields.get(1)?,
+                fields.get(2)?,
+                fields.get(6)?,
+                fields.get(7)?,
+            );
+
+            // addresses are printed as the u32 holding them in network order
+            let gateway = u32::from_str_radix(gateway, 16).ok()?.to_ne_bytes();
+            (*dest == "00000000" && *mask == "00000000")
+                .then(|| (metric.parse::<u32>().unwrap_or(u32::MAX), gateway.into()))
+        })
+        .min()
+        .map(|(_, gateway)| gateway)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddrV4, time::Duration};
+
+    use tokio::net::UdpSocket;
+
+    use super::{Granted, PcpError, parse_gateway, request};
+
+    #[test]
+    fn test_parse_gateway() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                     eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n\
+                     wwan0\t00000000\t0102A8C0\t0003\t0\t0\t700\t00000000\t0\t0\t0\n\
+                     wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n";
+
+        assert_eq!(parse_gateway(table), Some("192.168.1.1".parse().unwrap()));
+        assert_eq!(parse_gateway("Iface\tDestination\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = match udp.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+
+        let router = tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+
+            // pcp
+            let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 60);
+            let mut res = buf[..60].to_vec();
+            res[1] |= 0x80;
+            res[3] = 0;
+            res[4..8].copy_from_slice(&600u32.to_be_bytes());
+            res[42..44].copy_from_slice(&40000u16.to_be_bytes());
+            res[56..60].copy_from_slice(&[203, 0, 113, 7]);
+            udp.send_to(&res, from).await.unwrap();
+
+            // nat-pmp only
+            let (_, from) = udp.recv_from(&mut buf).await.unwrap();
+            udp.send_to(&[0, 0x81, 0, 1, 0, 0, 0, 0], from)
+                .await
+                .unwrap();
+
+            let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], &[0, 0]);
+            let res = [0, 0x80, 0, 0, 0, 0, 0, 1, 198, 51, 100, 4];
+            udp.send_to(&res, from).await.unwrap();
+
+            let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 12);
+            assert_eq!(&buf[..6], &[0, 1, 0, 0, 0xca, 0x6c]);
+            let mut res = vec![0, 0x81, 0, 0, 0, 0, 0, 1];
+            res.extend(&buf[4..6]);
+            res.extend(51821u16.to_be_bytes());
+            res.extend(3600u32.to_be_bytes());
+            udp.send_to(&res, from).await.unwrap();
+
+            // refused
+            let (_, from) = udp.recv_from(&mut buf).await.unwrap();
+            let mut res = buf[..60].to_vec();
+            res[1] |= 0x80;
+            res[3] = 8;
+            udp.send_to(&res, from).await.unwrap();
+        });
+
+        let nonce = [7; 12];
+        let local: SocketAddrV4 = "127.0.0.1:51820".parse().unwrap();
+        assert_eq!(
+            request(server, 51820, 51820, &nonce).await.unwrap(),
+            Granted {
+                public: "203.0.113.7:40000".parse().unwrap(),
+                local,
+                lifetime: Duration::from_secs(600),
+            }
+        );
+        assert_eq!(
+            request(server, 51820, 51820, &nonce).await.unwrap(),
+            Granted {
+                public: "198.51.100.4:51821".parse().unwrap(),
+                local,
+                lifetime: Duration::from_secs(3600),
+            }
+        );
+        assert!(matches!(
+            request(server, 51820, 51820, &nonce).await,
+            Err(PcpError::Refused(8))
+        ));
+
+        router.await.unwrap();
+    }
+}
@@ -1,48 +1,352 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    os::fd::AsRawFd,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+use socket2::{Domain, Protocol, Socket, Type};
 use stunclient::StunClient;
 
-use super::Discover;
+use crate::retry::RetryPolicy;
 
-const DEFAULT_STUN_SERVER: &str = "stun.l.google.com:19302";
+use super::{
+    Discover, Mapping, local,
+    nat::{self, NatType},
+};
+
+/// Rotated through unless others are configured, in order of preference.
+pub const PUBLIC_STUN_SERVERS: &[&str] = &[
+    "stun.l.google.com:19302",
+    "stun.cloudflare.com:3478",
+    "stun1.l.google.com:19302",
+    "stun.nextcloud.com:3478",
+    "stun.sipgate.net:3478",
+    "stun2.l.google.com:19302",
+];
+
+/// Servers are skipped for this long after failing, doubling with every
+/// further failure.
+const BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// How long a server has to answer before the next one is asked.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
 
 #[derive(Debug, Clone)]
 pub struct StunDiscover {
-    server: SocketAddr,
-}
+    rotation: Arc<Rotation>,
 
-impl Default for StunDiscover {
-    fn default() -> Self {
-        Self::new(std::env::var("STUN_SERVER").unwrap_or_else(|_| DEFAULT_STUN_SERVER.into()))
-    }
+    /// IPv6 addresses of the servers, empty when none resolves to one.
+    rotation6: Arc<Rotation>,
+    retry: RetryPolicy,
+    device: Option<String>,
 }
 
 impl StunDiscover {
-    pub fn new(server: String) -> Self {
-        let server = server
-            .to_socket_addrs()
-            .unwrap()
-            .filter(|x| x.is_ipv4())
-            .next()
-            .unwrap();
+    pub fn new(server: &str) -> io::Result<Self> {
+        Self::rotate(&[server])
+    }
+
+    /// Rotates through `servers`, those not resolving are left out. Those
+    /// resolving to an IPv6 address as well are asked over IPv6 too. Fails
+    /// when none resolves to an IPv4 address.
+    pub fn rotate(servers: &[impl AsRef<str>]) -> io::Result<Self> {
+        let mut resolved = Vec::new();
+        let mut resolved6 = Vec::new();
+        for server in servers {
+            let server = server.as_ref();
+            match server.to_socket_addrs() {
+                Ok(addrs) => {
+                    let addrs: Vec<_> = addrs.collect();
+                    resolved.extend(addrs.iter().find(|addr| addr.is_ipv4()));
+                    resolved6.extend(addrs.iter().find(|addr| addr.is_ipv6()));
+                }
+                Err(err) => log::warn!("can't resolve stun server {server}: {err}"),
+            }
+        }
+
+        if resolved.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no stun server resolves to an ipv4 address",
+            ));
+        }
+
+        Ok(Self::with_servers(resolved, resolved6))
+    }
+
+    fn with_servers(servers: Vec<SocketAddr>, servers6: Vec<SocketAddr>) -> Self {
+        Self {
+            rotation: Arc::new(Rotation::new(servers)),
+            rotation6: Arc::new(Rotation::new(servers6)),
+            retry: RetryPolicy::none(),
+            device: None,
+        }
+    }
+
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// Queries leave through `device` unless another one is asked for.
+    pub fn with_device(self, device: Option<String>) -> Self {
+        Self { device, ..self }
+    }
+
+    /// Server the next query goes to.
+    pub fn server(&self) -> SocketAddr {
+        self.rotation.servers[self.rotation.pick(Instant::now())]
+    }
+
+    /// All servers queries may go to.
+    pub fn servers(&self) -> &[SocketAddr] {
+        &self.rotation.servers
+    }
+
+    /// Queries the servers healthiest first until one answers, with the
+    /// error of the last one when none does.
+    async fn query(
+        &self,
+        rotation: &Rotation,
+        port: u16,
+        device: Option<&str>,
+    ) -> Result<Mapping, stunclient::Error> {
+        let mut res = Err(stunclient::Error::NoAddress(()));
+
+        for i in rotation.order(Instant::now()) {
+            res = self.query_server(rotation.servers[i], port, device).await;
+            rotation.record(i, res.is_ok(), Instant::now());
+
+            if res.is_ok() {
+                break;
+            }
+        }
+
+        res
+    }
+
+    async fn query_server(
+        &self,
+        server: SocketAddr,
+        port: u16,
+        device: Option<&str>,
+    ) -> Result<Mapping, stunclient::Error> {
+        let udp = bind(server, port, device)
+            .await
+            .map_err(stunclient::Error::Socket)?;
+
+        let mut stun_client = StunClient::new(server);
+        stun_client.set_timeout(QUERY_TIMEOUT);
+        let public = stun_client.query_external_address_async(&udp).await?;
 
-        Self { server }
+        // a server answering with nonsense is as good as a silent one
+        if public.port() == 0
+            || public.ip().is_unspecified()
+            || public.ip().is_multicast()
+            || public.is_ipv4() != server.is_ipv4()
+        {
+            log::warn!("stun server {server} answered with bogus address {public}");
+            return Err(stunclient::Error::NoAddress(()));
+        }
+
+        // connecting resolves the source address the kernel picks for this route
+        udp.connect(server)
+            .await
+            .map_err(stunclient::Error::Socket)?;
+        let local = udp.local_addr().map_err(stunclient::Error::Socket)?;
+
+        Ok(Mapping { public, local })
     }
 }
 
 impl Discover for StunDiscover {
     type Error = stunclient::Error;
 
-    async fn discover(&self) -> Result<(SocketAddr, u16), Self::Error> {
-        let udp = tokio::net::UdpSocket::bind("0:0")
+    async fn discover(&self, port: u16) -> Result<Mapping, Self::Error> {
+        let device = self.device.as_deref();
+        self.retry
+            .retry("stun query", || self.query(&self.rotation, port, device))
             .await
-            .map_err(stunclient::Error::Socket)?;
+    }
+
+    async fn discover_via(&self, port: u16, device: &str) -> Result<Mapping, Self::Error> {
+        self.retry
+            .retry("stun query", || {
+                self.query(&self.rotation, port, Some(device))
+            })
+            .await
+    }
+
+    /// Not retried, IPv6 is a bonus on top of the IPv4 mapping.
+    async fn discover_v6(&self, port: u16) -> Option<Result<Mapping, Self::Error>> {
+        let device = self.device.as_deref();
+        if self.rotation6.servers.is_empty() || !local::has_global_v6(device) {
+            return None;
+        }
+
+        Some(self.query(&self.rotation6, port, device).await)
+    }
+
+    /// Servers are asked healthiest first, the filtering tests only work
+    /// with one supporting RFC 5780.
+    async fn nat_type(&self, port: u16) -> Option<Result<NatType, Self::Error>> {
+        let servers: Vec<_> = self
+            .rotation
+            .order(Instant::now())
+            .into_iter()
+            .map(|i| self.rotation.servers[i])
+            .collect();
+
+        let res = async {
+            let udp = bind(servers[0], port, self.device.as_deref()).await?;
+            nat::detect(&udp, &servers).await
+        };
+        Some(res.await.map_err(stunclient::Error::Socket))
+    }
+}
+
+/// Udp socket on `port` of the family of `server`, leaving through `device`
+/// when given. The device is bound first, so sockets of different uplinks
+/// share the port.
+async fn bind(
+    server: SocketAddr,
+    port: u16,
+    device: Option<&str>,
+) -> io::Result<tokio::net::UdpSocket> {
+    let any: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let addr = SocketAddr::new(any, port);
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if let Some(device) = device {
+        bind_device(&socket, device)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    tokio::net::UdpSocket::from_std(socket.into())
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Health {
+    /// Consecutive failures.
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// Servers with their health, shared by clones of a [`StunDiscover`].
+#[derive(Debug)]
+struct Rotation {
+    servers: Vec<SocketAddr>,
+    health: Mutex<Vec<Health>>,
+}
+
+impl Rotation {
+    fn new(servers: Vec<SocketAddr>) -> Self {
+        let health = Mutex::new(vec![Health::default(); servers.len()]);
+        Self { servers, health }
+    }
+
+    /// First server not backing off, or the one whose backoff ends first
+    /// when all are.
+    fn pick(&self, now: Instant) -> usize {
+        self.order(now).first().copied().unwrap_or(0)
+    }
+
+    /// Servers not backing off in order of preference, then those backing
+    /// off by when their backoff ends.
+    fn order(&self, now: Instant) -> Vec<usize> {
+        let health = self.health.lock().unwrap();
+
+        let mut order: Vec<usize> = (0..health.len()).collect();
+        order.sort_by_key(|&i| health[i].retry_at.filter(|&at| at > now));
+        order
+    }
+
+    fn record(&self, i: usize, ok: bool, now: Instant) {
+        let mut health = self.health.lock().unwrap();
+        let health = &mut health[i];
+
+        if ok {
+            if health.failures > 0 {
+                log::info!("stun server {} works again", self.servers[i]);
+            }
+            *health = Health::default();
+            return;
+        }
+
+        let backoff = BACKOFF
+            .saturating_mul(1 << health.failures.min(16))
+            .min(MAX_BACKOFF);
+        health.failures += 1;
+        health.retry_at = Some(now + backoff);
+
+        log::warn!(
+            "stun server {} failed, {} in a row, skipping it for {backoff:?}",
+            self.servers[i],
+            health.failures
+        );
+    }
+}
+
+/// `SO_BINDTODEVICE`, the socket only sends and receives via `device`.
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, device: &str) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr().cast(),
+            device.len() as libc::socklen_t,
+        )
+    };
+
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, _device: &str) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Rotation;
+
+    #[test]
+    fn test_rotation() {
+        let servers = vec![
+            "192.0.2.1:3478".parse().unwrap(),
+            "192.0.2.2:3478".parse().unwrap(),
+        ];
+        let rotation = Rotation::new(servers);
+        let now = Instant::now();
+
+        assert_eq!(rotation.pick(now), 0);
 
-        let local_port = udp.local_addr().map_err(stunclient::Error::Socket)?.port();
+        rotation.record(0, false, now);
+        assert_eq!(rotation.pick(now), 1);
+        assert_eq!(rotation.order(now), [1, 0]);
 
-        let stun_client = StunClient::new(self.server);
-        let addr = stun_client.query_external_address_async(&udp).await?;
+        // both backing off, the first ends sooner
+        rotation.record(1, false, now);
+        rotation.record(1, false, now);
+        assert_eq!(rotation.pick(now), 0);
 
-        Ok((addr, local_port))
+        // backoff ran out
+        assert_eq!(rotation.pick(now + Duration::from_secs(31)), 0);
+        rotation.record(0, true, now);
+        rotation.record(1, true, now);
+        assert_eq!(rotation.pick(now), 0);
     }
 }
@@ -0,0 +1,513 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use super::{Discover, Mapping, free_port, is_public, stun::StunDiscover};
+
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+const SEARCH_TARGETS: &[&str] = &[
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:2",
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:1",
+];
+
+/// Services able to map ports, in order of preference.
+const SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// Waiting for gateways to answer the search.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// One request to the gateway, connecting included.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest response read from the gateway, descriptions are a few kB.
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+/// Lease asked for, renewed by every rediscovery long before it runs out.
+const LEASE: Duration = Duration::from_secs(3600);
+
+/// Attempts to find an external port no other host has mapped.
+const MAX_ATTEMPTS: usize = 4;
+
+/// Mapping requested from the router with UPnP IGD, it survives without
+/// keepalives unlike one punched by STUN. Routers without UPnP, or behind
+/// another NAT, fall back to STUN.
+#[derive(Debug, Clone)]
+pub struct UpnpDiscover {
+    fallback: StunDiscover,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    gateway: Option<Gateway>,
+
+    /// External ports mapped to local ones, kept across renewals.
+    ports: HashMap<u16, u16>,
+}
+
+impl UpnpDiscover {
+    pub fn new(fallback: StunDiscover) -> Self {
+        Self {
+            fallback,
+            state: Default::default(),
+        }
+    }
+
+    pub fn fallback(&self) -> &StunDiscover {
+        &self.fallback
+    }
+
+    /// Mapping of the local udp `port` from the router alone, without
+    /// falling back to STUN.
+    pub async fn router_mapping(&self, port: u16) -> io::Result<Mapping> {
+        self.map(port).await.map_err(|err| {
+            // searched again next time, the router may have changed
+            self.state.lock().unwrap().gateway = None;
+            io::Error::other(err)
+        })
+    }
+
+    async fn gateway(&self) -> io::Result<Gateway> {
+        if let Some(gateway) = self.state.lock().unwrap().gateway.clone() {
+            return Ok(gateway);
+        }
+
+        let gateway = Gateway::search().await?;
+        log::info!("upnp gateway {} found", gateway.addr);
+        self.state.lock().unwrap().gateway = Some(gateway.clone());
+
+        Ok(gateway)
+    }
+
+    async fn map(&self, port: u16) -> Result<Mapping, UpnpError> {
+        let gateway = self.gateway().await?;
+
+        let external_ip = gateway.external_ip().await?;
+        if !is_public(external_ip) {
+            return Err(UpnpError::NotPublic(external_ip));
+        }
+
+        // the address the gateway reaches us at
+        let udp = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        udp.connect(gateway.addr)?;
+        let SocketAddr::V4(local) = udp.local_addr()? else {
+            return Err(io::Error::other("no ipv4 route to the gateway").into());
+        };
+        let local = SocketAddrV4::new(*local.ip(), port);
+
+        let external = self.state.lock().unwrap().ports.get(&port).copied();
+        let external = gateway
+            .add_mapping(local, external.unwrap_or(port), LEASE)
+            .await?;
+        self.state.lock().unwrap().ports.insert(port, external);
+
+        Ok(Mapping {
+            public: SocketAddr::V4(SocketAddrV4::new(external_ip, external)),
+            local: SocketAddr::V4(local),
+        })
+    }
+}
+
+impl Discover for UpnpDiscover {
+    type Error = stunclient::Error;
+
+    async fn discover(&self, port: u16) -> Result<Mapping, Self::Error> {
+        let port = match port {
+            0 => free_port().await.map_err(stunclient::Error::Socket)?,
+            port => port,
+        };
+
+        match self.router_mapping(port).await {
+            Ok(mapping) => Ok(mapping),
+            Err(err) => {
+                log::warn!("upnp mapping failed: {err}, falling back to stun");
+                self.fallback.discover(port).await
+            }
+        }
+    }
+
+    /// The gateway found belongs to the default route, other uplinks use
+    /// STUN.
+    async fn discover_via(&self, port: u16, device: &str) -> Result<Mapping, Self::Error> {
+        self.fallback.discover_via(port, device).await
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum UpnpError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("gateway error {0}: {1}")]
+    Fault(u16, String),
+
+    #[error("gateway address {0} is behind another nat")]
+    NotPublic(Ipv4Addr),
+}
+
+/// WAN connection service of an internet gateway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Gateway {
+    addr: SocketAddr,
+    control: String,
+    service: String,
+}
+
+impl Gateway {
+    /// First gateway answering the SSDP search with a usable description.
+    async fn search() -> io::Result<Self> {
+        let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        for target in SEARCH_TARGETS {
+            let msg = format!(
+                "M-SEARCH * HTTP/1.1\r\n\
+                 HOST: {SSDP_ADDR}\r\n\
+                 MAN: \"ssdp:discover\"\r\n\
+                 MX: 2\r\n\
+                 ST: {target}\r\n\r\n"
+            );
+            udp.send_to(msg.as_bytes(), SSDP_ADDR).await?;
+        }
+
+        let search = async {
+            let mut buf = [0u8; 2048];
+            loop {
+                let (len, from) = udp.recv_from(&mut buf).await?;
+                let response = String::from_utf8_lossy(&buf[..len]);
+                let Some(location) = header(&response, "location") else {
+                    continue;
+                };
+
+                match Self::fetch(location).await {
+                    Ok(gateway) => return Ok(gateway),
+                    Err(err) => log::debug!("upnp device {from} at {location}: {err}"),
+                }
+            }
+        };
+
+        tokio::time::timeout(SEARCH_TIMEOUT, search)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no upnp gateway answered"))?
+    }
+
+    /// Reads the device description at `location`.
+    async fn fetch(location: &str) -> io::Result<Self> {
+        let (addr, path) = parse_url(location)
+            .ok_or_else(|| io::Error::other(format!("bad location {location}")))?;
+
+        let request = format!("GET {path} HTTP/1.0\r\nHost: {addr}\r\n\r\n");
+        let (status, body) = http(addr, &request).await?;
+        if status != 200 {
+            return Err(io::Error::other(format!("description answered {status}")));
+        }
+
+        parse_description(addr, &body).ok_or_else(|| io::Error::other("no wan connection service"))
+    }
+
+    async fn external_ip(&self) -> Result<Ipv4Addr, UpnpError> {
+        let body = self.soap("GetExternalIPAddress", &[]).await?;
+
+        tag(&body, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(|| io::Error::other("no external address").into())
+    }
+
+    /// Maps `external` to `local`, another external port is picked when a
+    /// different host has it. Returns the external port mapped.
+    async fn add_mapping(
+        &self,
+        local: SocketAddrV4,
+        mut external: u16,
+        lease: Duration,
+    ) -> Result<u16, UpnpError> {
+        let mut lease = lease.as_secs();
+
+        for _ in 0..MAX_ATTEMPTS {
+            let args = [
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external.to_string()),
+                ("NewProtocol", "UDP".into()),
+                ("NewInternalPort", local.port().to_string()),
+                ("NewInternalClient", local.ip().to_string()),
+                ("NewEnabled", "1".into()),
+                (
+                    "NewPortMappingDescription",
+                    format!("wg-disco {}", local.port()),
+                ),
+                ("NewLeaseDuration", lease.to_string()),
+            ];
+
+            match self.soap("AddPortMapping", &args).await {
+                Ok(_) => return Ok(external),
+
+                // OnlyPermanentLeasesSupported
+                Err(UpnpError::Fault(725, _)) if lease != 0 => lease = 0,
+
+                // ConflictInMappingEntry
+                Err(UpnpError::Fault(718, _)) => external = rand::random_range(1024..=65535),
+
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(io::Error::other("no free external port").into())
+    }
+
+    async fn soap(&self, action: &str, args: &[(&str, String)]) -> Result<String, UpnpError> {
+        let service = &self.service;
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+            .collect();
+
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body>\
+             </s:Envelope>\r\n"
+        );
+        let request = format!(
+            "POST {} HTTP/1.0\r\n\
+             Host: {}\r\n\
+             Content-Type: text/xml; charset=\"utf-8\"\r\n\
+             SOAPAction: \"{service}#{action}\"\r\n\
+             Content-Length: {}\r\n\r\n{body}",
+            self.control,
+            self.addr,
+            body.len()
+        );
+
+        match http(self.addr, &request).await? {
+            (200, body) => Ok(body),
+            (status, body) => Err(match tag(&body, "errorCode") {
+                Some(code) => UpnpError::Fault(
+                    code.parse().unwrap_or_default(),
+                    tag(&body, "errorDescription").unwrap_or_default().into(),
+                ),
+                None => io::Error::other(format!("{action} answered {status}")).into(),
+            }),
+        }
+    }
+}
+
+/// Sends `request` and reads the status and body of the response. HTTP/1.0,
+/// the body is never chunked and ends with the connection.
+async fn http(addr: SocketAddr, request: &str) -> io::Result<(u16, String)> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream
+            .take(MAX_RESPONSE_LEN)
+            .read_to_end(&mut response)
+            .await?;
+        Ok::<_, io::Error>(response)
+    };
+
+    let response = tokio::time::timeout(HTTP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "upnp gateway timed out"))??;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| io::Error::other("truncated http response"))?;
+
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::other("bad http status"))?;
+
+    Ok((status, body.to_string()))
+}
+
+/// `http://host:port/path` with `host` an address, as gateways give them.
+fn parse_url(url: &str) -> Option<(SocketAddr, String)> {
+    let rest = url.trim().strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+
+    let addr = match host.parse() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::new(host.parse().ok()?, 80),
+    };
+
+    Some((addr, path.to_string()))
+}
+
+/// Best service of a device description served by `addr`.
+fn parse_description(addr: SocketAddr, xml: &str) -> Option<Gateway> {
+    let base = tag(xml, "URLBase").and_then(parse_url);
+
+    let mut services: Vec<(usize, &str, &str)> = xml
+        .split("<service>")
+        .skip(1)
+        .filter_map(|service| {
+            let service = service.split("</service>").next()?;
+            let kind = tag(service, "serviceType")?;
+            let rank = SERVICES.iter().position(|&s| s == kind)?;
+            Some((rank, kind, tag(service, "controlURL")?))
+        })
+        .collect();
+    services.sort_by_key(|&(rank, ..)| rank);
+
+    let &(_, service, control) = services.first()?;
+    let (addr, control) = match control.starts_with("http://") {
+        true => parse_url(control)?,
+        false => {
+            let addr = base.map_or(addr, |(base, _)| base);
+            match control.starts_with('/') {
+                true => (addr, control.to_string()),
+                false => (addr, format!("/{control}")),
+            }
+        }
+    };
+
+    Some(Gateway {
+        addr,
+        control,
+        service: service.to_string(),
+    })
+}
+
+/// Text of the first `<name>` element, namespace prefixes aren't matched.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let len = xml[start..].find(&format!("</{name}>"))?;
+
+    Some(xml[start..start + len].trim())
+}
+
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddrV4;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::discover::is_public;
+
+    use super::{Gateway, UpnpError, parse_description};
+
+    const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+<controlURL>/ctl/L3F</controlURL>
+</service>
+</serviceList>
+<deviceList><device><deviceList><device>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>
+<controlURL>/ctl/PPP</controlURL>
+</service>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+<controlURL>ctl/IPConn</controlURL>
+</service>
+</serviceList>
+</device></deviceList></device></deviceList>
+</device>
+</root>"#;
+
+    #[test]
+    fn test_description() {
+        let addr = "192.168.1.1:5000".parse().unwrap();
+        assert_eq!(
+            parse_description(addr, DESCRIPTION),
+            Some(Gateway {
+                addr,
+                control: "/ctl/IPConn".into(),
+                service: "urn:schemas-upnp-org:service:WANIPConnection:1".into(),
+            })
+        );
+
+        let based = DESCRIPTION.replace(
+            "<device>\n<deviceType>",
+            "<URLBase>http://192.168.1.1:49000/</URLBase><device>\n<deviceType>",
+        );
+        let gateway = parse_description(addr, &based).unwrap();
+        assert_eq!(gateway.addr, "192.168.1.1:49000".parse().unwrap());
+
+        assert!(!is_public("100.100.0.1".parse().unwrap()));
+        assert!(!is_public("10.0.0.1".parse().unwrap()));
+        assert!(is_public("203.0.113.7".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_add_mapping() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gateway = Gateway {
+            addr: listener.local_addr().unwrap(),
+            control: "/ctl/IPConn".into(),
+            service: "urn:schemas-upnp-org:service:WANIPConnection:1".into(),
+        };
+
+        let router = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for answer in [
+                "HTTP/1.0 500 Internal Server Error\r\n\r\n<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                 <errorCode>725</errorCode><errorDescription>OnlyPermanentLeasesSupported</errorDescription>\
+                 </UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+                "HTTP/1.0 200 OK\r\n\r\n<s:Envelope><s:Body><u:AddPortMappingResponse/></s:Body></s:Envelope>",
+                "HTTP/1.0 500 Internal Server Error\r\n\r\n<UPnPError><errorCode>501</errorCode>\
+                 <errorDescription>ActionFailed</errorDescription></UPnPError>",
+            ] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let len = stream.read(&mut request).await.unwrap();
+                requests.push(String::from_utf8_lossy(&request[..len]).into_owned());
+                stream.write_all(answer.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        let local = SocketAddrV4::new([192, 168, 1, 10].into(), 51820);
+        let lease = std::time::Duration::from_secs(3600);
+        assert_eq!(
+            gateway.add_mapping(local, 51820, lease).await.unwrap(),
+            51820
+        );
+        assert!(matches!(
+            gateway.add_mapping(local, 51820, lease).await,
+            Err(UpnpError::Fault(501, _))
+        ));
+
+        let requests = router.await.unwrap();
+        assert!(requests[0].starts_with("POST /ctl/IPConn HTTP/1.0\r\n"));
+        assert!(requests[0].contains("<NewLeaseDuration>3600</NewLeaseDuration>"));
+        assert!(requests[1].contains("<NewLeaseDuration>0</NewLeaseDuration>"));
+        assert!(requests[1].contains("<NewInternalClient>192.168.1.10</NewInternalClient>"));
+    }
+}
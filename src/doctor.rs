@@ -0,0 +1,413 @@
+//! `wg-disco doctor`: checks of what most often keeps nodes from finding
+//! each other, each with a hint what to do about it.
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    process::Command,
+    time::{Duration, SystemTime},
+};
+
+use stunclient::StunClient;
+use tokio::net::UdpSocket;
+
+use crate::{
+    dial,
+    discover::Mapping,
+    route,
+    wg::{WgBackendKind, config::WgConfig},
+};
+
+/// Two servers at different addresses tell endpoint dependent mappings
+/// apart.
+const STUN_SERVERS: [&str; 2] = ["stun.l.google.com:19302", "stun.cloudflare.com:3478"];
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+
+    /// What to do about it, for warnings and failures.
+    pub hint: Option<&'static str>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            check,
+            severity: Severity::Ok,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(check: &'static str, message: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            check,
+            severity: Severity::Warn,
+            message: message.into(),
+            hint: Some(hint),
+        }
+    }
+
+    fn fail(check: &'static str, message: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            check,
+            severity: Severity::Fail,
+            message: message.into(),
+            hint: Some(hint),
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tag = match self.severity {
+            Severity::Ok => " ok ",
+            Severity::Warn => "warn",
+            Severity::Fail => "FAIL",
+        };
+        write!(f, "[{tag}] {}: {}", self.check, self.message)?;
+
+        if let Some(hint) = self.hint {
+            write!(f, "\n       {hint}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs every check, NAT checks stop at the first STUN failure.
+pub async fn run(iface: &str, irc_servers: &[String]) -> Vec<Finding> {
+    let mut findings = vec![check_wg(iface), check_interface(iface)];
+    findings.extend(check_nat().await);
+
+    for server in irc_servers {
+        findings.push(check_irc(server).await);
+    }
+
+    findings.push(check_clock());
+    findings
+}
+
+/// Checks run by the daemon before it starts, only failures are returned.
+/// Interface checks are left out when it only observes the mesh, the `wg`
+/// binary is only needed by the cmd backend.
+pub async fn preflight(
+    iface: &str,
+    config: &WgConfig,
+    stun: SocketAddr,
+    observe: bool,
+    backend: WgBackendKind,
+) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    if !observe {
+        if backend == WgBackendKind::Cmd {
+            findings.push(check_wg(iface));
+        }
+        findings.push(check_interface(iface));
+    }
+
+    findings.extend(check_allowed_ips(config));
+    findings.push(check_udp(stun).await);
+
+    findings.retain(|finding| finding.severity == Severity::Fail);
+    findings
+}
+
+fn check_interface(iface: &str) -> Finding {
+    match route::query::if_index(iface) {
+        Some(_) => Finding::ok("interface", format!("{iface} exists")),
+        None => Finding::fail(
+            "interface",
+            format!("{iface} doesn't exist"),
+            "bring it up first, e.g. `wg-quick up <iface>`",
+        ),
+    }
+}
+
+/// Wireguard sends nothing to a peer without AllowedIPs.
+fn check_allowed_ips(config: &WgConfig) -> Vec<Finding> {
+    config
+        .peers
+        .iter()
+        .filter(|peer| peer.allowed_ips.as_ref().is_none_or(Vec::is_empty))
+        .map(|peer| {
+            Finding::fail(
+                "config",
+                format!("peer {} has no AllowedIPs", peer.public_key),
+                "add AllowedIPs to the peer in the wireguard config",
+            )
+        })
+        .collect()
+}
+
+/// Outgoing UDP towards the STUN server is routed and not refused locally,
+/// an answer is not waited for.
+async fn check_udp(server: SocketAddr) -> Finding {
+    let hint = "check the default route and that the firewall lets outgoing UDP through";
+
+    let res = async {
+        let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        udp.connect(server).await?;
+        udp.send(&[]).await
+    };
+
+    match res.await {
+        Ok(_) => Finding::ok("udp", format!("can send to {server}")),
+        Err(err) => Finding::fail("udp", format!("can't send to {server}: {err}"), hint),
+    }
+}
+
+fn check_wg(iface: &str) -> Finding {
+    let version = match Command::new("wg").arg("--version").output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        Err(err) => {
+            return Finding::fail(
+                "wg",
+                format!("can't run wg: {err}"),
+                "install wireguard-tools, wg has to be in PATH",
+            );
+        }
+    };
+
+    match Command::new("wg")
+        .args(["show", iface, "private-key"])
+        .output()
+    {
+        Ok(output) if output.status.success() => Finding::ok("wg", version),
+        Ok(output) if String::from_utf8_lossy(&output.stderr).contains("not permitted") => {
+            Finding::fail(
+                "wg",
+                "not permitted to query the interface",
+                "run as root or with CAP_NET_ADMIN",
+            )
+        }
+        // a missing interface is reported by its own check
+        _ => Finding::ok("wg", version),
+    }
+}
+
+async fn stun_query(udp: &UdpSocket, server: &str) -> Result<SocketAddr, String> {
+    let addr = tokio::net::lookup_host(server)
+        .await
+        .map_err(|err| format!("can't resolve {server}: {err}"))?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| format!("{server} has no IPv4 address"))?;
+
+    let query = StunClient::new(addr).query_external_address_async(udp);
+    match tokio::time::timeout(TIMEOUT, query).await {
+        Ok(Ok(public)) => Ok(public),
+        Ok(Err(err)) => Err(format!("{server}: {err}")),
+        Err(_) => Err(format!("{server} doesn't answer")),
+    }
+}
+
+/// UDP egress, NAT type and stability of the mapping, all from one local
+/// port like wireguard uses.
+async fn check_nat() -> Vec<Finding> {
+    let udp = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+        Ok(udp) => udp,
+        Err(err) => {
+            return vec![Finding::fail(
+                "stun",
+                format!("can't bind a UDP socket: {err}"),
+                "check local firewall and sandboxing of the service",
+            )];
+        }
+    };
+
+    let first = match stun_query(&udp, STUN_SERVERS[0]).await {
+        Ok(public) => public,
+        Err(err) => {
+            return vec![Finding::fail(
+                "stun",
+                err,
+                "outgoing UDP seems blocked, allow it or run a server node with --server",
+            )];
+        }
+    };
+
+    let local = match local_addr(&udp, STUN_SERVERS[0]).await {
+        Some(local) => local,
+        None => udp.local_addr().unwrap_or(first),
+    };
+    let mapping = Mapping {
+        public: first,
+        local,
+    };
+    let mut findings = vec![Finding::ok("stun", format!("discovered mapping {mapping}"))];
+
+    findings.push(match stun_query(&udp, STUN_SERVERS[1]).await {
+        Err(err) => Finding::warn(
+            "nat",
+            format!("can't tell the NAT type, {err}"),
+            "a second STUN server is needed to detect symmetric NAT",
+        ),
+        Ok(_) if first.ip() == local.ip() => Finding::ok("nat", "no NAT, the address is public"),
+        Ok(second) if second == first => {
+            Finding::ok("nat", "endpoint independent mapping, hole punching works")
+        }
+        Ok(second) => Finding::warn(
+            "nat",
+            format!("symmetric NAT, mapped to {first} and {second} for two servers"),
+            "direct connections are unlikely, add a [[transport]] or a server peer",
+        ),
+    });
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    findings.push(match stun_query(&udp, STUN_SERVERS[0]).await {
+        Ok(again) if again != first => Finding::warn(
+            "mapping",
+            format!("mapping changed from {first} to {again} within a second"),
+            "the NAT rebinds quickly, peers need keepalives and may still flap",
+        ),
+        Ok(_) if first.port() != local.port() => Finding::ok(
+            "mapping",
+            format!(
+                "stable, the NAT rewrites port {} to {}",
+                local.port(),
+                first.port()
+            ),
+        ),
+        Ok(_) => Finding::ok("mapping", "stable and port preserving"),
+        Err(err) => Finding::warn(
+            "mapping",
+            err,
+            "the STUN server stopped answering, rerun the doctor",
+        ),
+    });
+
+    findings
+}
+
+/// Source address the kernel picks towards `server`.
+async fn local_addr(udp: &UdpSocket, server: &str) -> Option<SocketAddr> {
+    let addr = tokio::net::lookup_host(server)
+        .await
+        .ok()?
+        .find(SocketAddr::is_ipv4)?;
+
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.ok()?;
+    probe.connect(addr).await.ok()?;
+
+    let port = udp.local_addr().ok()?.port();
+    Some(SocketAddr::new(probe.local_addr().ok()?.ip(), port))
+}
+
+async fn check_irc(server: &str) -> Finding {
+    let (host, port) = server.rsplit_once(':').unwrap_or((server, "6667"));
+    let port: u16 = port.parse().unwrap_or(6667);
+
+    match tokio::time::timeout(TIMEOUT, dial::connect(host, port)).await {
+        Ok(Ok(_)) => Finding::ok("irc", format!("{server} is reachable")),
+        Ok(Err(err)) => Finding::fail(
+            "irc",
+            format!("can't connect to {server}: {err}"),
+            "allow outgoing TCP to the IRC server or pick another with --irc-server",
+        ),
+        Err(_) => Finding::fail(
+            "irc",
+            format!("{server} doesn't answer"),
+            "allow outgoing TCP to the IRC server or pick another with --irc-server",
+        ),
+    }
+}
+
+/// Announcements carry timestamps, peers drop them when the clock is off.
+fn check_clock() -> Finding {
+    let installed = std::env::current_exe()
+        .and_then(std::fs::metadata)
+        .and_then(|meta| meta.modified())
+        .ok();
+
+    if let Some(installed) = installed
+        && SystemTime::now() < installed
+    {
+        return Finding::fail(
+            "clock",
+            "the clock is behind the time wg-disco was installed",
+            "enable time synchronisation (NTP), peers drop stale announcements",
+        );
+    }
+
+    match synchronized() {
+        Some(false) => Finding::warn(
+            "clock",
+            "the kernel reports the clock as unsynchronised",
+            "enable time synchronisation (NTP), peers drop stale announcements",
+        ),
+        _ => Finding::ok("clock", "synchronised"),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn synchronized() -> Option<bool> {
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    let state = unsafe { libc::adjtimex(&mut timex) };
+
+    (state >= 0).then_some(state != libc::TIME_ERROR)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn synchronized() -> Option<bool> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wg::config::WgConfig;
+
+    use super::{Finding, Severity, check_allowed_ips};
+
+    #[test]
+    fn test_finding_display() {
+        let ok = Finding::ok("wg", "wireguard-tools v1.0.20210914");
+        assert_eq!(ok.to_string(), "[ ok ] wg: wireguard-tools v1.0.20210914");
+
+        let warn = Finding::warn("nat", "symmetric NAT", "add a server peer");
+        assert_eq!(warn.severity, Severity::Warn);
+        assert_eq!(
+            warn.to_string(),
+            "[warn] nat: symmetric NAT\n       add a server peer"
+        );
+    }
+
+    #[test]
+    fn test_check_allowed_ips() {
+        let config = WgConfig::parse_config(
+            &mut "[Interface]
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+Address = 10.0.0.1/24
+
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+AllowedIPs = 10.0.0.2/32
+
+[Peer]
+PublicKey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+",
+        )
+        .unwrap();
+
+        let failed = check_allowed_ips(&config);
+        assert_eq!(failed.len(), 1);
+        assert!(
+            failed[0]
+                .message
+                .contains("TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=")
+        );
+    }
+}
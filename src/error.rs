@@ -1,5 +1,6 @@
 use crate::wg::config::ParseError;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("parse error: {0}")]
@@ -8,9 +9,13 @@ pub enum Error {
     #[error("io error: {0}")]
     IoError(#[from] std::io::Error),
 
-    #[error("wg cmd fail: {0:?}")]
-    WgCommandFail(Option<i32>),
+    #[error("wg cmd fail: {0:?} {1}")]
+    WgCommandFail(Option<i32>, String),
 
+    #[error("{0} fail: {1:?}")]
+    CommandFail(&'static str, Option<i32>),
+
+    #[cfg(feature = "irc")]
     #[error("irc error: {0}")]
     IrcError(#[from] irc::error::Error),
 
@@ -20,9 +25,208 @@ pub enum Error {
     #[error("base64 decode error: {0}")]
     Base64DecodeError(#[from] base64::DecodeError),
 
+    #[error("message too long")]
+    MessageTooLong,
+
+    #[error("sealed message can't be opened, do all peers share the psk?")]
+    SealMismatch,
+
     #[error("decode error: {0}")]
     DecodeError(#[from] bincode::error::DecodeError),
 
     #[error("stun error: {0}")]
     StunError(#[from] stunclient::Error),
+
+    #[error(
+        "ListenPort {0} doesn't match the discovered mapping {1}, \
+         remove ListenPort or use --port-mismatch rediscover"
+    )]
+    PortMismatch(u16, crate::discover::Mapping),
+
+    #[error("dns update failed, rcode {0}")]
+    DnsUpdateFail(u8),
+
+    #[error("config error: {0}")]
+    ConfigError(#[from] toml::de::Error),
+
+    #[error("no signaling backend connected")]
+    NoSignaling,
+
+    #[error("the [api] config section is missing, the daemon api is disabled")]
+    NoApi,
+
+    #[error("no api token in the config nor in the wg-disco-api-token credential")]
+    NoApiToken,
+
+    #[error("can't read {0}: {1}")]
+    ReadConfig(String, std::io::Error),
+
+    #[error("interface {0} doesn't exist")]
+    NoInterface(String),
+
+    #[error("{0} of {1} signaling backends unreachable")]
+    PartialSignaling(usize, usize),
+
+    #[error("can't read secret key {0:?}: {1}")]
+    SecretKey(crate::secret::KeySource, std::io::Error),
+
+    #[error("encrypted config value is corrupted or encrypted with another key")]
+    SecretMismatch,
+
+    #[error("{0} doctor checks failed")]
+    DoctorFailed(usize),
+
+    #[error("{0} config problems found")]
+    CheckFailed(usize),
+
+    #[error("{0} preflight checks failed")]
+    PreflightFailed(usize),
+
+    #[error("daemon api answered {0}")]
+    ExplainFailed(u16),
+
+    #[error("the [provision] config section is missing, it holds the client templates")]
+    NoProvision,
+
+    #[error("can't add client: {0}")]
+    GenClient(#[from] crate::provision::GenClientError),
+
+    #[error("networks {0} and {1} share {2}")]
+    NotIsolated(String, String, &'static str),
+
+    #[error(
+        "--obfuscate can't tell who sent an announcement, set --irc-psk or drop --allow-unsigned"
+    )]
+    UnauthenticatedObfuscation,
+}
+
+impl From<std::convert::Infallible> for Error {
+    fn from(err: std::convert::Infallible) -> Self {
+        match err {}
+    }
+}
+
+/// Process exit codes, stable so scripts and systemd units can tell
+/// failure classes apart. `2` is taken by usage errors.
+pub mod exit {
+    pub const FAILURE: u8 = 1;
+    pub const CONFIG: u8 = 3;
+    pub const NO_INTERFACE: u8 = 4;
+    pub const SIGNALING: u8 = 5;
+    pub const DISCOVERY: u8 = 6;
+    pub const PARTIAL: u8 = 7;
+}
+
+/// Messages of failures which go away on their own: a resolver not
+/// reachable yet while the network comes up, a busy device.
+const TRANSIENT: [&str; 5] = [
+    "Temporary failure in name resolution",
+    "Try again",
+    "Resource temporarily unavailable",
+    "Device or resource busy",
+    "No buffer space available",
+];
+
+impl Error {
+    /// Worth retrying, see [`TRANSIENT`].
+    pub fn is_transient(&self) -> bool {
+        let message = match self {
+            Error::IoError(err) => match err.kind() {
+                std::io::ErrorKind::Interrupted
+                | std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::WouldBlock => return true,
+                _ => err.to_string(),
+            },
+            Error::WgCommandFail(_, stderr) => stderr.clone(),
+            _ => return false,
+        };
+
+        TRANSIENT
+            .iter()
+            .any(|transient| message.contains(transient))
+    }
+
+    /// The message was fine but didn't get through: the connection to the
+    /// signaling server failed or isn't up yet. Sending it again may work.
+    pub fn is_send_failure(&self) -> bool {
+        match self {
+            Error::IoError(_) | Error::NoSignaling => true,
+            #[cfg(feature = "irc")]
+            Error::IrcError(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The interface an operation was on doesn't exist: wg, the kernel or
+    /// the userspace implementation had no such device.
+    pub fn is_no_device(&self) -> bool {
+        match self {
+            Error::IoError(err) => err.raw_os_error() == Some(libc::ENODEV),
+            Error::WgCommandFail(_, stderr) => stderr.contains("No such device"),
+            _ => false,
+        }
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::ParseError(_)
+            | Error::ConfigError(_)
+            | Error::CheckFailed(_)
+            | Error::NoApi
+            | Error::NoApiToken
+            | Error::NoProvision
+            | Error::NotIsolated(..)
+            | Error::UnauthenticatedObfuscation
+            | Error::ReadConfig(..)
+            | Error::SecretKey(..)
+            | Error::SecretMismatch => exit::CONFIG,
+            Error::NoInterface(_) => exit::NO_INTERFACE,
+            #[cfg(feature = "irc")]
+            Error::IrcError(_) => exit::SIGNALING,
+            Error::NoSignaling | Error::DnsUpdateFail(_) => exit::SIGNALING,
+            Error::StunError(_) | Error::PortMismatch(..) => exit::DISCOVERY,
+            Error::PartialSignaling(..) => exit::PARTIAL,
+            _ => exit::FAILURE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{Error, exit};
+
+    #[test]
+    fn test_exit_code() {
+        let codes = [
+            (Error::NoInterface("wg0".into()), exit::NO_INTERFACE),
+            (Error::NoApiToken, exit::CONFIG),
+            (Error::UnauthenticatedObfuscation, exit::CONFIG),
+            (Error::NoSignaling, exit::SIGNALING),
+            (Error::DnsUpdateFail(5), exit::SIGNALING),
+            (Error::PartialSignaling(1, 2), exit::PARTIAL),
+            (Error::MessageTooLong, exit::FAILURE),
+            (
+                Error::from(io::Error::from(io::ErrorKind::PermissionDenied)),
+                exit::FAILURE,
+            ),
+        ];
+
+        for (err, code) in codes {
+            assert_eq!(err.exit_code(), code, "{err}");
+        }
+    }
+
+    #[test]
+    fn test_is_no_device() {
+        let stderr = "Unable to access interface: No such device\n";
+        assert!(Error::WgCommandFail(Some(1), stderr.into()).is_no_device());
+        assert!(Error::from(io::Error::from_raw_os_error(libc::ENODEV)).is_no_device());
+
+        // missing permissions or tools are no reason to look for the interface
+        let stderr = "Unable to access interface: Operation not permitted\n";
+        assert!(!Error::WgCommandFail(Some(1), stderr.into()).is_no_device());
+        assert!(!Error::from(io::Error::from(io::ErrorKind::NotFound)).is_no_device());
+    }
 }
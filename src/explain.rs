@@ -0,0 +1,325 @@
+//! `wg-disco explain`: what the runner knows about a peer and why it is
+//! down, with what to try next.
+
+use std::{fmt, net::SocketAddr};
+
+use crate::{
+    discover::nat::NatType,
+    json::Value,
+    runner::{Candidate, PunchProgress},
+    wg::Key,
+};
+
+/// Snapshot of the decision trail of one peer, ages in seconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub key: Key,
+
+    /// A `[Peer]` of the wireguard config.
+    pub configured: bool,
+    pub up: bool,
+    pub handshake_age: Option<u64>,
+    pub endpoint: Option<String>,
+
+    /// Since its last announcement reached us.
+    pub announce_age: Option<u64>,
+
+    /// Its signaling nickname is known, targeted messages reach it.
+    pub online: bool,
+    pub peer_nat: Option<bool>,
+    pub our_nat: Option<bool>,
+    pub peer_nat_type: Option<NatType>,
+    pub our_nat_type: Option<NatType>,
+    pub punch: Option<PunchProgress>,
+
+    /// Candidate which worked last time.
+    pub hint: Option<Candidate>,
+
+    /// Server peers usable as relays.
+    pub relays: usize,
+
+    /// Transport helper the peer is reached through.
+    pub transport: Option<String>,
+    pub pinned: Option<SocketAddr>,
+    pub blocked: bool,
+    pub revoked: bool,
+    pub frozen: bool,
+}
+
+/// A finding and what to do about it.
+type Reason = (String, &'static str);
+
+impl Explanation {
+    /// Why the peer is down, most fundamental first. Empty while it is up.
+    pub fn reasons(&self) -> Vec<Reason> {
+        let mut reasons: Vec<Reason> = Vec::new();
+        if self.up {
+            return reasons;
+        }
+
+        if !self.configured {
+            reasons.push((
+                "it is not a peer of the wireguard config".into(),
+                "add a [Peer] section with its key and AllowedIPs",
+            ));
+        }
+        if self.revoked {
+            reasons.push((
+                "its key is revoked".into(),
+                "the device needs a new key, revocations are permanent",
+            ));
+        } else if self.blocked {
+            reasons.push((
+                "it is blocked, its announcements are ignored".into(),
+                "unblock it with DELETE /v1/block if it is trusted again",
+            ));
+        }
+        if self.frozen {
+            reasons.push((
+                "changes are frozen, endpoints are not applied".into(),
+                "resume with DELETE /v1/freeze",
+            ));
+        }
+        if let Some(pinned) = self.pinned {
+            reasons.push((
+                format!("its endpoint is pinned to {pinned}, announcements are ignored"),
+                "remove the pin with DELETE /v1/pin if the address is stale",
+            ));
+        }
+
+        match (self.announce_age, self.online) {
+            (None, _) => reasons.push((
+                "no announcement of it was seen".into(),
+                "check that its daemon runs and reaches the same signaling servers",
+            )),
+            (Some(age), false) => reasons.push((
+                format!("its last announcement is {age}s old and it left signaling"),
+                "check that its daemon runs and has connectivity",
+            )),
+            (Some(_), true) => {}
+        }
+
+        match &self.punch {
+            Some(punch) if punch.exhausted => {
+                reasons.push((
+                    format!(
+                        "all {} candidates were tried without a handshake",
+                        punch.candidates.len()
+                    ),
+                    match (self.relays, &self.transport) {
+                        (_, Some(_)) => "the relay transport doesn't work either, check the helper",
+                        (0, None) => "add a server peer or a [[transport]] to relay through",
+                        (_, None) => {
+                            "a relay is tried once punching gives up, check the server peers"
+                        }
+                    },
+                ));
+            }
+            Some(punch) => reasons.push((
+                format!(
+                    "punching is in progress, attempt {} on the {} candidate",
+                    punch.attempt + 1,
+                    punch.candidates[punch.current].0
+                ),
+                "wait for the remaining attempts",
+            )),
+            None if self.announce_age.is_some() && self.handshake_age.is_none() => {
+                reasons.push((
+                    "it was announced but never completed a handshake".into(),
+                    "check AllowedIPs and keys on both sides, POST /v1/probe tests the path",
+                ));
+            }
+            None => {}
+        }
+
+        if let (Some(theirs), Some(ours)) = (self.peer_nat_type, self.our_nat_type)
+            && !ours.punchable(theirs)
+        {
+            reasons.push((
+                format!("its NAT is {theirs} and ours {ours}, punching can't work"),
+                "add a server peer or a [[transport]] to relay through",
+            ));
+        } else if self.peer_nat == Some(true) && self.our_nat == Some(true) {
+            reasons.push((
+                "both sides are behind NAT".into(),
+                "symmetric NATs can't be punched through, `wg-disco doctor` tells the NAT type",
+            ));
+        }
+
+        if reasons.is_empty() {
+            reasons.push((
+                "nothing obvious, the handshake just stopped".into(),
+                "POST /v1/debug logs what happens with the peer",
+            ));
+        }
+
+        reasons
+    }
+
+    pub fn to_json(&self) -> Value {
+        let reasons = self.reasons();
+
+        Value::object([
+            ("key", Value::from(self.key.to_string())),
+            ("up", Value::from(self.up)),
+            ("handshake_age", Value::from(self.handshake_age)),
+            ("announce_age", Value::from(self.announce_age)),
+            (
+                "reasons",
+                Value::Array(reasons.iter().map(|(why, _)| why.as_str().into()).collect()),
+            ),
+            (
+                "steps",
+                Value::Array(reasons.iter().map(|(_, step)| (*step).into()).collect()),
+            ),
+        ])
+    }
+}
+
+fn age(age: Option<u64>) -> String {
+    match age {
+        Some(age) => format!("{age}s ago"),
+        None => "never".into(),
+    }
+}
+
+fn nat(nat: Option<bool>, nat_type: Option<NatType>) -> String {
+    match (nat, nat_type) {
+        (_, Some(NatType::Open)) | (Some(false), _) => "public".into(),
+        (_, Some(nat_type)) => format!("behind {nat_type} NAT"),
+        (Some(true), None) => "behind NAT".into(),
+        (None, None) => "unknown".into(),
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.up { "up" } else { "down" };
+        writeln!(f, "peer {} is {state}", self.key)?;
+
+        write!(f, "  handshake     {}", age(self.handshake_age))?;
+        match &self.endpoint {
+            Some(endpoint) => writeln!(f, " via {endpoint}")?,
+            None => writeln!(f)?,
+        }
+
+        let online = if self.online {
+            "on signaling"
+        } else {
+            "not on signaling"
+        };
+        writeln!(f, "  announcement  {}, {online}", age(self.announce_age))?;
+        writeln!(
+            f,
+            "  nat           peer {}, we are {}",
+            nat(self.peer_nat, self.peer_nat_type),
+            nat(self.our_nat, self.our_nat_type)
+        )?;
+
+        if let Some(punch) = &self.punch {
+            writeln!(f, "  candidates")?;
+            for (i, (kind, addr)) in punch.candidates.iter().enumerate() {
+                let tried = punch.attempt as usize >= i;
+                let state = match i == punch.current {
+                    true if punch.exhausted => "last attempt",
+                    true => "trying",
+                    false if tried => "no handshake",
+                    false => "not tried yet",
+                };
+                writeln!(f, "    {:<5} {addr} {state}", kind.to_string())?;
+            }
+        }
+        if let Some(hint) = self.hint {
+            writeln!(f, "  worked before {hint}")?;
+        }
+
+        let transport = self.transport.as_deref().unwrap_or("none");
+        writeln!(
+            f,
+            "  relays        {} server peers, transport {transport}",
+            self.relays
+        )?;
+
+        let reasons = self.reasons();
+        if reasons.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(f, "why")?;
+        for (i, (why, _)) in reasons.iter().enumerate() {
+            writeln!(f, "  {}. {why}", i + 1)?;
+        }
+
+        writeln!(f, "next steps")?;
+        for (_, step) in &reasons {
+            writeln!(f, "  - {step}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        discover::nat::NatType,
+        runner::{Candidate, PunchProgress},
+        wg::Key,
+    };
+
+    use super::Explanation;
+
+    #[test]
+    fn test_reasons() {
+        let mut explanation = Explanation {
+            key: Key::random(),
+            configured: true,
+            up: false,
+            handshake_age: Some(600),
+            endpoint: Some("192.0.2.1:51820".into()),
+            announce_age: Some(30),
+            online: true,
+            peer_nat: Some(true),
+            our_nat: Some(false),
+            peer_nat_type: Some(NatType::Symmetric),
+            our_nat_type: None,
+            punch: Some(PunchProgress {
+                candidates: vec![
+                    (Candidate::V4, "192.0.2.1:51820".parse().unwrap()),
+                    (Candidate::Lan, "10.1.1.2:51820".parse().unwrap()),
+                ],
+                current: 1,
+                attempt: 5,
+                exhausted: true,
+            }),
+            hint: None,
+            relays: 0,
+            transport: None,
+            pinned: None,
+            blocked: false,
+            revoked: false,
+            frozen: false,
+        };
+
+        let reasons = explanation.reasons();
+        assert_eq!(reasons.len(), 1);
+        assert!(reasons[0].0.contains("all 2 candidates"));
+        assert!(reasons[0].1.contains("server peer"));
+
+        let text = explanation.to_string();
+        assert!(text.contains("why\n  1. all 2 candidates"));
+        assert!(text.contains("10.1.1.2:51820 last attempt"));
+
+        assert!(text.contains("nat           peer behind symmetric NAT, we are public"));
+
+        explanation.our_nat = Some(true);
+        explanation.our_nat_type = Some(NatType::PortRestricted);
+        let reasons = explanation.reasons();
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons[1].0.contains("symmetric and ours port-restricted"));
+
+        explanation.up = true;
+        assert!(explanation.reasons().is_empty());
+        assert!(!explanation.to_string().contains("why"));
+    }
+}
@@ -0,0 +1,76 @@
+//! File helpers shared by everything writing state, exports and configs.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+/// Replaces `path` with `data`, creating its directory when missing.
+/// Readers see the old file or the new one, never a partial one, and the
+/// new one is on disk once this returns, a crash right after keeps it.
+/// The file is created with the permission bits of `mode`, less the umask,
+/// on unix.
+pub fn write_atomic(path: &Path, data: impl AsRef<[u8]>, mode: u32) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    // a leftover of a crash keeps its mode when truncated
+    match fs::remove_file(&tmp) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    let mut file = options.open(&tmp)?;
+    file.write_all(data.as_ref())?;
+    file.sync_all()?;
+
+    fs::rename(&tmp, path)?;
+
+    // the rename is only durable once the directory is synced
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::write_atomic;
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("wg-disco-fs-{}", std::process::id()));
+        let path = dir.join("state").join("file");
+
+        write_atomic(&path, "old", 0o600).unwrap();
+        write_atomic(&path, "new", 0o600).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!dir.join("state").join("file.tmp").exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+
+use crate::wg::Key;
+
+/// `[group.<name>]` section of the config.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    pub peers: Vec<Key>,
+
+    /// Install routes advertised by members.
+    #[serde(default = "enabled")]
+    pub accept_routes: bool,
+
+    /// Forward announcements of members to other peers.
+    #[serde(default = "enabled")]
+    pub relay: bool,
+
+    /// Answer requests of members with a targeted announcement.
+    #[serde(default = "enabled")]
+    pub announce: bool,
+
+    /// Also send members a targeted announcement whenever ours changes,
+    /// for peers like servers that must not miss one. Members of groups
+    /// without it only hear from us when they ask or on the channel.
+    #[serde(default)]
+    pub push: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub accept_routes: bool,
+    pub relay: bool,
+    pub announce: bool,
+    pub push: bool,
+}
+
+/// Peers outside of any group are trusted with everything.
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            accept_routes: true,
+            relay: true,
+            announce: true,
+            push: false,
+        }
+    }
+}
+
+/// Per peer policy resolved from the configured groups.
+#[derive(Debug, Default, Clone)]
+pub struct Groups {
+    policies: HashMap<Key, Policy>,
+}
+
+impl Groups {
+    /// A peer in several groups gets whatever any of them allows.
+    pub fn new(groups: &BTreeMap<String, GroupConfig>) -> Self {
+        let mut policies: HashMap<Key, Policy> = HashMap::new();
+
+        for group in groups.values() {
+            for key in &group.peers {
+                let policy = policies.entry(*key).or_insert(Policy {
+                    accept_routes: false,
+                    relay: false,
+                    announce: false,
+                    push: false,
+                });
+
+                policy.accept_routes |= group.accept_routes;
+                policy.relay |= group.relay;
+                policy.announce |= group.announce;
+                policy.push |= group.push && group.announce;
+            }
+        }
+
+        Self { policies }
+    }
+
+    pub fn policy(&self, key: &Key) -> Policy {
+        self.policies.get(key).copied().unwrap_or_default()
+    }
+
+    /// Peers our changed announcements are pushed to.
+    pub fn pushed(&self) -> impl Iterator<Item = &Key> {
+        self.policies
+            .iter()
+            .filter(|(_, policy)| policy.push)
+            .map(|(key, _)| key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::wg::Key;
+
+    use super::{GroupConfig, Groups, Policy};
+
+    #[test]
+    fn test_policy_merge() {
+        let (server, laptop, other) = (Key::random(), Key::random(), Key::random());
+
+        let groups: BTreeMap<String, GroupConfig> = toml::from_str(&format!(
+            r#"
+[servers]
+peers = ["{server}"]
+announce = false
+
+[relays]
+peers = ["{server}"]
+push = true
+
+[laptops]
+peers = ["{laptop}", "{server}"]
+accept_routes = false
+relay = false
+"#
+        ))
+        .unwrap();
+        let groups = Groups::new(&groups);
+
+        assert_eq!(
+            groups.policy(&laptop),
+            Policy {
+                accept_routes: false,
+                relay: false,
+                announce: true,
+                push: false,
+            }
+        );
+        assert_eq!(
+            groups.policy(&server),
+            Policy {
+                push: true,
+                ..Policy::default()
+            }
+        );
+        assert_eq!(groups.policy(&other), Policy::default());
+        assert_eq!(groups.pushed().collect::<Vec<_>>(), [&server]);
+    }
+}
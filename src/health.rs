@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    process::{Command, Stdio},
+};
+
+use serde::Deserialize;
+
+use crate::wg::Cidr;
+
+/// Consecutive results needed to change a route's state, so a single lost
+/// ping doesn't make the whole mesh reroute.
+const FALL: u32 = 2;
+const RISE: u32 = 2;
+
+/// `[[route_check]]` guarding an advertised route: it is withdrawn while the
+/// check fails, so peers don't send traffic into a dead network.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteCheck {
+    /// One of `AdvertiseRoutes`.
+    pub route: Cidr,
+
+    /// Host in the network answering pings.
+    pub ping: Option<IpAddr>,
+
+    /// Interface carrying the network, has to exist and be up.
+    pub device: Option<String>,
+}
+
+impl RouteCheck {
+    /// Blocks for up to a second per ping.
+    pub fn run(&self) -> bool {
+        if let Some(device) = &self.device
+            && !is_up(device)
+        {
+            log::debug!("route check {}: {device} is down", self.route);
+            return false;
+        }
+
+        if let Some(ip) = self.ping
+            && !ping(ip)
+        {
+            log::debug!("route check {}: {ip} doesn't answer", self.route);
+            return false;
+        }
+
+        true
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_up(device: &str) -> bool {
+    // virtual devices without carrier detection report "unknown"
+    match fs::read_to_string(format!("/sys/class/net/{device}/operstate")) {
+        Ok(state) => matches!(state.trim(), "up" | "unknown"),
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_up(device: &str) -> bool {
+    crate::route::query::if_index(device).is_some()
+}
+
+fn ping(ip: IpAddr) -> bool {
+    let mut cmd = Command::new("ping");
+    if ip.is_ipv6() {
+        cmd.arg("-6");
+    }
+
+    cmd.args(["-c", "1", "-W", "1", "-n", "-q"])
+        .arg(ip.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[derive(Debug, Default)]
+struct State {
+    healthy: bool,
+    streak: u32,
+}
+
+/// Health of the checked routes. The first result decides right away,
+/// nothing is advertised before it's known to work.
+#[derive(Debug, Default)]
+pub struct RouteHealth {
+    routes: HashMap<Cidr, State>,
+}
+
+impl RouteHealth {
+    /// Records a check result, `Some` with the new state when it changed.
+    pub fn record(&mut self, route: Cidr, ok: bool) -> Option<bool> {
+        let Some(state) = self.routes.get_mut(&route) else {
+            self.routes.insert(
+                route,
+                State {
+                    healthy: ok,
+                    streak: 0,
+                },
+            );
+            return Some(ok);
+        };
+
+        if state.healthy == ok {
+            state.streak = 0;
+            return None;
+        }
+
+        state.streak += 1;
+        if state.streak < if ok { RISE } else { FALL } {
+            return None;
+        }
+
+        state.healthy = ok;
+        state.streak = 0;
+        Some(ok)
+    }
+
+    /// Routes without a check are always healthy.
+    #[inline]
+    pub fn is_healthy(&self, route: &Cidr) -> bool {
+        self.routes.get(route).is_none_or(|state| state.healthy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::Config, wg::Cidr};
+
+    use super::RouteHealth;
+
+    #[test]
+    fn test_route_health() {
+        let config: Config = toml::from_str(
+            r#"
+[[route_check]]
+route = "192.168.10.0/24"
+ping = "192.168.10.1"
+device = "eth1"
+"#,
+        )
+        .unwrap();
+        let lan = config.route_check[0].route;
+        let other: Cidr = "10.0.0.0/8".parse().unwrap();
+
+        let mut health = RouteHealth::default();
+        assert!(health.is_healthy(&other));
+
+        assert_eq!(health.record(lan, true), Some(true));
+
+        // one lost ping is not enough
+        assert_eq!(health.record(lan, false), None);
+        assert_eq!(health.record(lan, true), None);
+        assert_eq!(health.record(lan, false), None);
+        assert_eq!(health.record(lan, false), Some(false));
+        assert!(!health.is_healthy(&lan));
+
+        assert_eq!(health.record(lan, true), None);
+        assert_eq!(health.record(lan, true), Some(true));
+        assert!(health.is_healthy(&lan));
+    }
+}
@@ -0,0 +1,369 @@
+use std::fmt::{self, Display, Write};
+
+/// Minimal JSON value, enough to report state to other programs and read
+/// back what the daemon API answers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn object<K: Into<String>>(fields: impl IntoIterator<Item = (K, Value)>) -> Self {
+        Value::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    /// `None` on malformed JSON or anything after the value.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parser = Parser {
+            s: s.as_bytes(),
+            at: 0,
+        };
+
+        let value = parser.value(0)?;
+        parser.space();
+        (parser.at == s.len()).then_some(value)
+    }
+
+    /// Field of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Int(n) => u64::try_from(*n).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// Nesting deeper than this is refused rather than overflowing the stack.
+const MAX_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    s: &'a [u8],
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn space(&mut self) {
+        while self.s.get(self.at).is_some_and(u8::is_ascii_whitespace) {
+            self.at += 1;
+        }
+    }
+
+    fn eat(&mut self, token: &[u8]) -> bool {
+        self.space();
+        let found = self.s[self.at..].starts_with(token);
+        if found {
+            self.at += token.len();
+        }
+        found
+    }
+
+    fn value(&mut self, depth: usize) -> Option<Value> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        self.space();
+        match *self.s.get(self.at)? {
+            b'n' if self.eat(b"null") => Some(Value::Null),
+            b't' if self.eat(b"true") => Some(Value::Bool(true)),
+            b'f' if self.eat(b"false") => Some(Value::Bool(false)),
+            b'"' => self.string().map(Value::Str),
+            b'[' => {
+                self.at += 1;
+                let mut items = Vec::new();
+                if !self.eat(b"]") {
+                    loop {
+                        items.push(self.value(depth + 1)?);
+                        if self.eat(b"]") {
+                            break;
+                        }
+                        self.eat(b",").then_some(())?;
+                    }
+                }
+                Some(Value::Array(items))
+            }
+            b'{' => {
+                self.at += 1;
+                let mut fields = Vec::new();
+                if !self.eat(b"}") {
+                    loop {
+                        self.space();
+                        let key = self.string()?;
+                        self.eat(b":").then_some(())?;
+                        fields.push((key, self.value(depth + 1)?));
+                        if self.eat(b"}") {
+                            break;
+                        }
+                        self.eat(b",").then_some(())?;
+                    }
+                }
+                Some(Value::Object(fields))
+            }
+            _ => self.number(),
+        }
+    }
+
+    fn number(&mut self) -> Option<Value> {
+        let start = self.at;
+        while self
+            .s
+            .get(self.at)
+            .is_some_and(|c| c.is_ascii_digit() || b"+-.eE".contains(c))
+        {
+            self.at += 1;
+        }
+
+        let number = std::str::from_utf8(&self.s[start..self.at]).ok()?;
+        match number.parse() {
+            Ok(n) => Some(Value::Int(n)),
+            Err(_) => number.parse().ok().map(Value::Float),
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.s.get(self.at) != Some(&b'"') {
+            return None;
+        }
+        self.at += 1;
+
+        let mut s = Vec::new();
+        loop {
+            let c = *self.s.get(self.at)?;
+            self.at += 1;
+
+            match c {
+                b'"' => return String::from_utf8(s).ok(),
+                b'\\' => {
+                    let c = *self.s.get(self.at)?;
+                    self.at += 1;
+
+                    let c = match c {
+                        b'"' | b'\\' | b'/' => c as char,
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let hex = self.s.get(self.at..self.at + 4)?;
+                            self.at += 4;
+                            let code =
+                                u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+                            // surrogate pairs aren't needed by anything we read
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => return None,
+                    };
+                    s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                c => s.push(c),
+            }
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Int(n) => write!(f, "{n}"),
+            Value::Float(n) if n.is_finite() => write!(f, "{n}"),
+            Value::Float(_) => f.write_str("null"),
+            Value::Str(s) => write_str(f, s),
+            Value::Array(items) => {
+                f.write_char('[')?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_char(']')
+            }
+            Value::Object(fields) => {
+                f.write_char('{')?;
+                for (idx, (key, value)) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+
+    f.write_char('"')
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::Int(n as i64)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(n: u32) -> Self {
+        Value::Int(n as i64)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(n: u16) -> Self {
+        Value::Int(n as i64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Self {
+        Value::Int(n as i64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Float(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(opt: Option<T>) -> Self {
+        opt.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Self {
+        Value::Array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Value;
+
+    #[test]
+    fn test_display() {
+        let value = Value::object([
+            ("name", Value::from("wg\"0\n")),
+            ("port", Value::from(51820u16)),
+            ("peers", Value::from(vec!["a", "b"])),
+            ("pinned", Value::from(None::<bool>)),
+        ]);
+
+        assert_eq!(
+            value.to_string(),
+            r#"{"name":"wg\"0\n","port":51820,"peers":["a","b"],"pinned":null}"#
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let value = Value::object([
+            ("name", Value::from("wg\"0\n\u{1}é")),
+            ("port", Value::from(51820u16)),
+            ("peers", Value::from(vec!["a", "b"])),
+            ("pinned", Value::from(None::<bool>)),
+            ("loss", Value::from(0.25)),
+            ("up", Value::from(true)),
+            ("nested", Value::object([("empty", Value::Array(vec![]))])),
+        ]);
+        assert_eq!(Value::parse(&value.to_string()), Some(value.clone()));
+
+        let value = Value::parse(" { \"port\" : 51820 , \"peers\": [ \"a\" ] } ").unwrap();
+        assert_eq!(value.get("port").and_then(Value::as_u64), Some(51820));
+        assert_eq!(
+            value.get("peers").and_then(Value::as_array).map(<[_]>::len),
+            Some(1)
+        );
+
+        for malformed in [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "\"open",
+            "1 2",
+            "nul",
+            &"[".repeat(100),
+        ] {
+            assert_eq!(Value::parse(malformed), None, "{malformed}");
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    io::Write,
+    net::SocketAddr,
+    process::{Command, Stdio},
+};
+
+use crate::error::Error;
+
+/// nftables rules dropping everything leaving the host outside of the
+/// tunnel, except traffic wg-disco itself needs: encapsulated wireguard
+/// packets, signaling and discovery servers, DHCP and neighbor discovery.
+#[derive(Debug)]
+pub struct KillSwitch {
+    iface: String,
+    table: String,
+    engaged: bool,
+}
+
+impl KillSwitch {
+    pub fn new(iface: &str) -> Self {
+        Self {
+            iface: iface.to_string(),
+            table: format!(
+                "wg_disco_{}",
+                iface.replace(|c: char| !c.is_alphanumeric(), "_")
+            ),
+            engaged: false,
+        }
+    }
+
+    #[inline]
+    pub fn is_engaged(&self) -> bool {
+        self.engaged
+    }
+
+    pub fn engage(&mut self, listen_port: u16, allow: &[SocketAddr]) -> Result<(), Error> {
+        if self.engaged {
+            return Ok(());
+        }
+
+        nft(&self.ruleset(listen_port, allow))?;
+        self.engaged = true;
+
+        log::info!("kill-switch engaged on {}", self.iface);
+        Ok(())
+    }
+
+    pub fn disengage(&mut self) -> Result<(), Error> {
+        if !self.engaged {
+            return Ok(());
+        }
+
+        nft(&format!("delete table inet {}\n", self.table))?;
+        self.engaged = false;
+
+        log::info!("kill-switch disengaged on {}", self.iface);
+        Ok(())
+    }
+
+    pub fn ruleset(&self, listen_port: u16, allow: &[SocketAddr]) -> String {
+        let mut rules = format!(
+            "table inet {table} {{
+    chain output {{
+        type filter hook output priority 0; policy drop;
+        oifname \"lo\" accept
+        oifname \"{iface}\" accept
+        udp sport {listen_port} accept
+        udp sport 68 udp dport 67 accept
+        udp sport 546 udp dport 547 accept
+        icmpv6 type {{ nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert }} accept
+",
+            table = self.table,
+            iface = self.iface,
+        );
+
+        for addr in allow {
+            let family = if addr.is_ipv4() { "ip" } else { "ip6" };
+            rules.push_str(&format!(
+                "        {family} daddr {} th dport {} accept\n",
+                addr.ip(),
+                addr.port()
+            ));
+        }
+
+        rules.push_str("    }\n}\n");
+        rules
+    }
+}
+
+fn nft(ruleset: &str) -> Result<(), Error> {
+    let mut child = Command::new("nft")
+        .arg("-f")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(ruleset.as_bytes())?;
+    }
+
+    let status = child.wait()?;
+
+    if !status.success() {
+        return Err(Error::CommandFail("nft", status.code()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KillSwitch;
+
+    #[test]
+    fn test_ruleset() {
+        let ks = KillSwitch::new("wg-0");
+        let rules = ks.ruleset(51820, &["1.2.3.4:6667".parse().unwrap()]);
+
+        assert!(rules.starts_with("table inet wg_disco_wg_0 {"));
+        assert!(rules.contains("oifname \"wg-0\" accept"));
+        assert!(rules.contains("udp sport 51820 accept"));
+        assert!(rules.contains("ip daddr 1.2.3.4 th dport 6667 accept"));
+    }
+}
@@ -0,0 +1,48 @@
+// Futures are driven on the main task only, `Send` bounds are not needed.
+#![allow(async_fn_in_trait)]
+
+pub mod android;
+pub mod api;
+pub mod capture;
+pub mod clock;
+pub mod config;
+pub mod control;
+pub mod crypto;
+#[cfg(all(unix, feature = "dbus"))]
+pub mod dbus;
+pub mod ddns;
+pub mod dial;
+pub mod discover;
+pub mod doctor;
+pub mod error;
+pub mod explain;
+pub mod fs;
+pub mod groups;
+pub mod health;
+pub mod json;
+pub mod killswitch;
+pub mod limits;
+pub mod metered;
+pub mod metrics;
+pub mod peerlog;
+pub mod provision;
+pub mod proxy;
+pub mod relay;
+pub mod retry;
+pub mod route;
+pub mod runner;
+pub mod secret;
+pub mod service;
+pub mod show;
+pub mod shutdown;
+pub mod signaling;
+#[cfg(feature = "stats")]
+pub mod stats;
+pub mod systemd;
+pub mod tenant;
+pub mod transport;
+pub mod uplink;
+pub mod validate;
+#[cfg(feature = "http")]
+pub mod web;
+pub mod wg;
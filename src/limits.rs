@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+/// Buffer sizes, retention and polling intervals. `low_memory = true` in
+/// the config trades responsiveness for footprint on OpenWrt-class routers
+/// with 64-128 MB of RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Received announcements folded into one batch of endpoint updates.
+    pub batch: usize,
+
+    /// Events kept for `GET /v1/events`.
+    pub event_log: usize,
+
+    /// Polling of the interface for handshakes and roaming.
+    pub watch_interval: Duration,
+
+    /// Expiry of pins, punch retries, route checks and the like.
+    pub housekeeping_interval: Duration,
+
+    /// Threads for blocking work: `wg` invocations, pings, captures.
+    pub blocking_threads: usize,
+}
+
+impl Limits {
+    pub const DEFAULT: Limits = Limits {
+        batch: 256,
+        event_log: 100,
+        watch_interval: Duration::from_secs(10),
+        housekeeping_interval: Duration::from_secs(5),
+        blocking_threads: 512,
+    };
+
+    pub const LOW_MEMORY: Limits = Limits {
+        batch: 32,
+        event_log: 16,
+        watch_interval: Duration::from_secs(30),
+        housekeeping_interval: Duration::from_secs(15),
+        blocking_threads: 4,
+    };
+
+    #[inline]
+    pub fn new(low_memory: bool) -> Self {
+        match low_memory {
+            true => Self::LOW_MEMORY,
+            false => Self::DEFAULT,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
@@ -1,110 +1,1455 @@
-use std::{fs, pin::pin};
-
-use clap::Parser;
-use discover::Discover;
-use error::Error;
-use futures::StreamExt;
-use signaling::{
-    PeerUpdate, Signaling,
-    irc::{IrcConfig, IrcSignaling, PeerEvent},
+use std::{
+    fs,
+    io::{self, IsTerminal, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    process::ExitCode,
+    time::Duration,
 };
-use wg::{Key, WireguardApi, cmd::WgCmdBackend, config::WgConfig};
 
-mod discover;
-pub(crate) mod error;
-mod signaling;
-mod wg;
+use clap::{
+    Parser, ValueEnum,
+    builder::{FalseyValueParser, PossibleValue},
+};
+#[cfg(feature = "irc")]
+use futures::future::Either;
+#[cfg(feature = "irc")]
+use std::sync::Arc;
+use tokio::{
+    runtime::{self, Runtime},
+    sync::mpsc,
+};
+#[cfg(feature = "dht")]
+use wg_disco::signaling::dht::DhtSignaling;
+use wg_disco::{
+    android,
+    api::ApiConfig,
+    clock::Clock,
+    config::{Config, IrcSettings},
+    control,
+    crypto::x25519_base,
+    ddns::{DdnsConfig, DdnsRunner, TsigKey},
+    discover::{
+        Discover, DiscoverBackend, DiscoverKind,
+        stun::{PUBLIC_STUN_SERVERS, StunDiscover},
+    },
+    doctor::{self, Severity},
+    error::Error,
+    groups::Groups,
+    json::Value,
+    limits::Limits,
+    metrics::Labels,
+    peerlog,
+    provision::NewClient,
+    proxy::Proxy,
+    relay,
+    retry::RetryPolicy,
+    runner::{AddressPolicy, Hints, PortPolicy, Revocations, RunnerOptions, Snapshot},
+    secret::{KeySource, SecretKey},
+    service::{self, ServiceAction},
+    show::Overview,
+    signaling::{Metadata, PeerEvent, beacon::Beacon, disguise::Disguise, dns, registry},
+    systemd,
+    tenant::{self, Network},
+    validate,
+    wg::{Key, WgBackend, WgBackendKind, WireguardApi, config::WgConfig, memory::MemoryBackend},
+};
+#[cfg(feature = "irc")]
+use wg_disco::{
+    runner::Runner,
+    signaling::{
+        dns::DnsSignaling,
+        irc::{IrcConfig, IrcSignaling},
+        multi::MultiSignaling,
+        seal::Seal,
+    },
+    wg::config::ParseError,
+};
+
+const DEFAULT_IRC_SERVER: &str = "irc.libera.chat";
+const DEFAULT_IRC_CHANNEL: &str = "#wg-disco-aeeab";
 
-#[derive(Debug, clap::Parser)]
+/// LAN beacon events waiting for the runner.
+const LAN_EVENTS: usize = 64;
+
+#[derive(Debug, Clone, clap::Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
-    iface: String,
+    #[command(subcommand)]
+    command: Option<Cmd>,
+
+    #[arg(required = true)]
+    iface: Option<String>,
+
+    /// Host another network in this process, with its own configs, state and [api] token
+    #[arg(
+        long,
+        value_name = "IFACE",
+        conflicts_with_all = [
+            "config", "wg_config", "server", "provision_file", "dbus", "stats_file", "beacon",
+            "ddns_name",
+        ]
+    )]
+    network: Vec<String>,
+
+    /// What to do when ListenPort is set but differs from the discovered mapping
+    #[arg(long, value_enum, default_value_t)]
+    port_mismatch: PortPolicy,
+
+    /// How to talk to wireguard, netlink needs no wg binary
+    #[arg(long, value_enum, default_value_t)]
+    wg_backend: WgBackendKind,
+
+    /// How to find our public endpoint, upnp and pcp ask the router for a port mapping; several
+    /// run together with STUN and announce every candidate found, best first
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "stun")]
+    discover: Vec<DiscoverKind>,
+
+    /// What to do when a peer announces a tunnel address outside of its AllowedIPs
+    #[arg(long, value_enum, default_value_t)]
+    address_mismatch: AddressPolicy,
+
+    /// Run as a publicly reachable server with this static endpoint, skipping discovery
+    #[arg(long, value_name = "ENDPOINT")]
+    server: Option<SocketAddr>,
+
+    /// Re-broadcast cached announcements of other peers to late joiners
+    #[arg(long, requires = "server")]
+    amplify: bool,
+
+    /// wg-disco config, defaults to /etc/wg-disco/<IFACE>.toml
+    #[arg(long, env = "WG_DISCO_CONFIG")]
+    config: Option<String>,
+
+    /// Wireguard config of the interface, defaults to /etc/wireguard/<IFACE>.conf
+    #[arg(long, value_name = "PATH", env = "WG_DISCO_WG_CONFIG")]
+    wg_config: Option<String>,
+
+    /// Directory of the state kept across restarts, defaults to /var/lib/wg-disco
+    #[arg(long, value_name = "PATH", env = "WG_DISCO_STATE_DIR")]
+    state_dir: Option<PathBuf>,
+
+    /// Use only the paths given by flags or environment: no default config, no state without --state-dir
+    #[arg(long, env = "WG_DISCO_PURE", value_parser = FalseyValueParser::new(), requires = "wg_config")]
+    pure: bool,
+
+    /// Print the effective configuration as JSON and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// IRC networks used for signaling as host:port, in order of preference [default: irc.libera.chat]
+    #[arg(long, value_name = "HOST:PORT")]
+    irc_server: Vec<String>,
+
+    /// IRC channel peers meet in [default: #wg-disco-aeeab]
+    #[arg(long, value_name = "CHANNEL")]
+    irc_channel: Option<String>,
+
+    /// Encrypt announcements end-to-end with this pre-shared key, every peer of the mesh needs the same
+    #[arg(
+        long,
+        value_name = "PSK",
+        env = "WG_DISCO_IRC_PSK",
+        hide_env_values = true
+    )]
+    irc_psk: Option<String>,
+
+    /// Connect to the IRC networks over TLS, ports default to 6697 (needs the tls feature)
+    #[arg(long)]
+    irc_tls: bool,
+
+    /// NickServ password of our nickname, a ghost of an earlier session holding it is killed with it
+    #[arg(
+        long,
+        value_name = "PASSWORD",
+        env = "WG_DISCO_IRC_NICKSERV_PASSWORD",
+        hide_env_values = true
+    )]
+    irc_nickserv_password: Option<String>,
+
+    /// Recover the nickname with NickServ REGAIN instead of GHOST
+    #[arg(long)]
+    irc_regain: bool,
+
+    /// STUN servers as host:port, in order of preference [default: public servers]
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        env = "STUN_SERVER",
+        value_delimiter = ','
+    )]
+    stun_server: Vec<String>,
+
+    /// Home relay as HOST:PORT of a `wg-disco relay`, peers no punching gets through to are relayed
+    #[arg(long, value_name = "HOST:PORT", env = "WG_DISCO_RELAY")]
+    relay: Option<String>,
+
+    /// Reach the signaling servers through a proxy, socks5://[USER:PASS@]HOST:PORT or http://[USER:PASS@]HOST:PORT
+    #[arg(long, value_name = "URL", env = "WG_DISCO_PROXY")]
+    proxy: Option<Proxy>,
+
+    /// Use random IRC nicknames, pad and jitter announcements against tracking
+    #[arg(long)]
+    obfuscate: bool,
+
+    /// How IRC messages look for networks flagging base64 blobs, all peers must use the same
+    #[arg(long, value_enum, default_value_t)]
+    disguise: Disguise,
+
+    /// List this node in the channel topic when running as a server, so joining nodes find it right away
+    #[arg(long)]
+    topic: bool,
+
+    /// Re-announce only what changed to the channel, all peers must run a version supporting it
+    #[arg(long)]
+    delta: bool,
+
+    /// Seconds between rediscovery and re-announcement of our endpoint, 0 disables
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    reannounce: u64,
+
+    /// Seconds a peer's announcement may be off from its clock before it's dropped as a replay, 0 disables
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    max_age: u64,
+
+    /// Seconds without a handshake before a peer's endpoint is asked for again, 0 disables
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    handshake_timeout: u64,
+
+    /// Accept unsigned announcements from peers running older versions
+    #[arg(long)]
+    allow_unsigned: bool,
+
+    /// Ask this coordinator peer for our client config (address, routes, DNS)
+    #[arg(long, value_name = "KEY", requires = "provision_file")]
+    provision_from: Option<Key>,
+
+    /// Save the client config the coordinator sends here, as a wg-quick config
+    #[arg(long, value_name = "PATH")]
+    provision_file: Option<PathBuf>,
+
+    /// Signal through the BitTorrent mainline DHT instead of IRC, no server to depend on
+    #[arg(long, conflicts_with = "observe")]
+    dht: bool,
+
+    /// Fall back to signaling through the BitTorrent mainline DHT once the IRC networks keep
+    /// failing
+    #[arg(long, conflicts_with = "dht")]
+    dht_fallback: bool,
+
+    /// Fall back to signaling through DNS queries answered by `wg-disco dns-server` as the
+    /// authority of this zone, for networks letting nothing else out
+    #[arg(long, value_name = "ZONE", conflicts_with_all = ["dht", "delta"])]
+    dns_zone: Option<String>,
+
+    /// Resolver the DNS signaling queries go to [default: first nameserver of /etc/resolv.conf]
+    #[arg(long, value_name = "IP:PORT", requires = "dns_zone")]
+    dns_resolver: Option<SocketAddr>,
+
+    /// Don't tell peers our hostname, version and platform, only the protocol version
+    #[arg(long)]
+    no_metadata: bool,
+
+    /// Check config, interface, discovery and signaling, then exit with a status telling what failed
+    #[arg(long)]
+    check: bool,
+
+    /// Only follow the mesh: never announce, install routes or touch the interface
+    #[arg(long)]
+    observe: bool,
+
+    /// Capture handshake packets of this peer while punching and log which direction gets through (needs CAP_NET_RAW)
+    #[arg(long, value_name = "KEY")]
+    capture: Option<Key>,
+
+    /// Expose the dev.wgdisco.Manager1 service on the D-Bus system bus, Announce is left to root
+    /// and the wg-disco group
+    #[arg(long)]
+    dbus: bool,
+
+    /// Periodically write a JSON snapshot of peers and rates to this file
+    #[arg(long, value_name = "PATH")]
+    stats_file: Option<PathBuf>,
+
+    /// Seconds between stats snapshots
+    #[arg(long, default_value_t = 15, requires = "stats_file")]
+    stats_interval: u64,
+
+    /// Find peers on the local network by broadcast beacons on this UDP port
+    #[arg(long, value_name = "PORT", num_args = 0..=1, default_missing_value = "51821")]
+    beacon: Option<u16>,
+
+    #[command(flatten)]
+    ddns: DdnsArgs,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum Cmd {
+    /// Manage the daemon as a system service (launchd, systemd)
+    Service {
+        #[arg(value_enum)]
+        action: ServiceAction,
+        iface: String,
+    },
+
+    /// Serve a web dashboard backed by the HTTP API of the running daemon
+    #[cfg(feature = "http")]
+    Web {
+        iface: String,
+
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+
+        /// wg-disco config, defaults to /etc/wg-disco/<IFACE>.toml
+        #[arg(long)]
+        config: Option<String>,
+    },
+
+    /// Explain why a peer is down and what to try next, asks the running daemon
+    #[cfg(feature = "http")]
+    Explain {
+        iface: String,
+        peer: Key,
+
+        /// wg-disco config, defaults to /etc/wg-disco/<IFACE>.toml
+        #[arg(long)]
+        config: Option<String>,
+    },
+
+    /// Encrypt a config value read from stdin, prints it as `enc:...`
+    Encrypt {
+        /// file:PATH, credential:NAME or keyring:DESCRIPTION, defaults like secret_key in the config
+        #[arg(long)]
+        key: Option<KeySource>,
+    },
+
+    /// Check the usual causes of connectivity problems and suggest fixes
+    Doctor {
+        iface: String,
+
+        /// IRC networks to check as host:port
+        #[arg(long, value_name = "HOST:PORT", default_value = "irc.libera.chat:6667")]
+        irc_server: Vec<String>,
+    },
+
+    /// List the peers like `wg show` along with how the daemon reaches them: path, NAT,
+    /// candidates and signaling presence
+    Show {
+        iface: String,
+
+        /// wg-disco settings, defaults to /etc/wg-disco/<iface>.toml
+        #[arg(long)]
+        config: Option<String>,
+
+        /// How to read the wireguard state when the daemon isn't reachable
+        #[arg(long, value_enum, default_value_t)]
+        wg_backend: WgBackendKind,
+    },
+
+    /// Validate the wireguard config and the wg-disco settings of an interface strictly, fails
+    /// on any problem
+    Check {
+        iface: String,
+
+        /// wg-disco settings, defaults to /etc/wg-disco/<iface>.toml
+        #[arg(long)]
+        config: Option<String>,
+
+        /// wireguard config, defaults to /etc/wireguard/<iface>.conf
+        #[arg(long)]
+        wg_config: Option<String>,
+
+        /// Print the problems as a JSON array
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Print the IRC nickname and username a peer uses, derived from its public key
+    Nickname { key: Key },
+
+    /// Answer DNS signaling queries as the authoritative server of a zone, see --dns-zone
+    DnsServer {
+        zone: String,
+
+        #[arg(long, default_value = "0.0.0.0:53")]
+        listen: SocketAddr,
+    },
+
+    /// Relay wireguard packets over TCP for peers no punching gets through to, see --relay
+    Relay {
+        #[arg(long, default_value = "0.0.0.0:51822")]
+        listen: SocketAddr,
+    },
+
+    /// Add a client on a coordinator from its `[provision]` templates, writes the wg-quick and
+    /// wg-disco configs of the client and adds it as a peer
+    GenClient {
+        iface: String,
+
+        /// Name of the client in `[provision.client]`
+        #[arg(long)]
+        name: String,
+
+        /// Tunnel address of the client, auto takes the next free one of the template
+        #[arg(long, default_value = "auto")]
+        ip: String,
+
+        /// Public key of the client, a key pair is generated without
+        #[arg(long)]
+        key: Option<Key>,
+
+        /// Directory the client configs are written to
+        #[arg(long, default_value = ".")]
+        out: PathBuf,
+
+        /// wg-disco config, defaults to /etc/wg-disco/<IFACE>.toml
+        #[arg(long)]
+        config: Option<String>,
+
+        /// wireguard config, defaults to /etc/wireguard/<IFACE>.conf
+        #[arg(long)]
+        wg_config: Option<String>,
+
+        #[arg(long, value_enum, default_value_t)]
+        wg_backend: WgBackendKind,
+    },
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct DdnsArgs {
+    /// Publish our address under this DNS name instead of using signaling
+    #[arg(long, requires_all = ["ddns_zone", "ddns_server"])]
+    ddns_name: Option<String>,
+
+    /// Zone the name belongs to
+    #[arg(long)]
+    ddns_zone: Option<String>,
+
+    /// Name server accepting RFC 2136 updates
+    #[arg(long)]
+    ddns_server: Option<SocketAddr>,
+
+    /// TSIG hmac-sha256 key as name:base64-secret
+    #[arg(long)]
+    ddns_key: Option<TsigKey>,
+
+    #[arg(long, default_value_t = 60)]
+    ddns_ttl: u32,
+
+    /// Seconds between rediscovery and re-resolving of peer names
+    #[arg(long, default_value_t = 60)]
+    ddns_interval: u64,
+}
+
+impl Args {
+    /// Fills in the IRC settings not given as flags from `[irc]` of the
+    /// config, then the defaults.
+    fn merge_irc(&mut self, irc: &IrcSettings) {
+        if self.irc_server.is_empty() {
+            self.irc_server = match irc.servers.is_empty() {
+                true => vec![DEFAULT_IRC_SERVER.into()],
+                false => irc.servers.clone(),
+            };
+        }
+
+        let channel = irc.channel.as_deref().unwrap_or(DEFAULT_IRC_CHANNEL);
+        self.irc_channel.get_or_insert_with(|| channel.into());
+        self.irc_tls |= irc.tls;
+        if self.irc_psk.is_none() {
+            self.irc_psk = irc.psk.clone();
+        }
+        if self.irc_nickserv_password.is_none() {
+            self.irc_nickserv_password = irc.nickserv_password.clone();
+        }
+        self.irc_regain |= irc.regain;
+    }
+}
+
+impl DdnsArgs {
+    fn config(&self) -> Option<DdnsConfig> {
+        Some(DdnsConfig {
+            server: self.ddns_server?,
+            zone: self.ddns_zone.clone()?,
+            name: self.ddns_name.clone()?,
+            ttl: self.ddns_ttl,
+            key: self.ddns_key.clone(),
+            interval: Duration::from_secs(self.ddns_interval),
+        })
+    }
+}
+
+fn main() -> ExitCode {
     unsafe { std::env::set_var("RUST_LOG", "info") };
-    env_logger::init();
+    env_logger::Builder::from_default_env()
+        .filter_module(peerlog::TARGET, log::LevelFilter::Debug)
+        .init();
+
+    match start(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            log::error!("{err}");
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
 
-    let args = Args::parse();
-    let config = load_wg_config(&args.iface)?;
-    let iface = args.iface;
+/// Subcommands run on the default runtime, the daemon on one sized by the
+/// `threads` and `low_memory` settings.
+fn start(mut args: Args) -> Result<(), Error> {
+    if let Some(command) = args.command.take() {
+        return runtime(None, Limits::DEFAULT)?.block_on(run(command));
+    }
 
-    let mut wg = WgCmdBackend::new();
-    let key = wg.get_pub_key(&iface)?;
+    if !args.network.is_empty() {
+        return host(args);
+    }
 
-    let cfg = IrcConfig {
-        server: "irc.libera.chat".to_string(),
-        port: Some(6667),
-        tls: false,
-        channel: "#wg-disco-aeeab".to_string(),
+    let iface = args.iface.clone().unwrap_or_default();
+    let settings = match &Paths::new(&args, &iface).config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
     };
 
-    let mut signaling =
-        IrcSignaling::connect(cfg, key, config.peers.iter().map(|x| &x.public_key)).await?;
+    if settings.low_memory {
+        log::info!("low memory mode");
+    }
+
+    runtime(settings.threads(), settings.limits())?.block_on(daemon(args, settings))
+}
+
+/// Runs a daemon per network on one runtime, see [`tenant`]. Each one
+/// stops on its own error, the first error is returned once all stopped.
+fn host(args: Args) -> Result<(), Error> {
+    let ifaces: Vec<_> = args.iface.iter().chain(&args.network).cloned().collect();
+
+    let (mut daemons, mut networks) = (Vec::new(), Vec::new());
+    for iface in &ifaces {
+        let args = Args {
+            iface: Some(iface.clone()),
+            ..args.clone()
+        };
+        let paths = Paths::new(&args, iface);
+        let settings = match &paths.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        networks.push(Network {
+            iface: iface.clone(),
+            settings: settings.clone(),
+            wg: load_wg_config(&paths.wg_config)?,
+        });
+        daemons.push(async move {
+            daemon(args, settings)
+                .await
+                .inspect_err(|err| log::error!("network {iface}: {err}"))
+        });
+    }
 
-    let discover = discover::stun::StunDiscover::default();
-    let (endpoint, local_port) = discover.discover().await?;
+    tenant::check_isolation(&networks)?;
+    log::info!("hosting networks {}", ifaces.join(", "));
 
-    if config.interface.listen_port.is_none() {
-        wg.set_listen_port(&iface, local_port)?;
+    runtime(None, Limits::DEFAULT)?.block_on(async {
+        futures::future::join_all(daemons)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<()>, _>>()
+            .map(drop)
+    })
+}
+
+fn runtime(threads: Option<usize>, limits: Limits) -> io::Result<Runtime> {
+    let mut builder = match threads {
+        Some(1) => runtime::Builder::new_current_thread(),
+        Some(threads) => {
+            let mut builder = runtime::Builder::new_multi_thread();
+            builder.worker_threads(threads);
+            builder
+        }
+        None => runtime::Builder::new_multi_thread(),
     };
 
-    // announcing self peer
-    signaling
-        .announce(
-            PeerUpdate {
-                key,
-                endpoint,
-                advertise_routes: vec![],
-            },
-            None,
-        )
-        .await?;
-
-    let mut stream = pin!(signaling.subscribe().await?);
-
-    while let Some(res) = stream.next().await {
-        match res {
-            Ok(PeerEvent::Request(nick, peer)) => {
-                // update peers endpoint
-                log::info!(
-                    "requested update from {} peer {} {}",
-                    nick,
-                    peer.key,
-                    peer.endpoint
+    builder
+        .enable_all()
+        .max_blocking_threads(limits.blocking_threads)
+        .build()
+}
+
+async fn run(command: Cmd) -> Result<(), Error> {
+    match command {
+        Cmd::Service { action, iface } => service::run(action, &iface),
+        #[cfg(feature = "http")]
+        Cmd::Web {
+            iface,
+            listen,
+            config,
+        } => {
+            let settings = Config::load(config.unwrap_or_else(|| Config::path(&iface)))?;
+            let api = settings.api.ok_or(Error::NoApi)?;
+
+            Ok(wg_disco::web::serve(listen, api).await?)
+        }
+        #[cfg(feature = "http")]
+        Cmd::Explain {
+            iface,
+            peer,
+            config,
+        } => {
+            let settings = Config::load(config.unwrap_or_else(|| Config::path(&iface)))?;
+            let api = settings.api.ok_or(Error::NoApi)?;
+
+            let key = peer.to_string().replace('+', "%2B").replace('/', "%2F");
+            let (status, body) =
+                wg_disco::web::fetch(&api, &format!("/v1/explain?key={key}")).await?;
+            print!("{body}");
+
+            match status {
+                200 => Ok(()),
+                _ => Err(Error::ExplainFailed(status)),
+            }
+        }
+        Cmd::Encrypt { key } => {
+            let mut value = String::new();
+            io::stdin().read_line(&mut value)?;
+
+            let key = SecretKey::load(&key.unwrap_or_default())?;
+            println!("{}", key.encrypt(value.trim_end_matches(['\r', '\n'])));
+            Ok(())
+        }
+        Cmd::Doctor { iface, irc_server } => {
+            let findings = doctor::run(&iface, &irc_server).await;
+            for finding in &findings {
+                println!("{finding}");
+            }
+
+            match findings
+                .iter()
+                .filter(|finding| finding.severity == Severity::Fail)
+                .count()
+            {
+                0 => Ok(()),
+                failed => Err(Error::DoctorFailed(failed)),
+            }
+        }
+        Cmd::Show {
+            iface,
+            config,
+            wg_backend,
+        } => {
+            let settings = Config::load(config.unwrap_or_else(|| Config::path(&iface)))?;
+            let mut overview = match daemon_overview(&settings).await {
+                Some(overview) => overview,
+                None => {
+                    let wg = WgBackend::new(wg_backend, settings.retry.wg);
+                    Overview::from_wg(&iface, &wg.get_state(&iface).await?)
+                }
+            };
+            overview.alias(&settings.alias);
+
+            let color = io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+            print!(
+                "{}",
+                overview.render(Clock::system().unix_ms() / 1000, color)
+            );
+            Ok(())
+        }
+        Cmd::Check {
+            iface,
+            config,
+            wg_config,
+            json,
+        } => {
+            let problems = validate::files(
+                &wg_config.unwrap_or_else(|| wg_config_path(&iface)),
+                &config.unwrap_or_else(|| Config::path(&iface)),
+            )?;
+
+            if json {
+                let problems = problems.iter().map(|problem| problem.to_json()).collect();
+                println!("{}", Value::Array(problems));
+            } else {
+                for problem in &problems {
+                    println!("{problem}");
+                }
+            }
+
+            match problems
+                .iter()
+                .filter(|problem| problem.severity == Severity::Fail)
+                .count()
+            {
+                0 => Ok(()),
+                failed => Err(Error::CheckFailed(failed)),
+            }
+        }
+        Cmd::Nickname { key } => {
+            let nickname = registry::nickname(&key);
+            println!("nickname {nickname}");
+            println!("username {}", registry::username(&key));
+            println!(
+                "alternates {}",
+                registry::alternate_nicknames(&nickname).join(" ")
+            );
+            Ok(())
+        }
+        Cmd::DnsServer { zone, listen } => {
+            let udp = tokio::net::UdpSocket::bind(listen).await?;
+            Ok(dns::serve(udp, &zone).await?)
+        }
+        Cmd::Relay { listen } => {
+            let listener = tokio::net::TcpListener::bind(listen).await?;
+            Ok(relay::serve(listener).await?)
+        }
+        Cmd::GenClient {
+            iface,
+            name,
+            ip,
+            key,
+            out,
+            config,
+            wg_config,
+            wg_backend,
+        } => {
+            let config = config.unwrap_or_else(|| Config::path(&iface));
+            let wg_config = wg_config.unwrap_or_else(|| wg_config_path(&iface));
+            let settings = Config::load(&config)?;
+            let provision = settings.provision.as_ref().ok_or(Error::NoProvision)?;
+            let wg_conf = load_wg_config(&wg_config)?;
+
+            let address = match ip.as_str() {
+                "auto" => None,
+                ip => Some(ip.parse()?),
+            };
+            let client = NewClient::allocate(provision, &wg_conf, &name, address, key)?;
+            let coordinator = Key::from(x25519_base(wg_conf.interface.private_key.as_bytes()));
+
+            let (conf, toml) = client.save(&out, &coordinator, &settings.irc)?;
+            append(&config, &client.provision_entry())?;
+            append(&wg_config, &client.peer_entry())?;
+            println!(
+                "client {name} {} at {}, configs {} and {}",
+                client.client.key,
+                client.config.address,
+                conf.display(),
+                toml.display()
+            );
+
+            let mut wg = WgBackend::new(wg_backend, settings.retry.wg);
+            if let Err(err) = wg.add_peer(&iface, &client.peer().into()).await {
+                log::warn!(
+                    "can't add the client to {iface}, it is added once {iface} comes up: {err}"
                 );
-                wg.set_peer_endpoint(&iface, peer.key, peer.endpoint.into())?;
-
-                signaling
-                    .announce(
-                        PeerUpdate {
-                            key,
-                            endpoint,
-                            advertise_routes: vec![],
-                        },
-                        Some(&nick),
-                    )
-                    .await?;
             }
 
-            Ok(PeerEvent::Response(peer)) => {
-                // update peers endpoint
-                log::info!("responded update peer {} {}", peer.key, peer.endpoint);
+            Ok(())
+        }
+    }
+}
+
+/// What the running daemon answers, `None` without `[api]` or when it isn't
+/// reachable.
+#[cfg(feature = "http")]
+async fn daemon_overview(settings: &Config) -> Option<Overview> {
+    let api = settings.api.as_ref()?;
+    let get = async |path| match wg_disco::web::fetch(api, path).await {
+        Ok((200, body)) => Value::parse(&body),
+        _ => None,
+    };
+
+    Overview::from_api(&get("/v1/status").await?, &get("/v1/peers").await?)
+}
+
+#[cfg(not(feature = "http"))]
+async fn daemon_overview(_settings: &Config) -> Option<Overview> {
+    None
+}
+
+/// Appends `entry` to the config at `path`.
+fn append(path: &str, entry: &str) -> Result<(), Error> {
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(|err| Error::ReadConfig(path.to_string(), err))?;
 
-                wg.set_peer_endpoint(&iface, peer.key, peer.endpoint.into())?;
+    Ok(file.write_all(entry.as_bytes())?)
+}
+
+async fn daemon(mut args: Args, settings: Config) -> Result<(), Error> {
+    args.merge_irc(&settings.irc);
+    if args.stun_server.is_empty() {
+        args.stun_server = settings.stun.servers.clone();
+    }
+    if args.relay.is_none() {
+        args.relay = settings.relay.server.clone();
+    }
+    let iface = args.iface.clone().unwrap_or_default();
+    let paths = Paths::new(&args, &iface);
+    let limits = settings.limits();
+
+    if args.print_config {
+        println!("{}", effective_config(&args, &iface, &paths, &settings));
+        return Ok(());
+    }
+
+    let config = load_wg_config(&paths.wg_config)?;
+    let retry = settings.retry;
+    let stun = match args.stun_server.is_empty() {
+        true => StunDiscover::rotate(PUBLIC_STUN_SERVERS)?,
+        false => StunDiscover::rotate(&args.stun_server)?,
+    }
+    .with_retry(retry.stun)
+    .with_device(settings.uplink.first().map(|uplink| uplink.device.clone()));
+    let discover = DiscoverBackend::new(&args.discover, stun);
+
+    let failed = doctor::preflight(
+        &iface,
+        &config,
+        discover.stun().server(),
+        args.observe,
+        args.wg_backend,
+    )
+    .await;
+    if !failed.is_empty() {
+        for (i, finding) in failed.iter().enumerate() {
+            log::error!("preflight {}. {finding}", i + 1);
+        }
+        return Err(Error::PreflightFailed(failed.len()));
+    }
+
+    let wg = WgBackend::new(args.wg_backend, retry.wg);
+    let key = match args.observe {
+        true => MemoryBackend::new(&config).get_pub_key(&iface).await?,
+        false => wg
+            .get_pub_key(&iface)
+            .await
+            .map_err(|err| match err.is_no_device() {
+                true => Error::NoInterface(iface.clone()),
+                false => err,
+            })?,
+    };
+    let mut options = RunnerOptions {
+        port_policy: args.port_mismatch,
+        address_policy: args.address_mismatch,
+        servers: discover.stun().servers().to_vec(),
+        server: args.server,
+        amplify: args.amplify,
+        announce_retry: retry.announce,
+        punch_retry: retry.punch,
+        hints_file: paths.hints,
+        transports: settings.transport,
+        relay: args.relay.clone(),
+        groups: Groups::new(&settings.group),
+        delta: args.delta,
+        uplinks: settings.uplink,
+        route_checks: settings.route_check,
+        route_export: settings.route_export,
+        observe: args.observe,
+        capture: args.capture,
+        limits,
+        blocklist: settings.blocklist,
+        revocation_signers: settings.revocation_signers,
+        revocations_file: paths.revocations,
+        metadata: Some(match args.no_metadata {
+            true => Metadata::anonymous(),
+            false => Metadata::local(),
+        }),
+        reannounce: Some(Duration::from_secs(args.reannounce))
+            .filter(|interval| !interval.is_zero()),
+        metered: settings.metered,
+        // an observer sharing the file would clobber the daemon's state
+        state_file: paths.state.clone().filter(|_| !args.observe),
+        allow_unsigned: args.allow_unsigned,
+        max_age: Some(Duration::from_secs(args.max_age)).filter(|age| !age.is_zero()),
+        handshake_timeout: Some(Duration::from_secs(args.handshake_timeout))
+            .filter(|timeout| !timeout.is_zero()),
+        provision_from: args.provision_from,
+        provision_file: args.provision_file.clone(),
+        provision: settings
+            .provision
+            .as_ref()
+            .map(|provision| provision.render())
+            .transpose()?
+            .unwrap_or_default(),
+    };
+
+    // the kill-switch keeps the relay reachable
+    if let Some(relay) = &args.relay {
+        match tokio::net::lookup_host(relay.as_str()).await {
+            Ok(addrs) => options.servers.extend(addrs),
+            Err(err) => log::warn!("can't resolve relay {relay}: {err}"),
+        }
+    }
+
+    if args.check {
+        return check(&args, &config, key, &discover, &retry.signaling).await;
+    }
+
+    let (lan, lan_events) = match args.beacon {
+        Some(port) if !args.observe => spawn_beacon(port, &iface, key, &config, &wg)?.unzip(),
+        _ => (None, None),
+    };
+
+    let res = if let Some(ddns) = args.ddns.config().filter(|_| !args.observe) {
+        DdnsRunner::new(iface, config, wg, discover, options, ddns)
+            .run()
+            .await
+    } else {
+        let labels = Labels::new(
+            &settings.alias,
+            config.peers.iter().map(|peer| peer.public_key),
+        );
+        let requests = spawn_control(&args, settings.api, limits, labels);
+        let node = (key, config, wg);
+        let inputs = (requests, lan_events);
+        match args.dht {
+            true => dht_daemon(&args, node, discover, options, inputs).await,
+            false => irc_daemon(&args, node, discover, options, &retry.signaling, inputs).await,
+        }
+    };
+
+    match (res, lan) {
+        // without internet LAN peers running the daemon can still find us,
+        // wireguard learns their endpoints from the handshakes
+        (Err(err), Some(lan)) => {
+            log::error!("{err}, continuing with LAN beacon only");
+            lan.await.map_err(io::Error::other)?
+        }
+        (res, _) => res,
+    }
+}
+
+/// Control channel for the frontends enabled by flags and config, `None`
+/// without any.
+#[cfg_attr(not(feature = "http"), allow(unused_variables))]
+fn spawn_control(
+    args: &Args,
+    api: Option<ApiConfig>,
+    limits: Limits,
+    labels: Labels,
+) -> Option<control::Receiver> {
+    if api.is_none() && !args.dbus && args.stats_file.is_none() {
+        return None;
+    }
+
+    // unused when built without any of the frontends
+    #[allow(unused_variables)]
+    let (handle, rx) = control::channel();
+
+    #[cfg(feature = "http")]
+    if let Some(api) = api {
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            if let Err(err) = wg_disco::api::serve(api, handle, limits, labels).await {
+                log::error!("http api failed: {err}");
             }
+        });
+    }
 
-            Err(err) => log::error!("error: {err}"),
+    #[cfg(not(feature = "http"))]
+    if let Some(api) = api {
+        log::warn!(
+            "built without the http feature, [api] on {} is disabled",
+            api.listen
+        );
+    }
+
+    if let Some(path) = args.stats_file.clone() {
+        #[cfg(feature = "stats")]
+        {
+            let interval = Duration::from_secs(args.stats_interval.max(1));
+            tokio::spawn(wg_disco::stats::export(path, interval, handle.clone()));
         }
+
+        #[cfg(not(feature = "stats"))]
+        log::warn!(
+            "built without the stats feature, {} is not written",
+            path.display()
+        );
+    }
+
+    if args.dbus {
+        #[cfg(all(unix, feature = "dbus"))]
+        tokio::spawn(async move {
+            if let Err(err) = wg_disco::dbus::serve(handle).await {
+                log::error!("dbus service failed: {err}");
+            }
+        });
+
+        #[cfg(not(all(unix, feature = "dbus")))]
+        log::warn!("built without the dbus feature, --dbus is ignored");
+    }
+
+    Some(rx)
+}
+
+/// Control requests and LAN beacon events for the runner, either optional.
+type Inputs = (Option<control::Receiver>, Option<mpsc::Receiver<PeerEvent>>);
+
+/// Runner serving `inputs`.
+#[cfg(any(feature = "irc", feature = "dht"))]
+fn with_inputs<W, S, D>(
+    mut runner: wg_disco::runner::Runner<W, S, D>,
+    (requests, events): Inputs,
+) -> wg_disco::runner::Runner<W, S, D>
+where
+    W: WireguardApi + Clone,
+    S: wg_disco::signaling::Signaling,
+    D: Discover,
+    Error: From<W::Error> + From<S::Error> + From<D::Error>,
+{
+    if let Some(requests) = requests {
+        runner = runner.with_control(requests);
+    }
+    if let Some(events) = events {
+        runner = runner.with_events(events);
     }
+    runner
+}
 
-    println!("exit");
+/// Runs the daemon with IRC signaling.
+#[cfg(feature = "irc")]
+async fn irc_daemon(
+    args: &Args,
+    (key, config, wg): (Key, WgConfig, WgBackend),
+    discover: DiscoverBackend,
+    mut options: RunnerOptions,
+    retry: &RetryPolicy,
+    inputs: Inputs,
+) -> Result<(), Error> {
+    let iface = args.iface.clone().unwrap_or_default();
+    let backends = connect_signaling(args, &config, key, retry, &mut options.servers).await?;
+    let backends = backends
+        .into_iter()
+        .map(|(name, res)| (name, res.map(Either::Left)))
+        .collect();
 
-    Ok(())
+    let mut signaling = MultiSignaling::new(backends);
+    if let Some(zone) = &args.dns_zone {
+        let peers = config.peers.iter().map(|peer| &peer.public_key);
+        let res = DnsSignaling::new(zone, args.dns_resolver, key, peers);
+        if let Ok(dns) = &res {
+            options.servers.push(dns.server());
+        }
+        #[cfg(feature = "dht")]
+        let res = res.map(Either::Left);
+        signaling = signaling.with_last_resort(format!("dns {zone}"), res.map(Either::Right));
+    }
+    if args.dht_fallback {
+        #[cfg(feature = "dht")]
+        {
+            let peers = config.peers.iter().map(|peer| &peer.public_key);
+            let res = DhtSignaling::connect(config.interface.private_key, peers).await;
+            if let Ok(dht) = &res {
+                options.servers.extend(dht.servers());
+            }
+            let res = res.map(|dht| Either::Right(Either::Right(dht)));
+            signaling = signaling.with_last_resort("dht".into(), res);
+        }
+
+        #[cfg(not(feature = "dht"))]
+        log::warn!("built without the dht feature, --dht-fallback is ignored");
+    }
+    if signaling.is_empty() {
+        return Err(Error::NoSignaling);
+    }
+
+    if args.observe {
+        log::info!("observing the mesh, {iface} is left alone");
+
+        let wg = MemoryBackend::new(&config);
+        let runner = Runner::new(iface, key, config, wg, signaling, discover, options);
+        with_inputs(runner, inputs).run().await
+    } else {
+        let runner = Runner::new(iface, key, config, wg, signaling, discover, options);
+        with_inputs(runner, inputs).run().await
+    }
+}
+
+#[cfg(not(feature = "irc"))]
+async fn irc_daemon(
+    _args: &Args,
+    _node: (Key, WgConfig, WgBackend),
+    _discover: DiscoverBackend,
+    _options: RunnerOptions,
+    _retry: &RetryPolicy,
+    _inputs: Inputs,
+) -> Result<(), Error> {
+    log::error!("built without the irc feature, use --ddns-name for signaling");
+    Err(Error::NoSignaling)
+}
+
+/// Runs the daemon with signaling over the mainline DHT. Only the bootstrap
+/// nodes are known in advance, the kill-switch can't keep the rest of the
+/// DHT reachable.
+#[cfg(feature = "dht")]
+async fn dht_daemon(
+    args: &Args,
+    (key, config, wg): (Key, WgConfig, WgBackend),
+    discover: DiscoverBackend,
+    mut options: RunnerOptions,
+    inputs: Inputs,
+) -> Result<(), Error> {
+    let iface = args.iface.clone().unwrap_or_default();
+    let peers = config.peers.iter().map(|peer| &peer.public_key);
+    let signaling = DhtSignaling::connect(config.interface.private_key, peers).await?;
+    options.servers.extend(signaling.servers());
+
+    let runner =
+        wg_disco::runner::Runner::new(iface, key, config, wg, signaling, discover, options);
+    with_inputs(runner, inputs).run().await
+}
+
+#[cfg(not(feature = "dht"))]
+async fn dht_daemon(
+    _args: &Args,
+    _node: (Key, WgConfig, WgBackend),
+    _discover: DiscoverBackend,
+    _options: RunnerOptions,
+    _inputs: Inputs,
+) -> Result<(), Error> {
+    log::error!("built without the dht feature, --dht is not available");
+    Err(Error::NoSignaling)
+}
+
+/// Connects to every configured IRC network, resolved addresses of the
+/// servers, or of the proxy, are added to `servers`.
+#[cfg(feature = "irc")]
+async fn connect_signaling(
+    args: &Args,
+    config: &WgConfig,
+    key: Key,
+    retry: &RetryPolicy,
+    servers: &mut Vec<SocketAddr>,
+) -> Result<Vec<(String, Result<IrcSignaling, Error>)>, Error> {
+    let mut backends = Vec::new();
+
+    // random nicknames leave the claimed key as the only identity, which
+    // only a seal or a signature vouches for
+    if args.obfuscate && args.irc_psk.is_none() && args.allow_unsigned {
+        return Err(Error::UnauthenticatedObfuscation);
+    }
+
+    if let Some(proxy) = &args.proxy {
+        match tokio::net::lookup_host((proxy.host.as_str(), proxy.port)).await {
+            Ok(addrs) => servers.extend(addrs),
+            Err(err) => log::warn!("can't resolve proxy {}: {err}", proxy.host),
+        }
+    }
+
+    let seal = args.irc_psk.as_ref().map(|psk| {
+        let peers = config.peers.iter().map(|peer| &peer.public_key);
+        Arc::new(Seal::new(
+            &config.interface.private_key,
+            psk.as_bytes(),
+            peers,
+        ))
+    });
+
+    let default_port = match args.irc_tls {
+        true => "6697",
+        false => "6667",
+    };
+
+    for server in &args.irc_server {
+        let (host, port) = server.rsplit_once(':').unwrap_or((server, default_port));
+        let cfg = IrcConfig {
+            server: host.to_string(),
+            port: Some(port.parse().map_err(ParseError::from)?),
+            channel: args
+                .irc_channel
+                .clone()
+                .unwrap_or_else(|| DEFAULT_IRC_CHANNEL.into()),
+            tls: args.irc_tls,
+            obfuscate: args.obfuscate,
+            topic: args.topic,
+            disguise: args.disguise,
+            proxy: args.proxy.clone(),
+            nickserv_password: args.irc_nickserv_password.clone(),
+            regain: args.irc_regain,
+        };
+
+        // behind a proxy names may not resolve locally, nor need to
+        if args.proxy.is_none() {
+            match tokio::net::lookup_host((host, cfg.port.unwrap_or(6667))).await {
+                Ok(addrs) => servers.extend(addrs),
+                Err(err) => log::warn!("can't resolve {host}: {err}"),
+            }
+        }
+
+        let peers = config.peers.iter().map(|x| &x.public_key);
+        let res = retry
+            .retry("irc connect", || {
+                IrcSignaling::connect(cfg.clone(), key, peers.clone())
+            })
+            .await;
+        let res = res.map(|irc| match &seal {
+            Some(seal) => irc.with_seal(seal.clone()),
+            None => irc,
+        });
+        backends.push((server.clone(), res.map_err(Error::from)));
+    }
+
+    Ok(backends)
+}
+
+/// One-shot `--check`, config and interface are verified by the time it
+/// runs. The error says which class of failure happened.
+async fn check(
+    args: &Args,
+    config: &WgConfig,
+    key: Key,
+    discover: &DiscoverBackend,
+    retry: &RetryPolicy,
+) -> Result<(), Error> {
+    let mapping = discover.discover(0).await?;
+    log::info!("check: discovered mapping {mapping}");
+
+    if args.ddns.ddns_name.is_some() {
+        log::info!("check: dns update signaling is not checked");
+        return Ok(());
+    }
+
+    check_signaling(args, config, key, retry).await
+}
+
+#[cfg(feature = "irc")]
+async fn check_signaling(
+    args: &Args,
+    config: &WgConfig,
+    key: Key,
+    retry: &RetryPolicy,
+) -> Result<(), Error> {
+    let backends = connect_signaling(args, config, key, retry, &mut Vec::new()).await?;
+    let failed = backends.iter().filter(|(_, res)| res.is_err()).count();
+
+    for (name, res) in &backends {
+        match res {
+            Ok(_) => log::info!("check: signaling {name} connected"),
+            Err(err) => log::warn!("check: signaling {name} failed: {err}"),
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        n if n == backends.len() => Err(Error::NoSignaling),
+        n => Err(Error::PartialSignaling(n, backends.len())),
+    }
+}
+
+#[cfg(not(feature = "irc"))]
+async fn check_signaling(
+    _args: &Args,
+    _config: &WgConfig,
+    _key: Key,
+    _retry: &RetryPolicy,
+) -> Result<(), Error> {
+    log::error!("check: built without the irc feature, use --ddns-name for signaling");
+    Err(Error::NoSignaling)
+}
+
+/// Beacon task and the events of the peers it hears.
+type Lan = (
+    tokio::task::JoinHandle<Result<(), Error>>,
+    mpsc::Receiver<PeerEvent>,
+);
+
+fn spawn_beacon(
+    port: u16,
+    iface: &str,
+    key: Key,
+    config: &WgConfig,
+    wg: &WgBackend,
+) -> Result<Option<Lan>, Error> {
+    let peers = config.peers.iter().map(|x| &x.public_key);
+
+    let Some(beacon) = Beacon::bind(port, key, &config.interface.private_key, peers)? else {
+        log::warn!("PrivateKey in config doesn't match {iface}, LAN beacon disabled");
+        return Ok(None);
+    };
+
+    let (tx, rx) = mpsc::channel(LAN_EVENTS);
+    let task = tokio::spawn(beacon.run(wg.clone(), iface.to_string(), tx));
+
+    Ok(Some((task, rx)))
+}
+
+/// Files the daemon reads and writes. Without `--pure` the ones not given
+/// are at their usual places.
+struct Paths {
+    wg_config: String,
+    config: Option<String>,
+    hints: Option<PathBuf>,
+    revocations: Option<PathBuf>,
+    state: Option<PathBuf>,
+}
+
+impl Paths {
+    fn new(args: &Args, iface: &str) -> Self {
+        let hints = match &args.state_dir {
+            Some(dir) => Some(dir.join(format!("{iface}.hints"))),
+            None if args.pure => None,
+            None => Some(Hints::path(iface)),
+        };
+        let revocations = match &args.state_dir {
+            Some(dir) => Some(dir.join(format!("{iface}.revoked"))),
+            None if args.pure => None,
+            None => Some(Revocations::path(iface)),
+        };
+        let state = match &args.state_dir {
+            Some(dir) => Some(dir.join(format!("{iface}.state"))),
+            None if args.pure => None,
+            None => Some(Snapshot::path(iface)),
+        };
+
+        Self {
+            wg_config: args
+                .wg_config
+                .clone()
+                .unwrap_or_else(|| wg_config_path(iface)),
+            config: args
+                .config
+                .clone()
+                .or_else(|| (!args.pure).then(|| Config::path(iface))),
+            hints,
+            revocations,
+            state,
+        }
+    }
+}
+
+/// Flags, paths and settings the daemon would run with.
+fn effective_config(args: &Args, iface: &str, paths: &Paths, settings: &Config) -> Value {
+    let policy = |value: Option<PossibleValue>| value.map(|value| value.get_name().to_string());
+
+    Value::object([
+        ("iface", iface.into()),
+        ("network", args.network.clone().into()),
+        ("pure", args.pure.into()),
+        ("wg_config", paths.wg_config.as_str().into()),
+        ("config", paths.config.clone().into()),
+        (
+            "hints_file",
+            paths
+                .hints
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .into(),
+        ),
+        (
+            "revocations_file",
+            paths
+                .revocations
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .into(),
+        ),
+        (
+            "state_file",
+            paths
+                .state
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .into(),
+        ),
+        (
+            "stats_file",
+            args.stats_file
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .into(),
+        ),
+        ("irc_server", args.irc_server.clone().into()),
+        ("stun_server", args.stun_server.clone().into()),
+        ("relay", args.relay.clone().into()),
+        ("irc_channel", args.irc_channel.clone().into()),
+        ("irc_tls", args.irc_tls.into()),
+        ("irc_encrypt", args.irc_psk.is_some().into()),
+        ("irc_nickserv", args.irc_nickserv_password.is_some().into()),
+        ("irc_regain", args.irc_regain.into()),
+        ("proxy", args.proxy.as_ref().map(Proxy::to_string).into()),
+        ("server", args.server.map(|addr| addr.to_string()).into()),
+        ("amplify", args.amplify.into()),
+        ("obfuscate", args.obfuscate.into()),
+        ("topic", args.topic.into()),
+        ("disguise", policy(args.disguise.to_possible_value()).into()),
+        ("delta", args.delta.into()),
+        ("dht", args.dht.into()),
+        ("dht_fallback", args.dht_fallback.into()),
+        ("dns_zone", args.dns_zone.clone().into()),
+        (
+            "dns_resolver",
+            args.dns_resolver.map(|addr| addr.to_string()).into(),
+        ),
+        ("reannounce", args.reannounce.into()),
+        ("max_age", args.max_age.into()),
+        ("handshake_timeout", args.handshake_timeout.into()),
+        ("allow_unsigned", args.allow_unsigned.into()),
+        (
+            "provision_from",
+            args.provision_from.map(|key| key.to_string()).into(),
+        ),
+        (
+            "provision_file",
+            args.provision_file
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .into(),
+        ),
+        ("metadata", (!args.no_metadata).into()),
+        ("observe", args.observe.into()),
+        ("capture", args.capture.map(|key| key.to_string()).into()),
+        ("dbus", args.dbus.into()),
+        ("beacon", args.beacon.into()),
+        (
+            "port_mismatch",
+            policy(args.port_mismatch.to_possible_value()).into(),
+        ),
+        (
+            "wg_backend",
+            policy(args.wg_backend.to_possible_value()).into(),
+        ),
+        (
+            "discover",
+            args.discover
+                .iter()
+                .map(|kind| policy(kind.to_possible_value()))
+                .collect::<Vec<_>>()
+                .into(),
+        ),
+        (
+            "address_mismatch",
+            policy(args.address_mismatch.to_possible_value()).into(),
+        ),
+        (
+            "ddns",
+            Value::object([
+                ("name", args.ddns.ddns_name.clone().into()),
+                ("zone", args.ddns.ddns_zone.clone().into()),
+                (
+                    "server",
+                    args.ddns.ddns_server.map(|addr| addr.to_string()).into(),
+                ),
+                ("ttl", args.ddns.ddns_ttl.into()),
+                ("interval", args.ddns.ddns_interval.into()),
+            ]),
+        ),
+        ("settings", settings.to_json()),
+    ])
+}
+
+/// The `<iface>.conf` credential when systemd passes it, so an
+/// unprivileged service doesn't need access to /etc/wireguard.
+fn wg_config_path(iface: &str) -> String {
+    match systemd::credential(&format!("{iface}.conf")) {
+        Some(path) => path.display().to_string(),
+        None => android::path(&format!("/etc/wireguard/{iface}.conf"))
+            .display()
+            .to_string(),
+    }
 }
 
-fn load_wg_config(iface: &str) -> Result<WgConfig, Error> {
-    let data = fs::read_to_string(format!("/etc/wireguard/{iface}.conf"))?;
+fn load_wg_config(path: &str) -> Result<WgConfig, Error> {
+    let data = fs::read_to_string(path).map_err(|err| Error::ReadConfig(path.to_string(), err))?;
     let mut reader = data.as_str();
 
     Ok(WgConfig::parse_config(&mut reader)?)
@@ -0,0 +1,77 @@
+//! Metered connections, LTE links with a data cap. While on one the runner
+//! keeps signaling to what peers need to reach us.
+
+use std::{fs, process::Command};
+
+/// Whether NetworkManager considers `device`, or the device of the IPv4
+/// default route, metered. `false` without NetworkManager.
+pub fn detect(device: Option<&str>) -> bool {
+    let device = match device {
+        Some(device) => device.to_string(),
+        None => match fs::read_to_string("/proc/net/route") {
+            Ok(table) => match default_device(&table) {
+                Some(device) => device,
+                None => return false,
+            },
+            Err(_) => return false,
+        },
+    };
+
+    let output = Command::new("nmcli")
+        .args(["-g", "GENERAL.METERED", "device", "show", &device])
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => {
+            is_metered(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => false,
+    }
+}
+
+/// `yes`, `no`, `yes (guessed)`, `no (guessed)` or `unknown`, guesses are
+/// trusted.
+fn is_metered(metered: &str) -> bool {
+    metered.trim().starts_with("yes")
+}
+
+/// Interface of the default route in `/proc/net/route`, the one with the
+/// lowest metric.
+fn default_device(table: &str) -> Option<String> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (iface, dest, metric, mask) = (
+                fields.first()?,
+                fields.get(1)?,
+                fields.get(6)?,
+                fields.get(7)?,
+            );
+
+            (*dest == "00000000" && *mask == "00000000")
+                .then(|| (metric.parse::<u32>().unwrap_or(u32::MAX), iface.to_string()))
+        })
+        .min()
+        .map(|(_, iface)| iface)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_device, is_metered};
+
+    #[test]
+    fn test_detect() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                     eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n\
+                     wwan0\t00000000\t0102A8C0\t0003\t0\t0\t700\t00000000\t0\t0\t0\n\
+                     wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n";
+        assert_eq!(default_device(table).as_deref(), Some("wlan0"));
+        assert_eq!(default_device("Iface\tDestination\n"), None);
+
+        assert!(is_metered("yes (guessed)\n"));
+        assert!(!is_metered("no\n"));
+        assert!(!is_metered("unknown\n"));
+    }
+}
@@ -0,0 +1,312 @@
+//! Prometheus text exposition of the mesh, `GET /metrics` of the HTTP API.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use hashes::sha2::sha256;
+
+use crate::{
+    control::{PeerStatus, Status},
+    runner::PEER_DOWN_AFTER,
+    wg::{Key, executor::Timing},
+};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// `peer` label values. Keys change with every re-keyed device and are
+/// unreadable on dashboards, so peers are labelled by their `[alias]` name,
+/// others by a short hash of the key that stays the same across restarts.
+#[derive(Debug, Clone, Default)]
+pub struct Labels {
+    aliases: HashMap<Key, String>,
+
+    /// Peers expected to show up: the ones of the wireguard config and the
+    /// aliased ones.
+    configured: Vec<Key>,
+}
+
+impl Labels {
+    pub fn new(aliases: &BTreeMap<String, Key>, configured: impl IntoIterator<Item = Key>) -> Self {
+        let aliases: HashMap<Key, String> = aliases
+            .iter()
+            .map(|(name, key)| (*key, name.clone()))
+            .collect();
+
+        let mut configured: Vec<Key> = configured.into_iter().collect();
+        configured.extend(aliases.keys().copied());
+        configured.sort_by_key(|key| *key.as_bytes());
+        configured.dedup();
+
+        Self {
+            aliases,
+            configured,
+        }
+    }
+
+    pub fn label(&self, key: &Key) -> String {
+        match self.aliases.get(key) {
+            Some(alias) => alias.clone(),
+            None => {
+                let hash = sha256::hash(key.as_bytes()).into_bytes();
+                let hex: String = hash[..6].iter().map(|b| format!("{b:02x}")).collect();
+                format!("key-{hex}")
+            }
+        }
+    }
+}
+
+/// Renders the metrics at unix time `now`, seconds.
+pub fn render(now: u64, status: &Status, peers: &[PeerStatus], labels: &Labels) -> String {
+    let mut out = String::new();
+
+    metric(
+        &mut out,
+        "wg_disco_frozen",
+        "gauge",
+        "Changes are not applied.",
+    );
+    let _ = writeln!(out, "wg_disco_frozen {}", u8::from(status.frozen));
+
+    if let Some(protocol) = status.min_protocol {
+        metric(
+            &mut out,
+            "wg_disco_min_peer_protocol",
+            "gauge",
+            "Lowest protocol version of the live peers, 0 predates versioning.",
+        );
+        let _ = writeln!(out, "wg_disco_min_peer_protocol {protocol}");
+    }
+
+    metric(
+        &mut out,
+        "wg_disco_feature_unsupported_peers",
+        "gauge",
+        "Live peers too old for an enabled feature.",
+    );
+    for unsupported in &status.unsupported {
+        let _ = writeln!(
+            out,
+            "wg_disco_feature_unsupported_peers{{feature=\"{}\"}} {}",
+            unsupported.feature.name(),
+            unsupported.peers.len()
+        );
+    }
+
+    metric(
+        &mut out,
+        "wg_disco_wg_operation_duration_seconds",
+        "summary",
+        "Time wireguard operations took, waits and retries included.",
+    );
+    for (op, timing) in &status.wg_operations {
+        let (sum, count) = (timing.total.as_secs_f64(), timing.calls);
+        let _ = writeln!(
+            out,
+            "wg_disco_wg_operation_duration_seconds_sum{{op=\"{op}\"}} {sum}"
+        );
+        let _ = writeln!(
+            out,
+            "wg_disco_wg_operation_duration_seconds_count{{op=\"{op}\"}} {count}"
+        );
+    }
+
+    let operations = [
+        (
+            "wg_disco_wg_operation_failures_total",
+            "Wireguard operations which failed after their last attempt.",
+            (|timing| timing.failures) as fn(&Timing) -> u64,
+        ),
+        (
+            "wg_disco_wg_operation_retries_total",
+            "Wireguard operation attempts repeated after transient failures.",
+            |timing| timing.retries,
+        ),
+    ];
+    for (name, help, value) in operations {
+        metric(&mut out, name, "counter", help);
+        for (op, timing) in &status.wg_operations {
+            let _ = writeln!(out, "{name}{{op=\"{op}\"}} {}", value(timing));
+        }
+    }
+
+    let peers: Vec<(String, &PeerStatus)> = peers
+        .iter()
+        .map(|peer| (escape(&labels.label(&peer.key)), peer))
+        .collect();
+
+    let mut series =
+        |name: &str, kind: &str, help: &str, value: &dyn Fn(&PeerStatus) -> Option<u64>| {
+            metric(&mut out, name, kind, help);
+            for (label, peer) in &peers {
+                if let Some(value) = value(peer) {
+                    let _ = writeln!(out, "{name}{{peer=\"{label}\"}} {value}");
+                }
+            }
+        };
+
+    series(
+        "wg_disco_peer_up",
+        "gauge",
+        "Handshake with the peer within the last 180 seconds.",
+        &|peer| {
+            let age = peer
+                .latest_handshake
+                .map(|at| now.saturating_sub(at as u64));
+            Some(
+                age.is_some_and(|age| age <= PEER_DOWN_AFTER.as_secs())
+                    .into(),
+            )
+        },
+    );
+    series(
+        "wg_disco_peer_latest_handshake_seconds",
+        "gauge",
+        "Unix time of the latest handshake.",
+        &|peer| peer.latest_handshake.map(u64::from),
+    );
+    series(
+        "wg_disco_peer_receive_bytes_total",
+        "counter",
+        "Bytes received from the peer.",
+        &|peer| peer.transfer.map(|(rx, _)| rx),
+    );
+    series(
+        "wg_disco_peer_transmit_bytes_total",
+        "counter",
+        "Bytes sent to the peer.",
+        &|peer| peer.transfer.map(|(_, tx)| tx),
+    );
+
+    metric(
+        &mut out,
+        "wg_disco_peer_never_seen",
+        "gauge",
+        "Configured peer without a handshake so far.",
+    );
+    for key in &labels.configured {
+        let seen = peers
+            .iter()
+            .any(|(_, peer)| peer.key == *key && peer.latest_handshake.is_some());
+        let label = escape(&labels.label(key));
+        let _ = writeln!(
+            out,
+            "wg_disco_peer_never_seen{{peer=\"{label}\"}} {}",
+            u8::from(!seen)
+        );
+    }
+
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Label values are quoted, backslashes, quotes and newlines are escaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use crate::{
+        control::{PeerStatus, Status, Unsupported},
+        signaling::Feature,
+        wg::{Key, executor::Timing},
+    };
+
+    use super::{Labels, render};
+
+    #[test]
+    fn test_render() {
+        let (laptop, phone, server) = (Key::random(), Key::random(), Key::random());
+        let aliases = BTreeMap::from([("laptop".to_string(), laptop)]);
+        let labels = Labels::new(&aliases, [phone, server]);
+
+        let hashed = labels.label(&phone);
+        assert!(hashed.starts_with("key-"));
+        assert_eq!(Labels::new(&BTreeMap::new(), []).label(&phone), hashed);
+
+        let peer = |key, latest_handshake| PeerStatus {
+            key,
+            endpoint: None,
+            latest_handshake,
+            transfer: Some((10, 20)),
+            routes: Vec::new(),
+            nat: false,
+            nat_type: None,
+            server: false,
+            candidates: Vec::new(),
+            online: true,
+            transport: None,
+            pinned_for: None,
+            bandwidth: None,
+            meta: None,
+            exit: false,
+            debug: false,
+            blocked: false,
+        };
+        let status = Status {
+            iface: "wg0".into(),
+            key: Key::random(),
+            endpoint: None,
+            listen_port: 51820,
+            nat_type: None,
+            server: false,
+            kill_switch: false,
+            peers: 2,
+            frozen: false,
+            metered: false,
+            advertise_routes: Vec::new(),
+            min_protocol: Some(0),
+            unsupported: vec![Unsupported {
+                feature: Feature::Delta,
+                peers: vec![phone],
+            }],
+            wg_operations: vec![(
+                "set_peer_endpoints",
+                Timing {
+                    calls: 4,
+                    failures: 1,
+                    retries: 2,
+                    total: Duration::from_millis(1500),
+                    max: Duration::from_secs(1),
+                },
+            )],
+        };
+        let peers = [peer(laptop, Some(1000)), peer(phone, None)];
+        let metrics = render(1100, &status, &peers, &labels);
+
+        assert!(metrics.contains("wg_disco_min_peer_protocol 0\n"));
+        assert!(metrics.contains(
+            "wg_disco_wg_operation_duration_seconds_sum{op=\"set_peer_endpoints\"} 1.5\n"
+        ));
+        assert!(metrics.contains(
+            "wg_disco_wg_operation_duration_seconds_count{op=\"set_peer_endpoints\"} 4\n"
+        ));
+        assert!(
+            metrics.contains("wg_disco_wg_operation_retries_total{op=\"set_peer_endpoints\"} 2\n")
+        );
+        assert!(metrics.contains("wg_disco_feature_unsupported_peers{feature=\"delta\"} 1\n"));
+        assert!(metrics.contains("wg_disco_peer_up{peer=\"laptop\"} 1\n"));
+        assert!(metrics.contains(&format!("wg_disco_peer_up{{peer=\"{hashed}\"}} 0\n")));
+        assert!(metrics.contains("wg_disco_peer_receive_bytes_total{peer=\"laptop\"} 10\n"));
+        assert!(metrics.contains("wg_disco_peer_never_seen{peer=\"laptop\"} 0\n"));
+        assert!(metrics.contains(&format!(
+            "wg_disco_peer_never_seen{{peer=\"{hashed}\"}} 1\n"
+        )));
+
+        let server = labels.label(&server);
+        assert!(metrics.contains(&format!(
+            "wg_disco_peer_never_seen{{peer=\"{server}\"}} 1\n"
+        )));
+    }
+}
@@ -0,0 +1,83 @@
+//! Debug logging of single peers, switched on at runtime through the
+//! control API instead of for the whole mesh.
+
+use std::sync::{
+    RwLock,
+    atomic::{AtomicBool, Ordering},
+};
+
+use crate::wg::Key;
+
+/// Log target of per-peer debug messages, the logger has to let its debug
+/// level through.
+pub const TARGET: &str = "wg_disco::peer";
+
+static PEERS: RwLock<Vec<Key>> = RwLock::new(Vec::new());
+
+/// Skips the lock while no peer is debugged, which is nearly always.
+static ANY: AtomicBool = AtomicBool::new(false);
+
+/// Logs messages of `key` at debug level from now on.
+pub fn enable(key: Key) {
+    let mut peers = PEERS.write().unwrap();
+    if !peers.contains(&key) {
+        peers.push(key);
+    }
+
+    ANY.store(true, Ordering::Relaxed);
+}
+
+/// `false` when it wasn't enabled.
+pub fn disable(key: &Key) -> bool {
+    let mut peers = PEERS.write().unwrap();
+    let len = peers.len();
+    peers.retain(|peer| peer != key);
+
+    ANY.store(!peers.is_empty(), Ordering::Relaxed);
+    peers.len() != len
+}
+
+pub fn enabled() -> Vec<Key> {
+    PEERS.read().unwrap().clone()
+}
+
+#[inline]
+pub fn is_enabled(key: &Key) -> bool {
+    ANY.load(Ordering::Relaxed) && PEERS.read().unwrap().contains(key)
+}
+
+/// `log::debug!` about peer `key`, logged under [`TARGET`] while the peer
+/// is debugged whatever the log level is.
+#[macro_export]
+macro_rules! peer_debug {
+    ($key:expr, $($arg:tt)+) => {
+        if $crate::peerlog::is_enabled(&$key) {
+            log::debug!(target: $crate::peerlog::TARGET, $($arg)+);
+        } else {
+            log::debug!($($arg)+);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wg::Key;
+
+    use super::{disable, enable, enabled, is_enabled};
+
+    #[test]
+    fn test_enable_peer() {
+        let key = Key::random();
+        let other = Key::random();
+
+        enable(key);
+        enable(key);
+        assert!(is_enabled(&key));
+        assert!(!is_enabled(&other));
+        assert_eq!(enabled().iter().filter(|peer| **peer == key).count(), 1);
+
+        assert!(disable(&key));
+        assert!(!disable(&key));
+        assert!(!is_enabled(&key));
+    }
+}
@@ -0,0 +1,566 @@
+//! Provisioning of new peers. A coordinator renders the client config of
+//! every peer listed in its `[provision]` section from templates and sends
+//! it to the peer when asked, the peer saves it as a wg-quick config.
+//! `wg-disco gen-client` adds such clients, see [`NewClient`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use bincode::{Decode, Encode};
+use serde::Deserialize;
+
+use crate::{
+    config::IrcSettings,
+    crypto::{x25519_base, xeddsa_sign, xeddsa_verify},
+    fs::write_atomic,
+    signaling::BINCODE_CONFIG,
+    wg::{
+        Cidr, Key,
+        config::{ParseError, WgConfig, WgConfigPeer},
+    },
+};
+
+/// Signed configs are prefixed so a signature can't be replayed as
+/// anything else.
+const CONTEXT: &[u8] = b"wg-disco provision";
+
+/// Client numbers [`ProvisionConfig::allocate`] tries, templates usually
+/// stop rendering addresses long before.
+const MAX_CLIENTS: u32 = 65535;
+
+/// `[provision]` section of a coordinator's config. `{n}` in the templates
+/// is replaced with the number of the client, `{name}` with its name.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvisionConfig {
+    /// Tunnel address, e.g. `10.0.0.{n}/32`.
+    pub address: String,
+
+    /// Routes clients send through the coordinator, their AllowedIPs of it.
+    pub routes: Vec<String>,
+
+    pub dns: Vec<String>,
+
+    /// `[provision.client.<name>]` peers allowed to ask.
+    pub client: BTreeMap<String, ProvisionClient>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProvisionClient {
+    pub key: Key,
+    pub n: u32,
+
+    /// Tunnel address instead of the one of the template.
+    #[serde(default)]
+    pub address: Option<Cidr>,
+}
+
+impl ProvisionConfig {
+    /// Config of every client.
+    pub fn render(&self) -> Result<HashMap<Key, ClientConfig>, ParseError> {
+        self.client
+            .iter()
+            .map(|(name, client)| Ok((client.key, self.render_client(name, client)?)))
+            .collect()
+    }
+
+    /// Config of client `name`.
+    pub fn render_client(
+        &self,
+        name: &str,
+        client: &ProvisionClient,
+    ) -> Result<ClientConfig, ParseError> {
+        let render = |template: &str| {
+            template
+                .replace("{n}", &client.n.to_string())
+                .replace("{name}", name)
+        };
+
+        Ok(ClientConfig {
+            address: match client.address {
+                Some(address) => address,
+                None => render(&self.address).parse()?,
+            },
+            routes: self
+                .routes
+                .iter()
+                .map(|route| render(route).parse())
+                .collect::<Result<_, _>>()?,
+            dns: self
+                .dns
+                .iter()
+                .map(|dns| render(dns).trim().parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Addresses in use on the coordinator: its own, routed to its peers
+    /// and of the clients. Default routes of exit peers don't count.
+    pub fn taken(&self, wg: &WgConfig) -> Vec<Cidr> {
+        let mut taken = vec![host(wg.interface.address.ip)];
+
+        taken.extend(
+            wg.peers
+                .iter()
+                .flat_map(|peer| peer.allowed_ips.iter().flatten())
+                .filter(|cidr| cidr.mask > 0),
+        );
+        taken.extend(
+            self.client
+                .iter()
+                .filter_map(|(name, client)| self.render_client(name, client).ok())
+                .map(|config| config.address),
+        );
+
+        taken
+    }
+
+    /// Lowest client number from 2 on, the coordinator usually being 1,
+    /// with a free address. `None` once the template renders no address.
+    pub fn allocate(&self, name: &str, taken: &[Cidr]) -> Option<(u32, Cidr)> {
+        for n in 2..=MAX_CLIENTS {
+            if self.client.values().any(|client| client.n == n) {
+                continue;
+            }
+
+            let client = ProvisionClient {
+                key: Key::default(),
+                n,
+                address: None,
+            };
+            let address = self.render_client(name, &client).ok()?.address;
+            if !is_taken(taken, &address) {
+                return Some((n, address));
+            }
+        }
+
+        None
+    }
+}
+
+/// Whether the address of `cidr` is in use.
+pub fn is_taken(taken: &[Cidr], cidr: &Cidr) -> bool {
+    taken.iter().any(|used| used.contains(&host(cidr.ip)))
+}
+
+fn host(ip: IpAddr) -> Cidr {
+    Cidr {
+        ip,
+        mask: if ip.is_ipv4() { 32 } else { 128 },
+    }
+}
+
+/// What a peer needs to join besides its private key.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct ClientConfig {
+    pub address: Cidr,
+    pub routes: Vec<Cidr>,
+    pub dns: Vec<IpAddr>,
+}
+
+impl ClientConfig {
+    /// Signs the config for `recipient` with the coordinator's wireguard
+    /// key, it can't be handed to anybody else.
+    pub fn sign(&self, private_key: &Key, recipient: &Key) -> SignedConfig {
+        let msg = message(self, recipient);
+
+        SignedConfig {
+            config: self.clone(),
+            signature: xeddsa_sign(private_key.as_bytes(), &msg, &rand::random()),
+        }
+    }
+
+    /// wg-quick config with the coordinator as the only peer, without a
+    /// `private_key` it is left for the client to fill in.
+    pub fn to_wg_quick(&self, coordinator: &Key, private_key: Option<&Key>) -> String {
+        let join = |items: Vec<String>| items.join(", ");
+
+        let mut config = format!("# provisioned by {coordinator}\n[Interface]\n");
+        if let Some(private_key) = private_key {
+            config.push_str(&format!("PrivateKey = {private_key}\n"));
+        }
+        config.push_str(&format!("Address = {}\n", self.address));
+        if !self.dns.is_empty() {
+            let dns = join(self.dns.iter().map(IpAddr::to_string).collect());
+            config.push_str(&format!("DNS = {dns}\n"));
+        }
+
+        config.push_str(&format!("\n[Peer]\nPublicKey = {coordinator}\n"));
+        if !self.routes.is_empty() {
+            let routes = join(self.routes.iter().map(Cidr::to_string).collect());
+            config.push_str(&format!("AllowedIPs = {routes}\n"));
+        }
+
+        config
+    }
+
+    /// Readable by the owner only, like the wg-quick configs it sits next
+    /// to.
+    pub fn save(&self, path: &Path, coordinator: &Key) -> io::Result<()> {
+        write_atomic(path, self.to_wg_quick(coordinator, None), 0o600)
+    }
+}
+
+/// [`ClientConfig`] signed by the coordinator for one recipient.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct SignedConfig {
+    pub config: ClientConfig,
+    signature: [u8; 64],
+}
+
+impl SignedConfig {
+    /// Whether `coordinator` signed it for `recipient`.
+    pub fn verify(&self, coordinator: &Key, recipient: &Key) -> bool {
+        xeddsa_verify(
+            coordinator.as_bytes(),
+            &message(&self.config, recipient),
+            &self.signature,
+        )
+    }
+}
+
+/// Client added on the coordinator by `wg-disco gen-client`.
+#[derive(Debug, Clone)]
+pub struct NewClient {
+    pub name: String,
+    pub client: ProvisionClient,
+
+    /// Generated unless the client brought its own key pair.
+    pub private_key: Option<Key>,
+    pub config: ClientConfig,
+}
+
+impl NewClient {
+    /// Client `name` with the next free address, or `address` when given.
+    /// Without a `key` a key pair is generated.
+    pub fn allocate(
+        provision: &ProvisionConfig,
+        wg: &WgConfig,
+        name: &str,
+        address: Option<Cidr>,
+        key: Option<Key>,
+    ) -> Result<Self, GenClientError> {
+        if provision.client.contains_key(name) {
+            return Err(GenClientError::NameTaken(name.to_string()));
+        }
+        if let Some(key) = key
+            && (provision.client.values().any(|client| client.key == key)
+                || wg.peers.iter().any(|peer| peer.public_key == key))
+        {
+            return Err(GenClientError::KeyTaken(key));
+        }
+
+        let taken = provision.taken(wg);
+        if let Some(address) = address
+            && is_taken(&taken, &address)
+        {
+            return Err(GenClientError::AddressTaken(address));
+        }
+        let (n, _) = provision
+            .allocate(name, &taken)
+            .ok_or(GenClientError::NoAddress)?;
+
+        let (key, private_key) = match key {
+            Some(key) => (key, None),
+            None => {
+                let mut private = Key::random().as_bytes().to_owned();
+                private[0] &= 248;
+                private[31] = (private[31] & 127) | 64;
+                (Key::from(x25519_base(&private)), Some(Key::from(private)))
+            }
+        };
+
+        let client = ProvisionClient { key, n, address };
+        Ok(Self {
+            config: provision.render_client(name, &client)?,
+            name: name.to_string(),
+            client,
+            private_key,
+        })
+    }
+
+    /// `[provision.client.<name>]` entry for the coordinator's config.
+    pub fn provision_entry(&self) -> String {
+        let mut entry = format!(
+            "\n[provision.client.{}]\nkey = \"{}\"\nn = {}\n",
+            toml_key(&self.name),
+            self.client.key,
+            self.client.n
+        );
+        if let Some(address) = self.client.address {
+            entry.push_str(&format!("address = \"{address}\"\n"));
+        }
+
+        entry
+    }
+
+    /// The client as a peer of the coordinator.
+    pub fn peer(&self) -> WgConfigPeer {
+        WgConfigPeer {
+            public_key: self.client.key,
+            allowed_ips: Some(vec![host(self.config.address.ip)]),
+            ..Default::default()
+        }
+    }
+
+    /// `[Peer]` section for the coordinator's wireguard config.
+    pub fn peer_entry(&self) -> String {
+        format!(
+            "\n[Peer]\nPublicKey = {}\nAllowedIPs = {}\n",
+            self.client.key,
+            host(self.config.address.ip)
+        )
+    }
+
+    /// wg-disco config of the client, meeting the coordinator on its IRC
+    /// channel.
+    pub fn settings(&self, irc: &IrcSettings) -> String {
+        let mut settings = format!("# wg-disco config of {}\n[irc]\n", self.name);
+        if !irc.servers.is_empty() {
+            let servers: Vec<_> = irc
+                .servers
+                .iter()
+                .map(|server| format!("{server:?}"))
+                .collect();
+            settings.push_str(&format!("servers = [{}]\n", servers.join(", ")));
+        }
+        if let Some(channel) = &irc.channel {
+            settings.push_str(&format!("channel = {channel:?}\n"));
+        }
+        if irc.tls {
+            settings.push_str("tls = true\n");
+        }
+        if let Some(psk) = &irc.psk {
+            settings.push_str(&format!("psk = {psk:?}\n"));
+        }
+
+        settings
+    }
+
+    /// Writes `<name>.conf` and `<name>.toml` to `dir`, the first one
+    /// readable by the owner only as it may hold the private key.
+    pub fn save(
+        &self,
+        dir: &Path,
+        coordinator: &Key,
+        irc: &IrcSettings,
+    ) -> io::Result<(PathBuf, PathBuf)> {
+        fs::create_dir_all(dir)?;
+        let conf = dir.join(format!("{}.conf", self.name));
+        let settings = dir.join(format!("{}.toml", self.name));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        options.open(&conf)?.write_all(
+            self.config
+                .to_wg_quick(coordinator, self.private_key.as_ref())
+                .as_bytes(),
+        )?;
+        fs::write(&settings, self.settings(irc))?;
+
+        Ok((conf, settings))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GenClientError {
+    #[error("client {0} exists already")]
+    NameTaken(String),
+
+    #[error("key {0} belongs to another peer")]
+    KeyTaken(Key),
+
+    #[error("address {0} is taken")]
+    AddressTaken(Cidr),
+
+    #[error("the [provision] address template has no free address left")]
+    NoAddress,
+
+    #[error("can't render client config: {0}")]
+    Template(#[from] ParseError),
+}
+
+/// Names which aren't bare TOML keys are quoted.
+fn toml_key(name: &str) -> String {
+    match !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        true => name.to_string(),
+        false => format!("{name:?}"),
+    }
+}
+
+fn message(config: &ClientConfig, recipient: &Key) -> Vec<u8> {
+    let mut msg = CONTEXT.to_vec();
+    msg.extend(recipient.as_bytes());
+    // encoding into a vec only fails on values bincode can't represent
+    msg.extend(bincode::encode_to_vec(config, BINCODE_CONFIG).unwrap_or_default());
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::Config,
+        crypto::x25519_base,
+        signaling::{Extensions, PeerUpdate, codec},
+        wg::{Key, config::WgConfig},
+    };
+
+    use super::{GenClientError, NewClient, ProvisionConfig};
+
+    #[test]
+    fn test_provision() {
+        let (laptop, phone) = (Key::random(), Key::random());
+        let mut config: ProvisionConfig = toml::from_str(&format!(
+            r#"address = "10.0.0.{{n}}/32"
+routes = ["10.0.0.0/24", "192.168.{{n}}.0/24"]
+dns = ["10.0.0.1"]
+
+[client.laptop]
+key = "{laptop}"
+n = 5
+
+[client.phone]
+key = "{phone}"
+n = 6
+"#
+        ))
+        .unwrap();
+
+        let configs = config.render().unwrap();
+        let laptop_config = &configs[&laptop];
+        assert_eq!(laptop_config.address, "10.0.0.5/32".parse().unwrap());
+        assert_eq!(configs[&phone].routes[1], "192.168.6.0/24".parse().unwrap());
+
+        let private = Key::random();
+        let coordinator = Key::from(x25519_base(private.as_bytes()));
+        assert_eq!(
+            laptop_config.to_wg_quick(&coordinator, None),
+            format!(
+                "# provisioned by {coordinator}\n[Interface]\nAddress = 10.0.0.5/32\n\
+                 DNS = 10.0.0.1\n\n[Peer]\nPublicKey = {coordinator}\n\
+                 AllowedIPs = 10.0.0.0/24, 192.168.5.0/24\n"
+            )
+        );
+
+        let signed = laptop_config.sign(&private, &laptop);
+        assert!(signed.verify(&coordinator, &laptop));
+        assert!(!signed.verify(&coordinator, &phone));
+        assert!(!signed.verify(&laptop, &laptop));
+
+        // carried by a targeted announcement
+        let mut update = PeerUpdate {
+            key: coordinator,
+            endpoint: "203.0.113.7:51820".parse().unwrap(),
+            advertise_routes: vec![],
+            ext: Extensions {
+                timestamp: Some(1_700_000_000_000),
+                ..Default::default()
+            },
+        };
+        update.ext.config = Some(signed.clone());
+        let mut msg = String::new();
+        codec::encode(&update, &mut msg).unwrap();
+        assert_eq!(codec::decode(&msg).unwrap().ext.config, Some(signed));
+
+        // templates rendering to something else than an address
+        config.address = "10.0.0.{name}/32".into();
+        assert!(config.render().is_err());
+    }
+
+    #[test]
+    fn test_new_client() {
+        let (coordinator, exit, tablet) = (Key::random(), Key::random(), Key::random());
+        let wg_conf = format!(
+            "[Interface]\nPrivateKey = {coordinator}\nAddress = 10.0.0.1/24\n\n\
+             [Peer]\nPublicKey = {exit}\nAllowedIPs = 10.0.0.2/32, 0.0.0.0/0\n"
+        );
+        let settings = format!(
+            "[provision]\naddress = \"10.0.0.{{n}}/32\"\nroutes = [\"10.0.0.0/24\"]\n\n\
+             [provision.client.tablet]\nkey = \"{tablet}\"\nn = 3\n"
+        );
+        let provision = |settings: &str| -> ProvisionConfig {
+            toml::from_str::<Config>(settings)
+                .unwrap()
+                .provision
+                .unwrap()
+        };
+        let wg = WgConfig::parse_config(&mut wg_conf.as_str()).unwrap();
+
+        // .1 is the coordinator, .2 routed to a peer, .3 provisioned
+        let client = NewClient::allocate(&provision(&settings), &wg, "laptop", None, None).unwrap();
+        assert_eq!(client.client.n, 4);
+        assert_eq!(client.config.address, "10.0.0.4/32".parse().unwrap());
+        let private = client.private_key.unwrap();
+        assert_eq!(
+            Key::from(x25519_base(private.as_bytes())),
+            client.client.key
+        );
+        assert!(
+            client
+                .config
+                .to_wg_quick(&coordinator, Some(&private))
+                .contains(&format!(
+                    "[Interface]\nPrivateKey = {private}\nAddress = 10.0.0.4/32\n"
+                ))
+        );
+
+        // the entries read back as the new peer and client
+        let provision = provision(&(settings + &client.provision_entry()));
+        assert_eq!(
+            provision.render().unwrap()[&client.client.key],
+            client.config
+        );
+        let wg_conf = wg_conf + &client.peer_entry();
+        let wg = WgConfig::parse_config(&mut wg_conf.as_str()).unwrap();
+        assert_eq!(wg.peers[1], client.peer());
+
+        // own key and address, both checked against what is there
+        let phone = Key::random();
+        let client = NewClient::allocate(
+            &provision,
+            &wg,
+            "phone",
+            Some("10.0.0.9".parse().unwrap()),
+            Some(phone),
+        )
+        .unwrap();
+        assert_eq!((client.client.n, client.private_key), (5, None));
+        assert!(
+            client
+                .provision_entry()
+                .ends_with("address = \"10.0.0.9/32\"\n")
+        );
+
+        assert!(matches!(
+            NewClient::allocate(&provision, &wg, "laptop", None, None),
+            Err(GenClientError::NameTaken(_))
+        ));
+        assert!(matches!(
+            NewClient::allocate(&provision, &wg, "tv", None, Some(tablet)),
+            Err(GenClientError::KeyTaken(_))
+        ));
+        assert!(matches!(
+            NewClient::allocate(
+                &provision,
+                &wg,
+                "tv",
+                Some("10.0.0.4".parse().unwrap()),
+                None
+            ),
+            Err(GenClientError::AddressTaken(_))
+        ));
+    }
+}
@@ -0,0 +1,278 @@
+//! Tunnels to signaling servers through a SOCKS5 or HTTP CONNECT proxy, for
+//! networks whose only way out is a proxy.
+
+use std::{fmt, net::IpAddr, str::FromStr, time::Duration};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{dial, wg::config::ParseError};
+
+/// Connecting to the proxy and its handshake.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest CONNECT response head read from an HTTP proxy.
+const MAX_RESPONSE_LEN: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// RFC 1928, names are resolved by the proxy.
+    Socks5,
+
+    /// `CONNECT host:port` of an HTTP proxy.
+    Http,
+}
+
+/// Proxy given as `socks5://[user:password@]host:port` or
+/// `http://[user:password@]host:port`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    auth: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// Opens a connection to `host:port` through the proxy.
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let handshake = async {
+            let mut stream = dial::connect(&self.host, self.port).await?;
+            match self.kind {
+                ProxyKind::Socks5 => self.socks5(&mut stream, host, port).await?,
+                ProxyKind::Http => self.http(&mut stream, host, port).await?,
+            }
+            Ok(stream)
+        };
+
+        tokio::time::timeout(TIMEOUT, handshake)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "proxy timed out"))?
+    }
+
+    async fn socks5(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let methods: &[u8] = match self.auth {
+            Some(_) => &[0, 2],
+            None => &[0],
+        };
+        stream.write_all(&[5, methods.len() as u8]).await?;
+        stream.write_all(methods).await?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+
+        match (reply, &self.auth) {
+            ([5, 0], _) => {}
+            ([5, 2], Some((user, password))) => {
+                let mut req = vec![1, field_len(user)?];
+                req.extend(user.as_bytes());
+                req.push(field_len(password)?);
+                req.extend(password.as_bytes());
+                stream.write_all(&req).await?;
+
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "socks5 proxy refused the credentials",
+                    ));
+                }
+            }
+            _ => return Err(io::Error::other("socks5 proxy accepts no offered method")),
+        }
+
+        let mut req = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                req.push(1);
+                req.extend(ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                req.push(4);
+                req.extend(ip.octets());
+            }
+            Err(_) => {
+                req.extend([3, field_len(host)?]);
+                req.extend(host.as_bytes());
+            }
+        }
+        req.extend(port.to_be_bytes());
+        stream.write_all(&req).await?;
+
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await?;
+        if head[1] != 0 {
+            return Err(io::Error::other(format!(
+                "socks5 proxy can't connect to {host}:{port}, reply {}",
+                head[1]
+            )));
+        }
+
+        // the bound address is of no use
+        let len = match head[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8().await? as usize,
+            _ => return Err(io::Error::other("socks5 proxy sent a bad address type")),
+        };
+        let mut bound = vec![0u8; len + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(())
+    }
+
+    async fn http(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let target = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{ip}]:{port}"),
+            _ => format!("{host}:{port}"),
+        };
+
+        let mut req = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some((user, password)) = &self.auth {
+            let credentials = BASE64_STANDARD.encode(format!("{user}:{password}"));
+            req.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+        }
+        req.push_str("\r\n");
+        stream.write_all(req.as_bytes()).await?;
+
+        // byte by byte, what follows the head belongs to the tunnel
+        let mut head = Vec::with_capacity(256);
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_RESPONSE_LEN {
+                return Err(io::Error::other("http proxy response too long"));
+            }
+            head.push(stream.read_u8().await?);
+        }
+
+        let head = String::from_utf8_lossy(&head);
+        let status = head.lines().next().unwrap_or_default();
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "http proxy can't connect to {target}: {status}"
+            ))),
+        }
+    }
+}
+
+fn field_len(field: &str) -> io::Result<u8> {
+    u8::try_from(field.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too long for socks5"))
+}
+
+impl FromStr for Proxy {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s.split_once("://").ok_or(ParseError::Expected(':'))?;
+        let kind = match scheme {
+            "socks5" | "socks5h" => ProxyKind::Socks5,
+            "http" => ProxyKind::Http,
+            _ => return Err(ParseError::UnexpectedToken),
+        };
+
+        let rest = rest.trim_end_matches('/');
+        let (auth, addr) = match rest.rsplit_once('@') {
+            Some((auth, addr)) => {
+                let (user, password) = auth.split_once(':').ok_or(ParseError::Expected(':'))?;
+                (Some((user.to_string(), password.to_string())), addr)
+            }
+            None => (None, rest),
+        };
+
+        let (host, port) = addr.rsplit_once(':').ok_or(ParseError::Expected(':'))?;
+
+        Ok(Self {
+            kind,
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: port.parse()?,
+            auth,
+        })
+    }
+}
+
+/// Without the password, for logs and `--print-config`.
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.kind {
+            ProxyKind::Socks5 => "socks5",
+            ProxyKind::Http => "http",
+        };
+
+        write!(f, "{scheme}://")?;
+        if let Some((user, _)) = &self.auth {
+            write!(f, "{user}@")?;
+        }
+
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Proxy({self})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{Proxy, ProxyKind};
+
+    #[test]
+    fn test_parse() {
+        let proxy: Proxy = "socks5://user:p@ss@proxy.example:1080".parse().unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Socks5);
+        assert_eq!((proxy.host.as_str(), proxy.port), ("proxy.example", 1080));
+        assert_eq!(proxy.to_string(), "socks5://user@proxy.example:1080");
+
+        let proxy: Proxy = "http://[2001:db8::1]:3128/".parse().unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Http);
+        assert_eq!((proxy.host.as_str(), proxy.port), ("2001:db8::1", 3128));
+        assert_eq!(proxy.to_string(), "http://[2001:db8::1]:3128");
+
+        assert!("ftp://proxy:21".parse::<Proxy>().is_err());
+        assert!("http://proxy".parse::<Proxy>().is_err());
+        assert!("proxy:3128".parse::<Proxy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy: Proxy = format!("http://u:pw@{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 1024];
+            let len = stream.read(&mut buf).await.unwrap();
+            let req = String::from_utf8_lossy(&buf[..len]).into_owned();
+
+            assert!(req.starts_with("CONNECT irc.example:6667 HTTP/1.1\r\n"));
+            assert!(req.contains("Proxy-Authorization: Basic dTpwdw==\r\n"));
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n:server NOTICE")
+                .await
+                .unwrap();
+        });
+
+        let mut stream = proxy.connect("irc.example", 6667).await.unwrap();
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, ":server NOTICE");
+    }
+}
@@ -0,0 +1,497 @@
+//! Relaying of wireguard packets through a server over TCP, DERP-style, for
+//! peers punching never gets through to, e.g. both behind symmetric NATs.
+//! Every node keeps a connection to its home relay, announced to peers as
+//! the [`TRANSPORT`] transport, and sends the packets of a peer to the
+//! peer's home relay. Wireguard sees every relayed peer as a loopback UDP
+//! socket.
+//!
+//! Frames are a type byte, the big-endian payload length as `u16` and the
+//! payload. The server sends a random challenge, the client answers with
+//! its wireguard key and an XEdDSA signature over the challenge, so nobody
+//! gets the packets of a key it doesn't hold. Packets are then sent as the
+//! recipient's key followed by the packet and arrive as the sender's key
+//! followed by the packet. Packets are dropped when a queue is full, as UDP
+//! would.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::{
+    crypto::{x25519_base, xeddsa_sign, xeddsa_verify},
+    dial,
+    wg::Key,
+};
+
+/// Name the home relay is announced under among the transports.
+pub const TRANSPORT: &str = "relay";
+
+const CHALLENGE: u8 = 1;
+const HELLO: u8 = 2;
+const SEND: u8 = 3;
+const RECV: u8 = 4;
+
+const SIGNATURE_CONTEXT: &[u8] = b"wg-disco relay v1";
+
+/// Connections a server takes at most.
+const MAX_CLIENTS: usize = 4096;
+
+/// Packets queued towards one connection.
+const QUEUE: usize = 256;
+
+/// How long a client may take to prove its key.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First wait before reconnecting to a relay, doubling up to
+/// [`MAX_RECONNECT`].
+const RECONNECT: Duration = Duration::from_secs(1);
+const MAX_RECONNECT: Duration = Duration::from_secs(60);
+
+type Packet = (Key, Vec<u8>);
+
+/// Connections to relay servers and the peers relayed through them.
+#[derive(Debug, Clone)]
+pub struct RelayClient {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    private_key: Key,
+
+    /// Where wireguard listens, on loopback.
+    wg: SocketAddr,
+
+    /// Packets to send by relay server, one connection each.
+    servers: Mutex<HashMap<String, mpsc::Sender<Packet>>>,
+
+    /// Loopback sockets of relayed peers.
+    links: Mutex<HashMap<Key, Arc<UdpSocket>>>,
+
+    /// Peers relaying packets to us without a link, see
+    /// [`RelayClient::knocking`].
+    knocking: Mutex<Vec<(Key, String)>>,
+}
+
+impl RelayClient {
+    /// Connects to our `home` relay right away, so peers reach us through
+    /// it. Relayed packets go to wireguard's `port`.
+    pub fn new(private_key: Key, port: u16, home: &str) -> Self {
+        let client = Self {
+            inner: Arc::new(Inner {
+                private_key,
+                wg: SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+                servers: Default::default(),
+                links: Default::default(),
+                knocking: Default::default(),
+            }),
+        };
+        client.server(home);
+        client
+    }
+
+    /// Relays the packets of `peer` through its home relay `server`.
+    /// Wireguard is pointed at the [`Link::local`] address, dropping the
+    /// link stops relaying.
+    pub fn open(&self, peer: Key, server: &str) -> io::Result<Link> {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_nonblocking(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket)?);
+        let local = socket.local_addr()?;
+
+        let packets = self.server(server);
+        let task = tokio::spawn({
+            let socket = socket.clone();
+            async move {
+                let mut buf = vec![0; u16::MAX as usize];
+                while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                    if from.ip().is_loopback() {
+                        let _ = packets.try_send((peer, buf[..len].to_vec()));
+                    }
+                }
+            }
+        });
+
+        self.inner.links.lock().unwrap().insert(peer, socket);
+        Ok(Link {
+            inner: Arc::downgrade(&self.inner),
+            peer,
+            local,
+            task,
+        })
+    }
+
+    /// Peers which relay packets to us through a relay without us relaying
+    /// back, with the relay. Packets of theirs are dropped until a link is
+    /// [`open`](Self::open)ed.
+    pub fn knocking(&self) -> Vec<(Key, String)> {
+        std::mem::take(&mut *self.inner.knocking.lock().unwrap())
+    }
+
+    /// Sender of packets through `server`, connecting to it on first use.
+    fn server(&self, server: &str) -> mpsc::Sender<Packet> {
+        let mut servers = self.inner.servers.lock().unwrap();
+        if let Some(packets) = servers.get(server) {
+            return packets.clone();
+        }
+
+        let (tx, rx) = mpsc::channel(QUEUE);
+        servers.insert(server.to_string(), tx.clone());
+        tokio::spawn(connection(
+            Arc::downgrade(&self.inner),
+            server.to_string(),
+            rx,
+        ));
+        tx
+    }
+}
+
+/// Relayed peer, relaying stops on drop.
+#[derive(Debug)]
+pub struct Link {
+    inner: Weak<Inner>,
+    peer: Key,
+    local: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl Link {
+    /// Where wireguard sends the peer's packets to.
+    #[inline]
+    pub fn local(&self) -> SocketAddr {
+        self.local
+    }
+
+    /// Whether packets from wireguard are still relayed.
+    pub fn is_alive(&self) -> bool {
+        !self.task.is_finished()
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.task.abort();
+
+        if let Some(inner) = self.inner.upgrade() {
+            let mut links = inner.links.lock().unwrap();
+            if links
+                .get(&self.peer)
+                .is_some_and(|socket| socket.local_addr().ok() == Some(self.local))
+            {
+                links.remove(&self.peer);
+            }
+        }
+    }
+}
+
+/// Keeps a connection to `server` up for as long as the client exists.
+async fn connection(inner: Weak<Inner>, server: String, mut packets: mpsc::Receiver<Packet>) {
+    let mut delay = RECONNECT;
+
+    while !packets.is_closed() {
+        let Some(private_key) = inner.upgrade().map(|inner| inner.private_key) else {
+            return;
+        };
+
+        // a connection which lasted starts over with short waits
+        let started = tokio::time::Instant::now();
+        let res = session(&inner, &server, private_key, &mut packets).await;
+        if started.elapsed() > MAX_RECONNECT {
+            delay = RECONNECT;
+        }
+
+        match res {
+            Ok(()) => return,
+            Err(err) => log::warn!("relay {server}: {err}, reconnecting in {delay:?}"),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT);
+    }
+}
+
+async fn session(
+    inner: &Weak<Inner>,
+    server: &str,
+    private_key: Key,
+    packets: &mut mpsc::Receiver<Packet>,
+) -> io::Result<()> {
+    let (host, port) = split_host_port(server)?;
+    let stream = dial::connect(host, port).await?;
+    stream.set_nodelay(true)?;
+    let (mut read, mut write) = stream.into_split();
+
+    let challenge = match tokio::time::timeout(HELLO_TIMEOUT, read_frame(&mut read)).await {
+        Ok(frame) => frame?,
+        Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+    };
+    let (CHALLENGE, challenge) = challenge else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no challenge"));
+    };
+
+    let key = Key::from(x25519_base(private_key.as_bytes()));
+    let signature = xeddsa_sign(
+        private_key.as_bytes(),
+        &signed_message(&challenge),
+        &rand::random(),
+    );
+    write_frame(&mut write, HELLO, &[key.as_ref(), &signature]).await?;
+    log::info!("connected to relay {server}");
+
+    let reading = async {
+        loop {
+            let (RECV, payload) = read_frame(&mut read).await? else {
+                continue;
+            };
+            let Some((from, packet)) = split_key(&payload) else {
+                continue;
+            };
+            let Some(inner) = inner.upgrade() else {
+                return Ok(());
+            };
+
+            let socket = inner.links.lock().unwrap().get(&from).cloned();
+            match socket {
+                Some(socket) => {
+                    let _ = socket.try_send_to(packet, inner.wg);
+                }
+                None => {
+                    let mut knocking = inner.knocking.lock().unwrap();
+                    if !knocking.iter().any(|(key, _)| *key == from) {
+                        knocking.push((from, server.to_string()));
+                    }
+                }
+            }
+        }
+    };
+    let writing = async {
+        while let Some((to, packet)) = packets.recv().await {
+            write_frame(&mut write, SEND, &[to.as_ref(), &packet]).await?;
+        }
+        Ok(())
+    };
+
+    tokio::select! {
+        res = reading => res,
+        res = writing => res,
+    }
+}
+
+/// Runs a relay server for the clients connecting to `listener`.
+pub async fn serve(listener: TcpListener) -> io::Result<()> {
+    log::info!("relaying on {}", listener.local_addr()?);
+    let clients: Clients = Default::default();
+
+    for id in 0u64.. {
+        let (stream, addr) = listener.accept().await?;
+        if clients.lock().unwrap().len() >= MAX_CLIENTS {
+            log::warn!("too many relay clients, refusing {addr}");
+            continue;
+        }
+
+        let clients = clients.clone();
+        tokio::spawn(async move {
+            if let Err(err) = client(stream, addr, id, &clients).await {
+                log::debug!("relay client {addr}: {err}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Connected clients by key, with the id of their connection.
+type Clients = Arc<Mutex<HashMap<Key, (u64, mpsc::Sender<Packet>)>>>;
+
+async fn client(stream: TcpStream, addr: SocketAddr, id: u64, clients: &Clients) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let (mut read, mut write) = stream.into_split();
+
+    let challenge: [u8; 32] = rand::random();
+    write_frame(&mut write, CHALLENGE, &[&challenge]).await?;
+
+    let hello = match tokio::time::timeout(HELLO_TIMEOUT, read_frame(&mut read)).await {
+        Ok(frame) => frame?,
+        Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+    };
+    let key = match hello {
+        (HELLO, payload) => split_key(&payload)
+            .and_then(|(key, signature)| Some((key, signature.try_into().ok()?)))
+            .filter(|(key, signature)| {
+                xeddsa_verify(key.as_bytes(), &signed_message(&challenge), signature)
+            })
+            .map(|(key, _)| key),
+        _ => None,
+    };
+    let Some(key) = key else {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "bad hello"));
+    };
+
+    // a reconnecting client takes over from its stale connection
+    let (tx, mut rx) = mpsc::channel(QUEUE);
+    clients.lock().unwrap().insert(key, (id, tx));
+    log::info!("relay client {key} connected from {addr}");
+
+    let reading = async {
+        loop {
+            let (SEND, payload) = read_frame(&mut read).await? else {
+                continue;
+            };
+            let Some((to, packet)) = split_key(&payload) else {
+                continue;
+            };
+
+            let recipient = clients.lock().unwrap().get(&to).map(|(_, tx)| tx.clone());
+            if let Some(recipient) = recipient {
+                let _ = recipient.try_send((key, packet.to_vec()));
+            }
+        }
+    };
+    let writing = async {
+        while let Some((from, packet)) = rx.recv().await {
+            write_frame(&mut write, RECV, &[from.as_ref(), &packet]).await?;
+        }
+        Ok(())
+    };
+
+    let res: io::Result<()> = tokio::select! {
+        res = reading => res,
+        res = writing => res,
+    };
+
+    let mut clients = clients.lock().unwrap();
+    if clients.get(&key).is_some_and(|(current, _)| *current == id) {
+        clients.remove(&key);
+    }
+    log::info!("relay client {key} disconnected");
+
+    res
+}
+
+fn signed_message(challenge: &[u8]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, challenge].concat()
+}
+
+/// Key the payload starts with and the rest.
+fn split_key(payload: &[u8]) -> Option<(Key, &[u8])> {
+    let (key, rest) = payload.split_first_chunk::<32>()?;
+    Some((Key::from(*key), rest))
+}
+
+fn split_host_port(server: &str) -> io::Result<(&str, u16)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("bad relay {server}"));
+    let (host, port) = server.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    Ok((host, port.parse().map_err(|_| invalid())?))
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(read: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let kind = read.read_u8().await?;
+    let len = read.read_u16().await?;
+
+    let mut payload = vec![0; len as usize];
+    read.read_exact(&mut payload).await?;
+    Ok((kind, payload))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    write: &mut W,
+    kind: u8,
+    parts: &[&[u8]],
+) -> io::Result<()> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    let len = u16::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+
+    let mut frame = vec![kind];
+    frame.extend(len.to_be_bytes());
+    for part in parts {
+        frame.extend(*part);
+    }
+    write.write_all(&frame).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use crate::{crypto::x25519_base, wg::Key};
+
+    use super::{RelayClient, serve};
+
+    #[tokio::test]
+    async fn test_relay() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve(listener));
+
+        // loopback sockets standing in for the wireguard of two peers
+        let node = || async {
+            let private = Key::random();
+            let wg = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let port = wg.local_addr().unwrap().port();
+            let key = Key::from(x25519_base(private.as_bytes()));
+            (key, wg, RelayClient::new(private, port, &server))
+        };
+        let (alice, alice_wg, alice_relay) = node().await;
+        let (bob, bob_wg, bob_relay) = node().await;
+
+        async fn recv(wg: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+            let mut buf = [0; 64];
+            let (len, from) = tokio::time::timeout(Duration::from_secs(5), wg.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            (buf[..len].to_vec(), from)
+        }
+
+        // bob doesn't relay to alice yet, her packets knock until he does
+        let to_bob = alice_relay.open(bob, &server).unwrap();
+        let mut knocking = Vec::new();
+        for _ in 0..50 {
+            alice_wg
+                .send_to(b"handshake", to_bob.local())
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            knocking = bob_relay.knocking();
+            if !knocking.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(knocking, vec![(alice, server.clone())]);
+
+        let to_alice = bob_relay.open(alice, &server).unwrap();
+        alice_wg
+            .send_to(b"initiation", to_bob.local())
+            .await
+            .unwrap();
+        assert_eq!(
+            recv(&bob_wg).await,
+            (b"initiation".to_vec(), to_alice.local())
+        );
+
+        bob_wg.send_to(b"response", to_alice.local()).await.unwrap();
+        assert_eq!(
+            recv(&alice_wg).await,
+            (b"response".to_vec(), to_bob.local())
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use std::{fmt::Display, time::Duration};
+
+use serde::Deserialize;
+
+/// Exponential backoff with jitter.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Delay before the first retry, milliseconds.
+    pub initial_ms: u64,
+
+    /// Every next delay is multiplied by this.
+    pub multiplier: f64,
+
+    /// Upper bound of a delay, milliseconds.
+    pub max_ms: u64,
+
+    /// Delays are randomly spread by this fraction, `0.2` is +-20%.
+    pub jitter: f64,
+
+    /// Attempts including the first one, `0` retries forever.
+    pub attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_ms: 1000,
+            multiplier: 2.0,
+            max_ms: 30_000,
+            jitter: 0.2,
+            attempts: 3,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Single attempt, no retries.
+    pub fn none() -> Self {
+        Self {
+            attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Delay before retrying after `attempt` (counted from 0) failed, or
+    /// `None` when attempts are exhausted.
+    pub fn backoff(&self, attempt: u32) -> Option<Duration> {
+        if self.attempts != 0 && attempt + 1 >= self.attempts {
+            return None;
+        }
+
+        let jitter = 1.0 + self.jitter * (rand::random::<f64>() * 2.0 - 1.0);
+        Some(self.delay(attempt).mul_f64(jitter.max(0.0)))
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let ms = self.initial_ms as f64 * self.multiplier.powi(attempt.min(64) as i32);
+
+        Duration::from_millis(ms.min(self.max_ms as f64) as u64)
+    }
+
+    pub async fn retry<T, E, F>(&self, what: &str, f: F) -> Result<T, E>
+    where
+        E: Display,
+        F: AsyncFnMut() -> Result<T, E>,
+    {
+        self.retry_if(what, |_| true, f).await
+    }
+
+    /// Same as [`retry`](Self::retry), giving up right away on errors
+    /// `retryable` rejects.
+    pub async fn retry_if<T, E, F>(
+        &self,
+        what: &str,
+        retryable: impl Fn(&E) -> bool,
+        mut f: F,
+    ) -> Result<T, E>
+    where
+        E: Display,
+        F: AsyncFnMut() -> Result<T, E>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            match f().await {
+                Ok(val) => return Ok(val),
+                Err(err) if !retryable(&err) => return Err(err),
+                Err(err) => {
+                    let Some(delay) = self.backoff(attempt) else {
+                        return Err(err);
+                    };
+
+                    log::warn!("{what} failed: {err}, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::RetryPolicy;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            initial_ms: 100,
+            multiplier: 3.0,
+            max_ms: 1000,
+            jitter: 0.0,
+            attempts: 4,
+        };
+
+        let delays: Vec<_> = (0..5).map_while(|n| policy.backoff(n)).collect();
+        assert_eq!(delays, [100, 300, 900].map(Duration::from_millis).to_vec());
+
+        let forever = RetryPolicy {
+            attempts: 0,
+            ..policy
+        };
+        assert_eq!(forever.backoff(100), Some(Duration::from_millis(1000)));
+        assert_eq!(RetryPolicy::none().backoff(0), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_if() {
+        let policy = RetryPolicy::default();
+
+        let mut attempts = 0;
+        let res: Result<(), &str> = policy
+            .retry_if(
+                "test",
+                |err| *err == "transient",
+                async || {
+                    attempts += 1;
+                    Err(if attempts < 2 {
+                        "transient"
+                    } else {
+                        "permanent"
+                    })
+                },
+            )
+            .await;
+        assert_eq!((res, attempts), (Err("permanent"), 2));
+
+        attempts = 0;
+        let res: Result<(), &str> = policy
+            .retry_if(
+                "test",
+                |_| true,
+                async || {
+                    attempts += 1;
+                    Err("transient")
+                },
+            )
+            .await;
+        assert_eq!((res, attempts), (Err("transient"), 3));
+    }
+}
@@ -0,0 +1,147 @@
+pub mod export;
+pub mod query;
+
+use std::{net::IpAddr, process::Command};
+
+use crate::{error::Error, wg::Cidr};
+
+/// Removes everything covered by `excludes` from advertised `routes`,
+/// splitting wider prefixes around the excluded ones (a peer advertising
+/// `0.0.0.0/0` still never gets our LAN).
+pub fn exclude(routes: &[Cidr], excludes: &[Cidr]) -> Vec<Cidr> {
+    let mut accepted: Vec<Cidr> = routes.iter().map(Cidr::network).collect();
+
+    for ex in excludes {
+        accepted = accepted.iter().flat_map(|r| r.subtract(ex)).collect();
+    }
+
+    accepted
+}
+
+/// Installs (or moves) a route to `cidr` via the interface.
+pub fn replace(iface: &str, cidr: &Cidr) -> Result<(), Error> {
+    ip(&["route", "replace", &cidr.to_string(), "dev", iface])
+}
+
+pub fn remove(iface: &str, cidr: &Cidr) -> Result<(), Error> {
+    ip(&["route", "del", &cidr.to_string(), "dev", iface])
+}
+
+/// Default route of `table` via `gateway`.
+pub fn replace_default(device: &str, gateway: IpAddr, table: u32) -> Result<(), Error> {
+    let default = if gateway.is_ipv4() {
+        "0.0.0.0/0"
+    } else {
+        "::/0"
+    };
+
+    ip(&[
+        "route",
+        "replace",
+        default,
+        "via",
+        &gateway.to_string(),
+        "dev",
+        device,
+        "table",
+        &table.to_string(),
+    ])
+}
+
+/// Routes packets carrying `mark` through `table`, both families.
+pub fn replace_rule(mark: u32, table: u32) -> Result<(), Error> {
+    let (mark, table) = (mark.to_string(), table.to_string());
+
+    for family in ["-4", "-6"] {
+        // `rule add` doesn't check for duplicates
+        let _ = ip_family(family, &["rule", "del", "fwmark", &mark, "table", &table]);
+        ip_family(family, &["rule", "add", "fwmark", &mark, "table", &table])?;
+    }
+
+    Ok(())
+}
+
+pub fn remove_rule(mark: u32, table: u32) -> Result<(), Error> {
+    let (mark, table) = (mark.to_string(), table.to_string());
+
+    for family in ["-4", "-6"] {
+        ip_family(family, &["rule", "del", "fwmark", &mark, "table", &table])?;
+    }
+
+    Ok(())
+}
+
+fn ip(args: &[&str]) -> Result<(), Error> {
+    let family = if args.iter().any(|a| a.contains(':')) {
+        "-6"
+    } else {
+        "-4"
+    };
+
+    ip_family(family, args)
+}
+
+fn ip_family(family: &str, args: &[&str]) -> Result<(), Error> {
+    let status = Command::new("ip")
+        .env("LC_ALL", "C")
+        .arg(family)
+        .args(args)
+        .status()?;
+
+    if !status.success() {
+        return Err(Error::CommandFail("ip", status.code()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wg::Cidr;
+
+    use super::exclude;
+
+    fn cidrs(s: &str) -> Vec<Cidr> {
+        s.split(',').map(|c| c.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_exclude() {
+        assert_eq!(
+            exclude(&cidrs("10.1.0.0/16"), &cidrs("192.168.1.0/24")),
+            cidrs("10.1.0.0/16")
+        );
+
+        assert!(exclude(&cidrs("192.168.1.128/25"), &cidrs("192.168.1.0/24")).is_empty());
+
+        assert_eq!(
+            exclude(&cidrs("10.0.0.0/8"), &cidrs("10.0.0.0/9")),
+            cidrs("10.128.0.0/9")
+        );
+
+        assert_eq!(
+            exclude(&cidrs("192.168.0.0/22"), &cidrs("192.168.1.0/24")),
+            cidrs("192.168.2.0/23,192.168.0.0/24")
+        );
+
+        let all = exclude(&cidrs("0.0.0.0/0"), &cidrs("192.168.1.0/24,203.0.113.7"));
+        assert_eq!(all.len(), 24 - 1 + (32 - 5));
+        assert!(
+            !all.iter()
+                .any(|c| c.contains(&"192.168.1.77".parse().unwrap()))
+        );
+        assert!(
+            !all.iter()
+                .any(|c| c.contains(&"203.0.113.7".parse().unwrap()))
+        );
+        assert!(
+            all.iter()
+                .any(|c| c.contains(&"203.0.113.6".parse().unwrap()))
+        );
+
+        assert_eq!(
+            exclude(&cidrs("2001:db8::/32"), &cidrs("2001:db8::/33")),
+            cidrs("2001:db8:8000::/33")
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! Hands accepted mesh routes to a local routing daemon instead of the
+//! kernel table, for sites where the mesh is part of a dynamic routing
+//! setup and BIRD or FRR own the table.
+
+use std::{
+    io,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{fs::write_atomic, wg::Cidr};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Daemon {
+    /// BIRD 2, routes are written to a file included into a `protocol
+    /// static` and BIRD is told to reconfigure.
+    Bird,
+
+    /// FRR, static routes are set through the vty socket of staticd.
+    Frr,
+}
+
+/// `[route_export]` section of the config.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteExport {
+    pub daemon: Daemon,
+
+    /// Control socket, `/run/bird/bird.ctl` or `/run/frr/staticd.vty` by
+    /// default.
+    pub socket: Option<PathBuf>,
+
+    /// BIRD only, `/etc/bird/wg-disco-<iface>.conf` by default.
+    pub file: Option<PathBuf>,
+}
+
+impl RouteExport {
+    pub fn socket(&self) -> PathBuf {
+        match (&self.socket, self.daemon) {
+            (Some(socket), _) => socket.clone(),
+            (None, Daemon::Bird) => PathBuf::from("/run/bird/bird.ctl"),
+            (None, Daemon::Frr) => PathBuf::from("/run/frr/staticd.vty"),
+        }
+    }
+
+    pub fn file(&self, iface: &str) -> PathBuf {
+        self.file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("/etc/bird/wg-disco-{iface}.conf")))
+    }
+
+    /// Moves the routes via `iface` known to the daemon from `previous` to
+    /// `routes`.
+    pub fn export(&self, iface: &str, previous: &[Cidr], routes: &[Cidr]) -> io::Result<()> {
+        match self.daemon {
+            Daemon::Bird => {
+                write_atomic(&self.file(iface), bird_routes(iface, routes), 0o644)?;
+                bird_command(&self.socket(), "configure")
+            }
+            Daemon::Frr => {
+                let removed = previous.iter().filter(|cidr| !routes.contains(cidr));
+                let added = routes.iter().filter(|cidr| !previous.contains(cidr));
+
+                let commands: Vec<String> = removed
+                    .map(|cidr| format!("no {}", frr_route(iface, cidr)))
+                    .chain(added.map(|cidr| frr_route(iface, cidr)))
+                    .collect();
+
+                if commands.is_empty() {
+                    return Ok(());
+                }
+                frr_commands(&self.socket(), &commands)
+            }
+        }
+    }
+}
+
+/// Body of the file included into a BIRD `protocol static`.
+fn bird_routes(iface: &str, routes: &[Cidr]) -> String {
+    let mut lines: Vec<String> = routes
+        .iter()
+        .map(|cidr| format!("route {cidr} via \"{iface}\";\n"))
+        .collect();
+    lines.sort();
+    lines.dedup();
+
+    let mut file = format!("# routes of the {iface} mesh, written by wg-disco\n");
+    file.extend(lines);
+    file
+}
+
+fn frr_route(iface: &str, cidr: &Cidr) -> String {
+    match cidr.ip.is_ipv4() {
+        true => format!("ip route {cidr} {iface}"),
+        false => format!("ipv6 route {cidr} {iface}"),
+    }
+}
+
+#[cfg(unix)]
+fn connect(socket: &Path) -> io::Result<std::os::unix::net::UnixStream> {
+    let stream = std::os::unix::net::UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+#[cfg(not(unix))]
+fn connect(_socket: &Path) -> io::Result<std::net::TcpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "routing daemon sockets are unix only",
+    ))
+}
+
+/// Runs a command on the BIRD control socket.
+fn bird_command(socket: &Path, command: &str) -> io::Result<()> {
+    let mut stream = connect(socket)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    // greeting, `0001 BIRD 2.x ready.`
+    bird_reply(&mut reader)?;
+
+    stream.write_all(format!("{command}\n").as_bytes())?;
+    bird_reply(&mut reader)
+}
+
+/// Reads up to the last line of a reply, `<code>-` lines continue it and
+/// codes from 8000 up are errors.
+fn bird_reply(reader: &mut impl BufRead) -> io::Result<()> {
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let (Some(code), sep) = (line.get(..4), line.as_bytes().get(4)) else {
+            continue;
+        };
+        if !code.bytes().all(|b| b.is_ascii_digit()) || sep == Some(&b'-') {
+            continue;
+        }
+
+        return match code.starts_with(['8', '9']) {
+            true => Err(io::Error::other(format!("bird: {}", line[4..].trim()))),
+            false => Ok(()),
+        };
+    }
+}
+
+/// Runs configuration commands on an FRR vty socket.
+fn frr_commands(socket: &Path, commands: &[String]) -> io::Result<()> {
+    let mut stream = connect(socket)?;
+
+    let commands = ["enable", "configure terminal"]
+        .into_iter()
+        .chain(commands.iter().map(String::as_str))
+        .chain(["end"]);
+
+    for command in commands {
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(&[0])?;
+
+        let (status, output) = frr_reply(&mut stream)?;
+        if status != 0 {
+            return Err(io::Error::other(format!(
+                "frr: `{command}` failed: {}",
+                output.trim()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Output and status of a vty command, the output ends with three zero
+/// bytes and the status follows.
+fn frr_reply(stream: &mut impl Read) -> io::Result<(u8, String)> {
+    let mut output = Vec::new();
+    let mut byte = [0u8];
+
+    while !output.ends_with(&[0, 0, 0]) {
+        stream.read_exact(&mut byte)?;
+        output.push(byte[0]);
+    }
+    stream.read_exact(&mut byte)?;
+
+    output.truncate(output.len() - 3);
+    Ok((byte[0], String::from_utf8_lossy(&output).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{bird_reply, bird_routes, frr_reply};
+
+    #[test]
+    fn test_bird() {
+        let routes = ["10.1.0.0/24".parse().unwrap(), "fd00::/64".parse().unwrap()];
+        assert_eq!(
+            bird_routes("wg0", &routes),
+            "# routes of the wg0 mesh, written by wg-disco\n\
+             route 10.1.0.0/24 via \"wg0\";\n\
+             route fd00::/64 via \"wg0\";\n"
+        );
+
+        let mut ok = Cursor::new("0001 BIRD 2.15 ready.\n0003-Reconfigured\n0003 Reconfigured\n");
+        assert!(bird_reply(&mut ok).is_ok());
+        assert!(bird_reply(&mut ok).is_ok());
+
+        let mut err = Cursor::new("8002 /etc/bird.conf:3:1 syntax error\n");
+        assert!(bird_reply(&mut err).is_err());
+    }
+
+    #[test]
+    fn test_frr_reply() {
+        let mut reply = Cursor::new(b"% Unknown command\n\0\0\0\x02".to_vec());
+        assert_eq!(
+            frr_reply(&mut reply).unwrap(),
+            (2, "% Unknown command\n".into())
+        );
+    }
+}
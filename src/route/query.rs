@@ -0,0 +1,314 @@
+//! Reads the kernel routing table over rtnetlink.
+
+use std::{
+    ffi::{CStr, CString},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use crate::wg::Cidr;
+
+/// Main routing table, where `ip route` puts routes without `table`.
+pub const MAIN_TABLE: u32 = 254;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub dst: Cidr,
+
+    /// Index of the outgoing interface, `None` for e.g. blackhole routes.
+    pub oif: Option<u32>,
+    pub table: u32,
+    pub metric: Option<u32>,
+}
+
+impl Route {
+    /// Name of the outgoing interface, the index if it's gone by now.
+    pub fn dev(&self) -> String {
+        let Some(oif) = self.oif else {
+            return "none".to_string();
+        };
+
+        let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+        let res = unsafe { libc::if_indextoname(oif, name.as_mut_ptr()) };
+
+        if res.is_null() {
+            return oif.to_string();
+        }
+
+        unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Interface index, `None` if there is no such interface.
+pub fn if_index(iface: &str) -> Option<u32> {
+    let name = CString::new(iface).ok()?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+
+    (index != 0).then_some(index)
+}
+
+/// Unicast routes of both families in all tables.
+#[cfg(target_os = "linux")]
+pub fn routes() -> io::Result<Vec<Route>> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let res = dump(fd);
+    unsafe { libc::close(fd) };
+    res
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn routes() -> io::Result<Vec<Route>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn dump(fd: libc::c_int) -> io::Result<Vec<Route>> {
+    // nlmsghdr followed by rtmsg with everything but the family zeroed
+    let mut request = [0u8; NLMSG_HDRLEN + RTMSG_LEN];
+    request[0..4].copy_from_slice(&((NLMSG_HDRLEN + RTMSG_LEN) as u32).to_ne_bytes());
+    request[4..6].copy_from_slice(&libc::RTM_GETROUTE.to_ne_bytes());
+    let flags = (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16;
+    request[6..8].copy_from_slice(&flags.to_ne_bytes());
+    request[NLMSG_HDRLEN] = libc::AF_UNSPEC as u8;
+
+    let sent = unsafe { libc::send(fd, request.as_ptr().cast(), request.len(), 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut routes = Vec::new();
+    let mut buf = vec![0u8; 32 * 1024];
+
+    loop {
+        let len = unsafe { libc::recv(fd, buf.as_mut_ptr().cast(), buf.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if parse(&buf[..len as usize], &mut routes)? {
+            return Ok(routes);
+        }
+    }
+}
+
+/// Routes of `table` to the same prefix as `cidr` leaving through another
+/// interface, installing ours would take them over. Default routes aren't
+/// reported, exit nodes replace them on purpose.
+pub fn conflicts<'a>(routes: &'a [Route], cidr: &Cidr, oif: u32, table: u32) -> Vec<&'a Route> {
+    routes
+        .iter()
+        .filter(|route| {
+            route.table == table
+                && route.oif != Some(oif)
+                && route.dst.mask != 0
+                && route.dst.network() == cidr.network()
+        })
+        .collect()
+}
+
+/// Whether traffic to `cidr` can leave through an interface other than
+/// `oif`: some route overlaps it. The default route only counts for
+/// advertising a default route, it would make everything reachable.
+pub fn reachable(routes: &[Route], cidr: &Cidr, oif: Option<u32>) -> bool {
+    routes.iter().any(|route| {
+        route.oif.is_some()
+            && route.oif != oif
+            && route.dst.ip.is_ipv4() == cidr.ip.is_ipv4()
+            && match cidr.mask {
+                0 => route.dst.mask == 0,
+                _ => route.dst.mask != 0 && route.dst.overlaps(cidr),
+            }
+    })
+}
+
+const NLMSG_HDRLEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const RTM_NEWROUTE: u16 = 24;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_PRIORITY: u16 = 6;
+const RTA_TABLE: u16 = 15;
+const RTN_UNICAST: u8 = 1;
+
+#[inline]
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn u16_at(buf: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(buf.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// Appends the routes of one datagram, `true` once the dump is complete.
+fn parse(mut buf: &[u8], routes: &mut Vec<Route>) -> io::Result<bool> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed netlink message");
+
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32_at(buf, 0).ok_or_else(malformed)? as usize;
+        let kind = u16_at(buf, 4).ok_or_else(malformed)?;
+
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            return Err(malformed());
+        }
+
+        let payload = &buf[NLMSG_HDRLEN..len];
+
+        match kind {
+            NLMSG_DONE => return Ok(true),
+            NLMSG_ERROR => {
+                let errno = u32_at(payload, 0).ok_or_else(malformed)? as i32;
+                if errno != 0 {
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+            }
+            RTM_NEWROUTE => routes.extend(parse_route(payload)),
+            _ => {}
+        }
+
+        buf = &buf[align(len).min(buf.len())..];
+    }
+
+    Ok(false)
+}
+
+/// `rtmsg` followed by attributes, `None` for routes which aren't unicast.
+fn parse_route(msg: &[u8]) -> Option<Route> {
+    let &[family, dst_len, _, _, table, _, _, kind] = msg.get(..8)? else {
+        return None;
+    };
+
+    if kind != RTN_UNICAST {
+        return None;
+    }
+
+    let mut route = Route {
+        dst: Cidr {
+            ip: match family as i32 {
+                libc::AF_INET => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                libc::AF_INET6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                _ => return None,
+            },
+            mask: dst_len,
+        },
+        oif: None,
+        table: table as u32,
+        metric: None,
+    };
+
+    let mut attrs = msg.get(RTMSG_LEN..)?;
+    while attrs.len() >= 4 {
+        let len = u16_at(attrs, 0)? as usize;
+        let kind = u16_at(attrs, 2)?;
+        let value = attrs.get(4..len)?;
+
+        match kind {
+            RTA_DST => {
+                route.dst.ip = match value.len() {
+                    4 => IpAddr::V4(<[u8; 4]>::try_from(value).ok()?.into()),
+                    16 => IpAddr::V6(<[u8; 16]>::try_from(value).ok()?.into()),
+                    _ => return None,
+                }
+            }
+            RTA_OIF => route.oif = u32_at(value, 0),
+            RTA_PRIORITY => route.metric = u32_at(value, 0),
+            // tables above 255 only fit here
+            RTA_TABLE => route.table = u32_at(value, 0)?,
+            _ => {}
+        }
+
+        attrs = &attrs[align(len).min(attrs.len())..];
+    }
+
+    Some(route)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wg::Cidr;
+
+    use super::{MAIN_TABLE, Route, conflicts, parse, reachable};
+
+    fn attr(kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut attr = Vec::new();
+        attr.extend(((4 + value.len()) as u16).to_ne_bytes());
+        attr.extend(kind.to_ne_bytes());
+        attr.extend(value);
+        attr.resize((attr.len() + 3) & !3, 0);
+        attr
+    }
+
+    fn message(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend(((16 + payload.len()) as u32).to_ne_bytes());
+        msg.extend(kind.to_ne_bytes());
+        msg.extend([0; 10]);
+        msg.extend(payload);
+        msg
+    }
+
+    #[test]
+    fn test_parse_dump() {
+        // 192.168.10.0/24 dev 3 metric 100, table main
+        let mut route = vec![libc::AF_INET as u8, 24, 0, 0, 254, 4, 0, 1, 0, 0, 0, 0];
+        route.extend(attr(15, &254u32.to_ne_bytes()));
+        route.extend(attr(1, &[192, 168, 10, 0]));
+        route.extend(attr(4, &3u32.to_ne_bytes()));
+        route.extend(attr(6, &100u32.to_ne_bytes()));
+
+        // local routes are skipped
+        let local = vec![libc::AF_INET as u8, 32, 0, 0, 255, 2, 254, 2, 0, 0, 0, 0];
+
+        let mut buf = message(24, &route);
+        buf.extend(message(24, &local));
+
+        let mut routes = Vec::new();
+        assert!(!parse(&buf, &mut routes).unwrap());
+        assert!(parse(&message(3, &[0; 4]), &mut routes).unwrap());
+
+        let lan: Cidr = "192.168.10.0/24".parse().unwrap();
+        assert_eq!(
+            routes,
+            vec![Route {
+                dst: lan,
+                oif: Some(3),
+                table: MAIN_TABLE,
+                metric: Some(100),
+            }]
+        );
+
+        assert_eq!(conflicts(&routes, &lan, 7, MAIN_TABLE).len(), 1);
+        assert!(conflicts(&routes, &lan, 3, MAIN_TABLE).is_empty());
+        assert!(conflicts(&routes, &"192.168.10.0/25".parse().unwrap(), 7, MAIN_TABLE).is_empty());
+
+        assert!(reachable(
+            &routes,
+            &"192.168.0.0/16".parse().unwrap(),
+            Some(7)
+        ));
+        assert!(!reachable(&routes, &lan, Some(3)));
+        assert!(!reachable(&routes, &"10.0.0.0/8".parse().unwrap(), Some(7)));
+        assert!(!reachable(&routes, &"0.0.0.0/0".parse().unwrap(), Some(7)));
+
+        // EPERM
+        let err = parse(&message(2, &(-1i32).to_ne_bytes()), &mut routes).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(1));
+    }
+}
@@ -0,0 +1,3868 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    pin::pin,
+    time::Duration,
+};
+
+use futures::{FutureExt, StreamExt};
+use tokio::{sync::mpsc, time::Instant};
+
+use crate::{
+    capture,
+    clock::Clock,
+    control::{self, Command, Event, PeerStatus, Response, RouteStatus, Status, Unsupported},
+    discover::{Discover, Mapping, nat::NatType},
+    error::Error,
+    explain::Explanation,
+    groups::Groups,
+    health::{RouteCheck, RouteHealth},
+    killswitch::KillSwitch,
+    limits::Limits,
+    metered, peer_debug, peerlog,
+    provision::ClientConfig,
+    relay::{self, RelayClient},
+    retry::RetryPolicy,
+    route::{self, export::RouteExport},
+    shutdown,
+    signaling::{
+        Extensions, Feature, Metadata, PROTOCOL_VERSION, PeerEvent, PeerUpdate, Signaling, codec,
+        delta::{DeltaReceiver, DeltaSender},
+        revocation::Revocation,
+        skew::ClockSkew,
+    },
+    transport::{self, Helper, TransportConfig},
+    uplink::{self, UplinkConfig},
+    wg::{
+        Cidr, Endpoint, Key, WireguardApi,
+        config::WgConfig,
+        executor,
+        watcher::{WgEvent, WgWatcher},
+    },
+};
+
+pub mod clones;
+pub mod damping;
+pub mod debounce;
+pub mod hints;
+pub mod limiter;
+pub mod punch;
+pub mod revocations;
+pub mod sleep;
+pub mod snapshot;
+pub mod stale;
+
+pub use clones::{CloneDetector, Origin};
+pub use damping::{EndpointHistory, Verdict};
+pub use debounce::Debounce;
+pub use hints::Hints;
+pub use limiter::RateLimiter;
+pub use punch::{Candidate, PunchProgress, PunchScheduler};
+pub use revocations::Revocations;
+pub use sleep::SleepDetector;
+pub use snapshot::Snapshot;
+pub use stale::HandshakeMonitor;
+
+/// Peer clocks off by more than this are reported.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Keepalive interval enabled towards peers behind NAT, seconds.
+const NAT_KEEPALIVE: u16 = 25;
+
+/// Peers without a handshake this long after their endpoint was set are
+/// tried over an alternative transport.
+const DIRECT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Handshakes happen every two minutes on a live session.
+pub(crate) const PEER_DOWN_AFTER: Duration = Duration::from_secs(180);
+
+/// How often uplinks are checked for failover and recovery.
+const UPLINK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Limit of a STUN probe of an uplink. Probes of several uplinks run
+/// together, the loop waits for them this long at most.
+const UPLINK_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How often `[[route_check]]`s of advertised routes run.
+const ROUTE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Least time between two snapshots written, to spare flash storage.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often NetworkManager is asked whether the connection is metered.
+const METERED_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long handshake packets of `--capture`d peers are captured, covers
+/// the first few punch attempts.
+const CAPTURE_DURATION: Duration = Duration::from_secs(30);
+
+/// How long a probe requested by a peer waits for a handshake.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How far ahead a rendezvous with a peer is set, time for the message to
+/// get through signaling.
+const PUNCH_LEAD: Duration = Duration::from_secs(3);
+
+/// Rendezvous further from now than this, by the peer's clock as far as
+/// we know it, are ignored.
+const MAX_PUNCH_LEAD: Duration = Duration::from_secs(10);
+
+/// Announcements forwarded by servers older than this are ignored.
+const MAX_RELAYED_AGE: Duration = Duration::from_secs(600);
+
+/// Channel announcements triggered within this of each other go out as one.
+const ANNOUNCE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Longest a triggered channel announcement waits for triggers to settle.
+const ANNOUNCE_MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// What to do when `ListenPort` is configured, but the NAT mapping was
+/// discovered for another local port.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PortPolicy {
+    /// Temporarily move wireguard off its port and discover the mapping of
+    /// the configured port itself.
+    #[default]
+    Rediscover,
+
+    /// Fail with an error.
+    Refuse,
+}
+
+/// What to do when a peer announces a tunnel address its AllowedIPs don't
+/// cover or which is ours, a sign of a misconfigured or cloned node.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AddressPolicy {
+    /// Log a warning and use the announcement anyway.
+    #[default]
+    Warn,
+
+    /// Log a warning and ignore the announcement.
+    Reject,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RunnerOptions {
+    pub port_policy: PortPolicy,
+
+    /// What to do with announcements whose tunnel address is outside of
+    /// the sender's AllowedIPs.
+    pub address_policy: AddressPolicy,
+
+    /// Signaling and discovery servers the kill-switch keeps reachable.
+    pub servers: Vec<SocketAddr>,
+
+    /// Static public endpoint of a server mode node, discovery is skipped
+    /// and requests are answered without rate limiting.
+    pub server: Option<SocketAddr>,
+
+    /// Forward cached announcements of other peers to late joiners.
+    pub amplify: bool,
+
+    /// Resending of failed announcements.
+    pub announce_retry: RetryPolicy,
+
+    /// Cycling through the addresses of peers without a session.
+    pub punch_retry: RetryPolicy,
+
+    /// Where [`Hints`] are kept, not persisted without it.
+    pub hints_file: Option<PathBuf>,
+
+    /// Helpers for peers direct UDP doesn't work with.
+    pub transports: Vec<TransportConfig>,
+
+    /// Our home relay as host:port, a `wg-disco relay` announced to peers
+    /// as a transport. Peers neither punching nor helpers get through to
+    /// are relayed through theirs.
+    pub relay: Option<String>,
+
+    /// What peers are trusted with, see [`Groups`].
+    pub groups: Groups,
+
+    /// Leave unchanged fields out of channel re-announcements, every peer
+    /// of the mesh has to understand deltas.
+    pub delta: bool,
+
+    /// Uplinks of a multi-homed host, wireguard traffic goes via the first.
+    pub uplinks: Vec<UplinkConfig>,
+
+    /// Health checks of advertised routes, failing ones are withdrawn.
+    pub route_checks: Vec<RouteCheck>,
+
+    /// Routing daemon accepted routes are handed to instead of the kernel.
+    pub route_export: Option<RouteExport>,
+
+    /// Never announce, install routes or touch the system, only follow the
+    /// mesh. Goes with a [`MemoryBackend`](crate::wg::memory::MemoryBackend).
+    pub observe: bool,
+
+    /// Capture handshake packets of this peer while punching and log what
+    /// got through.
+    pub capture: Option<Key>,
+
+    /// Batch size and polling intervals, see [`Limits`].
+    pub limits: Limits,
+
+    /// Peers whose announcements are ignored, more are blocked through the
+    /// control API.
+    pub blocklist: Vec<Key>,
+
+    /// Keys whose [`Revocation`]s are followed, ours included lets us
+    /// revoke peers through the control API.
+    pub revocation_signers: Vec<Key>,
+
+    /// Where [`Revocations`] are kept, not persisted without it.
+    pub revocations_file: Option<PathBuf>,
+
+    /// Sent along with our announcements, only the protocol version when
+    /// the operator keeps the rest to itself.
+    pub metadata: Option<Metadata>,
+
+    /// Rediscover our mapping and announce it again this often, NAT
+    /// mappings and the announcements peers cached expire otherwise.
+    pub reannounce: Option<Duration>,
+
+    /// Whether we are on a metered connection, NetworkManager is asked
+    /// when `None`.
+    pub metered: Option<bool>,
+
+    /// Where [`Snapshot`]s of what was learned are kept, a restart starts
+    /// from scratch without it.
+    pub state_file: Option<PathBuf>,
+
+    /// Accept announcements without a signature, for meshes with peers
+    /// predating them. Badly signed ones, and unsigned ones relayed by
+    /// another peer, are dropped regardless.
+    pub allow_unsigned: bool,
+
+    /// Direct announcements further than this from what the sender's clock
+    /// should show, or older than one already seen, are dropped as replays.
+    /// Ones without a timestamp can't be checked and are let through.
+    pub max_age: Option<Duration>,
+
+    /// Peers without a handshake this long get their endpoint asked for
+    /// again over signaling, see [`HandshakeMonitor`].
+    pub handshake_timeout: Option<Duration>,
+
+    /// Coordinator we ask for our client config, see
+    /// [`ClientConfig`].
+    pub provision_from: Option<Key>,
+
+    /// Where the client config the coordinator sent is saved.
+    pub provision_file: Option<PathBuf>,
+
+    /// Configs of the clients we coordinate, rendered from the
+    /// `[provision]` templates.
+    pub provision: HashMap<Key, ClientConfig>,
+}
+
+pub struct Runner<W, S, D> {
+    iface: String,
+    key: Key,
+    config: WgConfig,
+    wg: W,
+    signaling: S,
+    discover: D,
+    options: RunnerOptions,
+    limiter: RateLimiter,
+    history: HashMap<Key, EndpointHistory>,
+    skew: ClockSkew,
+    peer_index: HashMap<Key, usize>,
+    routes: HashMap<Key, Vec<Cidr>>,
+    exit_nodes: HashSet<Key>,
+    relay_candidates: HashSet<Key>,
+    keepalives: HashSet<Key>,
+    applied_at: HashMap<Key, Instant>,
+    handshakes: HashMap<Key, Instant>,
+    helpers: HashMap<Key, Helper>,
+    relay: Option<RelayClient>,
+    announcements: HashMap<Key, PeerUpdate>,
+
+    /// When the cached announcement of each peer arrived.
+    announced_at: HashMap<Key, Instant>,
+    forwards: VecDeque<(String, PeerUpdate)>,
+    synced: bool,
+    sync_from: Option<String>,
+    kill_switch: KillSwitch,
+    listen_port: u16,
+    /// Wireguard is off `listen_port` for STUN probes, see
+    /// [`Runner::restore_port`].
+    port_freed: bool,
+    public: Option<SocketAddr>,
+    /// Public IPv6 endpoint when dual-stack.
+    public6: Option<SocketAddr>,
+    /// How our NAT behaves, when it could be told.
+    nat_type: Option<NatType>,
+    /// Candidates discovery found besides the mapping, announced as extra
+    /// endpoints.
+    gathered: Vec<SocketAddr>,
+    local: Option<SocketAddr>,
+    control: Option<control::Receiver>,
+
+    /// Events of sources besides signaling, the LAN beacon.
+    events: Option<mpsc::Receiver<PeerEvent>>,
+    up: HashSet<Key>,
+
+    /// Endpoints pinned through the control API and when the pins expire.
+    pins: HashMap<Key, (SocketAddr, Instant)>,
+    deltas: DeltaSender,
+    bases: DeltaReceiver,
+    punch: PunchScheduler,
+    hints: Hints,
+
+    /// Last nickname each peer announced from, for targeted messages.
+    nicks: HashMap<Key, String>,
+
+    /// Addresses punched through to, to be reported to their peers.
+    punched: Vec<(Key, SocketAddr)>,
+
+    /// Peers whose session went stale, sent our announcement so they punch
+    /// back while we punch them.
+    recoveries: Vec<Key>,
+
+    /// Peers without a session to set a rendezvous with, so both sides
+    /// punch at the same time.
+    rendezvous: Vec<Key>,
+
+    /// Index of the uplink wireguard traffic goes through.
+    active_uplink: usize,
+    uplink_checked: Option<Instant>,
+
+    /// Last periodic re-announcement, the first announcement counts.
+    reannounced: Instant,
+
+    /// Resumes from suspend, after which mapping and peers are refreshed.
+    sleep: SleepDetector,
+
+    /// Encoding of the last snapshot written and when.
+    saved: Option<(Vec<u8>, Instant)>,
+
+    stale: Option<HandshakeMonitor>,
+
+    /// On a metered connection, periodic re-announcements and pushes stop.
+    metered: bool,
+    metered_checked: Option<Instant>,
+
+    clock: Clock,
+
+    /// Announced tunnel addresses already complained about.
+    address_warned: HashMap<Key, Cidr>,
+
+    clones: CloneDetector,
+    cloned_self: bool,
+
+    /// `AdvertiseRoutes` which passed validation, announced unless their
+    /// health check fails.
+    advertised: Vec<Cidr>,
+
+    /// Routes each peer advertised and its groups allow, before the exit
+    /// node choice.
+    advertised_by: HashMap<Key, Vec<Cidr>>,
+
+    /// Bandwidth hints of peers, Mbit/s.
+    bandwidth: HashMap<Key, u32>,
+
+    /// Enabled features live peers can't follow, as last warned about.
+    unsupported: Vec<Unsupported>,
+    health: RouteHealth,
+    routes_checked: Option<Instant>,
+
+    /// Until when the running capture lasts.
+    capturing: Option<Instant>,
+
+    /// Probes peers asked for, the probed endpoint and the deadline.
+    probes: HashMap<Key, (SocketAddr, Instant)>,
+
+    /// Probe results to be reported to the peers which asked.
+    probe_reports: Vec<(Key, SocketAddr, bool)>,
+
+    /// Peers cut off by the operator, see [`RunnerOptions::blocklist`].
+    blocked: HashSet<Key>,
+    revocations: Revocations,
+
+    /// Changes are logged but not applied, see [`Command::Freeze`].
+    frozen: bool,
+
+    /// Clients which asked for their config, to be sent it.
+    provisions: Vec<(String, Key)>,
+
+    /// Client config the coordinator sent us, asked for until then.
+    provisioned: Option<ClientConfig>,
+}
+
+impl<W, S, D> Runner<W, S, D>
+where
+    W: WireguardApi + Clone,
+    S: Signaling,
+    D: Discover,
+    Error: From<W::Error> + From<S::Error> + From<D::Error>,
+{
+    pub fn new(
+        iface: String,
+        key: Key,
+        config: WgConfig,
+        wg: W,
+        signaling: S,
+        discover: D,
+        options: RunnerOptions,
+    ) -> Self {
+        let peer_index = config
+            .peers
+            .iter()
+            .enumerate()
+            .map(|(idx, peer)| (peer.public_key, idx))
+            .collect();
+
+        let stale = options.handshake_timeout.map(HandshakeMonitor::new);
+
+        Self {
+            key,
+            config,
+            wg,
+            signaling,
+            discover,
+            limiter: RateLimiter::default(),
+            history: HashMap::new(),
+            skew: ClockSkew::default(),
+            peer_index,
+            routes: HashMap::new(),
+            exit_nodes: HashSet::new(),
+            relay_candidates: HashSet::new(),
+            keepalives: HashSet::new(),
+            applied_at: HashMap::new(),
+            handshakes: HashMap::new(),
+            helpers: HashMap::new(),
+            relay: None,
+            announcements: HashMap::new(),
+            announced_at: HashMap::new(),
+            forwards: VecDeque::new(),
+            synced: false,
+            sync_from: None,
+            kill_switch: KillSwitch::new(&iface),
+            listen_port: 0,
+            port_freed: false,
+            public: None,
+            public6: None,
+            nat_type: None,
+            gathered: Vec::new(),
+            local: None,
+            control: None,
+            events: None,
+            up: HashSet::new(),
+            pins: HashMap::new(),
+            deltas: DeltaSender::default(),
+            bases: DeltaReceiver::default(),
+            punch: PunchScheduler::new(options.punch_retry.clone()),
+            hints: options
+                .hints_file
+                .clone()
+                .map(Hints::load)
+                .unwrap_or_default(),
+            nicks: HashMap::new(),
+            punched: Vec::new(),
+            recoveries: Vec::new(),
+            rendezvous: Vec::new(),
+            active_uplink: 0,
+            uplink_checked: None,
+            reannounced: Instant::now(),
+            sleep: SleepDetector::default(),
+            saved: None,
+            stale,
+            metered: false,
+            metered_checked: None,
+            clock: Clock::system(),
+            address_warned: HashMap::new(),
+            clones: CloneDetector::default(),
+            cloned_self: false,
+            advertised: Vec::new(),
+            advertised_by: HashMap::new(),
+            bandwidth: HashMap::new(),
+            unsupported: Vec::new(),
+            health: RouteHealth::default(),
+            routes_checked: None,
+            capturing: None,
+            probes: HashMap::new(),
+            probe_reports: Vec::new(),
+            blocked: options.blocklist.iter().copied().collect(),
+            revocations: options
+                .revocations_file
+                .clone()
+                .map(Revocations::load)
+                .unwrap_or_default(),
+            frozen: false,
+            provisions: Vec::new(),
+            provisioned: None,
+            options,
+            iface,
+        }
+    }
+
+    /// Serves requests of control frontends, see [`control::channel`].
+    pub fn with_control(mut self, control: control::Receiver) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Handles the events of `events` like those of signaling, see
+    /// [`Beacon::run`](crate::signaling::beacon::Beacon::run).
+    pub fn with_events(mut self, events: mpsc::Receiver<PeerEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Time source, a manual clock lets tests drive it with paused time.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Serves until the signaling stream ends or a shutdown signal arrives,
+    /// then removes installed routes and the kill-switch.
+    pub async fn run(mut self) -> Result<(), Error> {
+        let res = self.serve().await;
+        self.restore_port().await;
+        self.save_snapshot(true);
+        self.teardown();
+        res
+    }
+
+    async fn serve(&mut self) -> Result<(), Error> {
+        self.apply_revocations().await;
+
+        if let Some(uplink) = self.options.uplinks.first()
+            && !self.options.observe
+        {
+            uplink::select(&mut self.wg, &self.iface, uplink).await?;
+        }
+
+        let snapshot = self.options.state_file.as_deref().and_then(Snapshot::load);
+        if let Some(snapshot) = &snapshot {
+            self.reconnect(snapshot).await?;
+        }
+
+        let (mapping, listen_port) = match self.options.server {
+            Some(public) => self.static_mapping(public).await?,
+            None => {
+                discover_mapping(
+                    &mut self.wg,
+                    &self.discover,
+                    &self.iface,
+                    self.config.interface.listen_port,
+                    self.options.port_policy,
+                )
+                .await?
+            }
+        };
+        log::info!("discovered mapping {mapping}");
+        if let Some(last) = snapshot.as_ref().and_then(|s| s.public)
+            && last != mapping.public
+        {
+            log::info!("public endpoint changed from {last} since the last run");
+        }
+        self.listen_port = listen_port;
+        self.public = Some(mapping.public);
+
+        if let Some(home) = self.options.relay.as_deref()
+            && !self.options.observe
+        {
+            let key = self.config.interface.private_key;
+            self.relay = Some(RelayClient::new(key, listen_port, home));
+        }
+
+        let probed = self
+            .probe_port(Probes {
+                uplinks: Some(mapping),
+                v6: true,
+                nat: true,
+                ..Default::default()
+            })
+            .await;
+        let mut endpoints = probed.uplinks;
+        self.public6 = probed.public6;
+        self.nat_type = probed.nat_type;
+        if self.options.server.is_none() {
+            self.gather_candidates(&mapping, &mut endpoints);
+        }
+        self.advertised = self.validate_advertised();
+        self.check_routes().await;
+
+        let mut update = PeerUpdate {
+            key: self.key,
+            endpoint: mapping.public,
+            advertise_routes: self.healthy_routes(),
+            ext: Extensions {
+                server: self.options.server.is_some(),
+                nat: self.options.server.is_none() && mapping.public.ip() != mapping.local.ip(),
+                transports: self
+                    .options
+                    .transports
+                    .iter()
+                    .filter_map(|t| Some((t.name.clone(), t.listen.clone()?)))
+                    .chain(
+                        self.options
+                            .relay
+                            .clone()
+                            .map(|home| (relay::TRANSPORT.to_string(), home)),
+                    )
+                    .collect(),
+                endpoints,
+                local_endpoint: match self.options.server {
+                    Some(_) => None,
+                    None => Some(SocketAddr::new(mapping.local.ip(), listen_port)),
+                },
+                endpoint6: self.public6,
+                nat_type: self.nat_type,
+                address: Some(self.config.interface.address)
+                    .filter(|addr| !addr.ip.is_unspecified()),
+                bandwidth: self.config.interface.bandwidth,
+                meta: self.options.metadata.clone(),
+                ..Default::default()
+            },
+        };
+        let mut public = update.endpoint;
+        self.local = update.ext.local_endpoint;
+
+        if let Some(snapshot) = snapshot {
+            self.restore(snapshot, &mapping.public).await?;
+        }
+
+        // announcing self peer
+        self.try_announce(&update, None).await;
+        self.reannounced = self.clock.now();
+
+        let mut stream = pin!(self.signaling.subscribe().await?);
+        let watcher = WgWatcher::new(
+            self.wg.clone(),
+            self.iface.clone(),
+            self.options.limits.watch_interval,
+        );
+        let mut wg_events = pin!(watcher.into_stream());
+        let mut replies = ReplyQueue::default();
+        let mut tick = tokio::time::interval(self.limiter.period());
+        let mut housekeeping = tokio::time::interval(self.options.limits.housekeeping_interval);
+        let mut announcing = Debounce::new(ANNOUNCE_DEBOUNCE, ANNOUNCE_MAX_DELAY);
+        let mut shutdown = pin!(shutdown::signal());
+
+        loop {
+            let announce_due = announcing.due();
+            let rendezvous = self.punch.next_rendezvous();
+
+            tokio::select! {
+                res = stream.next() => {
+                    let Some(res) = res else { break };
+
+                    let mut endpoints = HashMap::new();
+                    self.handle(res, &public, &mut endpoints, &mut replies).await;
+
+                    // fold everything that is already received into one batch
+                    while endpoints.len() < self.options.limits.batch {
+                        match stream.next().now_or_never() {
+                            Some(Some(res)) => self.handle(res, &public, &mut endpoints, &mut replies).await,
+                            _ => break,
+                        }
+                    }
+
+                    self.try_apply_endpoints(endpoints).await;
+
+                    if let Some(nick) = self.sync_from.take() {
+                        let nick = self.preferred_relay().unwrap_or(nick);
+                        log::info!("requesting state sync from {nick}");
+
+                        let mut request = update.clone();
+                        request.ext.sync = true;
+                        self.try_announce(&request, Some(&nick)).await;
+                    }
+                }
+
+                Some(event) = next_event(&mut self.events) => {
+                    let mut endpoints = HashMap::new();
+                    self.handle(Ok(event), &public, &mut endpoints, &mut replies).await;
+                    self.try_apply_endpoints(endpoints).await;
+                }
+
+                Some(res) = wg_events.next() => match res {
+                    Ok(event) => self.handle_wg_event(event),
+                    Err(err) => log::error!("wg error: {}", Error::from(err)),
+                },
+
+                _ = tokio::time::sleep_until(announce_due.unwrap_or_else(Instant::now)), if announce_due.is_some() => {
+                    if announcing.take(self.clock.now()) {
+                        self.try_announce(&update, None).await;
+                        self.push(&mut replies);
+                    }
+                }
+
+                _ = tokio::time::sleep_until(rendezvous.unwrap_or_else(Instant::now)), if rendezvous.is_some() => {
+                    self.punch_together().await?;
+                }
+
+                _ = tick.tick(), if !replies.is_empty() || !self.forwards.is_empty() || !self.punched.is_empty() || !self.probe_reports.is_empty() || !self.recoveries.is_empty() || !self.rendezvous.is_empty() || !self.provisions.is_empty() => {}
+
+                _ = housekeeping.tick() => {
+                    self.expire_pins().await;
+                    self.mark_down().await;
+                    self.expire_probes();
+                    self.check_upgrades();
+                    self.check_metered().await;
+                    self.save_snapshot(false);
+                    self.restore_port().await;
+
+                    if self.frozen {
+                        continue;
+                    }
+
+                    self.release_damped().await?;
+                    self.fallback_transports().await?;
+                    self.retry_punches().await?;
+                    self.verify_routes();
+
+                    let mut mapping = self.check_uplinks().await;
+                    let resumed = self.check_resume();
+                    if self.check_handshakes().await {
+                        announcing.trigger(self.clock.now());
+                    }
+
+                    // everything to ask stun in one go, see `probe_port`
+                    let due = self.reannounce_due() || resumed;
+                    if due || mapping.is_some() {
+                        let probed = self
+                            .probe_port(Probes {
+                                mapping: mapping.is_none(),
+                                v6: due,
+                                nat: true,
+                                ..Default::default()
+                            })
+                            .await;
+
+                        if let Some(found) = probed.mapping {
+                            if Some(found.public) != self.public {
+                                log::info!("mapping changed to {found}");
+                                self.public = Some(found.public);
+                            }
+                            mapping = Some(found).filter(|mapping| mapping.public != public);
+                        }
+
+                        if due && probed.public6 != self.public6 {
+                            let public6 = probed.public6;
+                            log::info!("ipv6 endpoint changed to {public6:?}");
+                            self.public6 = public6;
+                            update.ext.endpoint6 = public6;
+                            update.ext.endpoints.retain(|addr| Some(*addr) != public6);
+                        }
+
+                        self.nat_type = probed.nat_type;
+                        update.ext.nat_type = self.nat_type;
+                    }
+
+                    if due {
+                        announcing.trigger(self.clock.now());
+                    }
+
+                    if let Some(mapping) = mapping {
+                        update.endpoint = mapping.public;
+                        update.ext.local_endpoint = Some(SocketAddr::new(mapping.local.ip(), self.listen_port));
+                        update.ext.nat = mapping.public.ip() != mapping.local.ip();
+                        update.ext.endpoints.retain(|addr| *addr != mapping.public && !self.gathered.contains(addr));
+                        self.gather_candidates(&mapping, &mut update.ext.endpoints);
+                        public = update.endpoint;
+                        self.local = update.ext.local_endpoint;
+
+                        // peers learn the new endpoint right away
+                        announcing.trigger(self.clock.now());
+                    }
+
+                    if self.check_routes().await {
+                        update.advertise_routes = self.healthy_routes();
+                        announcing.trigger(self.clock.now());
+                    }
+                }
+
+                Some(req) = next_request(&mut self.control) => {
+                    let response = match req.command {
+                        Command::Announce => {
+                            announcing.trigger(self.clock.now());
+                            Response::Ok
+                        }
+                        Command::Pin { key, endpoint, ttl } => self.pin(key, endpoint, ttl).await,
+                        Command::Unpin(key) => match self.unpin(key).await {
+                            true => Response::Ok,
+                            false => Response::Error(format!("peer {key} is not pinned")),
+                        },
+                        Command::Probe(key) => self.request_probe(key, &update).await,
+                        Command::Block(key) => self.block(key).await,
+                        Command::Freeze { enable: true } => self.freeze(),
+                        Command::Freeze { enable: false } => self.thaw().await,
+                        Command::Revoke(key) => match self.revoke(key).await {
+                            Ok(()) => {
+                                announcing.trigger(self.clock.now());
+                                Response::Ok
+                            }
+                            Err(err) => Response::Error(err),
+                        },
+                        Command::Unblock(key) if self.revocations.contains(&key) => {
+                            Response::Error(format!("peer {key} is revoked"))
+                        }
+                        Command::Unblock(key) => match self.unblock(key) {
+                            true => Response::Ok,
+                            false => Response::Error(format!("peer {key} is not blocked")),
+                        },
+                        command => self.query(command).await,
+                    };
+
+                    let _ = req.reply.send(response);
+                }
+
+                _ = &mut shutdown => {
+                    log::info!("shutting down");
+                    break;
+                }
+            }
+
+            // servers answer everybody right away
+            let server = self.options.server.is_some();
+            while !replies.is_empty() && (server || self.limiter.try_acquire(self.clock.now())) {
+                if let Some(nick) = replies.pop() {
+                    self.try_announce(&update, Some(&nick)).await;
+                }
+            }
+
+            while !self.forwards.is_empty() && self.limiter.try_acquire(self.clock.now()) {
+                if let Some((nick, peer)) = self.forwards.pop_front()
+                    && let Err(err) = self.signaling.announce(peer, Some(&nick)).await
+                {
+                    log::error!("can't forward to {nick}: {}", Error::from(err));
+                }
+            }
+
+            while !self.punched.is_empty() && self.limiter.try_acquire(self.clock.now()) {
+                let Some((key, addr)) = self.punched.pop() else {
+                    break;
+                };
+                let Some(nick) = self.nicks.get(&key).cloned() else {
+                    continue;
+                };
+
+                let mut report = update.clone();
+                report.ext.punched = Some(addr);
+                self.try_announce(&report, Some(&nick)).await;
+            }
+
+            while !self.recoveries.is_empty() && self.limiter.try_acquire(self.clock.now()) {
+                let key = self.recoveries.remove(0);
+                let Some(nick) = self.nicks.get(&key).cloned() else {
+                    continue;
+                };
+
+                self.try_announce(&update, Some(&nick)).await;
+            }
+
+            while !self.rendezvous.is_empty() && self.limiter.try_acquire(self.clock.now()) {
+                let key = self.rendezvous.remove(0);
+                let Some(nick) = self.nicks.get(&key).cloned() else {
+                    continue;
+                };
+
+                if !self.punch.meet(key, self.clock.now() + PUNCH_LEAD) {
+                    continue;
+                }
+
+                let mut request = update.clone();
+                request.ext.punch_at = Some(self.clock.unix_ms() + PUNCH_LEAD.as_millis() as u64);
+                self.try_announce(&request, Some(&nick)).await;
+            }
+
+            while !self.probe_reports.is_empty() && self.limiter.try_acquire(self.clock.now()) {
+                let Some((key, addr, answered)) = self.probe_reports.pop() else {
+                    break;
+                };
+                let Some(nick) = self.nicks.get(&key).cloned() else {
+                    continue;
+                };
+
+                let mut report = update.clone();
+                report.ext.probed = Some((addr, answered));
+                self.try_announce(&report, Some(&nick)).await;
+            }
+
+            while !self.provisions.is_empty() && self.limiter.try_acquire(self.clock.now()) {
+                let (nick, key) = self.provisions.remove(0);
+                let Some(config) = self.options.provision.get(&key) else {
+                    continue;
+                };
+
+                let mut reply = update.clone();
+                reply.ext.config = Some(config.sign(&self.config.interface.private_key, &key));
+                self.try_announce(&reply, Some(&nick)).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn announce(&mut self, update: &PeerUpdate, nick: Option<&str>) -> Result<(), Error> {
+        if self.options.observe {
+            return Ok(());
+        }
+
+        let mut full = update.clone();
+        full.ext.provision = self.options.provision_from.is_some() && self.provisioned.is_none();
+        full.ext.revoked = self.revocations.to_announce();
+        codec::fit(&mut full);
+
+        let update = match self.options.delta {
+            true => self.deltas.encode(full.clone(), nick.is_some()),
+            false => full.clone(),
+        };
+
+        // stamped and signed anew for every attempt
+        let retry = self.options.announce_retry.clone();
+        retry
+            .retry_if("announce", Error::is_send_failure, async || {
+                let mut update = update.clone();
+                update.ext.timestamp = Some(self.clock.unix_ms());
+                update.ext.signature = self.signature(&full, update.ext.timestamp, update.ext.seq);
+
+                Ok(self.signaling.announce(update, nick).await?)
+            })
+            .await
+    }
+
+    /// [`announce`](Self::announce) from the event loop: a failure is
+    /// logged and the daemon keeps running, the next announcement may get
+    /// through.
+    async fn try_announce(&mut self, update: &PeerUpdate, nick: Option<&str>) {
+        if let Err(err) = self.announce(update, nick).await {
+            match nick {
+                Some(nick) => log::error!("can't announce to {nick}: {err}"),
+                None => log::error!("can't announce: {err}"),
+            }
+        }
+    }
+
+    /// Signature of the complete announcement as it goes out at
+    /// `timestamp`, even when only a delta of it is sent.
+    fn signature(&self, full: &PeerUpdate, timestamp: Option<u64>, seq: u32) -> Option<[u8; 64]> {
+        let mut signed = full.clone();
+        signed.ext.timestamp = timestamp;
+        signed.ext.seq = seq;
+        signed.sign(&self.config.interface.private_key);
+        signed.ext.signature
+    }
+
+    async fn handle(
+        &mut self,
+        res: Result<PeerEvent, S::Error>,
+        public: &SocketAddr,
+        endpoints: &mut HashMap<Key, SocketAddr>,
+        replies: &mut ReplyQueue,
+    ) {
+        match res {
+            Ok(PeerEvent::Request(nick, peer)) => {
+                let Some(peer) = self.complete(peer, self.options.max_age).await else {
+                    return;
+                };
+
+                if !self.observe_source(&nick, &peer) {
+                    return;
+                }
+
+                log::info!(
+                    "requested update from {} peer {} {} (local {:?})",
+                    nick,
+                    peer.key,
+                    peer.endpoint,
+                    peer.ext.local_endpoint
+                );
+
+                let announce = self.options.groups.policy(&peer.key).announce;
+
+                self.nicks.insert(peer.key, nick.clone());
+                self.observe_clock(&peer);
+                self.observe_metadata(&peer);
+                self.observe_punch(&peer);
+                self.accept_routes(&peer).await;
+                self.exchange_keepalive(&peer).await;
+                endpoints.insert(peer.key, self.first_candidate(&peer, public));
+                self.observe_rendezvous(&peer);
+                self.observe_probe(&peer, endpoints).await;
+                self.observe_provision(&nick, &peer);
+                self.forward_to(&nick, &peer);
+                self.remember(peer);
+
+                if announce {
+                    replies.push(nick);
+                }
+            }
+
+            Ok(PeerEvent::Response(nick, peer)) => {
+                let Some(peer) = self.complete(peer, self.options.max_age).await else {
+                    return;
+                };
+
+                if !self.observe_source(&nick, &peer) {
+                    return;
+                }
+
+                log::info!(
+                    "responded update peer {} {} (local {:?})",
+                    peer.key,
+                    peer.endpoint,
+                    peer.ext.local_endpoint
+                );
+
+                self.nicks.insert(peer.key, nick.clone());
+                self.observe_clock(&peer);
+                self.observe_metadata(&peer);
+                self.observe_punch(&peer);
+                self.accept_routes(&peer).await;
+                self.exchange_keepalive(&peer).await;
+                endpoints.insert(peer.key, self.first_candidate(&peer, public));
+                self.observe_rendezvous(&peer);
+                self.observe_probe(&peer, endpoints).await;
+                self.observe_provision(&nick, &peer);
+                self.observe_config(&peer);
+
+                if peer.ext.sync {
+                    self.forward_all(&nick, &peer);
+                }
+
+                // one established peer is enough to learn about everybody
+                if !self.synced && !peer.ext.sync {
+                    self.synced = true;
+                    self.sync_from = Some(nick);
+                }
+
+                self.remember(peer);
+            }
+
+            Ok(PeerEvent::Relayed(peer)) => {
+                let Some(peer) = self.complete(peer, Some(MAX_RELAYED_AGE)).await else {
+                    return;
+                };
+
+                log::info!(
+                    "relayed update peer {} {} (local {:?})",
+                    peer.key,
+                    peer.endpoint,
+                    peer.ext.local_endpoint
+                );
+
+                self.accept_routes(&peer).await;
+                self.exchange_keepalive(&peer).await;
+                endpoints.insert(peer.key, self.first_candidate(&peer, public));
+            }
+
+            Ok(PeerEvent::Bootstrap(nick, peer)) => {
+                // whatever the server announced itself is more recent
+                if peer.key == self.key
+                    || self.announcements.contains_key(&peer.key)
+                    || self.blocked.contains(&peer.key)
+                {
+                    return;
+                }
+
+                // ask it for everybody else before any peer speaks
+                if !self.synced
+                    && self.sync_from.is_none()
+                    && let Some(nick) = nick
+                {
+                    self.sync_from = Some(nick);
+                }
+
+                // anybody in the channel can set the topic and its entries
+                // can't be signed. They are only tried on peers we have no
+                // session with, and don't make relays. Where signatures
+                // are required the endpoint waits for the signed
+                // announcement the server answers the sync with.
+                if !self.options.allow_unsigned || self.up.contains(&peer.key) {
+                    return;
+                }
+
+                log::info!(
+                    "channel topic lists server peer {} {}, probing it",
+                    peer.key,
+                    peer.endpoint
+                );
+                endpoints.entry(peer.key).or_insert(peer.endpoint);
+            }
+
+            Ok(PeerEvent::Lan(key, addr)) => {
+                // the beacon is authentic, the peer may still be unwelcome
+                if !self.peer_index.contains_key(&key)
+                    || self.blocked.contains(&key)
+                    || self.revocations.contains(&key)
+                {
+                    return;
+                }
+
+                log::info!("peer {key} found on LAN at {addr}");
+                endpoints.insert(key, addr);
+            }
+
+            Err(err) => log::error!("error: {}", Error::from(err)),
+        }
+    }
+
+    /// Fills in fields left out of a delta announcement, drops it when the
+    /// announced address is rejected, the signature doesn't check out or it
+    /// is further than `window` from what the sender's clock should show.
+    async fn complete(&mut self, peer: PeerUpdate, window: Option<Duration>) -> Option<PeerUpdate> {
+        let key = peer.key;
+        peer_debug!(key, "update of {key}: {peer:?}");
+
+        if self.blocked.contains(&key) {
+            peer_debug!(key, "dropping update of blocked peer {key}");
+            return None;
+        }
+
+        if let Some(revocation) = peer.ext.revoked {
+            self.observe_revocation(revocation).await;
+
+            // signed by somebody else but carried by the revoked peer
+            if self.blocked.contains(&key) {
+                return None;
+            }
+        }
+
+        let Some(peer) = self.bases.complete(peer) else {
+            peer_debug!(key, "dropping delta of {key}, missed its full announcement");
+            return None;
+        };
+
+        if !self.check_signature(&peer) {
+            return None;
+        }
+
+        // peers which don't timestamp their announcements can't be checked
+        if let Some(window) = window
+            && let Some(sent) = peer.ext.timestamp
+            && !self.skew.is_fresh(&key, sent, self.clock.unix_ms(), window)
+        {
+            peer_debug!(key, "dropping stale or replayed update of {key}");
+            return None;
+        }
+
+        match self.check_address(&peer) {
+            true => Some(peer),
+            false => None,
+        }
+    }
+
+    /// Drops forged announcements and unsigned ones unless allowed.
+    fn check_signature(&self, peer: &PeerUpdate) -> bool {
+        let key = peer.key;
+
+        match peer.ext.signature {
+            Some(_) if !peer.verify() => {
+                log::warn!("dropping announcement of {key} with a bad signature");
+                false
+            }
+            None if !self.options.allow_unsigned => {
+                peer_debug!(key, "dropping unsigned announcement of {key}");
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// False while the key is announced by two nodes at once, using their
+    /// announcements would only flap the endpoint between them.
+    fn observe_source(&mut self, nick: &str, peer: &PeerUpdate) -> bool {
+        // our own announcements don't come back, somebody else has our key
+        if peer.key == self.key {
+            if !self.cloned_self {
+                self.cloned_self = true;
+                log::error!(
+                    "CLONED NODE: {nick} at {} announces our own key {}. Copied VM image? \
+                     Give each node its own key.",
+                    peer.endpoint,
+                    self.key
+                );
+                self.emit(Event::Cloned(self.key));
+            }
+
+            return false;
+        }
+
+        let now = self.clock.now();
+
+        match self.clones.observe(peer.key, nick, peer.endpoint, now) {
+            Origin::Unique => true,
+            Origin::Detected => {
+                log::error!(
+                    "CLONED NODE: key {} is announced by two live nodes ({nick} at {} and another one), \
+                     ignoring its announcements until one of them stops. Copied VM image? \
+                     Give each node its own key.",
+                    peer.key,
+                    peer.endpoint
+                );
+                self.emit(Event::Cloned(peer.key));
+                false
+            }
+            Origin::Cloned => false,
+        }
+    }
+
+    /// Whether the announced tunnel address fits the AllowedIPs configured
+    /// for the peer and isn't ours. Complains once per address.
+    fn check_address(&mut self, peer: &PeerUpdate) -> bool {
+        let (Some(address), Some(&idx)) = (peer.ext.address, self.peer_index.get(&peer.key)) else {
+            return true;
+        };
+
+        let host = Cidr {
+            ip: address.ip,
+            mask: if address.ip.is_ipv4() { 32 } else { 128 },
+        };
+        let allowed = self.config.peers[idx]
+            .allowed_ips
+            .as_deref()
+            .unwrap_or_default();
+
+        let problem = if address.ip == self.config.interface.address.ip {
+            "is our own"
+        } else if !allowed.iter().any(|cidr| cidr.contains(&host)) {
+            "is outside of its AllowedIPs"
+        } else {
+            self.address_warned.remove(&peer.key);
+            return true;
+        };
+
+        if self.address_warned.insert(peer.key, address) != Some(address) {
+            log::warn!(
+                "peer {} announces tunnel address {address}, which {problem}: misconfigured or cloned node?",
+                peer.key
+            );
+        }
+
+        self.options.address_policy == AddressPolicy::Warn
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(control) = &self.control {
+            control.emit(event);
+        }
+    }
+
+    /// Peers are down once wireguard would have rekeyed by now.
+    async fn mark_down(&mut self) {
+        let now = self.clock.now();
+        let down: Vec<Key> = self
+            .up
+            .iter()
+            .filter(|key| {
+                self.handshakes
+                    .get(key)
+                    .is_none_or(|at| now - *at > PEER_DOWN_AFTER)
+            })
+            .copied()
+            .collect();
+
+        for key in down {
+            log::info!("peer {key} is down");
+            self.up.remove(&key);
+            self.emit(Event::PeerDown(key));
+            self.recover(key).await;
+        }
+    }
+
+    /// Negotiates candidates again with a peer whose session just went
+    /// stale, instead of waiting for one side to re-announce. Our
+    /// announcement is sent to its nick, which only arrives while the peer
+    /// is still on signaling.
+    async fn recover(&mut self, key: Key) {
+        if self.frozen
+            || self.options.observe
+            || self.pins.contains_key(&key)
+            || self.helpers.contains_key(&key)
+            || self.blocked.contains(&key)
+            || !self.nicks.contains_key(&key)
+        {
+            return;
+        }
+
+        let (Some(peer), Some(public)) = (self.announcements.get(&key).cloned(), self.public)
+        else {
+            return;
+        };
+
+        log::info!("session with {key} went stale, negotiating candidates again");
+        let addr = self.first_candidate(&peer, &public);
+
+        match self.set_endpoints(&[(key, Endpoint::from(addr))]).await {
+            Ok(()) => {
+                self.applied_at.insert(key, self.clock.now());
+            }
+            Err(err) => log::error!("can't set endpoint of {key}: {}", Error::from(err)),
+        }
+
+        if !self.recoveries.contains(&key) {
+            self.recoveries.push(key);
+        }
+    }
+
+    async fn pin(&mut self, key: Key, endpoint: SocketAddr, ttl: Duration) -> Response {
+        if !self.peer_index.contains_key(&key) {
+            return Response::Error(format!("unknown peer {key}"));
+        }
+
+        // wg would add it back
+        if self.revocations.contains(&key) {
+            return Response::Error(format!("peer {key} is revoked"));
+        }
+
+        if self.frozen {
+            return Response::Error("changes are frozen".into());
+        }
+
+        let Some(until) = self.clock.now().checked_add(ttl) else {
+            return Response::Error(format!("pin ttl {ttl:?} is too long"));
+        };
+
+        if let Err(err) = self.set_endpoints(&[(key, Endpoint::from(endpoint))]).await {
+            return Response::Error(Error::from(err).to_string());
+        }
+
+        log::warn!("peer {key} pinned to {endpoint} for {ttl:?}");
+        self.punch.cancel(&key);
+        self.helpers.remove(&key);
+        self.applied_at.remove(&key);
+        self.pins.insert(key, (endpoint, until));
+
+        Response::Ok
+    }
+
+    /// Removes the pin and goes back to the last announced endpoint,
+    /// `false` when the peer wasn't pinned.
+    async fn unpin(&mut self, key: Key) -> bool {
+        if self.pins.remove(&key).is_none() {
+            return false;
+        }
+
+        log::info!("peer {key} unpinned");
+
+        let (Some(peer), Some(public)) = (self.announcements.get(&key), self.public) else {
+            return true;
+        };
+
+        let endpoint = peer.endpoint_for(&public);
+        match self.set_endpoints(&[(key, Endpoint::from(endpoint))]).await {
+            Ok(()) => {
+                self.applied_at.insert(key, self.clock.now());
+            }
+            Err(err) => log::error!("can't restore endpoint of {key}: {}", Error::from(err)),
+        }
+
+        true
+    }
+
+    /// Ignores announcements of the peer from now on and forgets what it
+    /// announced: its routes are withdrawn and it isn't forwarded to others.
+    async fn block(&mut self, key: Key) -> Response {
+        if key == self.key {
+            return Response::Error("can't block ourselves".into());
+        }
+
+        if !self.blocked.insert(key) {
+            return Response::Error(format!("peer {key} is already blocked"));
+        }
+
+        log::warn!("peer {key} blocked, ignoring its announcements");
+        self.announcements.remove(&key);
+        self.announced_at.remove(&key);
+        self.forwards.retain(|(_, peer)| peer.key != key);
+        self.relay_candidates.remove(&key);
+        self.punch.cancel(&key);
+        self.helpers.remove(&key);
+        self.probes.remove(&key);
+        self.probe_reports.retain(|(to, _, _)| *to != key);
+
+        if self.advertised_by.remove(&key).is_some() {
+            self.apply_routes(key).await;
+        }
+
+        Response::Ok
+    }
+
+    /// Its next announcement is used again, `false` when the peer wasn't
+    /// blocked.
+    fn unblock(&mut self, key: Key) -> bool {
+        if !self.blocked.remove(&key) {
+            return false;
+        }
+
+        log::info!("peer {key} unblocked");
+        true
+    }
+
+    /// Signs a revocation of the peer and applies it, it's passed on with
+    /// our announcements. Only for configured signers.
+    async fn revoke(&mut self, key: Key) -> Result<(), String> {
+        if !self.options.revocation_signers.contains(&self.key) {
+            return Err("we are not a revocation signer".into());
+        }
+
+        if self.revocations.contains(&key) {
+            return Err(format!("peer {key} is already revoked"));
+        }
+
+        let revocation = Revocation::sign(
+            &self.config.interface.private_key,
+            key,
+            self.clock.unix_ms(),
+        );
+        self.observe_revocation(revocation).await;
+
+        match self.revocations.contains(&key) {
+            true => Ok(()),
+            false => Err(format!("can't revoke peer {key}")),
+        }
+    }
+
+    /// Follows a revocation signed by one of the configured signers: the
+    /// peer is blocked, removed from the interface and the revocation is
+    /// saved and passed on.
+    async fn observe_revocation(&mut self, revocation: Revocation) {
+        let key = revocation.key;
+        if self.options.revocation_signers.is_empty() || self.revocations.contains(&key) {
+            return;
+        }
+
+        let Some(signer) = self
+            .options
+            .revocation_signers
+            .iter()
+            .find(|signer| revocation.verify(signer))
+        else {
+            peer_debug!(
+                key,
+                "ignoring revocation of {key}, no signer of ours signed it"
+            );
+            return;
+        };
+
+        if key == self.key {
+            log::error!("our key {key} is revoked by {signer}, ignoring it");
+            return;
+        }
+
+        log::warn!("peer {key} revoked by {signer}");
+        self.revocations.insert(revocation);
+        self.cut_off(key).await;
+        self.emit(Event::Revoked(key));
+    }
+
+    /// Revocations kept from previous runs, dropping those of signers no
+    /// longer configured.
+    async fn apply_revocations(&mut self) {
+        let signers = &self.options.revocation_signers;
+        self.revocations
+            .retain(|revocation| signers.iter().any(|signer| revocation.verify(signer)));
+
+        let keys: Vec<Key> = self
+            .revocations
+            .iter()
+            .map(|revocation| revocation.key)
+            .collect();
+        for key in keys {
+            log::info!("peer {key} is revoked");
+            self.cut_off(key).await;
+        }
+    }
+
+    /// Blocks the peer and drops it from the interface.
+    async fn cut_off(&mut self, key: Key) {
+        if !self.blocked.contains(&key) {
+            self.block(key).await;
+        }
+        self.pins.remove(&key);
+        self.up.remove(&key);
+
+        if self.options.observe || self.frozen || !self.peer_index.contains_key(&key) {
+            return;
+        }
+
+        if let Err(err) = self.wg.remove_peer(&self.iface, key).await {
+            log::error!("can't remove revoked peer {key}: {}", Error::from(err));
+        }
+    }
+
+    /// Stops applying endpoints, routes, keepalives and revocations, they
+    /// are only logged. Announcements are still followed and answered.
+    fn freeze(&mut self) -> Response {
+        if self.frozen {
+            return Response::Error("already frozen".into());
+        }
+
+        log::warn!("frozen, changes are logged but not applied until unfrozen");
+        self.frozen = true;
+        Response::Ok
+    }
+
+    /// Applies what peers announced while frozen and resumes.
+    async fn thaw(&mut self) -> Response {
+        if !self.frozen {
+            return Response::Error("not frozen".into());
+        }
+
+        log::warn!("unfrozen, applying changes announced meanwhile");
+        self.frozen = false;
+
+        let revoked: Vec<Key> = self
+            .revocations
+            .iter()
+            .map(|revocation| revocation.key)
+            .collect();
+        for key in revoked {
+            self.cut_off(key).await;
+        }
+
+        let advertised: Vec<Key> = self.advertised_by.keys().copied().collect();
+        for key in advertised {
+            self.apply_routes(key).await;
+        }
+
+        let Some(public) = self.public else {
+            return Response::Ok;
+        };
+
+        let endpoints = self
+            .announcements
+            .values()
+            .filter(|peer| !self.blocked.contains(&peer.key))
+            .map(|peer| (peer.key, peer.endpoint_for(&public)))
+            .collect();
+
+        match self.apply_endpoints(endpoints).await {
+            Ok(()) => Response::Ok,
+            Err(err) => Response::Error(err.to_string()),
+        }
+    }
+
+    async fn expire_pins(&mut self) {
+        let now = self.clock.now();
+        let expired: Vec<Key> = self
+            .pins
+            .iter()
+            .filter(|(_, (_, until))| *until <= now)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in expired {
+            log::info!("pin of peer {key} expired");
+            self.unpin(key).await;
+        }
+    }
+
+    async fn explain(&self, key: Key) -> Result<Explanation, Error> {
+        let state = self.wg.get_state(&self.iface).await?;
+        let info = state.peers.iter().find(|p| p.public_key == key);
+
+        let now = self.clock.now();
+        let unix = self.clock.unix_ms() / 1000;
+        let announcement = self.announcements.get(&key);
+
+        Ok(Explanation {
+            key,
+            configured: self.peer_index.contains_key(&key),
+            up: self.up.contains(&key),
+            handshake_age: info
+                .and_then(|i| i.latest_handshake)
+                .map(|at| unix.saturating_sub(at as u64)),
+            endpoint: info.and_then(|i| i.endpoint.as_ref().map(|e| e.to_string())),
+            announce_age: self
+                .announced_at
+                .get(&key)
+                .map(|at| now.saturating_duration_since(*at).as_secs()),
+            online: self.nicks.contains_key(&key),
+            peer_nat: announcement.map(|a| a.ext.nat),
+            peer_nat_type: announcement.and_then(|a| a.ext.nat_type),
+            our_nat_type: self.nat_type,
+            our_nat: self
+                .public
+                .zip(self.local)
+                .map(|(public, local)| public.ip() != local.ip()),
+            punch: self.punch.progress(&key),
+            hint: self.hints.get(&key),
+            relays: self.relay_candidates.len(),
+            transport: self.helpers.get(&key).map(|h| h.name().to_string()),
+            pinned: self.pins.get(&key).map(|(addr, _)| *addr),
+            blocked: self.blocked.contains(&key),
+            revoked: self.revocations.contains(&key),
+            frozen: self.frozen,
+        })
+    }
+
+    async fn query(&self, command: Command) -> Response {
+        match command {
+            Command::Status => Response::Status(Status {
+                iface: self.iface.clone(),
+                key: self.key,
+                endpoint: self.public,
+                listen_port: self.listen_port,
+                nat_type: self.nat_type,
+                server: self.options.server.is_some(),
+                kill_switch: self.kill_switch.is_engaged(),
+                peers: self.config.peers.len(),
+                frozen: self.frozen,
+                metered: self.metered,
+                advertise_routes: self.healthy_routes(),
+                min_protocol: self.live_protocols().map(|(_, protocol)| protocol).min(),
+                unsupported: self.unsupported_features(),
+                wg_operations: executor::timings(&self.iface),
+            }),
+
+            Command::Peers => {
+                let state = match self.wg.get_state(&self.iface).await {
+                    Ok(state) => state,
+                    Err(err) => return Response::Error(Error::from(err).to_string()),
+                };
+
+                let now = self.clock.now();
+                let peers =
+                    self.config
+                        .peers
+                        .iter()
+                        .map(|peer| {
+                            let key = peer.public_key;
+                            let info = state.peers.iter().find(|p| p.public_key == key);
+                            let announcement = self.announcements.get(&key);
+                            let ext = announcement.map(|a| &a.ext);
+
+                            PeerStatus {
+                                key,
+                                endpoint: info.and_then(|i| i.endpoint.clone()),
+                                latest_handshake: info.and_then(|i| i.latest_handshake),
+                                transfer: info.and_then(|i| i.transfer),
+                                routes: self.routes.get(&key).cloned().unwrap_or_default(),
+                                nat: ext.is_some_and(|e| e.nat),
+                                nat_type: ext.and_then(|e| e.nat_type),
+                                server: ext.is_some_and(|e| e.server),
+                                candidates: announcement
+                                    .map(PeerUpdate::candidates)
+                                    .unwrap_or_default(),
+                                online: self.nicks.contains_key(&key),
+                                transport: self.helpers.get(&key).map(|h| h.name().to_string()),
+                                pinned_for: self.pins.get(&key).map(|(_, until)| {
+                                    until.saturating_duration_since(now).as_secs()
+                                }),
+                                bandwidth: self.bandwidth.get(&key).copied(),
+                                meta: ext.and_then(|e| e.meta.clone()),
+                                exit: self.exit_nodes.contains(&key),
+                                debug: peerlog::is_enabled(&key),
+                                blocked: self.blocked.contains(&key),
+                            }
+                        })
+                        .collect();
+
+                Response::Peers(peers)
+            }
+
+            Command::Routes => Response::Routes(
+                self.routes
+                    .iter()
+                    .flat_map(|(via, routes)| {
+                        routes.iter().map(|cidr| RouteStatus {
+                            cidr: *cidr,
+                            via: *via,
+                        })
+                    })
+                    .collect(),
+            ),
+
+            Command::Debug { key, enable: true } => {
+                log::info!("logging debug messages of peer {key}");
+                peerlog::enable(key);
+                Response::Ok
+            }
+
+            Command::Debug { key, enable: false } => match peerlog::disable(&key) {
+                true => Response::Ok,
+                false => Response::Error(format!("peer {key} is not debugged")),
+            },
+
+            Command::Explain(key) => match self.explain(key).await {
+                Ok(explanation) => Response::Explanation(Box::new(explanation)),
+                Err(err) => Response::Error(err.to_string()),
+            },
+
+            Command::Announce
+            | Command::Pin { .. }
+            | Command::Unpin(_)
+            | Command::Probe(_)
+            | Command::Block(_)
+            | Command::Unblock(_)
+            | Command::Freeze { .. }
+            | Command::Revoke(_) => Response::Error("command is handled by the loop".into()),
+        }
+    }
+
+    /// Nickname of the server with the most bandwidth, the one to sync from.
+    fn preferred_relay(&self) -> Option<String> {
+        self.relay_candidates
+            .iter()
+            .filter(|key| self.bandwidth.contains_key(key))
+            .max_by_key(|key| self.bandwidth[key])
+            .and_then(|key| self.nicks.get(key).cloned())
+    }
+
+    /// Notes server peers and keeps the latest direct announcement of every
+    /// peer for late joiners.
+    fn remember(&mut self, mut peer: PeerUpdate) {
+        if peer.ext.server && self.relay_candidates.insert(peer.key) {
+            log::info!("peer {} is a server, using it as relay candidate", peer.key);
+        }
+
+        // signed, a request stripped of them wouldn't verify anymore
+        if peer.ext.punch_at.is_some() || peer.ext.probe {
+            return;
+        }
+
+        peer.ext.sync = false;
+        peer.ext.punched = None;
+        peer.ext.probed = None;
+        peer.ext.revoked = None;
+        peer.ext.config = None;
+        self.announced_at.insert(peer.key, self.clock.now());
+        self.announcements.insert(peer.key, peer);
+    }
+
+    /// Queues the config of a client asking for it, when we coordinate it.
+    fn observe_provision(&mut self, nick: &str, peer: &PeerUpdate) {
+        let key = peer.key;
+        if !peer.ext.provision || self.options.provision.is_empty() {
+            return;
+        }
+
+        if !self.options.provision.contains_key(&key) {
+            log::warn!("peer {key} asks for a client config but isn't in [provision]");
+            return;
+        }
+
+        // allowed unsigned announcements could come from anybody
+        if peer.ext.signature.is_none() {
+            log::warn!("ignoring unsigned client config request of {key}");
+            return;
+        }
+
+        if !self.provisions.iter().any(|(_, queued)| *queued == key) {
+            log::info!("sending client config to peer {key}");
+            self.provisions.push((nick.to_string(), key));
+        }
+    }
+
+    /// Saves the client config our coordinator sent.
+    fn observe_config(&mut self, peer: &PeerUpdate) {
+        let Some(signed) = &peer.ext.config else {
+            return;
+        };
+
+        let key = peer.key;
+        if self.options.provision_from != Some(key) {
+            log::warn!("ignoring client config from {key}, not our coordinator");
+            return;
+        }
+
+        if !signed.verify(&key, &self.key) {
+            log::warn!("dropping client config from {key} with a bad signature");
+            return;
+        }
+
+        if self.provisioned.as_ref() == Some(&signed.config) {
+            return;
+        }
+
+        log::info!(
+            "provisioned by {key} with address {}",
+            signed.config.address
+        );
+        if let Some(path) = &self.options.provision_file
+            && let Err(err) = signed.config.save(path, &key)
+        {
+            log::error!("can't save client config to {}: {err}", path.display());
+            return;
+        }
+
+        self.provisioned = Some(signed.config.clone());
+    }
+
+    /// Queues targeted announcements to the online peers whose groups want
+    /// every change pushed.
+    fn push(&self, replies: &mut ReplyQueue) {
+        if self.metered {
+            return;
+        }
+
+        for key in self.options.groups.pushed() {
+            if self.blocked.contains(key) {
+                continue;
+            }
+
+            if let Some(nick) = self.nicks.get(key) {
+                replies.push(nick.clone());
+            }
+        }
+    }
+
+    /// When amplifying, queues cached announcements to a peer which just
+    /// joined the channel.
+    fn forward_to(&mut self, nick: &str, joined: &PeerUpdate) {
+        if self.options.amplify && !self.announcements.contains_key(&joined.key) {
+            self.forward_all(nick, joined);
+        }
+    }
+
+    /// Queues cached announcements of everybody else we relay for to `nick`.
+    fn forward_all(&mut self, nick: &str, to: &PeerUpdate) {
+        log::info!("syncing {} known peers to {nick}", self.announcements.len());
+
+        for peer in self.announcements.values() {
+            if peer.key == to.key || !self.options.groups.policy(&peer.key).relay {
+                continue;
+            }
+
+            let mut peer = peer.clone();
+            peer.ext.relayed = true;
+            self.forwards.push_back((nick.to_string(), peer));
+        }
+    }
+
+    /// Adds the candidates of the last discovery to `endpoints`, see
+    /// [`prepend_candidates`].
+    fn gather_candidates(&mut self, primary: &Mapping, endpoints: &mut Vec<SocketAddr>) {
+        self.gathered = self
+            .discover
+            .candidates()
+            .into_iter()
+            .filter(|addr| *addr != primary.public && Some(*addr) != self.public6)
+            .collect();
+
+        if !self.gathered.is_empty() {
+            log::info!("announcing candidates {:?}", self.gathered);
+        }
+        prepend_candidates(endpoints, &self.gathered);
+    }
+
+    /// Moves wireguard traffic to the next uplink whose STUN server answers
+    /// when no peer is up and the active uplink doesn't answer either, and
+    /// back to the first one once it recovers. Returns the mapping of the
+    /// uplink switched to.
+    async fn check_uplinks(&mut self) -> Option<Mapping> {
+        let count = self.options.uplinks.len();
+        if count < 2 || self.options.server.is_some() || self.options.observe {
+            return None;
+        }
+
+        let now = self.clock.now();
+        if self
+            .uplink_checked
+            .is_some_and(|at| now - at < UPLINK_CHECK_INTERVAL)
+        {
+            return None;
+        }
+        self.uplink_checked = Some(now);
+
+        // both probes at once, the loop waits for them
+        let active = self.active_uplink;
+        let (active_up, primary_up) = futures::join!(
+            async { !self.up.is_empty() || self.probe_uplink(active).await },
+            async { active != 0 && self.probe_uplink(0).await },
+        );
+
+        let order: Vec<usize> = if !active_up {
+            log::warn!(
+                "uplink {} is down, no peer is up and STUN doesn't answer",
+                self.options.uplinks[active].name
+            );
+            (0..count).filter(|&idx| idx != active).collect()
+        } else if primary_up {
+            vec![0]
+        } else {
+            return None;
+        };
+
+        for idx in order {
+            if let Some(mapping) = self.switch_uplink(idx).await {
+                return Some(mapping);
+            }
+        }
+
+        None
+    }
+
+    /// Follows whether the connection is metered, asking NetworkManager
+    /// about the active uplink unless the config tells.
+    async fn check_metered(&mut self) {
+        if self.options.observe {
+            return;
+        }
+
+        let metered = match self.options.metered {
+            Some(metered) => metered,
+            None => {
+                let now = self.clock.now();
+                if self
+                    .metered_checked
+                    .is_some_and(|at| now - at < METERED_CHECK_INTERVAL)
+                {
+                    return;
+                }
+                self.metered_checked = Some(now);
+
+                let device = self
+                    .options
+                    .uplinks
+                    .get(self.active_uplink)
+                    .map(|uplink| uplink.device.clone());
+
+                // nmcli blocks, keep it off the loop's thread
+                tokio::task::spawn_blocking(move || metered::detect(device.as_deref()))
+                    .await
+                    .unwrap_or(false)
+            }
+        };
+
+        if metered != self.metered {
+            match metered {
+                true => {
+                    log::info!("on a metered connection, periodic re-announcements and pushes stop")
+                }
+                false => log::info!("connection is not metered anymore"),
+            }
+            self.metered = metered;
+        }
+    }
+
+    /// Puts peers back on the endpoints their sessions had when the last run
+    /// stopped, so wireguard reconnects while discovery and signaling are
+    /// still coming up. Announcements take over once they arrive.
+    async fn reconnect(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        if snapshot.frozen {
+            return Ok(());
+        }
+
+        let endpoints: HashMap<_, _> = snapshot
+            .endpoints
+            .iter()
+            .filter(|(key, _)| {
+                self.peer_index.contains_key(key)
+                    && !self.revocations.contains(key)
+                    && !snapshot.blocked.contains(key)
+                    && !snapshot.pins.iter().any(|(pinned, _, _)| pinned == key)
+            })
+            .copied()
+            .collect();
+        if endpoints.is_empty() {
+            return Ok(());
+        }
+
+        log::info!(
+            "reconnecting {} peers at their last endpoints",
+            endpoints.len()
+        );
+        self.apply_endpoints(endpoints).await
+    }
+
+    /// Picks up where the last run stopped, see [`Snapshot`]. Announcements
+    /// too old to be trusted as relayed ones are dropped.
+    async fn restore(&mut self, snapshot: Snapshot, public: &SocketAddr) -> Result<(), Error> {
+        log::info!(
+            "resuming from the last state, {} peer announcements",
+            snapshot.announcements.len()
+        );
+
+        let unix_ms = self.clock.unix_ms();
+        for (key, endpoint, expires) in snapshot.pins {
+            if expires > unix_ms {
+                self.pin(key, endpoint, Duration::from_millis(expires - unix_ms))
+                    .await;
+            }
+        }
+        for key in snapshot.blocked {
+            self.block(key).await;
+        }
+        if snapshot.frozen {
+            self.freeze();
+        }
+
+        let mut endpoints = HashMap::new();
+        let mut replies = ReplyQueue::default();
+        for peer in snapshot.announcements {
+            self.handle(
+                Ok(PeerEvent::Relayed(peer)),
+                public,
+                &mut endpoints,
+                &mut replies,
+            )
+            .await;
+        }
+        self.apply_endpoints(endpoints).await
+    }
+
+    /// Writes a [`Snapshot`] when the state changed, at most every
+    /// [`SNAPSHOT_INTERVAL`] unless `now`.
+    fn save_snapshot(&mut self, now: bool) {
+        let Some(path) = &self.options.state_file else {
+            return;
+        };
+
+        let at = self.clock.now();
+        if !now
+            && self
+                .saved
+                .as_ref()
+                .is_some_and(|(_, saved)| at - *saved < SNAPSHOT_INTERVAL)
+        {
+            return;
+        }
+
+        let unix_ms = self.clock.unix_ms();
+        let mut snapshot = Snapshot {
+            announcements: self.announcements.values().cloned().collect(),
+            pins: self
+                .pins
+                .iter()
+                .map(|(key, (endpoint, expires))| {
+                    let ttl = expires.saturating_duration_since(at).as_millis() as u64;
+                    (*key, *endpoint, unix_ms + ttl)
+                })
+                .collect(),
+            blocked: self
+                .blocked
+                .iter()
+                .filter(|key| {
+                    !self.options.blocklist.contains(key) && !self.revocations.contains(key)
+                })
+                .copied()
+                .collect(),
+            frozen: self.frozen,
+            endpoints: self
+                .up
+                .iter()
+                .filter(|key| !self.helpers.contains_key(key))
+                .filter_map(|key| Some((*key, self.history.get(key)?.current()?)))
+                .filter(|(_, addr)| !addr.ip().is_loopback())
+                .collect(),
+            public: self.public,
+        };
+        snapshot.sort();
+
+        let data = snapshot.encode();
+        if self.saved.as_ref().is_some_and(|(saved, _)| *saved == data) {
+            return;
+        }
+
+        if let Err(err) = snapshot.save(path) {
+            log::warn!("can't save state to {}: {err}", path.display());
+        }
+        self.saved = Some((data, at));
+    }
+
+    /// Whether the system just resumed from suspend. NAT mappings and the
+    /// endpoints of peers are stale then, the next peer answering our
+    /// announcement is asked for everybody.
+    fn check_resume(&mut self) -> bool {
+        let Some(slept) = self.sleep.observe(self.clock.now(), self.clock.unix_ms()) else {
+            return false;
+        };
+
+        log::info!(
+            "resumed after sleeping for {}s, refreshing mapping and peers",
+            slept.as_secs()
+        );
+        self.synced = false;
+        self.reannounced = self.clock.now();
+        true
+    }
+
+    /// Whether peers went without a handshake for too long. Our channel
+    /// announcement then asks every peer for its current endpoint, the
+    /// stale ones included while they are still on signaling.
+    async fn check_handshakes(&mut self) -> bool {
+        if self.options.observe || self.metered {
+            return false;
+        }
+
+        let Some(monitor) = &mut self.stale else {
+            return false;
+        };
+
+        let mut handshakes = match self.wg.get_handshakes(&self.iface).await {
+            Ok(handshakes) => handshakes,
+            Err(err) => {
+                log::warn!("can't get handshakes: {}", Error::from(err));
+                return false;
+            }
+        };
+
+        // nothing to re-resolve for peers we never heard of or that are
+        // kept where they are
+        handshakes.retain(|key, _| {
+            self.announcements.contains_key(key)
+                && !self.pins.contains_key(key)
+                && !self.blocked.contains(key)
+        });
+
+        let stale = monitor.stale(&handshakes, self.clock.unix_ms());
+        for (key, age) in &stale {
+            log::info!(
+                "no handshake with {key} for {}s, asking for its endpoint",
+                age.as_secs()
+            );
+        }
+
+        !stale.is_empty()
+    }
+
+    /// Whether the periodic re-announcement is due, restarts its interval.
+    fn reannounce_due(&mut self) -> bool {
+        let now = self.clock.now();
+        match self.options.reannounce {
+            Some(interval) if !self.metered && now - self.reannounced >= interval => {
+                self.reannounced = now;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Asks STUN everything `probes` wants to know about our listen port in
+    /// one go: the port is freed for the STUN sockets once, moving
+    /// wireguard and its live tunnels to a random port meanwhile, and put
+    /// back whatever the probes come to. Failed probes are logged and left
+    /// out.
+    async fn probe_port(&mut self, probes: Probes) -> Probed {
+        let mut probed = Probed::default();
+        if self.options.server.is_some() || self.options.observe {
+            return probed;
+        }
+
+        // `0` lets the kernel pick
+        self.port_freed = true;
+        if let Err(err) = self.wg.set_listen_port(&self.iface, 0).await {
+            log::error!("can't free the listen port for stun: {}", Error::from(err));
+            self.restore_port().await;
+            return probed;
+        }
+
+        let port = self.listen_port;
+        if probes.mapping {
+            let res = match self.options.uplinks.get(self.active_uplink) {
+                Some(uplink) => self.discover.discover_via(port, &uplink.device).await,
+                None => self.discover.discover(port).await,
+            };
+
+            match res {
+                Ok(mapping) => probed.mapping = Some(mapping),
+                Err(err) => log::warn!("can't rediscover mapping: {}", Error::from(err)),
+            }
+        }
+
+        if let Some(primary) = probes.uplinks {
+            let uplinks: Vec<_> = self
+                .options
+                .uplinks
+                .iter()
+                .skip(1)
+                .filter(|uplink| uplink.advertise)
+                .collect();
+
+            if !uplinks.is_empty() {
+                probed.uplinks = discover_uplinks(&self.discover, port, &uplinks, &primary).await;
+            }
+        }
+
+        if probes.v6 {
+            match self.discover.discover_v6(port).await {
+                Some(Ok(mapping)) => {
+                    log::info!("ipv6 mapping {mapping}");
+                    probed.public6 = Some(mapping.public);
+                }
+                Some(Err(err)) => log::info!("no ipv6 mapping: {}", Error::from(err)),
+                None => {}
+            }
+        }
+
+        if probes.nat {
+            match self.discover.nat_type(port).await {
+                Some(Ok(nat_type)) => {
+                    log::info!("nat type {nat_type}");
+                    probed.nat_type = Some(nat_type);
+                }
+                Some(Err(err)) => log::info!("can't tell the nat type: {}", Error::from(err)),
+                None => {}
+            }
+        }
+
+        self.restore_port().await;
+        probed
+    }
+
+    /// Moves wireguard back to our listen port after [`probe_port`]
+    /// freed it. Failing that, housekeeping and the shutdown try again.
+    ///
+    /// [`probe_port`]: Self::probe_port
+    async fn restore_port(&mut self) {
+        if !self.port_freed {
+            return;
+        }
+
+        match self.wg.set_listen_port(&self.iface, self.listen_port).await {
+            Ok(()) => self.port_freed = false,
+            Err(err) => log::error!(
+                "can't move back to listen port {}: {}",
+                self.listen_port,
+                Error::from(err)
+            ),
+        }
+    }
+
+    async fn probe_uplink(&self, idx: usize) -> bool {
+        let device = &self.options.uplinks[idx].device;
+        let probe = self.discover.discover_via(0, device);
+
+        matches!(
+            tokio::time::timeout(UPLINK_PROBE_TIMEOUT, probe).await,
+            Ok(Ok(_))
+        )
+    }
+
+    /// Discovers the mapping of our listen port via uplink `idx` and routes
+    /// wireguard traffic through it, `None` if discovery or routing fails.
+    async fn switch_uplink(&mut self, idx: usize) -> Option<Mapping> {
+        let uplink = self.options.uplinks[idx].clone();
+
+        // the port is freed like by `probe_port`
+        self.port_freed = true;
+        let res = match self.wg.set_listen_port(&self.iface, 0).await {
+            Ok(()) => self
+                .discover
+                .discover_via(self.listen_port, &uplink.device)
+                .await
+                .map_err(Error::from),
+            Err(err) => Err(Error::from(err)),
+        };
+        self.restore_port().await;
+
+        let mapping = match res {
+            Ok(mapping) => mapping,
+            Err(err) => {
+                log::warn!("can't discover uplink {}: {err}", uplink.name);
+                return None;
+            }
+        };
+
+        if let Err(err) = uplink::release(&self.options.uplinks[self.active_uplink]) {
+            log::warn!("can't remove uplink rule: {err}");
+        }
+        if let Err(err) = uplink::select(&mut self.wg, &self.iface, &uplink).await {
+            log::error!("can't switch to uplink {}: {err}", uplink.name);
+            return None;
+        }
+
+        log::info!("switched to uplink {}, mapping {mapping}", uplink.name);
+        self.active_uplink = idx;
+        self.public = Some(mapping.public);
+
+        Some(mapping)
+    }
+
+    /// Mapping of a server mode node, whose public endpoint is configured.
+    async fn static_mapping(&mut self, public: SocketAddr) -> Result<(Mapping, u16), Error> {
+        let listen_port = match self.config.interface.listen_port {
+            Some(port) => port,
+            None => {
+                self.wg.set_listen_port(&self.iface, public.port()).await?;
+                public.port()
+            }
+        };
+
+        let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), listen_port);
+        Ok((Mapping { public, local }, listen_port))
+    }
+
+    fn observe_clock(&mut self, peer: &PeerUpdate) {
+        let Some(sent) = peer.ext.timestamp else {
+            return;
+        };
+        let offset = self.skew.observe(peer.key, sent, self.clock.unix_ms());
+
+        if offset.unsigned_abs() > MAX_CLOCK_SKEW.as_millis() as u64 {
+            log::warn!("peer {} clock is off by {}s", peer.key, offset / 1000);
+        }
+    }
+
+    /// Warns about a peer speaking another protocol version, once until the
+    /// version it announces changes.
+    fn observe_metadata(&self, peer: &PeerUpdate) {
+        let Some(meta) = &peer.ext.meta else {
+            return;
+        };
+
+        let known = self
+            .announcements
+            .get(&peer.key)
+            .and_then(|known| known.ext.meta.as_ref());
+        if meta.protocol == PROTOCOL_VERSION || known.is_some_and(|m| m.protocol == meta.protocol) {
+            return;
+        }
+
+        let upgrade = match meta.protocol < PROTOCOL_VERSION {
+            true => "upgrade it",
+            false => "upgrade this node",
+        };
+        log::warn!(
+            "peer {} ({}) runs wg-disco {} with protocol {}, ours is {PROTOCOL_VERSION}, {upgrade}",
+            peer.key,
+            meta.hostname,
+            meta.version,
+            meta.protocol
+        );
+    }
+
+    /// Protocol versions of peers which are up or announced themselves
+    /// lately, `0` for those predating versioning.
+    fn live_protocols(&self) -> impl Iterator<Item = (Key, u16)> + '_ {
+        let now = self.clock.now();
+
+        self.announcements.iter().filter_map(move |(key, peer)| {
+            let announced = self
+                .announced_at
+                .get(key)
+                .is_some_and(|at| now - *at <= PEER_DOWN_AFTER);
+            let protocol = peer.ext.meta.as_ref().map_or(0, |meta| meta.protocol);
+
+            (self.up.contains(key) || announced).then_some((*key, protocol))
+        })
+    }
+
+    /// Features we have turned on that some live peers are too old for.
+    fn unsupported_features(&self) -> Vec<Unsupported> {
+        let mut enabled = Vec::new();
+        if self.options.delta {
+            enabled.push(Feature::Delta);
+        }
+        if self
+            .options
+            .uplinks
+            .iter()
+            .skip(1)
+            .any(|uplink| uplink.advertise)
+        {
+            enabled.push(Feature::Endpoints);
+        }
+
+        enabled
+            .into_iter()
+            .filter_map(|feature| {
+                let mut peers: Vec<Key> = self
+                    .live_protocols()
+                    .filter(|(_, protocol)| *protocol < feature.protocol())
+                    .map(|(key, _)| key)
+                    .collect();
+                peers.sort_by_key(|key| *key.as_bytes());
+
+                (!peers.is_empty()).then_some(Unsupported { feature, peers })
+            })
+            .collect()
+    }
+
+    /// Warns when a peer too old for an enabled feature shows up, and
+    /// tells once everybody caught up.
+    fn check_upgrades(&mut self) {
+        let unsupported = self.unsupported_features();
+        if unsupported == self.unsupported {
+            return;
+        }
+
+        for entry in &unsupported {
+            if self.unsupported.contains(entry) {
+                continue;
+            }
+
+            let peers: Vec<String> = entry.peers.iter().map(Key::to_string).collect();
+            log::warn!(
+                "{} is enabled, but {} peers speak a protocol older than {} and can't follow it, \
+                 upgrade them or turn it off: {}",
+                entry.feature.name(),
+                peers.len(),
+                entry.feature.protocol(),
+                peers.join(", ")
+            );
+        }
+
+        for entry in &self.unsupported {
+            if !unsupported.iter().any(|e| e.feature == entry.feature) {
+                log::info!("all live peers understand {} now", entry.feature.name());
+            }
+        }
+
+        self.unsupported = unsupported;
+    }
+
+    /// Adds routes advertised by the peer (minus `ExcludeRoutes`) to its
+    /// AllowedIPs and the routing table, unless its groups don't allow it.
+    async fn accept_routes(&mut self, peer: &PeerUpdate) {
+        if !self.peer_index.contains_key(&peer.key) {
+            return;
+        }
+
+        let advertised = match self.options.groups.policy(&peer.key).accept_routes {
+            true => peer.advertise_routes.clone(),
+            false => Vec::new(),
+        };
+
+        match peer.ext.bandwidth {
+            Some(bandwidth) => self.bandwidth.insert(peer.key, bandwidth),
+            None => self.bandwidth.remove(&peer.key),
+        };
+        self.advertised_by.insert(peer.key, advertised);
+        self.apply_routes(peer.key).await;
+
+        // a better exit node takes the default route over
+        if self.exit_nodes.contains(&peer.key) {
+            let others: Vec<Key> = self
+                .exit_nodes
+                .iter()
+                .filter(|key| **key != peer.key)
+                .copied()
+                .collect();
+
+            for key in others {
+                self.apply_routes(key).await;
+            }
+        }
+    }
+
+    /// One exit node at a time, the one announcing the highest bandwidth.
+    /// On a tie the current one stays.
+    fn is_best_exit(&self, key: &Key) -> bool {
+        let bandwidth = |key| self.bandwidth.get(key).copied().unwrap_or_default();
+
+        self.exit_nodes
+            .iter()
+            .filter(|other| *other != key)
+            .all(|other| bandwidth(other) < bandwidth(key))
+    }
+
+    async fn apply_routes(&mut self, key: Key) {
+        let Some(&idx) = self.peer_index.get(&key) else {
+            return;
+        };
+
+        let mut advertised = self.advertised_by.get(&key).cloned().unwrap_or_default();
+        if advertised.iter().any(|c| c.mask == 0) && !self.is_best_exit(&key) {
+            if self.exit_nodes.contains(&key) {
+                log::info!("exit node {key} replaced by one with more bandwidth");
+            }
+            advertised.retain(|c| c.mask != 0);
+        }
+        let advertised = advertised.as_slice();
+
+        let excludes = self
+            .config
+            .interface
+            .exclude_routes
+            .as_deref()
+            .unwrap_or_default();
+
+        let accepted = route::exclude(advertised, excludes);
+        let installed = self.routes.get(&key).cloned().unwrap_or_default();
+
+        if accepted == installed {
+            return;
+        }
+
+        if self.frozen {
+            log::info!("frozen, not changing routes via peer {key}");
+            return;
+        }
+
+        for cidr in advertised {
+            if excludes.iter().any(|ex| ex.overlaps(cidr)) {
+                log::info!("peer {} route {cidr} intersects ExcludeRoutes", key);
+            }
+        }
+
+        let mut allowed_ips = self.config.peers[idx]
+            .allowed_ips
+            .clone()
+            .unwrap_or_default();
+        allowed_ips.extend(&accepted);
+
+        if let Err(err) = self
+            .wg
+            .set_allowed_ips(&self.iface, key, &allowed_ips)
+            .await
+        {
+            log::error!("can't set allowed ips of {}: {}", key, Error::from(err));
+            return;
+        }
+
+        if !self.options.observe {
+            self.install_routes(key, &installed, &accepted);
+        }
+
+        // a default route stays an exit route even when split by ExcludeRoutes
+        if !accepted.is_empty() && advertised.iter().any(|c| c.mask == 0) {
+            self.exit_nodes.insert(key);
+        } else {
+            self.exit_nodes.remove(&key);
+        }
+
+        self.routes.insert(key, accepted);
+        self.update_kill_switch();
+    }
+
+    /// Moves the routing table from `installed` to `accepted` routes via
+    /// peer `key`.
+    fn install_routes(&self, key: Key, installed: &[Cidr], accepted: &[Cidr]) {
+        if let Some(export) = &self.options.route_export {
+            let previous: Vec<Cidr> = self.routes.values().flatten().copied().collect();
+            let routes: Vec<Cidr> = self
+                .routes
+                .iter()
+                .filter(|(via, _)| **via != key)
+                .flat_map(|(_, routes)| routes)
+                .chain(accepted)
+                .copied()
+                .collect();
+
+            match export.export(&self.iface, &previous, &routes) {
+                Ok(()) => log::info!("exported {} routes to {:?}", routes.len(), export.daemon),
+                Err(err) => log::warn!("can't export routes to {:?}: {err}", export.daemon),
+            }
+            return;
+        }
+
+        for cidr in installed.iter().filter(|c| !accepted.contains(c)) {
+            if let Err(err) = route::remove(&self.iface, cidr) {
+                log::warn!("can't remove route {cidr}: {err}");
+            }
+        }
+
+        let existing = match route::query::routes() {
+            Ok(routes) => routes,
+            Err(err) => {
+                log::debug!("can't read routing table: {err}");
+                Vec::new()
+            }
+        };
+        let oif = route::query::if_index(&self.iface).unwrap_or_default();
+
+        for cidr in accepted.iter().filter(|c| !installed.contains(c)) {
+            let conflicts = route::query::conflicts(&existing, cidr, oif, route::query::MAIN_TABLE);
+            if let Some(other) = conflicts.first() {
+                log::warn!(
+                    "not installing route {cidr} via {key}, it is already routed via {}",
+                    other.dev()
+                );
+                continue;
+            }
+
+            match route::replace(&self.iface, cidr) {
+                Ok(()) => log::info!("installed route {cidr} via {key}"),
+                Err(err) => log::warn!("can't install route {cidr}: {err}"),
+            }
+        }
+    }
+
+    /// `AdvertiseRoutes` worth announcing: prefixes with host bits set are
+    /// cut to the network, ones without a local route elsewhere than the
+    /// tunnel are left out, peers would send traffic for them into nowhere.
+    fn validate_advertised(&self) -> Vec<Cidr> {
+        let Some(configured) = &self.config.interface.advertise_routes else {
+            return Vec::new();
+        };
+
+        let existing = match route::query::routes() {
+            Ok(routes) => Some(routes),
+            Err(err) => {
+                log::warn!("can't read routing table to check AdvertiseRoutes: {err}");
+                None
+            }
+        };
+        let oif = route::query::if_index(&self.iface);
+
+        let mut advertised = Vec::new();
+        for cidr in configured {
+            let network = cidr.network();
+            if network != *cidr {
+                log::warn!("AdvertiseRoutes {cidr} has host bits set, advertising {network}");
+            }
+
+            if let Some(existing) = &existing
+                && !route::query::reachable(existing, &network, oif)
+            {
+                log::warn!("not advertising {network}, there is no local route to it");
+                continue;
+            }
+
+            if !advertised.contains(&network) {
+                advertised.push(network);
+            }
+        }
+
+        if !advertised.is_empty() {
+            let list: Vec<String> = advertised.iter().map(Cidr::to_string).collect();
+            log::info!("advertising routes {}", list.join(", "));
+        }
+
+        advertised
+    }
+
+    /// Advertised routes whose health check passes or which have none.
+    fn healthy_routes(&self) -> Vec<Cidr> {
+        self.advertised
+            .iter()
+            .filter(|cidr| self.health.is_healthy(cidr))
+            .copied()
+            .collect()
+    }
+
+    /// Runs the `[[route_check]]`s when due, true when a route was
+    /// withdrawn or restored.
+    async fn check_routes(&mut self) -> bool {
+        if self.options.route_checks.is_empty() || self.options.observe {
+            return false;
+        }
+
+        let now = self.clock.now();
+        if self
+            .routes_checked
+            .is_some_and(|at| now - at < ROUTE_CHECK_INTERVAL)
+        {
+            return false;
+        }
+        self.routes_checked = Some(now);
+
+        // pings block, keep them off the loop's thread
+        let checks = self.options.route_checks.clone();
+        let results = tokio::task::spawn_blocking(move || {
+            checks
+                .iter()
+                .map(|check| (check.route.network(), check.run()))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_default();
+
+        let mut changed = false;
+        for (route, ok) in results {
+            if !self.advertised.contains(&route) {
+                log::debug!("route check of {route}, which isn't advertised");
+                continue;
+            }
+
+            match self.health.record(route, ok) {
+                Some(true) => log::info!("route check of {route} passes, advertising it"),
+                Some(false) => log::warn!("route check of {route} fails, withdrawing it"),
+                None => continue,
+            }
+            changed = true;
+        }
+
+        changed
+    }
+
+    /// Reinstalls accepted routes which disappeared from the routing table,
+    /// e.g. after the interface was bounced or someone flushed it.
+    fn verify_routes(&self) {
+        if self.options.observe
+            || self.options.route_export.is_some()
+            || self.routes.values().all(Vec::is_empty)
+        {
+            return;
+        }
+
+        let Some(oif) = route::query::if_index(&self.iface) else {
+            return;
+        };
+
+        let existing = match route::query::routes() {
+            Ok(routes) => routes,
+            Err(err) => {
+                log::debug!("can't read routing table: {err}");
+                return;
+            }
+        };
+
+        let present = |cidr: &Cidr| {
+            existing
+                .iter()
+                .any(|route| route.oif == Some(oif) && route.dst.network() == cidr.network())
+        };
+
+        for cidr in self.routes.values().flatten() {
+            // conflicting ones were never installed, warned about already
+            let table = route::query::MAIN_TABLE;
+            if present(cidr) || !route::query::conflicts(&existing, cidr, oif, table).is_empty() {
+                continue;
+            }
+
+            log::warn!("route {cidr} is gone from the routing table, reinstalling");
+            if let Err(err) = route::replace(&self.iface, cidr) {
+                log::warn!("can't install route {cidr}: {err}");
+            }
+        }
+    }
+
+    /// Engages the kill-switch while some peer is our exit node, i.e. its
+    /// default route is accepted.
+    fn update_kill_switch(&mut self) {
+        if self.config.interface.kill_switch != Some(true) || self.options.observe {
+            return;
+        }
+
+        let res = if !self.exit_nodes.is_empty() {
+            self.kill_switch
+                .engage(self.listen_port, &self.options.servers)
+        } else {
+            self.kill_switch.disengage()
+        };
+
+        if let Err(err) = res {
+            log::error!("can't update kill-switch: {err}");
+        }
+    }
+
+    fn teardown(&mut self) {
+        self.helpers.clear();
+
+        if self.options.observe {
+            return;
+        }
+
+        if let Some(uplink) = self.options.uplinks.get(self.active_uplink)
+            && let Err(err) = uplink::release(uplink)
+        {
+            log::error!("can't remove uplink {} rule: {err}", uplink.name);
+        }
+
+        if let Err(err) = self.kill_switch.disengage() {
+            log::error!("can't remove kill-switch: {err}");
+        }
+
+        let routes: Vec<Cidr> = self.routes.drain().flat_map(|(_, routes)| routes).collect();
+        if let Some(export) = &self.options.route_export {
+            if let Err(err) = export.export(&self.iface, &routes, &[]) {
+                log::warn!("can't withdraw routes from {:?}: {err}", export.daemon);
+            }
+            return;
+        }
+
+        for cidr in routes {
+            if let Err(err) = route::remove(&self.iface, &cidr) {
+                log::warn!("can't remove route {cidr}: {err}");
+            }
+        }
+    }
+
+    /// Keeps NAT mappings of peers asking for it open, unless the config
+    /// sets PersistentKeepalive explicitly.
+    async fn exchange_keepalive(&mut self, peer: &PeerUpdate) {
+        let Some(&idx) = self.peer_index.get(&peer.key) else {
+            return;
+        };
+
+        if self.frozen {
+            return;
+        }
+
+        if self.config.peers[idx].persistent_keepalive.is_some()
+            || peer.ext.nat == self.keepalives.contains(&peer.key)
+        {
+            return;
+        }
+
+        let interval = if peer.ext.nat { NAT_KEEPALIVE } else { 0 };
+
+        match self
+            .wg
+            .set_persistent_keepalive(&self.iface, peer.key, interval)
+            .await
+        {
+            Ok(()) if peer.ext.nat => {
+                log::info!("peer {} is behind NAT, enabling keepalive", peer.key);
+                self.keepalives.insert(peer.key);
+            }
+            Ok(()) => {
+                log::info!(
+                    "peer {} is not behind NAT anymore, disabling keepalive",
+                    peer.key
+                );
+                self.keepalives.remove(&peer.key);
+            }
+            Err(err) => log::error!("can't set keepalive of {}: {}", peer.key, Error::from(err)),
+        }
+    }
+
+    fn handle_wg_event(&mut self, event: WgEvent) {
+        match event {
+            WgEvent::Endpoint {
+                key,
+                endpoint: Some(Endpoint::Ip(addr)),
+            } => self.history.entry(key).or_default().observe(addr),
+            WgEvent::Handshake { key, .. } => {
+                self.handshakes.insert(key, self.clock.now());
+
+                if let Some((addr, _)) = self.probes.remove(&key) {
+                    log::info!("probe of {addr} of {key} answered");
+                    self.probe_reports.push((key, addr, true));
+                }
+
+                if let Some((kind, addr)) = self.punch.succeeded(&key) {
+                    log::info!("punched through to {key} via its {kind} address {addr}");
+                    self.hints.set(key, kind);
+                    self.punched.push((key, addr));
+                } else if self.helpers.contains_key(&key) {
+                    self.hints.set(key, Candidate::Relay);
+                }
+
+                if self.up.insert(key) {
+                    self.emit(Event::PeerUp(key));
+                }
+            }
+            _ => {}
+        }
+
+        match event {
+            WgEvent::Handshake { key, at } => peer_debug!(key, "handshake with {key} at {at}"),
+            WgEvent::Transfer { key, rx, tx } => log::trace!("transfer {key} rx {rx} tx {tx}"),
+            WgEvent::Endpoint { key, endpoint } => match endpoint {
+                Some(endpoint) => log::info!("peer {key} roamed to {endpoint}"),
+                None => log::info!("peer {key} lost its endpoint"),
+            },
+            WgEvent::Removed { key } => log::info!("peer {key} removed from interface"),
+        }
+    }
+
+    /// [`apply_endpoints`](Self::apply_endpoints) from the event loop, a
+    /// failure is logged and the endpoints come again with the next
+    /// announcements.
+    async fn try_apply_endpoints(&mut self, endpoints: HashMap<Key, SocketAddr>) {
+        if let Err(err) = self.apply_endpoints(endpoints).await {
+            log::error!("can't apply endpoints: {err}");
+        }
+    }
+
+    async fn apply_endpoints(&mut self, endpoints: HashMap<Key, SocketAddr>) -> Result<(), Error> {
+        if endpoints.is_empty() {
+            return Ok(());
+        }
+
+        if self.frozen {
+            for (key, addr) in endpoints {
+                log::info!("frozen, not applying endpoint {addr} of peer {key}");
+            }
+
+            return Ok(());
+        }
+
+        let now = self.clock.now();
+        let endpoints: Vec<_> = endpoints
+            .into_iter()
+            .filter(|(key, _)| !self.helpers.contains_key(key))
+            .filter(|(key, addr)| match self.pins.get(key) {
+                Some((pinned, _)) => {
+                    peer_debug!(*key, "peer {key} is pinned to {pinned}, ignoring {addr}");
+                    false
+                }
+                None => true,
+            })
+            .filter(
+                |(key, addr)| match self.history.entry(*key).or_default().record(*addr, now) {
+                    Verdict::Apply => true,
+                    Verdict::Unchanged => false,
+                    Verdict::Damped(until) => {
+                        log::warn!(
+                            "peer {key} endpoint is flapping, holding {addr} for {:?}",
+                            until - now
+                        );
+                        false
+                    }
+                    Verdict::Suppressed(_) => {
+                        peer_debug!(*key, "peer {key} endpoint {addr} held down");
+                        false
+                    }
+                },
+            )
+            .map(|(key, addr)| (key, Endpoint::from(addr)))
+            .collect();
+
+        self.set_endpoints(&endpoints).await?;
+        self.applied_at
+            .extend(endpoints.iter().map(|(key, _)| (*key, now)));
+
+        Ok(())
+    }
+
+    /// Address of the peer to try first. Peers without a session get the
+    /// others tried by [`PunchScheduler`] until a handshake happens.
+    fn first_candidate(&mut self, peer: &PeerUpdate, public: &SocketAddr) -> SocketAddr {
+        // dual-stack peers talk directly over IPv6 unless they share a LAN
+        let endpoint6 = peer.ext.endpoint6.filter(|_| self.public6.is_some());
+        let preferred = match (peer.endpoint_for(public), endpoint6) {
+            (preferred, Some(endpoint6)) if preferred == peer.endpoint => endpoint6,
+            (preferred, _) => preferred,
+        };
+
+        if self.up.contains(&peer.key) {
+            self.punch.cancel(&peer.key);
+            return preferred;
+        }
+
+        let mut candidates = vec![(Candidate::public(&peer.endpoint), peer.endpoint)];
+        if let Some(endpoint6) = endpoint6 {
+            candidates.insert(0, (Candidate::V6, endpoint6));
+        }
+        candidates.extend(
+            peer.ext
+                .endpoints
+                .iter()
+                .map(|addr| (Candidate::public(addr), *addr)),
+        );
+        if let Some(local) = peer.ext.local_endpoint {
+            match local == preferred {
+                true => candidates.insert(0, (Candidate::Lan, local)),
+                false => candidates.push((Candidate::Lan, local)),
+            }
+        }
+
+        self.capture(peer.key, &candidates);
+
+        // whatever worked before a restart goes first
+        let hint = self.hints.get(&peer.key);
+        peer_debug!(
+            peer.key,
+            "punch candidates of {}: {candidates:?}, hint {hint:?}",
+            peer.key
+        );
+        let Some(addr) = self
+            .punch
+            .start(peer.key, candidates, hint, self.clock.now())
+        else {
+            return preferred;
+        };
+
+        self.coordinate(peer);
+        addr
+    }
+
+    /// Queues a rendezvous with a peer we punch, when both of us are behind
+    /// NAT and punching can work. Only the one of us with the lower key
+    /// sets it, so there is one at a time.
+    fn coordinate(&mut self, peer: &PeerUpdate) {
+        let key = peer.key;
+        let punchable = match self.nat_type.zip(peer.ext.nat_type) {
+            Some((ours, theirs)) => ours.punchable(theirs),
+            None => true,
+        };
+
+        if self.frozen
+            || self.options.observe
+            || self.options.server.is_some()
+            || !peer.ext.nat
+            || !punchable
+            || self.key.as_ref() > key.as_ref()
+            || !self.nicks.contains_key(&key)
+            || self.rendezvous.contains(&key)
+        {
+            return;
+        }
+
+        self.rendezvous.push(key);
+    }
+
+    /// Sets the rendezvous the peer asks for, converted to our clock.
+    fn observe_rendezvous(&mut self, peer: &PeerUpdate) {
+        let Some(at) = peer.ext.punch_at else {
+            return;
+        };
+
+        if self.options.observe || self.punch.progress(&peer.key).is_none() {
+            return;
+        }
+
+        let offset = self.skew.offset(&peer.key).unwrap_or_default();
+        let lead = at as i64 - offset - self.clock.unix_ms() as i64;
+        if lead.unsigned_abs() > MAX_PUNCH_LEAD.as_millis() as u64 {
+            log::warn!(
+                "ignoring rendezvous with {} {lead}ms from now, clocks are too far off",
+                peer.key
+            );
+            return;
+        }
+
+        let at = self.clock.now() + Duration::from_millis(lead.max(0) as u64);
+        if self.punch.meet(peer.key, at) {
+            peer_debug!(peer.key, "punching {} together in {lead}ms", peer.key);
+        }
+    }
+
+    /// Punches the current candidate of peers at their rendezvous: points
+    /// wireguard at it, which handshakes right away, and opens our NAT's
+    /// mapping with probes meanwhile.
+    async fn punch_together(&mut self) -> Result<(), Error> {
+        let mut endpoints = Vec::new();
+
+        for (key, addr) in self.punch.rendezvous_due(self.clock.now()) {
+            if self.up.contains(&key)
+                || self.pins.contains_key(&key)
+                || self.helpers.contains_key(&key)
+            {
+                continue;
+            }
+
+            log::info!("punching {addr} of {key} together with it");
+            endpoints.push((key, Endpoint::from(addr)));
+
+            let port = self.listen_port;
+            tokio::task::spawn_blocking(move || {
+                if let Err(err) = punch::probe(port, addr) {
+                    log::debug!("can't probe {addr} of {key}: {err}");
+                }
+            });
+        }
+
+        if !endpoints.is_empty() {
+            self.set_endpoints(&endpoints).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Captures handshake packets exchanged with the `--capture`d peer in the
+    /// background and logs a summary with the likely cause of a failure.
+    fn capture(&mut self, key: Key, candidates: &[(Candidate, SocketAddr)]) {
+        let now = self.clock.now();
+        if self.options.capture != Some(key) || self.capturing.is_some_and(|until| now < until) {
+            return;
+        }
+        self.capturing = Some(now + CAPTURE_DURATION);
+
+        let port = self.listen_port;
+        let mut remotes: Vec<IpAddr> = candidates.iter().map(|(_, addr)| addr.ip()).collect();
+        remotes.dedup();
+
+        log::info!("capturing handshakes with {key} for {CAPTURE_DURATION:?}");
+        tokio::task::spawn_blocking(move || {
+            match capture::capture(port, &remotes, CAPTURE_DURATION) {
+                Ok(summary) => {
+                    log::info!("capture of {key}: {summary}");
+                    for finding in summary.diagnosis() {
+                        log::info!("capture of {key}: {finding}");
+                    }
+                }
+                Err(err) => log::warn!("can't capture handshakes with {key}: {err}"),
+            }
+        });
+    }
+
+    /// The peer tells which of our addresses it punched through to.
+    fn observe_punch(&mut self, peer: &PeerUpdate) {
+        let Some(addr) = peer.ext.punched else {
+            return;
+        };
+
+        let kind = if Some(addr) == self.local {
+            Candidate::Lan
+        } else if Some(addr) == self.public {
+            Candidate::public(&addr)
+        } else {
+            return;
+        };
+
+        // the same kind of its addresses is the one to try on reconnects
+        log::info!("peer {} reached us via our {kind} address", peer.key);
+        self.hints.set(peer.key, kind);
+    }
+
+    /// Asks the peer to probe our public endpoint, the answer arrives as an
+    /// event.
+    async fn request_probe(&mut self, key: Key, update: &PeerUpdate) -> Response {
+        let Some(nick) = self.nicks.get(&key).cloned() else {
+            return Response::Error(format!("peer {key} hasn't announced itself yet"));
+        };
+
+        log::info!("asking {key} to probe our endpoint {}", update.endpoint);
+        let mut request = update.clone();
+        request.ext.probe = true;
+
+        match self.announce(&request, Some(&nick)).await {
+            Ok(()) => Response::Ok,
+            Err(err) => Response::Error(err.to_string()),
+        }
+    }
+
+    /// Starts the probe the peer asks for and takes note of the results of
+    /// ours. A probe points wireguard at the peer's endpoint and waits for
+    /// a handshake.
+    async fn observe_probe(&mut self, peer: &PeerUpdate, endpoints: &mut HashMap<Key, SocketAddr>) {
+        if let Some((addr, answered)) = peer.ext.probed {
+            if Some(addr) != self.public {
+                peer_debug!(
+                    peer.key,
+                    "peer {} probed {addr}, which isn't our endpoint",
+                    peer.key
+                );
+            } else if answered {
+                log::info!("peer {} reached our endpoint {addr}", peer.key);
+                self.emit(Event::ProbeAnswered(peer.key));
+            } else {
+                log::warn!(
+                    "peer {} got no answer from our endpoint {addr}, our NAT doesn't let peers in",
+                    peer.key
+                );
+                self.emit(Event::ProbeSilent(peer.key));
+            }
+        }
+
+        if !peer.ext.probe || self.options.observe {
+            return;
+        }
+
+        let (key, target) = (peer.key, peer.endpoint);
+        if self.pins.contains_key(&key) || self.helpers.contains_key(&key) {
+            log::info!("not probing {target} of {key}, its endpoint is pinned or relayed");
+            return;
+        }
+
+        // a running session doesn't handshake again, but proves the endpoint
+        // works when it runs over it
+        if self.up.contains(&key) {
+            let current = self
+                .wg
+                .get_endpoints(&self.iface)
+                .await
+                .ok()
+                .and_then(|endpoints| endpoints.get(&key).copied().flatten());
+
+            match current == Some(target) {
+                true => self.probe_reports.push((key, target, true)),
+                false => {
+                    log::info!("not probing {target} of {key}, the session runs over {current:?}")
+                }
+            }
+            return;
+        }
+
+        log::info!("probing {target} of {key}");
+        if let Err(err) = self.set_endpoints(&[(key, Endpoint::from(target))]).await {
+            log::warn!("can't probe {target} of {key}: {}", Error::from(err));
+            return;
+        }
+
+        // other candidates would answer for it
+        self.punch.cancel(&key);
+        endpoints.insert(key, target);
+        self.probes
+            .insert(key, (target, self.clock.now() + PROBE_TIMEOUT));
+    }
+
+    /// Reports probes without a handshake in time as unanswered.
+    fn expire_probes(&mut self) {
+        let now = self.clock.now();
+
+        for (key, (addr, _)) in self.probes.extract_if(|_, (_, deadline)| *deadline <= now) {
+            log::info!("probe of {addr} of {key} got no answer");
+            self.probe_reports.push((key, addr, false));
+        }
+    }
+
+    /// Moves peers whose punch attempt timed out to their next candidate.
+    async fn retry_punches(&mut self) -> Result<(), Error> {
+        let mut endpoints = Vec::new();
+
+        for (key, addr) in self.punch.due(self.clock.now()) {
+            // something else took over the endpoint meanwhile
+            if self.up.contains(&key)
+                || self.pins.contains_key(&key)
+                || self.helpers.contains_key(&key)
+            {
+                self.punch.cancel(&key);
+                continue;
+            }
+
+            log::info!("no handshake with {key} yet, trying {addr}");
+            endpoints.push((key, Endpoint::from(addr)));
+        }
+
+        if !endpoints.is_empty() {
+            self.set_endpoints(&endpoints).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets endpoints and makes wireguard handshake with peers which have no
+    /// session right away, so punching happens while the peer's NAT mapping
+    /// towards us is still open.
+    async fn set_endpoints(&mut self, endpoints: &[(Key, Endpoint)]) -> Result<(), W::Error> {
+        if self.frozen {
+            return Ok(());
+        }
+
+        self.wg.set_peer_endpoints(&self.iface, endpoints).await?;
+
+        for (key, _) in endpoints {
+            if self.up.contains(key) {
+                continue;
+            }
+
+            let keepalive = self.keepalive_of(key);
+            if let Err(err) = self
+                .wg
+                .trigger_handshake(&self.iface, *key, keepalive)
+                .await
+            {
+                log::warn!("can't trigger handshake with {key}: {}", Error::from(err));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether punching with the peer can work as far as the NAT types we
+    /// know of tell.
+    fn punchable(&self, key: &Key) -> bool {
+        let theirs = self
+            .announcements
+            .get(key)
+            .and_then(|peer| peer.ext.nat_type);
+        match self.nat_type.zip(theirs) {
+            Some((ours, theirs)) => ours.punchable(theirs),
+            None => true,
+        }
+    }
+
+    /// Keepalive interval the peer is supposed to have.
+    fn keepalive_of(&self, key: &Key) -> u16 {
+        let configured = self
+            .peer_index
+            .get(key)
+            .and_then(|&idx| self.config.peers[idx].persistent_keepalive);
+
+        match configured {
+            Some(interval) => interval.min(u16::MAX as u32) as u16,
+            None if self.keepalives.contains(key) => NAT_KEEPALIVE,
+            None => 0,
+        }
+    }
+
+    /// Applies endpoints held down by flap damping once the hold-down expired.
+    async fn release_damped(&mut self) -> Result<(), Error> {
+        let now = self.clock.now();
+        let endpoints: Vec<_> = self
+            .history
+            .iter_mut()
+            .filter_map(|(key, history)| Some((*key, Endpoint::from(history.release(now)?))))
+            .filter(|(key, _)| !self.pins.contains_key(key))
+            .collect();
+
+        for (key, endpoint) in &endpoints {
+            log::info!("peer {key} hold-down expired, applying {endpoint}");
+        }
+
+        self.set_endpoints(&endpoints).await?;
+        self.applied_at
+            .extend(endpoints.iter().map(|(key, _)| (*key, now)));
+
+        Ok(())
+    }
+
+    /// Starts a transport helper for peers which never completed a
+    /// handshake over their direct endpoint, and points wireguard at it.
+    async fn fallback_transports(&mut self) -> Result<(), Error> {
+        if (self.options.transports.is_empty() && self.relay.is_none()) || self.options.observe {
+            return Ok(());
+        }
+
+        self.helpers.retain(|key, helper| {
+            let alive = helper.is_alive();
+            if !alive {
+                log::warn!("{} helper of peer {key} exited", helper.name());
+            }
+            alive
+        });
+
+        let now = self.clock.now();
+        let blocked: Vec<Key> = self
+            .applied_at
+            .iter()
+            .filter(|&(key, at)| {
+                // relayed last time or the NATs can't be punched through,
+                // don't wait for direct UDP to fail
+                (now - *at > DIRECT_TIMEOUT
+                    || self.hints.get(key) == Some(Candidate::Relay)
+                    || !self.punchable(key))
+                    && !self.helpers.contains_key(key)
+                    && self.handshakes.get(key).is_none_or(|h| h < at)
+            })
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in blocked {
+            self.applied_at.remove(&key);
+
+            let Some(peer) = self.announcements.get(&key) else {
+                continue;
+            };
+
+            let relay = self.relay.as_ref().and_then(|relay| {
+                let (_, home) = peer
+                    .ext
+                    .transports
+                    .iter()
+                    .find(|(name, _)| name == relay::TRANSPORT)?;
+                Some((relay, home.clone()))
+            });
+
+            let (name, remote, res) =
+                match transport::pick(&self.options.transports, &peer.ext.transports) {
+                    Some((config, remote)) => (
+                        config.name.clone(),
+                        remote.to_string(),
+                        Helper::spawn(config, remote),
+                    ),
+                    None => match relay {
+                        Some((relay, home)) => {
+                            let res = relay.open(key, &home).map(Helper::relayed);
+                            (relay::TRANSPORT.to_string(), home, res)
+                        }
+                        None => continue,
+                    },
+                };
+
+            match res {
+                Ok(helper) => {
+                    log::info!("no handshake with {key}, tunneling via {name} {remote}");
+                    self.start_helper(key, helper).await?;
+                }
+                Err(err) => log::warn!("can't start {name} helper: {err}"),
+            }
+        }
+
+        self.relay_back().await
+    }
+
+    /// Relays back to peers relaying to us, their packets are dropped
+    /// until we do.
+    async fn relay_back(&mut self) -> Result<(), Error> {
+        let Some(relay) = self.relay.clone() else {
+            return Ok(());
+        };
+
+        for (key, server) in relay.knocking() {
+            if self.helpers.contains_key(&key)
+                || self.pins.contains_key(&key)
+                || self.blocked.contains(&key)
+                || !self.peer_index.contains_key(&key)
+            {
+                continue;
+            }
+
+            match relay.open(key, &server) {
+                Ok(link) => {
+                    log::info!("peer {key} relays to us via {server}, relaying back");
+                    self.start_helper(key, Helper::relayed(link)).await?;
+                }
+                Err(err) => log::warn!("can't relay to {key}: {err}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Points wireguard at the helper, which takes over from punching.
+    async fn start_helper(&mut self, key: Key, helper: Helper) -> Result<(), Error> {
+        self.punch.cancel(&key);
+        self.set_endpoints(&[(key, Endpoint::from(helper.local()))])
+            .await?;
+        self.helpers.insert(key, helper);
+
+        Ok(())
+    }
+}
+
+/// Discovers the NAT mapping making sure it belongs to the port wireguard
+/// actually listens on, returns the mapping and the listen port.
+pub async fn discover_mapping<W, D>(
+    wg: &mut W,
+    discover: &D,
+    iface: &str,
+    listen_port: Option<u16>,
+    policy: PortPolicy,
+) -> Result<(Mapping, u16), Error>
+where
+    W: WireguardApi,
+    D: Discover,
+    Error: From<W::Error> + From<D::Error>,
+{
+    let Some(port) = listen_port else {
+        let mapping = discover.discover(0).await?;
+        wg.set_listen_port(iface, mapping.local.port()).await?;
+
+        return Ok((mapping, mapping.local.port()));
+    };
+
+    match policy {
+        PortPolicy::Refuse => {
+            // wireguard stays where it is, discovery fails when the port is
+            // taken exclusively
+            let mapping = discover.discover(port).await?;
+
+            if mapping.local.port() != port {
+                return Err(Error::PortMismatch(port, mapping));
+            }
+
+            Ok((mapping, port))
+        }
+
+        PortPolicy::Rediscover => {
+            // free the port for the stun socket, `0` lets the kernel pick
+            wg.set_listen_port(iface, 0).await?;
+            let res = discover.discover(port).await;
+            wg.set_listen_port(iface, port).await?;
+
+            Ok((res?, port))
+        }
+    }
+}
+
+/// Next control request, pending forever without control frontends.
+async fn next_request(control: &mut Option<control::Receiver>) -> Option<control::Request> {
+    match control {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Next event besides signaling, pending forever without other sources.
+async fn next_event(events: &mut Option<mpsc::Receiver<PeerEvent>>) -> Option<PeerEvent> {
+    match events {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// What to ask STUN about our listen port, see [`Runner::probe_port`].
+#[derive(Debug, Clone, Copy, Default)]
+struct Probes {
+    /// Mapping via the active uplink.
+    mapping: bool,
+
+    /// Mappings via the other uplinks set to be advertised, besides the
+    /// primary one.
+    uplinks: Option<Mapping>,
+    v6: bool,
+    nat: bool,
+}
+
+/// Answers to [`Probes`], empty where not asked or failed.
+#[derive(Debug, Default)]
+struct Probed {
+    mapping: Option<Mapping>,
+    uplinks: Vec<SocketAddr>,
+    public6: Option<SocketAddr>,
+    nat_type: Option<NatType>,
+}
+
+/// Pending directed replies, deduplicated by nickname.
+#[derive(Debug, Default)]
+struct ReplyQueue {
+    queue: VecDeque<String>,
+    queued: HashSet<String>,
+}
+
+impl ReplyQueue {
+    fn push(&mut self, nick: String) {
+        if self.queued.insert(nick.clone()) {
+            self.queue.push_back(nick);
+        }
+    }
+
+    fn pop(&mut self) -> Option<String> {
+        let nick = self.queue.pop_front()?;
+        self.queued.remove(&nick);
+        Some(nick)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// Public endpoints of the local udp `port` via `uplinks`, besides the
+/// `primary` one. The uplinks are asked together, each for
+/// [`UPLINK_PROBE_TIMEOUT`] at most.
+async fn discover_uplinks<D>(
+    discover: &D,
+    port: u16,
+    uplinks: &[&UplinkConfig],
+    primary: &Mapping,
+) -> Vec<SocketAddr>
+where
+    D: Discover,
+    Error: From<D::Error>,
+{
+    let probes = uplinks.iter().map(|uplink| {
+        tokio::time::timeout(
+            UPLINK_PROBE_TIMEOUT,
+            discover.discover_via(port, &uplink.device),
+        )
+    });
+
+    let mut endpoints = Vec::new();
+    for (uplink, res) in uplinks.iter().zip(futures::future::join_all(probes).await) {
+        match res {
+            Ok(Ok(mapping)) if mapping.public == primary.public => {}
+            Ok(Ok(mapping)) => {
+                log::info!("uplink {} mapping {mapping}", uplink.name);
+                if !endpoints.contains(&mapping.public) {
+                    endpoints.push(mapping.public);
+                }
+            }
+            Ok(Err(err)) => log::warn!(
+                "can't discover uplink {}: {}",
+                uplink.name,
+                Error::from(err)
+            ),
+            Err(_) => log::warn!("can't discover uplink {}: timed out", uplink.name),
+        }
+    }
+
+    endpoints
+}
+
+/// Puts `candidates` of the primary uplink ahead of the endpoints of other
+/// uplinks in `endpoints`, which peers are to try only when the primary
+/// one's fail.
+fn prepend_candidates(endpoints: &mut Vec<SocketAddr>, candidates: &[SocketAddr]) {
+    endpoints.retain(|addr| !candidates.contains(addr));
+    endpoints.splice(0..0, candidates.iter().copied());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        io,
+        net::{Ipv4Addr, SocketAddr},
+        sync::Mutex,
+        time::Duration,
+    };
+
+    use futures::{Stream, stream};
+    use tokio::time::Instant;
+
+    use crate::{
+        discover::{Discover, Mapping, nat::NatType},
+        error::Error,
+        retry::RetryPolicy,
+        signaling::{PeerEvent, PeerUpdate, Signaling},
+        uplink::UplinkConfig,
+        wg::{
+            Key, WireguardApi,
+            config::{WgConfig, WgConfigInterface},
+            memory::MemoryBackend,
+        },
+    };
+
+    use super::{
+        PortPolicy, Probes, Runner, RunnerOptions, UPLINK_PROBE_TIMEOUT, discover_mapping,
+        discover_uplinks, prepend_candidates,
+    };
+
+    /// Maps every port to `local`, or the one asked for.
+    #[derive(Default)]
+    struct Nat {
+        local: Option<u16>,
+        asked: Mutex<Vec<u16>>,
+    }
+
+    impl Discover for Nat {
+        type Error = Infallible;
+
+        async fn discover(&self, port: u16) -> Result<Mapping, Self::Error> {
+            self.asked.lock().unwrap().push(port);
+            let local = self.local.unwrap_or(port);
+
+            Ok(Mapping {
+                public: SocketAddr::new(Ipv4Addr::new(203, 0, 113, 7).into(), 40000),
+                local: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local),
+            })
+        }
+
+        async fn discover_via(&self, port: u16, _device: &str) -> Result<Mapping, Self::Error> {
+            self.discover(port).await
+        }
+    }
+
+    fn listening(listen_port: u16) -> MemoryBackend {
+        MemoryBackend::new(&WgConfig {
+            interface: WgConfigInterface {
+                listen_port: Some(listen_port),
+                ..Default::default()
+            },
+            peers: Vec::new(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_port_policy() {
+        // no ListenPort, wireguard follows the mapping
+        let (mut wg, nat) = (listening(0), Nat::default());
+        let (mapping, port) = discover_mapping(&mut wg, &nat, "wg0", None, PortPolicy::Refuse)
+            .await
+            .unwrap();
+        assert_eq!(*nat.asked.lock().unwrap(), [0]);
+        assert_eq!(port, mapping.local.port());
+        assert_eq!(wg.get_listen_port("wg0").await.unwrap(), port);
+
+        // the configured port is discovered, wireguard moved back onto it
+        let (mut wg, nat) = (listening(51820), Nat::default());
+        let (mapping, port) =
+            discover_mapping(&mut wg, &nat, "wg0", Some(51820), PortPolicy::Rediscover)
+                .await
+                .unwrap();
+        assert_eq!(*nat.asked.lock().unwrap(), [51820]);
+        assert_eq!((mapping.local.port(), port), (51820, 51820));
+        assert_eq!(wg.get_listen_port("wg0").await.unwrap(), 51820);
+
+        // discovered from the port wireguard listens on, without moving it
+        let (mut wg, nat) = (listening(51820), Nat::default());
+        let (mapping, port) =
+            discover_mapping(&mut wg, &nat, "wg0", Some(51820), PortPolicy::Refuse)
+                .await
+                .unwrap();
+        assert_eq!(*nat.asked.lock().unwrap(), [51820]);
+        assert_eq!((mapping.local.port(), port), (51820, 51820));
+
+        let (mut wg, nat) = (
+            listening(51820),
+            Nat {
+                local: Some(40123),
+                ..Default::default()
+            },
+        );
+        let res = discover_mapping(&mut wg, &nat, "wg0", Some(51820), PortPolicy::Refuse).await;
+        assert!(matches!(res, Err(Error::PortMismatch(51820, _))));
+        assert_eq!(wg.get_listen_port("wg0").await.unwrap(), 51820);
+    }
+
+    /// Uplink `wanN` maps to port `4000N` after `N` seconds, `down` never
+    /// answers.
+    struct Uplinks;
+
+    impl Discover for Uplinks {
+        type Error = Infallible;
+
+        async fn discover(&self, port: u16) -> Result<Mapping, Self::Error> {
+            self.discover_via(port, "wan0").await
+        }
+
+        async fn discover_via(&self, port: u16, device: &str) -> Result<Mapping, Self::Error> {
+            let Some(n) = device
+                .strip_prefix("wan")
+                .and_then(|n| n.parse::<u16>().ok())
+            else {
+                return std::future::pending().await;
+            };
+            tokio::time::sleep(Duration::from_secs(n.into())).await;
+
+            Ok(Mapping {
+                public: SocketAddr::new(Ipv4Addr::new(203, 0, 113, 7).into(), 40000 + n),
+                local: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+            })
+        }
+    }
+
+    fn uplink(device: &str) -> UplinkConfig {
+        UplinkConfig {
+            name: device.into(),
+            device: device.into(),
+            fwmark: None,
+            table: None,
+            gateway: None,
+            advertise: true,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_discover_uplinks() {
+        let primary = Uplinks.discover(51820).await.unwrap();
+        let uplinks = [
+            uplink("wan2"),
+            uplink("down"),
+            uplink("wan1"),
+            uplink("wan0"),
+        ];
+        let uplinks: Vec<_> = uplinks.iter().collect();
+
+        // asked together, the silent one given up on, the primary's own
+        // mapping left out
+        let started = Instant::now();
+        let endpoints = discover_uplinks(&Uplinks, 51820, &uplinks, &primary).await;
+        assert_eq!(started.elapsed(), UPLINK_PROBE_TIMEOUT);
+        assert_eq!(
+            endpoints,
+            [
+                "203.0.113.7:40002".parse().unwrap(),
+                "203.0.113.7:40001".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prepend_candidates() {
+        let addr = |port| SocketAddr::new(Ipv4Addr::new(203, 0, 113, 7).into(), port);
+
+        // the other uplinks come last
+        let mut endpoints = vec![addr(1), addr(2)];
+        prepend_candidates(&mut endpoints, &[addr(3), addr(2)]);
+        assert_eq!(endpoints, [addr(3), addr(2), addr(1)]);
+    }
+
+    /// Fails every announcement with `error`, counting them.
+    struct Failing {
+        error: fn() -> Error,
+        attempts: usize,
+    }
+
+    impl Signaling for Failing {
+        type Error = Error;
+
+        async fn announce(&mut self, _peer: PeerUpdate, _nick: Option<&str>) -> Result<(), Error> {
+            self.attempts += 1;
+            Err((self.error)())
+        }
+
+        async fn subscribe(
+            &mut self,
+        ) -> Result<impl Stream<Item = Result<PeerEvent, Error>> + use<>, Error> {
+            Ok(stream::pending())
+        }
+    }
+
+    /// Fails every probe, noting the port wireguard listens on meanwhile.
+    struct Down {
+        wg: MemoryBackend,
+        seen: Mutex<Vec<u16>>,
+    }
+
+    impl Down {
+        async fn fail<T>(&self) -> Result<T, io::Error> {
+            let port = self.wg.get_listen_port("wg0").await.unwrap();
+            self.seen.lock().unwrap().push(port);
+            Err(io::ErrorKind::TimedOut.into())
+        }
+    }
+
+    impl Discover for Down {
+        type Error = io::Error;
+
+        async fn discover(&self, _port: u16) -> Result<Mapping, Self::Error> {
+            self.fail().await
+        }
+
+        async fn discover_via(&self, _port: u16, _device: &str) -> Result<Mapping, Self::Error> {
+            self.fail().await
+        }
+
+        async fn discover_v6(&self, _port: u16) -> Option<Result<Mapping, Self::Error>> {
+            Some(self.fail().await)
+        }
+
+        async fn nat_type(&self, _port: u16) -> Option<Result<NatType, Self::Error>> {
+            Some(self.fail().await)
+        }
+    }
+
+    fn runner(wg: MemoryBackend, error: fn() -> Error) -> Runner<MemoryBackend, Failing, Down> {
+        let config = WgConfig {
+            interface: WgConfigInterface {
+                listen_port: Some(51820),
+                ..Default::default()
+            },
+            peers: Vec::new(),
+        };
+        let signaling = Failing { error, attempts: 0 };
+        let discover = Down {
+            wg: wg.clone(),
+            seen: Mutex::default(),
+        };
+
+        let mut runner = Runner::new(
+            "wg0".into(),
+            Key::random(),
+            config,
+            wg,
+            signaling,
+            discover,
+            RunnerOptions::default(),
+        );
+        runner.listen_port = 51820;
+        runner
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_errors() {
+        let update = PeerUpdate {
+            key: Key::random(),
+            endpoint: "203.0.113.7:51820".parse().unwrap(),
+            advertise_routes: Vec::new(),
+            ext: Default::default(),
+        };
+
+        // sending the same message again can't help
+        let mut too_long = runner(listening(51820), || Error::MessageTooLong);
+        let res = too_long.announce(&update, None).await;
+        assert!(matches!(res, Err(Error::MessageTooLong)));
+        assert_eq!(too_long.signaling.attempts, 1);
+
+        // a dropped connection may be back
+        let broken = || io::Error::from(io::ErrorKind::BrokenPipe).into();
+        let mut disconnected = runner(listening(51820), broken);
+        let res = disconnected.announce(&update, Some("peer")).await;
+        assert!(matches!(res, Err(Error::IoError(_))));
+        assert_eq!(
+            disconnected.signaling.attempts,
+            RetryPolicy::default().attempts as usize
+        );
+
+        // the event loop only logs it
+        too_long.try_announce(&update, None).await;
+        assert_eq!(too_long.signaling.attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_listen_port_restored() {
+        let wg = listening(51820);
+        let mut runner = runner(wg.clone(), || Error::NoSignaling);
+
+        let probed = runner
+            .probe_port(Probes {
+                mapping: true,
+                uplinks: None,
+                v6: true,
+                nat: true,
+            })
+            .await;
+        assert_eq!(probed.mapping, None);
+        assert_eq!(probed.public6, None);
+        assert_eq!(probed.nat_type, None);
+
+        // freed for every probe and moved back though all of them failed
+        assert_eq!(*runner.discover.seen.lock().unwrap(), [0, 0, 0]);
+        assert_eq!(wg.get_listen_port("wg0").await.unwrap(), 51820);
+
+        // a restore that failed is tried again by housekeeping
+        runner.wg.set_listen_port("wg0", 0).await.unwrap();
+        runner.port_freed = true;
+        runner.restore_port().await;
+        assert_eq!(wg.get_listen_port("wg0").await.unwrap(), 51820);
+        assert!(!runner.port_freed);
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::wg::Key;
+
+/// Switches between sources are counted over this long.
+const WINDOW: Duration = Duration::from_secs(600);
+
+/// A node roaming away and back switches twice, copies of one keep going.
+const SWITCHES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// Announced by a single node.
+    Unique,
+
+    /// Just found to be announced by two nodes.
+    Detected,
+
+    /// Still announced by two nodes.
+    Cloned,
+}
+
+#[derive(Debug)]
+struct Sources {
+    nick: String,
+    endpoint: SocketAddr,
+    switches: VecDeque<Instant>,
+    cloned: bool,
+}
+
+/// Tells apart two live nodes announcing the same key, e.g. started from a
+/// copied VM image, by their announcements alternating between different
+/// nicknames and endpoints.
+#[derive(Debug, Default)]
+pub struct CloneDetector {
+    peers: HashMap<Key, Sources>,
+}
+
+impl CloneDetector {
+    pub fn observe(&mut self, key: Key, nick: &str, endpoint: SocketAddr, now: Instant) -> Origin {
+        let Some(sources) = self.peers.get_mut(&key) else {
+            self.peers.insert(
+                key,
+                Sources {
+                    nick: nick.to_string(),
+                    endpoint,
+                    switches: VecDeque::new(),
+                    cloned: false,
+                },
+            );
+
+            return Origin::Unique;
+        };
+
+        if sources.nick != nick && sources.endpoint != endpoint {
+            sources.switches.push_back(now);
+        }
+
+        sources.nick = nick.to_string();
+        sources.endpoint = endpoint;
+
+        while sources
+            .switches
+            .front()
+            .is_some_and(|at| now - *at > WINDOW)
+        {
+            sources.switches.pop_front();
+        }
+
+        match (sources.cloned, sources.switches.len() >= SWITCHES) {
+            (false, true) => {
+                sources.cloned = true;
+                Origin::Detected
+            }
+            // one of them is gone
+            (true, _) if sources.switches.is_empty() => {
+                sources.cloned = false;
+                Origin::Unique
+            }
+            (true, _) => Origin::Cloned,
+            (false, false) => Origin::Unique,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::time::Instant;
+
+    use crate::wg::Key;
+
+    use super::{CloneDetector, Origin};
+
+    #[test]
+    fn test_alternating_sources() {
+        let mut clones = CloneDetector::default();
+        let key = Key::random();
+        let a: SocketAddr = "203.0.113.7:51820".parse().unwrap();
+        let b: SocketAddr = "198.51.100.20:51820".parse().unwrap();
+        let mut now = Instant::now();
+
+        // roaming away and back is fine
+        assert_eq!(clones.observe(key, "wg-a", a, now), Origin::Unique);
+        assert_eq!(clones.observe(key, "wg-b", b, now), Origin::Unique);
+        assert_eq!(clones.observe(key, "wg-a", a, now), Origin::Unique);
+
+        now += Duration::from_secs(30);
+        assert_eq!(clones.observe(key, "wg-b", b, now), Origin::Detected);
+        assert_eq!(clones.observe(key, "wg-a", a, now), Origin::Cloned);
+
+        // the copy is shut down
+        now += Duration::from_secs(601);
+        assert_eq!(clones.observe(key, "wg-a", a, now), Origin::Unique);
+    }
+}
@@ -0,0 +1,165 @@
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
+
+use tokio::time::Instant;
+
+/// Changes within this window are considered for flap detection.
+const FLAP_WINDOW: Duration = Duration::from_secs(60);
+
+/// More changes than this within the window is a flap.
+const FLAP_THRESHOLD: usize = 4;
+
+const MIN_HOLD_DOWN: Duration = Duration::from_secs(30);
+const MAX_HOLD_DOWN: Duration = Duration::from_secs(3600);
+
+/// Number of remembered endpoint changes.
+const HISTORY_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// New endpoint, should be applied.
+    Apply,
+
+    /// Endpoint is already in place.
+    Unchanged,
+
+    /// Flap detected just now, updates are held down until the instant.
+    Damped(Instant),
+
+    /// Still held down from an earlier flap.
+    Suppressed(Instant),
+}
+
+/// Endpoint changes of a single peer with exponentially increasing
+/// hold-down when they come too often (two nodes fighting over the key,
+/// a broken NAT).
+#[derive(Debug, Default, Clone)]
+pub struct EndpointHistory {
+    changes: VecDeque<(Instant, SocketAddr)>,
+    current: Option<SocketAddr>,
+    pending: Option<SocketAddr>,
+    hold_down: Duration,
+    suppressed_until: Option<Instant>,
+}
+
+impl EndpointHistory {
+    pub fn record(&mut self, addr: SocketAddr, now: Instant) -> Verdict {
+        if let Some(until) = self.suppressed_until {
+            if now < until {
+                self.pending = Some(addr);
+                return Verdict::Suppressed(until);
+            }
+
+            self.suppressed_until = None;
+        }
+
+        if self.current == Some(addr) {
+            return Verdict::Unchanged;
+        }
+
+        if self.changes.len() == HISTORY_LEN {
+            self.changes.pop_front();
+        }
+        self.changes.push_back((now, addr));
+
+        let recent = self
+            .changes
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) <= FLAP_WINDOW)
+            .count();
+
+        if recent > FLAP_THRESHOLD {
+            self.hold_down = (self.hold_down * 2).clamp(MIN_HOLD_DOWN, MAX_HOLD_DOWN);
+            self.pending = Some(addr);
+
+            let until = now + self.hold_down;
+            self.suppressed_until = Some(until);
+
+            return Verdict::Damped(until);
+        }
+
+        // calmed down for long enough, forget the penalty
+        if recent == 1 && self.hold_down > Duration::ZERO {
+            self.hold_down /= 2;
+        }
+
+        self.current = Some(addr);
+        Verdict::Apply
+    }
+
+    /// The kernel reported the endpoint on its own (peer roamed).
+    pub fn observe(&mut self, addr: SocketAddr) {
+        self.current = Some(addr);
+    }
+
+    /// Endpoint the peer is at as far as we know, applied or roamed to.
+    #[inline]
+    pub fn current(&self) -> Option<SocketAddr> {
+        self.current
+    }
+
+    /// Returns the last endpoint received during the hold-down once it
+    /// expired.
+    pub fn release(&mut self, now: Instant) -> Option<SocketAddr> {
+        match self.suppressed_until {
+            Some(until) if now >= until => {
+                self.suppressed_until = None;
+                let addr = self.pending.take().filter(|&a| Some(a) != self.current)?;
+                self.current = Some(addr);
+                Some(addr)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_suppressed(&self) -> bool {
+        self.suppressed_until.is_some()
+    }
+
+    pub fn changes(&self) -> impl Iterator<Item = &(Instant, SocketAddr)> {
+        self.changes.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::time::Instant;
+
+    use super::{EndpointHistory, MIN_HOLD_DOWN, Verdict};
+
+    #[test]
+    fn test_flap_damping() {
+        let a: SocketAddr = "203.0.113.7:51820".parse().unwrap();
+        let b: SocketAddr = "198.51.100.3:40000".parse().unwrap();
+        let start = Instant::now();
+        let mut history = EndpointHistory::default();
+
+        assert_eq!(history.record(a, start), Verdict::Apply);
+        assert_eq!(history.record(a, start), Verdict::Unchanged);
+
+        let mut now = start;
+        for addr in [b, a, b] {
+            now += Duration::from_secs(1);
+            assert_eq!(history.record(addr, now), Verdict::Apply);
+        }
+
+        now += Duration::from_secs(1);
+        assert_eq!(history.record(a, now), Verdict::Damped(now + MIN_HOLD_DOWN));
+        assert!(history.is_suppressed());
+        assert!(matches!(history.record(b, now), Verdict::Suppressed(_)));
+        assert_eq!(history.release(now), None);
+
+        // the last endpoint seen during the hold-down is already in place
+        assert_eq!(history.release(now + MIN_HOLD_DOWN), None);
+        assert!(!history.is_suppressed());
+        assert_eq!(history.changes().count(), 5);
+
+        // flapping again right away doubles the hold-down
+        let now = now + MIN_HOLD_DOWN;
+        assert_eq!(
+            history.record(a, now),
+            Verdict::Damped(now + MIN_HOLD_DOWN * 2)
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Folds triggers arriving close together into one action: it is due once
+/// no trigger came for `quiet`, but no later than `max_delay` after the
+/// first one, so a steady stream of triggers can't postpone it forever.
+#[derive(Debug, Clone)]
+pub struct Debounce {
+    quiet: Duration,
+    max_delay: Duration,
+
+    /// First and latest trigger since the action was last taken.
+    pending: Option<(Instant, Instant)>,
+}
+
+impl Debounce {
+    pub fn new(quiet: Duration, max_delay: Duration) -> Self {
+        Self {
+            quiet,
+            max_delay,
+            pending: None,
+        }
+    }
+
+    pub fn trigger(&mut self, now: Instant) {
+        self.pending = match self.pending {
+            Some((first, _)) => Some((first, now)),
+            None => Some((now, now)),
+        };
+    }
+
+    /// When the action is to be taken, `None` without triggers.
+    pub fn due(&self) -> Option<Instant> {
+        let (first, last) = self.pending?;
+        Some((last + self.quiet).min(first + self.max_delay))
+    }
+
+    /// `true` once when the action is due, forgetting the triggers.
+    pub fn take(&mut self, now: Instant) -> bool {
+        match self.due() {
+            Some(due) if due <= now => {
+                self.pending = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::Debounce;
+
+    #[test]
+    fn test_debounce() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut debounce = Debounce::new(ms(500), ms(2000));
+
+        assert_eq!(debounce.due(), None);
+        assert!(!debounce.take(start));
+
+        debounce.trigger(start);
+        debounce.trigger(start + ms(300));
+        assert_eq!(debounce.due(), Some(start + ms(800)));
+        assert!(!debounce.take(start + ms(700)));
+        assert!(debounce.take(start + ms(800)));
+        assert!(!debounce.take(start + ms(900)));
+
+        // triggers every 400ms are cut off after max_delay
+        for at in (0..10).map(|i| start + ms(3000 + 400 * i)) {
+            debounce.trigger(at);
+        }
+        assert_eq!(debounce.due(), Some(start + ms(5000)));
+    }
+}
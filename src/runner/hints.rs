@@ -0,0 +1,94 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{android, fs::write_atomic, systemd, wg::Key};
+
+use super::Candidate;
+
+/// How each peer was reached last time, kept across restarts so the
+/// candidate which worked is tried first. One `<key> <candidate>` per line.
+#[derive(Debug, Default)]
+pub struct Hints {
+    path: Option<PathBuf>,
+    hints: HashMap<Key, Candidate>,
+}
+
+impl Hints {
+    /// In the `StateDirectory=` of the unit when there is one.
+    pub fn path(iface: &str) -> PathBuf {
+        systemd::state_directory()
+            .unwrap_or_else(|| android::path("/var/lib/wg-disco"))
+            .join(format!("{iface}.hints"))
+    }
+
+    /// Starts empty when the file is missing or unreadable.
+    pub fn load(path: PathBuf) -> Self {
+        let hints = match fs::read_to_string(&path) {
+            Ok(data) => parse(&data),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                log::warn!("can't read hints {}: {err}", path.display());
+                HashMap::new()
+            }
+        };
+
+        Self {
+            path: Some(path),
+            hints,
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, key: &Key) -> Option<Candidate> {
+        self.hints.get(key).copied()
+    }
+
+    pub fn set(&mut self, key: Key, kind: Candidate) {
+        if self.hints.insert(key, kind) == Some(kind) {
+            return;
+        }
+
+        if let Some(path) = &self.path
+            && let Err(err) = save(path, &self.hints)
+        {
+            log::warn!("can't save hints to {}: {err}", path.display());
+        }
+    }
+}
+
+fn parse(data: &str) -> HashMap<Key, Candidate> {
+    data.lines()
+        .filter_map(|line| {
+            let (key, kind) = line.split_once(' ')?;
+            Some((key.parse().ok()?, kind.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+fn save(path: &Path, hints: &HashMap<Key, Candidate>) -> io::Result<()> {
+    let data: String = hints
+        .iter()
+        .map(|(key, kind)| format!("{key} {kind}\n"))
+        .collect();
+
+    write_atomic(path, data, 0o600)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{runner::Candidate, wg::Key};
+
+    use super::parse;
+
+    #[test]
+    fn test_parse_skips_garbage() {
+        let (a, b) = (Key::random(), Key::random());
+        let hints = parse(&format!("{a} lan\n{b} carrier-pigeon\nnonsense\n\n"));
+
+        assert_eq!(hints.len(), 1);
+        assert_eq!(hints.get(&a), Some(&Candidate::Lan));
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Token bucket limiting how fast outgoing announcements are sent, so a
+/// burst of requests from a large mesh doesn't get us flood-kicked.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    burst: u32,
+    tokens: u32,
+    period: Duration,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, period: Duration) -> Self {
+        Self {
+            burst,
+            tokens: burst,
+            period,
+            last: Instant::now(),
+        }
+    }
+
+    /// Time needed to refill a single token.
+    #[inline]
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last);
+        let added = (elapsed.as_nanos() / self.period.as_nanos().max(1)) as u32;
+
+        if added > 0 {
+            self.tokens = (self.tokens + added).min(self.burst);
+            self.last += self.period * added;
+        }
+
+        if self.tokens == self.burst {
+            self.last = now;
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::RateLimiter;
+
+    #[test]
+    fn test_refill() {
+        let period = Duration::from_secs(2);
+        let mut limiter = RateLimiter::new(3, period);
+        let start = Instant::now();
+
+        // the burst goes right away
+        assert!((0..3).all(|_| limiter.try_acquire(start)));
+        assert!(!limiter.try_acquire(start));
+
+        // a token per period, time short of one carries over
+        let later = start + period + period / 2;
+        assert!(limiter.try_acquire(later));
+        assert!(!limiter.try_acquire(later));
+        assert!(limiter.try_acquire(start + period * 2));
+
+        // idle time refills up to the burst only
+        let idle = start + period * 100;
+        assert!((0..3).all(|_| limiter.try_acquire(idle)));
+        assert!(!limiter.try_acquire(idle));
+    }
+
+    #[test]
+    fn test_clock_going_back() {
+        let mut limiter = RateLimiter::new(1, Duration::from_secs(2));
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire(now + Duration::from_secs(10)));
+        assert!(!limiter.try_acquire(now));
+    }
+}
@@ -0,0 +1,362 @@
+use std::{collections::HashMap, fmt, io, net::SocketAddr, str::FromStr, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{retry::RetryPolicy, wg::Key};
+
+/// Probes sent at a rendezvous, spread over a second to make up for clock
+/// offset estimates being off.
+const PROBES: u32 = 10;
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How a peer is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Candidate {
+    /// Address behind the NAT, works on the same LAN.
+    Lan,
+
+    /// Public IPv6 address, usually needs no punching.
+    V6,
+
+    /// NAT-mapped IPv4 address.
+    V4,
+
+    /// Transport helper, direct UDP didn't work.
+    Relay,
+}
+
+impl Candidate {
+    /// Kind of a public address.
+    pub fn public(addr: &SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(_) => Candidate::V4,
+            SocketAddr::V6(_) => Candidate::V6,
+        }
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Candidate::Lan => "lan",
+            Candidate::V6 => "v6",
+            Candidate::V4 => "v4",
+            Candidate::Relay => "relay",
+        })
+    }
+}
+
+impl FromStr for Candidate {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lan" => Ok(Candidate::Lan),
+            "v6" => Ok(Candidate::V6),
+            "v4" => Ok(Candidate::V4),
+            "relay" => Ok(Candidate::Relay),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How far punching a peer got, see [`PunchScheduler::progress`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PunchProgress {
+    pub candidates: Vec<(Candidate, SocketAddr)>,
+
+    /// Index of the candidate being tried.
+    pub current: usize,
+
+    /// Attempts made after the first one.
+    pub attempt: u32,
+
+    /// The last attempt is running, nothing is tried after it.
+    pub exhausted: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Punch {
+    candidates: Vec<(Candidate, SocketAddr)>,
+    current: usize,
+    attempt: u32,
+
+    /// `None` once the last attempt is running.
+    next: Option<Instant>,
+}
+
+/// Cycles through the candidates of peers without a session with growing
+/// delays until a handshake happens and tells which candidate worked.
+#[derive(Debug, Default)]
+pub struct PunchScheduler {
+    policy: RetryPolicy,
+    active: HashMap<Key, Punch>,
+
+    /// When peers punch together with us, agreed on over signaling.
+    rendezvous: HashMap<Key, Instant>,
+}
+
+impl PunchScheduler {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Starts punching with `candidates` in order of preference, the
+    /// `preferred` kind goes first. Returns the address to try now.
+    pub fn start(
+        &mut self,
+        key: Key,
+        mut candidates: Vec<(Candidate, SocketAddr)>,
+        preferred: Option<Candidate>,
+        now: Instant,
+    ) -> Option<SocketAddr> {
+        if let Some(preferred) = preferred {
+            candidates.sort_by_key(|(kind, _)| *kind != preferred);
+        }
+
+        let first = candidates.first()?.1;
+        self.active.insert(
+            key,
+            Punch {
+                candidates,
+                current: 0,
+                attempt: 0,
+                next: self.policy.backoff(0).map(|delay| now + delay),
+            },
+        );
+
+        Some(first)
+    }
+
+    /// Next candidates of peers whose attempt ran out of time.
+    pub fn due(&mut self, now: Instant) -> Vec<(Key, SocketAddr)> {
+        let mut due = Vec::new();
+
+        for (key, punch) in &mut self.active {
+            if punch.next.is_none_or(|next| next > now) {
+                continue;
+            }
+
+            punch.attempt += 1;
+            punch.current = (punch.current + 1) % punch.candidates.len();
+            punch.next = self.policy.backoff(punch.attempt).map(|delay| now + delay);
+
+            due.push((*key, punch.candidates[punch.current].1));
+        }
+
+        due
+    }
+
+    /// Handshake completed, returns the candidate being tried.
+    pub fn succeeded(&mut self, key: &Key) -> Option<(Candidate, SocketAddr)> {
+        let punch = self.active.remove(key)?;
+        Some(punch.candidates[punch.current])
+    }
+
+    pub fn progress(&self, key: &Key) -> Option<PunchProgress> {
+        let punch = self.active.get(key)?;
+
+        Some(PunchProgress {
+            candidates: punch.candidates.clone(),
+            current: punch.current,
+            attempt: punch.attempt,
+            exhausted: punch.next.is_none(),
+        })
+    }
+
+    /// Stops punching, e.g. when the endpoint is taken over by something else.
+    pub fn cancel(&mut self, key: &Key) {
+        self.active.remove(key);
+        self.rendezvous.remove(key);
+    }
+
+    /// Punches the peer again at `at`, the moment it punches us, so both
+    /// NATs open their mappings before the other side's packets arrive.
+    /// `false` when a rendezvous is already set.
+    pub fn meet(&mut self, key: Key, at: Instant) -> bool {
+        match self.rendezvous.contains_key(&key) {
+            true => false,
+            false => {
+                self.rendezvous.insert(key, at);
+                true
+            }
+        }
+    }
+
+    /// When the next rendezvous is.
+    pub fn next_rendezvous(&self) -> Option<Instant> {
+        self.rendezvous.values().min().copied()
+    }
+
+    /// Candidates to punch now together with their peers. The attempt
+    /// gets its full time from the rendezvous on.
+    pub fn rendezvous_due(&mut self, now: Instant) -> Vec<(Key, SocketAddr)> {
+        let mut due = Vec::new();
+
+        for (key, _) in self.rendezvous.extract_if(|_, at| *at <= now) {
+            let Some(punch) = self.active.get_mut(&key) else {
+                continue;
+            };
+
+            if punch.next.is_some() {
+                punch.next = self.policy.backoff(punch.attempt).map(|delay| now + delay);
+            }
+            due.push((key, punch.candidates[punch.current].1));
+        }
+
+        due
+    }
+}
+
+/// Sends empty UDP datagrams from wireguard's listen `port` to `target`,
+/// opening our NAT's mapping towards the peer while its probes are on the
+/// way. Wireguard holds the port, so they go out of a raw socket, which
+/// needs CAP_NET_RAW. The peer's wireguard drops them. Blocks meanwhile.
+#[cfg(target_os = "linux")]
+pub fn probe(port: u16, target: SocketAddr) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(target), Type::RAW, Some(Protocol::UDP))?;
+
+    // the checksum is mandatory over IPv6, the kernel knows the source
+    // address it covers
+    if target.is_ipv6() {
+        let offset: libc::c_int = 6;
+        let res = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_CHECKSUM,
+                (&offset as *const libc::c_int).cast(),
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // the ports are in the header, the address takes none
+    let addr = SockAddr::from(SocketAddr::new(target.ip(), 0));
+    let datagram = udp_header(port, target.port());
+
+    for i in 0..PROBES {
+        if i > 0 {
+            std::thread::sleep(PROBE_INTERVAL);
+        }
+        socket.send_to(&datagram, &addr)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn probe(_port: u16, _target: SocketAddr) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Header of an empty UDP datagram, without checksum.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn udp_header(src: u16, dst: u16) -> [u8; 8] {
+    let mut header = [0; 8];
+    header[..2].copy_from_slice(&src.to_be_bytes());
+    header[2..4].copy_from_slice(&dst.to_be_bytes());
+    header[4..6].copy_from_slice(&8u16.to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::time::Instant;
+
+    use crate::{retry::RetryPolicy, wg::Key};
+
+    use super::{Candidate, PunchScheduler};
+
+    #[test]
+    fn test_punch_cycles_and_settles() {
+        let mut punch = PunchScheduler::new(RetryPolicy {
+            initial_ms: 1000,
+            multiplier: 2.0,
+            max_ms: 10_000,
+            jitter: 0.0,
+            attempts: 3,
+        });
+
+        let key = Key::random();
+        let local: SocketAddr = "192.168.1.2:51820".parse().unwrap();
+        let public: SocketAddr = "1.2.3.4:40000".parse().unwrap();
+        let candidates = vec![(Candidate::V4, public), (Candidate::Lan, local)];
+
+        let now = Instant::now();
+        assert_eq!(
+            punch.start(key, candidates.clone(), None, now),
+            Some(public)
+        );
+        assert!(punch.due(now).is_empty());
+
+        let now = now + Duration::from_secs(1);
+        assert_eq!(punch.due(now), vec![(key, local)]);
+
+        // last attempt, nothing more is scheduled
+        let now = now + Duration::from_secs(2);
+        assert_eq!(punch.due(now), vec![(key, public)]);
+        assert!(punch.due(now + Duration::from_secs(60)).is_empty());
+
+        assert_eq!(punch.succeeded(&key), Some((Candidate::V4, public)));
+        assert_eq!(punch.succeeded(&key), None);
+
+        let preferred = Some(Candidate::Lan);
+        assert_eq!(punch.start(key, candidates, preferred, now), Some(local));
+    }
+
+    #[test]
+    fn test_rendezvous() {
+        let mut punch = PunchScheduler::new(RetryPolicy {
+            initial_ms: 1000,
+            multiplier: 1.0,
+            max_ms: 1000,
+            jitter: 0.0,
+            attempts: 5,
+        });
+
+        let (key, idle) = (Key::random(), Key::random());
+        let public: SocketAddr = "1.2.3.4:40000".parse().unwrap();
+        let other: SocketAddr = "5.6.7.8:40000".parse().unwrap();
+        let candidates = vec![(Candidate::V4, public), (Candidate::V4, other)];
+
+        let now = Instant::now();
+        punch.start(key, candidates, None, now);
+
+        let at = now + Duration::from_millis(900);
+        assert!(punch.meet(key, at));
+        assert!(!punch.meet(key, at + Duration::from_secs(1)));
+        assert!(punch.meet(idle, now + Duration::from_secs(5)));
+        assert_eq!(punch.next_rendezvous(), Some(at));
+        assert!(punch.rendezvous_due(now).is_empty());
+
+        // the attempt isn't cut short right after the rendezvous
+        assert_eq!(punch.rendezvous_due(at), vec![(key, public)]);
+        assert!(punch.due(now + Duration::from_secs(1)).is_empty());
+        assert_eq!(punch.due(at + Duration::from_secs(1)), vec![(key, other)]);
+
+        // peers which aren't punched anymore are skipped
+        assert!(
+            punch
+                .rendezvous_due(now + Duration::from_secs(5))
+                .is_empty()
+        );
+        assert_eq!(punch.next_rendezvous(), None);
+
+        punch.meet(key, now);
+        punch.cancel(&key);
+        assert_eq!(punch.next_rendezvous(), None);
+    }
+}
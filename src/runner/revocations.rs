@@ -0,0 +1,127 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{android, fs::write_atomic, signaling::revocation::Revocation, systemd, wg::Key};
+
+/// Revocations received or issued, kept across restarts so revoked peers
+/// stay removed. One `<key> <timestamp> <signature>` per line.
+#[derive(Debug, Default)]
+pub struct Revocations {
+    path: Option<PathBuf>,
+    revocations: Vec<Revocation>,
+
+    /// The one to attach to the next announcement.
+    next: usize,
+}
+
+impl Revocations {
+    /// In the `StateDirectory=` of the unit when there is one.
+    pub fn path(iface: &str) -> PathBuf {
+        systemd::state_directory()
+            .unwrap_or_else(|| android::path("/var/lib/wg-disco"))
+            .join(format!("{iface}.revoked"))
+    }
+
+    /// Starts empty when the file is missing or unreadable. Signatures are
+    /// checked by the caller, the signers may have changed since.
+    pub fn load(path: PathBuf) -> Self {
+        let revocations = match fs::read_to_string(&path) {
+            Ok(data) => parse(&data),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                log::warn!("can't read revocations {}: {err}", path.display());
+                Vec::new()
+            }
+        };
+
+        Self {
+            path: Some(path),
+            revocations,
+            next: 0,
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, key: &Key) -> bool {
+        self.revocations
+            .iter()
+            .any(|revocation| revocation.key == *key)
+    }
+
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &Revocation> {
+        self.revocations.iter()
+    }
+
+    /// Drops revocations no longer signed by a configured signer.
+    pub fn retain(&mut self, f: impl FnMut(&Revocation) -> bool) {
+        self.revocations.retain(f);
+    }
+
+    /// Adds and saves it, `false` when the key is already revoked.
+    pub fn insert(&mut self, revocation: Revocation) -> bool {
+        if self.contains(&revocation.key) {
+            return false;
+        }
+        self.revocations.push(revocation);
+
+        if let Some(path) = &self.path
+            && let Err(err) = save(path, &self.revocations)
+        {
+            log::warn!("can't save revocations to {}: {err}", path.display());
+        }
+
+        true
+    }
+
+    /// Cycles through the revocations, one per announcement, so every
+    /// peer learns all of them eventually while messages stay small.
+    pub fn to_announce(&mut self) -> Option<Revocation> {
+        let revocation = *self
+            .revocations
+            .get(self.next % self.revocations.len().max(1))?;
+        self.next = self.next.wrapping_add(1);
+
+        Some(revocation)
+    }
+}
+
+fn parse(data: &str) -> Vec<Revocation> {
+    data.lines().filter_map(|line| line.parse().ok()).collect()
+}
+
+fn save(path: &Path, revocations: &[Revocation]) -> io::Result<()> {
+    let data: String = revocations
+        .iter()
+        .map(|revocation| format!("{revocation}\n"))
+        .collect();
+
+    write_atomic(path, data, 0o600)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{signaling::revocation::Revocation, wg::Key};
+
+    use super::Revocations;
+
+    #[test]
+    fn test_next_cycles() {
+        let signer = Key::random();
+        let (a, b) = (Key::random(), Key::random());
+
+        let mut revocations = Revocations::default();
+        assert_eq!(revocations.to_announce(), None);
+
+        assert!(revocations.insert(Revocation::sign(&signer, a, 1)));
+        assert!(revocations.insert(Revocation::sign(&signer, b, 2)));
+        assert!(!revocations.insert(Revocation::sign(&signer, a, 3)));
+
+        let keys: Vec<Key> = (0..4)
+            .map(|_| revocations.to_announce().unwrap().key)
+            .collect();
+        assert_eq!(keys, [a, b, a, b]);
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Wall time running ahead of monotonic time by more than this is taken
+/// as a suspend rather than a clock adjustment.
+const MIN_SLEEP: Duration = Duration::from_secs(30);
+
+/// Notices that the system was suspended: monotonic time stands still
+/// while it sleeps, wall time doesn't.
+#[derive(Debug, Clone, Default)]
+pub struct SleepDetector {
+    /// Monotonic and unix time of the previous observation.
+    last: Option<(Instant, u64)>,
+}
+
+impl SleepDetector {
+    /// How long the system slept since the previous observation, `None`
+    /// when it didn't.
+    pub fn observe(&mut self, now: Instant, unix_ms: u64) -> Option<Duration> {
+        let (then, then_ms) = self.last.replace((now, unix_ms))?;
+
+        let wall = Duration::from_millis(unix_ms.saturating_sub(then_ms));
+        let slept = wall.saturating_sub(now - then);
+        (slept >= MIN_SLEEP).then_some(slept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::SleepDetector;
+
+    #[test]
+    fn test_sleep() {
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let mut detector = SleepDetector::default();
+
+        assert_eq!(detector.observe(start, 1_000_000), None);
+        assert_eq!(detector.observe(start + secs(5), 1_005_000), None);
+
+        // an hour and 5s passed on the wall clock, 5s of it awake
+        assert_eq!(
+            detector.observe(start + secs(10), 4_610_000),
+            Some(secs(3600))
+        );
+
+        // the wall clock stepping back isn't a sleep
+        assert_eq!(detector.observe(start + secs(15), 4_000_000), None);
+    }
+}
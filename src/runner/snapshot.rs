@@ -0,0 +1,241 @@
+use std::{
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use bincode::{Decode, Encode};
+use hashes::sha2::sha256;
+
+use crate::{
+    android,
+    fs::write_atomic,
+    signaling::{BINCODE_CONFIG, PeerUpdate},
+    systemd,
+    wg::Key,
+};
+
+const MAGIC: &[u8; 4] = b"wgds";
+const VERSION: u8 = 2;
+const CHECKSUM_LEN: usize = 32;
+
+/// What the runner learned and would take long to learn again, kept
+/// across restarts so a crashed daemon resumes from it instead of
+/// reconverging from scratch. Routes follow from the announcements. The
+/// endpoints peers were last reached at are put back right on startup, so
+/// the mesh reconnects before discovery and signaling are up.
+///
+/// Written whole to a temporary file which then replaces the previous
+/// snapshot, a crash leaves one or the other. Magic, version, the bincode
+/// body and a SHA-256 of it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Latest announcement of every peer, replayed like relayed ones.
+    pub announcements: Vec<PeerUpdate>,
+
+    /// Pinned endpoints and when the pins expire, unix time in
+    /// milliseconds.
+    pub pins: Vec<(Key, SocketAddr, u64)>,
+
+    /// Peers blocked through the control API.
+    pub blocked: Vec<Key>,
+    pub frozen: bool,
+
+    /// Endpoints of the peers with a live session.
+    pub endpoints: Vec<(Key, SocketAddr)>,
+
+    /// Our public endpoint as last discovered.
+    pub public: Option<SocketAddr>,
+}
+
+/// Announcements take the rest of the input for extensions, each is
+/// encoded on its own.
+#[derive(Encode, Decode)]
+struct Body {
+    announcements: Vec<Vec<u8>>,
+    pins: Vec<(Key, SocketAddr, u64)>,
+    blocked: Vec<Key>,
+    frozen: bool,
+    endpoints: Vec<(Key, SocketAddr)>,
+    public: Option<SocketAddr>,
+}
+
+/// Body of version 1 snapshots, without endpoints.
+#[derive(Encode, Decode)]
+struct BodyV1 {
+    announcements: Vec<Vec<u8>>,
+    pins: Vec<(Key, SocketAddr, u64)>,
+    blocked: Vec<Key>,
+    frozen: bool,
+}
+
+impl From<BodyV1> for Body {
+    fn from(body: BodyV1) -> Self {
+        Self {
+            announcements: body.announcements,
+            pins: body.pins,
+            blocked: body.blocked,
+            frozen: body.frozen,
+            endpoints: Vec::new(),
+            public: None,
+        }
+    }
+}
+
+impl Snapshot {
+    /// In the `StateDirectory=` of the unit when there is one.
+    pub fn path(iface: &str) -> PathBuf {
+        systemd::state_directory()
+            .unwrap_or_else(|| android::path("/var/lib/wg-disco"))
+            .join(format!("{iface}.state"))
+    }
+
+    /// `None` when the file is missing, unreadable or corrupted.
+    pub fn load(path: &Path) -> Option<Self> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                log::warn!("can't read state {}: {err}", path.display());
+                return None;
+            }
+        };
+
+        let snapshot = Self::decode(&data);
+        if snapshot.is_none() {
+            log::warn!("state {} is corrupted, starting afresh", path.display());
+        }
+        snapshot
+    }
+
+    /// Replaces the snapshot at `path`.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        write_atomic(path, self.encode(), 0o600)
+    }
+
+    /// Sorted, so equal state encodes equally.
+    pub fn sort(&mut self) {
+        self.announcements.sort_by_key(|peer| *peer.key.as_bytes());
+        self.pins.sort_by_key(|(key, _, _)| *key.as_bytes());
+        self.blocked.sort_by_key(|key| *key.as_bytes());
+        self.endpoints.sort_by_key(|(key, _)| *key.as_bytes());
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+
+        let body = Body {
+            announcements: self
+                .announcements
+                .iter()
+                .filter_map(|peer| bincode::encode_to_vec(peer, BINCODE_CONFIG).ok())
+                .collect(),
+            pins: self.pins.clone(),
+            blocked: self.blocked.clone(),
+            frozen: self.frozen,
+            endpoints: self.endpoints.clone(),
+            public: self.public,
+        };
+        let body = bincode::encode_to_vec(body, BINCODE_CONFIG).unwrap_or_default();
+        data.extend(sha256::hash(&body).into_bytes());
+        data.extend(body);
+        data
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let (&version, rest) = data.strip_prefix(MAGIC)?.split_first()?;
+        let (checksum, body) = rest.split_at_checked(CHECKSUM_LEN)?;
+
+        if sha256::hash(body).into_bytes() != checksum {
+            return None;
+        }
+
+        let body = match version {
+            1 => decode_body::<BodyV1>(body)?.into(),
+            VERSION => decode_body::<Body>(body)?,
+            _ => return None,
+        };
+
+        let announcements = body
+            .announcements
+            .iter()
+            .map(|peer| Some(bincode::decode_from_slice(peer, BINCODE_CONFIG).ok()?.0))
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            announcements,
+            pins: body.pins,
+            blocked: body.blocked,
+            frozen: body.frozen,
+            endpoints: body.endpoints,
+            public: body.public,
+        })
+    }
+}
+
+/// `None` unless `body` is exactly one `T`.
+fn decode_body<T: Decode<()>>(body: &[u8]) -> Option<T> {
+    let (decoded, len) = bincode::decode_from_slice(body, BINCODE_CONFIG).ok()?;
+    (len == body.len()).then_some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use hashes::sha2::sha256;
+
+    use crate::{
+        signaling::{BINCODE_CONFIG, Extensions, PeerUpdate},
+        wg::Key,
+    };
+
+    use super::{BodyV1, Snapshot};
+
+    #[test]
+    fn test_roundtrip() {
+        let key = Key::random();
+        let snapshot = Snapshot {
+            announcements: vec![PeerUpdate {
+                key,
+                endpoint: "203.0.113.7:51820".parse().unwrap(),
+                advertise_routes: vec!["10.1.0.0/24".parse().unwrap()],
+                ext: Extensions {
+                    timestamp: Some(1_700_000_000_000),
+                    ..Default::default()
+                },
+            }],
+            pins: vec![(key, "192.0.2.1:51820".parse().unwrap(), 1_700_000_060_000)],
+            blocked: vec![Key::random()],
+            frozen: true,
+            endpoints: vec![(key, "198.51.100.7:40123".parse().unwrap())],
+            public: Some("203.0.113.1:51820".parse().unwrap()),
+        };
+
+        let mut data = snapshot.encode();
+        assert_eq!(Snapshot::decode(&data), Some(snapshot.clone()));
+
+        // snapshots of the previous version are taken without endpoints
+        let v1 = BodyV1 {
+            announcements: Vec::new(),
+            pins: snapshot.pins.clone(),
+            blocked: snapshot.blocked.clone(),
+            frozen: true,
+        };
+        let body = bincode::encode_to_vec(v1, BINCODE_CONFIG).unwrap();
+        let mut old = b"wgds\x01".to_vec();
+        old.extend(sha256::hash(&body).into_bytes());
+        old.extend(body);
+        let expected = Snapshot {
+            announcements: Vec::new(),
+            endpoints: Vec::new(),
+            public: None,
+            ..snapshot
+        };
+        assert_eq!(Snapshot::decode(&old), Some(expected));
+
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert_eq!(Snapshot::decode(&data), None);
+        assert_eq!(Snapshot::decode(b"wgds"), None);
+    }
+}
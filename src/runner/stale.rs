@@ -0,0 +1,128 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::wg::Key;
+
+/// Longest wait between two requests for the endpoint of a peer which stays
+/// stale, it is probably just offline.
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Watches the latest handshake of every peer and tells which ones went
+/// without one long enough that their endpoint should be asked for again.
+/// A peer staying stale is asked for less and less often.
+#[derive(Debug, Clone)]
+pub struct HandshakeMonitor {
+    timeout: Duration,
+
+    /// When peers without any handshake were first seen, unix ms.
+    missing: HashMap<Key, u64>,
+
+    /// When peers were last asked for and how many times in a row.
+    requested: HashMap<Key, (u64, u32)>,
+}
+
+impl HandshakeMonitor {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            missing: HashMap::new(),
+            requested: HashMap::new(),
+        }
+    }
+
+    /// Peers to re-resolve with their handshake age, out of `handshakes` as
+    /// [`get_handshakes`](crate::wg::WireguardApi::get_handshakes) returns
+    /// them. `now` is unix time in milliseconds.
+    pub fn stale(
+        &mut self,
+        handshakes: &HashMap<Key, Option<u32>>,
+        now: u64,
+    ) -> Vec<(Key, Duration)> {
+        self.missing.retain(|key, _| handshakes.contains_key(key));
+        self.requested.retain(|key, _| handshakes.contains_key(key));
+
+        let mut stale = Vec::new();
+        for (key, handshake) in handshakes {
+            let since = match handshake {
+                Some(secs) => {
+                    self.missing.remove(key);
+                    *secs as u64 * 1000
+                }
+                None => *self.missing.entry(*key).or_insert(now),
+            };
+
+            let age = Duration::from_millis(now.saturating_sub(since));
+            if age <= self.timeout {
+                self.requested.remove(key);
+                continue;
+            }
+
+            let (at, attempts) = self.requested.get(key).copied().unwrap_or_default();
+            let backoff = self
+                .timeout
+                .saturating_mul(1 << attempts.min(16))
+                .min(MAX_BACKOFF);
+
+            if attempts > 0 && Duration::from_millis(now.saturating_sub(at)) < backoff {
+                continue;
+            }
+
+            self.requested.insert(*key, (now, attempts + 1));
+            stale.push((*key, age));
+        }
+
+        stale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use crate::wg::Key;
+
+    use super::HandshakeMonitor;
+
+    #[test]
+    fn test_stale_handshakes() {
+        let (live, gone, new) = (Key::random(), Key::random(), Key::random());
+        let mut monitor = HandshakeMonitor::new(Duration::from_secs(300));
+        let start = 1_700_000_000_000u64;
+        let at = |secs: u64| start + secs * 1000;
+
+        let mut handshakes = HashMap::from([
+            (live, Some((start / 1000) as u32)),
+            (gone, Some((start / 1000 - 600) as u32)),
+            (new, None),
+        ]);
+
+        assert_eq!(
+            monitor.stale(&handshakes, at(0)),
+            vec![(gone, Duration::from_secs(600))]
+        );
+
+        // never handshaked, counted from when it showed up
+        handshakes.insert(live, Some((at(200) / 1000) as u32));
+        assert_eq!(
+            monitor.stale(&handshakes, at(400)),
+            vec![(new, Duration::from_secs(400))]
+        );
+
+        // asked again only after a backoff of twice the timeout
+        assert_eq!(monitor.stale(&handshakes, at(500)), vec![]);
+        handshakes.insert(live, Some((at(500) / 1000) as u32));
+        assert_eq!(
+            monitor.stale(&handshakes, at(601)),
+            vec![(gone, Duration::from_secs(1201))]
+        );
+
+        // a handshake resets it, removed peers are forgotten
+        handshakes.remove(&new);
+        handshakes.insert(gone, Some((at(700) / 1000) as u32));
+        handshakes.insert(live, Some((at(900) / 1000) as u32));
+        assert_eq!(monitor.stale(&handshakes, at(800)), vec![]);
+        assert_eq!(
+            monitor.stale(&handshakes, at(1001)),
+            vec![(gone, Duration::from_secs(301))]
+        );
+    }
+}
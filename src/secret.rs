@@ -0,0 +1,266 @@
+//! Encrypted config values, so configs can be kept in git. A value written
+//! as `enc:<base64>` is decrypted at startup with a key taken from a file,
+//! a systemd credential or the kernel keyring.
+//!
+//! The payload is a 16 byte nonce, the value encrypted with AES-256-CTR
+//! and an HMAC-SHA256 tag over both, both keys derived from the key
+//! material with HMAC.
+
+use std::{fmt, fs, io, path::PathBuf, str::FromStr};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+
+use crate::{
+    crypto::{aes256_ctr, ct_eq, hmac_sha256},
+    error::Error,
+    systemd,
+    wg::config::ParseError,
+};
+
+const PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 32;
+
+/// Name of the systemd credential and of the keyring key.
+const NAME: &str = "wg-disco";
+
+/// Where the key material comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// `file:<path>`
+    File(PathBuf),
+
+    /// `credential:<name>`, passed by systemd `LoadCredential=`.
+    Credential(String),
+
+    /// `keyring:<description>`, a `user` key in the kernel keyring.
+    Keyring(String),
+}
+
+impl Default for KeySource {
+    /// The systemd credential when started with it, the key file next to
+    /// the configs otherwise.
+    fn default() -> Self {
+        match systemd::credential(NAME) {
+            Some(_) => KeySource::Credential(NAME.to_string()),
+            None => KeySource::File(PathBuf::from("/etc/wg-disco/secret.key")),
+        }
+    }
+}
+
+impl FromStr for KeySource {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s.split_once(':').ok_or(ParseError::Expected(':'))?;
+
+        match kind {
+            "file" => Ok(KeySource::File(value.into())),
+            "credential" => Ok(KeySource::Credential(value.to_string())),
+            "keyring" => Ok(KeySource::Keyring(value.to_string())),
+            _ => Err(ParseError::UnexpectedToken),
+        }
+    }
+}
+
+impl fmt::Display for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::File(path) => write!(f, "file:{}", path.display()),
+            KeySource::Credential(name) => write!(f, "credential:{name}"),
+            KeySource::Keyring(description) => write!(f, "keyring:{description}"),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for KeySource {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl KeySource {
+    /// Raw key material, surrounding whitespace dropped.
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let data = match self {
+            KeySource::File(path) => fs::read(path)?,
+            KeySource::Credential(name) => match systemd::credential(name) {
+                Some(path) => fs::read(path)?,
+                None => return Err(io::Error::other("not passed by systemd")),
+            },
+            KeySource::Keyring(description) => keyring::read(description)?,
+        };
+
+        Ok(data.trim_ascii().to_vec())
+    }
+}
+
+/// Keys of one key material.
+pub struct SecretKey {
+    enc: [u8; 32],
+    mac: [u8; 32],
+}
+
+impl SecretKey {
+    pub fn new(material: &[u8]) -> Self {
+        Self {
+            enc: hmac_sha256(material, b"wg-disco secret encryption"),
+            mac: hmac_sha256(material, b"wg-disco secret authentication"),
+        }
+    }
+
+    pub fn load(source: &KeySource) -> Result<Self, Error> {
+        let material = source
+            .read()
+            .map_err(|err| Error::SecretKey(source.clone(), err))?;
+
+        if material.is_empty() {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "empty key");
+            return Err(Error::SecretKey(source.clone(), err));
+        }
+
+        Ok(Self::new(&material))
+    }
+
+    pub fn encrypt(&self, value: &str) -> String {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+
+        let mut payload = nonce.to_vec();
+        payload.extend(value.as_bytes());
+        aes256_ctr(&self.enc, &nonce, &mut payload[NONCE_LEN..]);
+        payload.extend(hmac_sha256(&self.mac, &payload));
+
+        format!("{PREFIX}{}", BASE64_STANDARD.encode(payload))
+    }
+
+    /// Plaintext of an `enc:` value, a corrupted or tampered one or one
+    /// encrypted with another key is an error.
+    pub fn decrypt(&self, value: &str) -> Result<String, Error> {
+        let payload = BASE64_STANDARD.decode(value.strip_prefix(PREFIX).unwrap_or(value))?;
+
+        if payload.len() < NONCE_LEN + TAG_LEN {
+            return Err(Error::SecretMismatch);
+        }
+
+        let (data, tag) = payload.split_at(payload.len() - TAG_LEN);
+        let expected = hmac_sha256(&self.mac, data);
+        if !ct_eq(tag, &expected) {
+            return Err(Error::SecretMismatch);
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let mut plaintext = ciphertext.to_vec();
+        aes256_ctr(&self.enc, nonce.try_into().unwrap(), &mut plaintext);
+
+        String::from_utf8(plaintext).map_err(|_| Error::SecretMismatch)
+    }
+}
+
+#[inline]
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+/// Decrypts the config values given as `enc:`, the key is loaded on the
+/// first one. Plain values are kept as they are.
+pub struct Reveal<'a> {
+    source: &'a KeySource,
+    key: Option<SecretKey>,
+}
+
+impl<'a> Reveal<'a> {
+    pub fn new(source: &'a KeySource) -> Self {
+        Self { source, key: None }
+    }
+
+    pub fn reveal(&mut self, value: &mut String) -> Result<(), Error> {
+        if !is_encrypted(value) {
+            return Ok(());
+        }
+
+        let key = match &mut self.key {
+            Some(key) => key,
+            None => self.key.insert(SecretKey::load(self.source)?),
+        };
+
+        *value = key.decrypt(value)?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod keyring {
+    use std::{ffi::CString, io};
+
+    const KEYCTL_READ: libc::c_long = 11;
+
+    pub fn read(description: &str) -> io::Result<Vec<u8>> {
+        let kind = c"user";
+        let description = CString::new(description).map_err(io::Error::other)?;
+
+        let id = unsafe {
+            libc::syscall(
+                libc::SYS_request_key,
+                kind.as_ptr(),
+                description.as_ptr(),
+                std::ptr::null::<libc::c_char>(),
+                0,
+            )
+        };
+        if id < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; 4096];
+        let len = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_READ,
+                id,
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        buf.truncate((len as usize).min(buf.len()));
+        Ok(buf)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod keyring {
+    use std::io;
+
+    pub fn read(_description: &str) -> io::Result<Vec<u8>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeySource, SecretKey, is_encrypted};
+
+    #[test]
+    fn test_roundtrip() {
+        let key = SecretKey::new(b"correct horse battery staple");
+        let value = "a token long enough to span a few cipher blocks";
+
+        let encrypted = key.encrypt(value);
+        assert!(is_encrypted(&encrypted));
+        assert_ne!(encrypted, key.encrypt(value));
+        assert_eq!(key.decrypt(&encrypted).unwrap(), value);
+
+        let other = SecretKey::new(b"another key");
+        assert!(other.decrypt(&encrypted).is_err());
+
+        assert_eq!(
+            "keyring:wg-disco".parse::<KeySource>().unwrap(),
+            KeySource::Keyring("wg-disco".to_string())
+        );
+        assert!("vault:wg-disco".parse::<KeySource>().is_err());
+    }
+}
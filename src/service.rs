@@ -0,0 +1,290 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ServiceAction {
+    Install,
+    Uninstall,
+    Start,
+    Stop,
+}
+
+/// Manages the daemon for `iface` as a system service: launchd on macOS
+/// and systemd everywhere else. The Windows service control manager only
+/// runs programs which talk to it through a dispatcher, which the daemon
+/// lacks, so there it has to be wrapped.
+pub fn run(action: ServiceAction, iface: &str) -> Result<(), Error> {
+    let exe = std::env::current_exe()?;
+
+    if cfg!(target_os = "macos") {
+        launchd(action, iface, &exe)
+    } else if cfg!(windows) {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "windows services aren't supported, run wg-disco with a service wrapper",
+        )
+        .into())
+    } else {
+        systemd(action, iface, &exe)
+    }
+}
+
+fn launchd(action: ServiceAction, iface: &str, exe: &Path) -> Result<(), Error> {
+    let label = format!("dev.wgdisco.{iface}");
+    let plist = PathBuf::from(format!("/Library/LaunchDaemons/{label}.plist"));
+
+    match action {
+        ServiceAction::Install => {
+            fs::write(&plist, launchd_plist(&label, exe, iface))?;
+            exec("launchctl", &["load", "-w", &plist.to_string_lossy()])
+        }
+        ServiceAction::Uninstall => {
+            exec("launchctl", &["unload", "-w", &plist.to_string_lossy()])?;
+            Ok(fs::remove_file(&plist)?)
+        }
+        ServiceAction::Start => exec("launchctl", &["start", &label]),
+        ServiceAction::Stop => exec("launchctl", &["stop", &label]),
+    }
+}
+
+pub fn launchd_plist(label: &str, exe: &Path, iface: &str) -> String {
+    let (label, exe, iface) = (
+        xml_escape(label),
+        xml_escape(&exe.to_string_lossy()),
+        xml_escape(iface),
+    );
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{exe}</string>
+        <string>{iface}</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardErrorPath</key>
+    <string>/var/log/wg-disco.{iface}.log</string>
+</dict>
+</plist>
+"#
+    )
+}
+
+/// `s` as XML character data.
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn systemd(action: ServiceAction, iface: &str, exe: &Path) -> Result<(), Error> {
+    let unit = PathBuf::from("/etc/systemd/system/wg-disco@.service");
+    let instance = format!("wg-disco@{iface}.service");
+
+    match action {
+        ServiceAction::Install => {
+            fs::write(&unit, systemd_unit(exe))?;
+
+            #[cfg(all(unix, feature = "dbus"))]
+            if Path::new(DBUS_POLICY_DIR).is_dir() {
+                fs::write(dbus_policy_path(), crate::dbus::POLICY)?;
+            }
+
+            exec("systemctl", &["daemon-reload"])?;
+            exec("systemctl", &["enable", &instance])
+        }
+        ServiceAction::Uninstall => {
+            exec("systemctl", &["disable", "--now", &instance])?;
+
+            // shared by all instances, removed with the last one
+            if enabled_instances(Path::new(SYSTEMD_WANTS_DIR)).is_empty() {
+                fs::remove_file(&unit)?;
+                let _ = fs::remove_file(dbus_policy_path());
+            }
+            exec("systemctl", &["daemon-reload"])
+        }
+        ServiceAction::Start => exec("systemctl", &["start", &instance]),
+        ServiceAction::Stop => exec("systemctl", &["stop", &instance]),
+    }
+}
+
+/// Where `systemctl enable` links instances to, see `WantedBy` of
+/// [`systemd_unit`].
+const SYSTEMD_WANTS_DIR: &str = "/etc/systemd/system/multi-user.target.wants";
+
+/// Instances of the unit linked into `wants`, that is enabled.
+fn enabled_instances(wants: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(wants) else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.starts_with("wg-disco@") && name.ends_with(".service"))
+        .collect()
+}
+
+const DBUS_POLICY_DIR: &str = "/etc/dbus-1/system.d";
+
+fn dbus_policy_path() -> PathBuf {
+    Path::new(DBUS_POLICY_DIR).join("dev.wgdisco.Manager1.conf")
+}
+
+pub fn systemd_unit(exe: &Path) -> String {
+    format!(
+        "[Unit]
+Description=wg-disco endpoint discovery for %i
+After=network-online.target wg-quick@%i.service
+Wants=network-online.target
+
+[Service]
+ExecStart={} %i
+Restart=on-failure
+RestartSec=5
+# config errors and a missing interface won't go away by restarting
+RestartPreventExitStatus=3 4
+LoadCredential=%i.conf:/etc/wireguard/%i.conf
+StateDirectory=wg-disco
+
+# Without root, secrets come as credentials: the key of enc: config values
+# and the api token. The api socket can be passed by a wg-disco@.socket.
+#LoadCredential=wg-disco:/etc/wg-disco/secret.key
+#LoadCredential=wg-disco-api-token:/etc/wg-disco/%i.token
+#DynamicUser=yes
+#AmbientCapabilities=CAP_NET_ADMIN CAP_NET_RAW
+#CapabilityBoundingSet=CAP_NET_ADMIN CAP_NET_RAW
+#ProtectSystem=strict
+#ProtectHome=yes
+#PrivateTmp=yes
+#NoNewPrivileges=yes
+
+[Install]
+WantedBy=multi-user.target
+",
+        exe.display()
+    )
+}
+
+fn exec(program: &'static str, args: &[&str]) -> Result<(), Error> {
+    let status = Command::new(program).args(args).status()?;
+
+    if !status.success() {
+        return Err(Error::CommandFail(program, status.code()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{enabled_instances, launchd_plist, systemd_unit};
+
+    #[test]
+    fn test_enabled_instances() {
+        let wants = std::env::temp_dir().join(format!("wg-disco-wants-{}", std::process::id()));
+        assert!(enabled_instances(&wants).is_empty());
+
+        fs::create_dir_all(&wants).unwrap();
+        for name in [
+            "wg-disco@wg1.service",
+            "wg-quick@wg0.service",
+            "wg-disco@.service.d",
+        ] {
+            fs::write(wants.join(name), "").unwrap();
+        }
+        assert_eq!(enabled_instances(&wants), ["wg-disco@wg1.service"]);
+
+        fs::remove_dir_all(&wants).unwrap();
+    }
+
+    #[test]
+    fn test_launchd_plist() {
+        let plist = launchd_plist("dev.wgdisco.wg<0>", Path::new("/opt/R&D/wg-disco"), "wg<0>");
+
+        assert_eq!(
+            plist,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>dev.wgdisco.wg&lt;0&gt;</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>/opt/R&amp;D/wg-disco</string>
+        <string>wg&lt;0&gt;</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardErrorPath</key>
+    <string>/var/log/wg-disco.wg&lt;0&gt;.log</string>
+</dict>
+</plist>
+"#
+        );
+    }
+
+    #[test]
+    fn test_systemd_unit() {
+        let unit = systemd_unit(Path::new("/usr/bin/wg-disco"));
+
+        assert_eq!(
+            unit,
+            "[Unit]
+Description=wg-disco endpoint discovery for %i
+After=network-online.target wg-quick@%i.service
+Wants=network-online.target
+
+[Service]
+ExecStart=/usr/bin/wg-disco %i
+Restart=on-failure
+RestartSec=5
+# config errors and a missing interface won't go away by restarting
+RestartPreventExitStatus=3 4
+LoadCredential=%i.conf:/etc/wireguard/%i.conf
+StateDirectory=wg-disco
+
+# Without root, secrets come as credentials: the key of enc: config values
+# and the api token. The api socket can be passed by a wg-disco@.socket.
+#LoadCredential=wg-disco:/etc/wg-disco/secret.key
+#LoadCredential=wg-disco-api-token:/etc/wg-disco/%i.token
+#DynamicUser=yes
+#AmbientCapabilities=CAP_NET_ADMIN CAP_NET_RAW
+#CapabilityBoundingSet=CAP_NET_ADMIN CAP_NET_RAW
+#ProtectSystem=strict
+#ProtectHome=yes
+#PrivateTmp=yes
+#NoNewPrivileges=yes
+
+[Install]
+WantedBy=multi-user.target
+"
+        );
+    }
+}
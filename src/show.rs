@@ -0,0 +1,448 @@
+//! `wg-disco show`: the peers of an interface as `wg show` lists them,
+//! along with what the daemon knows about them: how they are reached, their
+//! NAT, candidates and presence on the signaling channel. Without a running
+//! daemon only the wireguard state is shown.
+
+use std::{collections::BTreeMap, fmt::Write, net::SocketAddr};
+
+use crate::{
+    json::Value,
+    relay,
+    runner::PEER_DOWN_AFTER,
+    wg::{Key, WgState},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Green,
+    Yellow,
+    Red,
+    Dim,
+}
+
+impl Color {
+    fn code(self) -> &'static str {
+        match self {
+            Color::Green => "32",
+            Color::Yellow => "33",
+            Color::Red => "31",
+            Color::Dim => "2",
+        }
+    }
+}
+
+/// Text of a table cell and how it is colored.
+type Cell = (String, Option<Color>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerRow {
+    pub key: Key,
+
+    /// Alias, or the hostname the peer announced.
+    pub name: Option<String>,
+    pub endpoint: Option<String>,
+
+    /// Unix time, seconds.
+    pub latest_handshake: Option<u64>,
+
+    /// Received and sent bytes.
+    pub transfer: Option<(u64, u64)>,
+
+    /// Transport helper the peer is reached through.
+    pub transport: Option<String>,
+    pub nat_type: Option<String>,
+    pub candidates: Vec<String>,
+
+    /// Presence on the signaling channel, `None` without the daemon.
+    pub online: Option<bool>,
+}
+
+impl PeerRow {
+    fn up(&self, now: u64) -> bool {
+        self.latest_handshake
+            .is_some_and(|at| now.saturating_sub(at) < PEER_DOWN_AFTER.as_secs())
+    }
+
+    /// How packets get to the peer while it is up.
+    fn path(&self, now: u64) -> Option<&str> {
+        if !self.up(now) {
+            return None;
+        }
+
+        let local = self
+            .endpoint
+            .as_ref()
+            .and_then(|e| e.parse::<SocketAddr>().ok())
+            .is_some_and(|e| e.ip().is_loopback());
+
+        Some(match &self.transport {
+            Some(transport) if transport == relay::TRANSPORT => "relayed",
+            Some(transport) => transport,
+            None if local => "helper",
+            None => "direct",
+        })
+    }
+}
+
+/// What `wg-disco show` prints of an interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overview {
+    pub iface: String,
+    pub key: Option<Key>,
+    pub listen_port: Option<u16>,
+
+    /// Our public endpoint and NAT type, as discovered by the daemon.
+    pub endpoint: Option<String>,
+    pub nat_type: Option<String>,
+
+    /// The disco columns come from a running daemon.
+    pub daemon: bool,
+    pub peers: Vec<PeerRow>,
+}
+
+impl Overview {
+    /// From the wireguard state alone.
+    pub fn from_wg(iface: &str, state: &WgState) -> Self {
+        Self {
+            iface: iface.to_string(),
+            key: state.interface.public_key,
+            listen_port: state.interface.listen_port,
+            endpoint: None,
+            nat_type: None,
+            daemon: false,
+            peers: state
+                .peers
+                .iter()
+                .map(|peer| PeerRow {
+                    key: peer.public_key,
+                    name: None,
+                    endpoint: peer.endpoint.as_ref().map(|e| e.to_string()),
+                    latest_handshake: peer.latest_handshake.map(u64::from),
+                    transfer: peer.transfer,
+                    transport: None,
+                    nat_type: None,
+                    candidates: Vec::new(),
+                    online: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// From what the daemon API answers to `/v1/status` and `/v1/peers`,
+    /// `None` if it isn't what is expected.
+    pub fn from_api(status: &Value, peers: &Value) -> Option<Self> {
+        let string = |value: &Value, key| value.get(key)?.as_str().map(str::to_string);
+
+        let peers = peers
+            .as_array()?
+            .iter()
+            .map(|peer| {
+                let rx = peer.get("rx").and_then(Value::as_u64);
+                let tx = peer.get("tx").and_then(Value::as_u64);
+
+                Some(PeerRow {
+                    key: peer.get("key")?.as_str()?.parse().ok()?,
+                    name: string(peer, "hostname"),
+                    endpoint: string(peer, "endpoint"),
+                    latest_handshake: peer.get("latest_handshake").and_then(Value::as_u64),
+                    transfer: rx.zip(tx),
+                    transport: string(peer, "transport"),
+                    nat_type: string(peer, "nat_type"),
+                    candidates: peer
+                        .get("candidates")
+                        .and_then(Value::as_array)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|c| c.as_str().map(str::to_string))
+                        .collect(),
+                    online: peer.get("online").and_then(Value::as_bool),
+                })
+            })
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            iface: string(status, "iface")?,
+            key: string(status, "key").and_then(|key| key.parse().ok()),
+            listen_port: status
+                .get("listen_port")
+                .and_then(Value::as_u64)
+                .and_then(|port| port.try_into().ok()),
+            endpoint: string(status, "endpoint"),
+            nat_type: string(status, "nat_type"),
+            daemon: true,
+            peers,
+        })
+    }
+
+    /// Names peers by their `[alias]` rather than their hostname.
+    pub fn alias(&mut self, aliases: &BTreeMap<String, Key>) {
+        for (name, key) in aliases {
+            for peer in self.peers.iter_mut().filter(|peer| peer.key == *key) {
+                peer.name = Some(name.clone());
+            }
+        }
+    }
+
+    /// Aligned table of the peers under a header about us, colorized with
+    /// ANSI escapes if `color`. `now` is unix time in seconds.
+    pub fn render(&self, now: u64, color: bool) -> String {
+        let mut out = String::new();
+        let paint = |text: &str, c: Option<Color>| match (c, color) {
+            (Some(c), true) => format!("\x1b[{}m{text}\x1b[0m", c.code()),
+            _ => text.to_string(),
+        };
+
+        let _ = writeln!(out, "interface: {}", self.iface);
+        if let Some(key) = &self.key {
+            let _ = writeln!(out, "  public key: {key}");
+        }
+        if let Some(port) = self.listen_port {
+            let _ = writeln!(out, "  listening port: {port}");
+        }
+        if self.daemon {
+            let endpoint = self.endpoint.as_deref().unwrap_or("undiscovered");
+            let _ = match &self.nat_type {
+                Some(nat_type) => writeln!(out, "  endpoint: {endpoint} ({nat_type} NAT)"),
+                None => writeln!(out, "  endpoint: {endpoint}"),
+            };
+        } else {
+            let note = "daemon not reachable, wireguard state only";
+            let _ = writeln!(out, "  {}", paint(note, Some(Color::Yellow)));
+        }
+
+        if self.peers.is_empty() {
+            return out;
+        }
+
+        let unknown = || ("-".to_string(), Some(Color::Dim));
+        let mut columns: Vec<(&str, Vec<Cell>)> = vec![
+            ("PEER", Vec::new()),
+            ("ENDPOINT", Vec::new()),
+            ("HANDSHAKE", Vec::new()),
+            ("TRANSFER", Vec::new()),
+            ("PATH", Vec::new()),
+            ("NAT", Vec::new()),
+            ("SIGNALING", Vec::new()),
+            ("CANDIDATES", Vec::new()),
+        ];
+
+        for peer in &self.peers {
+            let key = peer.key.to_string();
+            let name = match &peer.name {
+                Some(name) => (format!("{name} ({})", &key[..8]), None),
+                None => (key, None),
+            };
+
+            let endpoint = peer.endpoint.clone().map_or_else(unknown, |e| (e, None));
+
+            let handshake = match peer.latest_handshake {
+                Some(at) => {
+                    let c = match peer.up(now) {
+                        true => Color::Green,
+                        false => Color::Yellow,
+                    };
+                    (age(now.saturating_sub(at)), Some(c))
+                }
+                None => ("never".into(), Some(Color::Red)),
+            };
+
+            let transfer = match peer.transfer {
+                Some((rx, tx)) => (format!("{} in, {} out", bytes(rx), bytes(tx)), None),
+                None => unknown(),
+            };
+
+            let path = match peer.path(now) {
+                Some("direct") => ("direct".into(), Some(Color::Green)),
+                Some(path) => (path.to_string(), Some(Color::Yellow)),
+                None => ("down".into(), Some(Color::Red)),
+            };
+
+            let nat = peer.nat_type.clone().map_or_else(unknown, |t| (t, None));
+
+            let signaling = match peer.online {
+                Some(true) => ("online".into(), Some(Color::Green)),
+                Some(false) => ("offline".into(), Some(Color::Dim)),
+                None => unknown(),
+            };
+
+            let candidates = match peer.candidates.is_empty() {
+                true => unknown(),
+                false => (peer.candidates.join(" "), None),
+            };
+
+            let cells = [
+                name, endpoint, handshake, transfer, path, nat, signaling, candidates,
+            ];
+            for ((_, column), cell) in columns.iter_mut().zip(cells) {
+                column.push(cell);
+            }
+        }
+
+        let widths: Vec<usize> = columns
+            .iter()
+            .map(|(header, cells)| {
+                cells
+                    .iter()
+                    .map(|(text, _)| text.chars().count())
+                    .chain([header.len()])
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        let last = columns.len() - 1;
+        out.push('\n');
+        let mut line = |cells: Vec<(&str, Option<Color>)>| {
+            let mut row = String::new();
+            for (i, ((text, c), width)) in cells.into_iter().zip(&widths).enumerate() {
+                row.push_str(&paint(text, c));
+                if i < last {
+                    let pad = width + 2 - text.chars().count();
+                    row.extend(std::iter::repeat_n(' ', pad));
+                }
+            }
+            let _ = writeln!(out, "{}", row.trim_end());
+        };
+
+        line(columns.iter().map(|(header, _)| (*header, None)).collect());
+        for i in 0..self.peers.len() {
+            line(
+                columns
+                    .iter()
+                    .map(|(_, cells)| (cells[i].0.as_str(), cells[i].1))
+                    .collect(),
+            );
+        }
+
+        out
+    }
+}
+
+fn age(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{secs}s ago"),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if n < 1024 {
+        return format!("{n} B");
+    }
+
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.2} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        control::{PeerStatus, Response, Status},
+        discover::nat::NatType,
+        wg::Key,
+    };
+
+    use super::Overview;
+
+    #[test]
+    fn test_render() {
+        let (laptop, phone) = (Key::random(), Key::random());
+        let peer = |key| PeerStatus {
+            key,
+            endpoint: None,
+            latest_handshake: None,
+            transfer: None,
+            routes: Vec::new(),
+            nat: true,
+            nat_type: None,
+            server: false,
+            candidates: Vec::new(),
+            online: false,
+            transport: None,
+            pinned_for: None,
+            bandwidth: None,
+            meta: None,
+            exit: false,
+            debug: false,
+            blocked: false,
+        };
+
+        let status = Status {
+            iface: "wg0".into(),
+            key: Key::random(),
+            endpoint: Some("198.51.100.1:51820".parse().unwrap()),
+            listen_port: 51820,
+            nat_type: Some(NatType::PortRestricted),
+            server: false,
+            kill_switch: false,
+            peers: 2,
+            frozen: false,
+            metered: false,
+            advertise_routes: Vec::new(),
+            min_protocol: None,
+            unsupported: Vec::new(),
+            wg_operations: Vec::new(),
+        };
+        let peers = vec![
+            PeerStatus {
+                endpoint: Some("127.0.0.1:40000".parse().unwrap()),
+                latest_handshake: Some(970),
+                transfer: Some((1536, 3 << 20)),
+                transport: Some("relay".into()),
+                nat_type: Some(NatType::Symmetric),
+                candidates: vec![
+                    "203.0.113.7:51820".parse().unwrap(),
+                    "192.168.1.7:51820".parse().unwrap(),
+                ],
+                online: true,
+                ..peer(laptop)
+            },
+            peer(phone),
+        ];
+
+        let status = Response::Status(status).to_json();
+        let peers = Response::Peers(peers).to_json();
+        let mut overview = Overview::from_api(&status, &peers).unwrap();
+        overview.alias(&BTreeMap::from([("laptop".to_string(), laptop)]));
+
+        let laptop = format!("laptop ({})", &laptop.to_string()[..8]);
+        let phone = phone.to_string();
+        let width = phone.len() + 2;
+        let table = overview.render(1000, false);
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(
+            lines[3],
+            "  endpoint: 198.51.100.1:51820 (port-restricted NAT)"
+        );
+        assert_eq!(
+            &lines[5..],
+            [
+                format!(
+                    "{:width$}ENDPOINT         HANDSHAKE  TRANSFER                   PATH     NAT        SIGNALING  CANDIDATES",
+                    "PEER"
+                ),
+                format!(
+                    "{laptop:width$}127.0.0.1:40000  30s ago    1.50 KiB in, 3.00 MiB out  relayed  symmetric  online     203.0.113.7:51820 192.168.1.7:51820"
+                ),
+                format!(
+                    "{phone:width$}-                never      -                          down     -          offline    -"
+                ),
+            ]
+        );
+
+        let table = overview.render(1000, true);
+        assert!(table.contains("\x1b[33mrelayed\x1b[0m  "));
+        assert!(table.contains("\x1b[31mdown\x1b[0m     "));
+    }
+}
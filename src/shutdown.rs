@@ -0,0 +1,79 @@
+use std::sync::OnceLock;
+
+use tokio::sync::watch;
+
+#[cfg(unix)]
+mod imp {
+    use std::{
+        io,
+        os::{fd::IntoRawFd, unix::net::UnixStream},
+        sync::atomic::{AtomicI32, Ordering},
+    };
+
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn on_signal(sig: libc::c_int) {
+        let fd = PIPE.load(Ordering::Relaxed);
+
+        // only async-signal-safe calls here; the second signal kills us as usual
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+
+            if fd >= 0 {
+                libc::write(fd, [1u8].as_ptr().cast(), 1);
+            }
+        }
+    }
+
+    pub async fn wait() -> io::Result<()> {
+        let (rx, tx) = UnixStream::pair()?;
+        rx.set_nonblocking(true)?;
+        tx.set_nonblocking(true)?;
+
+        let rx = tokio::net::UnixStream::from_std(rx)?;
+        PIPE.store(tx.into_raw_fd(), Ordering::Relaxed);
+
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        unsafe {
+            libc::signal(libc::SIGINT, handler);
+            libc::signal(libc::SIGTERM, handler);
+        }
+
+        rx.readable().await
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    pub async fn wait() -> std::io::Result<()> {
+        std::future::pending().await
+    }
+}
+
+/// Set once a signal arrived, shared by every caller.
+static SIGNALED: OnceLock<watch::Receiver<bool>> = OnceLock::new();
+
+/// Resolves on SIGINT or SIGTERM, so the daemon can undo what it changed
+/// (routes, firewall rules) before exiting. Every caller is woken, the
+/// daemons of several networks may share the process.
+pub async fn signal() {
+    let mut signaled = SIGNALED
+        .get_or_init(|| {
+            let (tx, rx) = watch::channel(false);
+            tokio::spawn(async move {
+                match imp::wait().await {
+                    Ok(()) => _ = tx.send(true),
+                    Err(err) => {
+                        log::error!("can't handle shutdown signals: {err}");
+                        std::future::pending::<()>().await
+                    }
+                }
+            });
+            rx
+        })
+        .clone();
+
+    if signaled.wait_for(|signaled| *signaled).await.is_err() {
+        std::future::pending::<()>().await
+    }
+}
@@ -1,18 +1,627 @@
 use std::net::SocketAddr;
 
-use bincode::{Decode, Encode};
+use bincode::{
+    Decode, Encode,
+    config::{BigEndian, Configuration, Limit, Varint},
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+};
 use futures::Stream;
-use irc::PeerEvent;
 
-use crate::wg::{Cidr, Key};
+use crate::{
+    crypto::{xeddsa_sign, xeddsa_verify},
+    discover::nat::NatType,
+    provision::SignedConfig,
+    wg::{Cidr, Key},
+};
 
+use self::revocation::Revocation;
+
+/// Wire encoding of every signaling payload. Changing it breaks
+/// compatibility with already deployed peers.
+pub const BINCODE_CONFIG: Configuration<BigEndian> = bincode::config::standard().with_big_endian();
+
+/// [`BINCODE_CONFIG`] for decoding what peers send: a length prefix
+/// claiming more than a message of [`codec::MAX_MSG_LEN`] can hold fails
+/// instead of allocating. The limit counts decoded memory, an element takes
+/// at most 32 bytes for each byte of its encoding.
+pub const DECODE_CONFIG: Configuration<BigEndian, Varint, Limit<{ 32 * codec::MAX_MSG_LEN }>> =
+    BINCODE_CONFIG.with_limit();
+
+/// Version of the announcement format, bumped when older peers would
+/// misread what newer ones send. Carried in [`Metadata`].
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Announcement features which can be turned on and older peers can't
+/// follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// Channel re-announcements leaving unchanged fields out.
+    Delta,
+
+    /// Endpoints of additional uplinks as candidates.
+    Endpoints,
+}
+
+impl Feature {
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Delta => "delta",
+            Feature::Endpoints => "endpoints",
+        }
+    }
+
+    /// Lowest protocol version known to understand the feature. Peers
+    /// announcing none predate versioning and are counted out.
+    pub fn protocol(self) -> u16 {
+        match self {
+            Feature::Delta | Feature::Endpoints => 1,
+        }
+    }
+}
+
+/// Longest hostname put into [`Metadata`], announcements have to fit into
+/// one IRC message.
+const MAX_HOSTNAME_LEN: usize = 32;
+
+/// Prefix of signed announcements, so their signatures can't be replayed
+/// as anything else.
+const SIGNATURE_CONTEXT: &[u8] = b"wg-disco announce";
+
+pub mod beacon;
+pub mod codec;
+pub mod delta;
+#[cfg(feature = "dht")]
+pub mod dht;
+pub mod disguise;
+pub mod dns;
+#[cfg(feature = "irc")]
 pub mod irc;
+pub mod multi;
+pub mod registry;
+pub mod revocation;
+pub mod seal;
+pub mod skew;
+pub mod topic;
 
-#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PeerUpdate {
     pub key: Key,
+
+    /// Public (NAT-mapped) endpoint.
     pub endpoint: SocketAddr,
+
     pub advertise_routes: Vec<Cidr>,
+
+    /// Encoded after the fields above only when not empty, so peers
+    /// predating them still decode the message.
+    pub ext: Extensions,
+}
+
+impl Encode for PeerUpdate {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.key.encode(encoder)?;
+        self.endpoint.encode(encoder)?;
+        self.advertise_routes.encode(encoder)?;
+
+        if self.ext != Extensions::default() {
+            self.ext.encode(encoder)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<C> Decode<C> for PeerUpdate {
+    fn decode<D: Decoder<Context = C>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(Self {
+            key: Decode::decode(decoder)?,
+            endpoint: Decode::decode(decoder)?,
+            advertise_routes: Decode::decode(decoder)?,
+            ext: match Extensions::decode(decoder) {
+                Err(DecodeError::UnexpectedEnd { .. }) => Extensions::default(),
+                res => res?,
+            },
+        })
+    }
+}
+
+bincode::impl_borrow_decode!(PeerUpdate);
+
+/// Optional announcement attributes. On the wire it is a list of
+/// `(tag, value)` records, records with unknown tags are skipped.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Extensions {
+    /// Sender has a static public endpoint and answers everybody.
+    pub server: bool,
+
+    /// Announcement of another peer re-broadcast by a server.
+    pub relayed: bool,
+
+    /// Sender just joined and asks for every announcement the recipient
+    /// knows about.
+    pub sync: bool,
+
+    /// Sender is behind NAT and needs keepalives to keep its mapping open.
+    pub nat: bool,
+
+    /// Alternative transports the sender accepts as `(name, endpoint)`,
+    /// for when direct UDP is blocked.
+    pub transports: Vec<(String, String)>,
+
+    /// Random bytes hiding the message size, carries no information.
+    pub padding: u16,
+
+    /// Version of the sender's routes, local endpoint and transports, bumped
+    /// whenever one of them changes. Zero when the sender doesn't track it.
+    pub seq: u32,
+
+    /// Fields left out because they are the same as in announcement `seq`.
+    pub delta: Delta,
+
+    /// Address of the recipient the sender completed a handshake with
+    /// after punching.
+    pub punched: Option<SocketAddr>,
+
+    /// Unix time in milliseconds, by the sender's clock, at which the
+    /// sender punches the recipient and asks it to punch back.
+    pub punch_at: Option<u64>,
+
+    /// Other candidate endpoints of the sender, of its other uplinks or
+    /// gathered by discovery, best first. Worth trying when `endpoint`
+    /// doesn't work.
+    pub endpoints: Vec<SocketAddr>,
+
+    /// Local address and wireguard listen port of the sender behind its NAT.
+    pub local_endpoint: Option<SocketAddr>,
+
+    /// Unix time in milliseconds, by the sender's clock, at which the
+    /// announcement was sent. Freshness can't be checked without it.
+    pub timestamp: Option<u64>,
+
+    /// Public IPv6 endpoint of a dual-stack sender, recipients with IPv6
+    /// prefer it over `endpoint` as it needs no NAT traversal.
+    pub endpoint6: Option<SocketAddr>,
+
+    /// How the sender's NAT maps and filters, tells recipients whether
+    /// punching can work or a relay is needed right away.
+    pub nat_type: Option<NatType>,
+
+    /// Tunnel address of the sender, checked against the AllowedIPs the
+    /// recipient has for it.
+    pub address: Option<Cidr>,
+
+    /// Capacity the sender offers for relaying and exit traffic, Mbit/s.
+    /// Only a hint to prefer one exit node or relay over another.
+    pub bandwidth: Option<u32>,
+
+    /// Sender asks the recipient to handshake with its `endpoint` and
+    /// report back with `probed`, to learn if its NAT lets peers in.
+    pub probe: bool,
+
+    /// Endpoint of the recipient the sender probed on request and whether
+    /// the probe was answered.
+    pub probed: Option<(SocketAddr, bool)>,
+
+    /// One of the revocations the sender knows about, each announcement
+    /// carries the next one.
+    pub revoked: Option<Revocation>,
+
+    /// What the sender runs on, for operators and version skew warnings.
+    pub meta: Option<Metadata>,
+
+    /// XEdDSA signature of the sender's wireguard key over the complete
+    /// announcement, see [`PeerUpdate::sign`].
+    pub signature: Option<[u8; 64]>,
+
+    /// Sender asks the recipient, its coordinator, for its client config.
+    pub provision: bool,
+
+    /// Client config the sender coordinates for the recipient, signed on
+    /// its own as it is meant for the recipient only.
+    pub config: Option<SignedConfig>,
+
+    /// Flags and records of newer versions, kept verbatim so their
+    /// signatures still verify and relays pass them on.
+    pub unknown_flags: u8,
+    pub unknown: Vec<(u8, Vec<u8>)>,
+}
+
+/// Descriptive attributes of the sender, nothing depends on them but the
+/// protocol version check.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Metadata {
+    pub hostname: String,
+
+    /// wg-disco version.
+    pub version: String,
+
+    /// `<os>-<arch>`.
+    pub platform: String,
+    pub protocol: u16,
+}
+
+impl Metadata {
+    /// Metadata of this host.
+    pub fn local() -> Self {
+        let mut hostname = hostname().unwrap_or_default();
+        if let Some((at, _)) = hostname.char_indices().nth(MAX_HOSTNAME_LEN) {
+            hostname.truncate(at);
+        }
+
+        Self {
+            hostname,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            platform: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+            protocol: PROTOCOL_VERSION,
+        }
+    }
+
+    /// Only the protocol version, for operators keeping the rest private.
+    pub fn anonymous() -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if res != 0 {
+        return None;
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// Fields omitted from a delta announcement.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Delta {
+    pub routes: bool,
+    pub local_endpoint: bool,
+    pub transports: bool,
+}
+
+impl Delta {
+    const ROUTES: u8 = 1;
+    const LOCAL_ENDPOINT: u8 = 2;
+    const TRANSPORTS: u8 = 4;
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn bits(&self) -> u8 {
+        (self.routes as u8 * Self::ROUTES)
+            | (self.local_endpoint as u8 * Self::LOCAL_ENDPOINT)
+            | (self.transports as u8 * Self::TRANSPORTS)
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        Self {
+            routes: bits & Self::ROUTES != 0,
+            local_endpoint: bits & Self::LOCAL_ENDPOINT != 0,
+            transports: bits & Self::TRANSPORTS != 0,
+        }
+    }
+}
+
+impl Extensions {
+    const FLAGS: u8 = 1;
+    const TRANSPORTS: u8 = 2;
+    const PADDING: u8 = 3;
+    const SEQ: u8 = 4;
+    const DELTA: u8 = 5;
+    const PUNCHED: u8 = 6;
+    const ENDPOINTS: u8 = 7;
+    const ADDRESS: u8 = 8;
+    const BANDWIDTH: u8 = 9;
+    const PROBED: u8 = 10;
+    const REVOKED: u8 = 11;
+    const META: u8 = 12;
+    const SIGNATURE: u8 = 13;
+    const CONFIG: u8 = 14;
+    const ENDPOINT6: u8 = 15;
+    const NAT_TYPE: u8 = 16;
+    const PUNCH_AT: u8 = 17;
+    const LOCAL_ENDPOINT: u8 = 18;
+    const TIMESTAMP: u8 = 19;
+
+    const SERVER: u8 = 1;
+    const RELAYED: u8 = 2;
+    const SYNC: u8 = 4;
+    const NAT: u8 = 8;
+    const PROBE: u8 = 16;
+    const PROVISION: u8 = 32;
+    const KNOWN_FLAGS: u8 = 63;
+}
+
+impl Encode for Extensions {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        let flags = (self.server as u8 * Self::SERVER)
+            | (self.relayed as u8 * Self::RELAYED)
+            | (self.sync as u8 * Self::SYNC)
+            | (self.nat as u8 * Self::NAT)
+            | (self.probe as u8 * Self::PROBE)
+            | (self.provision as u8 * Self::PROVISION)
+            | (self.unknown_flags & !Self::KNOWN_FLAGS);
+        let mut records: Vec<(u8, Vec<u8>)> = vec![(Self::FLAGS, vec![flags])];
+
+        if !self.transports.is_empty() {
+            let value = bincode::encode_to_vec(&self.transports, BINCODE_CONFIG)?;
+            records.push((Self::TRANSPORTS, value));
+        }
+
+        if self.padding > 0 {
+            let value = (0..self.padding).map(|_| rand::random()).collect();
+            records.push((Self::PADDING, value));
+        }
+
+        if self.seq != 0 {
+            records.push((Self::SEQ, self.seq.to_be_bytes().to_vec()));
+        }
+
+        if !self.delta.is_empty() {
+            records.push((Self::DELTA, vec![self.delta.bits()]));
+        }
+
+        if let Some(addr) = self.punched {
+            records.push((Self::PUNCHED, bincode::encode_to_vec(addr, BINCODE_CONFIG)?));
+        }
+
+        if !self.endpoints.is_empty() {
+            let value = bincode::encode_to_vec(&self.endpoints, BINCODE_CONFIG)?;
+            records.push((Self::ENDPOINTS, value));
+        }
+
+        if let Some(address) = self.address {
+            let value = bincode::encode_to_vec(address, BINCODE_CONFIG)?;
+            records.push((Self::ADDRESS, value));
+        }
+
+        if let Some(bandwidth) = self.bandwidth {
+            records.push((Self::BANDWIDTH, bandwidth.to_be_bytes().to_vec()));
+        }
+
+        if let Some(probed) = self.probed {
+            records.push((
+                Self::PROBED,
+                bincode::encode_to_vec(probed, BINCODE_CONFIG)?,
+            ));
+        }
+
+        if let Some(revocation) = &self.revoked {
+            records.push((Self::REVOKED, revocation.to_bytes()));
+        }
+
+        if let Some(meta) = &self.meta {
+            records.push((Self::META, bincode::encode_to_vec(meta, BINCODE_CONFIG)?));
+        }
+
+        if let Some(signature) = self.signature {
+            records.push((Self::SIGNATURE, signature.to_vec()));
+        }
+
+        if let Some(config) = &self.config {
+            records.push((
+                Self::CONFIG,
+                bincode::encode_to_vec(config, BINCODE_CONFIG)?,
+            ));
+        }
+
+        if let Some(addr) = self.endpoint6 {
+            records.push((
+                Self::ENDPOINT6,
+                bincode::encode_to_vec(addr, BINCODE_CONFIG)?,
+            ));
+        }
+
+        if let Some(nat_type) = self.nat_type {
+            records.push((Self::NAT_TYPE, vec![nat_type.to_u8()]));
+        }
+
+        if let Some(at) = self.punch_at {
+            records.push((Self::PUNCH_AT, at.to_be_bytes().to_vec()));
+        }
+
+        if let Some(addr) = self.local_endpoint {
+            records.push((
+                Self::LOCAL_ENDPOINT,
+                bincode::encode_to_vec(addr, BINCODE_CONFIG)?,
+            ));
+        }
+
+        if let Some(timestamp) = self.timestamp {
+            records.push((Self::TIMESTAMP, timestamp.to_be_bytes().to_vec()));
+        }
+
+        // ordered by tag whatever the version, signatures cover this
+        records.extend(self.unknown.iter().cloned());
+        records.sort_by_key(|(tag, _)| *tag);
+
+        records.encode(encoder)
+    }
+}
+
+impl<C> Decode<C> for Extensions {
+    fn decode<D: Decoder<Context = C>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let records: Vec<(u8, Vec<u8>)> = Decode::decode(decoder)?;
+        let mut ext = Extensions::default();
+
+        for (tag, value) in records {
+            match (tag, &value[..]) {
+                (Self::FLAGS, [flags, ..]) => {
+                    ext.server = flags & Self::SERVER != 0;
+                    ext.relayed = flags & Self::RELAYED != 0;
+                    ext.sync = flags & Self::SYNC != 0;
+                    ext.nat = flags & Self::NAT != 0;
+                    ext.probe = flags & Self::PROBE != 0;
+                    ext.provision = flags & Self::PROVISION != 0;
+                    ext.unknown_flags = flags & !Self::KNOWN_FLAGS;
+                }
+                (Self::TRANSPORTS, value) => {
+                    if let Ok((transports, _)) = bincode::decode_from_slice(value, DECODE_CONFIG) {
+                        ext.transports = transports;
+                    }
+                }
+                (Self::PADDING, value) => ext.padding = value.len() as u16,
+                (Self::SEQ, &[a, b, c, d, ..]) => ext.seq = u32::from_be_bytes([a, b, c, d]),
+                (Self::DELTA, [bits, ..]) => ext.delta = Delta::from_bits(*bits),
+                (Self::PUNCHED, value) => {
+                    ext.punched = bincode::decode_from_slice(value, DECODE_CONFIG)
+                        .ok()
+                        .map(|(addr, _)| addr);
+                }
+                (Self::ENDPOINTS, value) => {
+                    if let Ok((endpoints, _)) = bincode::decode_from_slice(value, DECODE_CONFIG) {
+                        ext.endpoints = endpoints;
+                    }
+                }
+                (Self::ADDRESS, value) => {
+                    ext.address = bincode::decode_from_slice(value, DECODE_CONFIG)
+                        .ok()
+                        .map(|(address, _)| address);
+                }
+                (Self::BANDWIDTH, &[a, b, c, d, ..]) => {
+                    ext.bandwidth = Some(u32::from_be_bytes([a, b, c, d]));
+                }
+                (Self::PROBED, value) => {
+                    ext.probed = bincode::decode_from_slice(value, DECODE_CONFIG)
+                        .ok()
+                        .map(|(probed, _)| probed);
+                }
+                (Self::REVOKED, value) => ext.revoked = Revocation::from_bytes(value),
+                (Self::META, value) => {
+                    ext.meta = bincode::decode_from_slice(value, DECODE_CONFIG)
+                        .ok()
+                        .map(|(meta, _)| meta);
+                }
+                (Self::SIGNATURE, value) => ext.signature = value.try_into().ok(),
+                (Self::CONFIG, value) => {
+                    ext.config = bincode::decode_from_slice(value, DECODE_CONFIG)
+                        .ok()
+                        .map(|(config, _)| config);
+                }
+                (Self::ENDPOINT6, value) => {
+                    ext.endpoint6 = bincode::decode_from_slice(value, DECODE_CONFIG)
+                        .ok()
+                        .map(|(addr, _)| addr);
+                }
+                (Self::NAT_TYPE, [value, ..]) => ext.nat_type = NatType::from_u8(*value),
+                (Self::PUNCH_AT, value) => {
+                    ext.punch_at = value.first_chunk().copied().map(u64::from_be_bytes);
+                }
+                (Self::LOCAL_ENDPOINT, value) => {
+                    ext.local_endpoint = bincode::decode_from_slice(value, DECODE_CONFIG)
+                        .ok()
+                        .map(|(addr, _)| addr);
+                }
+                (Self::TIMESTAMP, value) => {
+                    ext.timestamp = value.first_chunk().copied().map(u64::from_be_bytes);
+                }
+                (tag, value) if tag > Self::TIMESTAMP => ext.unknown.push((tag, value.to_vec())),
+                _ => {}
+            }
+        }
+
+        Ok(ext)
+    }
+}
+
+bincode::impl_borrow_decode!(Extensions);
+
+impl PeerUpdate {
+    /// Every endpoint announced: IPv6, public, the other candidates, then
+    /// the local one, without duplicates.
+    pub fn candidates(&self) -> Vec<SocketAddr> {
+        let mut candidates: Vec<SocketAddr> = Vec::new();
+        let all = self.ext.endpoint6.into_iter().chain([self.endpoint]);
+        for addr in all
+            .chain(self.ext.endpoints.iter().copied())
+            .chain(self.ext.local_endpoint)
+        {
+            if !candidates.contains(&addr) {
+                candidates.push(addr);
+            }
+        }
+        candidates
+    }
+
+    /// Endpoint to reach this peer from a host with `our_public` address:
+    /// peers sharing our public ip are behind the same NAT, so their local
+    /// endpoint is preferred.
+    pub fn endpoint_for(&self, our_public: &SocketAddr) -> SocketAddr {
+        match self.ext.local_endpoint {
+            Some(local) if self.endpoint.ip() == our_public.ip() => local,
+            _ => self.endpoint,
+        }
+    }
+
+    /// Signs the announcement with our wireguard key. The signature covers
+    /// the complete announcement with what relays and deltas change left
+    /// out, so it holds after forwarding and delta completion. A client
+    /// config is signed on its own.
+    pub fn sign(&mut self, private_key: &Key) {
+        let msg = self.signed_message();
+        self.ext.signature = Some(xeddsa_sign(private_key.as_bytes(), &msg, &rand::random()));
+    }
+
+    /// Whether the announcement carries a valid signature of `key`. Deltas
+    /// have to be completed first.
+    pub fn verify(&self) -> bool {
+        let Some(signature) = &self.ext.signature else {
+            return false;
+        };
+
+        xeddsa_verify(self.key.as_bytes(), &self.signed_message(), signature)
+    }
+
+    fn signed_message(&self) -> Vec<u8> {
+        let mut peer = self.clone();
+        peer.ext.relayed = false;
+        peer.ext.sync = false;
+        peer.ext.padding = 0;
+        peer.ext.punched = None;
+        peer.ext.probed = None;
+        peer.ext.revoked = None;
+        peer.ext.delta = Delta::default();
+        peer.ext.signature = None;
+        peer.ext.config = None;
+
+        let mut msg = SIGNATURE_CONTEXT.to_vec();
+        // encoding into a vec only fails on values bincode can't represent
+        msg.extend(bincode::encode_to_vec(&peer, BINCODE_CONFIG).unwrap_or_default());
+        msg
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Request(String, PeerUpdate),
+    Response(String, PeerUpdate),
+
+    /// Announcement of another peer forwarded by a server.
+    Relayed(PeerUpdate),
+
+    /// Server listed in the channel topic, with its nickname unless
+    /// nicknames are random. Carries only the key, endpoint and server flag,
+    /// none of it authenticated.
+    Bootstrap(Option<String>, PeerUpdate),
+
+    /// Peer heard on the LAN at its wireguard endpoint, authenticated by
+    /// its [beacon](beacon::Beacon).
+    Lan(Key, SocketAddr),
 }
 
 // Register
@@ -22,5 +631,5 @@ pub trait Signaling {
     async fn announce(&mut self, peer: PeerUpdate, nick: Option<&str>) -> Result<(), Self::Error>;
     async fn subscribe(
         &mut self,
-    ) -> Result<impl Stream<Item = Result<PeerEvent, Self::Error>>, Self::Error>;
+    ) -> Result<impl Stream<Item = Result<PeerEvent, Self::Error>> + use<Self>, Self::Error>;
 }
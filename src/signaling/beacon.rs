@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use bincode::{Decode, Encode};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, sync::mpsc};
+
+use crate::{
+    clock::Clock,
+    crypto::{hmac_sha256, x25519, x25519_base},
+    error::Error,
+    wg::{Key, WireguardApi},
+};
+
+use super::{BINCODE_CONFIG, DECODE_CONFIG, PeerEvent};
+
+pub const DEFAULT_BEACON_PORT: u16 = 51821;
+
+const BEACON_INTERVAL: Duration = Duration::from_secs(5);
+
+const TAG_LEN: usize = 16;
+
+/// Keeps a beacon within a single unfragmented datagram, ~70 peers.
+const MAX_BEACON_LEN: usize = 1200;
+
+const TAG_CONTEXT: &[u8] = b"wg-disco beacon";
+
+/// LAN broadcast announcement. It is authenticated per recipient: one tag
+/// for every configured peer, keyed by the static X25519 secret the pair
+/// shares, so only our peers can verify it and nobody else can forge it.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+struct Message {
+    key: Key,
+    listen_port: u16,
+    timestamp: u64,
+    tags: Vec<[u8; TAG_LEN]>,
+}
+
+impl Message {
+    fn tag(&self, secret: &[u8; 32]) -> [u8; TAG_LEN] {
+        let mut msg = Vec::with_capacity(TAG_CONTEXT.len() + 32 + 2 + 8);
+        msg.extend_from_slice(TAG_CONTEXT);
+        msg.extend_from_slice(self.key.as_bytes());
+        msg.extend_from_slice(&self.listen_port.to_be_bytes());
+        msg.extend_from_slice(&self.timestamp.to_be_bytes());
+
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&hmac_sha256(secret, &msg)[..TAG_LEN]);
+        tag
+    }
+}
+
+/// Finds peers on the same broadcast domain without any rendezvous server.
+pub struct Beacon {
+    socket: UdpSocket,
+    port: u16,
+    key: Key,
+    secrets: HashMap<Key, [u8; 32]>,
+    last_seen: HashMap<Key, u64>,
+    clock: Clock,
+}
+
+impl Beacon {
+    /// Returns `None` when `private` doesn't belong to `key`, so the
+    /// beacons couldn't be verified by anybody anyway.
+    pub fn bind<'a>(
+        port: u16,
+        key: Key,
+        private: &Key,
+        peers: impl IntoIterator<Item = &'a Key>,
+    ) -> io::Result<Option<Self>> {
+        if x25519_base(private.as_bytes()) != *key.as_bytes() {
+            return Ok(None);
+        }
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+
+        let secrets = peers
+            .into_iter()
+            .map(|peer| (*peer, x25519(private.as_bytes(), peer.as_bytes())))
+            .collect();
+
+        Ok(Some(Self {
+            socket: UdpSocket::from_std(socket.into())?,
+            port,
+            key,
+            secrets,
+            last_seen: HashMap::new(),
+            clock: Clock::system(),
+        }))
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Self {
+        Self { clock, ..self }
+    }
+
+    pub async fn send(&self, listen_port: u16) -> io::Result<()> {
+        let mut msg = Message {
+            key: self.key,
+            listen_port,
+            timestamp: self.clock.unix_ms(),
+            tags: vec![],
+        };
+        msg.tags = self
+            .secrets
+            .values()
+            .map(|secret| msg.tag(secret))
+            .collect();
+
+        let mut buf = [0u8; MAX_BEACON_LEN];
+        let len = bincode::encode_into_slice(&msg, &mut buf, BINCODE_CONFIG)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        self.socket
+            .send_to(&buf[..len], (Ipv4Addr::BROADCAST, self.port))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Waits for the next authentic beacon, returns the peer and its
+    /// wireguard endpoint on the LAN. Cancel safe.
+    pub async fn recv(&mut self) -> io::Result<(Key, SocketAddr)> {
+        let mut buf = [0u8; MAX_BEACON_LEN];
+
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf).await?;
+
+            if let Some(peer) = self.verify(&buf[..len]) {
+                return Ok((peer.key, SocketAddr::new(from.ip(), peer.listen_port)));
+            }
+        }
+    }
+
+    /// Beacons our listen port and passes peers heard on the LAN to the
+    /// runner as [`PeerEvent::Lan`], which applies them like any other
+    /// endpoint. Without the runner the beacons still let peers with one
+    /// reach us. Needs neither discovery nor signaling to work.
+    pub async fn run<W>(
+        mut self,
+        wg: W,
+        iface: String,
+        events: mpsc::Sender<PeerEvent>,
+    ) -> Result<(), Error>
+    where
+        W: WireguardApi,
+        Error: From<W::Error>,
+    {
+        let mut tick = tokio::time::interval(BEACON_INTERVAL);
+        let mut heard = HashMap::new();
+
+        loop {
+            tokio::select! {
+                _ = tick.tick() => {
+                    // the runner may move the port after discovery
+                    let listen_port = wg.get_listen_port(&iface).await?;
+
+                    if let Err(err) = self.send(listen_port).await {
+                        log::warn!("can't send beacon: {err}");
+                    }
+                }
+
+                res = self.recv(), if !events.is_closed() => {
+                    let (key, addr) = res?;
+
+                    if heard.insert(key, addr) != Some(addr) {
+                        let _ = events.send(PeerEvent::Lan(key, addr)).await;
+                    }
+                }
+            }
+        }
+    }
+
+    fn verify(&mut self, bytes: &[u8]) -> Option<Message> {
+        let (msg, _): (Message, _) = bincode::decode_from_slice(bytes, DECODE_CONFIG).ok()?;
+
+        if msg.key == self.key {
+            return None;
+        }
+
+        let expected = msg.tag(self.secrets.get(&msg.key)?);
+        if !msg.tags.contains(&expected) {
+            log::debug!("beacon of {} is not for us", msg.key);
+            return None;
+        }
+
+        // LAN-only nodes may have no synced clock, so timestamps are only
+        // required to grow to reject replays
+        let last = self.last_seen.entry(msg.key).or_default();
+        if msg.timestamp <= *last {
+            return None;
+        }
+        *last = msg.timestamp;
+
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        crypto::{x25519, x25519_base},
+        wg::Key,
+    };
+
+    use super::Message;
+
+    #[test]
+    fn test_tag_is_pairwise() {
+        let (a, b, c) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+        let a_pub = x25519_base(&a);
+
+        let msg = Message {
+            key: Key::from(a_pub),
+            listen_port: 51820,
+            timestamp: 1,
+            tags: vec![],
+        };
+
+        let sent = msg.tag(&x25519(&a, &x25519_base(&b)));
+        assert_eq!(msg.tag(&x25519(&b, &a_pub)), sent);
+        assert_ne!(msg.tag(&x25519(&c, &a_pub)), sent);
+
+        let tampered = Message {
+            listen_port: 1,
+            ..msg.clone()
+        };
+        assert_ne!(tampered.tag(&x25519(&b, &a_pub)), sent);
+    }
+}
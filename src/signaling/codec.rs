@@ -0,0 +1,429 @@
+use base64::{DecodeSliceError, Engine, prelude::BASE64_URL_SAFE};
+use bincode::error::EncodeError;
+
+use crate::{error::Error, wg::Key};
+
+use super::{
+    BINCODE_CONFIG, DECODE_CONFIG, Delta, PeerUpdate,
+    seal::{OVERHEAD, Seal},
+};
+
+/// Longest base64 text sent in one IRC line. Lines are limited to 512
+/// bytes including the `:nick!user@host PRIVMSG #channel :` prefix the
+/// server puts in front and the trailing CRLF.
+pub const MAX_LINE_LEN: usize = 400;
+
+/// Upper bound of a message, what [`MAX_LINE_LEN`] characters of base64
+/// decode to.
+pub const MAX_MSG_LEN: usize = MAX_LINE_LEN / 4 * 3;
+
+/// Upper bound of an announcement before sealing, so it fits into
+/// [`MAX_MSG_LEN`] either way.
+pub const MAX_PLAIN_LEN: usize = MAX_MSG_LEN - OVERHEAD;
+
+/// Drops what matters least from `peer` until it fits into
+/// [`MAX_PLAIN_LEN`] once stamped, signed and sent as a delta: the
+/// revocation first, the metadata next, then the additional endpoints from
+/// the last one. Routes are never dropped, too many of them still fail
+/// with [`Error::MessageTooLong`] when encoding.
+pub fn fit(peer: &mut PeerUpdate) {
+    while encoded_len(peer) > MAX_PLAIN_LEN {
+        if peer.ext.revoked.take().is_some() {
+            continue;
+        }
+        if peer.ext.meta.take().is_some() {
+            continue;
+        }
+        if peer.ext.endpoints.pop().is_none() {
+            break;
+        }
+    }
+}
+
+/// Padding `peer` may still take before it's too long to be sent, sealed
+/// or not.
+pub fn room(peer: &PeerUpdate, sealed: bool) -> usize {
+    let len = match sealed {
+        true => MAX_PLAIN_LEN,
+        false => MAX_MSG_LEN,
+    };
+    let mut peer = peer.clone();
+    peer.ext.padding = 0;
+
+    // the padding record has a tag and a length prefix of its own
+    let used = bincode::encode_to_vec(&peer, BINCODE_CONFIG).map_or(usize::MAX, |buf| buf.len());
+    len.saturating_sub(used.saturating_add(4))
+}
+
+/// Length of `peer` with every field that is set on the way out filled in.
+fn encoded_len(peer: &PeerUpdate) -> usize {
+    let mut peer = peer.clone();
+    peer.ext.padding = 0;
+    peer.ext.seq = peer.ext.seq.max(1);
+    peer.ext.delta = Delta {
+        routes: true,
+        local_endpoint: true,
+        transports: true,
+    };
+    peer.ext.timestamp = Some(0);
+    peer.ext.signature = Some([0; 64]);
+
+    bincode::encode_to_vec(&peer, BINCODE_CONFIG).map_or(usize::MAX, |buf| buf.len())
+}
+
+/// Encodes `peer` appending base64 text to `out`, so the caller can reuse
+/// the same buffer for every announcement. Fails with
+/// [`Error::MessageTooLong`] past [`MAX_MSG_LEN`].
+pub fn encode(peer: &PeerUpdate, out: &mut String) -> Result<(), Error> {
+    let mut buf = [0u8; MAX_MSG_LEN];
+    let len = encode_into(peer, &mut buf)?;
+
+    BASE64_URL_SAFE.encode_string(&buf[..len], out);
+    Ok(())
+}
+
+/// Same as [`encode`], sealed for peer `to` or for the channel.
+pub fn encode_sealed(
+    peer: &PeerUpdate,
+    seal: &Seal,
+    to: Option<&Key>,
+    out: &mut String,
+) -> Result<(), Error> {
+    let mut buf = [0u8; MAX_PLAIN_LEN];
+    let len = encode_into(peer, &mut buf)?;
+
+    BASE64_URL_SAFE.encode_string(seal.seal(to, &buf[..len]), out);
+    Ok(())
+}
+
+fn encode_into(peer: &PeerUpdate, buf: &mut [u8]) -> Result<usize, Error> {
+    bincode::encode_into_slice(peer, buf, BINCODE_CONFIG).map_err(|err| match err {
+        EncodeError::UnexpectedEnd => Error::MessageTooLong,
+        err => Error::from(err),
+    })
+}
+
+/// Decodes a message using a stack buffer, oversized input is rejected
+/// before any decoding happens.
+pub fn decode(msg: &str) -> Result<PeerUpdate, Error> {
+    let mut buf = [0u8; MAX_MSG_LEN];
+    let len = unbase64(msg, &mut buf)?;
+
+    Ok(bincode::decode_from_slice(&buf[..len], DECODE_CONFIG)?.0)
+}
+
+/// Same as [`decode`] for sealed messages from peer `from`, see
+/// [`Seal::open`].
+pub fn decode_sealed(
+    msg: &str,
+    seal: &Seal,
+    from: Option<&Key>,
+) -> Result<(PeerUpdate, Option<Key>), Error> {
+    let mut buf = [0u8; MAX_MSG_LEN];
+    let len = unbase64(msg, &mut buf)?;
+
+    let (plain, opener) = seal.open(from, &buf[..len]).ok_or(Error::SealMismatch)?;
+    Ok((bincode::decode_from_slice(&plain, DECODE_CONFIG)?.0, opener))
+}
+
+fn unbase64(msg: &str, buf: &mut [u8; MAX_MSG_LEN]) -> Result<usize, Error> {
+    if base64::decoded_len_estimate(msg.len()) > MAX_MSG_LEN {
+        return Err(Error::MessageTooLong);
+    }
+
+    BASE64_URL_SAFE
+        .decode_slice(msg, buf)
+        .map_err(|err| match err {
+            DecodeSliceError::DecodeError(err) => Error::from(err),
+            DecodeSliceError::OutputSliceTooSmall => Error::MessageTooLong,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        crypto::x25519_base,
+        discover::nat::NatType,
+        signaling::{
+            BINCODE_CONFIG, Extensions, Metadata, PeerUpdate, disguise, revocation::Revocation,
+            seal::Seal,
+        },
+        wg::{Cidr, Key},
+    };
+
+    use base64::{Engine, prelude::BASE64_URL_SAFE};
+
+    use crate::error::Error;
+
+    use super::{
+        MAX_LINE_LEN, MAX_MSG_LEN, decode, decode_sealed, encode, encode_sealed, fit, room,
+    };
+
+    // Any change here means already deployed peers can't understand us anymore.
+    const GOLDEN_BYTES: &[u8] = &[
+        1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25,
+        26, 27, 28, 29, 30, 31, 32, // key
+        0, 203, 0, 113, 7, 251, 202, 108, // endpoint, v4 tag + ip + varint port
+        1, 0, 10, 0, 0, 0, 8, // advertise_routes, len + v4 tag + ip + mask
+    ];
+
+    const GOLDEN_MSG: &str = "AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyAAywBxB_vKbAEACgAAAAg=";
+
+    fn golden_peer() -> PeerUpdate {
+        PeerUpdate {
+            key: "AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA="
+                .parse()
+                .unwrap(),
+            endpoint: "203.0.113.7:51820".parse().unwrap(),
+            advertise_routes: vec!["10.0.0.0/8".parse::<Cidr>().unwrap()],
+            ext: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_golden_bytes() {
+        let bytes = bincode::encode_to_vec(golden_peer(), BINCODE_CONFIG).unwrap();
+        assert_eq!(bytes, GOLDEN_BYTES);
+
+        let (peer, _): (PeerUpdate, _) =
+            bincode::decode_from_slice(GOLDEN_BYTES, BINCODE_CONFIG).unwrap();
+        assert_eq!(peer, golden_peer());
+    }
+
+    #[test]
+    fn test_golden_msg() {
+        let mut msg = String::new();
+        encode(&golden_peer(), &mut msg).unwrap();
+
+        assert_eq!(msg, GOLDEN_MSG);
+        assert_eq!(decode(GOLDEN_MSG).unwrap(), golden_peer());
+    }
+
+    #[test]
+    fn test_too_long() {
+        // the longest message still fits into a line
+        let mut peer = golden_peer();
+        disguise::pad(&mut peer, MAX_MSG_LEN);
+
+        let mut msg = String::new();
+        encode(&peer, &mut msg).unwrap();
+        assert_eq!(msg.len(), MAX_LINE_LEN);
+
+        peer.advertise_routes = (0..60)
+            .map(|i| format!("10.{i}.0.0/16").parse().unwrap())
+            .collect();
+        assert!(matches!(
+            encode(&peer, &mut String::new()),
+            Err(Error::MessageTooLong)
+        ));
+    }
+
+    #[test]
+    fn test_fit() {
+        let private = Key::random();
+        let loaded = PeerUpdate {
+            key: Key::from(x25519_base(private.as_bytes())),
+            advertise_routes: vec!["10.1.0.0/16".parse().unwrap(); 4],
+            ext: Extensions {
+                server: true,
+                transports: vec![("tcp".into(), "203.0.113.7:443".into())],
+                seq: 7,
+                endpoints: (1..=4)
+                    .map(|i| format!("198.51.100.{i}:51820").parse().unwrap())
+                    .collect(),
+                local_endpoint: Some("192.168.1.10:51820".parse().unwrap()),
+                endpoint6: Some("[2001:db8::7]:51820".parse().unwrap()),
+                address: Some("100.64.0.2/24".parse().unwrap()),
+                bandwidth: Some(100_000),
+                revoked: Some(Revocation::sign(&Key::random(), Key::random(), 1)),
+                meta: Some(Metadata {
+                    hostname: "h".repeat(32),
+                    ..Metadata::local()
+                }),
+                nat_type: Some(NatType::Restricted),
+                timestamp: Some(1_700_000_000_000),
+                ..Default::default()
+            },
+            ..golden_peer()
+        };
+
+        let mut signed = loaded.clone();
+        signed.sign(&private);
+        assert!(matches!(
+            encode(&signed, &mut String::new()),
+            Err(Error::MessageTooLong)
+        ));
+
+        let mut peer = loaded.clone();
+        fit(&mut peer);
+        assert_eq!(peer.ext.revoked, None);
+        assert_eq!(peer.ext.meta, None);
+        assert!(!peer.ext.endpoints.is_empty());
+        assert_eq!(
+            peer.ext.endpoints,
+            loaded.ext.endpoints[..peer.ext.endpoints.len()]
+        );
+
+        peer.sign(&private);
+        encode(&peer, &mut String::new()).unwrap();
+        let seal = Seal::new(&Key::random(), b"psk", []);
+        encode_sealed(&peer, &seal, None, &mut String::new()).unwrap();
+
+        // and the obfuscation padding on top of that
+        peer.ext.padding = room(&peer, true) as u16;
+        encode_sealed(&peer, &seal, None, &mut String::new()).unwrap();
+
+        // nothing to drop from what already fits
+        let mut small = golden_peer();
+        fit(&mut small);
+        assert_eq!(small, golden_peer());
+    }
+
+    #[test]
+    fn test_oversized_length() {
+        // a varint u64 length prefix claiming way more than was sent
+        const HUGE: [u8; 9] = [253, 255, 255, 255, 255, 255, 255, 255, 255];
+        let b64 = |bytes: &[u8]| BASE64_URL_SAFE.encode(bytes);
+
+        let records = [GOLDEN_BYTES, &HUGE].concat();
+        assert!(decode(&b64(&records)).is_err());
+
+        let seal = Seal::new(&Key::random(), b"psk", []);
+        let msg = b64(&seal.seal(None, &records));
+        assert!(decode_sealed(&msg, &seal, None).is_err());
+
+        // [(ENDPOINTS, <huge list>)], the record is skipped
+        let nested = [GOLDEN_BYTES, &[1, 7, 9], &HUGE].concat();
+        let peer = decode(&b64(&nested)).unwrap();
+        assert!(peer.ext.endpoints.is_empty());
+    }
+
+    #[test]
+    fn test_address_extension() {
+        let peer = PeerUpdate {
+            ext: Extensions {
+                address: Some("100.64.0.2/24".parse().unwrap()),
+                bandwidth: Some(250),
+                probe: true,
+                probed: Some(("203.0.113.7:51820".parse().unwrap(), false)),
+                revoked: Some(Revocation::sign(&Key::random(), Key::random(), 1)),
+                meta: Some(Metadata::local()),
+                endpoint6: Some("[2001:db8::7]:51820".parse().unwrap()),
+                local_endpoint: Some("192.168.1.10:51820".parse().unwrap()),
+                timestamp: Some(1_700_000_000_000),
+                nat_type: Some(NatType::Restricted),
+                punch_at: Some(1_700_000_002_000),
+                ..Default::default()
+            },
+            ..golden_peer()
+        };
+
+        let mut msg = String::new();
+        encode(&peer, &mut msg).unwrap();
+        assert_eq!(decode(&msg).unwrap(), peer);
+    }
+
+    #[test]
+    fn test_signed() {
+        let private = Key::random();
+        let mut peer = PeerUpdate {
+            key: Key::from(x25519_base(private.as_bytes())),
+            ..golden_peer()
+        };
+        assert!(!peer.verify());
+
+        peer.sign(&private);
+        let mut msg = String::new();
+        encode(&peer, &mut msg).unwrap();
+        let decoded = decode(&msg).unwrap();
+        assert!(decoded.verify());
+
+        // servers flag what they forward
+        let mut relayed = decoded.clone();
+        relayed.ext.relayed = true;
+        relayed.ext.padding = 16;
+        assert!(relayed.verify());
+
+        // nor asked to punch or probe by somebody else
+        let mut punch = decoded.clone();
+        punch.ext.punch_at = Some(1_700_000_002_000);
+        assert!(!punch.verify());
+
+        let mut probe = decoded.clone();
+        probe.ext.probe = true;
+        assert!(!probe.verify());
+
+        let mut redirected = decoded;
+        redirected.endpoint = "198.51.100.1:51820".parse().unwrap();
+        assert!(!redirected.verify());
+
+        let mut forged = golden_peer();
+        forged.sign(&Key::random());
+        assert!(!forged.verify());
+    }
+
+    #[test]
+    fn test_signed_by_newer_version() {
+        let private = Key::random();
+        let mut peer = PeerUpdate {
+            key: Key::from(x25519_base(private.as_bytes())),
+            ext: Extensions {
+                server: true,
+                unknown_flags: 128,
+                unknown: vec![(200, vec![1, 2, 3]), (42, vec![])],
+                ..Default::default()
+            },
+            ..golden_peer()
+        };
+        peer.sign(&private);
+
+        let mut msg = String::new();
+        encode(&peer, &mut msg).unwrap();
+        let decoded = decode(&msg).unwrap();
+        assert_eq!(decoded.ext.unknown, [(42, vec![]), (200, vec![1, 2, 3])]);
+        assert_eq!(decoded.ext.unknown_flags, 128);
+        assert!(decoded.verify());
+
+        let mut stripped = decoded;
+        stripped.ext.unknown.clear();
+        assert!(!stripped.verify());
+    }
+
+    #[test]
+    fn test_sealed() {
+        let (a, b) = (Key::random(), Key::random());
+        let a_pub = Key::from(x25519_base(a.as_bytes()));
+        let b_pub = Key::from(x25519_base(b.as_bytes()));
+        let (alice, bob) = (
+            Seal::new(&a, b"psk", [&b_pub]),
+            Seal::new(&b, b"psk", [&a_pub]),
+        );
+
+        let mut msg = String::new();
+        encode_sealed(&golden_peer(), &alice, Some(&b_pub), &mut msg).unwrap();
+        assert_eq!(
+            decode_sealed(&msg, &bob, Some(&a_pub)).unwrap(),
+            (golden_peer(), Some(a_pub))
+        );
+        assert!(decode_sealed(GOLDEN_MSG, &bob, Some(&a_pub)).is_err());
+    }
+
+    #[test]
+    fn test_extensions_are_appended() {
+        let peer = PeerUpdate {
+            ext: Extensions {
+                server: true,
+                ..Default::default()
+            },
+            ..golden_peer()
+        };
+
+        let bytes = bincode::encode_to_vec(&peer, BINCODE_CONFIG).unwrap();
+        assert_eq!(&bytes[..GOLDEN_BYTES.len()], GOLDEN_BYTES);
+        assert_eq!(&bytes[GOLDEN_BYTES.len()..], &[1, 1, 1, 1]); // [(FLAGS, [SERVER])]
+
+        let (decoded, _): (PeerUpdate, _) =
+            bincode::decode_from_slice(&bytes, BINCODE_CONFIG).unwrap();
+        assert_eq!(decoded, peer);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use crate::wg::Key;
+
+use super::{Delta, PeerUpdate};
+
+/// Sender side: stamps announcements with the version of the rarely
+/// changing fields and strips them from channel announcements once that
+/// version went out in full.
+#[derive(Debug)]
+pub struct DeltaSender {
+    seq: u32,
+    base: Option<PeerUpdate>,
+    published: bool,
+}
+
+impl Default for DeltaSender {
+    /// Random start, so receivers holding a base from before our restart
+    /// can't mistake it for the current one.
+    fn default() -> Self {
+        Self {
+            seq: rand::random::<u32>().max(1),
+            base: None,
+            published: false,
+        }
+    }
+}
+
+impl DeltaSender {
+    /// `targeted` announcements go to a single listener which may have no
+    /// base yet and are always full.
+    pub fn encode(&mut self, mut update: PeerUpdate, targeted: bool) -> PeerUpdate {
+        let changed = self.base.as_ref().is_none_or(|base| {
+            base.advertise_routes != update.advertise_routes
+                || base.ext.local_endpoint != update.ext.local_endpoint
+                || base.ext.transports != update.ext.transports
+        });
+
+        if changed {
+            if self.base.is_some() {
+                self.seq = self.seq.checked_add(1).unwrap_or(1);
+            }
+
+            self.base = Some(update.clone());
+            self.published = false;
+        }
+
+        update.ext.seq = self.seq;
+
+        if targeted {
+            return update;
+        }
+
+        if !self.published {
+            self.published = true;
+            return update;
+        }
+
+        update.advertise_routes.clear();
+        update.ext.local_endpoint = None;
+        update.ext.transports.clear();
+        update.ext.delta = Delta {
+            routes: true,
+            local_endpoint: true,
+            transports: true,
+        };
+
+        update
+    }
+}
+
+/// Receiver side: remembers the last full announcement of every peer to
+/// fill in the fields deltas leave out.
+#[derive(Debug, Default)]
+pub struct DeltaReceiver {
+    bases: HashMap<Key, PeerUpdate>,
+}
+
+impl DeltaReceiver {
+    /// The complete announcement, `None` for a delta whose base we missed.
+    pub fn complete(&mut self, mut update: PeerUpdate) -> Option<PeerUpdate> {
+        if update.ext.delta.is_empty() {
+            if update.ext.seq != 0 {
+                self.bases.insert(update.key, update.clone());
+            }
+
+            return Some(update);
+        }
+
+        let base = self
+            .bases
+            .get(&update.key)
+            .filter(|base| base.ext.seq == update.ext.seq)?;
+
+        let delta = update.ext.delta;
+        if delta.routes {
+            update.advertise_routes = base.advertise_routes.clone();
+        }
+        if delta.local_endpoint {
+            update.ext.local_endpoint = base.ext.local_endpoint;
+        }
+        if delta.transports {
+            update.ext.transports = base.ext.transports.clone();
+        }
+
+        update.ext.delta = Delta::default();
+        Some(update)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        signaling::{Extensions, PeerUpdate},
+        wg::Key,
+    };
+
+    use super::{DeltaReceiver, DeltaSender};
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let update = PeerUpdate {
+            key: Key::random(),
+            endpoint: "1.2.3.4:51820".parse().unwrap(),
+            advertise_routes: vec!["10.0.0.0/8".parse().unwrap()],
+            ext: Extensions {
+                local_endpoint: Some("192.168.1.2:51820".parse().unwrap()),
+                ..Default::default()
+            },
+        };
+
+        let mut sender = DeltaSender::default();
+        let mut receiver = DeltaReceiver::default();
+
+        let first = sender.encode(update.clone(), false);
+        assert!(first.ext.delta.is_empty());
+
+        let roamed = PeerUpdate {
+            endpoint: "1.2.3.4:40000".parse().unwrap(),
+            ..update.clone()
+        };
+        let delta = sender.encode(roamed.clone(), false);
+        assert!(delta.advertise_routes.is_empty());
+        assert_eq!(delta.ext.seq, first.ext.seq);
+
+        // late listener without the base can't use it
+        assert_eq!(receiver.complete(delta.clone()), None);
+
+        receiver.complete(first.clone()).unwrap();
+        let completed = receiver.complete(delta).unwrap();
+        assert_eq!(completed.advertise_routes, roamed.advertise_routes);
+        assert_eq!(completed.ext.local_endpoint, roamed.ext.local_endpoint);
+        assert_eq!(completed.endpoint, roamed.endpoint);
+
+        // changed routes go out in full under a new version
+        let rerouted = PeerUpdate {
+            advertise_routes: vec![],
+            ..update
+        };
+        assert!(sender.encode(rerouted.clone(), true).ext.delta.is_empty());
+
+        let full = sender.encode(rerouted, false);
+        assert!(full.ext.delta.is_empty());
+        assert_ne!(full.ext.seq, first.ext.seq);
+    }
+}
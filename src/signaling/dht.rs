@@ -0,0 +1,785 @@
+//! Signaling without a server to depend on: announcements are BEP 44
+//! mutable items on the BitTorrent mainline DHT. An item is signed with the
+//! wireguard key of its peer and stored under a hash of it, so peers find
+//! each other by the keys they already know.
+//!
+//! There is no way to address a single peer, targeted announcements are
+//! dropped and everybody reads the one item we publish.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{Stream, future::join_all, stream};
+use hashes::sha1;
+use tokio::{net::UdpSocket, time::Instant};
+
+use crate::{
+    crypto::{xeddsa_public, xeddsa_sign, xeddsa_verify},
+    error::Error,
+    peer_debug,
+    wg::Key,
+};
+
+use super::{BINCODE_CONFIG, DECODE_CONFIG, PeerEvent, PeerUpdate, Signaling, skew::unix_ms};
+
+/// Well known entry points into the DHT.
+pub const BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Separates our items from anything else signed with the same key.
+const SALT: &[u8] = b"wg-disco";
+
+/// Closest nodes an item is stored on and asked for.
+const K: usize = 8;
+
+const MAX_ROUNDS: usize = 8;
+
+/// Answers of a lookup round are waited for this long.
+const ROUND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Items of peers are looked up this often.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Nodes drop items after about two hours.
+const REPUBLISH_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// BEP 44 limit of an item value.
+const MAX_VALUE_LEN: usize = 1000;
+
+/// Nodes which answered lately are kept as entry points next to the
+/// bootstrap nodes.
+const MAX_CONTACTS: usize = 32;
+
+type NodeId = [u8; 20];
+
+pub struct DhtSignaling {
+    dht: Arc<Dht>,
+    peers: Vec<Key>,
+}
+
+impl DhtSignaling {
+    /// Resolves the [`BOOTSTRAP`] nodes, fails when none resolves.
+    pub async fn connect<'a>(
+        private_key: Key,
+        peers: impl IntoIterator<Item = &'a Key>,
+    ) -> Result<Self, Error> {
+        let mut bootstrap = Vec::new();
+        for host in BOOTSTRAP {
+            match tokio::net::lookup_host(host).await {
+                Ok(addrs) => bootstrap.extend(addrs.filter(SocketAddr::is_ipv4)),
+                Err(err) => log::warn!("can't resolve {host}: {err}"),
+            }
+        }
+
+        if bootstrap.is_empty() {
+            return Err(Error::NoSignaling);
+        }
+
+        Ok(Self::with_bootstrap(private_key, peers, bootstrap))
+    }
+
+    pub fn with_bootstrap<'a>(
+        private_key: Key,
+        peers: impl IntoIterator<Item = &'a Key>,
+        bootstrap: Vec<SocketAddr>,
+    ) -> Self {
+        Self {
+            dht: Arc::new(Dht {
+                id: rand::random(),
+                private_key,
+                bootstrap,
+                contacts: Mutex::new(Vec::new()),
+                published: Mutex::new(None),
+            }),
+            peers: peers.into_iter().copied().collect(),
+        }
+    }
+
+    /// Bootstrap nodes, for the kill-switch. Other nodes of the DHT can't
+    /// be known in advance.
+    pub fn servers(&self) -> &[SocketAddr] {
+        &self.dht.bootstrap
+    }
+}
+
+impl Signaling for DhtSignaling {
+    type Error = Error;
+
+    async fn subscribe(
+        &mut self,
+    ) -> Result<impl Stream<Item = Result<PeerEvent, Self::Error>> + use<>, Self::Error> {
+        let state = Poll {
+            dht: self.dht.clone(),
+            peers: self.peers.clone(),
+            seen: HashMap::new(),
+            events: Vec::new(),
+            next: Instant::now(),
+        };
+
+        Ok(stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(event) = state.events.pop() {
+                    return Some((Ok(event), state));
+                }
+
+                tokio::time::sleep_until(state.next).await;
+                state.next = Instant::now() + POLL_INTERVAL;
+                state.poll().await;
+            }
+        }))
+    }
+
+    async fn announce(&mut self, peer: PeerUpdate, nick: Option<&str>) -> Result<(), Self::Error> {
+        if nick.is_some() {
+            return Ok(());
+        }
+
+        log::info!("publishing peer {} {} to the DHT", peer.key, peer.endpoint);
+
+        let value = bincode::encode_to_vec(&peer, BINCODE_CONFIG)?;
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::MessageTooLong);
+        }
+
+        let item = Item::sign(&self.dht.private_key, unix_ms() as i64, value);
+        self.dht.put(&item).await?;
+
+        *self.dht.published.lock().unwrap() = Some((item, Instant::now()));
+        Ok(())
+    }
+}
+
+/// State of the subscription stream.
+struct Poll {
+    dht: Arc<Dht>,
+    peers: Vec<Key>,
+
+    /// Sequence number of the last item of each peer.
+    seen: HashMap<Key, i64>,
+    events: Vec<PeerEvent>,
+    next: Instant,
+}
+
+impl Poll {
+    /// Looks up the items of all peers at once, new ones become events.
+    /// Our own item is published again before nodes drop it.
+    async fn poll(&mut self) {
+        let dht = &self.dht;
+        let found = join_all(self.peers.iter().map(|key| async move {
+            match dht.get(key).await {
+                Ok(item) => (*key, item),
+                Err(err) => {
+                    peer_debug!(*key, "DHT lookup of {key} failed: {err}");
+                    (*key, None)
+                }
+            }
+        }))
+        .await;
+
+        for (key, item) in found {
+            let Some(item) = item else {
+                continue;
+            };
+
+            if self.seen.get(&key).is_some_and(|&seq| seq >= item.seq) {
+                continue;
+            }
+            self.seen.insert(key, item.seq);
+
+            let update =
+                match bincode::decode_from_slice::<PeerUpdate, _>(&item.value, DECODE_CONFIG) {
+                    Ok((update, _)) if update.key == key => update,
+                    _ => {
+                        log::warn!("DHT item of {key} doesn't hold its announcement, dropping");
+                        continue;
+                    }
+                };
+
+            self.events
+                .push(PeerEvent::Response(key.to_string(), update));
+        }
+
+        let due = self
+            .dht
+            .published
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|(_, at)| at.elapsed() >= REPUBLISH_INTERVAL)
+            .map(|(item, _)| item.clone());
+
+        if let Some(item) = due {
+            match self.dht.put(&item).await {
+                Ok(()) => *self.dht.published.lock().unwrap() = Some((item, Instant::now())),
+                Err(err) => log::warn!("can't republish to the DHT: {err}"),
+            }
+        }
+    }
+}
+
+/// A signed mutable item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Item {
+    /// Ed25519 form of the wireguard public key.
+    key: [u8; 32],
+    seq: i64,
+    sig: [u8; 64],
+    value: Vec<u8>,
+}
+
+impl Item {
+    fn sign(private_key: &Key, seq: i64, value: Vec<u8>) -> Self {
+        let public = crate::crypto::x25519_base(private_key.as_bytes());
+        let sig = xeddsa_sign(private_key.as_bytes(), &signed(seq, &value), &rand_bytes());
+
+        Self {
+            key: xeddsa_public(&public),
+            seq,
+            sig,
+            value,
+        }
+    }
+
+    /// Whether `peer` signed it.
+    fn verify(&self, peer: &Key) -> bool {
+        self.key == xeddsa_public(peer.as_bytes())
+            && xeddsa_verify(peer.as_bytes(), &signed(self.seq, &self.value), &self.sig)
+    }
+
+    fn target(&self) -> NodeId {
+        target(&self.key)
+    }
+
+    /// From a `get` response, unverified.
+    fn from_response(r: &Bencode) -> Option<Self> {
+        Some(Self {
+            key: r.get("k")?.bytes()?.try_into().ok()?,
+            seq: r.get("seq")?.int()?,
+            sig: r.get("sig")?.bytes()?.try_into().ok()?,
+            value: r.get("v")?.bytes()?.to_vec(),
+        })
+    }
+}
+
+fn rand_bytes() -> [u8; 64] {
+    let mut random = [0u8; 64];
+    random[..32].copy_from_slice(&rand::random::<[u8; 32]>());
+    random[32..].copy_from_slice(&rand::random::<[u8; 32]>());
+    random
+}
+
+/// What BEP 44 signs: salt, seq and the bencoded value.
+fn signed(seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    Bencode::from("salt").encode(&mut buf);
+    Bencode::from(SALT).encode(&mut buf);
+    Bencode::from("seq").encode(&mut buf);
+    Bencode::Int(seq).encode(&mut buf);
+    Bencode::from("v").encode(&mut buf);
+    Bencode::from(value).encode(&mut buf);
+    buf
+}
+
+/// Where the items of an Ed25519 key are stored.
+fn target(key: &[u8; 32]) -> NodeId {
+    let mut data = key.to_vec();
+    data.extend(SALT);
+    sha1::hash(&data).into_bytes()
+}
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+struct Dht {
+    id: NodeId,
+    private_key: Key,
+    bootstrap: Vec<SocketAddr>,
+    contacts: Mutex<Vec<SocketAddr>>,
+
+    /// Our last item and when it was stored.
+    published: Mutex<Option<(Item, Instant)>>,
+}
+
+/// Nodes closest to a target which answered, with their write tokens, and
+/// the newest valid item they had.
+#[derive(Debug, Default)]
+struct Lookup {
+    closest: Vec<(SocketAddr, Vec<u8>)>,
+    item: Option<Item>,
+}
+
+impl Dht {
+    /// Newest item of `peer`.
+    async fn get(&self, peer: &Key) -> io::Result<Option<Item>> {
+        let key = xeddsa_public(peer.as_bytes());
+        let lookup = self.lookup(&target(&key), Some(peer)).await?;
+        Ok(lookup.item)
+    }
+
+    /// Stores `item` on the closest nodes, fails when none took it.
+    async fn put(&self, item: &Item) -> io::Result<()> {
+        let lookup = self.lookup(&item.target(), None).await?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+
+        let mut pending = HashMap::new();
+        for (tid, (addr, token)) in lookup.closest.iter().enumerate() {
+            let tid = (tid as u16).to_be_bytes();
+            let args = [
+                ("id", Bencode::from(&self.id[..])),
+                ("k", Bencode::from(&item.key[..])),
+                ("salt", Bencode::from(SALT)),
+                ("seq", Bencode::Int(item.seq)),
+                ("sig", Bencode::from(&item.sig[..])),
+                ("token", Bencode::from(&token[..])),
+                ("v", Bencode::from(&item.value[..])),
+            ];
+
+            socket.send_to(&query(&tid, "put", args), addr).await?;
+            pending.insert(tid.to_vec(), *addr);
+        }
+
+        let mut stored = 0;
+        let deadline = Instant::now() + ROUND_TIMEOUT;
+        receive(&socket, &mut pending, deadline, |_| stored += 1).await;
+
+        match stored {
+            0 => Err(io::Error::other("no DHT node stored the announcement")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Walks towards `target` in rounds, asking the closest nodes not
+    /// asked yet. Items are only accepted when signed by `peer`.
+    async fn lookup(&self, target: &NodeId, peer: Option<&Key>) -> io::Result<Lookup> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+
+        let mut queried = HashSet::new();
+        let mut candidates: BTreeMap<NodeId, SocketAddr> = BTreeMap::new();
+        let mut responders: BTreeMap<NodeId, (SocketAddr, Vec<u8>)> = BTreeMap::new();
+        let mut lookup = Lookup::default();
+        let mut tid: u16 = 0;
+
+        let mut batch: Vec<SocketAddr> = self.contacts.lock().unwrap().clone();
+        batch.extend(&self.bootstrap);
+
+        for _ in 0..MAX_ROUNDS {
+            batch.retain(|addr| queried.insert(*addr));
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut pending = HashMap::new();
+            for addr in batch.drain(..) {
+                tid = tid.wrapping_add(1);
+                let args = [
+                    ("id", Bencode::from(&self.id[..])),
+                    ("target", Bencode::from(&target[..])),
+                ];
+
+                if socket
+                    .send_to(&query(&tid.to_be_bytes(), "get", args), addr)
+                    .await
+                    .is_ok()
+                {
+                    pending.insert(tid.to_be_bytes().to_vec(), addr);
+                }
+            }
+
+            let deadline = Instant::now() + ROUND_TIMEOUT;
+            receive(&socket, &mut pending, deadline, |(addr, r)| {
+                if let Some(id) = r.get("id").and_then(Bencode::bytes)
+                    && let Ok(id) = NodeId::try_from(id)
+                    && let Some(token) = r.get("token").and_then(Bencode::bytes)
+                {
+                    responders.insert(distance(&id, target), (addr, token.to_vec()));
+                }
+
+                for (id, addr) in r
+                    .get("nodes")
+                    .and_then(Bencode::bytes)
+                    .map(nodes)
+                    .unwrap_or_default()
+                {
+                    if !queried.contains(&addr) {
+                        candidates.insert(distance(&id, target), addr);
+                    }
+                }
+
+                if let (Some(peer), Some(item)) = (peer, Item::from_response(r))
+                    && item.verify(peer)
+                    && lookup
+                        .item
+                        .as_ref()
+                        .is_none_or(|known| known.seq < item.seq)
+                {
+                    lookup.item = Some(item);
+                }
+            })
+            .await;
+
+            // done once nothing unasked is closer than the K closest which
+            // answered
+            let kth = responders.keys().nth(K - 1).copied();
+            batch = candidates
+                .iter()
+                .filter(|(dist, addr)| {
+                    !queried.contains(*addr) && kth.is_none_or(|kth| **dist < kth)
+                })
+                .map(|(_, addr)| *addr)
+                .take(K)
+                .collect();
+        }
+
+        lookup.closest = responders.into_values().take(K).collect();
+
+        let mut contacts = self.contacts.lock().unwrap();
+        for (addr, _) in &lookup.closest {
+            if !contacts.contains(addr) {
+                contacts.insert(0, *addr);
+            }
+        }
+        contacts.truncate(MAX_CONTACTS);
+
+        Ok(lookup)
+    }
+}
+
+/// Passes responses to the queries in `pending` on until all arrived or
+/// `deadline` passes.
+async fn receive(
+    socket: &UdpSocket,
+    pending: &mut HashMap<Vec<u8>, SocketAddr>,
+    deadline: Instant,
+    mut on_response: impl FnMut((SocketAddr, &Bencode)),
+) {
+    let mut buf = [0u8; 1500];
+
+    while !pending.is_empty() {
+        let Ok(Ok((len, _))) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        else {
+            return;
+        };
+
+        let Some(msg) = Bencode::decode(&buf[..len]) else {
+            continue;
+        };
+        let Some(tid) = msg.get("t").and_then(Bencode::bytes) else {
+            continue;
+        };
+        let Some(addr) = pending.remove(tid) else {
+            continue;
+        };
+
+        if let Some(r) = msg.get("r") {
+            on_response((addr, r));
+        }
+    }
+}
+
+fn query<const N: usize>(tid: &[u8], name: &str, args: [(&str, Bencode); N]) -> Vec<u8> {
+    let msg = Bencode::dict([
+        ("a", Bencode::dict(args)),
+        ("q", Bencode::from(name)),
+        ("t", Bencode::from(tid)),
+        ("y", Bencode::from("q")),
+    ]);
+
+    let mut buf = Vec::new();
+    msg.encode(&mut buf);
+    buf
+}
+
+/// Compact node infos: id, IPv4 address and port, 26 bytes each.
+fn nodes(buf: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    buf.chunks_exact(26)
+        .filter_map(|node| {
+            let id = NodeId::try_from(&node[..20]).ok()?;
+            let ip = Ipv4Addr::new(node[20], node[21], node[22], node[23]);
+            let port = u16::from_be_bytes([node[24], node[25]]);
+
+            (!ip.is_unspecified() && port != 0)
+                .then_some((id, SocketAddr::V4(SocketAddrV4::new(ip, port))))
+        })
+        .collect()
+}
+
+/// Deepest nesting decoded, KRPC messages are shallow.
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Bencode {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl From<&[u8]> for Bencode {
+    fn from(bytes: &[u8]) -> Self {
+        Bencode::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<&str> for Bencode {
+    fn from(s: &str) -> Self {
+        Bencode::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl Bencode {
+    fn dict<const N: usize>(entries: [(&str, Bencode); N]) -> Self {
+        Bencode::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    fn get(&self, key: &str) -> Option<&Bencode> {
+        match self {
+            Bencode::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Bencode::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    fn int(&self) -> Option<i64> {
+        match self {
+            Bencode::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Bencode::Int(n) => out.extend(format!("i{n}e").as_bytes()),
+            Bencode::Bytes(bytes) => {
+                out.extend(format!("{}:", bytes.len()).as_bytes());
+                out.extend(bytes);
+            }
+            Bencode::List(items) => {
+                out.push(b'l');
+                items.iter().for_each(|item| item.encode(out));
+                out.push(b'e');
+            }
+            // keys are sorted by the map
+            Bencode::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    Bencode::Bytes(key.clone()).encode(out);
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    /// `None` unless `buf` is exactly one value.
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut pos = 0;
+        let value = Self::parse(buf, &mut pos, 0)?;
+        (pos == buf.len()).then_some(value)
+    }
+
+    fn parse(buf: &[u8], pos: &mut usize, depth: usize) -> Option<Self> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        match *buf.get(*pos)? {
+            b'i' => {
+                let end = *pos + buf[*pos..].iter().position(|&b| b == b'e')?;
+                let n = std::str::from_utf8(&buf[*pos + 1..end])
+                    .ok()?
+                    .parse()
+                    .ok()?;
+                *pos = end + 1;
+                Some(Bencode::Int(n))
+            }
+            b'l' => {
+                *pos += 1;
+                let mut items = Vec::new();
+                while *buf.get(*pos)? != b'e' {
+                    items.push(Self::parse(buf, pos, depth + 1)?);
+                }
+                *pos += 1;
+                Some(Bencode::List(items))
+            }
+            b'd' => {
+                *pos += 1;
+                let mut dict = BTreeMap::new();
+                while *buf.get(*pos)? != b'e' {
+                    let Bencode::Bytes(key) = Self::parse(buf, pos, depth + 1)? else {
+                        return None;
+                    };
+                    dict.insert(key, Self::parse(buf, pos, depth + 1)?);
+                }
+                *pos += 1;
+                Some(Bencode::Dict(dict))
+            }
+            b'0'..=b'9' => {
+                let colon = *pos + buf[*pos..].iter().position(|&b| b == b':')?;
+                let len: usize = std::str::from_utf8(&buf[*pos..colon]).ok()?.parse().ok()?;
+                let bytes = buf.get(colon + 1..colon + 1 + len)?;
+                *pos = colon + 1 + len;
+                Some(Bencode::Bytes(bytes.to_vec()))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr};
+
+    use futures::StreamExt;
+    use tokio::net::UdpSocket;
+
+    use crate::{
+        crypto::x25519_base,
+        signaling::{Extensions, PeerEvent, PeerUpdate, Signaling},
+        wg::Key,
+    };
+
+    use super::{Bencode, DhtSignaling, Item};
+
+    #[test]
+    fn test_bencode() {
+        let msg = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let value = Bencode::decode(msg).unwrap();
+        assert_eq!(value.get("q"), Some(&Bencode::from("ping")));
+        assert_eq!(
+            value
+                .get("a")
+                .and_then(|a| a.get("id"))
+                .and_then(Bencode::bytes),
+            Some(&b"abcdefghij0123456789"[..])
+        );
+
+        let mut buf = Vec::new();
+        value.encode(&mut buf);
+        assert_eq!(buf, msg);
+
+        assert_eq!(
+            Bencode::decode(b"li-3e0:e"),
+            Some(Bencode::List(vec![Bencode::Int(-3), Bencode::from("")]))
+        );
+        assert!(Bencode::decode(b"5:abc").is_none());
+        assert!(Bencode::decode(b"i1ei2e").is_none());
+        assert!(Bencode::decode(&[b'l'; 64]).is_none());
+    }
+
+    #[test]
+    fn test_item() {
+        let private = Key::random();
+        let public = Key::from(x25519_base(private.as_bytes()));
+
+        let item = Item::sign(&private, 7, b"announcement".to_vec());
+        assert!(item.verify(&public));
+        assert!(!item.verify(&Key::random()));
+
+        let forged = Item { seq: 8, ..item };
+        assert!(!forged.verify(&public));
+    }
+
+    /// A single DHT node storing whatever is put, without checking tokens
+    /// or signatures.
+    async fn node() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut items: HashMap<Vec<u8>, Bencode> = HashMap::new();
+            let mut buf = [0u8; 1500];
+
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let msg = Bencode::decode(&buf[..len]).unwrap();
+                let args = msg.get("a").unwrap();
+
+                let mut r = Bencode::dict([
+                    ("id", Bencode::from(&[0u8; 20][..])),
+                    ("token", Bencode::from("tok")),
+                ]);
+                match msg.get("q").and_then(Bencode::bytes).unwrap() {
+                    b"put" => {
+                        let mut item = args.clone();
+                        let target = super::target(
+                            args.get("k").unwrap().bytes().unwrap().try_into().unwrap(),
+                        );
+                        if let Bencode::Dict(item) = &mut item {
+                            item.remove(&b"id"[..]);
+                        }
+                        items.insert(target.to_vec(), item);
+                    }
+                    _ => {
+                        let target = args.get("target").unwrap().bytes().unwrap();
+                        if let (Bencode::Dict(r), Some(Bencode::Dict(item))) =
+                            (&mut r, items.get(target))
+                        {
+                            r.extend(item.clone());
+                        }
+                    }
+                }
+
+                let reply = Bencode::dict([
+                    ("r", r),
+                    ("t", msg.get("t").unwrap().clone()),
+                    ("y", Bencode::from("r")),
+                ]);
+                let mut out = Vec::new();
+                reply.encode(&mut out);
+                socket.send_to(&out, from).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_publish_and_poll() {
+        let node = node().await;
+        let (a, b) = (Key::random(), Key::random());
+        let (a_pub, b_pub) = (
+            Key::from(x25519_base(a.as_bytes())),
+            Key::from(x25519_base(b.as_bytes())),
+        );
+
+        let update = PeerUpdate {
+            key: a_pub,
+            endpoint: "203.0.113.7:51820".parse().unwrap(),
+            advertise_routes: Vec::new(),
+            ext: Extensions {
+                timestamp: Some(1),
+                ..Default::default()
+            },
+        };
+
+        let mut alice = DhtSignaling::with_bootstrap(a, [&b_pub], vec![node]);
+        alice.announce(update.clone(), None).await.unwrap();
+
+        let mut bob = DhtSignaling::with_bootstrap(b, [&a_pub], vec![node]);
+        let mut events = Box::pin(bob.subscribe().await.unwrap());
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            PeerEvent::Response(a_pub.to_string(), update)
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! Disguises of signaling messages for networks flagging long base64 blobs
+//! in IRC. `padded` brings every message to the same size, `chat` spells
+//! the base64 text as sentences of made up words, one syllable per
+//! character, split over several lines when it gets long.
+
+use std::collections::HashMap;
+
+use super::{BINCODE_CONFIG, PeerUpdate};
+
+/// Size padded messages are brought to before base64, leaves room in an IRC
+/// line for the prefix the server puts in front.
+pub const PADDED_LEN: usize = 300;
+
+/// Longest chat line, continued lines end with `...`.
+const CHAT_LINE_LEN: usize = 360;
+
+/// Chat lines of one message kept while waiting for the rest.
+const MAX_PENDING_LEN: usize = 4 * CHAT_LINE_LEN;
+
+const CONSONANTS: &[u8; 16] = b"bdfghklmnprstvyz";
+const VOWELS: &[u8; 4] = b"aeio";
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Disguise {
+    /// Plain base64.
+    #[default]
+    None,
+
+    /// Base64 of a fixed size.
+    Padded,
+
+    /// Sentences of made up words.
+    Chat,
+}
+
+impl Disguise {
+    /// Lines to send for the base64 message `msg`.
+    pub fn wrap(self, msg: &str) -> Vec<String> {
+        match self {
+            Disguise::None | Disguise::Padded => vec![msg.to_string()],
+            Disguise::Chat => to_chat(msg),
+        }
+    }
+}
+
+/// Sets the padding of `peer` so it encodes to `len` bytes, when it isn't
+/// that long already.
+pub fn pad(peer: &mut PeerUpdate, len: usize) {
+    peer.ext.padding = 0;
+
+    // the padding record and its length prefix count too
+    for _ in 0..4 {
+        let Ok(encoded) = bincode::encode_to_vec(&*peer, BINCODE_CONFIG) else {
+            return;
+        };
+
+        let padding = (peer.ext.padding as usize + len).saturating_sub(encoded.len());
+        if encoded.len() == len || padding == peer.ext.padding as usize {
+            return;
+        }
+
+        peer.ext.padding = padding.min(u16::MAX as usize) as u16;
+    }
+}
+
+fn to_chat(msg: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut syllables = 0;
+    let mut sentence = 0;
+
+    for c in msg.bytes().filter(|&c| c != b'=') {
+        let Some(index) = BASE64.iter().position(|&b| b == c) else {
+            continue;
+        };
+
+        let (consonant, vowel) = (CONSONANTS[index / 4], VOWELS[index % 4]);
+        match word.is_empty() && sentence == 0 {
+            true => word.push(consonant.to_ascii_uppercase() as char),
+            false => word.push(consonant as char),
+        }
+        word.push(vowel as char);
+        syllables += 1;
+
+        if syllables >= rand::random_range(1..=3) {
+            sentence += 1;
+            if sentence >= rand::random_range(4..=9) {
+                word.push(['.', '.', '?', '!'][rand::random_range(0..4)]);
+                sentence = 0;
+            } else if rand::random_ratio(1, 8) {
+                word.push(',');
+            }
+
+            words.push(std::mem::take(&mut word));
+            syllables = 0;
+        }
+    }
+
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    if let Some(last) = words.last_mut()
+        && !last.ends_with(['.', '?', '!'])
+    {
+        last.truncate(last.trim_end_matches(',').len());
+        last.push('.');
+    }
+
+    let mut lines = vec![String::new()];
+    for word in words {
+        let line = lines.last_mut().unwrap();
+        if !line.is_empty() && line.len() + 1 + word.len() > CHAT_LINE_LEN - 3 {
+            line.push_str("...");
+            lines.push(word);
+            continue;
+        }
+
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&word);
+    }
+
+    lines
+}
+
+fn from_chat(letters: &[u8]) -> Option<String> {
+    if !letters.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let mut msg: String = letters
+        .chunks(2)
+        .map(|pair| {
+            let consonant = CONSONANTS.iter().position(|&c| c == pair[0])?;
+            let vowel = VOWELS.iter().position(|&v| v == pair[1])?;
+            Some(BASE64[consonant * 4 + vowel] as char)
+        })
+        .collect::<Option<_>>()?;
+
+    while !msg.len().is_multiple_of(4) {
+        msg.push('=');
+    }
+
+    Some(msg)
+}
+
+/// Receiving side of a [`Disguise`], puts chat lines of every sender back
+/// together.
+#[derive(Debug, Default)]
+pub struct Unwrap {
+    disguise: Disguise,
+    pending: HashMap<String, Vec<u8>>,
+}
+
+impl Unwrap {
+    pub fn new(disguise: Disguise) -> Self {
+        Self {
+            disguise,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// The base64 message once `line` from `nick` completes one.
+    pub fn push(&mut self, nick: &str, line: &str) -> Option<String> {
+        if self.disguise != Disguise::Chat {
+            return Some(line.to_string());
+        }
+
+        let letters = line
+            .bytes()
+            .filter(u8::is_ascii_alphabetic)
+            .map(|c| c.to_ascii_lowercase());
+
+        let pending = self.pending.entry(nick.to_string()).or_default();
+        pending.extend(letters);
+
+        if line.trim_end().ends_with("...") && pending.len() <= MAX_PENDING_LEN {
+            return None;
+        }
+
+        let letters = self.pending.remove(nick)?;
+        from_chat(&letters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signaling::{BINCODE_CONFIG, Extensions, PeerUpdate, codec};
+
+    use super::{CHAT_LINE_LEN, Disguise, PADDED_LEN, Unwrap, pad};
+
+    fn peer() -> PeerUpdate {
+        PeerUpdate {
+            key: crate::wg::Key::random(),
+            endpoint: "203.0.113.7:51820".parse().unwrap(),
+            advertise_routes: vec!["10.0.0.0/8".parse().unwrap()],
+            ext: Extensions {
+                timestamp: Some(1_700_000_000_000),
+                local_endpoint: Some("192.168.1.10:51820".parse().unwrap()),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_padded() {
+        let mut peer = peer();
+        pad(&mut peer, PADDED_LEN);
+        assert_eq!(
+            bincode::encode_to_vec(&peer, BINCODE_CONFIG).unwrap().len(),
+            PADDED_LEN
+        );
+
+        peer.ext.transports = vec![("tcp".into(), "x".repeat(100))];
+        pad(&mut peer, PADDED_LEN);
+        assert_eq!(
+            bincode::encode_to_vec(&peer, BINCODE_CONFIG).unwrap().len(),
+            PADDED_LEN
+        );
+    }
+
+    #[test]
+    fn test_chat() {
+        let mut peer = peer();
+        pad(&mut peer, PADDED_LEN);
+
+        let mut msg = String::new();
+        codec::encode(&peer, &mut msg).unwrap();
+
+        let lines = Disguise::Chat.wrap(&msg);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= CHAT_LINE_LEN));
+        assert!(
+            lines
+                .iter()
+                .flat_map(|line| line.split(' '))
+                .all(|word| word.trim_end_matches("...").len() <= 7)
+        );
+
+        let mut unwrap = Unwrap::new(Disguise::Chat);
+        let (last, rest) = lines.split_last().unwrap();
+        for line in rest {
+            assert_eq!(unwrap.push("alice", line), None);
+            assert_eq!(unwrap.push("bob", "Hello there."), None);
+        }
+
+        let unwrapped = unwrap.push("alice", last).unwrap();
+        assert_eq!(unwrapped, msg);
+        assert_eq!(codec::decode(&unwrapped).unwrap(), peer);
+    }
+}
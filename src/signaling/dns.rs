@@ -0,0 +1,754 @@
+//! Last resort signaling for networks letting nothing out but DNS: peers
+//! announce through queries for names under a zone whose authoritative
+//! server, [`serve`], cooperates, and read announcements of others from its
+//! TXT answers. Queries go through the system resolver, the server never
+//! needs to be reachable directly.
+//!
+//! An announcement is split over the names of several queries,
+//! `<data>.<i>-<n>-<seq>.<key>.put.<zone>`, with the data and the key in
+//! base32 as resolvers don't keep case. The latest announcement of a peer
+//! is the TXT record of `<nonce>.<key>.get.<zone>`, the nonce keeps
+//! resolvers from answering out of their cache.
+//!
+//! Like on the DHT there is no way to address a single peer, targeted
+//! announcements are dropped.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{Stream, stream};
+use tokio::{net::UdpSocket, time::Instant};
+
+use crate::{error::Error, peer_debug, wg::Key};
+
+use super::{BINCODE_CONFIG, DECODE_CONFIG, PeerEvent, PeerUpdate, Signaling};
+
+const TYPE_TXT: u16 = 16;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION: u16 = 0x0100;
+
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_REFUSED: u16 = 5;
+
+/// Payload size offered with EDNS, safe from fragmentation.
+const UDP_PAYLOAD: u16 = 1232;
+
+/// Longest name on the wire, without the length of the first label.
+const MAX_NAME_LEN: usize = 253;
+
+/// Bytes per data label, 56 characters of base32.
+const LABEL_BYTES: usize = 35;
+
+/// Parts an announcement may be split into.
+const MAX_FRAGMENTS: usize = 32;
+
+/// Peers' announcements are asked for this often.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Our announcement is sent again this often in case the server restarted.
+const REPUBLISH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Resolvers forward queries to the authoritative server, give them time.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Announcements the server keeps, and announcements it reassembles at
+/// once.
+const MAX_STORED: usize = 4096;
+const MAX_PENDING: usize = 1024;
+
+pub struct DnsSignaling {
+    dns: Arc<Dns>,
+    peers: Vec<Key>,
+}
+
+struct Dns {
+    zone: String,
+    resolver: SocketAddr,
+
+    /// Our key, announcements are filed under it.
+    key: Key,
+
+    /// Our last announcement and when it was sent.
+    published: Mutex<Option<(Vec<u8>, Instant)>>,
+}
+
+impl DnsSignaling {
+    /// Queries go to `resolver`, the first nameserver of
+    /// `/etc/resolv.conf` without.
+    pub fn new<'a>(
+        zone: &str,
+        resolver: Option<SocketAddr>,
+        key: Key,
+        peers: impl IntoIterator<Item = &'a Key>,
+    ) -> Result<Self, Error> {
+        let resolver = match resolver {
+            Some(resolver) => resolver,
+            None => system_resolver().ok_or(Error::NoSignaling)?,
+        };
+
+        Ok(Self {
+            dns: Arc::new(Dns {
+                zone: zone.trim_matches('.').to_ascii_lowercase(),
+                resolver,
+                key,
+                published: Mutex::new(None),
+            }),
+            peers: peers.into_iter().copied().collect(),
+        })
+    }
+
+    /// The resolver, for the kill-switch.
+    pub fn server(&self) -> SocketAddr {
+        self.dns.resolver
+    }
+}
+
+impl Signaling for DnsSignaling {
+    type Error = Error;
+
+    async fn subscribe(
+        &mut self,
+    ) -> Result<impl Stream<Item = Result<PeerEvent, Self::Error>> + use<>, Self::Error> {
+        let state = Poll {
+            dns: self.dns.clone(),
+            peers: self.peers.clone(),
+            seen: HashMap::new(),
+            events: Vec::new(),
+            next: Instant::now(),
+        };
+
+        Ok(stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(event) = state.events.pop() {
+                    return Some((Ok(event), state));
+                }
+
+                tokio::time::sleep_until(state.next).await;
+                state.next = Instant::now() + POLL_INTERVAL;
+                state.poll().await;
+            }
+        }))
+    }
+
+    async fn announce(&mut self, peer: PeerUpdate, nick: Option<&str>) -> Result<(), Self::Error> {
+        if nick.is_some() {
+            return Ok(());
+        }
+
+        log::info!("publishing peer {} {} over dns", peer.key, peer.endpoint);
+
+        let value = bincode::encode_to_vec(minimal(peer), BINCODE_CONFIG)?;
+        self.dns.put(&value).await?;
+
+        *self.dns.published.lock().unwrap() = Some((value, Instant::now()));
+        Ok(())
+    }
+}
+
+/// The announcement without what only matters to a recipient it is
+/// addressed to, nor padding, which queries pay for dearly. None of it is
+/// signed.
+fn minimal(mut peer: PeerUpdate) -> PeerUpdate {
+    peer.ext.padding = 0;
+    peer.ext.punched = None;
+    peer.ext.probed = None;
+    peer.ext.revoked = None;
+    peer.ext.config = None;
+    peer
+}
+
+/// State of the subscription stream.
+struct Poll {
+    dns: Arc<Dns>,
+    peers: Vec<Key>,
+
+    /// Timestamp of the last announcement of each peer.
+    seen: HashMap<Key, u64>,
+    events: Vec<PeerEvent>,
+    next: Instant,
+}
+
+impl Poll {
+    /// Asks for the announcements of all peers at once, new ones become
+    /// events. Our own is sent again before a restarted server misses it
+    /// for long.
+    async fn poll(&mut self) {
+        let dns = &self.dns;
+        let found = futures::future::join_all(self.peers.iter().map(|key| async move {
+            match dns.get(key).await {
+                Ok(value) => (*key, value),
+                Err(err) => {
+                    peer_debug!(*key, "dns lookup of {key} failed: {err}");
+                    (*key, None)
+                }
+            }
+        }))
+        .await;
+
+        for (key, value) in found {
+            let Some(value) = value else {
+                continue;
+            };
+
+            let update = match bincode::decode_from_slice::<PeerUpdate, _>(&value, DECODE_CONFIG) {
+                Ok((update, _)) if update.key == key => update,
+                _ => {
+                    log::warn!("dns record of {key} doesn't hold its announcement, dropping");
+                    continue;
+                }
+            };
+
+            let timestamp = update.ext.timestamp.unwrap_or_default();
+            if self.seen.get(&key).is_some_and(|&seen| seen >= timestamp) {
+                continue;
+            }
+            self.seen.insert(key, timestamp);
+
+            self.events
+                .push(PeerEvent::Response(key.to_string(), update));
+        }
+
+        let due = self
+            .dns
+            .published
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|(_, at)| at.elapsed() >= REPUBLISH_INTERVAL)
+            .map(|(value, _)| value.clone());
+
+        if let Some(value) = due {
+            match self.dns.put(&value).await {
+                Ok(()) => *self.dns.published.lock().unwrap() = Some((value, Instant::now())),
+                Err(err) => log::warn!("can't republish over dns: {err}"),
+            }
+        }
+    }
+}
+
+impl Dns {
+    /// Latest announcement of `peer` the server has, encoded.
+    async fn get(&self, peer: &Key) -> io::Result<Option<Vec<u8>>> {
+        let nonce: u32 = rand::random();
+        let name = format!("{nonce:08x}.{}.get.{}", base32(peer.as_bytes()), self.zone);
+
+        Ok(self.query(&name).await?.map(|strings| strings.concat()))
+    }
+
+    /// Sends `value` as our announcement, in as many queries as it takes.
+    async fn put(&self, value: &[u8]) -> Result<(), Error> {
+        let Some(fragments) = fragments(value, &self.zone) else {
+            return Err(Error::MessageTooLong);
+        };
+
+        let seq = super::skew::unix_ms();
+        let key = base32(self.key.as_bytes());
+        let count = fragments.len();
+
+        for (i, fragment) in fragments.iter().enumerate() {
+            let labels: Vec<String> = fragment.chunks(LABEL_BYTES).map(base32).collect();
+            let name = format!(
+                "{}.{i:x}-{count:x}-{seq:x}.{key}.put.{}",
+                labels.join("."),
+                self.zone
+            );
+
+            if self.query(&name).await?.is_none() {
+                return Err(io::Error::other("the dns server didn't take the announcement").into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// TXT strings of `name`, `None` when it doesn't exist.
+    async fn query(&self, name: &str) -> io::Result<Option<Vec<Vec<u8>>>> {
+        let id: u16 = rand::random();
+
+        let bind: SocketAddr = match self.resolver {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let udp = UdpSocket::bind(bind).await?;
+        udp.connect(self.resolver).await?;
+        udp.send(&query(id, name)).await?;
+
+        let mut buf = [0u8; UDP_PAYLOAD as usize];
+        loop {
+            let len = tokio::time::timeout(TIMEOUT, udp.recv(&mut buf))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+            if let Some(res) = parse_response(&buf[..len], id) {
+                return res;
+            }
+        }
+    }
+}
+
+/// First nameserver of `/etc/resolv.conf`.
+fn system_resolver() -> Option<SocketAddr> {
+    let conf = fs::read_to_string("/etc/resolv.conf").ok()?;
+
+    conf.lines().find_map(|line| {
+        let ip = line.trim().strip_prefix("nameserver")?.trim();
+        Some(SocketAddr::new(ip.parse::<IpAddr>().ok()?, 53))
+    })
+}
+
+/// `value` cut into parts fitting a name under `zone`, `None` when it
+/// needs too many.
+fn fragments(value: &[u8], zone: &str) -> Option<Vec<Vec<u8>>> {
+    // key, "put", the longest fragment label and zone, with their dots
+    let fixed = 53 + 4 + 28 + zone.len();
+    let labels = MAX_NAME_LEN.checked_sub(fixed)? / (LABEL_BYTES.div_ceil(5) * 8 + 1);
+    if labels == 0 {
+        return None;
+    }
+
+    let fragments: Vec<Vec<u8>> = value
+        .chunks(labels * LABEL_BYTES)
+        .map(<[u8]>::to_vec)
+        .collect();
+    (fragments.len() <= MAX_FRAGMENTS).then_some(fragments)
+}
+
+fn query(id: u16, name: &str) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(512);
+    msg.extend(id.to_be_bytes());
+    msg.extend(FLAG_RECURSION.to_be_bytes());
+    msg.extend(1u16.to_be_bytes()); // questions
+    msg.extend(0u16.to_be_bytes()); // answers
+    msg.extend(0u16.to_be_bytes()); // authority
+    msg.extend(1u16.to_be_bytes()); // additional
+
+    crate::ddns::push_name(&mut msg, name);
+    msg.extend(TYPE_TXT.to_be_bytes());
+    msg.extend(CLASS_IN.to_be_bytes());
+
+    // EDNS, the answers don't fit 512 bytes
+    msg.push(0);
+    msg.extend(TYPE_OPT.to_be_bytes());
+    msg.extend(UDP_PAYLOAD.to_be_bytes());
+    msg.extend([0u8; 6]);
+
+    msg
+}
+
+/// TXT strings of the answer to query `id`, `None` when it isn't one.
+fn parse_response(msg: &[u8], id: u16) -> Option<io::Result<Option<Vec<Vec<u8>>>>> {
+    if msg.len() < 12 || msg[..2] != id.to_be_bytes() || msg[2] & 0x80 == 0 {
+        return None;
+    }
+
+    let flags = u16::from_be_bytes([msg[2], msg[3]]);
+    match flags & 0x0f {
+        0 => {}
+        RCODE_NXDOMAIN => return Some(Ok(None)),
+        rcode => return Some(Err(io::Error::other(format!("dns answered rcode {rcode}")))),
+    }
+    if flags & FLAG_TRUNCATED != 0 {
+        return Some(Err(io::Error::other("dns answer truncated")));
+    }
+
+    let questions = u16::from_be_bytes([msg[4], msg[5]]);
+    let answers = u16::from_be_bytes([msg[6], msg[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    let mut strings = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let header = msg.get(pos..pos + 10)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let mut rdata = msg.get(pos + 10..pos + 10 + len)?;
+        pos += 10 + len;
+
+        if rtype != TYPE_TXT {
+            continue;
+        }
+        while let [len, rest @ ..] = rdata {
+            strings.push(rest.get(..*len as usize)?.to_vec());
+            rdata = &rest[*len as usize..];
+        }
+    }
+
+    Some(Ok(Some(strings)))
+}
+
+/// Position after the name at `pos`.
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        match *msg.get(pos)? {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Lowercase base32 without padding.
+fn base32(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+
+    for &byte in bytes {
+        buffer = buffer << 8 | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+
+    for c in text.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c.to_ascii_lowercase())?;
+        buffer = buffer << 5 | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+/// Announcements the server has, complete and being reassembled.
+#[derive(Debug, Default)]
+struct Store {
+    /// Latest announcement of each peer with its timestamp.
+    announcements: HashMap<Key, (u64, Vec<u8>)>,
+    pending: HashMap<(Key, u64), Fragments>,
+}
+
+/// Parts of an announcement received so far, and when the first came.
+type Fragments = (Instant, Vec<Option<Vec<u8>>>);
+
+/// Parsed name of a query under the zone.
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    Get(Key),
+    Put {
+        key: Key,
+        seq: u64,
+        index: usize,
+        count: usize,
+        data: Vec<u8>,
+    },
+}
+
+impl Request {
+    /// `None` for names which are neither, resolvers minimizing their
+    /// queries ask for their parents too.
+    fn parse(name: &str) -> Option<Self> {
+        let mut labels: Vec<&str> = name.split('.').collect();
+        let kind = labels.pop()?;
+        let key = Key::from(<[u8; 32]>::try_from(base32_decode(labels.pop()?)?).ok()?);
+
+        match kind {
+            "get" if labels.len() == 1 => Some(Self::Get(key)),
+            "put" => {
+                let mut meta = labels.pop()?.split('-');
+                let index = usize::from_str_radix(meta.next()?, 16).ok()?;
+                let count = usize::from_str_radix(meta.next()?, 16).ok()?;
+                let seq = u64::from_str_radix(meta.next()?, 16).ok()?;
+
+                let mut data = Vec::new();
+                for label in labels {
+                    data.extend(base32_decode(label)?);
+                }
+
+                (index < count && count <= MAX_FRAGMENTS && !data.is_empty()).then_some(Self::Put {
+                    key,
+                    seq,
+                    index,
+                    count,
+                    data,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Store {
+    /// TXT data answering `request`, `None` when the name doesn't exist.
+    fn handle(&mut self, request: Request) -> Result<Option<Vec<u8>>, u16> {
+        let (key, seq, index, count, data) = match request {
+            Request::Get(key) => {
+                return Ok(self.announcements.get(&key).map(|(_, value)| value.clone()));
+            }
+            Request::Put {
+                key,
+                seq,
+                index,
+                count,
+                data,
+            } => (key, seq, index, count, data),
+        };
+
+        if !self.announcements.contains_key(&key) && self.announcements.len() >= MAX_STORED {
+            return Err(RCODE_REFUSED);
+        }
+
+        if self.pending.len() >= MAX_PENDING
+            && !self.pending.contains_key(&(key, seq))
+            && let Some(oldest) = self
+                .pending
+                .iter()
+                .min_by_key(|(_, (at, _))| *at)
+                .map(|(id, _)| *id)
+        {
+            self.pending.remove(&oldest);
+        }
+
+        let (_, fragments) = self
+            .pending
+            .entry((key, seq))
+            .or_insert_with(|| (Instant::now(), vec![None; count]));
+        if fragments.len() != count {
+            return Err(RCODE_REFUSED);
+        }
+        fragments[index] = Some(data);
+
+        if fragments.iter().any(Option::is_none) {
+            return Ok(Some(b"ok".to_vec()));
+        }
+
+        let value: Vec<u8> = fragments.drain(..).flatten().flatten().collect();
+        self.pending.remove(&(key, seq));
+
+        // only what its key signed is passed on
+        let update = match bincode::decode_from_slice::<PeerUpdate, _>(&value, DECODE_CONFIG) {
+            Ok((update, _)) if update.key == key && update.verify() => update,
+            _ => return Err(RCODE_REFUSED),
+        };
+
+        let timestamp = update.ext.timestamp.unwrap_or_default();
+        if self
+            .announcements
+            .get(&key)
+            .is_none_or(|(stored, _)| *stored < timestamp)
+        {
+            log::info!("stored announcement of {key} {}", update.endpoint);
+            self.announcements.insert(key, (timestamp, value));
+        }
+
+        Ok(Some(b"ok".to_vec()))
+    }
+
+    /// Response to the query `msg`, `None` when it isn't one.
+    fn answer(&mut self, msg: &[u8], zone: &str) -> Option<Vec<u8>> {
+        if msg.len() < 12 || msg[2] & 0x80 != 0 || u16::from_be_bytes([msg[4], msg[5]]) != 1 {
+            return None;
+        }
+
+        let mut labels = Vec::new();
+        let mut pos = 12;
+        loop {
+            let len = *msg.get(pos)? as usize;
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            if len > 63 {
+                return None;
+            }
+            labels.push(String::from_utf8_lossy(msg.get(pos..pos + len)?).to_ascii_lowercase());
+            pos += len;
+        }
+        let question = msg.get(12..pos + 4)?;
+        let qtype =
+            u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+        let edns = u16::from_be_bytes([msg[10], msg[11]]) > 0;
+
+        let name = labels.join(".");
+        let sub = match name.strip_suffix(zone) {
+            Some("") => Some(""),
+            Some(sub) => sub.strip_suffix('.'),
+            None => None,
+        };
+
+        let res = match sub.map(Request::parse) {
+            None => Err(RCODE_REFUSED),
+            Some(Some(request)) if qtype == TYPE_TXT => match self.handle(request) {
+                Ok(Some(txt)) => Ok(Some(txt)),
+                Ok(None) => Err(RCODE_NXDOMAIN),
+                Err(rcode) => Err(rcode),
+            },
+            Some(_) => Ok(None),
+        };
+
+        Some(response(msg, question, res, edns))
+    }
+}
+
+/// Response to `msg` echoing its `question`, with the TXT data of `res` as
+/// the answer or its rcode.
+fn response(msg: &[u8], question: &[u8], res: Result<Option<Vec<u8>>, u16>, edns: bool) -> Vec<u8> {
+    let recursion = u16::from_be_bytes([msg[2], msg[3]]) & FLAG_RECURSION;
+    let mut flags = FLAG_RESPONSE | FLAG_AUTHORITATIVE | recursion;
+    let txt = match res {
+        Ok(txt) => txt,
+        Err(rcode) => {
+            flags |= rcode;
+            None
+        }
+    };
+
+    let limit = match edns {
+        true => UDP_PAYLOAD as usize,
+        false => 512,
+    };
+
+    let mut answer = Vec::new();
+    if let Some(txt) = &txt {
+        let rdata: Vec<u8> = txt
+            .chunks(255)
+            .flat_map(|chunk| std::iter::once(chunk.len() as u8).chain(chunk.iter().copied()))
+            .collect();
+
+        answer.extend(0xc00cu16.to_be_bytes());
+        answer.extend(TYPE_TXT.to_be_bytes());
+        answer.extend(CLASS_IN.to_be_bytes());
+        answer.extend(0u32.to_be_bytes()); // not cached
+        answer.extend((rdata.len() as u16).to_be_bytes());
+        answer.extend(rdata);
+    }
+
+    if 12 + question.len() + answer.len() + 11 > limit {
+        flags |= FLAG_TRUNCATED;
+        answer.clear();
+    }
+
+    let mut res = Vec::with_capacity(512);
+    res.extend(&msg[..2]);
+    res.extend(flags.to_be_bytes());
+    res.extend(1u16.to_be_bytes());
+    res.extend((!answer.is_empty() as u16).to_be_bytes());
+    res.extend(0u16.to_be_bytes());
+    res.extend((edns as u16).to_be_bytes());
+    res.extend(question);
+    res.extend(answer);
+
+    if edns {
+        res.push(0);
+        res.extend(TYPE_OPT.to_be_bytes());
+        res.extend(UDP_PAYLOAD.to_be_bytes());
+        res.extend([0u8; 6]);
+    }
+
+    res
+}
+
+/// Authoritative server of `zone` keeping the announcements peers put and
+/// answering those they get.
+pub async fn serve(udp: UdpSocket, zone: &str) -> io::Result<()> {
+    let zone = zone.trim_matches('.').to_ascii_lowercase();
+    let mut store = Store::default();
+    let mut buf = [0u8; 512];
+
+    log::info!("serving signaling for {zone} on {}", udp.local_addr()?);
+    loop {
+        let (len, from) = udp.recv_from(&mut buf).await?;
+        if let Some(res) = store.answer(&buf[..len], &zone) {
+            udp.send_to(&res, from).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::net::UdpSocket;
+
+    use crate::{
+        crypto::x25519_base,
+        signaling::{Extensions, Metadata, PeerEvent, PeerUpdate, Signaling},
+        wg::Key,
+    };
+
+    use super::{DnsSignaling, Request, base32, base32_decode, fragments, serve};
+
+    #[tokio::test]
+    async fn test_announce_and_poll() {
+        assert_eq!(base32(b"wg-disco"), "o5ts2zdjonrw6");
+        assert_eq!(base32_decode("O5TS2ZDJONRW6").unwrap(), b"wg-disco");
+        assert_eq!(Request::parse("x.get"), None);
+
+        let zone = "sig.example.org";
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver = udp.local_addr().unwrap();
+        tokio::spawn(async move { serve(udp, zone).await });
+
+        let private = Key::random();
+        let key = Key::from(x25519_base(private.as_bytes()));
+        let other = Key::random();
+
+        let mut peer = PeerUpdate {
+            key,
+            endpoint: "203.0.113.7:51820".parse().unwrap(),
+            advertise_routes: vec!["10.0.0.0/8".parse().unwrap()],
+            ext: Extensions {
+                timestamp: Some(1_700_000_000_000),
+                endpoints: vec!["198.51.100.4:40000".parse().unwrap()],
+                local_endpoint: Some("192.168.1.10:51820".parse().unwrap()),
+                meta: Some(Metadata::local()),
+                padding: 64,
+                ..Default::default()
+            },
+        };
+        peer.sign(&private);
+        let value = bincode::encode_to_vec(&peer, super::BINCODE_CONFIG).unwrap();
+        assert!(fragments(&value, zone).unwrap().len() > 1);
+
+        let mut ours = DnsSignaling::new(zone, Some(resolver), key, [&other]).unwrap();
+        ours.announce(peer.clone(), None).await.unwrap();
+
+        // unsigned announcements are refused
+        let mut forged = DnsSignaling::new(zone, Some(resolver), other, [&key]).unwrap();
+        let unsigned = PeerUpdate {
+            key: other,
+            ..peer.clone()
+        };
+        assert!(forged.announce(unsigned, None).await.is_err());
+
+        let mut events = Box::pin(forged.subscribe().await.unwrap());
+        match events.next().await.unwrap().unwrap() {
+            PeerEvent::Response(nick, update) => {
+                assert_eq!(nick, key.to_string());
+                assert_eq!(update.endpoint, peer.endpoint);
+                assert_eq!(update.ext.endpoints, peer.ext.endpoints);
+                assert!(update.verify());
+            }
+            event => panic!("unexpected {event:?}"),
+        }
+    }
+}
@@ -1,63 +1,119 @@
-use std::{collections::HashMap, hash::Hash};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use base64::{Engine, prelude::BASE64_URL_SAFE};
-use bincode::config::{BigEndian, Configuration};
-use futures::TryStreamExt;
-use hashes::sha2::sha256;
+use futures::{TryStreamExt, stream};
 use irc::{
-    client::{Client, data::Config},
-    proto::{Command, Prefix},
+    client::{Client, Sender, data::Config},
+    proto::{Command, Message, Prefix, Response},
 };
+use tokio::time::Instant;
 
-use crate::{error::Error, wg::Key};
+use crate::{dial, error::Error, peer_debug, proxy::Proxy, wg::Key};
 
-use super::{PeerUpdate, Signaling};
+use super::{
+    Extensions, PeerEvent, PeerUpdate, Signaling, codec,
+    disguise::{self, Disguise, PADDED_LEN, Unwrap},
+    registry::{NICKNAME_LENGTH, Registry, alternate_nicknames, nickname, username},
+    seal::{self, Seal},
+    topic::Topic,
+};
 
-const BINCODE_CONFIG: Configuration<BigEndian> = bincode::config::standard().with_big_endian();
-const NICKNAME_LENGTH: usize = 12;
+/// Random padding added to obfuscated messages, bytes.
+const MAX_PADDING: u16 = 96;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum PeerEvent {
-    Request(String, PeerUpdate),
-    Response(PeerUpdate),
-}
+/// Random delay before obfuscated messages are sent.
+const MAX_JITTER: Duration = Duration::from_millis(1500);
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct IrcConfig {
     pub server: String,
     pub port: Option<u16>,
-    pub tls: bool,
     pub channel: String,
-}
 
-#[derive(Hash, Clone, Copy, PartialEq, Eq)]
-struct Nickname([u8; NICKNAME_LENGTH]);
+    /// TLS to the server, the port defaults to 6697 then. Needs the `tls`
+    /// feature.
+    pub tls: bool,
+
+    /// Random nickname per session, padded messages and jittered sends,
+    /// so channel observers can't easily track nodes. Identity is only in
+    /// the payload then, every peer of the mesh has to enable it. Only a
+    /// seal or the announcement's signature vouches for it, so one of them
+    /// is required.
+    pub obfuscate: bool,
 
-impl From<[u8; 44]> for Nickname {
-    fn from(username: [u8; 44]) -> Nickname {
-        let mut buf = [0; NICKNAME_LENGTH];
-        buf.copy_from_slice(&username[0..NICKNAME_LENGTH]);
-        Nickname(buf)
-    }
+    /// List ourselves in the channel topic when running as a server. It is
+    /// read for bootstrap data either way. Ignored when obfuscating, the
+    /// topic ties keys to endpoints.
+    pub topic: bool,
+
+    /// How messages look in the channel, every peer of the mesh has to use
+    /// the same.
+    pub disguise: Disguise,
+
+    /// Reach the server through it instead of directly.
+    pub proxy: Option<Proxy>,
+
+    /// NickServ password of our nickname. A ghost of an earlier session
+    /// still holding the nickname is killed with it right after connecting,
+    /// without it the nickname is taken back once the ghost times out.
+    pub nickserv_password: Option<String>,
+
+    /// Recover the nickname with NickServ REGAIN instead of GHOST.
+    pub regain: bool,
 }
 
-impl std::fmt::Debug for Nickname {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("Nickname").field(&self.0).finish()
-    }
+/// Channel topic as last seen and our entry to keep in it.
+#[derive(Debug, Default)]
+struct TopicState {
+    /// `None` until the server tells the topic on join.
+    current: Option<String>,
+    ours: Option<(Key, SocketAddr)>,
+
+    /// Last topic we asked for, not asked again when refused.
+    requested: Option<String>,
 }
 
-impl std::fmt::Display for Nickname {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", str::from_utf8(&self.0).unwrap_or("<err>"))
+impl TopicState {
+    /// Sets the topic when it lacks our entry and is ours to change.
+    fn maintain(&mut self, sender: &Sender, channel: &str) {
+        let (Some(current), Some((key, endpoint))) = (&self.current, self.ours) else {
+            return;
+        };
+
+        let Some(topic) = Topic::maintain(current, key, endpoint) else {
+            return;
+        };
+
+        // without channel operator rights the server refuses it
+        if self.requested.as_ref() == Some(&topic) {
+            return;
+        }
+
+        log::info!("listing ourselves in the topic of {channel}");
+
+        if let Err(err) = sender.send_topic(channel, &topic) {
+            log::warn!("can't set topic of {channel}: {err}");
+        }
+        self.requested = Some(topic);
     }
 }
 
 pub struct IrcSignaling {
-    channel: String,
+    channel: Arc<str>,
+    nickname: Arc<str>,
     client: Client,
-    registry: HashMap<Nickname, Key>,
-    nickname: String,
+    registry: Arc<Registry>,
+    buf: String,
+    obfuscate: bool,
+    disguise: Disguise,
+    topic: Option<Arc<Mutex<TopicState>>>,
+    seal: Option<Arc<Seal>>,
+
+    /// When the last jittered message goes out, see [`MAX_JITTER`].
+    jittered: Instant,
 }
 
 impl IrcSignaling {
@@ -66,24 +122,43 @@ impl IrcSignaling {
         pub_key: Key,
         peers: impl IntoIterator<Item = &Key>,
     ) -> Result<Self, irc::error::Error> {
-        let username = str::from_utf8(&Self::username(&pub_key))
-            .unwrap()
-            .to_string()
-            .replace(['-', '_'], "");
+        let (username, nickname) = if config.obfuscate {
+            let nickname = random_nickname();
+            (nickname.clone(), nickname)
+        } else {
+            (username(&pub_key), nickname(&pub_key))
+        };
+        let registry: Registry = peers.into_iter().collect();
 
-        let nickname = format!("{}", &username[..NICKNAME_LENGTH]);
-        let mut registry = HashMap::new();
-
-        for key in peers {
-            registry.insert(Self::username(key).into(), *key);
-        }
+        // the client connects by itself, it is pointed at a local tunnel
+        let port = config.port.unwrap_or(if config.tls { 6697 } else { 6667 });
+        let upstream = match &config.proxy {
+            Some(proxy) => {
+                log::info!("connecting to {}:{port} through {proxy}", config.server);
+                proxy.connect(&config.server, port).await?
+            }
+            None => dial::connect(&config.server, port).await?,
+        };
+        let local = match config.tls {
+            true => dial::forward(dial::tls(&config.server, upstream).await?).await?,
+            false => dial::forward(upstream).await?,
+        };
 
         let client = Client::from_config(Config {
             username: Some(username),
+            alt_nicks: alternate_nicknames(&nickname),
             nickname: Some(nickname.clone()),
-            server: Some(config.server),
-            port: config.port,
-            use_tls: Some(config.tls),
+            nick_password: config.nickserv_password.clone(),
+            should_ghost: config.nickserv_password.is_some(),
+            ghost_sequence: Some(vec![
+                match config.regain {
+                    true => "REGAIN",
+                    false => "GHOST",
+                }
+                .into(),
+            ]),
+            server: Some(local.ip().to_string()),
+            port: Some(local.port()),
 
             ..Default::default()
         })
@@ -94,34 +169,159 @@ impl IrcSignaling {
 
         Ok(Self {
             client,
-            channel: config.channel,
-            nickname,
-            registry,
+            channel: config.channel.into(),
+            nickname: nickname.into(),
+            registry: Arc::new(registry),
+            buf: String::with_capacity(codec::MAX_MSG_LEN),
+            obfuscate: config.obfuscate,
+            disguise: config.disguise,
+            topic: (config.topic && !config.obfuscate).then(Default::default),
+            seal: None,
+            jittered: Instant::now(),
         })
     }
 
-    #[inline]
-    fn username(key: &Key) -> [u8; 44] {
-        let mut username = [0u8; 44];
-        BASE64_URL_SAFE
-            .encode_slice(sha256::hash(key.as_ref()).into_bytes(), &mut username)
-            .unwrap();
-        username
+    /// Seals every message and drops the ones not sealed. The topic is
+    /// neither maintained nor read then, anybody can set it.
+    pub fn with_seal(mut self, seal: Arc<Seal>) -> Self {
+        self.seal = Some(seal);
+        self.topic = None;
+        self
     }
 
-    #[inline]
-    fn encode_msg(peer: &PeerUpdate) -> Result<String, Error> {
-        Ok(BASE64_URL_SAFE.encode(bincode::encode_to_vec(
-            &peer,
-            bincode::config::standard().with_big_endian(),
-        )?))
+    /// Turns a raw IRC message into a peer event. Messages from nicknames
+    /// missing in the registry are dropped before anything gets decoded,
+    /// unless nicknames are random and only the payload tells who it is.
+    fn peer_event(
+        channel: &str,
+        registry: &Registry,
+        obfuscate: bool,
+        seal: Option<&Seal>,
+        unwrap: &mut Unwrap,
+        msg: Message,
+    ) -> Option<PeerEvent> {
+        let Command::PRIVMSG(target, line) = msg.command else {
+            return None;
+        };
+
+        let Some(Prefix::Nickname(nick, _, _)) = msg.prefix else {
+            return None;
+        };
+
+        let text = unwrap.push(&nick, &line)?;
+
+        let decode = |from: Option<&Key>| match seal {
+            Some(seal) => codec::decode_sealed(&text, seal, from).ok(),
+            None => codec::decode(&text).ok().map(|upd| (upd, None)),
+        };
+
+        let (key, upd) = match registry.key(&nick) {
+            Some(key) => (*key, decode(Some(key))?.0),
+            // the claimed key is checked by the seal or by the runner
+            // against the signature
+            None if obfuscate => {
+                let (upd, opener) = decode(None)?;
+                registry.nickname(&upd.key)?;
+
+                // sealed for us by another peer than it claims to be
+                if opener.is_some_and(|opener| opener != upd.key) {
+                    return None;
+                }
+                (upd.key, upd)
+            }
+            None => return None,
+        };
+        peer_debug!(key, "{nick} sent {text}");
+
+        // any configured peer can claim to forward announcements, only the
+        // original sender's signature vouches for them, whatever
+        // allow_unsigned says
+        if upd.ext.relayed && upd.key != key && registry.nickname(&upd.key).is_some() {
+            if upd.ext.signature.is_none() {
+                log::warn!(
+                    "{nick} relayed unsigned announcement of {}, dropping",
+                    upd.key
+                );
+                return None;
+            }
+            return Some(PeerEvent::Relayed(upd));
+        }
+
+        if upd.key != key {
+            log::warn!("{nick} announced foreign key {}, dropping", upd.key);
+            return None;
+        }
+
+        Some(if target == channel {
+            PeerEvent::Request(nick, upd)
+        } else {
+            PeerEvent::Response(nick, upd)
+        })
+    }
+
+    /// Servers of the mesh listed in the channel topic. Others are dropped
+    /// like their announcements would be.
+    fn bootstrap_events(registry: &Registry, obfuscate: bool, topic: &str) -> Vec<PeerEvent> {
+        let Some(topic) = Topic::parse(topic) else {
+            return Vec::new();
+        };
+
+        topic
+            .servers
+            .into_iter()
+            .filter_map(|(key, endpoint)| {
+                let nick = registry.nickname(&key)?;
+                let upd = PeerUpdate {
+                    key,
+                    endpoint,
+                    advertise_routes: Vec::new(),
+                    ext: Extensions {
+                        server: true,
+                        ..Default::default()
+                    },
+                };
+
+                Some(PeerEvent::Bootstrap(
+                    (!obfuscate).then(|| nick.to_string()),
+                    upd,
+                ))
+            })
+            .collect()
     }
 
-    #[inline]
-    fn decode_msg(msg: &str) -> Result<PeerUpdate, Error> {
-        let msg = BASE64_URL_SAFE.decode(msg)?;
+    /// Takes our nickname back when the ghost of an earlier session holding
+    /// it leaves, the server tells us as we share the channel.
+    fn collect_ghost(nickname: &str, sender: &Sender, msg: &Message) {
+        match &msg.command {
+            Command::Response(Response::ERR_NICKNAMEINUSE, args) if args.len() >= 2 => {
+                log::warn!(
+                    "nickname {} is in use, likely by a ghost of an earlier session",
+                    args[1]
+                );
+            }
+            Command::QUIT(_) | Command::NICK(_) if msg.source_nickname() == Some(nickname) => {
+                log::info!("ghost {nickname} left, taking the nickname back");
+
+                if let Err(err) = sender.send(Command::NICK(nickname.to_string())) {
+                    log::warn!("can't take nickname {nickname} back: {err}");
+                }
+            }
+            _ => {}
+        }
+    }
 
-        Ok(bincode::decode_from_slice(&msg, BINCODE_CONFIG)?.0)
+    /// Topic of `channel` carried by `msg`, an empty one when it is unset.
+    fn topic_of<'a>(channel: &str, msg: &'a Message) -> Option<&'a str> {
+        match &msg.command {
+            Command::TOPIC(target, Some(topic)) if target == channel => Some(topic),
+            Command::Response(Response::RPL_TOPIC, args) if args.len() >= 3 => {
+                (args[1] == channel).then_some(&args[2])
+            }
+            Command::Response(Response::RPL_NOTOPIC, args) if args.len() >= 2 => {
+                (args[1] == channel).then_some("")
+            }
+            _ => None,
+        }
     }
 }
 
@@ -134,41 +334,76 @@ impl Signaling for IrcSignaling {
     {
         let channel = self.channel.clone();
         let nickname = self.nickname.clone();
+        let registry = self.registry.clone();
+        let obfuscate = self.obfuscate;
+        let state = self.topic.clone();
+        let seal = self.seal.clone();
+        let sender = self.client.sender();
+        let mut unwrap = Unwrap::new(self.disguise);
 
         Ok(self
             .client
             .stream()?
             .map_err(Error::IrcError)
-            .try_filter_map(move |x| {
-                let channel = channel.clone();
-                let nickname = nickname.clone();
-
-                async move {
-                    println!("msg {:?} {:?}", x.prefix, x.command);
-
-                    Ok(match x.command {
-                        Command::PRIVMSG(target, msg) => {
-                            if let Some(Prefix::Nickname(nm, _, _)) = x.prefix {
-                                let msg = Self::decode_msg(&msg).ok();
-
-                                if target == channel {
-                                    msg.map(|upd| PeerEvent::Request(nm, upd))
-                                } else {
-                                    msg.map(PeerEvent::Response)
-                                }
-                            } else {
-                                None
-                            }
+            .map_ok(move |msg| {
+                log::trace!("msg {:?} {:?}", msg.prefix, msg.command);
+                Self::collect_ghost(&nickname, &sender, &msg);
+
+                let events = match Self::topic_of(&channel, &msg) {
+                    Some(_) if seal.is_some() => Vec::new(),
+                    Some(topic) => {
+                        if let Some(state) = &state {
+                            let mut state = state.lock().unwrap();
+                            state.current = Some(topic.to_string());
+                            state.maintain(&sender, &channel);
                         }
-                        _ => None,
-                    })
-                }
-            }))
+
+                        Self::bootstrap_events(&registry, obfuscate, topic)
+                    }
+                    None => Vec::from_iter(Self::peer_event(
+                        &channel,
+                        &registry,
+                        obfuscate,
+                        seal.as_deref(),
+                        &mut unwrap,
+                        msg,
+                    )),
+                };
+
+                stream::iter(events.into_iter().map(Ok))
+            })
+            .try_flatten())
     }
 
-    async fn announce(&mut self, peer: PeerUpdate, nick: Option<&str>) -> Result<(), Self::Error> {
+    async fn announce(
+        &mut self,
+        mut peer: PeerUpdate,
+        nick: Option<&str>,
+    ) -> Result<(), Self::Error> {
         let target = nick.unwrap_or(&self.channel);
 
+        if nick.is_none()
+            && peer.ext.server
+            && let Some(state) = &self.topic
+        {
+            let mut state = state.lock().unwrap();
+            state.ours = Some((peer.key, peer.endpoint));
+            state.maintain(&self.client.sender(), &self.channel);
+        }
+
+        if self.obfuscate {
+            let room = codec::room(&peer, self.seal.is_some());
+            peer.ext.padding = rand::random_range(0..=MAX_PADDING).min(room as u16);
+        }
+
+        if self.disguise == Disguise::Padded {
+            let len = match self.seal {
+                Some(_) => PADDED_LEN - seal::OVERHEAD,
+                None => PADDED_LEN,
+            };
+            disguise::pad(&mut peer, len);
+        }
+
         log::info!(
             "announcing peer for {} {} {}",
             target,
@@ -176,8 +411,57 @@ impl Signaling for IrcSignaling {
             peer.endpoint
         );
 
-        let msg = Self::encode_msg(&peer)?;
-        self.client.send_privmsg(target, msg)?;
+        self.buf.clear();
+        match &self.seal {
+            Some(seal) => {
+                let to = nick.and_then(|nick| self.registry.key(nick));
+                codec::encode_sealed(&peer, seal, to, &mut self.buf)?;
+            }
+            None => codec::encode(&peer, &mut self.buf)?,
+        }
+
+        if let Some(key) = nick.and_then(|nick| self.registry.key(nick)) {
+            peer_debug!(*key, "sending {target} {peer:?}");
+        }
+
+        let lines = self.disguise.wrap(&self.buf);
+        if lines.iter().any(|line| line.len() > codec::MAX_LINE_LEN) {
+            return Err(Error::MessageTooLong);
+        }
+
+        if !self.obfuscate {
+            for line in lines {
+                self.client.send_privmsg(target, line)?;
+            }
+            return Ok(());
+        }
+
+        // the jitter is waited out by a task of its own, not by the caller,
+        // and never puts a message ahead of an earlier one
+        let jitter = rand::random_range(0..=MAX_JITTER.as_millis() as u64);
+        let at = (Instant::now() + Duration::from_millis(jitter))
+            .max(self.jittered + Duration::from_millis(1));
+        self.jittered = at;
+
+        let sender = self.client.sender();
+        let target = target.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(at).await;
+
+            for line in lines {
+                if let Err(err) = sender.send_privmsg(&target, line) {
+                    log::warn!("can't send to {target}: {}", Error::from(err));
+                    break;
+                }
+            }
+        });
+
         Ok(())
     }
 }
+
+fn random_nickname() -> String {
+    (0..NICKNAME_LENGTH)
+        .map(|_| (b'a' + rand::random_range(0..26u8)) as char)
+        .collect()
+}
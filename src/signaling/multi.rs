@@ -0,0 +1,473 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{Stream, StreamExt, future::Either, stream};
+use tokio::time::Instant;
+
+use super::{PeerEvent, PeerUpdate, Signaling};
+
+/// Failed backends are retried as candidates after this long.
+const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// A backend which delivered something recently is preferred.
+const DELIVERY_WINDOW: Duration = Duration::from_secs(300);
+
+/// Keeps a last resort backend from being picked while the others work.
+const LAST_RESORT_PENALTY: i64 = 60;
+
+/// Nicknames whose backend is remembered, for replying to them.
+const MAX_SENDERS: usize = 1024;
+
+/// Senders heard from longer ago are forgotten first.
+const SENDER_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    pub connected: bool,
+
+    /// Consecutive failed announcements.
+    pub failures: u32,
+    pub last_failure: Option<Instant>,
+
+    /// Smoothed duration of announcements.
+    pub latency: Option<Duration>,
+    pub last_delivery: Option<Instant>,
+
+    /// Only used once the others keep failing.
+    pub last_resort: bool,
+}
+
+impl Health {
+    /// Higher is better, `None` when the backend can't be used at all.
+    pub fn score(&self, now: Instant) -> Option<i64> {
+        if !self.connected {
+            return None;
+        }
+
+        let mut score = 100;
+
+        match self.last_failure {
+            Some(at) if now - at < RETRY_AFTER => score -= 50 * self.failures.min(4) as i64,
+            _ => score -= 10 * self.failures.min(4) as i64,
+        }
+
+        if let Some(latency) = self.latency {
+            score -= (latency.as_millis() / 100).min(30) as i64;
+        }
+
+        if self
+            .last_delivery
+            .is_some_and(|at| now - at < DELIVERY_WINDOW)
+        {
+            score += 10;
+        }
+
+        if self.last_resort {
+            score -= LAST_RESORT_PENALTY;
+        }
+
+        Some(score)
+    }
+
+    fn succeeded(&mut self, took: Duration) {
+        self.failures = 0;
+        self.latency = Some(match self.latency {
+            Some(latency) => (latency * 7 + took) / 8,
+            None => took,
+        });
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.failures += 1;
+        self.last_failure = Some(now);
+    }
+}
+
+/// Index of the best backend, ties go to the one configured first.
+pub fn best(health: &[Health], now: Instant) -> Option<usize> {
+    health
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, h)| Some((h.score(now)?, idx)))
+        .max_by_key(|&(score, idx)| (score, std::cmp::Reverse(idx)))
+        .map(|(_, idx)| idx)
+}
+
+/// Listens on every backend and announces through the healthiest one,
+/// falling over to the next one when it fails. Announcements to a nickname
+/// go through the backend it was heard on, nicknames mean nothing on the
+/// others.
+pub struct MultiSignaling<S> {
+    backends: Vec<(String, S)>,
+    health: Arc<Mutex<Vec<Health>>>,
+    active: Option<usize>,
+
+    /// Backend each nickname was last heard on and when.
+    senders: Arc<Mutex<HashMap<String, (usize, Instant)>>>,
+}
+
+impl<S: Signaling> MultiSignaling<S> {
+    /// Backends failed to connect are kept for reporting only.
+    pub fn new(backends: Vec<(String, Result<S, S::Error>)>) -> Self
+    where
+        S::Error: std::fmt::Display,
+    {
+        let mut connected = Vec::new();
+        let mut health = Vec::new();
+
+        for (name, res) in backends {
+            match res {
+                Ok(backend) => {
+                    connected.push((name, backend));
+                    health.push(Health {
+                        connected: true,
+                        ..Default::default()
+                    });
+                }
+                Err(err) => log::warn!("signaling backend {name} failed to connect: {err}"),
+            }
+        }
+
+        Self {
+            backends: connected,
+            health: Arc::new(Mutex::new(health)),
+            active: None,
+            senders: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Adds a backend announced through only when the others keep failing.
+    pub fn with_last_resort(mut self, name: String, res: Result<S, S::Error>) -> Self
+    where
+        S::Error: std::fmt::Display,
+    {
+        match res {
+            Ok(backend) => {
+                self.backends.push((name, backend));
+                self.health.lock().unwrap().push(Health {
+                    connected: true,
+                    last_resort: true,
+                    ..Default::default()
+                });
+            }
+            Err(err) => log::warn!("signaling backend {name} failed to connect: {err}"),
+        }
+
+        self
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.backends.is_empty()
+    }
+
+    pub fn health(&self) -> Vec<(String, Health)> {
+        let health = self.health.lock().unwrap();
+
+        self.backends
+            .iter()
+            .map(|(name, _)| name.clone())
+            .zip(health.iter().cloned())
+            .collect()
+    }
+
+    fn select(&mut self, now: Instant) -> Option<usize> {
+        let best = best(&self.health.lock().unwrap(), now);
+
+        if best != self.active {
+            match (self.active, best) {
+                (Some(from), Some(to)) => log::warn!(
+                    "signaling failover {} -> {}",
+                    self.backends[from].0,
+                    self.backends[to].0
+                ),
+                (None, Some(to)) => log::info!("announcing via {}", self.backends[to].0),
+                (_, None) => log::error!("no healthy signaling backend left"),
+            }
+
+            self.active = best;
+        }
+
+        best
+    }
+
+    /// Backend `nick` was last heard on, while it is still connected.
+    fn sender(&self, nick: &str) -> Option<usize> {
+        let senders = self.senders.lock().unwrap();
+        let &(idx, _) = senders.get(nick)?;
+        self.health.lock().unwrap()[idx].connected.then_some(idx)
+    }
+}
+
+/// Remembers `idx` as the backend of the sender of `event`.
+fn heard(senders: &Mutex<HashMap<String, (usize, Instant)>>, idx: usize, event: &PeerEvent) {
+    let nick = match event {
+        PeerEvent::Request(nick, _)
+        | PeerEvent::Response(nick, _)
+        | PeerEvent::Bootstrap(Some(nick), _) => nick,
+        _ => return,
+    };
+
+    let now = Instant::now();
+    let mut senders = senders.lock().unwrap();
+    if senders.len() >= MAX_SENDERS && !senders.contains_key(nick) {
+        senders.retain(|_, &mut (_, at)| now - at < SENDER_TTL);
+        if senders.len() >= MAX_SENDERS {
+            return;
+        }
+    }
+    senders.insert(nick.clone(), (idx, now));
+}
+
+impl<S> Signaling for MultiSignaling<S>
+where
+    S: Signaling + 'static,
+    S::Error: std::fmt::Display + 'static,
+{
+    type Error = S::Error;
+
+    async fn announce(&mut self, peer: PeerUpdate, nick: Option<&str>) -> Result<(), Self::Error> {
+        if let Some(idx) = nick.and_then(|nick| self.sender(nick)) {
+            let now = Instant::now();
+            let res = self.backends[idx].1.announce(peer, nick).await;
+            let mut health = self.health.lock().unwrap();
+
+            return match res {
+                Ok(()) => {
+                    health[idx].succeeded(now.elapsed());
+                    Ok(())
+                }
+                Err(err) => {
+                    health[idx].failed(now);
+                    Err(err)
+                }
+            };
+        }
+
+        let mut last_err = None;
+
+        for _ in 0..self.backends.len() {
+            let now = Instant::now();
+            let Some(idx) = self.select(now) else { break };
+
+            let res = self.backends[idx].1.announce(peer.clone(), nick).await;
+            let mut health = self.health.lock().unwrap();
+
+            match res {
+                Ok(()) => {
+                    health[idx].succeeded(now.elapsed());
+                    return Ok(());
+                }
+                Err(err) => {
+                    log::warn!("announce via {} failed: {err}", self.backends[idx].0);
+                    health[idx].failed(now);
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    async fn subscribe(
+        &mut self,
+    ) -> Result<impl Stream<Item = Result<PeerEvent, Self::Error>> + use<S>, Self::Error> {
+        let mut streams = Vec::new();
+
+        for (idx, (_, backend)) in self.backends.iter_mut().enumerate() {
+            let health = self.health.clone();
+            let senders = self.senders.clone();
+
+            let events = backend.subscribe().await?.map(move |res| {
+                let mut health = health.lock().unwrap();
+
+                match &res {
+                    Ok(event) => {
+                        heard(&senders, idx, event);
+                        health[idx].last_delivery = Some(Instant::now());
+                    }
+                    Err(_) => health[idx].failed(Instant::now()),
+                }
+
+                res
+            });
+
+            let health = self.health.clone();
+            let closed = stream::once(async move {
+                health.lock().unwrap()[idx].connected = false;
+                None
+            });
+
+            let events: Pin<Box<dyn Stream<Item = _>>> = Box::pin(
+                events
+                    .map(Some)
+                    .chain(closed)
+                    .filter_map(futures::future::ready),
+            );
+            streams.push(events);
+        }
+
+        Ok(stream::select_all(streams))
+    }
+}
+
+/// Backends of two kinds in one [`MultiSignaling`].
+impl<A, B> Signaling for Either<A, B>
+where
+    A: Signaling,
+    B: Signaling<Error = A::Error>,
+{
+    type Error = A::Error;
+
+    async fn announce(&mut self, peer: PeerUpdate, nick: Option<&str>) -> Result<(), Self::Error> {
+        match self {
+            Either::Left(a) => a.announce(peer, nick).await,
+            Either::Right(b) => b.announce(peer, nick).await,
+        }
+    }
+
+    async fn subscribe(
+        &mut self,
+    ) -> Result<impl Stream<Item = Result<PeerEvent, Self::Error>> + use<A, B>, Self::Error> {
+        Ok(match self {
+            Either::Left(a) => Either::Left(a.subscribe().await?),
+            Either::Right(b) => Either::Right(b.subscribe().await?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use futures::{Stream, StreamExt, stream};
+    use tokio::time::Instant;
+
+    use crate::{
+        signaling::{PeerEvent, PeerUpdate, Signaling},
+        wg::Key,
+    };
+
+    use super::{Health, MultiSignaling, best};
+
+    /// Delivers `events` and records the nicknames announced to.
+    struct Fake {
+        events: Vec<PeerEvent>,
+        announced: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    impl Signaling for Fake {
+        type Error = Infallible;
+
+        async fn announce(&mut self, _: PeerUpdate, nick: Option<&str>) -> Result<(), Infallible> {
+            self.announced
+                .lock()
+                .unwrap()
+                .push(nick.map(str::to_string));
+            Ok(())
+        }
+
+        async fn subscribe(
+            &mut self,
+        ) -> Result<impl Stream<Item = Result<PeerEvent, Infallible>> + use<>, Infallible> {
+            let events = std::mem::take(&mut self.events).into_iter().map(Ok);
+            Ok(stream::iter(events).chain(stream::pending()))
+        }
+    }
+
+    fn up() -> Health {
+        Health {
+            connected: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_failover_and_recovery() {
+        let now = Instant::now();
+        let mut health = vec![up(), up()];
+        assert_eq!(best(&health, now), Some(0));
+
+        health[0].failed(now);
+        assert_eq!(best(&health, now), Some(1));
+
+        // retried once the failure is old enough, configured order wins again
+        let later = now + Duration::from_secs(120);
+        health[1].failed(later - Duration::from_secs(90));
+        assert_eq!(best(&health, later), Some(0));
+
+        health[0].connected = false;
+        health[1].connected = false;
+        assert_eq!(best(&health, later), None);
+
+        // a last resort only wins over repeated failures
+        let last_resort = Health {
+            last_resort: true,
+            ..up()
+        };
+        let mut health = vec![up(), last_resort];
+        assert_eq!(best(&health, now), Some(0));
+        health[0].failed(now);
+        assert_eq!(best(&health, now), Some(0));
+        health[0].failed(now);
+        assert_eq!(best(&health, now), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_reply_through_sender_backend() {
+        let peer = PeerUpdate {
+            key: Key::random(),
+            endpoint: "1.2.3.4:51820".parse().unwrap(),
+            advertise_routes: Vec::new(),
+            ext: Default::default(),
+        };
+        let fake = |events| Fake {
+            events,
+            announced: Default::default(),
+        };
+
+        let first = fake(vec![PeerEvent::Response("bob".into(), peer.clone())]);
+        let second = fake(vec![PeerEvent::Request("alice".into(), peer.clone())]);
+        let (first_announced, second_announced) =
+            (first.announced.clone(), second.announced.clone());
+
+        let mut signaling = MultiSignaling::new(vec![
+            ("first".into(), Ok(first)),
+            ("second".into(), Ok(second)),
+        ]);
+        let mut events = Box::pin(signaling.subscribe().await.unwrap());
+        for _ in 0..2 {
+            events.next().await.unwrap().unwrap();
+        }
+
+        // broadcasts and unknown nicknames go through the best backend
+        signaling.announce(peer.clone(), None).await.unwrap();
+        signaling
+            .announce(peer.clone(), Some("carol"))
+            .await
+            .unwrap();
+        signaling
+            .announce(peer.clone(), Some("alice"))
+            .await
+            .unwrap();
+        signaling.announce(peer.clone(), Some("bob")).await.unwrap();
+
+        let first_announced = first_announced.lock().unwrap();
+        assert_eq!(
+            *first_announced,
+            [None, Some("carol".into()), Some("bob".into())]
+        );
+        assert_eq!(*second_announced.lock().unwrap(), [Some("alice".into())]);
+    }
+}
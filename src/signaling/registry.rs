@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use base64::{Engine, prelude::BASE64_URL_SAFE};
+use hashes::sha2::sha256;
+
+use crate::wg::Key;
+
+pub const NICKNAME_LENGTH: usize = 12;
+
+/// Alternate nicknames tried while ours is taken, usually by a ghost of an
+/// earlier session the server hasn't timed out yet.
+pub const ALTERNATE_NICKNAMES: usize = 3;
+
+/// IRC username of the peer with public `key`: the url-safe base64 of its
+/// SHA-256 with `-` and `_` left out. Every peer derives it the same way,
+/// which is how they find each other without random nicknames.
+pub fn username(key: &Key) -> String {
+    let mut buf = [0u8; 44];
+    BASE64_URL_SAFE
+        .encode_slice(sha256::hash(key.as_ref()).into_bytes(), &mut buf)
+        .unwrap();
+
+    buf.iter()
+        .filter(|c| !matches!(c, b'-' | b'_'))
+        .map(|&c| c as char)
+        .collect()
+}
+
+/// IRC nickname of the peer with public `key`, the first
+/// [`NICKNAME_LENGTH`] characters of its [`username`]. With random
+/// nicknames peers pick a new one every session instead.
+pub fn nickname(key: &Key) -> String {
+    username(key)[..NICKNAME_LENGTH].to_string()
+}
+
+/// Nicknames to fall back on, `nickname` with one to
+/// [`ALTERNATE_NICKNAMES`] underscores appended. Usernames have no
+/// underscores, so peers still tell whose they are.
+pub fn alternate_nicknames(nickname: &str) -> Vec<String> {
+    (1..=ALTERNATE_NICKNAMES)
+        .map(|n| format!("{nickname}{}", "_".repeat(n)))
+        .collect()
+}
+
+#[derive(Hash, Clone, Copy, PartialEq, Eq)]
+pub struct Nickname([u8; NICKNAME_LENGTH]);
+
+impl Nickname {
+    pub fn parse(nick: &str) -> Option<Nickname> {
+        Some(Nickname(nick.as_bytes().try_into().ok()?))
+    }
+}
+
+impl From<&Key> for Nickname {
+    fn from(key: &Key) -> Nickname {
+        let mut buf = [0; NICKNAME_LENGTH];
+        buf.copy_from_slice(nickname(key).as_bytes());
+        Nickname(buf)
+    }
+}
+
+impl std::fmt::Debug for Nickname {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Nickname").field(&self.to_string()).finish()
+    }
+}
+
+impl std::fmt::Display for Nickname {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", str::from_utf8(&self.0).unwrap_or("<err>"))
+    }
+}
+
+/// Known peers indexed both by nickname and by public key.
+#[derive(Debug, Default, Clone)]
+pub struct Registry {
+    by_nick: HashMap<Nickname, Key>,
+    by_key: HashMap<Key, Nickname>,
+}
+
+impl Registry {
+    pub fn insert(&mut self, key: Key) -> Nickname {
+        let nick = Nickname::from(&key);
+
+        if let Some(old) = self.by_nick.insert(nick, key)
+            && old != key
+        {
+            log::warn!("nickname collision {nick} between {old} and {key}");
+        }
+
+        self.by_key.insert(key, nick);
+        nick
+    }
+
+    /// Key of `nick`, which may be one of the
+    /// [alternate nicknames](alternate_nicknames).
+    pub fn key(&self, nick: &str) -> Option<&Key> {
+        let (nick, suffix) = nick.split_at_checked(NICKNAME_LENGTH)?;
+        if suffix.len() > ALTERNATE_NICKNAMES || suffix.bytes().any(|c| c != b'_') {
+            return None;
+        }
+
+        self.by_nick.get(&Nickname::parse(nick)?)
+    }
+
+    #[inline]
+    pub fn nickname(&self, key: &Key) -> Option<&Nickname> {
+        self.by_key.get(key)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.by_key.is_empty()
+    }
+}
+
+impl<'a> FromIterator<&'a Key> for Registry {
+    fn from_iter<T: IntoIterator<Item = &'a Key>>(iter: T) -> Self {
+        let mut registry = Registry::default();
+
+        for key in iter {
+            registry.insert(*key);
+        }
+
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wg::Key;
+
+    use super::{ALTERNATE_NICKNAMES, Nickname, Registry, alternate_nicknames, nickname, username};
+
+    /// Fixed, peers running other versions have to agree on them.
+    const VECTORS: &[(&str, &str)] = &[
+        (
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+            "Zmh6rfhivXdsj8GLjpOIAiXFIVu4jOzkCpZHQ1fKSU=",
+        ),
+        (
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=",
+            "Yw3NKWbEM2aRElRIu7JbTQSpJxzLbLIq8G4WBvXEN0=",
+        ),
+        (
+            "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=",
+            "Lez1qt8jmxeqPjt1CREFrfcWbGJOhWJ3aHSDewlUhK4=",
+        ),
+    ];
+
+    #[test]
+    fn test_vectors() {
+        for (key, expected) in VECTORS {
+            let key: Key = key.parse().unwrap();
+
+            assert_eq!(username(&key), *expected);
+            assert_eq!(nickname(&key), expected[..12]);
+            assert_eq!(Nickname::from(&key).to_string(), expected[..12]);
+        }
+    }
+
+    #[test]
+    fn test_alternate_nicknames() {
+        let key = Key::random();
+        let registry: Registry = [key].iter().collect();
+        let nick = Nickname::from(&key).to_string();
+
+        let alternates = alternate_nicknames(&nick);
+        assert_eq!(alternates[0], format!("{nick}_"));
+        assert_eq!(alternates[2], format!("{nick}___"));
+
+        for alternate in alternates.iter().chain([&nick]) {
+            assert_eq!(registry.key(alternate), Some(&key));
+        }
+        assert_eq!(registry.key(&nick[1..]), None);
+        assert_eq!(registry.key(&format!("{}_", &nick[1..])), None);
+    }
+
+    #[test]
+    fn test_registry() {
+        let (a, b) = (Key::random(), Key::random());
+        let mut registry: Registry = [a].iter().collect();
+
+        let nick = registry.insert(b);
+        assert_eq!(registry.insert(b), nick);
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.key(&nick.to_string()), Some(&b));
+        assert_eq!(registry.nickname(&b), Some(&nick));
+        assert_eq!(registry.nickname(&Key::random()), None);
+        assert_eq!(registry.key("not a nickname"), None);
+
+        for alternate in alternate_nicknames(&nick.to_string()) {
+            assert_eq!(registry.key(&alternate), Some(&b));
+        }
+        let extra = "_".repeat(ALTERNATE_NICKNAMES + 1);
+        assert_eq!(registry.key(&format!("{nick}{extra}")), None);
+        assert_eq!(registry.key(&format!("{nick}_x")), None);
+    }
+
+    #[test]
+    fn test_nickname_collision() {
+        let (a, b) = (Key::random(), Key::random());
+        let mut registry: Registry = [a].iter().collect();
+
+        // truncated hashes colliding can't be found, fake one: the later
+        // key takes the nickname, the earlier one keeps it for sending
+        let nick = Nickname::from(&a);
+        registry.by_nick.remove(&nick);
+        registry.by_nick.insert(Nickname::from(&b), a);
+        registry.by_key.insert(a, Nickname::from(&b));
+
+        let nick = registry.insert(b);
+        assert_eq!(registry.key(&nick.to_string()), Some(&b));
+        assert_eq!(registry.nickname(&a), Some(&nick));
+        assert_eq!(registry.nickname(&b), Some(&nick));
+        assert_eq!(registry.len(), 2);
+    }
+}
@@ -0,0 +1,138 @@
+use std::{fmt, str::FromStr};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+
+use crate::{
+    crypto::{xeddsa_sign, xeddsa_verify},
+    wg::Key,
+};
+
+/// Signed statements are prefixed so a signature can't be replayed as
+/// anything else.
+const CONTEXT: &[u8] = b"wg-disco revoke";
+
+const LEN: usize = 32 + 8 + 64;
+
+/// A peer's key revoked by an operator designated signer, e.g. of a stolen
+/// device. Signed with the signer's wireguard key, so any peer can pass it
+/// on and nobody can forge it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Revocation {
+    pub key: Key,
+
+    /// When it was signed, unix time in milliseconds.
+    pub timestamp: u64,
+    signature: [u8; 64],
+}
+
+impl Revocation {
+    pub fn sign(private_key: &Key, key: Key, timestamp: u64) -> Self {
+        let msg = message(&key, timestamp);
+        let signature = xeddsa_sign(private_key.as_bytes(), &msg, &rand::random());
+
+        Self {
+            key,
+            timestamp,
+            signature,
+        }
+    }
+
+    /// Whether `signer` signed it.
+    pub fn verify(&self, signer: &Key) -> bool {
+        xeddsa_verify(
+            signer.as_bytes(),
+            &message(&self.key, self.timestamp),
+            &self.signature,
+        )
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(LEN);
+        bytes.extend(self.key.as_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.signature);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != LEN {
+            return None;
+        }
+
+        let key: [u8; 32] = bytes[..32].try_into().ok()?;
+        Some(Self {
+            key: Key::from(key),
+            timestamp: u64::from_be_bytes(bytes[32..40].try_into().ok()?),
+            signature: bytes[40..].try_into().ok()?,
+        })
+    }
+}
+
+fn message(key: &Key, timestamp: u64) -> Vec<u8> {
+    let mut msg = CONTEXT.to_vec();
+    msg.extend(key.as_bytes());
+    msg.extend(timestamp.to_be_bytes());
+    msg
+}
+
+/// `<key> <timestamp> <signature>`, a line of the revocations file.
+impl fmt::Display for Revocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.key,
+            self.timestamp,
+            BASE64_STANDARD.encode(self.signature)
+        )
+    }
+}
+
+impl FromStr for Revocation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let (Some(key), Some(timestamp), Some(signature), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(());
+        };
+
+        let signature = BASE64_STANDARD.decode(signature).map_err(|_| ())?;
+        Ok(Self {
+            key: key.parse().map_err(|_| ())?,
+            timestamp: timestamp.parse().map_err(|_| ())?,
+            signature: signature.try_into().map_err(|_| ())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{crypto::x25519_base, wg::Key};
+
+    use super::Revocation;
+
+    #[test]
+    fn test_revocation() {
+        let private = Key::random();
+        let signer = Key::from(x25519_base(private.as_bytes()));
+        let revoked = Key::random();
+
+        let revocation = Revocation::sign(&private, revoked, 1_700_000_000_000);
+        assert!(revocation.verify(&signer));
+        assert!(!revocation.verify(&revoked));
+
+        let bytes = revocation.to_bytes();
+        assert_eq!(Revocation::from_bytes(&bytes), Some(revocation));
+        assert_eq!(Revocation::from_bytes(&bytes[1..]), None);
+        assert_eq!(revocation.to_string().parse(), Ok(revocation));
+
+        let forged = Revocation {
+            key: Key::random(),
+            ..revocation
+        };
+        assert!(!forged.verify(&signer));
+    }
+}
@@ -0,0 +1,173 @@
+//! End-to-end encryption of announcements, so neither the signaling server
+//! nor others in the channel can read or forge them. Messages to one peer
+//! are sealed with a key both sides derive from X25519 between their
+//! wireguard keys, channel announcements with one derived from a PSK every
+//! peer of the mesh shares.
+//!
+//! A sealed message is a kind byte, a 16 byte nonce, the payload encrypted
+//! with AES-256-CTR and the first 16 bytes of an HMAC-SHA256 over all of
+//! it.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    crypto::{aes256_ctr, ct_eq, hmac_sha256, x25519},
+    wg::Key,
+};
+
+const PAIRWISE: u8 = 0xa1;
+const CHANNEL: u8 = 0xa2;
+
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+
+/// Bytes sealing adds to a payload.
+pub const OVERHEAD: usize = 1 + NONCE_LEN + TAG_LEN;
+
+#[derive(Clone)]
+struct Keys {
+    enc: [u8; 32],
+    mac: [u8; 32],
+}
+
+impl Keys {
+    fn new(secret: &[u8], context: &str) -> Self {
+        Self {
+            enc: hmac_sha256(secret, format!("wg-disco {context} encryption").as_bytes()),
+            mac: hmac_sha256(
+                secret,
+                format!("wg-disco {context} authentication").as_bytes(),
+            ),
+        }
+    }
+
+    fn seal(&self, kind: u8, plain: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+
+        let mut sealed = vec![kind];
+        sealed.extend(nonce);
+        sealed.extend(plain);
+        aes256_ctr(&self.enc, &nonce, &mut sealed[1 + NONCE_LEN..]);
+
+        let tag = hmac_sha256(&self.mac, &sealed);
+        sealed.extend(&tag[..TAG_LEN]);
+        sealed
+    }
+
+    fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let (data, tag) = sealed.split_at(sealed.len().checked_sub(TAG_LEN)?);
+        if !ct_eq(tag, &hmac_sha256(&self.mac, data)[..TAG_LEN]) {
+            return None;
+        }
+
+        let (nonce, ciphertext) = data[1..].split_at(NONCE_LEN);
+        let mut plain = ciphertext.to_vec();
+        aes256_ctr(&self.enc, nonce.try_into().unwrap(), &mut plain);
+        Some(plain)
+    }
+}
+
+/// Keys of the channel and of every configured peer.
+pub struct Seal {
+    channel: Keys,
+    peers: HashMap<Key, Keys>,
+}
+
+impl fmt::Debug for Seal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Seal")
+            .field("peers", &self.peers.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Seal {
+    pub fn new<'a>(
+        private_key: &Key,
+        psk: &[u8],
+        peers: impl IntoIterator<Item = &'a Key>,
+    ) -> Self {
+        let peers = peers
+            .into_iter()
+            .map(|peer| {
+                let shared = x25519(private_key.as_bytes(), peer.as_bytes());
+                (*peer, Keys::new(&shared, "pairwise"))
+            })
+            .collect();
+
+        Self {
+            channel: Keys::new(psk, "channel"),
+            peers,
+        }
+    }
+
+    /// Seals `plain` for peer `to`, for the channel when it is `None` or
+    /// not configured.
+    pub fn seal(&self, to: Option<&Key>, plain: &[u8]) -> Vec<u8> {
+        match to.and_then(|to| self.peers.get(to)) {
+            Some(keys) => keys.seal(PAIRWISE, plain),
+            None => self.channel.seal(CHANNEL, plain),
+        }
+    }
+
+    /// Plaintext of a message from peer `from`, every configured peer is
+    /// tried when the sender is unknown. Comes with the peer whose key
+    /// opened it, `None` for channel messages.
+    pub fn open(&self, from: Option<&Key>, sealed: &[u8]) -> Option<(Vec<u8>, Option<Key>)> {
+        if sealed.len() < OVERHEAD {
+            return None;
+        }
+
+        match (sealed[0], from) {
+            (CHANNEL, _) => Some((self.channel.open(sealed)?, None)),
+            (PAIRWISE, Some(from)) => Some((self.peers.get(from)?.open(sealed)?, Some(*from))),
+            (PAIRWISE, None) => self
+                .peers
+                .iter()
+                .find_map(|(key, keys)| Some((keys.open(sealed)?, Some(*key)))),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{crypto::x25519_base, wg::Key};
+
+    use super::Seal;
+
+    #[test]
+    fn test_seal() {
+        let (a, b) = (Key::random(), Key::random());
+        let (a_pub, b_pub) = (
+            Key::from(x25519_base(a.as_bytes())),
+            Key::from(x25519_base(b.as_bytes())),
+        );
+        let alice = Seal::new(&a, b"psk", [&b_pub]);
+        let bob = Seal::new(&b, b"psk", [&a_pub]);
+
+        let sealed = alice.seal(Some(&b_pub), b"hello");
+        assert_eq!(
+            bob.open(Some(&a_pub), &sealed),
+            Some((b"hello".to_vec(), Some(a_pub)))
+        );
+        assert_eq!(
+            bob.open(None, &sealed),
+            Some((b"hello".to_vec(), Some(a_pub)))
+        );
+
+        let broadcast = alice.seal(None, b"everyone");
+        assert_eq!(
+            bob.open(Some(&a_pub), &broadcast),
+            Some((b"everyone".to_vec(), None))
+        );
+
+        let mut forged = sealed.clone();
+        forged[20] ^= 1;
+        assert_eq!(bob.open(Some(&a_pub), &forged), None);
+
+        let eve = Seal::new(&Key::random(), b"other", [&a_pub, &b_pub]);
+        assert_eq!(eve.open(None, &sealed), None);
+        assert_eq!(eve.open(None, &broadcast), None);
+    }
+}
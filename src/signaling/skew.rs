@@ -0,0 +1,115 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::wg::Key;
+
+/// Number of offset samples kept per peer.
+const SAMPLES: usize = 8;
+
+/// Current unix time in milliseconds.
+pub fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[derive(Debug, Default, Clone)]
+struct PeerClock {
+    samples: VecDeque<i64>,
+    last: u64,
+}
+
+impl PeerClock {
+    fn offset(&self) -> Option<i64> {
+        let mut sorted: Vec<_> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        sorted.get(sorted.len() / 2).copied()
+    }
+}
+
+/// Per-peer clock offsets estimated from the timestamps peers put into
+/// their announcements. Freshness is checked against the peer's own clock
+/// so nodes with a broken RTC aren't locked out.
+#[derive(Debug, Default, Clone)]
+pub struct ClockSkew {
+    peers: HashMap<Key, PeerClock>,
+}
+
+impl ClockSkew {
+    /// Records a timestamp `sent` by `key` received at local time `now`,
+    /// returns the updated offset estimate (peer clock minus ours).
+    pub fn observe(&mut self, key: Key, sent: u64, now: u64) -> i64 {
+        let clock = self.peers.entry(key).or_default();
+
+        if clock.samples.len() == SAMPLES {
+            clock.samples.pop_front();
+        }
+
+        clock.samples.push_back(sent as i64 - now as i64);
+        clock.last = clock.last.max(sent);
+        clock.offset().unwrap_or_default()
+    }
+
+    pub fn offset(&self, key: &Key) -> Option<i64> {
+        self.peers.get(key)?.offset()
+    }
+
+    /// Message is fresh when it is newer than anything seen from the peer
+    /// and within `window` from what the peer's clock should show now.
+    /// Peers without an estimate yet are accepted.
+    pub fn is_fresh(&self, key: &Key, sent: u64, now: u64, window: Duration) -> bool {
+        let Some(clock) = self.peers.get(key) else {
+            return true;
+        };
+
+        let Some(offset) = clock.offset() else {
+            return true;
+        };
+
+        let expected = now as i64 + offset;
+
+        sent > clock.last && (sent as i64 - expected).unsigned_abs() <= window.as_millis() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::wg::Key;
+
+    use super::ClockSkew;
+
+    #[test]
+    fn test_skewed_peer_is_fresh() {
+        let key = Key::random();
+        let mut skew = ClockSkew::default();
+        let window = Duration::from_secs(30);
+
+        // the peer clock is an hour behind
+        let behind = 3_600_000;
+        let now = 1_700_000_000_000u64;
+
+        assert!(skew.is_fresh(&key, now - behind, now, window));
+        for i in 0..5 {
+            let t = now + i * 60_000;
+            assert_eq!(
+                skew.observe(key, t - behind + 120, t),
+                -(behind as i64) + 120
+            );
+        }
+
+        let now = now + 10 * 60_000;
+        assert!(skew.is_fresh(&key, now - behind + 150, now, window));
+
+        // replayed and stale messages
+        assert!(!skew.is_fresh(&key, now - 9 * 60_000 - behind, now, window));
+        assert!(!skew.is_fresh(&key, now - behind - 60_000, now, window));
+
+        // absolute wall-clock check would accept it, peer clock says no
+        assert!(!skew.is_fresh(&key, now, now, window));
+    }
+}
@@ -0,0 +1,139 @@
+//! Bootstrap data kept in the channel topic, so a node joining the channel
+//! learns about servers before any of them speaks:
+//!
+//! ```text
+//! wg-disco/1 <key>@<endpoint> <key>@<endpoint> ...
+//! ```
+//!
+//! Anybody in the channel can change the topic, the entries are hints to
+//! probe, never trusted like announcements.
+
+use std::{fmt, net::SocketAddr};
+
+use crate::wg::Key;
+
+const PREFIX: &str = "wg-disco/";
+
+/// Version of the topic format written by this build.
+pub const VERSION: u32 = 1;
+
+/// Servers listed at most, the longest ago refreshed are dropped first.
+const MAX_SERVERS: usize = 4;
+
+/// Stays below the topic limit of common IRC servers.
+const MAX_LEN: usize = 300;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topic {
+    pub version: u32,
+
+    /// Server-mode peers with their public endpoints, most recent first.
+    pub servers: Vec<(Key, SocketAddr)>,
+}
+
+impl Default for Topic {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            servers: Vec::new(),
+        }
+    }
+}
+
+impl Topic {
+    /// `None` for topics not written by us. Entries which don't parse are
+    /// skipped, newer versions may add other kinds.
+    pub fn parse(topic: &str) -> Option<Topic> {
+        let mut words = topic.split_whitespace();
+        let version = words.next()?.strip_prefix(PREFIX)?.parse().ok()?;
+
+        let servers = words
+            .filter_map(|word| {
+                let (key, endpoint) = word.split_once('@')?;
+                Some((key.parse().ok()?, endpoint.parse().ok()?))
+            })
+            .collect();
+
+        Some(Topic { version, servers })
+    }
+
+    /// Topic to set so it lists `key` at `endpoint`, `None` when `current`
+    /// already does or isn't ours to change: somebody wrote it by hand or a
+    /// newer version maintains it.
+    pub fn maintain(current: &str, key: Key, endpoint: SocketAddr) -> Option<String> {
+        let mut topic = match Topic::parse(current) {
+            Some(topic) if topic.version == VERSION => topic,
+            None if current.trim().is_empty() => Topic::default(),
+            _ => return None,
+        };
+
+        if topic.servers.contains(&(key, endpoint)) {
+            return None;
+        }
+
+        topic.servers.retain(|(server, _)| *server != key);
+        topic.servers.insert(0, (key, endpoint));
+        topic.servers.truncate(MAX_SERVERS);
+
+        while topic.servers.len() > 1 && topic.to_string().len() > MAX_LEN {
+            topic.servers.pop();
+        }
+
+        Some(topic.to_string())
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{PREFIX}{}", self.version)?;
+
+        for (key, endpoint) in &self.servers {
+            write!(f, " {key}@{endpoint}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::wg::Key;
+
+    use super::{MAX_SERVERS, Topic};
+
+    #[test]
+    fn test_maintain() {
+        let a = Key::random();
+        let b = Key::random();
+        let addr: SocketAddr = "203.0.113.7:51820".parse().unwrap();
+        let addr6: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+
+        // empty topics are taken over, hand written ones left alone
+        let topic = Topic::maintain("", a, addr).unwrap();
+        assert_eq!(Topic::maintain("mesh of alice", a, addr), None);
+        assert_eq!(Topic::maintain("wg-disco/2", a, addr), None);
+        assert_eq!(Topic::maintain(&topic, a, addr), None);
+
+        let topic = Topic::maintain(&topic, b, addr6).unwrap();
+        assert_eq!(
+            Topic::parse(&format!("{topic} future@entry")).unwrap(),
+            Topic {
+                version: 1,
+                servers: vec![(b, addr6), (a, addr)],
+            }
+        );
+
+        let mut topic = Topic::maintain(&topic, a, addr6).unwrap();
+        assert_eq!(
+            Topic::parse(&topic).unwrap().servers,
+            vec![(a, addr6), (b, addr6)]
+        );
+
+        for _ in 0..10 {
+            topic = Topic::maintain(&topic, Key::random(), addr6).unwrap();
+        }
+        assert_eq!(Topic::parse(&topic).unwrap().servers.len(), MAX_SERVERS);
+    }
+}
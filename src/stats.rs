@@ -0,0 +1,138 @@
+//! Periodic JSON snapshot of the mesh for textfile collectors (telegraf,
+//! node-exporter via a converter, ...).
+
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{
+    control::{Command, Handle, PeerStatus, Response, Status},
+    fs::write_atomic,
+    json::Value,
+    runner::PEER_DOWN_AFTER,
+    signaling::skew::unix_ms,
+    wg::Key,
+};
+
+/// Received and sent byte counters per peer.
+type Transfer = HashMap<Key, (u64, u64)>;
+
+/// Rewrites `path` with a snapshot every `interval` until the runner is gone.
+pub async fn export(path: PathBuf, interval: Duration, control: Handle) {
+    let mut ticker = tokio::time::interval(interval);
+    let mut prev: Option<(Instant, Transfer)> = None;
+
+    loop {
+        ticker.tick().await;
+
+        let (Some(Response::Status(status)), Some(Response::Peers(peers))) = (
+            control.call(Command::Status).await,
+            control.call(Command::Peers).await,
+        ) else {
+            return;
+        };
+
+        let now = Instant::now();
+        let elapsed = prev.as_ref().map(|(at, _)| now - *at);
+        let transfer = prev.map(|(_, transfer)| transfer).unwrap_or_default();
+        let snapshot = snapshot(unix_ms() / 1000, &status, &peers, &transfer, elapsed);
+
+        if let Err(err) = write_atomic(&path, snapshot.to_string(), 0o644) {
+            log::warn!("can't write stats to {}: {err}", path.display());
+        }
+
+        prev = Some((
+            now,
+            peers
+                .iter()
+                .filter_map(|peer| Some((peer.key, peer.transfer?)))
+                .collect(),
+        ));
+    }
+}
+
+/// Bytes per second, `None` without a previous sample or after a counter
+/// reset (peer re-added).
+fn rate(prev: Option<u64>, now: u64, elapsed: Option<Duration>) -> Option<f64> {
+    let elapsed = elapsed?.as_secs_f64();
+    let delta = now.checked_sub(prev?)?;
+
+    (elapsed > 0.0).then(|| delta as f64 / elapsed)
+}
+
+fn snapshot(
+    now: u64,
+    status: &Status,
+    peers: &[PeerStatus],
+    prev: &Transfer,
+    elapsed: Option<Duration>,
+) -> Value {
+    let age = |peer: &PeerStatus| {
+        peer.latest_handshake
+            .map(|at| now.saturating_sub(at as u64))
+    };
+    let is_up = |peer: &PeerStatus| age(peer).is_some_and(|age| age <= PEER_DOWN_AFTER.as_secs());
+
+    let up = peers.iter().filter(|peer| is_up(peer)).count();
+    let peers: Vec<Value> = peers
+        .iter()
+        .map(|peer| {
+            let (rx, tx) = peer.transfer.unzip();
+            let last = prev.get(&peer.key);
+
+            Value::object([
+                ("key", Value::from(peer.key.to_string())),
+                (
+                    "endpoint",
+                    Value::from(peer.endpoint.as_ref().map(|e| e.to_string())),
+                ),
+                ("up", Value::from(is_up(peer))),
+                ("latest_handshake", Value::from(peer.latest_handshake)),
+                ("handshake_age", Value::from(age(peer))),
+                ("rx_bytes", Value::from(rx)),
+                ("tx_bytes", Value::from(tx)),
+                (
+                    "rx_rate",
+                    Value::from(rx.and_then(|rx| rate(last.map(|l| l.0), rx, elapsed))),
+                ),
+                (
+                    "tx_rate",
+                    Value::from(tx.and_then(|tx| rate(last.map(|l| l.1), tx, elapsed))),
+                ),
+            ])
+        })
+        .collect();
+
+    Value::object([
+        ("timestamp", Value::from(now)),
+        ("iface", Value::from(status.iface.as_str())),
+        ("key", Value::from(status.key.to_string())),
+        (
+            "endpoint",
+            Value::from(status.endpoint.map(|e| e.to_string())),
+        ),
+        ("frozen", Value::from(status.frozen)),
+        ("peers_total", Value::from(peers.len())),
+        ("peers_up", Value::from(up)),
+        ("peers", Value::Array(peers)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::rate;
+
+    #[test]
+    fn test_rate() {
+        let elapsed = Some(Duration::from_secs(10));
+
+        assert_eq!(rate(Some(1000), 6000, elapsed), Some(500.0));
+        assert_eq!(rate(None, 6000, elapsed), None);
+        assert_eq!(rate(Some(1000), 6000, None), None);
+
+        // counters start over when the peer is re-added
+        assert_eq!(rate(Some(6000), 1000, elapsed), None);
+    }
+}
@@ -0,0 +1,117 @@
+//! What systemd passes to a service: credentials (`LoadCredential=`),
+//! sockets (socket activation) and private directories, so the unit can run
+//! sandboxed with `DynamicUser=`.
+
+use std::{
+    env, fs, io,
+    net::TcpListener,
+    path::{Path, PathBuf},
+};
+
+/// First file descriptor passed by socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Path of credential `name`, `None` when not started with it.
+pub fn credential(name: &str) -> Option<PathBuf> {
+    let path = Path::new(&env::var_os("CREDENTIALS_DIRECTORY")?).join(name);
+    path.exists().then_some(path)
+}
+
+/// Contents of credential `name` without surrounding whitespace.
+pub fn read_credential(name: &str) -> Option<io::Result<String>> {
+    let path = credential(name)?;
+    Some(fs::read_to_string(path).map(|data| data.trim().to_string()))
+}
+
+/// `StateDirectory=` of the unit, kept across restarts but private to it.
+pub fn state_directory() -> Option<PathBuf> {
+    // several directories are separated by colons, the first one is ours
+    let dirs = env::var("STATE_DIRECTORY").ok()?;
+    dirs.split(':').next().map(PathBuf::from)
+}
+
+/// Listening TCP socket passed by socket activation, taken once: the
+/// variables are cleared so children don't see it.
+#[cfg(unix)]
+pub fn take_tcp_listener() -> Option<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let count: i32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+
+    if pid != std::process::id() {
+        return None;
+    }
+
+    unsafe {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).find_map(|fd| {
+        if !is_tcp_listener(fd) {
+            log::warn!("ignoring socket activated fd {fd}, not a listening TCP socket");
+            return None;
+        }
+
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+        match listener.set_nonblocking(true) {
+            Ok(()) => Some(listener),
+            Err(err) => {
+                log::warn!("socket activated fd {fd}: {err}");
+                None
+            }
+        }
+    })
+}
+
+#[cfg(not(unix))]
+pub fn take_tcp_listener() -> Option<TcpListener> {
+    None
+}
+
+#[cfg(unix)]
+fn is_tcp_listener(fd: i32) -> bool {
+    let option = |name| {
+        let mut value: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                (&mut value as *mut libc::c_int).cast(),
+                &mut len,
+            )
+        };
+
+        (res == 0).then_some(value)
+    };
+
+    option(libc::SO_TYPE) == Some(libc::SOCK_STREAM) && option(libc::SO_ACCEPTCONN) == Some(1)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream, UdpSocket},
+        os::fd::AsRawFd,
+    };
+
+    use super::is_tcp_listener;
+
+    #[test]
+    fn test_is_tcp_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        assert!(is_tcp_listener(listener.as_raw_fd()));
+        assert!(!is_tcp_listener(stream.as_raw_fd()));
+        assert!(!is_tcp_listener(udp.as_raw_fd()));
+    }
+}
@@ -0,0 +1,119 @@
+//! Several independent networks hosted by one process, e.g. meshes for
+//! family and friends on one homelab coordinator. Each network keeps its
+//! own interface, configs, state and api token, [`check_isolation`] makes
+//! sure nothing leaks between them.
+
+use crate::{config::Config, error::Error, wg::config::WgConfig};
+
+/// One hosted network.
+#[derive(Debug, Clone)]
+pub struct Network {
+    pub iface: String,
+    pub settings: Config,
+    pub wg: WgConfig,
+}
+
+/// Fails on networks sharing what would let one reach into another: a
+/// wireguard key, overlapping tunnel addresses, an IRC psk, or the api
+/// listener and token.
+pub fn check_isolation(networks: &[Network]) -> Result<(), Error> {
+    for (i, a) in networks.iter().enumerate() {
+        for b in &networks[i + 1..] {
+            let shared = |what: &'static str| {
+                Err(Error::NotIsolated(a.iface.clone(), b.iface.clone(), what))
+            };
+
+            if a.iface == b.iface {
+                return shared("the interface");
+            }
+
+            if a.wg.interface.private_key == b.wg.interface.private_key {
+                return shared("the wireguard key");
+            }
+
+            if a.wg.interface.address.overlaps(&b.wg.interface.address) {
+                return shared("tunnel addresses");
+            }
+
+            if a.settings.irc.psk.is_some() && a.settings.irc.psk == b.settings.irc.psk {
+                return shared("the irc psk");
+            }
+
+            if let (Some(a), Some(b)) = (&a.settings.api, &b.settings.api) {
+                if a.listen == b.listen {
+                    return shared("the api listener");
+                }
+                if a.token == b.token {
+                    return shared("the api token");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Error,
+        wg::{Key, config::WgConfig},
+    };
+
+    use super::{Network, check_isolation};
+
+    #[test]
+    fn test_check_isolation() {
+        let network = |iface: &str, address: &str, api: &str| Network {
+            iface: iface.into(),
+            settings: toml::from_str(api).unwrap(),
+            wg: WgConfig::parse_config(
+                &mut format!(
+                    "[Interface]\nPrivateKey = {}\nAddress = {address}\n",
+                    Key::random()
+                )
+                .as_str(),
+            )
+            .unwrap(),
+        };
+        let api = |port: u16, token: &str| {
+            format!("[api]\nlisten = \"127.0.0.1:{port}\"\ntoken = \"{token}\"\n")
+        };
+
+        let family = network("wg-family", "10.1.0.1/24", &api(9191, "family"));
+        let friends = network("wg-friends", "10.2.0.1/24", &api(9192, "friends"));
+        assert!(check_isolation(&[family.clone(), friends.clone()]).is_ok());
+
+        let not_isolated = |networks: &[Network]| match check_isolation(networks) {
+            Err(Error::NotIsolated(_, _, what)) => what,
+            res => panic!("isolated: {res:?}"),
+        };
+
+        let mut overlapping = friends.clone();
+        overlapping.wg.interface.address = "10.1.0.128/25".parse().unwrap();
+        assert_eq!(
+            not_isolated(&[family.clone(), overlapping]),
+            "tunnel addresses"
+        );
+
+        let mut same_token = friends.clone();
+        same_token.settings.api.as_mut().unwrap().token = "family".into();
+        assert_eq!(not_isolated(&[family.clone(), same_token]), "the api token");
+
+        let mut same_key = friends.clone();
+        same_key.wg.interface.private_key = family.wg.interface.private_key;
+        assert_eq!(
+            not_isolated(&[family.clone(), same_key]),
+            "the wireguard key"
+        );
+
+        // networks without psk nor api have nothing to share there
+        let mut lab = network("wg-lab", "10.3.0.1/24", "");
+        let mut home = network("wg-home", "10.4.0.1/24", "");
+        assert!(check_isolation(&[family, friends, lab.clone(), home.clone()]).is_ok());
+
+        lab.settings.irc.psk = Some("secret".into());
+        home.settings.irc.psk = Some("secret".into());
+        assert_eq!(not_isolated(&[lab, home]), "the irc psk");
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    process::{Child, Command, Stdio},
+};
+
+use serde::Deserialize;
+
+use crate::relay::{self, Link};
+
+/// Helper tunneling wireguard UDP over something less likely to be blocked
+/// (udp2raw, wstunnel, ...).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransportConfig {
+    /// Must be the same on both sides.
+    pub name: String,
+
+    /// Client side command. `{remote}` is replaced with the endpoint the
+    /// peer advertised and `{local}` with the local UDP address wireguard
+    /// is pointed at.
+    pub client: String,
+
+    /// Endpoint of our server side, advertised to peers. The server itself
+    /// is expected to be running already.
+    pub listen: Option<String>,
+}
+
+/// Running client helper, killed on drop.
+#[derive(Debug)]
+pub struct Helper {
+    name: String,
+    local: SocketAddr,
+    running: Running,
+}
+
+#[derive(Debug)]
+enum Running {
+    Process(Child),
+
+    /// Built-in [`relay`] client.
+    Relay(Link),
+}
+
+impl Helper {
+    /// Runs the client command of `config` for the peer's `remote`
+    /// endpoint, which came over signaling and is checked first.
+    pub fn spawn(config: &TransportConfig, remote: &str) -> io::Result<Self> {
+        let local = free_udp_addr()?;
+        let args = command(&config.client, remote, local)?;
+        let (program, args) = args
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty client command"))?;
+
+        let child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .spawn()?;
+
+        Ok(Self {
+            name: config.name.clone(),
+            local,
+            running: Running::Process(child),
+        })
+    }
+
+    /// Helper relaying through a relay server.
+    pub fn relayed(link: Link) -> Self {
+        Self {
+            name: relay::TRANSPORT.to_string(),
+            local: link.local(),
+            running: Running::Relay(link),
+        }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Where wireguard sends the peer's packets to.
+    #[inline]
+    pub fn local(&self) -> SocketAddr {
+        self.local
+    }
+
+    /// Whether the helper process is still running.
+    pub fn is_alive(&mut self) -> bool {
+        match &mut self.running {
+            Running::Process(child) => matches!(child.try_wait(), Ok(None)),
+            Running::Relay(link) => link.is_alive(),
+        }
+    }
+}
+
+impl Drop for Helper {
+    fn drop(&mut self) {
+        if let Running::Process(child) = &mut self.running {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// First of our transports the peer accepts, with the peer's endpoint.
+pub fn pick<'a, 'b>(
+    ours: &'a [TransportConfig],
+    theirs: &'b [(String, String)],
+) -> Option<(&'a TransportConfig, &'b str)> {
+    ours.iter().find_map(|config| {
+        theirs
+            .iter()
+            .find(|(name, _)| *name == config.name)
+            .map(|(_, remote)| (config, remote.as_str()))
+    })
+}
+
+/// Program and arguments of the client command `template`. The endpoint
+/// is substituted within the words of the template, so it can't add any,
+/// and must not look like an option.
+fn command(template: &str, remote: &str, local: SocketAddr) -> io::Result<Vec<String>> {
+    if remote.is_empty()
+        || remote.starts_with('-')
+        || remote.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("refusing transport endpoint {remote:?}"),
+        ));
+    }
+
+    let local = local.to_string();
+    Ok(template
+        .split_whitespace()
+        .map(|word| word.replace("{remote}", remote).replace("{local}", &local))
+        .collect())
+}
+
+fn free_udp_addr() -> io::Result<SocketAddr> {
+    UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TransportConfig, command, pick};
+
+    #[test]
+    fn test_command() {
+        let local = "127.0.0.1:40000".parse().unwrap();
+        let template = "udp2raw -c -l{local} -r {remote} --raw-mode faketcp";
+
+        assert_eq!(
+            command(template, "198.51.100.1:4096", local).unwrap(),
+            [
+                "udp2raw",
+                "-c",
+                "-l127.0.0.1:40000",
+                "-r",
+                "198.51.100.1:4096",
+                "--raw-mode",
+                "faketcp"
+            ]
+        );
+
+        for remote in ["", "1.2.3.4:1 --exec sh", "--exec=sh", "a\tb", "a\nb"] {
+            assert!(command(template, remote, local).is_err(), "{remote:?}");
+        }
+    }
+
+    #[test]
+    fn test_pick_in_our_order() {
+        let ours = ["udp2raw", "wstunnel"].map(|name| TransportConfig {
+            name: name.to_string(),
+            client: String::new(),
+            listen: None,
+        });
+
+        let theirs = vec![
+            ("wstunnel".to_string(), "wss://vpn.example.com".to_string()),
+            ("udp2raw".to_string(), "198.51.100.1:4096".to_string()),
+        ];
+
+        let (config, remote) = pick(&ours, &theirs).unwrap();
+        assert_eq!(config.name, "udp2raw");
+        assert_eq!(remote, "198.51.100.1:4096");
+
+        assert!(pick(&ours[1..], &theirs[1..]).is_none());
+    }
+}
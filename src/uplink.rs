@@ -0,0 +1,103 @@
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+use crate::{error::Error, route, wg::WireguardApi};
+
+/// `[[uplink]]` of a multi-homed host. The first one carries discovery and
+/// wireguard traffic, the others take over in order when it fails and are
+/// discovered and advertised as additional candidates when `advertise` is
+/// set.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UplinkConfig {
+    pub name: String,
+
+    /// Network device STUN queries are bound to.
+    pub device: String,
+
+    /// Marks wireguard packets so a rule routes them via `table`.
+    pub fwmark: Option<u32>,
+
+    /// Routing table of the uplink, defaults to the fwmark.
+    pub table: Option<u32>,
+
+    /// Default route of `table` is set to go via this gateway on `device`,
+    /// without it the table is expected to be maintained elsewhere.
+    pub gateway: Option<IpAddr>,
+
+    /// Discover the mapping of the uplink and advertise it to peers.
+    #[serde(default)]
+    pub advertise: bool,
+}
+
+impl UplinkConfig {
+    #[inline]
+    pub fn table(&self) -> Option<u32> {
+        self.table.or(self.fwmark)
+    }
+}
+
+/// Sends wireguard traffic via `uplink`: marks it and routes marked
+/// packets through the uplink's table.
+pub async fn select<W>(wg: &mut W, iface: &str, uplink: &UplinkConfig) -> Result<(), Error>
+where
+    W: WireguardApi,
+    Error: From<W::Error>,
+{
+    let (Some(mark), Some(table)) = (uplink.fwmark, uplink.table()) else {
+        return Ok(());
+    };
+
+    if let Some(gateway) = uplink.gateway {
+        route::replace_default(&uplink.device, gateway, table)?;
+    }
+
+    route::replace_rule(mark, table)?;
+    wg.set_fwmark(iface, mark).await?;
+
+    log::info!("wireguard traffic goes via uplink {}", uplink.name);
+    Ok(())
+}
+
+/// Removes the rule installed by [`select`], the fwmark goes with the
+/// interface.
+pub fn release(uplink: &UplinkConfig) -> Result<(), Error> {
+    let (Some(mark), Some(table)) = (uplink.fwmark, uplink.table()) else {
+        return Ok(());
+    };
+
+    route::remove_rule(mark, table)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+
+    #[test]
+    fn test_uplink_config() {
+        let config: Config = toml::from_str(
+            r#"
+[[uplink]]
+name = "fiber"
+device = "eth0"
+fwmark = 51820
+gateway = "192.168.1.1"
+
+[[uplink]]
+name = "lte"
+device = "wwan0"
+advertise = true
+"#,
+        )
+        .unwrap();
+
+        let [fiber, lte] = &config.uplink[..] else {
+            panic!("expected two uplinks");
+        };
+        assert_eq!(fiber.table(), Some(51820));
+        assert!(!fiber.advertise);
+        assert_eq!(lte.table(), None);
+        assert!(lte.advertise);
+    }
+}
@@ -0,0 +1,647 @@
+//! `wg-disco check`: strict validation of the wireguard config and the
+//! wg-disco settings of an interface, for CI of the repos holding them.
+//! Unlike loading them it goes on after the first problem and tells where
+//! every one is.
+
+use std::{fmt, fs, io, net::IpAddr};
+
+use crate::{
+    config::Config,
+    crypto::x25519_base,
+    doctor::Severity,
+    json::Value,
+    wg::{Cidr, Key},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub file: String,
+
+    /// Line the problem is on, 1-based.
+    pub line: Option<usize>,
+
+    /// Setting it is about, e.g. `Peer[1].AllowedIPs` or `irc`.
+    pub path: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Problem {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("file", self.file.as_str().into()),
+            ("line", self.line.into()),
+            ("path", self.path.as_str().into()),
+            (
+                "severity",
+                match self.severity {
+                    Severity::Fail => "error",
+                    _ => "warning",
+                }
+                .into(),
+            ),
+            ("message", self.message.as_str().into()),
+        ])
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.file)?;
+        if let Some(line) = self.line {
+            write!(f, "{line}:")?;
+        }
+
+        let severity = match self.severity {
+            Severity::Fail => "error",
+            _ => "warning",
+        };
+        write!(f, " {severity}: {}: {}", self.path, self.message)
+    }
+}
+
+/// What a value has to be.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Key,
+    Cidr,
+    Cidrs,
+    Ips,
+    U16,
+    U32,
+    Bool,
+    Endpoint,
+    Any,
+}
+
+/// Keys of the sections as wg-disco spells them, other spellings are
+/// ignored by it.
+const INTERFACE: &[(&str, Kind)] = &[
+    ("PrivateKey", Kind::Key),
+    ("Address", Kind::Cidr),
+    ("ListenPort", Kind::U16),
+    ("Fwmark", Kind::U32),
+    ("MTU", Kind::U16),
+    ("DNS", Kind::Ips),
+    ("Table", Kind::U32),
+    ("AdvertiseRoutes", Kind::Cidrs),
+    ("ExcludeRoutes", Kind::Cidrs),
+    ("KillSwitch", Kind::Bool),
+    ("Bandwidth", Kind::U32),
+    ("PreUp", Kind::Any),
+    ("PostUp", Kind::Any),
+    ("PreDown", Kind::Any),
+    ("PostDown", Kind::Any),
+    ("SaveConfig", Kind::Bool),
+];
+
+const PEER: &[(&str, Kind)] = &[
+    ("PublicKey", Kind::Key),
+    ("PresharedKey", Kind::Key),
+    ("Endpoint", Kind::Endpoint),
+    ("AllowedIPs", Kind::Cidrs),
+    ("PersistentKeepalive", Kind::U32),
+];
+
+/// Why `value` isn't a `kind`.
+fn check_value(kind: Kind, value: &str) -> Option<String> {
+    let list = || value.split(',').map(str::trim);
+
+    let res = match kind {
+        Kind::Key => value.parse::<Key>().err().map(|err| err.to_string()),
+        Kind::Cidr => check_cidr(value),
+        Kind::Cidrs => list().find_map(|cidr| check_cidr(cidr).map(|err| format!("{cidr}: {err}"))),
+        Kind::Ips => {
+            list().find_map(|ip| ip.parse::<IpAddr>().err().map(|err| format!("{ip}: {err}")))
+        }
+        Kind::U16 => value.parse::<u16>().err().map(|err| err.to_string()),
+        Kind::U32 => value.parse::<u32>().err().map(|err| err.to_string()),
+        Kind::Bool => value.parse::<bool>().err().map(|err| err.to_string()),
+        Kind::Endpoint => check_endpoint(value),
+        Kind::Any => None,
+    };
+
+    res.map(|err| format!("bad value {value:?}: {err}"))
+}
+
+/// [`Cidr`] clamps the prefix length, wireguard doesn't.
+fn check_cidr(value: &str) -> Option<String> {
+    let cidr = match value.parse::<Cidr>() {
+        Ok(cidr) => cidr,
+        Err(err) => return Some(err.to_string()),
+    };
+
+    let bits = if cidr.ip.is_ipv4() { 32 } else { 128 };
+    match value
+        .split_once('/')
+        .map(|(_, mask)| mask.trim().parse::<u32>())
+    {
+        Some(Ok(mask)) if mask > bits => Some(format!("prefix longer than {bits} bits")),
+        _ => None,
+    }
+}
+
+/// Endpoints are `ip:port`, `[ipv6]:port` or `hostname:port`.
+fn check_endpoint(value: &str) -> Option<String> {
+    if value.parse::<std::net::SocketAddr>().is_ok() {
+        return None;
+    }
+
+    let Some((host, port)) = value.rsplit_once(':') else {
+        return Some("no port".into());
+    };
+
+    if port.parse::<u16>().map_or(true, |port| port == 0) {
+        return Some(format!("bad port {port:?}"));
+    }
+
+    if host.contains(':') {
+        return Some("IPv6 addresses go in brackets".into());
+    }
+
+    let hostname = !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    match hostname {
+        true => None,
+        false => Some(format!("bad host {host:?}")),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Section {
+    path: String,
+    line: usize,
+    peer: bool,
+
+    /// Keys given, with their line.
+    keys: Vec<(String, usize)>,
+    public_key: Option<Key>,
+    private_key: Option<Key>,
+    allowed_ips: Vec<(Cidr, usize)>,
+}
+
+/// Problems of the wireguard config `text` of `file`, along with the keys
+/// of its peers.
+pub fn wg_config(file: &str, text: &str) -> (Vec<Problem>, Vec<Key>) {
+    let mut problems = Vec::new();
+    let mut problem = |line: Option<usize>, path: &str, severity, message: String| {
+        problems.push(Problem {
+            file: file.to_string(),
+            line,
+            path: path.to_string(),
+            severity,
+            message,
+        })
+    };
+
+    let mut sections: Vec<Section> = Vec::new();
+    let mut peers = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+
+        // wg-quick drops everything from `#` on
+        let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            let (path, peer) = match name.trim() {
+                "Interface" => ("Interface".to_string(), false),
+                "Peer" => {
+                    peers += 1;
+                    (format!("Peer[{}]", peers - 1), true)
+                }
+                name => {
+                    problem(Some(number), name, Severity::Fail, "unknown section".into());
+                    continue;
+                }
+            };
+
+            sections.push(Section {
+                path,
+                line: number,
+                peer,
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            problem(
+                Some(number),
+                "",
+                Severity::Fail,
+                "expected key = value".into(),
+            );
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+
+        let Some(section) = sections.last_mut() else {
+            problem(
+                Some(number),
+                key,
+                Severity::Fail,
+                "outside of a section".into(),
+            );
+            continue;
+        };
+        let path = format!("{}.{key}", section.path);
+
+        let schema = match section.peer {
+            true => PEER,
+            false => INTERFACE,
+        };
+        let Some(&(_, kind)) = schema.iter().find(|(name, _)| *name == key) else {
+            let message = match schema
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            {
+                Some((name, _)) => format!("unknown key, wg-disco only reads {name}"),
+                None => "unknown key".into(),
+            };
+            problem(Some(number), &path, Severity::Fail, message);
+            continue;
+        };
+
+        if let Some((_, first)) = section.keys.iter().find(|(name, _)| name == key) {
+            let message = format!("given again after line {first}, only the last one counts");
+            problem(Some(number), &path, Severity::Fail, message);
+        }
+        section.keys.push((key.to_string(), number));
+
+        if let Some(err) = check_value(kind, value) {
+            problem(Some(number), &path, Severity::Fail, err);
+            continue;
+        }
+
+        match key {
+            "PublicKey" => section.public_key = value.parse().ok(),
+            "PrivateKey" => section.private_key = value.parse().ok(),
+            "AllowedIPs" => section.allowed_ips.extend(
+                value
+                    .split(',')
+                    .filter_map(|cidr| cidr.parse::<Cidr>().ok())
+                    .map(|cidr| (cidr.network(), number)),
+            ),
+            _ => {}
+        }
+    }
+
+    let has = |section: &Section, key: &str| section.keys.iter().any(|(name, _)| name == key);
+    let mut interfaces = sections.iter().filter(|section| !section.peer);
+    let own_key = match interfaces.next() {
+        Some(interface) => {
+            if !has(interface, "PrivateKey") {
+                let message = "no PrivateKey".into();
+                problem(Some(interface.line), "Interface", Severity::Fail, message);
+            }
+            interface
+                .private_key
+                .map(|key| Key::from(x25519_base(key.as_bytes())))
+        }
+        None => {
+            problem(
+                None,
+                "Interface",
+                Severity::Fail,
+                "no [Interface] section".into(),
+            );
+            None
+        }
+    };
+    for interface in interfaces {
+        let message = "second [Interface] section".into();
+        problem(Some(interface.line), "Interface", Severity::Fail, message);
+    }
+
+    let peers: Vec<&Section> = sections.iter().filter(|section| section.peer).collect();
+    for (i, peer) in peers.iter().enumerate() {
+        let line = Some(peer.line);
+        if !has(peer, "PublicKey") {
+            problem(
+                line,
+                &peer.path,
+                Severity::Fail,
+                "peer without PublicKey".into(),
+            );
+        }
+        if !has(peer, "AllowedIPs") {
+            let message = "no AllowedIPs, wireguard routes nothing to the peer".into();
+            problem(line, &peer.path, Severity::Fail, message);
+        }
+
+        if peer.public_key.is_some() && peer.public_key == own_key {
+            let message = "PublicKey is our own".into();
+            problem(line, &peer.path, Severity::Fail, message);
+        }
+        if let Some(other) = peers[..i]
+            .iter()
+            .find(|other| other.public_key.is_some() && other.public_key == peer.public_key)
+        {
+            let message = format!("same PublicKey as {}", other.path);
+            problem(line, &peer.path, Severity::Fail, message);
+        }
+
+        for (cidr, line) in &peer.allowed_ips {
+            let path = format!("{}.AllowedIPs", peer.path);
+
+            for other in &peers[..i] {
+                let Some((theirs, _)) = other
+                    .allowed_ips
+                    .iter()
+                    .find(|(theirs, _)| theirs.overlaps(cidr))
+                else {
+                    continue;
+                };
+
+                match theirs == cidr {
+                    true => {
+                        let message = format!(
+                            "{cidr} is given to {} as well, wireguard routes it to the last one",
+                            other.path
+                        );
+                        problem(Some(*line), &path, Severity::Fail, message);
+                    }
+                    false => {
+                        let message = format!(
+                            "{cidr} overlaps {theirs} of {}, the more specific one wins",
+                            other.path
+                        );
+                        problem(Some(*line), &path, Severity::Warn, message);
+                    }
+                }
+            }
+        }
+    }
+
+    let keys = peers.iter().filter_map(|peer| peer.public_key).collect();
+    (problems, keys)
+}
+
+/// Problems of the wg-disco settings `text` of `file`. Every section is
+/// checked against what [`Config`] takes on its own, so all of them are
+/// reported. Settings naming keys which aren't among `peers` are warned
+/// about.
+pub fn settings(file: &str, text: &str, peers: &[Key]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |line: Option<usize>, path: &str, severity, message: String| {
+        problems.push(Problem {
+            file: file.to_string(),
+            line,
+            path: path.to_string(),
+            severity,
+            message,
+        })
+    };
+
+    let table: toml::Table = match toml::from_str(text) {
+        Ok(table) => table,
+        Err(err) => {
+            let line = err.span().map(|span| line_of(text, span.start));
+            problem(line, "", Severity::Fail, err.message().to_string());
+            return problems;
+        }
+    };
+
+    for (key, value) in &table {
+        let section = toml::Table::from_iter([(key.clone(), value.clone())]);
+        if let Err(err) = toml::Value::Table(section).try_into::<Config>() {
+            let message = err.message().trim().to_string();
+            problem(find_key(text, key), key, Severity::Fail, message);
+        }
+    }
+
+    let Ok(config) = toml::from_str::<Config>(text) else {
+        return problems;
+    };
+
+    let named = config
+        .group
+        .iter()
+        .flat_map(|(name, group)| {
+            group
+                .peers
+                .iter()
+                .map(move |key| (format!("group.{name}"), "group", key))
+        })
+        .chain(
+            config
+                .alias
+                .iter()
+                .map(|(name, key)| (format!("alias.{name}"), "alias", key)),
+        );
+    for (path, section, key) in named {
+        if !peers.contains(key) {
+            let message = format!("{key} is not a peer of the wireguard config");
+            problem(find_key(text, section), &path, Severity::Warn, message);
+        }
+    }
+
+    let servers = config
+        .stun
+        .servers
+        .iter()
+        .map(|server| ("stun.servers", server))
+        .chain(
+            config
+                .relay
+                .server
+                .iter()
+                .map(|server| ("relay.server", server)),
+        );
+    for (path, server) in servers {
+        if let Some(err) = check_endpoint(server) {
+            let message = format!("bad server {server:?}: {err}");
+            problem(
+                find_key(text, path.split('.').next().unwrap_or(path)),
+                path,
+                Severity::Fail,
+                message,
+            );
+        }
+    }
+
+    problems
+}
+
+/// Checks the wireguard config and the wg-disco settings files, a missing
+/// settings file means defaults.
+pub fn files(wg_config_path: &str, settings_path: &str) -> io::Result<Vec<Problem>> {
+    let (mut problems, peers) = wg_config(wg_config_path, &fs::read_to_string(wg_config_path)?);
+
+    match fs::read_to_string(settings_path) {
+        Ok(text) => problems.extend(settings(settings_path, &text, &peers)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    Ok(problems)
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+/// Line of the top-level `key`, as a table header or a key of its own.
+fn find_key(text: &str, key: &str) -> Option<usize> {
+    text.lines()
+        .position(|line| {
+            let line = line.trim();
+            let header = line.trim_start_matches('[').trim_end_matches(']');
+
+            (line.starts_with('[') && (header == key || header.starts_with(&format!("{key}."))))
+                || line
+                    .strip_prefix(key)
+                    .is_some_and(|rest| rest.trim_start().starts_with('='))
+        })
+        .map(|i| i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{crypto::x25519_base, doctor::Severity, wg::Key};
+
+    use super::{Problem, settings, wg_config};
+
+    #[test]
+    fn test_wg_config() {
+        let private = Key::random();
+        let own = Key::from(x25519_base(private.as_bytes()));
+        let (laptop, phone) = (Key::random(), Key::random());
+
+        let text = format!(
+            "[Interface]
+PrivateKey = {private}
+Address = 10.0.0.1/24
+FwMark = 51820
+DNS = 1.1.1.1, 8.8.8.8
+
+[Peer] # laptop
+PublicKey = {laptop}
+AllowedIPs = 10.0.0.2/32, 192.168.1.0/24
+Endpoint = laptop.example.com:51820
+
+[Peer]
+PublicKey = {laptop}
+AllowedIPs = 10.0.0.2/32
+AllowedIPs = 192.168.1.128/25
+
+[Peer]
+PublicKey = {phone}
+Endpoint = 2001:db8::1:51820
+
+[Peer]
+PublicKey = {own}
+AllowedIPs = 10.0.0.0/33
+"
+        );
+
+        let (problems, peers) = wg_config("wg0.conf", &text);
+        assert_eq!(peers, vec![laptop, laptop, phone, own]);
+
+        let found: Vec<_> = problems
+            .iter()
+            .map(|problem| (problem.line, problem.path.as_str(), problem.severity))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Some(4), "Interface.FwMark", Severity::Fail),
+                (Some(15), "Peer[1].AllowedIPs", Severity::Fail),
+                (Some(19), "Peer[2].Endpoint", Severity::Fail),
+                (Some(23), "Peer[3].AllowedIPs", Severity::Fail),
+                (Some(12), "Peer[1]", Severity::Fail),
+                (Some(14), "Peer[1].AllowedIPs", Severity::Fail),
+                (Some(15), "Peer[1].AllowedIPs", Severity::Warn),
+                (Some(17), "Peer[2]", Severity::Fail),
+                (Some(21), "Peer[3]", Severity::Fail),
+            ]
+        );
+        assert_eq!(
+            problems[0].to_string(),
+            "wg0.conf:4: error: Interface.FwMark: unknown key, wg-disco only reads Fwmark"
+        );
+
+        let (problems, _) = wg_config("wg0.conf", "[Peer]\nPublicKey = x\n");
+        let messages: Vec<_> = problems.iter().map(|p| p.message.as_str()).collect();
+        assert_eq!(messages[1], "no [Interface] section");
+    }
+
+    #[test]
+    fn test_settings() {
+        let peer = Key::random();
+        let text = format!(
+            "[irc]
+chanel = \"#mesh\"
+
+[stun]
+servers = [\"stun.example.com\"]
+
+[alias]
+laptop = \"{peer}\"
+phone = \"{}\"
+
+[api]
+listen = 1
+",
+            Key::random()
+        );
+
+        let problems = settings("wg0.toml", &text, &[peer]);
+        let found: Vec<_> = problems
+            .iter()
+            .map(
+                |Problem {
+                     line,
+                     path,
+                     severity,
+                     ..
+                 }| (*line, path.as_str(), *severity),
+            )
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Some(11), "api", Severity::Fail),
+                (Some(1), "irc", Severity::Fail),
+            ]
+        );
+        assert!(problems[1].message.contains("chanel"));
+
+        let text = text
+            .replace("chanel", "channel")
+            .replace("listen = 1", "listen = \"127.0.0.1:9191\"\ntoken = \"t\"");
+        let problems = settings("wg0.toml", &text, &[peer]);
+        let found: Vec<_> = problems
+            .iter()
+            .map(
+                |Problem {
+                     line,
+                     path,
+                     severity,
+                     ..
+                 }| (*line, path.as_str(), *severity),
+            )
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Some(7), "alias.phone", Severity::Warn),
+                (Some(4), "stun.servers", Severity::Fail),
+            ]
+        );
+
+        let problems = settings("wg0.toml", "[irc\n", &[]);
+        assert_eq!(problems[0].line, Some(1));
+    }
+}
@@ -0,0 +1,173 @@
+//! `wg-disco web`, a small read-only dashboard on top of the HTTP API of a
+//! running daemon. The browser never sees the API token.
+//!
+//! Only requests naming the dashboard by an IP address or `localhost` are
+//! served, so a web page whose name is rebound to the loopback address
+//! can't read the API through it.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::api::{ApiConfig, REQUEST_TIMEOUT, read_head, respond_with};
+
+const DASHBOARD: &str = include_str!("web/dashboard.html");
+
+/// Peer lists of big meshes still fit, anything bigger is not the API.
+const MAX_RESPONSE_LEN: u64 = 1024 * 1024;
+
+/// Serves the dashboard on `listen`, `GET /api/<name>` is forwarded to
+/// `GET /v1/<name>` of the daemon API.
+pub async fn serve(listen: SocketAddr, api: ApiConfig) -> io::Result<()> {
+    if !listen.ip().is_loopback() {
+        log::warn!("dashboard listens on non-loopback {listen}, it has no authentication");
+    }
+
+    let listener = TcpListener::bind(listen).await?;
+    let api = Arc::new(api);
+    log::info!("dashboard on http://{listen}/");
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let api = api.clone();
+
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, handle(stream, &api)).await {
+                Ok(Err(err)) => log::debug!("dashboard {peer}: {err}"),
+                Err(_) => log::debug!("dashboard {peer}: timed out"),
+                Ok(Ok(())) => {}
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, api: &ApiConfig) -> io::Result<()> {
+    let Some(head) = read_head(&mut stream).await? else {
+        return respond_with(&mut stream, 413, "text/plain", "request too large").await;
+    };
+
+    let mut request = head.lines().next().unwrap_or_default().split(' ');
+    let (method, path) = (
+        request.next().unwrap_or_default(),
+        request.next().unwrap_or_default(),
+    );
+
+    if method != "GET" {
+        return respond_with(&mut stream, 405, "text/plain", "method not allowed").await;
+    }
+
+    if !trusted_host(&head) {
+        return respond_with(&mut stream, 421, "text/plain", "unexpected host").await;
+    }
+
+    match path {
+        "/" | "/index.html" => {
+            respond_with(&mut stream, 200, "text/html; charset=utf-8", DASHBOARD).await
+        }
+        "/api/status" | "/api/peers" | "/api/routes" | "/api/events" => {
+            let path = path.replacen("/api/", "/v1/", 1);
+
+            let (status, body) = match fetch(api, &path).await {
+                Ok(res) => res,
+                Err(err) => {
+                    log::warn!("daemon api at {} unreachable: {err}", api.listen);
+                    (502, "{\"error\":\"daemon api unreachable\"}".into())
+                }
+            };
+
+            respond_with(&mut stream, status, "application/json", &body).await
+        }
+        _ => respond_with(&mut stream, 404, "text/plain", "not found").await,
+    }
+}
+
+/// Whether the `Host` header names an IP address or `localhost`. Names
+/// anybody can point at our address are refused.
+fn trusted_host(head: &str) -> bool {
+    let Some(host) = head
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim())
+    else {
+        return false;
+    };
+
+    // the port is optional, brackets only around v6 addresses
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split_once(']').map_or(v6, |(ip, _)| ip),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+
+    host.eq_ignore_ascii_case("localhost") || host.parse::<IpAddr>().is_ok()
+}
+
+/// Status code and body of `GET <path>` of the daemon API.
+pub async fn fetch(api: &ApiConfig, path: &str) -> io::Result<(u16, String)> {
+    let mut stream = TcpStream::connect(api.listen).await?;
+
+    let request = format!(
+        "GET {path} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Authorization: Bearer {}\r\n\
+         Connection: close\r\n\r\n",
+        api.listen, api.token
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buf = Vec::new();
+    stream.take(MAX_RESPONSE_LEN).read_to_end(&mut buf).await?;
+
+    parse_response(&buf)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed api response"))
+}
+
+/// Status code and body of a `Connection: close` response.
+fn parse_response(buf: &[u8]) -> Option<(u16, String)> {
+    let at = buf.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&buf[..at]).ok()?;
+    let status = head.split(' ').nth(1)?.parse().ok()?;
+
+    Some((status, String::from_utf8_lossy(&buf[at + 4..]).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_response, trusted_host};
+
+    #[test]
+    fn test_parse_response() {
+        let res = b"HTTP/1.1 503 Service Unavailable\r\n\
+                    Content-Type: application/json\r\n\r\n\
+                    {\"error\":\"runner is not running\"}";
+
+        assert_eq!(
+            parse_response(res),
+            Some((503, "{\"error\":\"runner is not running\"}".into()))
+        );
+        assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\n"), None);
+    }
+
+    #[test]
+    fn test_trusted_host() {
+        let head = |host: &str| format!("GET / HTTP/1.1\r\nHost: {host}\r\n");
+
+        assert!(trusted_host(&head("127.0.0.1:8080")));
+        assert!(trusted_host(&head("localhost:8080")));
+        assert!(trusted_host(&head("[::1]:8080")));
+        assert!(trusted_host(&head("192.168.1.2")));
+
+        // a rebound name, or none at all
+        assert!(!trusted_host(&head("attacker.example:8080")));
+        assert!(!trusted_host(&head("localhost.attacker.example")));
+        assert!(!trusted_host("GET / HTTP/1.1\r\n"));
+    }
+}
@@ -1,17 +1,25 @@
 use base64::prelude::*;
 use bincode::{Decode, Encode};
 use config::ParseError;
+use executor::{Access, Executor};
 use instance::WgInterfaceInfo;
 use peer::WgPeerInfo;
 use std::{
+    collections::HashMap,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     str::FromStr,
 };
 
 pub mod cmd;
 pub mod config;
+pub mod executor;
 pub mod instance;
+pub mod memory;
+pub mod netlink;
 pub mod peer;
+#[cfg(unix)]
+pub mod uapi;
+pub mod watcher;
 
 pub type DecodeError = base64::DecodeSliceError;
 
@@ -29,20 +37,44 @@ impl FromStr for Key {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut inner = [0u8; 32];
-        BASE64_STANDARD.decode_slice(s, &mut inner)?;
+        let len = BASE64_STANDARD.decode_slice(s, &mut inner)?;
+
+        if len != inner.len() {
+            return Err(base64::DecodeError::InvalidLength(len).into());
+        }
+
         Ok(Key(inner))
     }
 }
 
+impl<'de> serde::Deserialize<'de> for Key {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 impl Key {
     pub fn random() -> Key {
         Key(rand::random())
     }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for Key {
+    fn from(bytes: [u8; 32]) -> Self {
+        Key(bytes)
+    }
 }
 
 impl std::fmt::Display for Key {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", BASE64_STANDARD.encode(&self.0))
+        write!(f, "{}", BASE64_STANDARD.encode(self.0))
     }
 }
 
@@ -64,7 +96,7 @@ impl std::fmt::Display for Peer {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode)]
 pub struct Cidr {
     pub ip: IpAddr,
     pub mask: u8,
@@ -87,15 +119,118 @@ impl FromStr for Cidr {
         let ip = ip.trim();
         let mask = mask.trim();
 
-        let mask: u32 = if !mask.is_empty() { mask.parse()? } else { 32 };
+        let ip: IpAddr = ip.parse()?;
+        let bits = if ip.is_ipv4() { 32 } else { 128 };
+        let mask: u32 = if !mask.is_empty() {
+            mask.parse()?
+        } else {
+            bits
+        };
 
         Ok(Cidr {
-            ip: ip.parse()?,
-            mask: mask as _,
+            ip,
+            mask: mask.min(bits) as _,
         })
     }
 }
 
+impl<'de> serde::Deserialize<'de> for Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.ip, self.mask)
+    }
+}
+
+impl Cidr {
+    #[inline]
+    fn bits(&self) -> u8 {
+        if self.ip.is_ipv4() { 32 } else { 128 }
+    }
+
+    /// Address as a number aligned to the top of u128, so v4 and v6 share
+    /// the same mask arithmetic.
+    #[inline]
+    fn value(&self) -> u128 {
+        match self.ip {
+            IpAddr::V4(ip) => (u32::from(ip) as u128) << 96,
+            IpAddr::V6(ip) => u128::from(ip),
+        }
+    }
+
+    fn from_value(v4: bool, value: u128, mask: u8) -> Cidr {
+        let ip = if v4 {
+            IpAddr::V4(((value >> 96) as u32).into())
+        } else {
+            IpAddr::V6(value.into())
+        };
+
+        Cidr { ip, mask }
+    }
+
+    #[inline]
+    fn netmask(mask: u8) -> u128 {
+        u128::MAX.checked_shl(128 - mask as u32).unwrap_or(0)
+    }
+
+    /// Cidr with host bits cleared.
+    pub fn network(&self) -> Cidr {
+        Cidr::from_value(
+            self.ip.is_ipv4(),
+            self.value() & Self::netmask(self.mask),
+            self.mask,
+        )
+    }
+
+    pub fn contains(&self, other: &Cidr) -> bool {
+        self.ip.is_ipv4() == other.ip.is_ipv4()
+            && self.mask <= other.mask
+            && (self.value() ^ other.value()) & Self::netmask(self.mask) == 0
+    }
+
+    pub fn overlaps(&self, other: &Cidr) -> bool {
+        self.contains(other) || other.contains(self)
+    }
+
+    /// Prefixes covering `self` except `other`.
+    pub fn subtract(&self, other: &Cidr) -> Vec<Cidr> {
+        if !self.overlaps(other) {
+            return vec![*self];
+        }
+
+        if other.contains(self) {
+            return vec![];
+        }
+
+        let v4 = self.ip.is_ipv4();
+        let mut rest = Vec::with_capacity((other.mask - self.mask) as usize);
+        let mut current = self.network();
+
+        while current.mask < other.mask && current.mask < self.bits() {
+            let mask = current.mask + 1;
+            let bit = 1u128 << (127 - current.mask as u32);
+            let low = Cidr::from_value(v4, current.value(), mask);
+            let high = Cidr::from_value(v4, current.value() | bit, mask);
+
+            if low.contains(other) {
+                rest.push(high);
+                current = low;
+            } else {
+                rest.push(low);
+                current = high;
+            }
+        }
+
+        rest
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Endpoint {
     Domain(String),
@@ -143,18 +278,288 @@ pub struct WgState {
 pub trait WireguardApi {
     type Error;
 
-    fn get_pub_key(&self, iface: &str) -> Result<Key, Self::Error>;
-    fn get_listen_port(&self, iface: &str) -> Result<u16, Self::Error>;
-    fn get_endpoints(
+    async fn get_pub_key(&self, iface: &str) -> Result<Key, Self::Error>;
+    async fn get_listen_port(&self, iface: &str) -> Result<u16, Self::Error>;
+    async fn get_endpoints(
         &self,
         iface: &str,
-    ) -> Result<std::collections::HashMap<super::Key, Option<SocketAddr>>, Self::Error>;
+    ) -> Result<HashMap<Key, Option<SocketAddr>>, Self::Error>;
+    async fn get_state(&self, iface: &str) -> Result<WgState, Self::Error>;
+
+    /// Latest handshake of every peer, unix time in seconds, `None` for
+    /// peers which never completed one.
+    async fn get_handshakes(&self, iface: &str) -> Result<HashMap<Key, Option<u32>>, Self::Error> {
+        Ok(self
+            .get_state(iface)
+            .await?
+            .peers
+            .into_iter()
+            .map(|peer| (peer.public_key, peer.latest_handshake))
+            .collect())
+    }
 
-    fn set_listen_port(&mut self, iface: &str, port: u16) -> Result<(), Self::Error>;
-    fn set_peer_endpoint(
+    async fn set_listen_port(&mut self, iface: &str, port: u16) -> Result<(), Self::Error>;
+    async fn set_peer_endpoint(
         &mut self,
         iface: &str,
         peer: Key,
         endpoint: Endpoint,
     ) -> Result<(), Self::Error>;
+
+    async fn set_allowed_ips(
+        &mut self,
+        iface: &str,
+        peer: Key,
+        ips: &[Cidr],
+    ) -> Result<(), Self::Error>;
+
+    /// `0` turns keepalives off.
+    async fn set_persistent_keepalive(
+        &mut self,
+        iface: &str,
+        peer: Key,
+        interval: u16,
+    ) -> Result<(), Self::Error>;
+
+    /// Marks outgoing packets for policy routing, `0` turns it off.
+    async fn set_fwmark(&mut self, iface: &str, mark: u32) -> Result<(), Self::Error>;
+
+    /// Adds a peer, or updates it when it exists. Settings left `None` are
+    /// not touched, allowed IPs replace the peer's current ones.
+    async fn add_peer(&mut self, iface: &str, peer: &WgPeerInfo) -> Result<(), Self::Error>;
+
+    /// Drops the peer and its session from the interface.
+    async fn remove_peer(&mut self, iface: &str, peer: Key) -> Result<(), Self::Error>;
+
+    /// `None` removes the preshared key.
+    async fn set_preshared_key(
+        &mut self,
+        iface: &str,
+        peer: Key,
+        psk: Option<Key>,
+    ) -> Result<(), Self::Error>;
+
+    /// Updates endpoints of several peers at once.
+    async fn set_peer_endpoints(
+        &mut self,
+        iface: &str,
+        endpoints: &[(Key, Endpoint)],
+    ) -> Result<(), Self::Error>;
+
+    /// Makes wireguard initiate a handshake right away instead of waiting
+    /// for traffic. Switching keepalives on sends one immediately, which
+    /// needs a session. `keepalive` is the interval left configured.
+    async fn trigger_handshake(
+        &mut self,
+        iface: &str,
+        peer: Key,
+        keepalive: u16,
+    ) -> Result<(), Self::Error> {
+        self.set_persistent_keepalive(iface, peer, 0).await?;
+        self.set_persistent_keepalive(iface, peer, keepalive.max(1))
+            .await?;
+
+        if keepalive == 0 {
+            self.set_persistent_keepalive(iface, peer, 0).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// How the daemon talks to wireguard.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum WgBackendKind {
+    /// Run the `wg` binary of wireguard-tools.
+    #[cfg_attr(not(target_os = "android"), default)]
+    Cmd,
+
+    /// Talk to the kernel module over generic netlink, Linux only.
+    Netlink,
+
+    /// Talk to userspace wireguard (wireguard-go, boringtun) over its
+    /// UAPI socket.
+    #[cfg(unix)]
+    Uapi,
+
+    /// Run `wg` as root through `su -c`, for rooted Android phones.
+    #[cfg_attr(target_os = "android", default)]
+    Su,
+}
+
+/// Either backend, picked at runtime, its operations run by an
+/// [`Executor`].
+#[derive(Debug, Clone)]
+pub struct WgBackend {
+    backend: Backend,
+    executor: Executor,
+}
+
+#[derive(Debug, Clone)]
+enum Backend {
+    Cmd(cmd::WgCmdBackend),
+    Netlink(netlink::WgNetlinkBackend),
+    #[cfg(unix)]
+    Uapi(uapi::WgUapiBackend),
+}
+
+impl WgBackend {
+    /// Transient failures are retried by `retry`.
+    pub fn new(kind: WgBackendKind, retry: crate::retry::RetryPolicy) -> Self {
+        let backend = match kind {
+            WgBackendKind::Cmd => Backend::Cmd(cmd::WgCmdBackend::new()),
+            WgBackendKind::Netlink => Backend::Netlink(netlink::WgNetlinkBackend::new()),
+            #[cfg(unix)]
+            WgBackendKind::Uapi => Backend::Uapi(uapi::WgUapiBackend::new()),
+            WgBackendKind::Su => Backend::Cmd(cmd::WgCmdBackend::new().with_su()),
+        };
+
+        Self {
+            backend,
+            executor: Executor::new(retry),
+        }
+    }
+}
+
+macro_rules! delegate {
+    ($self:ident, $iface:ident, $access:ident, $op:literal, $wg:ident => $call:expr) => {{
+        let mut call = $self.executor.call($iface, $op, Access::$access);
+        loop {
+            let turn = call.turn().await;
+            let res = match &mut $self.backend {
+                Backend::Cmd($wg) => $call,
+                Backend::Netlink($wg) => $call,
+                #[cfg(unix)]
+                Backend::Uapi($wg) => $call,
+            };
+            drop(turn);
+            if let Some(res) = call.finish(res).await {
+                break res;
+            }
+        }
+    }};
+    ($self:ident, $iface:ident, $op:literal, $wg:ident => $call:expr) => {{
+        let mut call = $self.executor.call($iface, $op, Access::Read);
+        loop {
+            let turn = call.turn().await;
+            let res = match &$self.backend {
+                Backend::Cmd($wg) => $call,
+                Backend::Netlink($wg) => $call,
+                #[cfg(unix)]
+                Backend::Uapi($wg) => $call,
+            };
+            drop(turn);
+            if let Some(res) = call.finish(res).await {
+                break res;
+            }
+        }
+    }};
+}
+
+impl WireguardApi for WgBackend {
+    type Error = crate::error::Error;
+
+    async fn get_pub_key(&self, iface: &str) -> Result<Key, Self::Error> {
+        delegate!(self, iface, "get_pub_key", wg => wg.get_pub_key(iface).await)
+    }
+
+    async fn get_listen_port(&self, iface: &str) -> Result<u16, Self::Error> {
+        delegate!(self, iface, "get_listen_port", wg => wg.get_listen_port(iface).await)
+    }
+
+    async fn get_endpoints(
+        &self,
+        iface: &str,
+    ) -> Result<HashMap<Key, Option<SocketAddr>>, Self::Error> {
+        delegate!(self, iface, "get_endpoints", wg => wg.get_endpoints(iface).await)
+    }
+
+    async fn get_state(&self, iface: &str) -> Result<WgState, Self::Error> {
+        delegate!(self, iface, "get_state", wg => wg.get_state(iface).await)
+    }
+
+    async fn get_handshakes(&self, iface: &str) -> Result<HashMap<Key, Option<u32>>, Self::Error> {
+        delegate!(self, iface, "get_handshakes", wg => wg.get_handshakes(iface).await)
+    }
+
+    async fn set_listen_port(&mut self, iface: &str, port: u16) -> Result<(), Self::Error> {
+        delegate!(self, iface, Write, "set_listen_port", wg => wg.set_listen_port(iface, port).await)
+    }
+
+    async fn set_peer_endpoint(
+        &mut self,
+        iface: &str,
+        peer: Key,
+        endpoint: Endpoint,
+    ) -> Result<(), Self::Error> {
+        delegate!(self, iface, Write, "set_peer_endpoint", wg => {
+            wg.set_peer_endpoint(iface, peer, endpoint.clone()).await
+        })
+    }
+
+    async fn set_allowed_ips(
+        &mut self,
+        iface: &str,
+        peer: Key,
+        ips: &[Cidr],
+    ) -> Result<(), Self::Error> {
+        delegate!(self, iface, Write, "set_allowed_ips", wg => wg.set_allowed_ips(iface, peer, ips).await)
+    }
+
+    async fn set_persistent_keepalive(
+        &mut self,
+        iface: &str,
+        peer: Key,
+        interval: u16,
+    ) -> Result<(), Self::Error> {
+        delegate!(self, iface, Write, "set_persistent_keepalive", wg => {
+            wg.set_persistent_keepalive(iface, peer, interval).await
+        })
+    }
+
+    async fn set_fwmark(&mut self, iface: &str, mark: u32) -> Result<(), Self::Error> {
+        delegate!(self, iface, Write, "set_fwmark", wg => wg.set_fwmark(iface, mark).await)
+    }
+
+    async fn add_peer(&mut self, iface: &str, peer: &WgPeerInfo) -> Result<(), Self::Error> {
+        delegate!(self, iface, Write, "add_peer", wg => wg.add_peer(iface, peer).await)
+    }
+
+    async fn remove_peer(&mut self, iface: &str, peer: Key) -> Result<(), Self::Error> {
+        delegate!(self, iface, Write, "remove_peer", wg => wg.remove_peer(iface, peer).await)
+    }
+
+    async fn set_preshared_key(
+        &mut self,
+        iface: &str,
+        peer: Key,
+        psk: Option<Key>,
+    ) -> Result<(), Self::Error> {
+        delegate!(self, iface, Write, "set_preshared_key", wg => {
+            wg.set_preshared_key(iface, peer, psk).await
+        })
+    }
+
+    async fn set_peer_endpoints(
+        &mut self,
+        iface: &str,
+        endpoints: &[(Key, Endpoint)],
+    ) -> Result<(), Self::Error> {
+        delegate!(self, iface, Write, "set_peer_endpoints", wg => {
+            wg.set_peer_endpoints(iface, endpoints).await
+        })
+    }
+
+    /// The keepalive toggling happens in one go, nothing else changes the
+    /// peer in between.
+    async fn trigger_handshake(
+        &mut self,
+        iface: &str,
+        peer: Key,
+        keepalive: u16,
+    ) -> Result<(), Self::Error> {
+        delegate!(self, iface, Write, "trigger_handshake", wg => {
+            wg.trigger_handshake(iface, peer, keepalive).await
+        })
+    }
 }
@@ -1,108 +1,612 @@
-use std::{collections::HashMap, net::SocketAddr, str::FromStr};
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    io::Write,
+    net::{SocketAddr, SocketAddrV6},
+    process::{Command, Output, Stdio},
+    str::FromStr,
+};
 
 use crate::error::Error;
 
-use super::{Endpoint, Key, WireguardApi, config::ParseError};
+use super::{
+    Cidr, Endpoint, Key, WgState, WireguardApi, config::ParseError, instance::WgInterfaceInfo,
+    peer::WgPeerInfo,
+};
+
+#[derive(Debug, Clone)]
+pub struct WgCmdBackend {
+    su: bool,
+}
+
+impl Default for WgCmdBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-pub struct WgCmdBackend;
 impl WgCmdBackend {
-    pub(crate) fn new() -> Self {
-        Self
+    pub fn new() -> Self {
+        Self { su: false }
+    }
+
+    /// Runs `wg` as root through `su -c`, for rooted Android phones where
+    /// the daemon itself runs as the Termux user.
+    pub fn with_su(mut self) -> Self {
+        self.su = true;
+        self
+    }
+
+    fn run(&self, cmd: &mut Command) -> Result<String, Error> {
+        let mut su;
+        let cmd = match self.su {
+            true => {
+                su = su_command(cmd);
+                &mut su
+            }
+            false => cmd,
+        };
+
+        output(cmd.output()?)
+    }
+
+    /// Like [`Self::run`], `input` is written to the command's stdin, for
+    /// keys wg only reads from files.
+    fn run_with_input(&self, cmd: &mut Command, input: &str) -> Result<String, Error> {
+        let mut su;
+        let cmd = match self.su {
+            true => {
+                su = su_command(cmd);
+                &mut su
+            }
+            false => cmd,
+        };
+
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input.as_bytes())?;
+        }
+
+        output(child.wait_with_output()?)
+    }
+
+    fn show(&self, iface: &str, what: &str) -> Result<String, Error> {
+        self.run(wg().arg("show").arg(iface).arg(what))
     }
 }
 
 impl WireguardApi for WgCmdBackend {
     type Error = Error;
 
-    fn get_pub_key(&self, iface: &str) -> Result<Key, Self::Error> {
-        let out = std::process::Command::new("wg")
-            .arg("show")
-            .arg(iface)
-            .arg("public-key")
-            .output()?;
+    async fn get_pub_key(&self, iface: &str) -> Result<Key, Self::Error> {
+        Ok(parse_pub_key(&self.show(iface, "public-key")?)?)
+    }
 
-        if !out.status.success() {
-            return Err(Error::WgCommandFail(out.status.code()));
-        }
+    async fn get_listen_port(&self, iface: &str) -> Result<u16, Self::Error> {
+        Ok(parse_listen_port(&self.show(iface, "listen-port")?)?)
+    }
 
-        let key_str = unsafe { String::from_utf8_unchecked(out.stdout) };
+    async fn get_endpoints(
+        &self,
+        iface: &str,
+    ) -> Result<HashMap<Key, Option<SocketAddr>>, Self::Error> {
+        Ok(parse_endpoints(&self.show(iface, "endpoints")?)?)
+    }
 
-        Ok(Key::from_str(key_str.trim()).map_err(ParseError::from)?)
+    async fn get_state(&self, iface: &str) -> Result<WgState, Self::Error> {
+        Ok(parse_dump(&self.show(iface, "dump")?)?)
     }
 
-    fn get_listen_port(&self, iface: &str) -> Result<u16, Self::Error> {
-        let out = std::process::Command::new("wg")
-            .arg("show")
-            .arg(iface)
-            .arg("listen-port")
-            .output()?;
+    async fn set_listen_port(&mut self, iface: &str, port: u16) -> Result<(), Self::Error> {
+        self.run(
+            wg().arg("set")
+                .arg(iface)
+                .arg("listen-port")
+                .arg(port.to_string()),
+        )?;
 
-        if !out.status.success() {
-            return Err(Error::WgCommandFail(out.status.code()));
-        }
+        Ok(())
+    }
+
+    async fn set_peer_endpoint(
+        &mut self,
+        iface: &str,
+        key: Key,
+        endpoint: Endpoint,
+    ) -> Result<(), Self::Error> {
+        self.set_peer_endpoints(iface, &[(key, endpoint)]).await
+    }
 
-        let port_str = unsafe { String::from_utf8_unchecked(out.stdout) };
+    async fn set_allowed_ips(
+        &mut self,
+        iface: &str,
+        key: Key,
+        ips: &[Cidr],
+    ) -> Result<(), Self::Error> {
+        let ips: Vec<_> = ips.iter().map(Cidr::to_string).collect();
 
-        Ok(u16::from_str(port_str.trim()).map_err(ParseError::from)?)
+        self.run(
+            wg().arg("set")
+                .arg(iface)
+                .arg("peer")
+                .arg(key.to_string())
+                .arg("allowed-ips")
+                .arg(ips.join(",")),
+        )?;
+
+        Ok(())
     }
 
-    fn get_endpoints(
-        &self,
+    async fn set_persistent_keepalive(
+        &mut self,
         iface: &str,
-    ) -> Result<std::collections::HashMap<Key, Option<SocketAddr>>, Self::Error> {
-        let out = std::process::Command::new("wg")
-            .arg("show")
+        key: Key,
+        interval: u16,
+    ) -> Result<(), Self::Error> {
+        let interval = match interval {
+            0 => "off".to_string(),
+            n => n.to_string(),
+        };
+
+        self.run(
+            wg().arg("set")
+                .arg(iface)
+                .arg("peer")
+                .arg(key.to_string())
+                .arg("persistent-keepalive")
+                .arg(interval),
+        )?;
+
+        Ok(())
+    }
+
+    async fn set_fwmark(&mut self, iface: &str, mark: u32) -> Result<(), Self::Error> {
+        let mark = match mark {
+            0 => "off".to_string(),
+            n => n.to_string(),
+        };
+
+        self.run(wg().arg("set").arg(iface).arg("fwmark").arg(mark))?;
+
+        Ok(())
+    }
+
+    async fn add_peer(&mut self, iface: &str, peer: &WgPeerInfo) -> Result<(), Self::Error> {
+        let mut cmd = wg();
+        cmd.arg("set")
             .arg(iface)
-            .arg("endpoints")
-            .output()?;
-        if !out.status.success() {
-            return Err(Error::WgCommandFail(out.status.code()));
+            .arg("peer")
+            .arg(peer.public_key.to_string());
+
+        if let Some(endpoint) = &peer.endpoint {
+            cmd.arg("endpoint").arg(endpoint.to_string());
         }
-        let mut map = HashMap::new();
-        let table = unsafe { String::from_utf8_unchecked(out.stdout) };
-        for line in table.lines() {
-            let (key_str, addr_str) = line.split_once(char::is_whitespace).unwrap();
-            let key = Key::from_str(key_str.trim()).map_err(ParseError::from)?;
-            let addr = SocketAddr::from_str(addr_str.trim()).ok();
-            map.insert(key, addr);
+
+        if let Some(ips) = &peer.allowed_ips {
+            let ips: Vec<_> = ips.iter().map(Cidr::to_string).collect();
+            cmd.arg("allowed-ips").arg(ips.join(","));
         }
-        Ok(map)
-    }
 
-    fn set_listen_port(&mut self, iface: &str, port: u16) -> Result<(), Self::Error> {
-        let out = std::process::Command::new("wg")
-            .arg("set")
-            .arg(iface)
-            .arg("listen-port")
-            .arg(port.to_string())
-            .output()?;
+        if let Some(interval) = peer.persistent_keepalive {
+            cmd.arg("persistent-keepalive").arg(interval.to_string());
+        }
 
-        if !out.status.success() {
-            return Err(Error::WgCommandFail(out.status.code()));
+        match peer.preshared_key {
+            Some(psk) => {
+                cmd.arg("preshared-key").arg("/dev/stdin");
+                self.run_with_input(&mut cmd, &format!("{psk}\n"))?;
+            }
+            None => {
+                self.run(&mut cmd)?;
+            }
         }
 
         Ok(())
     }
 
-    fn set_peer_endpoint(
+    async fn set_preshared_key(
         &mut self,
         iface: &str,
         key: Key,
-        endpoint: Endpoint,
+        psk: Option<Key>,
     ) -> Result<(), Self::Error> {
-        let out = std::process::Command::new("wg")
-            .arg("set")
+        let mut cmd = wg();
+        cmd.arg("set")
             .arg(iface)
             .arg("peer")
             .arg(key.to_string())
-            .arg("endpoint")
-            .arg(endpoint.to_string())
-            .output()?;
+            .arg("preshared-key");
+
+        // an empty file removes it
+        match psk {
+            Some(psk) => self.run_with_input(cmd.arg("/dev/stdin"), &format!("{psk}\n"))?,
+            None => self.run(cmd.arg("/dev/null"))?,
+        };
+
+        Ok(())
+    }
+
+    async fn remove_peer(&mut self, iface: &str, key: Key) -> Result<(), Self::Error> {
+        self.run(
+            wg().arg("set")
+                .arg(iface)
+                .arg("peer")
+                .arg(key.to_string())
+                .arg("remove"),
+        )?;
 
-        if !out.status.success() {
-            return Err(Error::WgCommandFail(out.status.code()));
+        Ok(())
+    }
+
+    async fn set_peer_endpoints(
+        &mut self,
+        iface: &str,
+        endpoints: &[(Key, Endpoint)],
+    ) -> Result<(), Self::Error> {
+        if endpoints.is_empty() {
+            return Ok(());
         }
 
+        let mut cmd = wg();
+        cmd.arg("set").arg(iface);
+
+        for (key, endpoint) in endpoints {
+            cmd.arg("peer")
+                .arg(key.to_string())
+                .arg("endpoint")
+                .arg(endpoint.to_string());
+        }
+
+        self.run(&mut cmd)?;
+
         Ok(())
     }
 }
+
+/// Output is parsed, so it must not depend on the user's locale.
+fn wg() -> Command {
+    let mut cmd = Command::new("wg");
+    cmd.env("LC_ALL", "C");
+    cmd
+}
+
+/// `cmd` as a shell line run by `su`, which takes the command as one
+/// argument. The environment doesn't pass through, so the locale is set in
+/// the line.
+fn su_command(cmd: &Command) -> Command {
+    let line: Vec<_> = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| format!("'{}'", arg.to_string_lossy().replace('\'', r"'\''")))
+        .collect();
+
+    let mut su = Command::new("su");
+    su.arg("-c").arg(format!("LC_ALL=C {}", line.join(" ")));
+    su
+}
+
+/// Stdout of a successful run, what wg printed to stderr otherwise, so
+/// transient failures can be told apart.
+fn output(out: Output) -> Result<String, Error> {
+    if !out.status.success() {
+        let stderr = String::from_utf8_lossy(&out.stderr).trim().to_string();
+        return Err(Error::WgCommandFail(out.status.code(), stderr));
+    }
+
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+fn parse_pub_key(out: &str) -> Result<Key, ParseError> {
+    Ok(Key::from_str(out.trim())?)
+}
+
+fn parse_listen_port(out: &str) -> Result<u16, ParseError> {
+    Ok(u16::from_str(out.trim())?)
+}
+
+/// Parses `wg show <iface> endpoints`, one `<key>\t<endpoint>` pair per line
+/// where endpoint is `(none)` for peers not seen yet.
+fn parse_endpoints(out: &str) -> Result<HashMap<Key, Option<SocketAddr>>, ParseError> {
+    let mut map = HashMap::new();
+
+    for (idx, line) in out.lines().enumerate() {
+        let [key, addr, ..] = fields(line)[..] else {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            return Err(ParseError::MalformedOutput(idx + 1, line.to_string()));
+        };
+
+        map.insert(Key::from_str(key)?, parse_addr(addr));
+    }
+
+    Ok(map)
+}
+
+/// Parses `wg show <iface> dump`: the first line describes the interface
+/// (`private-key public-key listen-port fwmark`), every next one a peer
+/// (`public-key preshared-key endpoint allowed-ips latest-handshake
+/// transfer-rx transfer-tx persistent-keepalive`).
+fn parse_dump(out: &str) -> Result<WgState, ParseError> {
+    let mut lines = out
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty());
+    let malformed = |idx: usize, line: &str| ParseError::MalformedOutput(idx + 1, line.to_string());
+
+    let (idx, line) = lines
+        .next()
+        .ok_or_else(|| ParseError::MalformedOutput(1, String::new()))?;
+
+    let [private_key, public_key, listen_port, fwmark, ..] = fields(line)[..] else {
+        return Err(malformed(idx, line));
+    };
+
+    let mut state = WgState {
+        interface: WgInterfaceInfo {
+            private_key: optional(private_key)?.unwrap_or_default(),
+            public_key: optional(public_key)?,
+            listen_port: optional(listen_port)?,
+            fwmark: optional::<Mark>(fwmark)?.map(|mark| mark.0),
+            ..Default::default()
+        },
+        peers: Vec::new(),
+    };
+
+    for (idx, line) in lines {
+        let [
+            key,
+            psk,
+            endpoint,
+            allowed_ips,
+            handshake,
+            rx,
+            tx,
+            keepalive,
+            ..,
+        ] = fields(line)[..]
+        else {
+            return Err(malformed(idx, line));
+        };
+
+        let allowed_ips = optional::<Str>(allowed_ips)?
+            .map(|ips| ips.0.split(',').map(str::parse).collect())
+            .transpose()?;
+
+        state.peers.push(WgPeerInfo {
+            public_key: key.parse()?,
+            preshared_key: optional(psk)?,
+            endpoint: optional(endpoint)?.map(|endpoint: Endpoint| match endpoint {
+                Endpoint::Domain(s) => parse_addr(&s).map_or(Endpoint::Domain(s), Endpoint::Ip),
+                ip => ip,
+            }),
+            allowed_ips,
+            persistent_keepalive: optional(keepalive)?,
+            latest_handshake: optional(handshake)?.filter(|&at| at != 0),
+            transfer: Some((rx.parse()?, tx.parse()?)),
+        });
+    }
+
+    Ok(state)
+}
+
+/// Columns of a line, tab separated as `wg` prints them or by any whitespace
+/// if something on the way expanded the tabs. `wg show all` prefixes every
+/// line with the interface name, which is dropped so columns don't shift.
+fn fields(line: &str) -> Vec<&str> {
+    let mut fields: Vec<_> = if line.contains('\t') {
+        line.split('\t').map(str::trim).collect()
+    } else {
+        line.split_whitespace().collect()
+    };
+
+    // the first column is always a key or `(none)` for a missing private key
+    if fields.len() > 1 && fields[0] != "(none)" && Key::from_str(fields[0]).is_err() {
+        fields.remove(0);
+    }
+
+    fields
+}
+
+/// Also takes link-local IPv6 with a scope, `[fe80::1%eth0]:51820`, which
+/// `SocketAddr` doesn't parse on its own.
+fn parse_addr(s: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = s.parse() {
+        return Some(addr);
+    }
+
+    let (ip, port) = s.strip_prefix('[')?.rsplit_once("]:")?;
+    let (ip, scope) = ip.split_once('%')?;
+    let scope = scope.parse().ok().or_else(|| {
+        let name = CString::new(scope).ok()?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        (index != 0).then_some(index)
+    })?;
+
+    Some(SocketAddr::V6(SocketAddrV6::new(
+        ip.parse().ok()?,
+        port.parse().ok()?,
+        0,
+        scope,
+    )))
+}
+
+/// `wg` prints `(none)` or `off` for the absent values.
+fn optional<T: FromStr>(field: &str) -> Result<Option<T>, T::Err> {
+    match field {
+        "(none)" | "off" | "" => Ok(None),
+        _ => field.parse().map(Some),
+    }
+}
+
+/// Fwmark is printed in hex, `0xca6c`.
+struct Mark(u32);
+impl FromStr for Mark {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Mark(match s.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16)?,
+            None => s.parse()?,
+        }))
+    }
+}
+
+struct Str(String);
+impl FromStr for Str {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Str(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::wg::{Endpoint, Key, config::ParseError};
+
+    use super::{parse_dump, parse_endpoints, parse_listen_port, parse_pub_key, su_command, wg};
+
+    #[test]
+    fn test_parse_pub_key() {
+        let key = Key::random();
+
+        assert_eq!(parse_pub_key(&format!("{key}\n")).unwrap(), key);
+        assert!(parse_pub_key("").is_err());
+        assert!(parse_pub_key(&key.to_string()[..20]).is_err());
+        assert!(parse_pub_key("Unable to access interface: No such device").is_err());
+
+        let garbage = String::from_utf8_lossy(b"\xff\xfe\x00garbage");
+        assert!(parse_pub_key(&garbage).is_err());
+    }
+
+    #[test]
+    fn test_su_command() {
+        let mut cmd = wg();
+        cmd.args([
+            "set",
+            "wg0",
+            "peer",
+            "it's",
+            "endpoint",
+            "[fe80::1%eth0]:51820",
+        ]);
+
+        let su = su_command(&cmd);
+        assert_eq!(su.get_program(), "su");
+        assert_eq!(
+            su.get_args().collect::<Vec<_>>(),
+            [
+                "-c",
+                r"LC_ALL=C 'wg' 'set' 'wg0' 'peer' 'it'\''s' 'endpoint' '[fe80::1%eth0]:51820'"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_listen_port() {
+        assert_eq!(parse_listen_port("51820\n").unwrap(), 51820);
+        assert!(parse_listen_port("").is_err());
+        assert!(parse_listen_port("518201").is_err());
+    }
+
+    #[test]
+    fn test_parse_endpoints() {
+        let a = Key::random();
+        let b = Key::random();
+
+        let map = parse_endpoints(&format!("{a}\t203.0.113.7:51820\n{b}\t(none)\n\n")).unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&a], Some("203.0.113.7:51820".parse().unwrap()));
+        assert_eq!(map[&b], None);
+
+        assert!(parse_endpoints("").unwrap().is_empty());
+
+        // truncated in the middle of the line
+        assert!(matches!(
+            parse_endpoints(&format!("{a}\t203.0.113.7:51820\n{}", &b.to_string()[..10])),
+            Err(ParseError::MalformedOutput(2, _))
+        ));
+
+        // truncated key
+        assert!(parse_endpoints(&format!("{}\t(none)", &a.to_string()[..30])).is_err());
+
+        let garbage = String::from_utf8_lossy(b"\xc3\x28 \xa0\xa1\n\xff");
+        assert!(parse_endpoints(&garbage).is_err());
+    }
+
+    #[test]
+    fn test_parse_dump() {
+        let (priv_key, pub_key, a, b) =
+            (Key::random(), Key::random(), Key::random(), Key::random());
+
+        let state = parse_dump(&format!(
+            "{priv_key}\t{pub_key}\t51820\toff\n\
+             {a}\t(none)\t203.0.113.7:51820\t100.64.0.1/32,192.168.0.0/24\t1718000000\t1024\t2048\t25\n\
+             {b}\t(none)\t(none)\t100.64.0.3/32\t0\t0\t0\toff\n"
+        ))
+        .unwrap();
+
+        assert_eq!(state.interface.public_key, Some(pub_key));
+        assert_eq!(state.interface.listen_port, Some(51820));
+        assert_eq!(state.interface.fwmark, None);
+        assert_eq!(state.peers.len(), 2);
+        assert_eq!(state.peers[0].public_key, a);
+        assert_eq!(state.peers[0].latest_handshake, Some(1718000000));
+        assert_eq!(state.peers[0].transfer, Some((1024, 2048)));
+        assert_eq!(state.peers[0].allowed_ips.as_ref().map(Vec::len), Some(2));
+        assert_eq!(state.peers[0].persistent_keepalive, Some(25));
+        assert_eq!(state.peers[1].endpoint, None);
+        assert_eq!(state.peers[1].latest_handshake, None);
+
+        assert!(parse_dump("").is_err());
+        assert!(matches!(
+            parse_dump(&format!(
+                "{priv_key}\t{pub_key}\t51820\toff\n{a}\t(none)\t(none)"
+            )),
+            Err(ParseError::MalformedOutput(2, _))
+        ));
+    }
+
+    #[test]
+    fn test_parse_fixtures() {
+        let old = parse_dump(include_str!("fixtures/dump-v0.0.20180613.txt")).unwrap();
+        assert_eq!(old.peers.len(), 2);
+        assert_eq!(
+            old.peers[0].endpoint,
+            Some(Endpoint::Ip("[2001:db8::7]:51820".parse().unwrap()))
+        );
+        assert_eq!(old.peers[1].allowed_ips, None);
+
+        let new = parse_dump(include_str!("fixtures/dump-v1.0.20210914.txt")).unwrap();
+        assert_eq!(new.interface.fwmark, Some(0xca6c));
+        assert!(new.peers[0].preshared_key.is_some());
+        let Some(Endpoint::Ip(SocketAddr::V6(scoped))) = new.peers[1].endpoint else {
+            panic!("scoped endpoint not parsed: {:?}", new.peers[1].endpoint);
+        };
+        assert_eq!(scoped.scope_id(), 1);
+
+        let all = parse_dump(include_str!("fixtures/dump-all-v1.0.20250521.txt")).unwrap();
+        assert_eq!(all.interface.listen_port, Some(51820));
+        assert_eq!(all.peers[0].transfer, Some((92, 148)));
+        assert_eq!(all.peers[1].endpoint, None);
+
+        let endpoints =
+            parse_endpoints(include_str!("fixtures/endpoints-v1.0.20210914.txt")).unwrap();
+        assert_eq!(endpoints.len(), 3);
+        assert_eq!(endpoints.values().filter(|addr| addr.is_some()).count(), 2);
+
+        // tabs expanded to spaces and a column added by a future version
+        let (a, b) = (Key::random(), Key::random());
+        let state = parse_dump(&format!(
+            "{a}  {b}  51820  off\n{b}  (none)  (none)  (none)  0  0  0  off  extra\n"
+        ))
+        .unwrap();
+        assert_eq!(state.peers[0].public_key, b);
+    }
+}
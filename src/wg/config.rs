@@ -2,10 +2,10 @@ use std::{
     net::{AddrParseError, IpAddr},
     num::ParseIntError,
     result::Result,
-    str::FromStr,
+    str::{FromStr, ParseBoolError},
 };
 
-use super::{Cidr, DecodeError, Endpoint, Key, instance::WgInterfaceInfo, peer::WgPeerInfo};
+use super::{Cidr, DecodeError, Endpoint, Key, peer::WgPeerInfo};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct WgConfig {
@@ -39,6 +39,15 @@ pub struct WgConfigInterface {
     // Instance Information
     pub advertise_routes: Option<Vec<Cidr>>,
 
+    // ExcludeRoutes
+    pub exclude_routes: Option<Vec<Cidr>>,
+
+    // KillSwitch
+    pub kill_switch: Option<bool>,
+
+    // Bandwidth, Mbit/s
+    pub bandwidth: Option<u32>,
+
     // PreUp
     pub pre_up: Option<String>,
 
@@ -87,6 +96,7 @@ impl From<WgConfigPeer> for WgPeerInfo {
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error)]
 pub enum ParseError {
     #[error("unexpected token")]
@@ -104,11 +114,17 @@ pub enum ParseError {
     #[error("int parse error: {0}")]
     ParseIntError(#[from] ParseIntError),
 
+    #[error("bool parse error: {0}")]
+    ParseBoolError(#[from] ParseBoolError),
+
     #[error("no interface section")]
     NoIntrerfaceSection,
 
     #[error("wrong peer format")]
     PeerParseError,
+
+    #[error("malformed output at line {0}: {1:?}")]
+    MalformedOutput(usize, String),
 }
 impl WgConfigInterface {
     fn parse(input: &mut &str) -> Result<Self, ParseError> {
@@ -124,13 +140,18 @@ impl WgConfigInterface {
                 WgPropKind::PrivateKey => iface.private_key = until('\n', input)?,
                 WgPropKind::Address => iface.address = until('\n', input)?,
                 WgPropKind::ListenPort => iface.listen_port = Some(until('\n', input)?),
-                WgPropKind::FWMark => iface.listen_port = Some(until('\n', input)?),
-                WgPropKind::MTU => iface.mtu = Some(until('\n', input)?),
-                WgPropKind::DNS => iface.dns = Some(until::<List<IpAddr>>('\n', input)?.0),
+                WgPropKind::FWMark => iface.fwmark = Some(until('\n', input)?),
+                WgPropKind::Mtu => iface.mtu = Some(until('\n', input)?),
+                WgPropKind::Dns => iface.dns = Some(until::<List<IpAddr>>('\n', input)?.0),
                 WgPropKind::Table => iface.table = Some(until('\n', input)?),
                 WgPropKind::AdvertiseRoutes => {
                     iface.advertise_routes = Some(until::<List<Cidr>>('\n', input)?.0)
                 }
+                WgPropKind::ExcludeRoutes => {
+                    iface.exclude_routes = Some(until::<List<Cidr>>('\n', input)?.0)
+                }
+                WgPropKind::KillSwitch => iface.kill_switch = Some(until('\n', input)?),
+                WgPropKind::Bandwidth => iface.bandwidth = Some(until('\n', input)?),
                 WgPropKind::PostUp => iface.post_up = Some(until::<Str>('\n', input)?.0),
                 WgPropKind::PostDown => iface.post_down = Some(until::<Str>('\n', input)?.0),
                 WgPropKind::PreUp => iface.pre_up = Some(until::<Str>('\n', input)?.0),
@@ -195,7 +216,7 @@ impl<I: FromStr> FromStr for List<I> {
         let mut ips = Vec::new();
 
         for s in s.split(',') {
-            ips.push(s.parse()?);
+            ips.push(s.trim().parse()?);
         }
 
         Ok(List(ips))
@@ -207,6 +228,9 @@ enum WgPropKind {
     PresharedKey,
     Endpoint,
     AdvertiseRoutes,
+    ExcludeRoutes,
+    KillSwitch,
+    Bandwidth,
     AllowedIPs,
     PersistentKeepalive,
     Unknown,
@@ -219,8 +243,8 @@ enum WgPropKind {
     PreDown,
     FWMark,
     Table,
-    MTU,
-    DNS,
+    Mtu,
+    Dns,
 }
 
 impl FromStr for WgPropKind {
@@ -231,6 +255,10 @@ impl FromStr for WgPropKind {
             "PublicKey" => WgPropKind::PublicKey,
             "PresharedKey" => WgPropKind::PresharedKey,
             "Endpoint" => WgPropKind::Endpoint,
+            "AdvertiseRoutes" => WgPropKind::AdvertiseRoutes,
+            "ExcludeRoutes" => WgPropKind::ExcludeRoutes,
+            "KillSwitch" => WgPropKind::KillSwitch,
+            "Bandwidth" => WgPropKind::Bandwidth,
             "AllowedIPs" => WgPropKind::AllowedIPs,
             "PersistentKeepalive" => WgPropKind::PersistentKeepalive,
             "PrivateKey" => WgPropKind::PrivateKey,
@@ -240,8 +268,8 @@ impl FromStr for WgPropKind {
             "PreUp" => WgPropKind::PreUp,
             "PreDown" => WgPropKind::PreDown,
             "Fwmark" => WgPropKind::FWMark,
-            "DNS" => WgPropKind::DNS,
-            "MTU" => WgPropKind::MTU,
+            "DNS" => WgPropKind::Dns,
+            "MTU" => WgPropKind::Mtu,
             "Address" => WgPropKind::Address,
             "Table" => WgPropKind::Table,
             _ => WgPropKind::Unknown,
@@ -264,6 +292,16 @@ fn until<M: FromStr>(p: char, input: &mut &str) -> Result<M, M::Err> {
 
 impl WgConfig {
     pub fn parse_config(input: &mut &str) -> Result<Self, ParseError> {
+        // a comment would be taken for part of the key after it, wg-quick
+        // drops everything from `#` on as well
+        let text: String = input
+            .lines()
+            .map(|line| line.split_once('#').map_or(line, |(line, _)| line))
+            .flat_map(|line| [line, "\n"])
+            .collect();
+        *input = "";
+        let input = &mut text.as_str();
+
         let mut interface = None;
         let mut peers = Vec::new();
 
@@ -290,8 +328,7 @@ mod tests {
 
     use crate::wg::{
         Endpoint, Key,
-        config::{Cidr, WgConfigInterface, WgConfigPeer, WgPeerInfo},
-        instance::WgInterfaceInfo,
+        config::{Cidr, WgConfigInterface, WgConfigPeer},
     };
 
     use super::WgConfig;
@@ -319,6 +356,7 @@ AllowedIPs = 100.64.0.1, 192.168.0.0/24, 192.168.1.1
 PersistentKeepalive = 25
 
 [Peer] # Laptop
+# comments don't hide the key after them
 PublicKey = {}
 AllowedIPs = 100.64.0.3
 PersistentKeepalive = 25
@@ -358,7 +396,10 @@ PersistentKeepalive = 25",
                             post_up: Some("iptables -A FORWARD -i %i -j ACCEPT; iptables -t nat -A POSTROUTING -o tun0 -j MASQUERADE".to_string()),
                             post_down: Some("iptables -D FORWARD -i %i -j ACCEPT; iptables -t nat -D POSTROUTING -o tun0 -j MASQUERADE".to_string()),
                             save_config: None,
-                            advertise_routes: None
+                            advertise_routes: None,
+                            exclude_routes: None,
+                            kill_switch: None,
+                            bandwidth: None,
                         },
                         peers: vec![
                             WgConfigPeer {
@@ -0,0 +1,365 @@
+//! Runs the operations of [`WgBackend`](super::WgBackend) on an interface:
+//! changes exclusive of everything else on it so nobody reads half of one,
+//! rate limited so a misbehaving loop can't hammer wireguard, transient
+//! failures retried and the time taken recorded for the metrics.
+//!
+//! Interfaces are tracked process-wide, so clones of a backend, e.g. the
+//! one of the watcher, take turns with each other.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Instant,
+};
+
+use crate::{error::Error, retry::RetryPolicy};
+
+/// Changes which can be made to an interface right away.
+const WRITE_BURST: u32 = 20;
+
+/// Time it takes for another change to be allowed once the burst is used.
+const WRITE_PERIOD: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Runs along with other reads.
+    Read,
+
+    /// Runs alone and counts against the rate limit.
+    Write,
+}
+
+/// What the operations of one kind on an interface took so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    /// Calls, however many attempts each took.
+    pub calls: u64,
+
+    /// Calls which failed after their last attempt.
+    pub failures: u64,
+
+    /// Attempts repeated after transient failures.
+    pub retries: u64,
+
+    /// Time spent, waiting for the interface and between attempts included.
+    pub total: Duration,
+    pub max: Duration,
+}
+
+#[derive(Debug)]
+struct Interface {
+    lock: RwLock<()>,
+    writes: Mutex<Bucket>,
+    timings: Mutex<BTreeMap<&'static str, Timing>>,
+}
+
+/// Token bucket of the changes to an interface.
+#[derive(Debug)]
+struct Bucket {
+    tokens: u32,
+    last: Instant,
+}
+
+impl Bucket {
+    /// Takes a token, or tells how long until the next one.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let added = now.saturating_duration_since(self.last).as_nanos() / WRITE_PERIOD.as_nanos();
+        let added = added.min(WRITE_BURST as u128) as u32;
+        if added > 0 {
+            self.tokens = (self.tokens + added).min(WRITE_BURST);
+            self.last += WRITE_PERIOD * added;
+        }
+        if self.tokens == WRITE_BURST {
+            self.last = now;
+        }
+
+        match self.tokens {
+            0 => Err((self.last + WRITE_PERIOD).saturating_duration_since(now)),
+            _ => {
+                self.tokens -= 1;
+                Ok(())
+            }
+        }
+    }
+}
+
+static INTERFACES: Mutex<BTreeMap<String, Arc<Interface>>> = Mutex::new(BTreeMap::new());
+
+fn interface(iface: &str) -> Arc<Interface> {
+    let mut interfaces = INTERFACES.lock().unwrap_or_else(PoisonError::into_inner);
+
+    interfaces
+        .entry(iface.to_string())
+        .or_insert_with(|| {
+            Arc::new(Interface {
+                lock: RwLock::new(()),
+                writes: Mutex::new(Bucket {
+                    tokens: WRITE_BURST,
+                    last: Instant::now(),
+                }),
+                timings: Mutex::new(BTreeMap::new()),
+            })
+        })
+        .clone()
+}
+
+/// Timings of the operations on `iface` by name, empty when nothing ran on
+/// it through an executor.
+pub fn timings(iface: &str) -> Vec<(&'static str, Timing)> {
+    let interfaces = INTERFACES.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(interface) = interfaces.get(iface) else {
+        return Vec::new();
+    };
+
+    let timings = interface
+        .timings
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    timings.iter().map(|(op, timing)| (*op, *timing)).collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Executor {
+    retry: RetryPolicy,
+}
+
+impl Executor {
+    /// Retries transient failures by `retry`.
+    pub fn new(retry: RetryPolicy) -> Self {
+        Self { retry }
+    }
+
+    /// Starts the operation `op` on `iface`. It is attempted on every
+    /// [`Call::turn`] until [`Call::finish`] gives its result.
+    pub fn call(&self, iface: &str, op: &'static str, access: Access) -> Call {
+        Call {
+            interface: interface(iface),
+            op,
+            access,
+            retry: self.retry.clone(),
+            started: Instant::now(),
+            attempt: 0,
+        }
+    }
+}
+
+/// Operation in progress, see [`Executor::call`]. Waits for its turns and
+/// between attempts without blocking the thread, so other tasks and other
+/// operations on the interface go on meanwhile.
+#[derive(Debug)]
+pub struct Call {
+    interface: Arc<Interface>,
+    op: &'static str,
+    access: Access,
+    retry: RetryPolicy,
+    started: Instant,
+    attempt: u32,
+}
+
+/// Hold on the interface for one attempt.
+#[derive(Debug)]
+pub enum Turn<'a> {
+    Shared(RwLockReadGuard<'a, ()>),
+    Exclusive(RwLockWriteGuard<'a, ()>),
+}
+
+impl Call {
+    /// Waits until the next attempt can be made, the attempt is to be made
+    /// while the turn is held.
+    pub async fn turn(&self) -> Turn<'_> {
+        match self.access {
+            Access::Read => Turn::Shared(self.interface.lock.read().await),
+            Access::Write => {
+                // sleeping outside the bucket's lock lets reads go on
+                loop {
+                    let taken = self
+                        .interface
+                        .writes
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .take(Instant::now());
+                    match taken {
+                        Ok(()) => break,
+                        Err(wait) => tokio::time::sleep(wait).await,
+                    }
+                }
+
+                Turn::Exclusive(self.interface.lock.write().await)
+            }
+        }
+    }
+
+    /// Result of the operation after an attempt returned `res`, `None`
+    /// when it is to be attempted again, after waiting for the backoff.
+    pub async fn finish<T>(&mut self, res: Result<T, Error>) -> Option<Result<T, Error>> {
+        let res = match res {
+            Err(err) if err.is_transient() => match self.retry.backoff(self.attempt) {
+                Some(delay) => {
+                    log::warn!("{} failed: {err}, retrying in {delay:?}", self.op);
+                    tokio::time::sleep(delay).await;
+                    self.attempt += 1;
+                    return None;
+                }
+                None => Err(err),
+            },
+            res => res,
+        };
+
+        let took = self.started.elapsed();
+        let mut timings = self
+            .interface
+            .timings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let timing = timings.entry(self.op).or_default();
+        timing.calls += 1;
+        timing.failures += u64::from(res.is_err());
+        timing.retries += u64::from(self.attempt);
+        timing.total += took;
+        timing.max = timing.max.max(took);
+
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        io,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, AtomicU32, Ordering},
+        },
+        time::Duration,
+    };
+
+    use tokio::time::Instant;
+
+    use crate::{error::Error, retry::RetryPolicy};
+
+    use super::{Access, Executor, WRITE_BURST, WRITE_PERIOD, timings};
+
+    async fn run<T, F, Fut>(
+        executor: &Executor,
+        iface: &str,
+        op: &'static str,
+        access: Access,
+        mut f: F,
+    ) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut call = executor.call(iface, op, access);
+        loop {
+            let turn = call.turn().await;
+            let res = f().await;
+            drop(turn);
+            if let Some(res) = call.finish(res).await {
+                break res;
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run() {
+        let executor = Executor::new(RetryPolicy {
+            initial_ms: 1,
+            jitter: 0.0,
+            attempts: 3,
+            ..Default::default()
+        });
+        let iface = "wg-executor-test";
+
+        let temporary = || {
+            let stderr = "Temporary failure in name resolution: `laptop.example.com:51820'";
+            Error::WgCommandFail(Some(1), stderr.into())
+        };
+
+        // transient failures are retried, others aren't
+        let calls = &AtomicU32::new(0);
+        let res = run(&executor, iface, "set", Access::Write, || async move {
+            match calls.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(temporary()),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert!(res.is_ok());
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 2);
+
+        let res: Result<(), _> = run(&executor, iface, "set", Access::Write, || async move {
+            calls.fetch_add(1, Ordering::Relaxed);
+            Err(Error::from(io::Error::from(io::ErrorKind::NotFound)))
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 1);
+
+        let res: Result<(), _> = run(&executor, iface, "get_state", Access::Read, || async move {
+            calls.fetch_add(1, Ordering::Relaxed);
+            Err(temporary())
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 3);
+
+        let timings = timings(iface);
+        let ops: Vec<_> = timings
+            .iter()
+            .map(|(op, t)| (*op, t.calls, t.failures, t.retries))
+            .collect();
+        assert_eq!(ops, [("get_state", 1, 1, 2), ("set", 2, 1, 1)]);
+
+        // a change has the interface to itself
+        let iface = "wg-executor-lock";
+        let writing = Arc::new(AtomicBool::new(false));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let (executor, writing) = (executor.clone(), writing.clone());
+                tokio::spawn(async move {
+                    let writing = &*writing;
+                    for _ in 0..5 {
+                        let access = match i % 2 {
+                            0 => Access::Write,
+                            _ => Access::Read,
+                        };
+                        run(&executor, iface, "op", access, || async move {
+                            let overlapped = match access {
+                                Access::Write => writing.swap(true, Ordering::SeqCst),
+                                Access::Read => writing.load(Ordering::SeqCst),
+                            };
+                            tokio::time::sleep(Duration::from_millis(2)).await;
+                            if access == Access::Write {
+                                writing.store(false, Ordering::SeqCst);
+                            }
+                            assert!(!overlapped);
+                            Ok(())
+                        })
+                        .await
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        // changes beyond the burst wait for the bucket to refill
+        let iface = "wg-executor-limit";
+        let started = Instant::now();
+        for _ in 0..WRITE_BURST + 2 {
+            run(&executor, iface, "set", Access::Write, || async { Ok(()) })
+                .await
+                .unwrap();
+        }
+        assert!(started.elapsed() >= WRITE_PERIOD);
+    }
+}
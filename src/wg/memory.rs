@@ -0,0 +1,264 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use crate::crypto::x25519_base;
+
+use super::{
+    Cidr, Endpoint, Key, WgState, WireguardApi, config::WgConfig, instance::WgInterfaceInfo,
+    peer::WgPeerInfo,
+};
+
+/// Interface kept in memory only, nothing is applied to the system. Lets an
+/// observer run the whole machinery and see what it would have configured.
+/// Clones share the state.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    state: Arc<Mutex<WgState>>,
+}
+
+impl MemoryBackend {
+    /// Starts with the interface and peers of `config`.
+    pub fn new(config: &WgConfig) -> Self {
+        let private_key = config.interface.private_key;
+        let state = WgState {
+            interface: WgInterfaceInfo {
+                private_key,
+                public_key: Some(Key::from(x25519_base(private_key.as_bytes()))),
+                listen_port: config.interface.listen_port,
+                ..Default::default()
+            },
+            peers: config.peers.iter().cloned().map(Into::into).collect(),
+        };
+
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn update(&self, key: Key, f: impl FnOnce(&mut WgPeerInfo)) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(peer) = state.peers.iter_mut().find(|peer| peer.public_key == key) {
+            f(peer);
+        }
+    }
+}
+
+impl WireguardApi for MemoryBackend {
+    type Error = Infallible;
+
+    async fn get_pub_key(&self, _iface: &str) -> Result<Key, Self::Error> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .interface
+            .public_key
+            .unwrap_or_default())
+    }
+
+    async fn get_listen_port(&self, _iface: &str) -> Result<u16, Self::Error> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .interface
+            .listen_port
+            .unwrap_or_default())
+    }
+
+    async fn get_endpoints(
+        &self,
+        _iface: &str,
+    ) -> Result<HashMap<Key, Option<SocketAddr>>, Self::Error> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .peers
+            .iter()
+            .map(|peer| {
+                let addr = match peer.endpoint {
+                    Some(Endpoint::Ip(addr)) => Some(addr),
+                    _ => None,
+                };
+
+                (peer.public_key, addr)
+            })
+            .collect())
+    }
+
+    async fn get_state(&self, _iface: &str) -> Result<WgState, Self::Error> {
+        Ok(self.state.lock().unwrap().clone())
+    }
+
+    async fn set_listen_port(&mut self, _iface: &str, port: u16) -> Result<(), Self::Error> {
+        self.state.lock().unwrap().interface.listen_port = Some(port);
+        Ok(())
+    }
+
+    async fn set_peer_endpoint(
+        &mut self,
+        _iface: &str,
+        key: Key,
+        endpoint: Endpoint,
+    ) -> Result<(), Self::Error> {
+        self.update(key, |peer| peer.endpoint = Some(endpoint));
+        Ok(())
+    }
+
+    async fn set_allowed_ips(
+        &mut self,
+        _iface: &str,
+        key: Key,
+        ips: &[Cidr],
+    ) -> Result<(), Self::Error> {
+        self.update(key, |peer| peer.allowed_ips = Some(ips.to_vec()));
+        Ok(())
+    }
+
+    async fn set_persistent_keepalive(
+        &mut self,
+        _iface: &str,
+        key: Key,
+        interval: u16,
+    ) -> Result<(), Self::Error> {
+        let interval = Some(interval as u32).filter(|&interval| interval != 0);
+        self.update(key, |peer| peer.persistent_keepalive = interval);
+        Ok(())
+    }
+
+    async fn set_fwmark(&mut self, _iface: &str, mark: u32) -> Result<(), Self::Error> {
+        self.state.lock().unwrap().interface.fwmark = Some(mark).filter(|&mark| mark != 0);
+        Ok(())
+    }
+
+    async fn add_peer(&mut self, _iface: &str, peer: &WgPeerInfo) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+
+        let Some(known) = state
+            .peers
+            .iter_mut()
+            .find(|known| known.public_key == peer.public_key)
+        else {
+            state.peers.push(peer.clone());
+            return Ok(());
+        };
+
+        known.preshared_key = peer.preshared_key.or(known.preshared_key);
+        known.endpoint = peer.endpoint.clone().or(known.endpoint.take());
+        known.allowed_ips = peer.allowed_ips.clone().or(known.allowed_ips.take());
+        known.persistent_keepalive = peer.persistent_keepalive.or(known.persistent_keepalive);
+        Ok(())
+    }
+
+    async fn set_preshared_key(
+        &mut self,
+        _iface: &str,
+        key: Key,
+        psk: Option<Key>,
+    ) -> Result<(), Self::Error> {
+        self.update(key, |peer| peer.preshared_key = psk);
+        Ok(())
+    }
+
+    async fn remove_peer(&mut self, _iface: &str, key: Key) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.peers.retain(|peer| peer.public_key != key);
+        Ok(())
+    }
+
+    async fn set_peer_endpoints(
+        &mut self,
+        iface: &str,
+        endpoints: &[(Key, Endpoint)],
+    ) -> Result<(), Self::Error> {
+        for (key, endpoint) in endpoints {
+            self.set_peer_endpoint(iface, *key, endpoint.clone())
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::wg::{
+        Key, WireguardApi,
+        config::{WgConfig, WgConfigPeer},
+        peer::WgPeerInfo,
+    };
+
+    use super::MemoryBackend;
+
+    #[tokio::test]
+    async fn test_clones_share_state() {
+        let peer = Key::random();
+        let config = WgConfig {
+            peers: vec![WgConfigPeer {
+                public_key: peer,
+                ..Default::default()
+            }],
+            interface: Default::default(),
+        };
+
+        let mut wg = MemoryBackend::new(&config);
+        let watcher = wg.clone();
+
+        let addr: SocketAddr = "203.0.113.7:51820".parse().unwrap();
+        wg.set_peer_endpoints("wg0", &[(peer, addr.into())])
+            .await
+            .unwrap();
+        wg.set_peer_endpoints("wg0", &[(Key::random(), addr.into())])
+            .await
+            .unwrap();
+
+        let endpoints = watcher.get_endpoints("wg0").await.unwrap();
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(endpoints[&peer], Some(addr));
+    }
+
+    #[tokio::test]
+    async fn test_add_peer() {
+        let mut wg = MemoryBackend::default();
+        let key = Key::random();
+        let psk = Key::random();
+
+        let ips = vec!["10.1.0.2/32".parse().unwrap()];
+        let peer = WgPeerInfo {
+            public_key: key,
+            allowed_ips: Some(ips.clone()),
+            persistent_keepalive: Some(25),
+            ..Default::default()
+        };
+        wg.add_peer("wg0", &peer).await.unwrap();
+        wg.set_preshared_key("wg0", key, Some(psk)).await.unwrap();
+
+        // settings left out stay as they are
+        let addr: SocketAddr = "203.0.113.7:51820".parse().unwrap();
+        let update = WgPeerInfo {
+            public_key: key,
+            endpoint: Some(addr.into()),
+            ..Default::default()
+        };
+        wg.add_peer("wg0", &update).await.unwrap();
+
+        let state = wg.get_state("wg0").await.unwrap();
+        assert_eq!(state.peers.len(), 1);
+        assert_eq!(state.peers[0].allowed_ips, Some(ips));
+        assert_eq!(state.peers[0].persistent_keepalive, Some(25));
+        assert_eq!(state.peers[0].preshared_key, Some(psk));
+        assert_eq!(state.peers[0].endpoint, Some(addr.into()));
+
+        wg.set_preshared_key("wg0", key, None).await.unwrap();
+        wg.remove_peer("wg0", Key::random()).await.unwrap();
+        let state = wg.get_state("wg0").await.unwrap();
+        assert_eq!(state.peers[0].preshared_key, None);
+    }
+}
@@ -0,0 +1,732 @@
+//! Talks to the kernel wireguard module over generic netlink, no `wg` binary
+//! and no process per operation.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
+};
+
+use crate::error::Error;
+
+use super::{Cidr, Endpoint, Key, WgState, WireguardApi, peer::WgPeerInfo};
+
+const NETLINK_GENERIC: i32 = 16;
+const NLMSG_HDRLEN: usize = 16;
+const GENL_HDRLEN: usize = 4;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_DUMP: u16 = 0x300;
+const NLA_F_NESTED: u16 = 0x8000;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WG_CMD_SET_DEVICE: u8 = 1;
+
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PRIVATE_KEY: u16 = 3;
+const WGDEVICE_A_PUBLIC_KEY: u16 = 4;
+const WGDEVICE_A_LISTEN_PORT: u16 = 6;
+const WGDEVICE_A_FWMARK: u16 = 7;
+const WGDEVICE_A_PEERS: u16 = 8;
+
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_PRESHARED_KEY: u16 = 2;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL: u16 = 5;
+const WGPEER_A_LAST_HANDSHAKE_TIME: u16 = 6;
+const WGPEER_A_RX_BYTES: u16 = 7;
+const WGPEER_A_TX_BYTES: u16 = 8;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+
+const WGPEER_F_REMOVE_ME: u32 = 1;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 2;
+
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+/// Large enough for a full dump datagram of the kernel.
+const RECV_BUF_LEN: usize = 64 * 1024;
+
+/// Writes the attributes of one peer after its key.
+type PeerAttrs = Box<dyn Fn(&mut Message)>;
+
+#[derive(Debug, Clone, Default)]
+pub struct WgNetlinkBackend;
+
+impl WgNetlinkBackend {
+    pub fn new() -> Self {
+        Self
+    }
+
+    fn get_device(&self, iface: &str) -> Result<WgState, Error> {
+        let socket = Socket::open()?;
+        let family = socket.family(WG_GENL_NAME)?;
+
+        let mut msg = Message::new(family, NLM_F_REQUEST | NLM_F_DUMP, WG_CMD_GET_DEVICE);
+        msg.str(WGDEVICE_A_IFNAME, iface);
+
+        let mut state = WgState {
+            interface: Default::default(),
+            peers: Vec::new(),
+        };
+        socket.request(&msg.finish(), |payload| parse_device(payload, &mut state))?;
+
+        Ok(state)
+    }
+
+    /// Sets attributes of `iface`, `peers` adds the nested peer list.
+    fn set_device(
+        &self,
+        iface: &str,
+        device: impl Fn(&mut Message),
+        peers: &[(Key, PeerAttrs)],
+    ) -> Result<(), Error> {
+        let socket = Socket::open()?;
+        let family = socket.family(WG_GENL_NAME)?;
+
+        let mut msg = Message::new(family, NLM_F_REQUEST | NLM_F_ACK, WG_CMD_SET_DEVICE);
+        msg.str(WGDEVICE_A_IFNAME, iface);
+        device(&mut msg);
+
+        if !peers.is_empty() {
+            msg.nest(WGDEVICE_A_PEERS);
+            for (key, peer) in peers {
+                msg.nest(0);
+                msg.attr(WGPEER_A_PUBLIC_KEY, key.as_bytes());
+                peer(&mut msg);
+                msg.end();
+            }
+            msg.end();
+        }
+
+        socket.request(&msg.finish(), |_| Ok(()))?;
+        Ok(())
+    }
+
+    fn set_peer(
+        &self,
+        iface: &str,
+        key: Key,
+        peer: impl Fn(&mut Message) + 'static,
+    ) -> Result<(), Error> {
+        self.set_device(iface, |_| {}, &[(key, Box::new(peer))])
+    }
+}
+
+impl WireguardApi for WgNetlinkBackend {
+    type Error = Error;
+
+    async fn get_pub_key(&self, iface: &str) -> Result<Key, Self::Error> {
+        self.get_device(iface)?
+            .interface
+            .public_key
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no private key set").into())
+    }
+
+    async fn get_listen_port(&self, iface: &str) -> Result<u16, Self::Error> {
+        Ok(self.get_device(iface)?.interface.listen_port.unwrap_or(0))
+    }
+
+    async fn get_endpoints(
+        &self,
+        iface: &str,
+    ) -> Result<HashMap<Key, Option<SocketAddr>>, Self::Error> {
+        let peers = self.get_device(iface)?.peers.into_iter();
+
+        Ok(peers
+            .map(|peer| {
+                let endpoint = match peer.endpoint {
+                    Some(Endpoint::Ip(addr)) => Some(addr),
+                    _ => None,
+                };
+                (peer.public_key, endpoint)
+            })
+            .collect())
+    }
+
+    async fn get_state(&self, iface: &str) -> Result<WgState, Self::Error> {
+        self.get_device(iface)
+    }
+
+    async fn set_listen_port(&mut self, iface: &str, port: u16) -> Result<(), Self::Error> {
+        self.set_device(iface, |msg| msg.u16(WGDEVICE_A_LISTEN_PORT, port), &[])
+    }
+
+    async fn set_peer_endpoint(
+        &mut self,
+        iface: &str,
+        key: Key,
+        endpoint: Endpoint,
+    ) -> Result<(), Self::Error> {
+        self.set_peer_endpoints(iface, &[(key, endpoint)]).await
+    }
+
+    async fn set_allowed_ips(
+        &mut self,
+        iface: &str,
+        key: Key,
+        ips: &[Cidr],
+    ) -> Result<(), Self::Error> {
+        let ips = ips.to_vec();
+
+        self.set_peer(iface, key, move |msg| {
+            msg.u32(WGPEER_A_FLAGS, WGPEER_F_REPLACE_ALLOWEDIPS);
+            msg.nest(WGPEER_A_ALLOWEDIPS);
+            for cidr in &ips {
+                allowed_ip(msg, cidr);
+            }
+            msg.end();
+        })
+    }
+
+    async fn set_persistent_keepalive(
+        &mut self,
+        iface: &str,
+        key: Key,
+        interval: u16,
+    ) -> Result<(), Self::Error> {
+        self.set_peer(iface, key, move |msg| {
+            msg.u16(WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL, interval)
+        })
+    }
+
+    async fn set_fwmark(&mut self, iface: &str, mark: u32) -> Result<(), Self::Error> {
+        self.set_device(iface, |msg| msg.u32(WGDEVICE_A_FWMARK, mark), &[])
+    }
+
+    async fn add_peer(&mut self, iface: &str, peer: &WgPeerInfo) -> Result<(), Self::Error> {
+        let endpoint = peer.endpoint.as_ref().map(resolve).transpose()?;
+        let peer = peer.clone();
+
+        self.set_peer(iface, peer.public_key, move |msg| {
+            if let Some(psk) = peer.preshared_key {
+                msg.attr(WGPEER_A_PRESHARED_KEY, psk.as_bytes());
+            }
+            if let Some(addr) = endpoint {
+                msg.attr(WGPEER_A_ENDPOINT, &sockaddr(&addr));
+            }
+            if let Some(interval) = peer.persistent_keepalive {
+                let interval = interval.min(u16::MAX as u32) as u16;
+                msg.u16(WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL, interval);
+            }
+            if let Some(ips) = &peer.allowed_ips {
+                msg.u32(WGPEER_A_FLAGS, WGPEER_F_REPLACE_ALLOWEDIPS);
+                msg.nest(WGPEER_A_ALLOWEDIPS);
+                for cidr in ips {
+                    allowed_ip(msg, cidr);
+                }
+                msg.end();
+            }
+        })
+    }
+
+    async fn set_preshared_key(
+        &mut self,
+        iface: &str,
+        key: Key,
+        psk: Option<Key>,
+    ) -> Result<(), Self::Error> {
+        // all zeros removes it
+        let psk = psk.unwrap_or_default();
+        self.set_peer(iface, key, move |msg| {
+            msg.attr(WGPEER_A_PRESHARED_KEY, psk.as_bytes())
+        })
+    }
+
+    async fn remove_peer(&mut self, iface: &str, key: Key) -> Result<(), Self::Error> {
+        self.set_peer(iface, key, |msg| {
+            msg.u32(WGPEER_A_FLAGS, WGPEER_F_REMOVE_ME)
+        })
+    }
+
+    async fn set_peer_endpoints(
+        &mut self,
+        iface: &str,
+        endpoints: &[(Key, Endpoint)],
+    ) -> Result<(), Self::Error> {
+        if endpoints.is_empty() {
+            return Ok(());
+        }
+
+        // names are resolved here, the kernel only takes addresses
+        let mut peers: Vec<(Key, PeerAttrs)> = Vec::new();
+        for (key, endpoint) in endpoints {
+            let addr = resolve(endpoint)?;
+            peers.push((
+                *key,
+                Box::new(move |msg| msg.attr(WGPEER_A_ENDPOINT, &sockaddr(&addr))),
+            ));
+        }
+
+        self.set_device(iface, |_| {}, &peers)
+    }
+}
+
+pub(super) fn resolve(endpoint: &Endpoint) -> io::Result<SocketAddr> {
+    match endpoint {
+        Endpoint::Ip(addr) => Ok(*addr),
+        Endpoint::Domain(name) => name.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{name} has no address"))
+        }),
+    }
+}
+
+fn allowed_ip(msg: &mut Message, cidr: &Cidr) {
+    msg.nest(0);
+    match cidr.ip {
+        IpAddr::V4(ip) => {
+            msg.u16(WGALLOWEDIP_A_FAMILY, libc::AF_INET as u16);
+            msg.attr(WGALLOWEDIP_A_IPADDR, &ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            msg.u16(WGALLOWEDIP_A_FAMILY, libc::AF_INET6 as u16);
+            msg.attr(WGALLOWEDIP_A_IPADDR, &ip.octets());
+        }
+    }
+    msg.attr(WGALLOWEDIP_A_CIDR_MASK, &[cidr.mask]);
+    msg.end();
+}
+
+/// `sockaddr_in` or `sockaddr_in6` as the kernel lays them out.
+fn sockaddr(addr: &SocketAddr) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(28);
+    match addr {
+        SocketAddr::V4(addr) => {
+            buf.extend((libc::AF_INET as u16).to_ne_bytes());
+            buf.extend(addr.port().to_be_bytes());
+            buf.extend(addr.ip().octets());
+            buf.extend([0; 8]);
+        }
+        SocketAddr::V6(addr) => {
+            buf.extend((libc::AF_INET6 as u16).to_ne_bytes());
+            buf.extend(addr.port().to_be_bytes());
+            buf.extend(addr.flowinfo().to_be_bytes());
+            buf.extend(addr.ip().octets());
+            buf.extend(addr.scope_id().to_ne_bytes());
+        }
+    }
+    buf
+}
+
+fn parse_sockaddr(buf: &[u8]) -> Option<SocketAddr> {
+    let family = u16::from_ne_bytes(buf.get(0..2)?.try_into().ok()?) as i32;
+    let port = u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?);
+
+    match family {
+        libc::AF_INET => {
+            let ip: [u8; 4] = buf.get(4..8)?.try_into().ok()?;
+            Some(SocketAddr::V4(SocketAddrV4::new(ip.into(), port)))
+        }
+        libc::AF_INET6 => {
+            let flowinfo = u32::from_be_bytes(buf.get(4..8)?.try_into().ok()?);
+            let ip: [u8; 16] = buf.get(8..24)?.try_into().ok()?;
+            let scope = u32::from_ne_bytes(buf.get(24..28)?.try_into().ok()?);
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                ip.into(),
+                port,
+                flowinfo,
+                scope,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Request being built, nested attributes are closed with [`Message::end`].
+struct Message {
+    buf: Vec<u8>,
+    nests: Vec<usize>,
+}
+
+impl Message {
+    fn new(family: u16, flags: u16, cmd: u8) -> Self {
+        let mut buf = Vec::with_capacity(256);
+        buf.extend(0u32.to_ne_bytes());
+        buf.extend(family.to_ne_bytes());
+        buf.extend(flags.to_ne_bytes());
+        buf.extend(1u32.to_ne_bytes()); // seq
+        buf.extend(0u32.to_ne_bytes()); // port id, the kernel fills it in
+        buf.extend([cmd, WG_GENL_VERSION, 0, 0]);
+
+        Self {
+            buf,
+            nests: Vec::new(),
+        }
+    }
+
+    fn attr(&mut self, kind: u16, value: &[u8]) {
+        self.buf.extend(((4 + value.len()) as u16).to_ne_bytes());
+        self.buf.extend(kind.to_ne_bytes());
+        self.buf.extend(value);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    fn u16(&mut self, kind: u16, value: u16) {
+        self.attr(kind, &value.to_ne_bytes());
+    }
+
+    fn u32(&mut self, kind: u16, value: u32) {
+        self.attr(kind, &value.to_ne_bytes());
+    }
+
+    fn str(&mut self, kind: u16, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.attr(kind, &bytes);
+    }
+
+    fn nest(&mut self, kind: u16) {
+        self.nests.push(self.buf.len());
+        self.attr(kind | NLA_F_NESTED, &[]);
+    }
+
+    fn end(&mut self) {
+        if let Some(start) = self.nests.pop() {
+            let len = (self.buf.len() - start) as u16;
+            self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+#[inline]
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn u16_at(buf: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(buf.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(buf: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_ne_bytes(buf.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn key(value: &[u8]) -> Option<Key> {
+    Some(Key::from(<[u8; 32]>::try_from(value).ok()?))
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed netlink message")
+}
+
+/// Attributes of `buf` as type and value, flags masked off the type.
+fn attrs(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let len = u16_at(buf, 0)? as usize;
+        let kind = u16_at(buf, 2)? & NLA_TYPE_MASK;
+        let value = buf.get(4..len)?;
+
+        buf = &buf[align(len).min(buf.len())..];
+        Some((kind, value))
+    })
+}
+
+/// Passes the payload of each message in one datagram to `on_message`,
+/// `true` once the kernel is done answering.
+fn parse(mut buf: &[u8], on_message: &mut impl FnMut(&[u8]) -> io::Result<()>) -> io::Result<bool> {
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32_at(buf, 0).ok_or_else(malformed)? as usize;
+        let kind = u16_at(buf, 4).ok_or_else(malformed)?;
+
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            return Err(malformed());
+        }
+
+        let payload = &buf[NLMSG_HDRLEN..len];
+
+        match kind {
+            // both carry an errno, zero for the ack of a request
+            NLMSG_DONE | NLMSG_ERROR => {
+                let errno = u32_at(payload, 0).unwrap_or(0) as i32;
+                if errno != 0 {
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+                return Ok(true);
+            }
+            _ => on_message(payload.get(GENL_HDRLEN..).ok_or_else(malformed)?)?,
+        }
+
+        buf = &buf[align(len).min(buf.len())..];
+    }
+
+    Ok(false)
+}
+
+/// Adds one `WG_CMD_GET_DEVICE` message to `state`. A peer with many
+/// allowed IPs continues in the next message, it's merged back.
+fn parse_device(payload: &[u8], state: &mut WgState) -> io::Result<()> {
+    let interface = &mut state.interface;
+
+    for (kind, value) in attrs(payload) {
+        match kind {
+            WGDEVICE_A_PRIVATE_KEY => interface.private_key = key(value).ok_or_else(malformed)?,
+            WGDEVICE_A_PUBLIC_KEY => interface.public_key = key(value),
+            WGDEVICE_A_LISTEN_PORT => interface.listen_port = u16_at(value, 0),
+            WGDEVICE_A_FWMARK => interface.fwmark = u32_at(value, 0).filter(|&mark| mark != 0),
+            WGDEVICE_A_PEERS => {
+                for (_, peer) in attrs(value) {
+                    let peer = parse_peer(peer)?;
+
+                    match state.peers.last_mut() {
+                        Some(last) if last.public_key == peer.public_key => {
+                            let ips = peer.allowed_ips.into_iter().flatten();
+                            last.allowed_ips.get_or_insert_default().extend(ips);
+                        }
+                        _ => state.peers.push(peer),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_peer(buf: &[u8]) -> io::Result<WgPeerInfo> {
+    let mut peer = WgPeerInfo::default();
+    let (mut rx, mut tx) = (0, 0);
+
+    for (kind, value) in attrs(buf) {
+        match kind {
+            WGPEER_A_PUBLIC_KEY => peer.public_key = key(value).ok_or_else(malformed)?,
+            WGPEER_A_PRESHARED_KEY => {
+                peer.preshared_key = key(value).filter(|psk| psk.as_bytes() != &[0; 32])
+            }
+            WGPEER_A_ENDPOINT => peer.endpoint = parse_sockaddr(value).map(Endpoint::Ip),
+            WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL => {
+                peer.persistent_keepalive = u16_at(value, 0).filter(|&n| n != 0).map(u32::from)
+            }
+            // __kernel_timespec, seconds are enough
+            WGPEER_A_LAST_HANDSHAKE_TIME => {
+                peer.latest_handshake = u64_at(value, 0).filter(|&at| at != 0).map(|at| at as u32)
+            }
+            WGPEER_A_RX_BYTES => rx = u64_at(value, 0).unwrap_or(0),
+            WGPEER_A_TX_BYTES => tx = u64_at(value, 0).unwrap_or(0),
+            WGPEER_A_ALLOWEDIPS => {
+                let ips: Vec<Cidr> = attrs(value)
+                    .filter_map(|(_, ip)| parse_allowed_ip(ip))
+                    .collect();
+                peer.allowed_ips = (!ips.is_empty()).then_some(ips);
+            }
+            _ => {}
+        }
+    }
+
+    peer.transfer = Some((rx, tx));
+    Ok(peer)
+}
+
+fn parse_allowed_ip(buf: &[u8]) -> Option<Cidr> {
+    let (mut ip, mut mask) = (None, None);
+
+    for (kind, value) in attrs(buf) {
+        match kind {
+            WGALLOWEDIP_A_IPADDR => {
+                ip = match value.len() {
+                    4 => Some(IpAddr::V4(<[u8; 4]>::try_from(value).ok()?.into())),
+                    16 => Some(IpAddr::V6(<[u8; 16]>::try_from(value).ok()?.into())),
+                    _ => None,
+                }
+            }
+            WGALLOWEDIP_A_CIDR_MASK => mask = value.first().copied(),
+            _ => {}
+        }
+    }
+
+    Some(Cidr {
+        ip: ip?,
+        mask: mask?,
+    })
+}
+
+/// Generic netlink socket, closed on drop.
+struct Socket(libc::c_int);
+
+impl Socket {
+    #[cfg(target_os = "linux")]
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                NETLINK_GENERIC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self(fd))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open() -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Sends `request` and reads the answer up to the ack or the end of a
+    /// dump.
+    fn request(
+        &self,
+        request: &[u8],
+        mut on_message: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let sent = unsafe { libc::send(self.0, request.as_ptr().cast(), request.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; RECV_BUF_LEN];
+        loop {
+            let len = unsafe { libc::recv(self.0, buf.as_mut_ptr().cast(), buf.len(), 0) };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if parse(&buf[..len as usize], &mut on_message)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Id of a generic netlink family, `ENOENT` if its module isn't loaded.
+    fn family(&self, name: &str) -> io::Result<u16> {
+        let mut msg = Message::new(GENL_ID_CTRL, NLM_F_REQUEST | NLM_F_ACK, CTRL_CMD_GETFAMILY);
+        msg.str(CTRL_ATTR_FAMILY_NAME, name);
+
+        let mut family = None;
+        self.request(&msg.finish(), |payload| {
+            family = attrs(payload)
+                .find(|&(kind, _)| kind == CTRL_ATTR_FAMILY_ID)
+                .and_then(|(_, value)| u16_at(value, 0));
+            Ok(())
+        })?;
+
+        family.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no wireguard module"))
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wg::{Endpoint, Key, WgState};
+
+    use super::{Message, NLA_F_NESTED, allowed_ip, parse, parse_device, sockaddr};
+
+    #[test]
+    fn test_parse_device() {
+        let (a, b) = (Key::random(), Key::random());
+        let endpoint = "203.0.113.7:51820".parse().unwrap();
+
+        // a reply is laid out like a request, the peer of `b` continues
+        // in a second message with more allowed IPs
+        let mut first = Message::new(0x15, 0, 0);
+        first.str(2, "wg0");
+        first.attr(4, a.as_bytes());
+        first.u16(6, 51820);
+        first.nest(8);
+        first.nest(0);
+        first.attr(1, b.as_bytes());
+        first.attr(4, &sockaddr(&endpoint));
+        first.attr(6, &[1u64.to_ne_bytes(), 0u64.to_ne_bytes()].concat());
+        first.attr(7, &1024u64.to_ne_bytes());
+        first.nest(9);
+        allowed_ip(&mut first, &"10.1.0.2/32".parse().unwrap());
+        first.end();
+        first.end();
+        first.end();
+
+        let mut second = Message::new(0x15, 0, 0);
+        second.nest(8);
+        second.nest(0);
+        second.attr(1, b.as_bytes());
+        second.nest(9);
+        allowed_ip(&mut second, &"fd00::2/128".parse().unwrap());
+        second.end();
+        second.end();
+        second.end();
+
+        let mut buf = first.finish();
+        buf.extend(second.finish());
+
+        let mut state = WgState {
+            interface: Default::default(),
+            peers: Vec::new(),
+        };
+        let mut on_message = |payload: &[u8]| parse_device(payload, &mut state);
+        assert!(!parse(&buf, &mut on_message).unwrap());
+
+        // done
+        let mut done = Message::new(3, 0, 0);
+        done.buf.truncate(16);
+        assert!(parse(&done.finish(), &mut |_: &[u8]| Ok(())).unwrap());
+
+        assert_eq!(state.interface.public_key, Some(a));
+        assert_eq!(state.interface.listen_port, Some(51820));
+        assert_eq!(state.peers.len(), 1);
+
+        let peer = &state.peers[0];
+        assert_eq!(peer.public_key, b);
+        assert_eq!(peer.endpoint, Some(Endpoint::Ip(endpoint)));
+        assert_eq!(peer.latest_handshake, Some(1));
+        assert_eq!(peer.transfer, Some((1024, 0)));
+        assert_eq!(
+            peer.allowed_ips,
+            Some(vec![
+                "10.1.0.2/32".parse().unwrap(),
+                "fd00::2/128".parse().unwrap()
+            ])
+        );
+
+        // ENODEV
+        let mut err = Message::new(2, 0, 0);
+        err.buf.truncate(16);
+        err.buf.extend((-19i32).to_ne_bytes());
+        let err = parse(&err.finish(), &mut |_: &[u8]| Ok(())).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(19));
+    }
+
+    #[test]
+    fn test_nest() {
+        let mut msg = Message::new(0x15, 0, 1);
+        msg.nest(8);
+        msg.attr(1, &[0; 32]);
+        msg.end();
+
+        let buf = msg.finish();
+        assert_eq!(u32::from_ne_bytes(buf[0..4].try_into().unwrap()), 60);
+        assert_eq!(u16::from_ne_bytes(buf[20..22].try_into().unwrap()), 40);
+        assert_eq!(
+            u16::from_ne_bytes(buf[22..24].try_into().unwrap()),
+            8 | NLA_F_NESTED
+        );
+    }
+}
@@ -0,0 +1,442 @@
+//! Talks to userspace wireguard, wireguard-go or boringtun, over its UAPI
+//! socket. That's what runs where the kernel has no wireguard module, like
+//! Termux on Android.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::SocketAddr,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+use crate::{android, crypto::x25519_base, error::Error};
+
+use super::{
+    Cidr, Endpoint, Key, WgState, WireguardApi, config::ParseError, netlink::resolve,
+    peer::WgPeerInfo,
+};
+
+/// Where userspace implementations put `<iface>.sock`.
+const SOCKET_DIR: &str = "/var/run/wireguard";
+
+#[derive(Debug, Clone)]
+pub struct WgUapiBackend {
+    dir: PathBuf,
+}
+
+impl Default for WgUapiBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WgUapiBackend {
+    pub fn new() -> Self {
+        Self {
+            dir: android::path(SOCKET_DIR),
+        }
+    }
+
+    /// Looks for sockets in `dir` instead of the default one.
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    /// Sends `request` and returns the response up to the empty line ending
+    /// it, failing with the errno it reports.
+    fn request(&self, iface: &str, request: &str) -> Result<String, Error> {
+        let path = self.dir.join(format!("{iface}.sock"));
+
+        // the socket goes away with the interface
+        let mut stream = UnixStream::connect(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => io::Error::from_raw_os_error(libc::ENODEV),
+            _ => err,
+        })?;
+        stream.write_all(request.as_bytes())?;
+
+        let mut reader = BufReader::new(stream);
+        let mut response = String::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line == "\n" {
+                break;
+            }
+            response.push_str(&line);
+        }
+
+        errno(&response)?;
+        Ok(response)
+    }
+
+    fn get(&self, iface: &str) -> Result<WgState, Error> {
+        Ok(parse_get(&self.request(iface, "get=1\n\n")?)?)
+    }
+
+    /// Applies `set` lines.
+    fn set(&self, iface: &str, lines: &str) -> Result<(), Error> {
+        self.request(iface, &format!("set=1\n{lines}\n"))?;
+        Ok(())
+    }
+}
+
+impl WireguardApi for WgUapiBackend {
+    type Error = Error;
+
+    async fn get_pub_key(&self, iface: &str) -> Result<Key, Self::Error> {
+        self.get(iface)?
+            .interface
+            .public_key
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no private key set").into())
+    }
+
+    async fn get_listen_port(&self, iface: &str) -> Result<u16, Self::Error> {
+        Ok(self.get(iface)?.interface.listen_port.unwrap_or(0))
+    }
+
+    async fn get_endpoints(
+        &self,
+        iface: &str,
+    ) -> Result<HashMap<Key, Option<SocketAddr>>, Self::Error> {
+        let peers = self.get(iface)?.peers.into_iter();
+
+        Ok(peers
+            .map(|peer| {
+                let endpoint = match peer.endpoint {
+                    Some(Endpoint::Ip(addr)) => Some(addr),
+                    _ => None,
+                };
+                (peer.public_key, endpoint)
+            })
+            .collect())
+    }
+
+    async fn get_state(&self, iface: &str) -> Result<WgState, Self::Error> {
+        self.get(iface)
+    }
+
+    async fn set_listen_port(&mut self, iface: &str, port: u16) -> Result<(), Self::Error> {
+        self.set(iface, &format!("listen_port={port}\n"))
+    }
+
+    async fn set_peer_endpoint(
+        &mut self,
+        iface: &str,
+        key: Key,
+        endpoint: Endpoint,
+    ) -> Result<(), Self::Error> {
+        self.set_peer_endpoints(iface, &[(key, endpoint)]).await
+    }
+
+    async fn set_allowed_ips(
+        &mut self,
+        iface: &str,
+        key: Key,
+        ips: &[Cidr],
+    ) -> Result<(), Self::Error> {
+        let mut lines = format!("public_key={}\nreplace_allowed_ips=true\n", hex(&key));
+        for cidr in ips {
+            let _ = writeln!(lines, "allowed_ip={cidr}");
+        }
+
+        self.set(iface, &lines)
+    }
+
+    async fn set_persistent_keepalive(
+        &mut self,
+        iface: &str,
+        key: Key,
+        interval: u16,
+    ) -> Result<(), Self::Error> {
+        self.set(
+            iface,
+            &format!(
+                "public_key={}\npersistent_keepalive_interval={interval}\n",
+                hex(&key)
+            ),
+        )
+    }
+
+    async fn set_fwmark(&mut self, iface: &str, mark: u32) -> Result<(), Self::Error> {
+        self.set(iface, &format!("fwmark={mark}\n"))
+    }
+
+    async fn add_peer(&mut self, iface: &str, peer: &WgPeerInfo) -> Result<(), Self::Error> {
+        let mut lines = format!("public_key={}\n", hex(&peer.public_key));
+
+        if let Some(psk) = &peer.preshared_key {
+            let _ = writeln!(lines, "preshared_key={}", hex(psk));
+        }
+        if let Some(endpoint) = &peer.endpoint {
+            let _ = writeln!(lines, "endpoint={}", resolve(endpoint)?);
+        }
+        if let Some(interval) = peer.persistent_keepalive {
+            let _ = writeln!(lines, "persistent_keepalive_interval={interval}");
+        }
+        if let Some(ips) = &peer.allowed_ips {
+            lines.push_str("replace_allowed_ips=true\n");
+            for cidr in ips {
+                let _ = writeln!(lines, "allowed_ip={cidr}");
+            }
+        }
+
+        self.set(iface, &lines)
+    }
+
+    async fn remove_peer(&mut self, iface: &str, key: Key) -> Result<(), Self::Error> {
+        self.set(iface, &format!("public_key={}\nremove=true\n", hex(&key)))
+    }
+
+    async fn set_preshared_key(
+        &mut self,
+        iface: &str,
+        key: Key,
+        psk: Option<Key>,
+    ) -> Result<(), Self::Error> {
+        // all zeros removes it
+        let psk = psk.unwrap_or_default();
+        self.set(
+            iface,
+            &format!("public_key={}\npreshared_key={}\n", hex(&key), hex(&psk)),
+        )
+    }
+
+    async fn set_peer_endpoints(
+        &mut self,
+        iface: &str,
+        endpoints: &[(Key, Endpoint)],
+    ) -> Result<(), Self::Error> {
+        if endpoints.is_empty() {
+            return Ok(());
+        }
+
+        // names are resolved here, UAPI only takes addresses
+        let mut lines = String::new();
+        for (key, endpoint) in endpoints {
+            let _ = writeln!(
+                lines,
+                "public_key={}\nendpoint={}",
+                hex(key),
+                resolve(endpoint)?
+            );
+        }
+
+        self.set(iface, &lines)
+    }
+}
+
+/// Keys go over UAPI in lowercase hex.
+fn hex(key: &Key) -> String {
+    key.as_bytes().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(s: &str) -> Option<Key> {
+    if s.len() != 64 {
+        return None;
+    }
+
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(Key::from(key))
+}
+
+/// The `errno=` line ending every response, non-zero is an error.
+fn errno(response: &str) -> io::Result<()> {
+    let errno = response
+        .lines()
+        .rev()
+        .find_map(|line| line.strip_prefix("errno="))
+        .and_then(|errno| errno.parse::<i32>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no errno in uapi response"))?;
+
+    match errno {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Parses the response to `get=1`: interface attributes, then every peer
+/// starting with its `public_key`.
+fn parse_get(out: &str) -> Result<WgState, ParseError> {
+    let mut state = WgState {
+        interface: Default::default(),
+        peers: Vec::new(),
+    };
+
+    for (idx, line) in out.lines().enumerate() {
+        let malformed = || ParseError::MalformedOutput(idx + 1, line.to_string());
+        let (name, value) = line.split_once('=').ok_or_else(malformed)?;
+
+        if name == "public_key" {
+            state.peers.push(WgPeerInfo {
+                public_key: unhex(value).ok_or_else(malformed)?,
+                transfer: Some((0, 0)),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let Some(peer) = state.peers.last_mut() else {
+            let interface = &mut state.interface;
+            match name {
+                "private_key" => {
+                    let key = unhex(value).ok_or_else(malformed)?;
+                    interface.public_key = Some(Key::from(x25519_base(key.as_bytes())));
+                    interface.private_key = key;
+                }
+                "listen_port" => interface.listen_port = Some(value.parse()?),
+                "fwmark" => interface.fwmark = Some(value.parse()?).filter(|&mark| mark != 0),
+                _ => {}
+            }
+            continue;
+        };
+
+        match name {
+            "preshared_key" => {
+                let psk = unhex(value).ok_or_else(malformed)?;
+                peer.preshared_key = Some(psk).filter(|psk| *psk != Key::default());
+            }
+            "endpoint" => peer.endpoint = Some(Endpoint::Ip(value.parse()?)),
+            "last_handshake_time_sec" => {
+                peer.latest_handshake = Some(value.parse()?).filter(|&at| at != 0);
+            }
+            "rx_bytes" => {
+                let (_, tx) = peer.transfer.unwrap_or_default();
+                peer.transfer = Some((value.parse()?, tx));
+            }
+            "tx_bytes" => {
+                let (rx, _) = peer.transfer.unwrap_or_default();
+                peer.transfer = Some((rx, value.parse()?));
+            }
+            "persistent_keepalive_interval" => {
+                peer.persistent_keepalive = Some(value.parse()?).filter(|&interval| interval != 0);
+            }
+            "allowed_ip" => peer
+                .allowed_ips
+                .get_or_insert_default()
+                .push(value.parse().map_err(|_| malformed())?),
+            _ => {}
+        }
+    }
+
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixListener,
+        thread,
+    };
+
+    use crate::{
+        crypto::x25519_base,
+        wg::{Endpoint, Key, WireguardApi},
+    };
+
+    use super::{WgUapiBackend, hex, parse_get};
+
+    #[test]
+    fn test_parse_get() {
+        let private = Key::random();
+        let (a, b) = (Key::random(), Key::random());
+        let out = format!(
+            "private_key={}\nlisten_port=51820\nfwmark=0\n\
+             public_key={}\npreshared_key={}\nprotocol_version=1\n\
+             endpoint=203.0.113.7:51820\nlast_handshake_time_sec=1718000000\n\
+             last_handshake_time_nsec=5\ntx_bytes=10\nrx_bytes=20\n\
+             persistent_keepalive_interval=25\nallowed_ip=10.0.0.2/32\nallowed_ip=fd00::2/128\n\
+             public_key={}\nlast_handshake_time_sec=0\npersistent_keepalive_interval=0\n\
+             errno=0\n",
+            hex(&private),
+            hex(&a),
+            hex(&Key::default()),
+            hex(&b),
+        );
+
+        let state = parse_get(&out).unwrap();
+        assert_eq!(
+            state.interface.public_key,
+            Some(Key::from(x25519_base(private.as_bytes())))
+        );
+        assert_eq!(state.interface.listen_port, Some(51820));
+        assert_eq!(state.interface.fwmark, None);
+
+        let peer = &state.peers[0];
+        assert_eq!(peer.public_key, a);
+        assert_eq!(peer.preshared_key, None);
+        assert_eq!(
+            peer.endpoint,
+            Some(Endpoint::Ip("203.0.113.7:51820".parse().unwrap()))
+        );
+        assert_eq!(peer.latest_handshake, Some(1718000000));
+        assert_eq!(peer.transfer, Some((20, 10)));
+        assert_eq!(peer.persistent_keepalive, Some(25));
+        assert_eq!(peer.allowed_ips.as_ref().unwrap().len(), 2);
+
+        let peer = &state.peers[1];
+        assert_eq!(peer.public_key, b);
+        assert_eq!(peer.latest_handshake, None);
+        assert_eq!(peer.persistent_keepalive, None);
+        assert_eq!(peer.allowed_ips, None);
+
+        assert!(parse_get("public_key=nothex\n").is_err());
+        assert!(parse_get("garbage\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socket() {
+        let dir = std::env::temp_dir().join(format!("wg-disco-uapi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let listener = UnixListener::bind(dir.join("wg0.sock")).unwrap();
+
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for errno in [0, 22] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\n" {
+                        break;
+                    }
+                    request.push_str(&line);
+                }
+
+                write!(reader.get_mut(), "errno={errno}\n\n").unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        let key = Key::random();
+        let mut wg = WgUapiBackend::new().with_dir(&dir);
+        wg.set_peer_endpoint(
+            "wg0",
+            key,
+            Endpoint::Ip("203.0.113.7:51820".parse().unwrap()),
+        )
+        .await
+        .unwrap();
+        assert!(wg.set_listen_port("wg0", 51820).await.is_err());
+
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                format!(
+                    "set=1\npublic_key={}\nendpoint=203.0.113.7:51820\n",
+                    hex(&key)
+                ),
+                "set=1\nlisten_port=51820\n".to_string(),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use futures::{Stream, stream};
+use tokio::time::{Interval, MissedTickBehavior};
+
+use super::{Endpoint, Key, WireguardApi, peer::WgPeerInfo};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WgEvent {
+    /// New handshake with the peer, unix time in seconds.
+    Handshake { key: Key, at: u32 },
+
+    /// Transfer counters changed.
+    Transfer { key: Key, rx: u64, tx: u64 },
+
+    /// The kernel learned a new endpoint (peer roamed).
+    Endpoint {
+        key: Key,
+        endpoint: Option<Endpoint>,
+    },
+
+    /// Peer disappeared from the interface.
+    Removed { key: Key },
+}
+
+/// Polls the interface state and reports handshake and transfer changes.
+pub struct WgWatcher<W> {
+    wg: W,
+    iface: String,
+    interval: Duration,
+    peers: HashMap<Key, WgPeerInfo>,
+}
+
+impl<W: WireguardApi> WgWatcher<W> {
+    pub fn new(wg: W, iface: String, interval: Duration) -> Self {
+        Self {
+            wg,
+            iface,
+            interval,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Queries the interface once and returns the changes since the
+    /// previous poll.
+    pub async fn poll(&mut self) -> Result<Vec<WgEvent>, W::Error> {
+        let state = self.wg.get_state(&self.iface).await?;
+        let mut events = Vec::new();
+        let mut peers = HashMap::with_capacity(state.peers.len());
+
+        for peer in state.peers {
+            let key = peer.public_key;
+            let prev = self.peers.remove(&key);
+            let prev = prev.as_ref();
+
+            if let Some(at) = peer.latest_handshake
+                && prev.and_then(|p| p.latest_handshake) != Some(at)
+            {
+                events.push(WgEvent::Handshake { key, at });
+            }
+
+            if let Some((rx, tx)) = peer.transfer
+                && prev.and_then(|p| p.transfer) != peer.transfer
+            {
+                events.push(WgEvent::Transfer { key, rx, tx });
+            }
+
+            if prev.is_some_and(|p| p.endpoint != peer.endpoint) {
+                events.push(WgEvent::Endpoint {
+                    key,
+                    endpoint: peer.endpoint.clone(),
+                });
+            }
+
+            peers.insert(key, peer);
+        }
+
+        events.extend(self.peers.keys().map(|&key| WgEvent::Removed { key }));
+        self.peers = peers;
+
+        Ok(events)
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<WgEvent, W::Error>> {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        stream::unfold(
+            (self, interval, VecDeque::new()),
+            |(mut this, mut interval, mut pending): (Self, Interval, VecDeque<WgEvent>)| async move {
+                loop {
+                    if let Some(event) = pending.pop_front() {
+                        return Some((Ok(event), (this, interval, pending)));
+                    }
+
+                    interval.tick().await;
+
+                    match this.poll().await {
+                        Ok(events) => pending.extend(events),
+                        Err(err) => return Some((Err(err), (this, interval, pending))),
+                    }
+                }
+            },
+        )
+    }
+}